@@ -3,14 +3,17 @@
 #![allow(missing_docs, clippy::missing_docs_in_private_items)]
 
 use crate::{err::ErrorDetail, BootstrapBehavior, Result, TorClient, TorClientConfig};
+use std::fmt;
+use std::sync::Arc;
+use tor_dirmgr::DirMgr;
 use tor_rtcompat::Runtime;
 
 /// An object for constructing a [`TorClient`].
 ///
 /// Returned by [`TorClient::builder()`].
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 #[must_use]
-pub struct TorClientBuilder<R> {
+pub struct TorClientBuilder<R: Runtime> {
     /// The runtime for the client to use
     runtime: R,
     /// The client's configuration.
@@ -18,15 +21,29 @@ pub struct TorClientBuilder<R> {
     /// How the client should behave when it is asked to do something on the Tor
     /// network before `bootstrap()` is called.
     bootstrap_behavior: BootstrapBehavior,
+    /// A directory manager to share with other clients, if we were given one.
+    dirmgr: Option<Arc<DirMgr<R>>>,
 }
 
-impl<R> TorClientBuilder<R> {
+impl<R: Runtime + fmt::Debug> fmt::Debug for TorClientBuilder<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TorClientBuilder")
+            .field("runtime", &self.runtime)
+            .field("config", &self.config)
+            .field("bootstrap_behavior", &self.bootstrap_behavior)
+            .field("shared_dirmgr", &self.dirmgr.is_some())
+            .finish()
+    }
+}
+
+impl<R: Runtime> TorClientBuilder<R> {
     /// Construct a new TorClientBuilder with the given runtime.
     pub(crate) fn new(runtime: R) -> Self {
         Self {
             runtime,
             config: TorClientConfig::default(),
             bootstrap_behavior: BootstrapBehavior::default(),
+            dirmgr: None,
         }
     }
 
@@ -46,9 +63,39 @@ impl<R> TorClientBuilder<R> {
         self.bootstrap_behavior = bootstrap_behavior;
         self
     }
-}
 
-impl<R: Runtime> TorClientBuilder<R> {
+    /// Make the `TorClient` under construction use `dirmgr` for its
+    /// directory information, instead of creating a directory manager of
+    /// its own.
+    ///
+    /// Use this when one process runs several separate `TorClient`s that
+    /// don't need to download and store a directory apiece: for example,
+    /// by passing [`TorClient::dirmgr`] from an existing client.
+    ///
+    /// The new client shares only its view of the network with the other
+    /// users of `dirmgr`.  It has its own channels, circuits, and circuit
+    /// manager, so its streams never share circuits with theirs.  It keeps
+    /// its own guards and other persistent state under the configured
+    /// `state_dir`; give each client a separate one if their guards should
+    /// be independent as well.  The directory configuration in this
+    /// client's [`TorClientConfig`] is ignored, except by
+    /// [`TorClient::reconfigure`], which changes `dirmgr` for everyone who
+    /// shares it.
+    ///
+    /// `dirmgr` keeps downloading directory documents through the circuit
+    /// manager of the client that created it, for as long as anybody uses
+    /// it: even if that first client is dropped, its circuit manager stays
+    /// alive.  That's also true if `dirmgr` hasn't been bootstrapped yet and
+    /// this client bootstraps it.
+    ///
+    /// This function is unstable. It is only enabled if the crate was
+    /// built with the `experimental-api` feature.
+    #[cfg(feature = "experimental-api")]
+    pub fn shared_dirmgr(mut self, dirmgr: Arc<DirMgr<R>>) -> Self {
+        self.dirmgr = Some(dirmgr);
+        self
+    }
+
     /// Create a `TorClient` from this builder, without automatically launching
     /// the bootstrap process.
     ///
@@ -65,8 +112,13 @@ impl<R: Runtime> TorClientBuilder<R> {
     /// process (for example, you might wish to avoid initiating network
     /// connections until explicit user confirmation is given).
     pub fn create_unbootstrapped(self) -> Result<TorClient<R>> {
-        TorClient::create_inner(self.runtime, self.config, self.bootstrap_behavior)
-            .map_err(ErrorDetail::into)
+        TorClient::create_inner(
+            self.runtime,
+            self.config,
+            self.bootstrap_behavior,
+            self.dirmgr,
+        )
+        .map_err(ErrorDetail::into)
     }
 
     /// Create a TorClient from this builder, and try to bootstrap it.
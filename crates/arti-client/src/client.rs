@@ -10,9 +10,11 @@ use crate::config::{ClientAddrConfig, StreamTimeoutConfig, TorClientConfig};
 use tor_circmgr::{DirInfo, IsolationToken, StreamIsolationBuilder, TargetPort};
 use tor_config::MutCfg;
 use tor_dirmgr::DirEvent;
+use tor_linkspec::ChanTarget;
+use tor_llcrypto::pk::{ed25519::Ed25519Identity, rsa::RsaIdentity};
 use tor_persist::{FsStateMgr, StateMgr};
-use tor_proto::circuit::ClientCirc;
-use tor_proto::stream::{DataStream, IpVersionPreference, StreamParameters};
+use tor_proto::circuit::{ClientCirc, UniqId};
+use tor_proto::stream::{DataStream, IpVersionPreference, StreamCounters, StreamParameters};
 use tor_rtcompat::{PreferredRuntime, Runtime, SleepProviderExt};
 
 use futures::lock::Mutex as AsyncMutex;
@@ -81,6 +83,10 @@ pub struct TorClient<R: Runtime> {
     /// bootstrapping. If this is `false`, we will just call `wait_for_bootstrap`
     /// instead.
     should_bootstrap: BootstrapBehavior,
+
+    /// Counters for the streams that this client (or any of its clones)
+    /// has opened.
+    stream_counters: Arc<StreamCounters>,
 }
 
 /// Preferences for whether a [`TorClient`] should bootstrap on its own or not.
@@ -114,6 +120,133 @@ pub struct StreamPrefs {
     isolation: StreamIsolationPreference,
     /// Whether to return the stream optimistically.
     optimistic_stream: bool,
+    /// The fewest hops that the stream's circuit may have.
+    ///
+    /// We only check circuits against this; it doesn't affect how we choose
+    /// their paths.
+    min_hops: usize,
+}
+
+/// Identifying information about a relay that a [`TorClient`] is using.
+///
+/// Relays in the microdescriptor consensus that we download don't have
+/// nicknames, so a relay is identified here only by its keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayInfo {
+    /// The relay's Ed25519 identity.
+    ed_identity: Ed25519Identity,
+    /// The relay's RSA identity (its "fingerprint").
+    rsa_identity: RsaIdentity,
+}
+
+impl RelayInfo {
+    /// Construct a `RelayInfo` for the relay described by `target`.
+    fn from_chan_target<T: ChanTarget + ?Sized>(target: &T) -> Self {
+        RelayInfo {
+            ed_identity: *target.ed_identity(),
+            rsa_identity: *target.rsa_identity(),
+        }
+    }
+
+    /// Return the Ed25519 identity of this relay.
+    pub fn ed_identity(&self) -> &Ed25519Identity {
+        &self.ed_identity
+    }
+
+    /// Return the RSA identity of this relay.
+    ///
+    /// This is the value that other Tor tools display as the relay's
+    /// fingerprint.
+    pub fn rsa_identity(&self) -> &RsaIdentity {
+        &self.rsa_identity
+    }
+}
+
+/// A handle to one of the circuits that a [`TorClient`] is holding.
+///
+/// Holding a `CircuitHandle` does not keep the circuit open: the circuit
+/// can close at any time, for example because of a network error or a
+/// call to [`TorClient::close_all_circuits`].
+#[derive(Clone, Debug)]
+pub struct CircuitHandle {
+    /// The underlying circuit.
+    circ: ClientCirc,
+}
+
+impl CircuitHandle {
+    /// Return an identifier for this circuit.
+    ///
+    /// Identifiers are unique within this process; a newly built circuit
+    /// never gets the identifier of one we had before.
+    pub fn id(&self) -> UniqId {
+        self.circ.unique_id()
+    }
+
+    /// Return the number of hops in this circuit.
+    pub fn n_hops(&self) -> usize {
+        self.circ.n_hops().into()
+    }
+
+    /// Return true if this circuit is closed or closing.
+    pub fn is_closing(&self) -> bool {
+        self.circ.is_closing()
+    }
+
+    /// Close this circuit, and every stream attached to it.
+    pub fn close(&self) {
+        self.circ.terminate();
+    }
+}
+
+/// Counters describing the activity of a [`TorClient`] so far, as
+/// returned by [`TorClient::stats`].
+///
+/// These counts cover the client and all of its clones, including those
+/// made with [`isolated_client`](TorClient::isolated_client).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    /// How many streams have we opened?
+    streams_opened: u64,
+    /// How many of those streams are still open?
+    open_streams: u64,
+    /// How many bytes have we written to our streams?
+    bytes_sent: u64,
+    /// How many bytes have we read from our streams?
+    bytes_received: u64,
+    /// How many circuits have we built?
+    circuits_built: u64,
+}
+
+impl ClientStats {
+    /// Return the number of streams that the client has opened.
+    pub fn streams_opened(&self) -> u64 {
+        self.streams_opened
+    }
+
+    /// Return the number of streams that are still open.
+    ///
+    /// A stream stays open until the application drops it (or, if it has
+    /// been split, until both halves are dropped).
+    pub fn open_streams(&self) -> u64 {
+        self.open_streams
+    }
+
+    /// Return the number of bytes that the application has written to the
+    /// client's streams.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Return the number of bytes that the application has read from the
+    /// client's streams.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Return the number of circuits that the client has built.
+    pub fn circuits_built(&self) -> u64 {
+        self.circuits_built
+    }
 }
 
 /// Record of how we are isolating connections
@@ -260,6 +393,20 @@ impl StreamPrefs {
         self
     }
 
+    /// Require that a stream go over a circuit with at least `n` hops.
+    ///
+    /// This is only a check: it doesn't change how Arti chooses paths, and
+    /// Arti won't build a longer circuit to satisfy it.  Every exit circuit
+    /// that Arti builds currently has three hops, so any value up to 3 is
+    /// always satisfied.  If you ask for more than that, connecting will
+    /// fail rather than give you a shorter circuit.
+    ///
+    /// By default there is no minimum.
+    pub fn min_hops(&mut self, n: usize) -> &mut Self {
+        self.min_hops = n;
+        self
+    }
+
     /// Return a token to describe which connections might use
     /// the same circuit as this one.
     fn isolation_group(&self) -> Option<IsolationToken> {
@@ -333,10 +480,14 @@ impl<R: Runtime> TorClient<R> {
 
     /// Implementation of `create_unbootstrapped`, split out in order to avoid manually specifying
     /// double error conversions.
+    ///
+    /// If `dirmgr` is provided, use it instead of creating a new directory
+    /// manager.
     pub(crate) fn create_inner(
         runtime: R,
         config: TorClientConfig,
         autobootstrap: BootstrapBehavior,
+        dirmgr: Option<Arc<tor_dirmgr::DirMgr<R>>>,
     ) -> StdResult<Self, ErrorDetail> {
         let circ_cfg = config.get_circmgr_config()?;
         let dir_cfg = config.get_dirmgr_config()?;
@@ -352,11 +503,14 @@ impl<R: Runtime> TorClient<R> {
         let circmgr =
             tor_circmgr::CircMgr::new(circ_cfg, statemgr.clone(), &runtime, Arc::clone(&chanmgr))
                 .map_err(ErrorDetail::CircMgrSetup)?;
-        let dirmgr = tor_dirmgr::DirMgr::create_unbootstrapped(
-            dir_cfg,
-            runtime.clone(),
-            Arc::clone(&circmgr),
-        )?;
+        let dirmgr = match dirmgr {
+            Some(dirmgr) => dirmgr,
+            None => tor_dirmgr::DirMgr::create_unbootstrapped(
+                dir_cfg,
+                runtime.clone(),
+                Arc::clone(&circmgr),
+            )?,
+        };
 
         let conn_status = chanmgr.bootstrap_events();
         let dir_status = dirmgr.bootstrap_events();
@@ -424,6 +578,7 @@ impl<R: Runtime> TorClient<R> {
             status_receiver,
             bootstrap_in_progress: Arc::new(AsyncMutex::new(())),
             should_bootstrap: autobootstrap,
+            stream_counters: Arc::new(StreamCounters::new()),
         })
     }
 
@@ -471,8 +626,24 @@ impl<R: Runtime> TorClient<R> {
 
         self.dirmgr.bootstrap().await?;
 
-        self.circmgr
-            .update_network_parameters(self.dirmgr.netdir()?.params());
+        // If we share our directory manager with another client, it may
+        // have bootstrapped before we were watching for its events: tell
+        // our circuit manager about the directory we have now.
+        let netdir = self.dirmgr.netdir()?;
+        self.circmgr.update_network_parameters(netdir.params());
+        self.circmgr.update_network(&netdir);
+
+        // If we've been asked to, build some circuits now so that the first
+        // request doesn't have to wait for one.  Failing to do so isn't fatal.
+        match self
+            .circmgr
+            .build_warm_circuits(DirInfo::Directory(&netdir))
+            .await
+        {
+            Ok(0) => {}
+            Ok(n) => info!("Built {} circuits in advance.", n),
+            Err(e) => warn!("Unable to build circuits in advance: {}", e),
+        }
 
         // Since we succeeded, disarm the unlock guard.
         unlock_guard.disarm();
@@ -511,7 +682,9 @@ impl<R: Runtime> TorClient<R> {
     ///
     /// This function applies its changes to **all** TorClient instances derived
     /// from the same call to `TorClient::create_*`: even ones whose circuits
-    /// are isolated from this handle.
+    /// are isolated from this handle.  If this client was built with a
+    /// shared directory manager (see `TorClientBuilder::shared_dirmgr`), the
+    /// directory configuration changes for every client that shares it.
     ///
     /// # Limitations
     ///
@@ -678,18 +851,48 @@ impl<R: Runtime> TorClient<R> {
             .map_err(wrap_err)?;
         info!("Got a circuit for {}:{}", addr, port);
 
-        let stream_future = circ.begin_stream(&addr, port, Some(prefs.stream_parameters()));
+        self.begin_stream_on(&circ, &addr, port, prefs).await
+    }
+
+    /// Open a stream to `addr`:`port` on `circ`, and count it in our
+    /// stats.
+    async fn begin_stream_on(
+        &self,
+        circ: &ClientCirc,
+        addr: &str,
+        port: u16,
+        prefs: &StreamPrefs,
+    ) -> crate::Result<DataStream> {
+        let stream_future = circ.begin_stream(addr, port, Some(prefs.stream_parameters()));
         // This timeout is needless but harmless for optimistic streams.
-        let stream = self
+        let mut stream = self
             .runtime
             .timeout(self.timeoutcfg.get().connect_timeout, stream_future)
             .await
             .map_err(|_| ErrorDetail::ExitTimeout)?
             .map_err(wrap_err)?;
+        stream.attach_counters(&self.stream_counters);
 
         Ok(stream)
     }
 
+    /// Return true if our current directory would let us build a circuit
+    /// for connecting to `port` with the preferences in `prefs`.
+    ///
+    /// This doesn't build anything: it only checks whether any relays fit
+    /// what we'd need, so that callers can tell in advance when a request
+    /// is impossible.  It returns false if we have no directory yet.
+    pub fn can_build_exit(&self, port: u16, prefs: &StreamPrefs) -> bool {
+        match self.dirmgr.opt_netdir() {
+            Some(dir) => self.circmgr.can_build_exit(
+                dir.as_ref().into(),
+                &[prefs.wrap_target_port(port)],
+                prefs.min_hops,
+            ),
+            None => false,
+        }
+    }
+
     /// Sets the default preferences for future connections made with this client.
     ///
     /// The preferences set with this function will be inherited by clones of this client, but
@@ -792,6 +995,76 @@ impl<R: Runtime> TorClient<R> {
         Arc::clone(&self.circmgr)
     }
 
+    /// Return the guard relays that this client currently uses as the first
+    /// hop of its circuits, from most to least preferred.
+    ///
+    /// The list is empty until this client has bootstrapped.  It can change
+    /// over time, as guards become unusable or leave the network.
+    pub fn current_guards(&self) -> Vec<RelayInfo> {
+        self.circmgr
+            .primary_guards()
+            .iter()
+            .map(RelayInfo::from_chan_target)
+            .collect()
+    }
+
+    /// Return a handle to each circuit that this client might currently use
+    /// for new streams.
+    ///
+    /// Circuits that are no longer handed out for new streams are not
+    /// listed, even if some existing stream is still using them.
+    pub fn circuits(&self) -> Vec<CircuitHandle> {
+        self.circmgr
+            .circuits()
+            .into_iter()
+            .map(|circ| CircuitHandle { circ })
+            .collect()
+    }
+
+    /// Close every circuit that this client is holding.
+    ///
+    /// Streams that are using those circuits will fail.  New streams will
+    /// be made on freshly built circuits, so that nothing done from now on
+    /// shares a circuit with anything done before: this is what a "new
+    /// identity" button wants.
+    ///
+    /// Clients made with [`isolated_client`](TorClient::isolated_client)
+    /// share their circuits with this one, so their circuits are closed too.
+    pub fn close_all_circuits(&self) {
+        self.circmgr.close_all_circuits();
+    }
+
+    /// Stop using every circuit that this client has built so far for new
+    /// streams, so that new streams are made on freshly built circuits.
+    ///
+    /// This is like Tor's `NEWNYM` signal.  Unlike
+    /// [`close_all_circuits`](TorClient::close_all_circuits), it lets
+    /// streams that are already open keep working until they are done;
+    /// their circuits are closed once nothing is using them.  Any connection
+    /// attempt that is still waiting for a circuit to be built may fail.
+    ///
+    /// This does not change which guards we use: as in Tor, rotating
+    /// guards more often would make it easier for an attacker to become
+    /// one of them.
+    ///
+    /// Clients made with [`isolated_client`](TorClient::isolated_client)
+    /// share their circuits with this one, so they get new circuits too.
+    pub fn new_identity(&self) {
+        self.circmgr.retire_all_circuits();
+    }
+
+    /// Return counters describing the streams and circuits that this client
+    /// has used so far.
+    pub fn stats(&self) -> ClientStats {
+        ClientStats {
+            streams_opened: self.stream_counters.n_opened(),
+            open_streams: self.stream_counters.n_open(),
+            bytes_sent: self.stream_counters.bytes_sent(),
+            bytes_received: self.stream_counters.bytes_received(),
+            circuits_built: self.circmgr.n_circuits_built(),
+        }
+    }
+
     /// Return a reference to the runtime being used by this client.
     //
     // This API is not a hostage to fortune since we already require that R: Clone,
@@ -832,7 +1105,12 @@ impl<R: Runtime> TorClient<R> {
 
         let circ = self
             .circmgr
-            .get_or_launch_exit(dir.as_ref().into(), exit_ports, isolation)
+            .get_or_launch_exit_with_min_hops(
+                dir.as_ref().into(),
+                exit_ports,
+                isolation,
+                prefs.min_hops,
+            )
             .await
             .map_err(|cause| ErrorDetail::ObtainExitCircuit {
                 cause,
@@ -1100,4 +1378,208 @@ mod test {
             assert_eq!(result.err().unwrap().kind(), ErrorKind::BootstrapRequired);
         });
     }
+
+    #[test]
+    fn can_build_exit() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+
+            // Without a directory, we can't build anything.
+            assert!(!client.can_build_exit(80, &StreamPrefs::new()));
+
+            // In the test network, every exit allows IPv4 port 80, and none
+            // allows any IPv6 ports.
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            let circmgr = &client.circmgr;
+            assert!(circmgr.can_build_exit((&netdir).into(), &[TargetPort::ipv4(80)], 0));
+            assert!(!circmgr.can_build_exit((&netdir).into(), &[TargetPort::ipv6(80)], 0));
+            // We never build exit circuits with more than three hops.
+            assert!(circmgr.can_build_exit((&netdir).into(), &[TargetPort::ipv4(80)], 3));
+            assert!(!circmgr.can_build_exit((&netdir).into(), &[TargetPort::ipv4(80)], 4));
+        });
+    }
+
+    #[test]
+    fn stats_start_empty() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+            assert_eq!(client.stats(), ClientStats::default());
+
+            // A stream that we couldn't open doesn't count.
+            assert!(client.connect("example.com:80").await.is_err());
+            assert_eq!(client.stats(), ClientStats::default());
+
+            // Isolated clients share their counters with the original.
+            let isolated = client.isolated_client();
+            assert!(Arc::ptr_eq(
+                &client.stream_counters,
+                &isolated.stream_counters
+            ));
+        });
+    }
+
+    #[test]
+    fn stats_count_activity() {
+        // Streams that we open and use show up in our stats.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use futures::{AsyncReadExt, AsyncWriteExt};
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let client = TorClient::with_runtime(rt.clone())
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+
+            // This circuit's exit echoes back whatever we send it.
+            let circ = tor_proto::circuit::testing::fake_echo_circuit(&rt)
+                .await
+                .unwrap();
+            let prefs = StreamPrefs::default();
+            let mut stream = client
+                .begin_stream_on(&circ, "example.com", 80, &prefs)
+                .await
+                .unwrap();
+            assert_eq!(client.stats().streams_opened(), 1);
+            assert_eq!(client.stats().open_streams(), 1);
+
+            stream.write_all(b"hello world").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut buf = [0_u8; 11];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello world");
+            let stats = client.stats();
+            assert_eq!(stats.bytes_sent(), 11);
+            assert_eq!(stats.bytes_received(), 11);
+
+            // Once we drop the stream, it's no longer open, but we still
+            // remember that we opened it.
+            drop(stream);
+            let stats = client.stats();
+            assert_eq!(stats.streams_opened(), 1);
+            assert_eq!(stats.open_streams(), 0);
+
+            // An isolated client sees the same counts.
+            assert_eq!(client.isolated_client().stats(), stats);
+        });
+    }
+
+    #[test]
+    fn current_guards() {
+        // We have no guards until we've bootstrapped; after that, we report
+        // the primary guards that our circuit manager picked.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+            assert!(client.current_guards().is_empty());
+
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            client.dirmgr.install_netdir_for_testing(netdir);
+            client.bootstrap().await.unwrap();
+
+            let guards = client.current_guards();
+            assert!(!guards.is_empty());
+            let ids: Vec<RsaIdentity> = guards.iter().map(|g| *g.rsa_identity()).collect();
+            let expected: Vec<RsaIdentity> = client
+                .circmgr
+                .primary_guards()
+                .iter()
+                .map(|g| *g.rsa_identity())
+                .collect();
+            assert_eq!(ids, expected);
+
+            // Every guard is a relay from our directory.
+            let netdir = client.dirmgr.netdir().unwrap();
+            for guard in &guards {
+                assert!(netdir.by_id(guard.ed_identity()).is_some());
+            }
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "experimental-api")]
+    fn shared_dirmgr() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let cache_dir = tempfile::tempdir().unwrap();
+            let make_client = |dirmgr: Option<Arc<tor_dirmgr::DirMgr<_>>>| {
+                let state_dir = tempfile::tempdir().unwrap();
+                let cfg = TorClientConfigBuilder::from_directories(&state_dir, &cache_dir)
+                    .build()
+                    .unwrap();
+                let mut builder = TorClient::with_runtime(rt.clone())
+                    .config(cfg)
+                    .bootstrap_behavior(BootstrapBehavior::Manual);
+                if let Some(dirmgr) = dirmgr {
+                    builder = builder.shared_dirmgr(dirmgr);
+                }
+                (state_dir, builder.create_unbootstrapped().unwrap())
+            };
+            let (_dir1, client1) = make_client(None);
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            let valid_after = netdir.lifetime().valid_after();
+            client1.dirmgr.install_netdir_for_testing(netdir);
+
+            // This client only starts watching the directory manager after
+            // it has its directory...
+            let (_dir2, client2) = make_client(Some(Arc::clone(&client1.dirmgr)));
+            assert!(client2.circmgr.primary_guards().is_empty());
+
+            // ... but it still learns about it when it bootstraps.
+            client2.bootstrap().await.unwrap();
+            assert!(!client2.circmgr.primary_guards().is_empty());
+
+            // Both clients see the same directory...
+            assert!(Arc::ptr_eq(&client1.dirmgr, &client2.dirmgr));
+            let valid_after_for =
+                |client: &TorClient<_>| client.dirmgr.netdir().unwrap().lifetime().valid_after();
+            assert_eq!(valid_after_for(&client1), valid_after);
+            assert_eq!(valid_after_for(&client2), valid_after);
+
+            // ... but build their circuits independently.
+            assert!(!Arc::ptr_eq(&client1.circmgr, &client2.circmgr));
+            assert_ne!(client1.statemgr.path(), client2.statemgr.path());
+
+            // An unrelated client gets a directory manager of its own.
+            let (_dir3, client3) = make_client(None);
+            assert!(!Arc::ptr_eq(&client1.dirmgr, &client3.dirmgr));
+        });
+    }
 }
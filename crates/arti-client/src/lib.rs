@@ -86,6 +86,37 @@
 //! # }
 //! ```
 //!
+//! ## Sharing directory information between clients
+//!
+//! If you run several separate clients in one process, they can share a single directory
+//! manager instead of each downloading and storing the directory for itself: pass
+//! `TorClient::dirmgr` from one client to `TorClientBuilder::shared_dirmgr` when building the
+//! others (both are available with the `experimental-api` feature).  The clients see the same
+//! view of the network, but keep their own circuits.  The shared manager keeps downloading
+//! through the first client's circuit manager, which stays alive even after that client is
+//! dropped.
+//!
+//! ```no_run
+//! # use anyhow::Result;
+//! # use arti_client::{TorClient, TorClientConfig};
+//! # use tokio_crate as tokio;
+//! # #[cfg(not(feature = "experimental-api"))]
+//! # fn main() {}
+//! # #[cfg(feature = "experimental-api")]
+//! # #[tokio::main]
+//! # async fn main() -> Result<()> {
+//! # let config = TorClientConfig::default();
+//! # let other_config = TorClientConfig::default();
+//! let first_client = TorClient::create_bootstrapped(config).await?;
+//! let second_client = TorClient::builder()
+//!     .config(other_config)
+//!     .shared_dirmgr(first_client.dirmgr())
+//!     .create_bootstrapped()
+//!     .await?;
+//! #    Ok(())
+//! # }
+//! ```
+//!
 //! ## Using the client
 //!
 //! A client can then be used to make connections over Tor with
@@ -225,11 +256,14 @@ pub mod status;
 
 pub use address::{DangerouslyIntoTorAddr, IntoTorAddr, TorAddr, TorAddrError};
 pub use builder::TorClientBuilder;
-pub use client::{BootstrapBehavior, StreamPrefs, TorClient};
+pub use client::{
+    BootstrapBehavior, CircuitHandle, ClientStats, RelayInfo, StreamPrefs, TorClient,
+};
 pub use config::TorClientConfig;
 
 pub use tor_circmgr::IsolationToken;
 pub use tor_error::{ErrorKind, HasKind};
+pub use tor_proto::circuit::UniqId;
 pub use tor_proto::stream::{DataReader, DataStream, DataWriter};
 
 mod err;
@@ -77,6 +77,22 @@ impl BootstrapStatus {
         }
     }
 
+    /// Return a description of this status in the format that C Tor uses for
+    /// bootstrap events on its control port.
+    ///
+    /// The result looks like `BOOTSTRAP PROGRESS=100 TAG=done
+    /// SUMMARY="Done"`.  We use C Tor's progress numbers and tags, so that
+    /// tools written to watch C Tor bootstrap can follow our progress too.
+    pub fn to_control_status_line(&self) -> String {
+        if self.ready_for_traffic() {
+            "BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"".into()
+        } else if !self.conn_status.usable() {
+            "BOOTSTRAP PROGRESS=5 TAG=conn SUMMARY=\"Connecting to a relay\"".into()
+        } else {
+            self.dir_status.to_control_status_line(SystemTime::now())
+        }
+    }
+
     /// Adjust this status based on new connection-status information.
     fn apply_conn_status(&mut self, status: ConnStatus) {
         self.conn_status = status;
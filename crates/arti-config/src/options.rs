@@ -638,7 +638,9 @@ mod test {
             .state_dir(CfgPath::new("/var/tmp/bar".to_owned()));
         bld.download_schedule()
             .retry_certs(DownloadSchedule::new(10, sec, 3))
-            .retry_microdescs(DownloadSchedule::new(30, 10 * sec, 9));
+            .retry_microdescs(DownloadSchedule::new(30, 10 * sec, 9))
+            .max_download_rate(65536)
+            .consensus_timeout(10 * sec);
         bld.override_net_params()
             .insert("wombats-per-quokka".to_owned(), 7);
         bld.path_rules()
@@ -648,12 +650,19 @@ mod test {
             .disable_at_threshold(12)
             .initial_predicted_ports(vec![80, 443])
             .prediction_lifetime(Duration::from_secs(3600))
-            .min_exit_circs_for_port(2);
+            .min_exit_circs_for_port(2)
+            .warm_circuits_at_bootstrap(0);
         bld.circuit_timing()
             .max_dirtiness(90 * sec)
             .request_timeout(10 * sec)
             .request_max_retries(22)
-            .request_loyalty(3600 * sec);
+            .request_loyalty(3600 * sec)
+            .max_concurrent_dir_builds(4)
+            .max_guard_failures(5)
+            .build_rate_burst(8)
+            .build_rate_interval(2 * sec)
+            .max_circs_per_usage(16)
+            .max_circs(64);
         bld.address_filter().allow_local_addrs(true);
 
         let val = bld.build().unwrap();
@@ -3,7 +3,7 @@
 use std::collections::HashSet;
 use std::convert::TryInto;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel as std_channel;
+use std::sync::mpsc::{channel as std_channel, Receiver};
 use std::time::Duration;
 
 use arti_client::config::Reconfigure;
@@ -25,53 +25,176 @@ pub(crate) fn watch_for_config_changes<R: Runtime>(
     original: ArtiConfig,
     client: TorClient<R>,
 ) -> anyhow::Result<()> {
-    let (tx, rx) = std_channel();
-    let mut watcher = FileWatcher::new(tx, POLL_INTERVAL)?;
-
-    for file in sources.files() {
-        watcher.watch_file(file)?;
-    }
-
-    std::thread::spawn(move || {
-        // TODO: If someday we make this facility available outside of the
-        // `arti` application, we probably don't want to have this thread own
-        // the FileWatcher.
-        debug!("Waiting for FS events");
-        while let Ok(event) = rx.recv() {
-            if !watcher.event_matched(&event) {
-                // NOTE: Sadly, it's not safe to log in this case.  If the user
-                // has put a configuration file and a logfile in the same
-                // directory, logging about discarded events will make us log
-                // every time we log, and fill up the filesystem.
-                continue;
+    let mut watcher = ConfigWatcher::new()?;
+    watcher.watch_client(sources, original, client)?;
+    watcher.start();
+    Ok(())
+}
+
+/// A set of configuration watches that share a single [`FileWatcher`].
+///
+/// Each watch set has its own list of files and its own client to
+/// reconfigure.  When a file changes, only the watch sets that contain that
+/// file are reloaded, so a single process managing several `TorClient`s can
+/// watch all of their configurations with one background thread.
+pub(crate) struct ConfigWatcher {
+    /// The underlying watcher that tells us about filesystem changes.
+    watcher: FileWatcher,
+    /// The channel on which `watcher` delivers its events.
+    rx: Receiver<notify::DebouncedEvent>,
+    /// The watch sets that we route events to.
+    sets: WatchSets,
+}
+
+impl ConfigWatcher {
+    /// Create a new `ConfigWatcher` with no watch sets.
+    pub(crate) fn new() -> anyhow::Result<Self> {
+        let (tx, rx) = std_channel();
+        let watcher = FileWatcher::new(tx, POLL_INTERVAL)?;
+        Ok(ConfigWatcher {
+            watcher,
+            rx,
+            sets: WatchSets::default(),
+        })
+    }
+
+    /// Add a watch set for the files in `sources`.
+    ///
+    /// Whenever one or more of those files changes, try to reload our
+    /// configuration from them and tell `client` about it.
+    pub(crate) fn watch_client<R: Runtime>(
+        &mut self,
+        sources: arti_config::ConfigurationSources,
+        original: ArtiConfig,
+        client: TorClient<R>,
+    ) -> anyhow::Result<()> {
+        let files: Vec<PathBuf> = sources.files().map(Into::into).collect();
+        self.watch_files(
+            files,
+            Box::new(move || reconfigure(&sources, &original, &client)),
+        )
+    }
+
+    /// Add a watch set that calls `reload` whenever one of `files` changes.
+    fn watch_files<I, P>(&mut self, files: I, reload: ReloadFn) -> anyhow::Result<()>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut watched = HashSet::new();
+        for file in files {
+            watched.insert(self.watcher.watch_file(file)?);
+        }
+        self.sets.add(watched, reload);
+        Ok(())
+    }
+
+    /// Launch a thread to watch every file in every watch set.
+    ///
+    /// The thread exits once every watch set has asked to stop watching.
+    pub(crate) fn start(self) {
+        let ConfigWatcher {
+            watcher,
+            rx,
+            mut sets,
+        } = self;
+
+        std::thread::spawn(move || {
+            // TODO: If someday we make this facility available outside of the
+            // `arti` application, we probably don't want to have this thread own
+            // the FileWatcher.
+            debug!("Waiting for FS events");
+            while let Ok(event) = rx.recv() {
+                if !watcher.event_matched(&event) {
+                    // NOTE: Sadly, it's not safe to log in this case.  If the user
+                    // has put a configuration file and a logfile in the same
+                    // directory, logging about discarded events will make us log
+                    // every time we log, and fill up the filesystem.
+                    continue;
+                }
+                let mut events = vec![event];
+                while let Ok(other) = rx.try_recv() {
+                    // Collect other pending events, so that we only reload each
+                    // watch set once.
+                    //
+                    // We can afford to treat both error cases from try_recv [Empty
+                    // and Disconnected] as meaning that we've seen all the other
+                    // events: if we're disconnected, we'll notice it when we next
+                    // call recv() in the outer loop.
+                    events.push(other);
+                }
+                debug!("FS events {:?}: reloading configuration.", events);
+                sets.dispatch(&events);
+                if sets.is_empty() {
+                    break;
+                }
             }
-            while let Ok(_ignore) = rx.try_recv() {
-                // Discard other events, so that we only reload once.
-                //
-                // We can afford to treat both error cases from try_recv [Empty
-                // and Disconnected] as meaning that we've discarded other
-                // events: if we're disconnected, we'll notice it when we next
-                // call recv() in the outer loop.
+            debug!("Thread exiting");
+        });
+
+        // Dropping the thread handle here means that we don't get any special
+        // notification about a panic.  TODO: We should change that at some point in
+        // the future.
+    }
+}
+
+/// A function to reload the configuration for a single watch set.
+///
+/// Returns true if we should stop watching the files in that set.
+type ReloadFn = Box<dyn FnMut() -> anyhow::Result<bool> + Send>;
+
+/// A list of files, and what to do when any of them changes.
+struct WatchSet {
+    /// The absolute paths of the files in this set.
+    files: HashSet<PathBuf>,
+    /// What to do when one of `files` changes.
+    reload: ReloadFn,
+}
+
+/// A collection of [`WatchSet`]s, used to route filesystem events to the sets
+/// that care about them.
+#[derive(Default)]
+struct WatchSets {
+    /// The sets that we're still watching.
+    sets: Vec<WatchSet>,
+}
+
+impl WatchSets {
+    /// Add a new watch set to this collection.
+    fn add(&mut self, files: HashSet<PathBuf>, reload: ReloadFn) {
+        self.sets.push(WatchSet { files, reload });
+    }
+
+    /// Return true if there are no watch sets left.
+    fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+
+    /// Reload every watch set affected by any of `events`, at most once each.
+    ///
+    /// Any watch set whose reload function asks to stop watching is removed.
+    fn dispatch(&mut self, events: &[notify::DebouncedEvent]) {
+        let mut remaining = Vec::with_capacity(self.sets.len());
+        for mut set in self.sets.drain(..) {
+            if !events.iter().any(|ev| event_affects(ev, &set.files)) {
+                remaining.push(set);
+                continue;
             }
-            debug!("FS event {:?}: reloading configuration.", event);
-            match reconfigure(&sources, &original, &client) {
+            match (set.reload)() {
                 Ok(exit) => {
                     info!("Successfully reloaded configuration.");
-                    if exit {
-                        break;
+                    if !exit {
+                        remaining.push(set);
                     }
                 }
-                Err(e) => warn!("Couldn't reload configuration: {}", e),
+                Err(e) => {
+                    warn!("Couldn't reload configuration: {}", e);
+                    remaining.push(set);
+                }
             }
         }
-        debug!("Thread exiting");
-    });
-
-    // Dropping the thread handle here means that we don't get any special
-    // notification about a panic.  TODO: We should change that at some point in
-    // the future.
-
-    Ok(())
+        self.sets = remaining;
+    }
 }
 
 /// Reload the configuration files, apply the runtime configuration, and
@@ -144,7 +267,9 @@ impl FileWatcher {
     }
 
     /// Watch a single file (not a directory).  Does nothing if we're already watching that file.
-    fn watch_file<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+    ///
+    /// Return the absolute path of the file, as it will appear in events.
+    fn watch_file<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<PathBuf> {
         // Make the path absolute (without necessarily making it canonical).
         //
         // We do this because `notify` reports all of its events in terms of
@@ -175,30 +300,108 @@ impl FileWatcher {
 
         // Note this file as one that we're watching, so that we can see changes
         // to it later on.
-        self.watching_files.insert(path);
+        self.watching_files.insert(path.clone());
 
-        Ok(())
+        Ok(path)
     }
 
     /// Return true if the provided event describes a change affecting one of
     /// the files that we care about.
     fn event_matched(&self, event: &notify::DebouncedEvent) -> bool {
-        let watching = |f| self.watching_files.contains(f);
-
-        match event {
-            notify::DebouncedEvent::NoticeWrite(f) => watching(f),
-            notify::DebouncedEvent::NoticeRemove(f) => watching(f),
-            notify::DebouncedEvent::Create(f) => watching(f),
-            notify::DebouncedEvent::Write(f) => watching(f),
-            notify::DebouncedEvent::Chmod(f) => watching(f),
-            notify::DebouncedEvent::Remove(f) => watching(f),
-            notify::DebouncedEvent::Rename(f1, f2) => watching(f1) || watching(f2),
-            notify::DebouncedEvent::Rescan => {
-                // We've missed some events: no choice but to reload.
-                true
-            }
-            notify::DebouncedEvent::Error(_, Some(f)) => watching(f),
-            notify::DebouncedEvent::Error(_, _) => false,
+        event_affects(event, &self.watching_files)
+    }
+}
+
+/// Return true if the provided event describes a change affecting one of the
+/// files in `files`.
+fn event_affects(event: &notify::DebouncedEvent, files: &HashSet<PathBuf>) -> bool {
+    let watching = |f| files.contains(f);
+
+    match event {
+        notify::DebouncedEvent::NoticeWrite(f) => watching(f),
+        notify::DebouncedEvent::NoticeRemove(f) => watching(f),
+        notify::DebouncedEvent::Create(f) => watching(f),
+        notify::DebouncedEvent::Write(f) => watching(f),
+        notify::DebouncedEvent::Chmod(f) => watching(f),
+        notify::DebouncedEvent::Remove(f) => watching(f),
+        notify::DebouncedEvent::Rename(f1, f2) => watching(f1) || watching(f2),
+        notify::DebouncedEvent::Rescan => {
+            // We've missed some events: no choice but to reload.
+            true
         }
+        notify::DebouncedEvent::Error(_, Some(f)) => watching(f),
+        notify::DebouncedEvent::Error(_, _) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use notify::DebouncedEvent;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Add a watch set for `file` to `sets`, and return a counter of how many
+    /// times it has been reloaded.
+    fn counting_set(sets: &mut WatchSets, file: &Path) -> Arc<AtomicUsize> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count2 = Arc::clone(&count);
+        sets.add(
+            vec![file.to_path_buf()].into_iter().collect(),
+            Box::new(move || {
+                count2.fetch_add(1, Ordering::SeqCst);
+                Ok(false)
+            }),
+        );
+        count
+    }
+
+    #[test]
+    fn dispatch_to_matching_set() {
+        let file_a = Path::new("/arti-test/tenant-a/arti.toml");
+        let file_b = Path::new("/arti-test/tenant-b/arti.toml");
+        let mut sets = WatchSets::default();
+        let count_a = counting_set(&mut sets, file_a);
+        let count_b = counting_set(&mut sets, file_b);
+
+        // Editing a's file reconfigures only a, even if we see it twice.
+        sets.dispatch(&[
+            DebouncedEvent::Write(file_a.into()),
+            DebouncedEvent::Chmod(file_a.into()),
+        ]);
+        assert_eq!(count_a.load(Ordering::SeqCst), 1);
+        assert_eq!(count_b.load(Ordering::SeqCst), 0);
+
+        // Editing b's file reconfigures only b.
+        sets.dispatch(&[DebouncedEvent::Rename(
+            "/arti-test/tenant-b/arti.toml.tmp".into(),
+            file_b.into(),
+        )]);
+        assert_eq!(count_a.load(Ordering::SeqCst), 1);
+        assert_eq!(count_b.load(Ordering::SeqCst), 1);
+
+        // Unrelated files reconfigure nobody.
+        sets.dispatch(&[DebouncedEvent::Write("/arti-test/tenant-a/log".into())]);
+        assert_eq!(count_a.load(Ordering::SeqCst), 1);
+        assert_eq!(count_b.load(Ordering::SeqCst), 1);
+
+        // A rescan means we might have missed anything, so everybody reloads.
+        sets.dispatch(&[DebouncedEvent::Rescan]);
+        assert_eq!(count_a.load(Ordering::SeqCst), 2);
+        assert_eq!(count_b.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn dispatch_stops_watching() {
+        let file_a = Path::new("/arti-test/tenant-a/arti.toml");
+        let mut sets = WatchSets::default();
+        sets.add(
+            vec![file_a.to_path_buf()].into_iter().collect(),
+            Box::new(|| Ok(true)),
+        );
+        assert!(!sets.is_empty());
+        sets.dispatch(&[DebouncedEvent::Write(file_a.into())]);
+        assert!(sets.is_empty());
     }
 }
@@ -1,7 +1,9 @@
 //! Facilities to build circuits directly, instead of via a circuit manager.
 
-use crate::path::{OwnedPath, TorPath};
+use crate::latency::RelayLatencies;
+use crate::path::{DiversityPolicy, HopFilter, OwnedPath, TorPath};
 use crate::timeouts::{self, Action};
+use crate::usage::PathRules;
 use crate::{Error, Result};
 use async_trait::async_trait;
 use futures::channel::oneshot;
@@ -9,8 +11,8 @@ use futures::task::SpawnExt;
 use futures::Future;
 use std::convert::TryInto;
 use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+    atomic::{AtomicU32, AtomicU64, Ordering},
+    Arc, Mutex,
 };
 use std::time::{Duration, Instant};
 use tor_chanmgr::ChanMgr;
@@ -18,6 +20,7 @@ use tor_guardmgr::GuardStatus;
 use tor_linkspec::{ChanTarget, OwnedChanTarget, OwnedCircTarget};
 use tor_proto::circuit::{CircParameters, ClientCirc, PendingClientCirc};
 use tor_rtcompat::{Runtime, SleepProviderExt};
+use tracing::debug;
 
 mod guardstatus;
 
@@ -121,6 +124,36 @@ impl Buildable for ClientCirc {
     }
 }
 
+/// The kind of circuit that we were trying to build, as reported to a
+/// [`BuildMetrics`] sink.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum BuildUsage {
+    /// A one-hop circuit for talking to a directory cache.
+    Dir,
+    /// A multi-hop circuit for exiting to the internet.
+    Exit,
+    /// A circuit that we built only to learn how long circuits take.
+    TimeoutTesting,
+    /// An exit circuit that we built in advance of needing it.
+    Preemptive,
+}
+
+/// A receiver for measurements about the circuits that a [`CircuitBuilder`]
+/// builds.
+///
+/// Implement this to send those measurements to a metrics system of your
+/// choice, and install it with
+/// [`CircMgr::set_build_metrics`](crate::CircMgr::set_build_metrics).
+pub trait BuildMetrics: Send + Sync {
+    /// Record that an attempt to build a circuit for `usage` finished after
+    /// `duration`, and whether it `success`fully built the circuit.
+    ///
+    /// This is called from inside the circuit manager, so it should return
+    /// quickly.
+    fn record_build_outcome(&self, usage: BuildUsage, duration: Duration, success: bool);
+}
+
 /// An implementation type for [`CircuitBuilder`].
 ///
 /// A `CircuitBuilder` holds references to all the objects that are needed
@@ -135,6 +168,14 @@ struct Builder<R: Runtime, C: Buildable + Sync + Send + 'static> {
     chanmgr: Arc<ChanMgr<R>>,
     /// An estimator to determine the correct timeouts for circuit building.
     timeouts: timeouts::Estimator,
+    /// If present, a sink to tell about how each circuit build turns out.
+    metrics: Mutex<Option<Arc<dyn BuildMetrics>>>,
+    /// How many circuits have we successfully built for the circuit
+    /// manager?
+    n_built: AtomicU64,
+    /// A table of how long each relay took to answer when we extended
+    /// circuits to it.
+    latencies: Arc<RelayLatencies>,
     /// We don't actually hold any clientcircs, so we need to put this
     /// type here so the compiler won't freak out.
     _phantom: std::marker::PhantomData<C>,
@@ -142,15 +183,28 @@ struct Builder<R: Runtime, C: Buildable + Sync + Send + 'static> {
 
 impl<R: Runtime, C: Buildable + Sync + Send + 'static> Builder<R, C> {
     /// Construct a new [`Builder`].
-    fn new(runtime: R, chanmgr: Arc<ChanMgr<R>>, timeouts: timeouts::Estimator) -> Self {
+    fn new(
+        runtime: R,
+        chanmgr: Arc<ChanMgr<R>>,
+        timeouts: timeouts::Estimator,
+        latencies: RelayLatencies,
+    ) -> Self {
         Builder {
             runtime,
             chanmgr,
             timeouts,
+            metrics: Mutex::new(None),
+            n_built: AtomicU64::new(0),
+            latencies: Arc::new(latencies),
             _phantom: std::marker::PhantomData,
         }
     }
 
+    /// Replace the sink that we tell about how our circuit builds turn out.
+    fn set_metrics(&self, metrics: Option<Arc<dyn BuildMetrics>>) {
+        *self.metrics.lock().expect("poisoned lock") = metrics;
+    }
+
     /// Build a circuit, without performing any timeout operations.
     ///
     /// After each hop is built, increments n_hops_built.  Make sure that
@@ -171,8 +225,9 @@ impl<R: Runtime, C: Buildable + Sync + Send + 'static> Builder<R, C> {
             OwnedPath::ChannelOnly(target) => {
                 // If we fail now, it's the guard's fault.
                 guard_status.pending(GuardStatus::Failure);
-                let circ =
-                    C::create_chantarget(&self.chanmgr, &self.runtime, &target, &params).await?;
+                let circ = C::create_chantarget(&self.chanmgr, &self.runtime, &target, &params)
+                    .await
+                    .map_err(|e| Error::at_hop(0, &target, e))?;
                 self.timeouts
                     .note_hop_completed(0, self.runtime.now() - start_time, true);
                 n_hops_built.fetch_add(1, Ordering::SeqCst);
@@ -183,7 +238,9 @@ impl<R: Runtime, C: Buildable + Sync + Send + 'static> Builder<R, C> {
                 let n_hops = p.len() as u8;
                 // If we fail now, it's the guard's fault.
                 guard_status.pending(GuardStatus::Failure);
-                let circ = C::create(&self.chanmgr, &self.runtime, &p[0], &params).await?;
+                let circ = C::create(&self.chanmgr, &self.runtime, &p[0], &params)
+                    .await
+                    .map_err(|e| Error::at_hop(0, &p[0], e))?;
                 self.timeouts
                     .note_hop_completed(0, self.runtime.now() - start_time, n_hops == 0);
                 // If we fail after this point, we can't tell whether it's
@@ -192,11 +249,17 @@ impl<R: Runtime, C: Buildable + Sync + Send + 'static> Builder<R, C> {
                 n_hops_built.fetch_add(1, Ordering::SeqCst);
                 let mut hop_num = 1;
                 for relay in p[1..].iter() {
-                    circ.extend(&self.runtime, relay, &params).await?;
+                    let extend_start = self.runtime.now();
+                    circ.extend(&self.runtime, relay, &params)
+                        .await
+                        .map_err(|e| Error::at_hop(hop_num.into(), relay, e))?;
                     n_hops_built.fetch_add(1, Ordering::SeqCst);
+                    let now = self.runtime.now();
+                    self.latencies
+                        .note_latency(relay.rsa_identity(), now - extend_start);
                     self.timeouts.note_hop_completed(
                         hop_num,
-                        self.runtime.now() - start_time,
+                        now - start_time,
                         hop_num == (n_hops - 1),
                     );
                     hop_num += 1;
@@ -207,11 +270,15 @@ impl<R: Runtime, C: Buildable + Sync + Send + 'static> Builder<R, C> {
     }
 
     /// Build a circuit from an [`OwnedPath`].
+    ///
+    /// If `usage` is provided, tell our metrics sink (if any) how the attempt
+    /// turned out.
     async fn build_owned(
         self: &Arc<Self>,
         path: OwnedPath,
         params: &CircParameters,
         guard_status: Arc<GuardStatusHandle>,
+        usage: Option<BuildUsage>,
     ) -> Result<C> {
         let action = Action::BuildCircuit { length: path.len() };
         let (timeout, abandon_timeout) = self.timeouts.timeouts(&action);
@@ -233,7 +300,20 @@ impl<R: Runtime, C: Buildable + Sync + Send + 'static> Builder<R, C> {
             guard_status,
         );
 
-        match double_timeout(&self.runtime, circuit_future, timeout, abandon_timeout).await {
+        let outcome = double_timeout(&self.runtime, circuit_future, timeout, abandon_timeout).await;
+
+        if let Some(usage) = usage {
+            if outcome.is_ok() {
+                self.n_built.fetch_add(1, Ordering::Relaxed);
+            }
+            let metrics = self.metrics.lock().expect("poisoned lock").clone();
+            if let Some(metrics) = metrics {
+                let duration = self.runtime.now().saturating_duration_since(start_time);
+                metrics.record_build_outcome(usage, duration, outcome.is_ok());
+            }
+        }
+
+        match outcome {
             Ok(circuit) => Ok(circuit),
             Err(Error::CircTimeout) => {
                 let n_built = hops_built.load(Ordering::SeqCst);
@@ -260,9 +340,16 @@ pub struct CircuitBuilder<R: Runtime> {
     path_config: tor_config::MutCfg<crate::PathConfig>,
     /// State-manager object to use in storing current state.
     storage: crate::TimeoutStateHandle,
+    /// State-manager object to use in storing our relay latency estimates.
+    latency_storage: crate::LatencyStateHandle,
     /// Guard manager to tell us which guards nodes to use for the circuits
     /// we build.
     guardmgr: tor_guardmgr::GuardMgr<R>,
+    /// If present, a callback to veto relays as we choose them for paths.
+    hop_filter: Mutex<Option<HopFilter>>,
+    /// If present, extra rules for how different the relays in our paths
+    /// must be.
+    diversity: Mutex<Option<DiversityPolicy>>,
 }
 
 impl<R: Runtime> CircuitBuilder<R> {
@@ -274,15 +361,20 @@ impl<R: Runtime> CircuitBuilder<R> {
         chanmgr: Arc<ChanMgr<R>>,
         path_config: crate::PathConfig,
         storage: crate::TimeoutStateHandle,
+        latency_storage: crate::LatencyStateHandle,
         guardmgr: tor_guardmgr::GuardMgr<R>,
     ) -> Self {
         let timeouts = timeouts::Estimator::from_storage(&storage);
+        let latencies = RelayLatencies::from_storage(&latency_storage);
 
         CircuitBuilder {
-            builder: Arc::new(Builder::new(runtime, chanmgr, timeouts)),
+            builder: Arc::new(Builder::new(runtime, chanmgr, timeouts, latencies)),
             path_config: path_config.into(),
             storage,
+            latency_storage,
             guardmgr,
+            hop_filter: Mutex::new(None),
+            diversity: Mutex::new(None),
         }
     }
 
@@ -306,6 +398,7 @@ impl<R: Runtime> CircuitBuilder<R> {
         // TODO: someday we'll want to only do this if there is something
         // changed.
         self.builder.timeouts.save_state(&self.storage)?;
+        self.builder.latencies.save(&self.latency_storage)?;
         self.guardmgr.store_persistent_state()?;
         Ok(true)
     }
@@ -325,6 +418,7 @@ impl<R: Runtime> CircuitBuilder<R> {
             self.builder
                 .timeouts
                 .reload_readonly_from_storage(&self.storage);
+            self.builder.latencies.load(&self.latency_storage);
         }
         self.guardmgr.reload_persistent_state()?;
         Ok(())
@@ -337,14 +431,79 @@ impl<R: Runtime> CircuitBuilder<R> {
         self.builder.timeouts.update_params(p);
     }
 
+    /// Install `metrics` as a sink to tell about how each circuit that we
+    /// build for the circuit manager turns out, replacing any previous
+    /// sink.
+    pub(crate) fn set_metrics(&self, metrics: Arc<dyn BuildMetrics>) {
+        self.builder.set_metrics(Some(metrics));
+    }
+
+    /// Return how many circuits we have successfully built for the
+    /// circuit manager.
+    pub(crate) fn n_circuits_built(&self) -> u64 {
+        self.builder.n_built.load(Ordering::Relaxed)
+    }
+
+    /// Install `filter` as a callback to veto relays as we choose them
+    /// for circuits that we plan, replacing any previous callback.
+    pub(crate) fn set_hop_filter(&self, filter: Option<HopFilter>) {
+        *self.hop_filter.lock().expect("poisoned lock") = filter;
+    }
+
+    /// Return the callback (if any) that we use to veto relays as we choose
+    /// them for circuits.
+    pub(crate) fn hop_filter(&self) -> Option<HopFilter> {
+        self.hop_filter.lock().expect("poisoned lock").clone()
+    }
+
+    /// Install `policy` as a set of extra rules for the relays in the
+    /// circuits that we plan, replacing any previous rules.
+    pub(crate) fn set_diversity_policy(&self, policy: Option<DiversityPolicy>) {
+        *self.diversity.lock().expect("poisoned lock") = policy;
+    }
+
+    /// Return the extra rules (if any) for the relays in the circuits that
+    /// we plan.
+    pub(crate) fn diversity_policy(&self) -> Option<DiversityPolicy> {
+        self.diversity.lock().expect("poisoned lock").clone()
+    }
+
+    /// Return the table of how quickly relays have answered when we
+    /// extended circuits to them.
+    pub(crate) fn latencies(&self) -> &Arc<RelayLatencies> {
+        &self.builder.latencies
+    }
+
+    /// Return the rules that we follow when we pick paths for our
+    /// circuits.
+    pub(crate) fn path_rules(&self) -> PathRules {
+        PathRules {
+            hop_filter: self.hop_filter(),
+            diversity: self.diversity_policy(),
+            latencies: Some(Arc::clone(self.latencies())),
+        }
+    }
+
     /// Like `build`, but construct a new circuit from an [`OwnedPath`].
+    ///
+    /// If `usage` is provided, report the outcome to our metrics sink.
     pub(crate) async fn build_owned(
         &self,
         path: OwnedPath,
         params: &CircParameters,
         guard_status: Arc<GuardStatusHandle>,
+        usage: Option<BuildUsage>,
     ) -> Result<ClientCirc> {
-        self.builder.build_owned(path, params, guard_status).await
+        let circ = self
+            .builder
+            .build_owned(path, params, guard_status, usage)
+            .await?;
+        debug!(
+            "Built circuit {} through {:?}",
+            circ.unique_id(),
+            circ.path()
+        );
+        Ok(circ)
     }
 
     /// Try to construct a new circuit from a given path, using appropriate
@@ -355,7 +514,8 @@ impl<R: Runtime> CircuitBuilder<R> {
     /// automatically go away when the last reference is dropped.
     pub async fn build(&self, path: &TorPath<'_>, params: &CircParameters) -> Result<ClientCirc> {
         let owned = path.try_into()?;
-        self.build_owned(owned, params, Arc::new(None.into())).await
+        self.build_owned(owned, params, Arc::new(None.into()), None)
+            .await
     }
 
     /// Return true if this builder is currently learning timeout info.
@@ -367,6 +527,28 @@ impl<R: Runtime> CircuitBuilder<R> {
     pub(crate) fn guardmgr(&self) -> &tor_guardmgr::GuardMgr<R> {
         &self.guardmgr
     }
+
+    /// Return true if we could pick a path for a circuit with `usage`
+    /// through `dir`, following our current path rules.
+    ///
+    /// This doesn't build anything.  We don't consult our `GuardMgr` here,
+    /// since asking it for a guard would count as an attempt to use one.
+    pub(crate) fn can_plan(
+        &self,
+        usage: &crate::usage::TargetCircUsage,
+        dir: crate::DirInfo<'_>,
+    ) -> bool {
+        let guards: Option<&tor_guardmgr::GuardMgr<R>> = None;
+        usage
+            .build_path(
+                &mut rand::thread_rng(),
+                dir,
+                guards,
+                self.path_config().as_ref(),
+                &self.path_rules(),
+            )
+            .is_ok()
+    }
 }
 
 /// Helper function: spawn a future as a background task, and run it with
@@ -574,6 +756,25 @@ mod test {
         bytes.into()
     }
 
+    /// Return a copy of `id` that our FakeCircuit code will refuse to add to
+    /// a circuit.
+    fn failing_key(id: Ed25519Identity) -> Ed25519Identity {
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(id.as_bytes());
+        bytes[16] = 0xff;
+        bytes.into()
+    }
+    /// Fail if `id` was made with `failing_key`.
+    fn check_failing_key(id: &Ed25519Identity) -> Result<()> {
+        if id.as_bytes()[16] == 0xff {
+            Err(Error::Protocol(tor_proto::Error::CircRefused(
+                "refused by test relay",
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Replacement type for circuit, to implement buildable.
     #[derive(Clone)]
     struct FakeCirc {
@@ -614,6 +815,8 @@ mod test {
                 rt.allow_one_advance(d2);
             }
 
+            check_failing_key(ed_id)?;
+
             let c = FakeCirc {
                 hops: vec![*ct.ed_identity()],
                 onehop: false,
@@ -633,6 +836,8 @@ mod test {
                 rt.allow_one_advance(d2);
             }
 
+            check_failing_key(ed_id)?;
+
             {
                 let mut c = self.lock().unwrap();
                 c.hops.push(*ed_id);
@@ -735,6 +940,7 @@ mod test {
             rt.clone(),
             chanmgr,
             timeouts::Estimator::new(Arc::clone(&timeouts)),
+            RelayLatencies::new(),
         );
 
         let params = CircParameters::default();
@@ -742,7 +948,7 @@ mod test {
         rt.block_advance("manually controlling advances");
         rt.allow_one_advance(advance_initial);
         let outcome = rt
-            .wait_for(Arc::new(builder).build_owned(path, &params, gs()))
+            .wait_for(Arc::new(builder).build_owned(path, &params, gs(), None))
             .await;
 
         // Now we wait for a success to finally, finally be reported.
@@ -863,4 +1069,166 @@ mod test {
                                           //assert_eq!(timeouts[1].2, Duration::from_millis(3300));
         });
     }
+
+    #[test]
+    fn build_failing_hop() {
+        test_with_all_runtimes!(|rt| async move {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let id_100ms =
+                key_from_timeouts(Duration::from_millis(100), Duration::from_millis(200));
+            let id_200ms = failing_key(key_from_timeouts(
+                Duration::from_millis(200),
+                Duration::from_millis(0),
+            ));
+            let id_300ms = key_from_timeouts(Duration::from_millis(300), Duration::from_millis(0));
+            let path =
+                OwnedPath::Normal(vec![circ_t(id_100ms), circ_t(id_200ms), circ_t(id_300ms)]);
+
+            let (outcome, timeouts) =
+                run_builder_test(rt, Duration::from_millis(100), path, None).await;
+            // The error tells us that the second hop is the one that failed.
+            match outcome {
+                Err(Error::Hop { hop, relay, cause }) => {
+                    assert_eq!(hop, 1);
+                    assert_eq!(relay.ed_identity(), &id_200ms);
+                    assert!(matches!(
+                        *cause,
+                        Error::Protocol(tor_proto::Error::CircRefused(_))
+                    ));
+                }
+                other => panic!("unexpected outcome: {:?}", other.map(|c| c.hops)),
+            }
+            // We never finished building it, so there's no timing to learn.
+            assert!(timeouts.is_empty());
+        });
+    }
+
+    /// Fake implementation of BuildMetrics that just records its inputs.
+    #[derive(Default)]
+    struct MetricsRecorder {
+        outcomes: Mutex<Vec<(BuildUsage, Duration, bool)>>,
+    }
+
+    impl BuildMetrics for MetricsRecorder {
+        fn record_build_outcome(&self, usage: BuildUsage, duration: Duration, success: bool) {
+            self.outcomes
+                .lock()
+                .unwrap()
+                .push((usage, duration, success));
+        }
+    }
+
+    #[test]
+    fn build_records_metrics() {
+        test_with_all_runtimes!(|rt| async move {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let chanmgr = Arc::new(ChanMgr::new(rt.clone()));
+            let timeouts = Arc::new(Mutex::new(TimeoutRecorder::new(rt.clone())));
+            let builder: Builder<_, Mutex<FakeCirc>> = Builder::new(
+                rt.clone(),
+                chanmgr,
+                timeouts::Estimator::new(timeouts),
+                RelayLatencies::new(),
+            );
+            let metrics = Arc::new(MetricsRecorder::default());
+            builder.set_metrics(Some(Arc::clone(&metrics) as Arc<dyn BuildMetrics>));
+            let builder = Arc::new(builder);
+
+            let id_100ms = key_from_timeouts(Duration::from_millis(100), Duration::from_millis(0));
+            let params = CircParameters::default();
+
+            // A circuit that we build without saying what it's for doesn't
+            // get recorded.
+            rt.block_advance("manually controlling advances");
+            rt.allow_one_advance(Duration::from_millis(100));
+            let path = OwnedPath::ChannelOnly(chan_t(id_100ms));
+            let outcome = rt
+                .wait_for(builder.build_owned(path, &params, gs(), None))
+                .await;
+            assert!(outcome.is_ok());
+            assert!(metrics.outcomes.lock().unwrap().is_empty());
+            assert_eq!(builder.n_built.load(Ordering::Relaxed), 0);
+
+            // One that we build for a purpose does.
+            rt.allow_one_advance(Duration::from_millis(100));
+            let path = OwnedPath::ChannelOnly(chan_t(id_100ms));
+            let outcome = rt
+                .wait_for(builder.build_owned(path, &params, gs(), Some(BuildUsage::Dir)))
+                .await;
+            assert!(outcome.is_ok());
+            let outcomes = metrics.outcomes.lock().unwrap();
+            assert_eq!(outcomes.len(), 1);
+            assert_eq!(outcomes[0].0, BuildUsage::Dir);
+            assert_eq!(outcomes[0].1, Duration::from_millis(100));
+            assert!(outcomes[0].2); // success
+            assert_eq!(builder.n_built.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn build_through_backup_guard() {
+        // Every circuit through our first guard dies after we've connected
+        // to the guard.  Once that has happened max_guard_failures times, we
+        // build our next circuit through a different guard, and it works.
+        test_with_all_runtimes!(|rt| async move {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            let statemgr = tor_persist::TestingStateMgr::new();
+            let guardmgr = tor_guardmgr::GuardMgr::new(rt.clone(), statemgr).unwrap();
+            guardmgr.update_network(&netdir);
+            guardmgr.set_max_guard_failures(3);
+
+            let chanmgr = Arc::new(ChanMgr::new(rt.clone()));
+            let timeouts = Arc::new(Mutex::new(TimeoutRecorder::new(rt.clone())));
+            let builder: Arc<Builder<_, Mutex<FakeCirc>>> = Arc::new(Builder::new(
+                rt.clone(),
+                chanmgr,
+                timeouts::Estimator::new(timeouts),
+                RelayLatencies::new(),
+            ));
+            let params = CircParameters::default();
+            let id_10ms = key_from_timeouts(Duration::from_millis(10), Duration::from_millis(0));
+
+            let mut first = None;
+            for attempt in 0..4 {
+                let (guard, mon, usable) = guardmgr
+                    .select_guard(tor_guardmgr::GuardUsage::default(), Some(&netdir))
+                    .unwrap();
+                let through_first = first.get_or_insert_with(|| guard.clone()) == &guard;
+                let middle = if through_first {
+                    failing_key(id_10ms)
+                } else {
+                    id_10ms
+                };
+                let path =
+                    OwnedPath::Normal(vec![circ_t(id_10ms), circ_t(middle), circ_t(id_10ms)]);
+
+                // Report the outcome the same way that CircuitBuilder does.
+                let guard_status: Arc<GuardStatusHandle> = Arc::new(Some(mon).into());
+                guard_status.pending(GuardStatus::AttemptAbandoned);
+                let outcome = rt
+                    .wait_for(builder.build_owned(path, &params, Arc::clone(&guard_status), None))
+                    .await;
+                if outcome.is_ok() {
+                    guard_status.report(GuardStatus::Success);
+                } else {
+                    guard_status.commit();
+                }
+                let usable = usable.await.unwrap();
+
+                if attempt < 3 {
+                    assert!(through_first);
+                    assert!(outcome.is_err());
+                    assert!(!usable);
+                } else {
+                    assert!(!through_first);
+                    assert_eq!(outcome.unwrap().lock().unwrap().hops.len(), 3);
+                    assert!(usable);
+                }
+            }
+        });
+    }
 }
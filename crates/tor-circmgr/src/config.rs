@@ -135,6 +135,15 @@ pub struct PreemptiveCircuitConfig {
     #[builder(default = "default_preemptive_min_exit_circs_for_port()")]
     #[serde(default = "default_preemptive_min_exit_circs_for_port")]
     pub(crate) min_exit_circs_for_port: usize,
+
+    /// How many general-purpose circuits should we build as part of
+    /// bootstrapping, so that the client's first connection doesn't have to
+    /// wait for one?
+    ///
+    /// If this is 0, we don't build any circuits at bootstrap.
+    #[builder(default)]
+    #[serde(default)]
+    pub(crate) warm_circuits_at_bootstrap: usize,
 }
 
 /// Configuration for circuit timeouts, expiration, and so on.
@@ -177,6 +186,62 @@ pub struct CircuitTiming {
     #[builder(default = "default_request_loyalty()")]
     #[serde(with = "humantime_serde", default = "default_request_loyalty")]
     pub(crate) request_loyalty: Duration,
+
+    /// How many directory circuits should we build at once, at most?
+    ///
+    /// Builds past this limit wait for an earlier one to finish.  If this is
+    /// 0, there is no limit.
+    #[builder(default)]
+    #[serde(default)]
+    pub(crate) max_concurrent_dir_builds: usize,
+
+    /// When this many circuits in a row fail after connecting to the same
+    /// guard, we decide that the guard is probably down, and try a
+    /// different guard for later attempts.
+    ///
+    /// If this is 0 (the default), we keep using a guard as long as we can
+    /// connect to it.
+    #[builder(default)]
+    #[serde(default)]
+    pub(crate) max_guard_failures: u32,
+
+    /// How many new circuits can we start building at once, before we start
+    /// pacing new builds according to `build_rate_interval`?
+    ///
+    /// This limits bursts of circuit construction, such as those that a
+    /// caller making many requests at once might cause.  It doesn't affect
+    /// requests that can reuse an existing or pending circuit.  If this is
+    /// 0, there is no limit.
+    #[builder(default)]
+    #[serde(default)]
+    pub(crate) build_rate_burst: u32,
+
+    /// Once we have started `build_rate_burst` new circuits, how long should
+    /// we wait before starting each additional one?
+    #[builder(default = "default_build_rate_interval()")]
+    #[serde(with = "humantime_serde", default = "default_build_rate_interval")]
+    pub(crate) build_rate_interval: Duration,
+
+    /// How many open circuits should we keep for each kind of usage (such
+    /// as exit circuits, or directory circuits), at most?
+    ///
+    /// When a new circuit takes us past this limit, we retire the least
+    /// recently used circuit of the same kind, so that a workload with many
+    /// isolation groups can't make us keep unboundedly many circuits.
+    /// (Retired circuits stay open for as long as they have streams, but we
+    /// don't give them out for new requests.)  If this is 0, there is no
+    /// limit.
+    #[builder(default)]
+    #[serde(default)]
+    pub(crate) max_circs_per_usage: usize,
+
+    /// How many open circuits should we keep in total, at most?
+    ///
+    /// When a new circuit takes us past this limit, we retire the least
+    /// recently used circuit of any kind.  If this is 0, there is no limit.
+    #[builder(default)]
+    #[serde(default)]
+    pub(crate) max_circs: usize,
 }
 
 /// Return default threshold
@@ -214,6 +279,11 @@ fn default_request_max_retries() -> u32 {
     32
 }
 
+/// Return the default value for `build_rate_interval`.
+fn default_build_rate_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
 /// Return the default request loyalty timeout.
 fn default_request_loyalty() -> Duration {
     Duration::from_millis(50)
@@ -243,7 +313,13 @@ impl From<CircuitTiming> for CircuitTimingBuilder {
             .max_dirtiness(cfg.max_dirtiness)
             .request_timeout(cfg.request_timeout)
             .request_max_retries(cfg.request_max_retries)
-            .request_loyalty(cfg.request_loyalty);
+            .request_loyalty(cfg.request_loyalty)
+            .max_concurrent_dir_builds(cfg.max_concurrent_dir_builds)
+            .max_guard_failures(cfg.max_guard_failures)
+            .build_rate_burst(cfg.build_rate_burst)
+            .build_rate_interval(cfg.build_rate_interval)
+            .max_circs_per_usage(cfg.max_circs_per_usage)
+            .max_circs(cfg.max_circs);
         builder
     }
 }
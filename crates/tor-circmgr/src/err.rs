@@ -7,7 +7,7 @@ use retry_error::RetryError;
 use thiserror::Error;
 
 use tor_error::{Bug, ErrorKind, HasKind};
-use tor_linkspec::OwnedChanTarget;
+use tor_linkspec::{ChanTarget, OwnedChanTarget};
 
 /// An error returned while looking up or building a circuit
 #[derive(Error, Debug, Clone)]
@@ -75,6 +75,21 @@ pub enum Error {
         cause: tor_chanmgr::Error,
     },
 
+    /// We couldn't add one of the hops to a circuit that we were building.
+    #[error("Problem building circuit at hop {hop} ({relay})")]
+    Hop {
+        /// Which hop we were trying to add.  The first hop (usually a
+        /// guard) is hop 0.
+        hop: usize,
+
+        /// The relay that we were trying to add at that hop.
+        relay: OwnedChanTarget,
+
+        /// What went wrong
+        #[source]
+        cause: Box<Error>,
+    },
+
     /// Protocol issue while building a circuit.
     #[error("Problem building a circuit: {0}")]
     Protocol(#[from] tor_proto::Error),
@@ -130,6 +145,7 @@ impl HasKind for Error {
         use ErrorKind as EK;
         match self {
             E::Channel { cause, .. } => cause.kind(),
+            E::Hop { cause, .. } => cause.kind(),
             E::Bug(e) => e.kind(),
             E::NoPath(_) => EK::NoPath,
             E::NoExit(_) => EK::NoExit,
@@ -162,6 +178,16 @@ impl Error {
         }
     }
 
+    /// Return an error saying that we couldn't add `relay` as hop number
+    /// `hop` of a circuit, because of `cause`.
+    pub(crate) fn at_hop<CT: ChanTarget + ?Sized>(hop: usize, relay: &CT, cause: Error) -> Error {
+        Error::Hop {
+            hop,
+            relay: OwnedChanTarget::from_chan_target(relay),
+            cause: Box::new(cause),
+        }
+    }
+
     /// Return an integer representing the relative severity of this error.
     ///
     /// Used to determine which error to use when determining the kind of a retry error.
@@ -179,6 +205,7 @@ impl Error {
             E::Guard(_) => 40,
             E::RequestFailed(_) => 40,
             E::Channel { .. } => 40,
+            E::Hop { cause, .. } => cause.severity(),
             E::Protocol(_) => 45,
             E::ExpiredConsensus => 50,
             E::Spawn { .. } => 90,
@@ -1,8 +1,9 @@
 //! Implement traits from [`crate::mgr`] for the circuit types we use.
 
+use crate::build::BuildUsage;
 use crate::mgr::{self, MockablePlan};
 use crate::path::OwnedPath;
-use crate::usage::{SupportedCircUsage, TargetCircUsage};
+use crate::usage::{DirPriority, SupportedCircUsage, TargetCircUsage};
 use crate::{DirInfo, Error, Result};
 use async_trait::async_trait;
 use futures::future::OptionFuture;
@@ -40,6 +41,9 @@ pub(crate) struct Plan {
     /// whether we're allowed to use the circuit or whether we have to
     /// wait a while.
     guard_usable: Option<tor_guardmgr::GuardUsable>,
+    /// The kind of circuit we're building, for reporting to our metrics
+    /// sink.
+    usage: BuildUsage,
 }
 
 impl Debug for Plan {
@@ -49,6 +53,7 @@ impl Debug for Plan {
             .field("path", &self.path)
             .field("params", &self.params)
             .field("guard_status", &self.guard_status)
+            .field("usage", &self.usage)
             .finish_non_exhaustive()
     }
 }
@@ -72,6 +77,7 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder for crate::build::CircuitBuilde
             dir,
             Some(self.guardmgr()),
             self.path_config().as_ref(),
+            &self.path_rules(),
         )?;
 
         let plan = Plan {
@@ -80,6 +86,7 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder for crate::build::CircuitBuilde
             params: dir.circ_params(),
             guard_status,
             guard_usable,
+            usage: usage.build_usage(),
         };
 
         Ok((plan, final_spec))
@@ -94,6 +101,7 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder for crate::build::CircuitBuilde
             params,
             guard_status,
             guard_usable,
+            usage,
         } = plan;
 
         let guard_usable: OptionFuture<_> = guard_usable.into();
@@ -108,7 +116,7 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder for crate::build::CircuitBuilde
         // This will probably require a different API for circuit
         // construction.
         match self
-            .build_owned(path, &params, Arc::clone(&guard_status))
+            .build_owned(path, &params, Arc::clone(&guard_status), Some(usage))
             .await
         {
             Ok(circuit) => {
@@ -142,7 +150,9 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder for crate::build::CircuitBuilde
 
     fn launch_parallelism(&self, spec: &TargetCircUsage) -> usize {
         match spec {
-            TargetCircUsage::Dir => 3,
+            TargetCircUsage::Dir {
+                priority: DirPriority::Foreground,
+            } => 3,
             _ => 1,
         }
     }
@@ -151,6 +161,10 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder for crate::build::CircuitBuilde
         self.launch_parallelism(spec)
     }
 
+    fn build_is_limited(&self, spec: &TargetCircUsage) -> bool {
+        matches!(spec, TargetCircUsage::Dir { .. })
+    }
+
     fn learning_timeouts(&self) -> bool {
         crate::build::CircuitBuilder::learning_timeouts(self)
     }
@@ -0,0 +1,252 @@
+//! Code to remember how quickly relays have answered us in the past.
+//!
+//! Every time we extend a circuit to a relay, we learn roughly how long
+//! that relay took to answer.  We keep a smoothed estimate of that time for
+//! each relay, so that path selection can prefer faster relays for
+//! interactive traffic.
+//!
+//! These measurements are noisy: the time to extend to a relay also
+//! includes the round trip through every earlier hop of the circuit.  We
+//! only use them as a gentle bias, never as a hard requirement.
+//!
+//! We save our estimates as part of our persistent state, so that we don't
+//! have to learn them all over again every time we start.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::Duration;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdir::{NetDir, Relay, WeightRole};
+use tor_persist::JsonValue;
+use tracing::{debug, warn};
+
+use crate::LatencyStateHandle;
+
+/// How much weight do we give to each new measurement, as `1/N`?
+const SMOOTHING_DENOMINATOR: u32 = 4;
+
+/// How many measurements will we keep at most?
+///
+/// This is comfortably larger than the number of relays in the network; we
+/// only hit it if we've been running for a very long time.
+const MAX_ENTRIES: usize = 16384;
+
+/// A table of how long each relay has taken to answer us, based on the
+/// circuits that we've built.
+#[derive(Default)]
+pub(crate) struct RelayLatencies {
+    /// Map from relay identity to our smoothed latency estimate for it.
+    estimates: Mutex<HashMap<RsaIdentity, Duration>>,
+}
+
+/// An object used to serialize our latency estimates for persistent state.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct LatencyState {
+    /// Our smoothed latency estimate for each relay, in milliseconds.
+    relays: Vec<(RsaIdentity, u32)>,
+
+    /// Fields from the state file that was used to make this `LatencyState`
+    /// that this version of Arti doesn't understand.
+    #[serde(flatten)]
+    unknown_fields: HashMap<String, JsonValue>,
+}
+
+impl RelayLatencies {
+    /// Construct a new, empty, `RelayLatencies`.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Construct a new `RelayLatencies` from the estimates in `storage`,
+    /// if there are any.
+    pub(crate) fn from_storage(storage: &LatencyStateHandle) -> Self {
+        let latencies = Self::new();
+        latencies.load(storage);
+        latencies
+    }
+
+    /// Replace our estimates with the ones saved in `storage`.
+    ///
+    /// If `storage` has nothing for us, or we can't read it, we keep the
+    /// estimates that we have.
+    pub(crate) fn load(&self, storage: &LatencyStateHandle) {
+        match storage.load() {
+            Ok(Some(state)) => self.replace_from_state(&state),
+            Ok(None) => debug!("No relay latency state to load."),
+            Err(e) => warn!("Unable to load relay latency state: {}", e),
+        }
+    }
+
+    /// Save our current estimates into `storage`.
+    pub(crate) fn save(&self, storage: &LatencyStateHandle) -> crate::Result<()> {
+        storage.store(&self.build_state())?;
+        Ok(())
+    }
+
+    /// Return a [`LatencyState`] holding our current estimates.
+    fn build_state(&self) -> LatencyState {
+        let estimates = self.estimates.lock().expect("poisoned lock");
+        let relays = estimates
+            .iter()
+            .map(|(id, d)| {
+                let msec = u32::try_from(d.as_millis()).unwrap_or(u32::MAX);
+                (*id, msec)
+            })
+            .collect();
+        LatencyState {
+            relays,
+            unknown_fields: HashMap::new(),
+        }
+    }
+
+    /// Replace our estimates with the ones in `state`.
+    fn replace_from_state(&self, state: &LatencyState) {
+        let mut estimates = self.estimates.lock().expect("poisoned lock");
+        *estimates = state
+            .relays
+            .iter()
+            .take(MAX_ENTRIES)
+            .map(|(id, msec)| (*id, Duration::from_millis((*msec).into())))
+            .collect();
+    }
+
+    /// Record that extending a circuit to the relay with `id` took `delay`.
+    pub(crate) fn note_latency(&self, id: &RsaIdentity, delay: Duration) {
+        let mut estimates = self.estimates.lock().expect("poisoned lock");
+        if let Some(old) = estimates.get_mut(id) {
+            *old = (*old * (SMOOTHING_DENOMINATOR - 1) + delay) / SMOOTHING_DENOMINATOR;
+            return;
+        }
+        if estimates.len() >= MAX_ENTRIES {
+            // We don't try to be clever about what to forget.
+            estimates.clear();
+        }
+        estimates.insert(*id, delay);
+    }
+
+    /// Return our current latency estimate for the relay with `id`, if we
+    /// have one.
+    pub(crate) fn estimate(&self, id: &RsaIdentity) -> Option<Duration> {
+        self.estimates
+            .lock()
+            .expect("poisoned lock")
+            .get(id)
+            .copied()
+    }
+
+    /// Pick a relay for `role` from `netdir`, among those that satisfy
+    /// `usable`, preferring ones that we have seen to be faster.
+    ///
+    /// We choose two candidates with the ordinary weighting, and keep the
+    /// one with the lower latency estimate.  If we don't have an estimate
+    /// for both of them, we keep the first.  This keeps the bandwidth
+    /// weighting roughly intact, while still biasing us toward fast relays.
+    pub(crate) fn pick_relay<'a, R, P>(
+        &self,
+        rng: &mut R,
+        netdir: &'a NetDir,
+        role: WeightRole,
+        usable: P,
+    ) -> Option<Relay<'a>>
+    where
+        R: rand::Rng,
+        P: Fn(&Relay<'a>) -> bool,
+    {
+        let first = netdir.pick_relay(rng, role, &usable)?;
+        let second = match netdir.pick_relay(rng, role, &usable) {
+            Some(r) => r,
+            None => return Some(first),
+        };
+        match (
+            self.estimate(first.rsa_id()),
+            self.estimate(second.rsa_id()),
+        ) {
+            (Some(t1), Some(t2)) if t2 < t1 => Some(second),
+            _ => Some(first),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn smoothing() {
+        let lat = RelayLatencies::new();
+        let id: RsaIdentity = [7; 20].into();
+        assert_eq!(lat.estimate(&id), None);
+
+        lat.note_latency(&id, Duration::from_millis(400));
+        assert_eq!(lat.estimate(&id), Some(Duration::from_millis(400)));
+
+        lat.note_latency(&id, Duration::from_millis(800));
+        assert_eq!(lat.estimate(&id), Some(Duration::from_millis(500)));
+
+        let other: RsaIdentity = [8; 20].into();
+        assert_eq!(lat.estimate(&other), None);
+    }
+
+    #[test]
+    fn save_and_load() {
+        use tor_persist::StateMgr;
+
+        let netdir = tor_netdir::testnet::construct_netdir()
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+        let storage = tor_persist::TestingStateMgr::new();
+        assert!(storage.try_lock().unwrap().held());
+        let handle = storage.clone().create_handle("latencies");
+
+        // Nothing has been saved yet.
+        let lat = RelayLatencies::from_storage(&handle);
+        assert!(lat.estimates.lock().unwrap().is_empty());
+
+        // Even-numbered relays have been fast; odd-numbered ones slow.
+        let is_fast = |id: &RsaIdentity| id.as_bytes()[0] % 2 == 0;
+        for r in netdir.relays() {
+            let delay = if is_fast(r.rsa_id()) {
+                Duration::from_millis(100)
+            } else {
+                Duration::from_millis(900)
+            };
+            lat.note_latency(r.rsa_id(), delay);
+        }
+        lat.save(&handle).unwrap();
+
+        // A new table that reads the same storage should know everything
+        // the old one did.
+        let lat2 = RelayLatencies::from_storage(&handle);
+        for r in netdir.relays() {
+            assert_eq!(lat2.estimate(r.rsa_id()), lat.estimate(r.rsa_id()));
+        }
+
+        // ... and it should use that knowledge to prefer fast relays.
+        let mut rng = rand::thread_rng();
+        let mut count_fast = |lat: &RelayLatencies| {
+            (0..1000)
+                .filter(|_| {
+                    let r = lat
+                        .pick_relay(&mut rng, &netdir, WeightRole::Middle, |_| true)
+                        .unwrap();
+                    is_fast(r.rsa_id())
+                })
+                .count()
+        };
+        let n_cold = count_fast(&RelayLatencies::new());
+        let n_loaded = count_fast(&lat2);
+        // We expect about 450 fast relays when we know nothing, and about
+        // 700 when we have our saved estimates.
+        assert!(
+            n_loaded > n_cold + 100,
+            "{} fast relays with saved estimates, {} without",
+            n_loaded,
+            n_cold
+        );
+    }
+}
@@ -64,6 +64,7 @@ pub mod build;
 mod config;
 mod err;
 mod impls;
+mod latency;
 mod mgr;
 pub mod path;
 mod preemptive;
@@ -71,7 +72,9 @@ mod timeouts;
 mod usage;
 
 pub use err::Error;
-pub use usage::{IsolationToken, StreamIsolation, StreamIsolationBuilder, TargetPort, TargetPorts};
+pub use usage::{
+    DirPriority, IsolationToken, StreamIsolation, StreamIsolationBuilder, TargetPort, TargetPorts,
+};
 
 pub use config::{
     CircMgrConfig, CircMgrConfigBuilder, CircuitTiming, CircuitTimingBuilder, PathConfig,
@@ -90,6 +93,13 @@ type TimeoutStateHandle = tor_persist::DynStorageHandle<timeouts::pareto::Pareto
 /// Key used to load timeout state information.
 const PARETO_TIMEOUT_DATA_KEY: &str = "circuit_timeouts";
 
+/// Type alias for dynamic StorageHandle that can handle our relay latency
+/// state.
+type LatencyStateHandle = tor_persist::DynStorageHandle<latency::LatencyState>;
+
+/// Key used to load relay latency state information.
+const RELAY_LATENCY_DATA_KEY: &str = "relay_latencies";
+
 /// Represents what we know about the Tor network.
 ///
 /// This can either be a complete directory, or a list of fallbacks.
@@ -177,7 +187,9 @@ impl<R: Runtime> CircMgr<R> {
         )));
 
         let guardmgr = tor_guardmgr::GuardMgr::new(runtime.clone(), storage.clone())?;
+        guardmgr.set_max_guard_failures(circuit_timing.max_guard_failures);
 
+        let latency_handle = storage.clone().create_handle(RELAY_LATENCY_DATA_KEY);
         let storage_handle = storage.create_handle(PARETO_TIMEOUT_DATA_KEY);
 
         let builder = build::CircuitBuilder::new(
@@ -185,6 +197,7 @@ impl<R: Runtime> CircMgr<R> {
             chanmgr,
             path_rules,
             storage_handle,
+            latency_handle,
             guardmgr,
         );
         let mgr = mgr::AbstractCircMgr::new(builder, runtime.clone(), circuit_timing);
@@ -225,6 +238,10 @@ impl<R: Runtime> CircMgr<R> {
         self.mgr
             .peek_builder()
             .set_path_config(new_config.path_rules.clone());
+        self.mgr
+            .peek_builder()
+            .guardmgr()
+            .set_max_guard_failures(new_config.circuit_timing.max_guard_failures);
         self.mgr
             .set_circuit_timing(new_config.circuit_timing.clone());
         predictor.set_config(new_config.preemptive_circuits.clone());
@@ -263,6 +280,47 @@ impl<R: Runtime> CircMgr<R> {
         self.mgr.peek_builder().save_state()
     }
 
+    /// Install `metrics` as a sink to tell about how each circuit that we
+    /// build turns out.
+    ///
+    /// This replaces any sink that was installed before.  Circuits that
+    /// you build yourself with a [`CircuitBuilder`](build::CircuitBuilder)
+    /// aren't reported.
+    pub fn set_build_metrics(&self, metrics: Arc<dyn build::BuildMetrics>) {
+        self.mgr.peek_builder().set_metrics(metrics);
+    }
+
+    /// Return how many circuits this circuit manager has successfully
+    /// built since it was created.
+    ///
+    /// As with [`set_build_metrics`](CircMgr::set_build_metrics), circuits
+    /// that you build yourself with a [`CircuitBuilder`](build::CircuitBuilder)
+    /// aren't counted.
+    pub fn n_circuits_built(&self) -> u64 {
+        self.mgr.peek_builder().n_circuits_built()
+    }
+
+    /// Install `filter` as a callback to accept or veto each relay that we
+    /// consider for the circuits we build, or remove it with `None`.
+    ///
+    /// The callback only affects multi-hop circuits that we build from
+    /// now on: one-hop directory circuits, and circuits that
+    /// are already open, are not affected.  See
+    /// [`HopFilter`](path::HopFilter) for details.
+    pub fn set_hop_filter(&self, filter: Option<path::HopFilter>) {
+        self.mgr.peek_builder().set_hop_filter(filter);
+    }
+
+    /// Install `policy` as a set of extra rules for how different the
+    /// relays in each circuit must be, or remove it with `None`.
+    ///
+    /// Like [`set_hop_filter`](Self::set_hop_filter), this only affects
+    /// multi-hop circuits that we build from now on.  See
+    /// [`DiversityPolicy`](path::DiversityPolicy) for details.
+    pub fn set_diversity_policy(&self, policy: Option<path::DiversityPolicy>) {
+        self.mgr.peek_builder().set_diversity_policy(policy);
+    }
+
     /// Reconfigure this circuit manager using the latest set of
     /// network parameters.
     ///
@@ -293,11 +351,31 @@ impl<R: Runtime> CircMgr<R> {
         self.mgr.peek_builder().guardmgr().update_network(netdir);
     }
 
+    /// Return the guards that this circuit manager currently prefers to use
+    /// as the first hop of its circuits, from best to worst.
+    pub fn primary_guards(&self) -> Vec<tor_guardmgr::Guard> {
+        self.mgr.peek_builder().guardmgr().primary_guards()
+    }
+
     /// Return a circuit suitable for sending one-hop BEGINDIR streams,
     /// launching it if necessary.
     pub async fn get_or_launch_dir(&self, netdir: DirInfo<'_>) -> Result<ClientCirc> {
+        self.get_or_launch_dir_with_priority(netdir, DirPriority::Foreground)
+            .await
+    }
+
+    /// Return a circuit suitable for sending one-hop BEGINDIR streams with a
+    /// given `priority`, launching it if necessary.
+    ///
+    /// Circuits for one priority are never given out for requests with
+    /// another.
+    pub async fn get_or_launch_dir_with_priority(
+        &self,
+        netdir: DirInfo<'_>,
+        priority: DirPriority,
+    ) -> Result<ClientCirc> {
         self.expire_circuits();
-        let usage = TargetCircUsage::Dir;
+        let usage = TargetCircUsage::Dir { priority };
         self.mgr.get_or_launch(&usage, netdir).await
     }
 
@@ -311,6 +389,53 @@ impl<R: Runtime> CircMgr<R> {
         netdir: DirInfo<'_>, // TODO: This has to be a NetDir.
         ports: &[TargetPort],
         isolation: StreamIsolation,
+    ) -> Result<ClientCirc> {
+        self.get_or_launch_exit_with_min_hops(netdir, ports, isolation, 0)
+            .await
+    }
+
+    /// Return a circuit suitable for exiting to all of the provided
+    /// `ports`, with at least `min_hops` hops, launching it if necessary.
+    ///
+    /// We only check `min_hops`; we don't build longer circuits to satisfy
+    /// it.  Every exit circuit that we build has three hops, so this fails
+    /// if `min_hops` is more than three.
+    pub async fn get_or_launch_exit_with_min_hops(
+        &self,
+        netdir: DirInfo<'_>, // TODO: This has to be a NetDir.
+        ports: &[TargetPort],
+        isolation: StreamIsolation,
+        min_hops: usize,
+    ) -> Result<ClientCirc> {
+        self.get_or_launch_exit_impl(netdir, ports, isolation, min_hops, false)
+            .await
+    }
+
+    /// Return a circuit suitable for exiting to all of the provided
+    /// `ports` with low latency, launching it if necessary.
+    ///
+    /// Use this for interactive traffic.  When we need to build a new
+    /// circuit, we prefer middle and exit relays that have answered us
+    /// quickly when we built circuits through them before.  (We still use
+    /// an existing circuit if we have a suitable one.)
+    pub async fn get_or_launch_low_latency_exit(
+        &self,
+        netdir: DirInfo<'_>, // TODO: This has to be a NetDir.
+        ports: &[TargetPort],
+        isolation: StreamIsolation,
+    ) -> Result<ClientCirc> {
+        self.get_or_launch_exit_impl(netdir, ports, isolation, 0, true)
+            .await
+    }
+
+    /// Helper: implement the `get_or_launch_*exit*` functions.
+    async fn get_or_launch_exit_impl(
+        &self,
+        netdir: DirInfo<'_>,
+        ports: &[TargetPort],
+        isolation: StreamIsolation,
+        min_hops: usize,
+        prefer_low_latency: bool,
     ) -> Result<ClientCirc> {
         self.expire_circuits();
         let time = Instant::now();
@@ -325,10 +450,38 @@ impl<R: Runtime> CircMgr<R> {
             }
         }
         let ports = ports.iter().map(Clone::clone).collect();
-        let usage = TargetCircUsage::Exit { ports, isolation };
+        let usage = TargetCircUsage::Exit {
+            ports,
+            isolation,
+            min_hops,
+            prefer_low_latency,
+        };
         self.mgr.get_or_launch(&usage, netdir).await
     }
 
+    /// Return true if we could build a circuit through `netdir` that exits to
+    /// all of the provided `ports`, with at least `min_hops` hops.
+    ///
+    /// This checks whether any relays in `netdir` fit our path rules
+    /// (including any [hop filter](Self::set_hop_filter)) for such a
+    /// circuit, without building one.  Use it to find out whether a request
+    /// is impossible before making it.  A `true` answer is no promise that
+    /// the circuit will build: the relays we pick might not answer.
+    pub fn can_build_exit(
+        &self,
+        netdir: DirInfo<'_>,
+        ports: &[TargetPort],
+        min_hops: usize,
+    ) -> bool {
+        let usage = TargetCircUsage::Exit {
+            ports: ports.to_vec(),
+            isolation: StreamIsolation::no_isolation(),
+            min_hops,
+            prefer_low_latency: false,
+        };
+        self.mgr.peek_builder().can_plan(&usage, netdir)
+    }
+
     /// Launch circuits preemptively, using the preemptive circuit predictor's predictions.
     ///
     /// # Note
@@ -362,6 +515,39 @@ impl<R: Runtime> CircMgr<R> {
         }
     }
 
+    /// Build general-purpose circuits until we have at least as many as
+    /// configured by `warm_circuits_at_bootstrap`, and wait for them to
+    /// finish.
+    ///
+    /// Return the number of circuits that we built.
+    ///
+    /// This is meant to be called once our directory is bootstrapped, so that
+    /// the client's first request can use a circuit that's already open.
+    pub async fn build_warm_circuits(&self, netdir: DirInfo<'_>) -> Result<usize> {
+        let n_wanted = {
+            let preemptive = self.predictor.lock().expect("preemptive lock poisoned");
+            preemptive.config().warm_circuits_at_bootstrap
+        };
+        // Only count circuits that we could use for exiting: directory
+        // circuits, for instance, don't help.
+        let usage = TargetCircUsage::Preemptive {
+            port: None,
+            circs: n_wanted,
+        };
+        let n_have = self.mgr.n_circs_supporting(&usage);
+        if n_have >= n_wanted {
+            return Ok(0);
+        }
+
+        let n_needed = n_wanted - n_have;
+        debug!("Building {} circuits to warm up.", n_needed);
+        let usage = TargetCircUsage::Preemptive {
+            port: None,
+            circs: n_needed,
+        };
+        self.mgr.launch_n_by_usage(&usage, netdir, n_needed).await
+    }
+
     /// If `circ_id` is the unique identifier for a circuit that we're
     /// keeping track of, don't give it out for any future requests.
     pub fn retire_circ(&self, circ_id: &UniqId) {
@@ -373,12 +559,46 @@ impl<R: Runtime> CircMgr<R> {
     /// streams attached to them, but it will prevent any future streams from
     /// being attached.
     ///
-    /// TODO: we may want to expose this eventually.  If we do, we should
-    /// be very clear that you don't want to use it haphazardly.
-    pub(crate) fn retire_all_circuits(&self) {
+    /// Requests that are waiting for a circuit that is still being built
+    /// will fail, since that circuit will not be handed out either.
+    ///
+    /// You don't want to call this haphazardly: every later request will
+    /// have to wait for a new circuit to be built.  It is meant for cases
+    /// like a "new identity" button, where the caller wants nothing they do
+    /// from now on to share a circuit with anything they did before, but
+    /// doesn't want to break the streams they already have.
+    pub fn retire_all_circuits(&self) {
         self.mgr.retire_all_circuits();
     }
 
+    /// Return every open circuit that this manager might currently hand
+    /// out for new requests.
+    ///
+    /// Circuits that have been retired or are due to expire are not
+    /// included, even if they are still open because some stream is using
+    /// them.
+    pub fn circuits(&self) -> Vec<ClientCirc> {
+        self.mgr.list_circs()
+    }
+
+    /// Close every circuit that this manager is holding, and forget about
+    /// any circuits that are still being built.
+    ///
+    /// Unlike [`retire_circ`](Self::retire_circ), this closes the circuits
+    /// right away, so any streams attached to them will fail.  Later
+    /// requests will get freshly built circuits.
+    ///
+    /// This is meant for cases like a "new identity" button, where the
+    /// caller wants to make sure that nothing they do from now on shares a
+    /// circuit with anything they did before.
+    pub fn close_all_circuits(&self) {
+        let circs = self.mgr.take_all_circs();
+        info!("Closing {} circuits on request.", circs.len());
+        for circ in circs {
+            circ.terminate();
+        }
+    }
+
     /// Expire every circuit that has been dirty for too long.
     ///
     /// Expired circuits are not closed while they still have users,
@@ -45,8 +45,13 @@ use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
 use weak_table::PtrWeakHashSet;
 
+mod limit;
+mod ratelimit;
 mod streams;
 
+use limit::BuildLimiter;
+use ratelimit::BuildRateLimiter;
+
 /// Represents restrictions on circuit usage.
 ///
 /// An `AbstractSpec` describes what a circuit can be used for.  Each
@@ -93,6 +98,12 @@ pub(crate) trait AbstractSpec: Clone + Debug {
     /// contained by the original spec, and must support `usage`.
     fn restrict_mut(&mut self, usage: &Self::Usage) -> Result<()>;
 
+    /// Return a name for the kind of usage that this spec permits.
+    ///
+    /// Open circuits whose specs have the same usage class count against
+    /// the same [`CircuitTiming::max_circs_per_usage`] limit.
+    fn usage_class(&self) -> &'static str;
+
     /// Find all open circuits in `list` whose specifications permit
     /// `usage`.
     ///
@@ -238,6 +249,15 @@ pub(crate) trait AbstractCircBuilder: Send + Sync {
         1
     }
 
+    /// Return true if building circuits for `usage` counts against
+    /// [`CircuitTiming::max_concurrent_dir_builds`].
+    ///
+    /// The default implementation returns false.
+    fn build_is_limited(&self, usage: &<Self::Spec as AbstractSpec>::Usage) -> bool {
+        let _ = usage; // default implementation ignores this.
+        false
+    }
+
     /// Return true if we are currently attempting to learn circuit
     /// timeouts by building testing circuits.
     fn learning_timeouts(&self) -> bool;
@@ -294,15 +314,20 @@ pub(crate) struct OpenEntry<S, C> {
     /// which does not actually close them until there are no more
     /// references to them.)
     expiration: ExpirationInfo,
+    /// When was this circuit last given out for a request?  (Or, if it
+    /// never has been, when was it built?)
+    last_used: Instant,
 }
 
 impl<S: AbstractSpec, C: AbstractCirc> OpenEntry<S, C> {
-    /// Make a new OpenEntry for a given circuit and spec.
-    fn new(spec: S, circ: C, expiration: ExpirationInfo) -> Self {
+    /// Make a new OpenEntry for a given circuit and spec, which was built
+    /// at `now`.
+    fn new(spec: S, circ: C, expiration: ExpirationInfo, now: Instant) -> Self {
         OpenEntry {
             spec,
             circ,
             expiration,
+            last_used: now,
         }
     }
 
@@ -318,6 +343,7 @@ impl<S: AbstractSpec, C: AbstractCirc> OpenEntry<S, C> {
     fn restrict_mut(&mut self, usage: &<S as AbstractSpec>::Usage, now: Instant) -> Result<()> {
         self.spec.restrict_mut(usage)?;
         self.expiration.mark_dirty(now);
+        self.last_used = now;
         Ok(())
     }
 
@@ -562,6 +588,64 @@ impl<B: AbstractCircBuilder> CircList<B> {
         }
     }
 
+    /// Remove least recently used circuits until we have no more than
+    /// `max_per_class` open circuits in the usage class `class`, and no more
+    /// than `max_total` open circuits overall.
+    ///
+    /// We never remove the circuit with ID `keep`.  A limit of 0 means
+    /// that there is no limit.  Return the number of circuits we removed.
+    fn retire_lru(
+        &mut self,
+        keep: &<B::Circ as AbstractCirc>::Id,
+        class: &'static str,
+        max_per_class: usize,
+        max_total: usize,
+    ) -> usize {
+        let mut n_retired = 0;
+        if max_per_class > 0 {
+            n_retired +=
+                self.retire_lru_matching(keep, max_per_class, |e| e.spec.usage_class() == class);
+        }
+        if max_total > 0 {
+            n_retired += self.retire_lru_matching(keep, max_total, |_| true);
+        }
+        n_retired
+    }
+
+    /// Helper for `retire_lru`: remove least recently used circuits (other
+    /// than `keep`) until no more than `max` open circuits match `filter`.
+    fn retire_lru_matching<F>(
+        &mut self,
+        keep: &<B::Circ as AbstractCirc>::Id,
+        max: usize,
+        filter: F,
+    ) -> usize
+    where
+        F: Fn(&OpenEntry<B::Spec, B::Circ>) -> bool,
+    {
+        let mut matching: Vec<_> = self
+            .open_circs
+            .iter()
+            .filter(|(_, e)| filter(e))
+            .map(|(id, e)| (e.last_used, id.clone()))
+            .collect();
+        if matching.len() <= max {
+            return 0;
+        }
+        let n_excess = matching.len() - max;
+        matching.sort_by_key(|(last_used, _)| *last_used);
+        let victims: Vec<_> = matching
+            .into_iter()
+            .map(|(_, id)| id)
+            .filter(|id| id != keep)
+            .take(n_excess)
+            .collect();
+        for id in &victims {
+            self.open_circs.remove(id);
+        }
+        victims.len()
+    }
+
     /// Add `pending` to the set of in-progress circuits.
     fn add_pending_circ(&mut self, pending: Arc<PendingEntry<B>>) {
         self.pending_circs.insert(pending);
@@ -619,6 +703,13 @@ impl<B: AbstractCircBuilder> CircList<B> {
         self.pending_circs.clear();
         self.open_circs.clear();
     }
+
+    /// Clear all pending circuits and open circuits, and return the open
+    /// circuits that we removed.
+    fn take_all_circuits(&mut self) -> Vec<B::Circ> {
+        self.pending_circs.clear();
+        self.open_circs.drain().map(|(_, e)| e.circ).collect()
+    }
 }
 
 /// Timing information for circuits that have been built but never used.
@@ -685,6 +776,13 @@ pub(crate) struct AbstractCircMgr<B: AbstractCircBuilder, R: Runtime> {
     ///
     /// Derived from the network parameters.
     unused_timing: sync::Mutex<UnusedTimings>,
+
+    /// A limit on how many circuits we build at once for usages where
+    /// [`AbstractCircBuilder::build_is_limited`] is true.
+    limited_builds: Arc<BuildLimiter>,
+
+    /// A rate limit on how often we start building new circuits.
+    build_rate: BuildRateLimiter,
 }
 
 /// An action to take in order to satisfy a request for a circuit.
@@ -711,6 +809,8 @@ impl<B: AbstractCircBuilder + 'static, R: Runtime> AbstractCircMgr<B, R> {
             circs,
             circuit_timing: circuit_timing.into(),
             unused_timing: sync::Mutex::new(unused_timing),
+            limited_builds: Arc::new(BuildLimiter::default()),
+            build_rate: BuildRateLimiter::default(),
         }
     }
 
@@ -1049,6 +1149,27 @@ impl<B: AbstractCircBuilder + 'static, R: Runtime> AbstractCircMgr<B, R> {
         Ok(Arc::clone(self).spawn_launch(usage, plan))
     }
 
+    /// Launch `n` new managed circuits for a target usage, without checking
+    /// whether any already exist or are pending, and wait for them all to
+    /// finish.
+    ///
+    /// Return the number of circuits that were built successfully.
+    pub(crate) async fn launch_n_by_usage(
+        self: &Arc<Self>,
+        usage: &<B::Spec as AbstractSpec>::Usage,
+        dir: DirInfo<'_>,
+        n: usize,
+    ) -> Result<usize> {
+        let receivers = (0..n)
+            .map(|_| self.launch_by_usage(usage, dir))
+            .collect::<Result<Vec<_>>>()?;
+        let outcomes = futures::future::join_all(receivers).await;
+        Ok(outcomes
+            .into_iter()
+            .filter(|outcome| matches!(outcome, Ok(Ok(_))))
+            .count())
+    }
+
     /// Spawn a background task to launch a circuit, and report its status.
     ///
     /// The `usage` argument is the usage from the original request that made
@@ -1058,7 +1179,7 @@ impl<B: AbstractCircBuilder + 'static, R: Runtime> AbstractCircMgr<B, R> {
         usage: &<B::Spec as AbstractSpec>::Usage,
         plan: CircBuildPlan<B>,
     ) -> Shared<oneshot::Receiver<PendResult<B>>> {
-        let _ = usage; // Currently unused.
+        let limited = self.builder.build_is_limited(usage);
         let CircBuildPlan {
             mut plan,
             sender,
@@ -1081,7 +1202,8 @@ impl<B: AbstractCircBuilder + 'static, R: Runtime> AbstractCircMgr<B, R> {
         runtime
             .spawn(async move {
                 let self_clone = Arc::clone(&self);
-                let future = AssertUnwindSafe(self_clone.do_launch(plan, pending)).catch_unwind();
+                let future =
+                    AssertUnwindSafe(self_clone.do_launch(plan, pending, limited)).catch_unwind();
                 let (new_spec, reply) = match future.await {
                     Ok(x) => x, // Success or regular failure
                     Err(e) => {
@@ -1125,11 +1247,40 @@ impl<B: AbstractCircBuilder + 'static, R: Runtime> AbstractCircMgr<B, R> {
 
     /// Run in the background to launch a circuit. Return a 2-tuple of the new
     /// circuit spec and the outcome that should be sent to the initiator.
+    ///
+    /// Before we begin, wait for our turn according to
+    /// [`CircuitTiming::build_rate_burst`].  If `limited` is true, wait until we are building few enough limited
+    /// circuits (according to [`CircuitTiming::max_concurrent_dir_builds`])
+    /// before we begin.
     async fn do_launch(
         self: Arc<Self>,
         plan: <B as AbstractCircBuilder>::Plan,
         pending: Arc<PendingEntry<B>>,
+        limited: bool,
     ) -> (Option<<B as AbstractCircBuilder>::Spec>, PendResult<B>) {
+        let circuit_timing = self.circuit_timing();
+        let now = self.runtime.now();
+        let start = self.build_rate.reserve(
+            now,
+            circuit_timing.build_rate_burst,
+            circuit_timing.build_rate_interval,
+        );
+        if let Some(wait) = start.checked_duration_since(now).filter(|d| !d.is_zero()) {
+            debug!(
+                "Waiting {:?} to start a new circuit, to respect rate limit",
+                wait
+            );
+            let sl = self.runtime.sleep(wait);
+            self.runtime.allow_one_advance(wait);
+            sl.await;
+        }
+
+        let _permit = if limited {
+            let max = circuit_timing.max_concurrent_dir_builds;
+            Some(self.limited_builds.acquire(max).await)
+        } else {
+            None
+        };
         let outcome = self.builder.build_circuit(plan).await;
 
         match outcome {
@@ -1150,11 +1301,25 @@ impl<B: AbstractCircBuilder + 'static, R: Runtime> AbstractCircMgr<B, R> {
                 //
                 // new_spec.restrict_mut(&usage_copy).unwrap();
                 let use_before = ExpirationInfo::new(exp_inst);
-                let open_ent = OpenEntry::new(new_spec.clone(), circ, use_before);
+                let open_ent =
+                    OpenEntry::new(new_spec.clone(), circ, use_before, self.runtime.now());
                 {
                     let mut list = self.circs.lock().expect("poisoned lock");
                     if list.circ_is_pending(&pending) {
                         list.add_open(open_ent);
+                        let circuit_timing = self.circuit_timing();
+                        let n_retired = list.retire_lru(
+                            &id,
+                            new_spec.usage_class(),
+                            circuit_timing.max_circs_per_usage,
+                            circuit_timing.max_circs,
+                        );
+                        if n_retired > 0 {
+                            debug!(
+                                "Retired {} least recently used circuits to stay within our limits",
+                                n_retired
+                            );
+                        }
                         // We drop our reference to 'pending' here:
                         // this should make all the weak references to
                         // the `PendingEntry` become dangling.
@@ -1188,6 +1353,30 @@ impl<B: AbstractCircBuilder + 'static, R: Runtime> AbstractCircMgr<B, R> {
         list.clear_all_circuits();
     }
 
+    /// Remove all circuits from this manager, as with
+    /// [`retire_all_circuits`](Self::retire_all_circuits), and return the
+    /// open circuits that we removed.
+    pub(crate) fn take_all_circs(&self) -> Vec<B::Circ> {
+        let mut list = self.circs.lock().expect("poisoned lock");
+        list.take_all_circuits()
+    }
+
+    /// Return a handle to every open circuit held by this circuit manager,
+    /// except for those that are due to expire.
+    ///
+    /// (Expired circuits stay in our list until the next call to
+    /// [`expire_circs`](Self::expire_circs), but we won't hand them out.)
+    pub(crate) fn list_circs(&self) -> Vec<B::Circ> {
+        let now = self.runtime.now();
+        let dirty_cutoff = now - self.circuit_timing().max_dirtiness;
+        let list = self.circs.lock().expect("poisoned lock");
+        list.open_circs
+            .values()
+            .filter(|e| !e.should_expire(now, dirty_cutoff))
+            .map(|e| e.circ.clone())
+            .collect()
+    }
+
     /// Expire circuits according to the rules in `config` and the
     /// current time `now`.
     ///
@@ -1213,6 +1402,29 @@ impl<B: AbstractCircBuilder + 'static, R: Runtime> AbstractCircMgr<B, R> {
         list.open_circs.len()
     }
 
+    /// Return the number of circuits held by this circuit manager, open or
+    /// pending, that we could use for `usage`.
+    ///
+    /// Open circuits that are due to expire, and pending circuits that have
+    /// already failed, aren't counted.
+    pub(crate) fn n_circs_supporting(&self, usage: &<B::Spec as AbstractSpec>::Usage) -> usize {
+        let now = self.runtime.now();
+        let dirty_cutoff = now - self.circuit_timing().max_dirtiness;
+        let list = self.circs.lock().expect("poisoned lock");
+        let n_open = list
+            .open_circs
+            .values()
+            .filter(|e| !e.should_expire(now, dirty_cutoff) && e.supports(usage))
+            .count();
+        let n_pending = list
+            .pending_circs
+            .iter()
+            .filter(|p| p.supports(usage))
+            .filter(|p| !matches!(p.receiver.peek(), Some(Err(_))))
+            .count();
+        n_open + n_pending
+    }
+
     /// Return the number of pending circuits tracked by this circuit manager.
     #[cfg(test)]
     pub(crate) fn n_pending_circs(&self) -> usize {
@@ -1303,7 +1515,7 @@ mod test {
     use super::*;
     use crate::usage::{ExitPolicy, SupportedCircUsage};
     use crate::{Error, StreamIsolation, TargetCircUsage, TargetPort};
-    use std::collections::BTreeSet;
+    use std::collections::{BTreeSet, HashSet};
     use std::sync::atomic::{self, AtomicUsize};
     use tor_error::bad_api_usage;
     use tor_netdir::testnet;
@@ -1376,6 +1588,15 @@ mod test {
             self.isolation_group = new_iso;
             Ok(())
         }
+        fn usage_class(&self) -> &'static str {
+            // As in `build_is_limited`, a circuit with no ports stands in
+            // for a directory circuit.
+            if self.ports.is_empty() {
+                "dir"
+            } else {
+                "exit"
+            }
+        }
     }
 
     impl FakeSpec {
@@ -1408,6 +1629,12 @@ mod test {
     struct FakeBuilder<RT: Runtime> {
         runtime: RT,
         script: sync::Mutex<HashMap<FakeSpec, Vec<FakeOp>>>,
+        /// How many circuits are we building right now?
+        n_building: AtomicUsize,
+        /// What's the largest number of circuits we've built at once?
+        max_building: AtomicUsize,
+        /// When did we start building each circuit?
+        started: sync::Mutex<Vec<Instant>>,
     }
 
     #[derive(Debug, Clone)]
@@ -1457,9 +1684,13 @@ mod test {
 
         async fn build_circuit(&self, plan: FakePlan) -> Result<(FakeSpec, FakeCirc)> {
             let op = plan.op;
+            self.started.lock().unwrap().push(self.runtime.now());
+            let n = self.n_building.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+            self.max_building.fetch_max(n, atomic::Ordering::SeqCst);
             let sl = self.runtime.sleep(FAKE_CIRC_DELAY);
             self.runtime.allow_one_advance(FAKE_CIRC_DELAY);
             sl.await;
+            self.n_building.fetch_sub(1, atomic::Ordering::SeqCst);
             match op {
                 FakeOp::Succeed => Ok((plan.spec, FakeCirc { id: FakeId::next() })),
                 FakeOp::WrongSpec(s) => Ok((s, FakeCirc { id: FakeId::next() })),
@@ -1481,6 +1712,11 @@ mod test {
             }
         }
 
+        fn build_is_limited(&self, spec: &FakeSpec) -> bool {
+            // A circuit with no ports stands in for a directory circuit.
+            spec.ports.is_empty()
+        }
+
         fn learning_timeouts(&self) -> bool {
             false
         }
@@ -1491,6 +1727,9 @@ mod test {
             FakeBuilder {
                 runtime: rt.clone(),
                 script: sync::Mutex::new(HashMap::new()),
+                n_building: AtomicUsize::new(0),
+                max_building: AtomicUsize::new(0),
+                started: sync::Mutex::new(Vec::new()),
             }
         }
 
@@ -1592,6 +1831,303 @@ mod test {
         });
     }
 
+    #[test]
+    fn take_all() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = MockSleepRuntime::new(rt);
+            let builder = FakeBuilder::new(&rt);
+            let mgr = Arc::new(AbstractCircMgr::new(
+                builder,
+                rt.clone(),
+                CircuitTiming::default(),
+            ));
+
+            // Build a couple of circuits that can't share.
+            let webports = FakeSpec::new(vec![80_u16, 443]);
+            let dnsport = FakeSpec::new(vec![53_u16]);
+            let c1 = rt.wait_for(mgr.get_or_launch(&webports, di())).await;
+            let c2 = rt.wait_for(mgr.get_or_launch(&dnsport, di())).await;
+            let (c1, c2) = (c1.unwrap(), c2.unwrap());
+            assert_ne!(c1.id(), c2.id());
+
+            let expected: HashSet<_> = vec![c1.id(), c2.id()].into_iter().collect();
+            let listed: HashSet<_> = mgr.list_circs().iter().map(|c| c.id()).collect();
+            assert_eq!(listed, expected);
+
+            // Take them all: we get both back, and keep none.
+            let taken: HashSet<_> = mgr.take_all_circs().iter().map(|c| c.id()).collect();
+            assert_eq!(taken, expected);
+            assert_eq!(mgr.n_circs(), 0);
+            assert!(mgr.list_circs().is_empty());
+
+            // Asking again builds new circuits.
+            let c3 = rt.wait_for(mgr.get_or_launch(&webports, di())).await;
+            let c4 = rt.wait_for(mgr.get_or_launch(&dnsport, di())).await;
+            let (c3, c4) = (c3.unwrap(), c4.unwrap());
+            for new in &[c3.id(), c4.id()] {
+                assert!(!expected.contains(new));
+            }
+            assert_eq!(mgr.n_circs(), 2);
+        });
+    }
+
+    #[test]
+    fn retire_all() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = MockSleepRuntime::new(rt);
+            let builder = FakeBuilder::new(&rt);
+            let mgr = Arc::new(AbstractCircMgr::new(
+                builder,
+                rt.clone(),
+                CircuitTiming::default(),
+            ));
+
+            // Before we retire anything, requests share a circuit.
+            let webports = FakeSpec::new(vec![80_u16, 443]);
+            let c1 = rt.wait_for(mgr.get_or_launch(&webports, di())).await;
+            let c2 = rt.wait_for(mgr.get_or_launch(&webports, di())).await;
+            let (c1, c2) = (c1.unwrap(), c2.unwrap());
+            assert!(FakeCirc::eq(&c1, &c2));
+
+            // Afterwards, we build a new one, and keep using that.
+            mgr.retire_all_circuits();
+            assert_eq!(mgr.n_circs(), 0);
+            let c3 = rt.wait_for(mgr.get_or_launch(&webports, di())).await;
+            let c4 = rt.wait_for(mgr.get_or_launch(&webports, di())).await;
+            let (c3, c4) = (c3.unwrap(), c4.unwrap());
+            assert!(!FakeCirc::eq(&c3, &c1));
+            assert!(FakeCirc::eq(&c3, &c4));
+            assert_eq!(mgr.n_circs(), 1);
+        });
+    }
+
+    #[test]
+    fn launch_n() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = MockSleepRuntime::new(rt);
+
+            let builder = FakeBuilder::new(&rt);
+            let mgr = Arc::new(AbstractCircMgr::new(
+                builder,
+                rt.clone(),
+                CircuitTiming::default(),
+            ));
+
+            let webports = FakeSpec::new(vec![80_u16, 443]);
+
+            // Nothing is launched if we ask for nothing.
+            let n = rt
+                .wait_for(mgr.launch_n_by_usage(&webports, di(), 0))
+                .await
+                .unwrap();
+            assert_eq!(n, 0);
+            assert_eq!(mgr.n_circs(), 0);
+
+            // We get two distinct circuits when we ask for two, even though
+            // either one would support the same usage.
+            let n = rt
+                .wait_for(mgr.launch_n_by_usage(&webports, di(), 2))
+                .await
+                .unwrap();
+            assert_eq!(n, 2);
+            assert_eq!(mgr.n_circs(), 2);
+            assert_eq!(mgr.n_pending_circs(), 0);
+
+            // Failed circuits aren't counted.
+            let dnsport = FakeSpec::new(vec![53_u16]);
+            mgr.peek_builder()
+                .set(dnsport.clone(), vec![FakeOp::Fail, FakeOp::Succeed]);
+            let n = rt
+                .wait_for(mgr.launch_n_by_usage(&dnsport, di(), 2))
+                .await
+                .unwrap();
+            assert_eq!(n, 1);
+            assert_eq!(mgr.n_circs(), 3);
+        });
+    }
+
+    #[test]
+    fn n_supporting() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = MockSleepRuntime::new(rt);
+
+            let builder = FakeBuilder::new(&rt);
+            let mgr = Arc::new(AbstractCircMgr::new(
+                builder,
+                rt.clone(),
+                CircuitTiming::default(),
+            ));
+
+            let webports = FakeSpec::new(vec![80_u16, 443]);
+            let dnsport = FakeSpec::new(vec![53_u16]);
+
+            // Circuits for some other usage don't count.
+            let n = rt
+                .wait_for(mgr.launch_n_by_usage(&dnsport, di(), 2))
+                .await
+                .unwrap();
+            assert_eq!(n, 2);
+            assert_eq!(mgr.n_circs_supporting(&webports), 0);
+            assert_eq!(mgr.n_circs_supporting(&dnsport), 2);
+
+            // Pending circuits count as soon as we launch them...
+            mgr.peek_builder()
+                .set(webports.clone(), vec![FakeOp::Succeed, FakeOp::Fail]);
+            let succeeding = mgr.launch_by_usage(&webports, di()).unwrap();
+            let failing = mgr.launch_by_usage(&webports, di()).unwrap();
+            assert_eq!(mgr.n_circs_supporting(&webports), 2);
+
+            // ... but not once they've failed.
+            let (succeeding, failing) = rt
+                .wait_for(futures::future::join(succeeding, failing))
+                .await;
+            assert!(matches!(succeeding, Ok(Ok(_))));
+            assert!(matches!(failing, Ok(Err(_))));
+            assert_eq!(mgr.n_circs_supporting(&webports), 1);
+            assert_eq!(mgr.n_circs_supporting(&dnsport), 2);
+        });
+    }
+
+    #[test]
+    fn limited_builds() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = MockSleepRuntime::new(rt);
+
+            let builder = FakeBuilder::new(&rt);
+            let timing = CircuitTiming::builder()
+                .max_concurrent_dir_builds(2)
+                .build()
+                .unwrap();
+            let mgr = Arc::new(AbstractCircMgr::new(builder, rt.clone(), timing));
+
+            // We never build more than two limited circuits at once...
+            let dirspec = FakeSpec::new(Vec::<u16>::new());
+            let n = rt
+                .wait_for(mgr.launch_n_by_usage(&dirspec, di(), 5))
+                .await
+                .unwrap();
+            assert_eq!(n, 5);
+            let max_building = &mgr.peek_builder().max_building;
+            assert_eq!(max_building.load(atomic::Ordering::SeqCst), 2);
+            assert_eq!(mgr.limited_builds.n_running(), 0);
+
+            // ... but other circuits aren't limited.
+            max_building.store(0, atomic::Ordering::SeqCst);
+            let webports = FakeSpec::new(vec![80_u16, 443]);
+            let n = rt
+                .wait_for(mgr.launch_n_by_usage(&webports, di(), 4))
+                .await
+                .unwrap();
+            assert_eq!(n, 4);
+            assert_eq!(max_building.load(atomic::Ordering::SeqCst), 4);
+        });
+    }
+
+    #[test]
+    fn rate_limited_builds() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = MockSleepRuntime::new(rt);
+
+            let builder = FakeBuilder::new(&rt);
+            let timing = CircuitTiming::builder()
+                .build_rate_burst(2)
+                .build_rate_interval(Duration::from_secs(1))
+                .build()
+                .unwrap();
+            let mgr = Arc::new(AbstractCircMgr::new(builder, rt.clone(), timing));
+
+            // Ask for five circuits at once: two start right away, and the
+            // rest are paced one per second.
+            let t0 = rt.now();
+            let webports = FakeSpec::new(vec![80_u16, 443]);
+            let n = rt
+                .wait_for(mgr.launch_n_by_usage(&webports, di(), 5))
+                .await
+                .unwrap();
+            assert_eq!(n, 5);
+            let offsets: Vec<_> = mgr
+                .peek_builder()
+                .started
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|t| t.duration_since(t0).as_secs())
+                .collect();
+            assert_eq!(offsets, vec![0, 0, 1, 2, 3]);
+
+            // The bucket is empty now, but a request that can reuse one of
+            // those circuits doesn't have to wait for it.
+            let c = mgr
+                .get_or_launch(&webports, di())
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+            assert_eq!(mgr.peek_builder().started.lock().unwrap().len(), 5);
+            assert!(mgr.list_circs().iter().any(|c2| c2.eq(&c)));
+        });
+    }
+
+    #[test]
+    fn pool_limits() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = MockSleepRuntime::new(rt);
+
+            let builder = FakeBuilder::new(&rt);
+            let timing = CircuitTiming::builder()
+                .max_circs_per_usage(2)
+                .max_circs(3)
+                .build()
+                .unwrap();
+            let mgr = Arc::new(AbstractCircMgr::new(builder, rt.clone(), timing));
+            let web = |group| FakeSpec::new(vec![80_u16]).isolated(group);
+            let has = |c: &FakeCirc| mgr.list_circs().iter().any(|c2| c2.eq(c));
+
+            // Each isolation group needs its own circuit, but we only keep
+            // two exit circuits: building the third retires the first.
+            let c1 = rt.wait_for(mgr.get_or_launch(&web(1), di())).await.unwrap();
+            rt.advance(Duration::from_secs(1)).await;
+            let c2 = rt.wait_for(mgr.get_or_launch(&web(2), di())).await.unwrap();
+            rt.advance(Duration::from_secs(1)).await;
+            let c3 = rt.wait_for(mgr.get_or_launch(&web(3), di())).await.unwrap();
+            assert_eq!(mgr.n_circs(), 2);
+            assert!(!has(&c1));
+            assert!(has(&c2));
+            assert!(has(&c3));
+
+            // It's the least recently _used_ circuit that goes, not the
+            // oldest: once we use c2 again, c3 is the one to retire.
+            rt.advance(Duration::from_secs(1)).await;
+            let c2_again = rt.wait_for(mgr.get_or_launch(&web(2), di())).await.unwrap();
+            assert!(c2_again.eq(&c2));
+            rt.advance(Duration::from_secs(1)).await;
+            let c4 = rt.wait_for(mgr.get_or_launch(&web(4), di())).await.unwrap();
+            assert_eq!(mgr.n_circs(), 2);
+            assert!(has(&c2));
+            assert!(!has(&c3));
+            assert!(has(&c4));
+
+            // Directory circuits have their own per-usage limit, but count
+            // against our overall limit, so adding one retires nothing,
+            // and adding a second retires the least recently used exit
+            // circuit.
+            let dirspec = FakeSpec::new(Vec::<u16>::new());
+            rt.advance(Duration::from_secs(1)).await;
+            let d1 = rt
+                .wait_for(mgr.launch_by_usage(&dirspec, di()).unwrap())
+                .await;
+            assert!(d1.unwrap().is_ok());
+            assert_eq!(mgr.n_circs(), 3);
+            rt.advance(Duration::from_secs(1)).await;
+            let d2 = rt
+                .wait_for(mgr.launch_by_usage(&dirspec, di()).unwrap())
+                .await;
+            assert!(d2.unwrap().is_ok());
+            assert_eq!(mgr.n_circs(), 3);
+            assert!(!has(&c2));
+            assert!(has(&c4));
+        });
+    }
+
     #[test]
     fn request_timeout() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
@@ -1932,6 +2468,12 @@ mod test {
             // it was not dirty until 15 seconds after the cutoff.
             let now = rt.now();
 
+            // We don't list the pop circuit any more, even before we've
+            // expired it.
+            let listed: Vec<_> = mgr.list_circs().iter().map(|c| c.id()).collect();
+            assert!(listed.contains(&imap1.id()));
+            assert!(!listed.contains(&pop1.id()));
+
             mgr.expire_circs(now);
 
             let (pop2, imap2) = rt
@@ -1980,8 +2522,9 @@ mod test {
     fn test_find_supported() {
         let (ep_none, ep_web, ep_full) = get_exit_policies();
         let fake_circ = FakeCirc { id: FakeId::next() };
+        let now = Instant::now();
         let expiration = ExpirationInfo::Unused {
-            use_before: Instant::now() + Duration::from_secs(60 * 60),
+            use_before: now + Duration::from_secs(60 * 60),
         };
 
         let mut entry_none = OpenEntry::new(
@@ -1991,6 +2534,7 @@ mod test {
             },
             fake_circ.clone(),
             expiration.clone(),
+            now,
         );
         let mut entry_none_c = entry_none.clone();
         let mut entry_web = OpenEntry::new(
@@ -2000,6 +2544,7 @@ mod test {
             },
             fake_circ.clone(),
             expiration.clone(),
+            now,
         );
         let mut entry_web_c = entry_web.clone();
         let mut entry_full = OpenEntry::new(
@@ -2009,12 +2554,15 @@ mod test {
             },
             fake_circ,
             expiration,
+            now,
         );
         let mut entry_full_c = entry_full.clone();
 
         let usage_web = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation: StreamIsolation::no_isolation(),
+            min_hops: 0,
+            prefer_low_latency: false,
         };
         let empty: Vec<&OpenEntry<SupportedCircUsage, FakeCirc>> = vec![];
 
@@ -0,0 +1,120 @@
+//! A limit on how many circuits of some kind can be under construction at
+//! once, to help implement [`AbstractCircMgr`](`super::AbstractCircMgr`).
+
+use futures::channel::oneshot;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// A counter of running circuit builds, along with a queue of builds that
+/// are waiting for their turn.
+///
+/// Unlike a semaphore, this type doesn't fix its limit when it's created:
+/// instead, every caller of [`BuildLimiter::acquire`] says what the limit
+/// currently is.  That way, reconfiguring the limit takes effect the next
+/// time somebody wants to build a circuit.
+#[derive(Debug, Default)]
+pub(super) struct BuildLimiter {
+    /// The mutable state of this limiter.
+    inner: Mutex<Inner>,
+}
+
+/// Mutable state for a [`BuildLimiter`].
+#[derive(Debug, Default)]
+struct Inner {
+    /// How many builds are currently running?
+    n_running: usize,
+    /// Senders to wake up the builds that are waiting for a turn, in the
+    /// order that they started waiting.
+    waiting: VecDeque<oneshot::Sender<()>>,
+}
+
+/// A token representing permission to build a single circuit.
+///
+/// When this is dropped, the next waiting build (if any) gets a chance to
+/// run.
+#[derive(Debug)]
+pub(super) struct BuildPermit {
+    /// The limiter that gave out this permit.
+    limiter: Arc<BuildLimiter>,
+}
+
+impl BuildLimiter {
+    /// Wait until fewer than `max` builds are running, and return a permit to
+    /// start another one.
+    ///
+    /// If `max` is 0, return a permit right away.
+    ///
+    /// If the returned future is dropped after it's been woken but before
+    /// it's been polled again, the next waiting build will not be woken
+    /// until another permit is dropped.  (Our circuit-building tasks never
+    /// drop this future early, so that's fine for now.)
+    pub(super) async fn acquire(self: &Arc<Self>, max: usize) -> BuildPermit {
+        loop {
+            let wait = {
+                let mut inner = self.inner.lock().expect("poisoned lock");
+                if max == 0 || inner.n_running < max {
+                    inner.n_running += 1;
+                    return BuildPermit {
+                        limiter: Arc::clone(self),
+                    };
+                }
+                let (send, recv) = oneshot::channel();
+                inner.waiting.push_back(send);
+                recv
+            };
+            // If this is cancelled, the limiter has been dropped, and
+            // there's nobody left to wait for: just try again.
+            let _ = wait.await;
+        }
+    }
+
+    /// Return the number of builds that are currently running.
+    #[cfg(test)]
+    pub(super) fn n_running(&self) -> usize {
+        self.inner.lock().expect("poisoned lock").n_running
+    }
+}
+
+impl Drop for BuildPermit {
+    fn drop(&mut self) {
+        let mut inner = self.limiter.inner.lock().expect("poisoned lock");
+        inner.n_running -= 1;
+        // Wake the first waiter that is still listening.
+        while let Some(send) = inner.waiting.pop_front() {
+            if send.send(()).is_ok() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn limit() {
+        let limiter = Arc::new(BuildLimiter::default());
+
+        // We can take two permits right away...
+        let p1 = limiter.acquire(2).now_or_never().unwrap();
+        let p2 = limiter.acquire(2).now_or_never().unwrap();
+        assert_eq!(limiter.n_running(), 2);
+
+        // ... but a third has to wait until one is released.
+        let mut waiting = Box::pin(limiter.acquire(2));
+        assert!((&mut waiting).now_or_never().is_none());
+        drop(p1);
+        let p3 = waiting.now_or_never().unwrap();
+        assert_eq!(limiter.n_running(), 2);
+
+        // With no limit, we never wait.
+        let p4 = limiter.acquire(0).now_or_never().unwrap();
+        assert_eq!(limiter.n_running(), 3);
+
+        drop((p2, p3, p4));
+        assert_eq!(limiter.n_running(), 0);
+    }
+}
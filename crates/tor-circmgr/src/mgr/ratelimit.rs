@@ -0,0 +1,89 @@
+//! A token-bucket rate limit on how often we launch new circuits, to help
+//! implement [`AbstractCircMgr`](`super::AbstractCircMgr`).
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A token bucket that paces the launch of new circuit builds.
+///
+/// The bucket holds up to `burst` tokens, and gains a token every
+/// `interval`.  Every new build takes a token; when the bucket is empty,
+/// builds queue up and start one per `interval`, in the order that they
+/// asked.
+///
+/// As with [`BuildLimiter`](super::limit::BuildLimiter), this type doesn't
+/// fix its parameters when it's created: every caller of
+/// [`BuildRateLimiter::reserve`] says what they currently are, so that
+/// reconfiguring the limit takes effect for the next build.
+#[derive(Debug, Default)]
+pub(super) struct BuildRateLimiter {
+    /// The time at which the bucket will next be full, if no more tokens
+    /// are taken.
+    ///
+    /// (This is the "theoretical arrival time" from the generic cell rate
+    /// algorithm, which is equivalent to a token bucket but needs only a
+    /// single timestamp of state.)
+    ///
+    /// None if we have never given out a token.
+    full_at: Mutex<Option<Instant>>,
+}
+
+impl BuildRateLimiter {
+    /// Reserve a token for a build that wants to start at `now`, and return
+    /// the time at which that build may start.
+    ///
+    /// If `burst` is 0, there is no limit, and we always return `now`.
+    ///
+    /// A reservation can't be given back: if the caller decides not to
+    /// build after all, later builds still wait for its turn to pass.
+    pub(super) fn reserve(&self, now: Instant, burst: u32, interval: Duration) -> Instant {
+        if burst == 0 {
+            return now;
+        }
+        let mut full_at = self.full_at.lock().expect("poisoned lock");
+        let full = match *full_at {
+            Some(t) if t > now => t,
+            _ => now,
+        };
+        // We can start as soon as the bucket is no more than `burst - 1`
+        // tokens short of full.
+        let tolerance = interval * (burst - 1);
+        let start = match full.checked_sub(tolerance) {
+            Some(t) if t > now => t,
+            _ => now,
+        };
+        *full_at = Some(full + interval);
+        start
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn rate() {
+        let limiter = BuildRateLimiter::default();
+        let now = Instant::now();
+        let sec = Duration::from_secs(1);
+
+        // With a burst of 2, two builds can start right away, and the rest
+        // start one per second.
+        let starts: Vec<_> = (0..5).map(|_| limiter.reserve(now, 2, sec)).collect();
+        assert_eq!(
+            starts,
+            vec![now, now, now + sec, now + 2 * sec, now + 3 * sec]
+        );
+
+        // Once the queue has drained and the bucket has refilled, we can
+        // burst again.
+        let later = now + 10 * sec;
+        assert_eq!(limiter.reserve(later, 2, sec), later);
+        assert_eq!(limiter.reserve(later, 2, sec), later);
+        assert_eq!(limiter.reserve(later, 2, sec), later + sec);
+
+        // With no limit, we never wait.
+        assert_eq!(limiter.reserve(later, 0, sec), later);
+    }
+}
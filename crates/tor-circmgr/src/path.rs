@@ -8,13 +8,117 @@ pub mod exitpath;
 
 use tor_error::bad_api_usage;
 use tor_linkspec::{OwnedChanTarget, OwnedCircTarget};
-use tor_netdir::{fallback::FallbackDir, Relay};
+use tor_netdir::{fallback::FallbackDir, Relay, SubnetConfig};
 
 use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
 
 use crate::usage::ExitPolicy;
 use crate::Result;
 
+/// The position of a hop within a multi-hop path.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum HopPosition {
+    /// The first hop: the guard.
+    Guard,
+    /// A hop between the guard and the exit.
+    Middle,
+    /// The last hop: the exit.
+    Exit,
+}
+
+/// A callback to accept or veto relays as they are chosen for a path.
+///
+/// The callback is given a candidate relay and the position where it would
+/// go; if it returns false, that relay is not used at that position, and
+/// another one is chosen instead.
+///
+/// The callback may be called many times for each path, and should be
+/// cheap.  If it vetoes every candidate for some position, building the
+/// path fails.
+pub type HopFilter = Arc<dyn Fn(&Relay<'_>, HopPosition) -> bool + Send + Sync>;
+
+/// A callback to look up the number of the autonomous system (AS) that a
+/// relay's address belongs to, or None if it isn't known.
+///
+/// Arti doesn't ship any AS data of its own: callers that want an AS-based
+/// [`DiversityPolicy`] have to provide it.
+pub type AsnLookup = Arc<dyn Fn(&Relay<'_>) -> Option<u32> + Send + Sync>;
+
+/// Extra rules for how different the relays in a path must be from one
+/// another.
+///
+/// These rules apply on top of the usual ones: no two relays in a path may
+/// be in the same family, or in the same subnet as configured by our
+/// [`PathConfig`](crate::PathConfig).  When we choose a relay that breaks
+/// one of them, we choose another instead.
+///
+/// The default policy adds no rules at all.
+#[derive(Clone, Default)]
+pub struct DiversityPolicy {
+    /// If present, no two relays may share a subnet of this size.
+    subnet_config: Option<SubnetConfig>,
+    /// If present, no two relays may be in the same autonomous system,
+    /// according to this lookup function.
+    asn_lookup: Option<AsnLookup>,
+}
+
+impl DiversityPolicy {
+    /// Return a new policy that adds no rules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require that no two relays in a path share an IPv4 prefix of
+    /// `ipv4_prefix` bits, or an IPv6 prefix of `ipv6_prefix` bits.
+    ///
+    /// For example, `subnet_prefixes(16, 32)` forbids two relays in the same
+    /// IPv4 /16.  This is only useful if it's stricter (shorter) than the
+    /// prefixes in our [`PathConfig`](crate::PathConfig).
+    pub fn subnet_prefixes(&mut self, ipv4_prefix: u8, ipv6_prefix: u8) -> &mut Self {
+        self.subnet_config = Some(SubnetConfig::new(ipv4_prefix, ipv6_prefix));
+        self
+    }
+
+    /// Require that no two relays in a path belong to the same autonomous
+    /// system, as reported by `lookup`.
+    ///
+    /// Relays for which `lookup` returns None are not restricted by this
+    /// rule.
+    pub fn distinct_asns(&mut self, lookup: AsnLookup) -> &mut Self {
+        self.asn_lookup = Some(lookup);
+        self
+    }
+
+    /// Return true if this policy allows `a` and `b` in the same path.
+    pub(crate) fn allows_together(&self, a: &Relay<'_>, b: &Relay<'_>) -> bool {
+        if let Some(subnet_config) = &self.subnet_config {
+            if a.in_same_subnet(b, subnet_config) {
+                return false;
+            }
+        }
+        if let Some(lookup) = &self.asn_lookup {
+            if let (Some(asn_a), Some(asn_b)) = (lookup(a), lookup(b)) {
+                if asn_a == asn_b {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+impl fmt::Debug for DiversityPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiversityPolicy")
+            .field("subnet_config", &self.subnet_config)
+            .field("asn_lookup", &self.asn_lookup.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
+
 /// A list of Tor relays through the network.
 pub struct TorPath<'a> {
     /// The inner TorPath state.
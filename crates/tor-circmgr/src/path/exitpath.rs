@@ -1,14 +1,20 @@
 //! Code for building paths to an exit relay.
 
-use super::TorPath;
+use super::{DiversityPolicy, HopFilter, HopPosition, TorPath};
+use crate::latency::RelayLatencies;
 use crate::{DirInfo, Error, PathConfig, Result, TargetPort};
 use rand::Rng;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tor_error::{bad_api_usage, internal};
 use tor_guardmgr::{GuardMgr, GuardMonitor, GuardUsable};
 use tor_netdir::{NetDir, Relay, SubnetConfig, WeightRole};
 use tor_rtcompat::Runtime;
 
+/// How many guards will we reject because of a [`HopFilter`] or a
+/// [`DiversityPolicy`] before we give up on building a path?
+const MAX_VETOED_GUARDS: usize = 8;
+
 /// Internal representation of PathBuilder.
 enum ExitPathBuilderInner<'a> {
     /// Request a path that allows exit to the given `TargetPort]`s.
@@ -30,6 +36,13 @@ enum ExitPathBuilderInner<'a> {
 pub struct ExitPathBuilder<'a> {
     /// The inner ExitPathBuilder state.
     inner: ExitPathBuilderInner<'a>,
+    /// A callback that can veto our choice of relay for each hop, if any.
+    hop_filter: Option<HopFilter>,
+    /// Extra rules, if any, for how different our relays must be.
+    diversity: Option<DiversityPolicy>,
+    /// If present, a table of relay latencies that we use to prefer faster
+    /// middle and exit relays.
+    latencies: Option<Arc<RelayLatencies>>,
 }
 
 impl<'a> ExitPathBuilder<'a> {
@@ -44,6 +57,9 @@ impl<'a> ExitPathBuilder<'a> {
         }
         Self {
             inner: ExitPathBuilderInner::WantsPorts(ports),
+            hop_filter: None,
+            diversity: None,
+            latencies: None,
         }
     }
 
@@ -52,6 +68,9 @@ impl<'a> ExitPathBuilder<'a> {
     pub fn from_chosen_exit(exit_relay: Relay<'a>) -> Self {
         Self {
             inner: ExitPathBuilderInner::ChosenExit(exit_relay),
+            hop_filter: None,
+            diversity: None,
+            latencies: None,
         }
     }
 
@@ -59,6 +78,9 @@ impl<'a> ExitPathBuilder<'a> {
     pub fn for_any_exit() -> Self {
         Self {
             inner: ExitPathBuilderInner::AnyExit { strict: true },
+            hop_filter: None,
+            diversity: None,
+            latencies: None,
         }
     }
 
@@ -67,6 +89,83 @@ impl<'a> ExitPathBuilder<'a> {
     pub(crate) fn for_timeout_testing() -> Self {
         Self {
             inner: ExitPathBuilderInner::AnyExit { strict: false },
+            hop_filter: None,
+            diversity: None,
+            latencies: None,
+        }
+    }
+
+    /// Use `filter` to accept or veto each relay that this builder
+    /// considers for the path.
+    ///
+    /// If `filter` rejects a chosen exit relay, or rejects every candidate
+    /// for some position, [`pick_path`](Self::pick_path) returns an error.
+    pub fn set_hop_filter(&mut self, filter: HopFilter) -> &mut Self {
+        self.hop_filter = Some(filter);
+        self
+    }
+
+    /// Require the relays in the path to follow the extra rules in `policy`.
+    ///
+    /// If the rules can't be satisfied (for example, because they rule out
+    /// every possible middle relay), [`pick_path`](Self::pick_path) returns
+    /// an error.
+    pub fn set_diversity_policy(&mut self, policy: DiversityPolicy) -> &mut Self {
+        self.diversity = Some(policy);
+        self
+    }
+
+    /// Use `latencies` to prefer middle and exit relays that have answered
+    /// us quickly in the past.
+    ///
+    /// This is meant for circuits that will carry interactive traffic.
+    pub(crate) fn prefer_low_latency(&mut self, latencies: Arc<RelayLatencies>) -> &mut Self {
+        self.latencies = Some(latencies);
+        self
+    }
+
+    /// Pick a relay for `role` from `netdir` that satisfies `usable`,
+    /// biased toward lower latency if we've been asked to prefer it.
+    fn pick_relay<R, P>(
+        &self,
+        rng: &mut R,
+        netdir: &'a NetDir,
+        role: WeightRole,
+        usable: P,
+    ) -> Option<Relay<'a>>
+    where
+        R: Rng,
+        P: Fn(&Relay<'a>) -> bool,
+    {
+        match &self.latencies {
+            Some(latencies) => latencies.pick_relay(rng, netdir, role, usable),
+            None => netdir.pick_relay(rng, role, usable),
+        }
+    }
+
+    /// Return true if our hop filter (if any) allows `relay` at `pos`.
+    fn allows(&self, relay: &Relay<'_>, pos: HopPosition) -> bool {
+        match &self.hop_filter {
+            Some(f) => f(relay, pos),
+            None => true,
+        }
+    }
+
+    /// Return true if `a` and `b` may appear together in our path, according
+    /// to `config` and our diversity policy (if any).
+    fn can_share(&self, a: &Relay<'_>, b: &Relay<'_>, config: SubnetConfig) -> bool {
+        relays_can_share_circuit(a, b, config)
+            && match &self.diversity {
+                Some(policy) => policy.allows_together(a, b),
+                None => true,
+            }
+    }
+
+    /// As [`can_share`](Self::can_share), but allow anything if `b` is None.
+    fn can_share_opt(&self, a: &Relay<'_>, b: Option<&Relay<'_>>, config: SubnetConfig) -> bool {
+        match b {
+            Some(b) => self.can_share(a, b, config),
+            None => true,
         }
     }
 
@@ -80,8 +179,10 @@ impl<'a> ExitPathBuilder<'a> {
     ) -> Result<Relay<'a>> {
         match &self.inner {
             ExitPathBuilderInner::AnyExit { strict } => {
-                let exit = netdir.pick_relay(rng, WeightRole::Exit, |r| {
-                    r.policies_allow_some_port() && relays_can_share_circuit_opt(r, guard, config)
+                let exit = self.pick_relay(rng, netdir, WeightRole::Exit, |r| {
+                    r.policies_allow_some_port()
+                        && self.can_share_opt(r, guard, config)
+                        && self.allows(r, HopPosition::Exit)
                 });
                 match (exit, strict) {
                     (Some(exit), _) => return Ok(exit),
@@ -91,24 +192,27 @@ impl<'a> ExitPathBuilder<'a> {
 
                 // Non-strict case.  Arguably this doesn't belong in
                 // ExitPathBuilder.
-                netdir
-                    .pick_relay(rng, WeightRole::Exit, |r| {
-                        relays_can_share_circuit_opt(r, guard, config)
-                    })
-                    .ok_or_else(|| Error::NoExit("No relay found".into()))
+                self.pick_relay(rng, netdir, WeightRole::Exit, |r| {
+                    self.can_share_opt(r, guard, config) && self.allows(r, HopPosition::Exit)
+                })
+                .ok_or_else(|| Error::NoExit("No relay found".into()))
             }
 
-            ExitPathBuilderInner::WantsPorts(wantports) => Ok(netdir
-                .pick_relay(rng, WeightRole::Exit, |r| {
-                    relays_can_share_circuit_opt(r, guard, config)
+            ExitPathBuilderInner::WantsPorts(wantports) => Ok(self
+                .pick_relay(rng, netdir, WeightRole::Exit, |r| {
+                    self.can_share_opt(r, guard, config)
                         && wantports.iter().all(|p| p.is_supported_by(r))
+                        && self.allows(r, HopPosition::Exit)
                 })
                 .ok_or_else(|| Error::NoExit("No exit relay found".into()))?),
 
             ExitPathBuilderInner::ChosenExit(exit_relay) => {
                 // NOTE that this doesn't check
-                // relays_can_share_circuit_opt(exit_relay,guard).  we
-                // already did that, sort of, in pick_path.
+                // can_share_opt(exit_relay, guard).  We already did that
+                // when we chose the guard, in pick_path.
+                if !self.allows(exit_relay, HopPosition::Exit) {
+                    return Err(Error::NoExit("Chosen exit relay was vetoed".into()));
+                }
                 Ok(exit_relay.clone())
             }
         }
@@ -161,14 +265,34 @@ impl<'a> ExitPathBuilder<'a> {
                     family.extend(netdir.known_family_members(exit_relay).map(|r| *r.id()));
                     b.push_restriction(tor_guardmgr::GuardRestriction::AvoidAllIds(family));
                 }
-                let guard_usage = b.build().expect("Failed while building guard usage!");
-                let (guard, mut mon, usable) = guardmgr.select_guard(guard_usage, Some(netdir))?;
-                let guard = guard.get_relay(netdir).ok_or_else(|| {
-                    internal!(
-                        "Somehow the guardmgr gave us an unlisted guard {:?}!",
-                        guard
-                    )
-                })?;
+                // If we have a hop filter, keep asking for guards (avoiding
+                // the ones it vetoed) until it accepts one, or until we've
+                // tried too many times.
+                let mut vetoed = 0;
+                let (guard, mut mon, usable) = loop {
+                    let guard_usage = b.build().expect("Failed while building guard usage!");
+                    let (guard, mon, usable) = guardmgr.select_guard(guard_usage, Some(netdir))?;
+                    let relay = guard.get_relay(netdir).ok_or_else(|| {
+                        internal!(
+                            "Somehow the guardmgr gave us an unlisted guard {:?}!",
+                            guard
+                        )
+                    })?;
+                    if self.allows(&relay, HopPosition::Guard)
+                        && self.can_share_opt(&relay, chosen_exit, subnet_config)
+                    {
+                        break (relay, mon, usable);
+                    }
+                    // We never tried this guard, so it shouldn't be blamed.
+                    mon.attempt_abandoned();
+                    vetoed += 1;
+                    if vetoed >= MAX_VETOED_GUARDS {
+                        return Err(Error::NoPath(
+                            "Every guard we tried was vetoed or too close to the exit".into(),
+                        ));
+                    }
+                    b.push_restriction(tor_guardmgr::GuardRestriction::AvoidId(*relay.id()));
+                };
                 if !path_is_fully_random {
                     // We were given a specific exit relay to use, and
                     // the choice of exit relay might be forced by
@@ -184,7 +308,8 @@ impl<'a> ExitPathBuilder<'a> {
                 let entry = netdir
                     .pick_relay(rng, WeightRole::Guard, |r| {
                         r.is_flagged_guard()
-                            && relays_can_share_circuit_opt(r, chosen_exit, subnet_config)
+                            && self.can_share_opt(r, chosen_exit, subnet_config)
+                            && self.allows(r, HopPosition::Guard)
                     })
                     .ok_or_else(|| Error::NoPath("No suitable  entry relay found".into()))?;
                 (entry, None, None)
@@ -193,10 +318,11 @@ impl<'a> ExitPathBuilder<'a> {
 
         let exit = self.pick_exit(rng, netdir, Some(&guard), subnet_config)?;
 
-        let middle = netdir
-            .pick_relay(rng, WeightRole::Middle, |r| {
-                relays_can_share_circuit(r, &exit, subnet_config)
-                    && relays_can_share_circuit(r, &guard, subnet_config)
+        let middle = self
+            .pick_relay(rng, netdir, WeightRole::Middle, |r| {
+                self.can_share(r, &exit, subnet_config)
+                    && self.can_share(r, &guard, subnet_config)
+                    && self.allows(r, HopPosition::Middle)
             })
             .ok_or_else(|| Error::NoPath("No suitable middle relay found".into()))?;
 
@@ -213,14 +339,6 @@ fn relays_can_share_circuit(a: &Relay<'_>, b: &Relay<'_>, subnet_config: SubnetC
     !a.in_same_family(b) && !a.in_same_subnet(b, &subnet_config)
 }
 
-/// Helper: wraps relays_can_share_circuit but takes an option.
-fn relays_can_share_circuit_opt(r1: &Relay<'_>, r2: Option<&Relay<'_>>, c: SubnetConfig) -> bool {
-    match r2 {
-        Some(r2) => relays_can_share_circuit(r1, r2, c),
-        None => true,
-    }
-}
-
 #[cfg(test)]
 mod test {
     #![allow(clippy::unwrap_used)]
@@ -324,6 +442,130 @@ mod test {
         }
     }
 
+    #[test]
+    fn hop_filter() {
+        let mut rng = rand::thread_rng();
+        let netdir = testnet::construct_netdir()
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+        let dirinfo = (&netdir).into();
+        let guards: OptDummyGuardMgr<'_> = None;
+        let config = PathConfig::default();
+        let vetoed: tor_llcrypto::pk::ed25519::Ed25519Identity = [0x20; 32].into();
+
+        // Veto one relay, but only as an exit.
+        let filter: HopFilter = std::sync::Arc::new(move |r: &Relay<'_>, pos: HopPosition| {
+            !(pos == HopPosition::Exit && r.id() == &vetoed)
+        });
+        let mut seen_elsewhere = false;
+        for _ in 0..1000 {
+            let (path, _, _) = ExitPathBuilder::for_any_exit()
+                .set_hop_filter(filter.clone())
+                .pick_path(&mut rng, dirinfo, guards, &config)
+                .unwrap();
+            if let TorPathInner::Path(p) = path.inner {
+                assert_exit_path_ok(&p[..]);
+                assert_ne!(p[2].id(), &vetoed);
+                seen_elsewhere |= p[..2].iter().any(|r| r.id() == &vetoed);
+            } else {
+                panic!("Generated the wrong kind of path");
+            }
+        }
+        assert!(seen_elsewhere);
+
+        // A vetoed chosen exit is an error.
+        let chosen = netdir.by_id(&vetoed).unwrap();
+        let outcome = ExitPathBuilder::from_chosen_exit(chosen)
+            .set_hop_filter(filter)
+            .pick_path(&mut rng, dirinfo, guards, &config);
+        assert!(matches!(outcome, Err(Error::NoExit(_))));
+
+        // Vetoing everything is an error, not an infinite loop.
+        for pos in [HopPosition::Guard, HopPosition::Middle, HopPosition::Exit] {
+            let filter: HopFilter =
+                std::sync::Arc::new(move |_: &Relay<'_>, p: HopPosition| p != pos);
+            let outcome = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(443)])
+                .set_hop_filter(filter)
+                .pick_path(&mut rng, dirinfo, guards, &config);
+            assert!(outcome.is_err());
+        }
+    }
+
+    #[test]
+    fn diversity_policy() {
+        use crate::path::DiversityPolicy;
+        let mut rng = rand::thread_rng();
+        let netdir = testnet::construct_netdir()
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+        let dirinfo = (&netdir).into();
+        let guards: OptDummyGuardMgr<'_> = None;
+        // Turn off the usual subnet rules, so we can see the effect of the
+        // policy by itself.
+        let config = PathConfig::builder()
+            .ipv4_subnet_family_prefix(33)
+            .ipv6_subnet_family_prefix(129)
+            .build()
+            .unwrap();
+        let slash16 = |r: &Relay<'_>| {
+            let addr = r.rs().orport_addrs().next().unwrap().ip();
+            match addr {
+                std::net::IpAddr::V4(a) => u32::from(a) >> 16,
+                std::net::IpAddr::V6(_) => panic!("unexpected IPv6 address"),
+            }
+        };
+        let n_distinct = |p: &[Relay<'_>], f: &dyn Fn(&Relay<'_>) -> u32| {
+            p.iter().map(f).collect::<HashSet<_>>().len()
+        };
+
+        // Without a policy, we sometimes pick two relays in the same /16.
+        let mut seen_shared = false;
+        for _ in 0..1000 {
+            let (path, _, _) = ExitPathBuilder::for_any_exit()
+                .pick_path(&mut rng, dirinfo, guards, &config)
+                .unwrap();
+            if let TorPathInner::Path(p) = path.inner {
+                seen_shared |= n_distinct(&p[..], &slash16) < 3;
+            }
+        }
+        assert!(seen_shared);
+
+        // With a strict /16 rule, we never do.
+        let mut policy = DiversityPolicy::new();
+        policy.subnet_prefixes(16, 32);
+        for _ in 0..1000 {
+            let (path, _, _) = ExitPathBuilder::for_any_exit()
+                .set_diversity_policy(policy.clone())
+                .pick_path(&mut rng, dirinfo, guards, &config)
+                .unwrap();
+            if let TorPathInner::Path(p) = path.inner {
+                assert_exit_path_ok(&p[..]);
+                assert_eq!(n_distinct(&p[..], &slash16), 3);
+            } else {
+                panic!("Generated the wrong kind of path");
+            }
+        }
+
+        // Pretend that relays are in one of three autonomous systems, and
+        // make sure we never pick two in the same one.
+        let asn = |r: &Relay<'_>| u32::from(r.rsa_id().as_bytes()[0] % 3);
+        let mut policy = DiversityPolicy::new();
+        policy.distinct_asns(std::sync::Arc::new(move |r: &Relay<'_>| Some(asn(r))));
+        for _ in 0..1000 {
+            let (path, _, _) = ExitPathBuilder::for_any_exit()
+                .set_diversity_policy(policy.clone())
+                .pick_path(&mut rng, dirinfo, guards, &config)
+                .unwrap();
+            if let TorPathInner::Path(p) = path.inner {
+                assert_eq!(n_distinct(&p[..], &asn), 3);
+            } else {
+                panic!("Generated the wrong kind of path");
+            }
+        }
+    }
+
     #[test]
     fn empty_path() {
         // This shouldn't actually be constructable IRL, but let's test to
@@ -425,6 +667,10 @@ mod test {
                 .unwrap();
             let exit_relay = netdir.by_id(distinct_exit.iter().next().unwrap()).unwrap();
 
+            // The guard we've been using is our best primary guard.
+            let primary = guards.primary_guards();
+            assert_eq!(primary[0].ed_identity(), guard_relay.ed_identity());
+
             // Now we'll try a forced exit that is not the same same as our
             // actual guard.
             let (path, mon, usable) = ExitPathBuilder::from_chosen_exit(exit_relay.clone())
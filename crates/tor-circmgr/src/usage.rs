@@ -8,14 +8,22 @@ use std::sync::Arc;
 use tor_error::bad_api_usage;
 use tracing::debug;
 
-use crate::path::{dirpath::DirPathBuilder, exitpath::ExitPathBuilder, TorPath};
+use crate::build::BuildUsage;
+use crate::latency::RelayLatencies;
+use crate::path::{
+    dirpath::DirPathBuilder, exitpath::ExitPathBuilder, DiversityPolicy, HopFilter, TorPath,
+};
 use tor_guardmgr::{GuardMgr, GuardMonitor, GuardUsable};
 use tor_netdir::Relay;
 use tor_netdoc::types::policy::PortPolicy;
 use tor_rtcompat::Runtime;
 
 use crate::mgr::{abstract_spec_find_supported, AbstractCirc, OpenEntry};
-use crate::Result;
+use crate::{Error, Result};
+
+/// The number of hops in every exit circuit that we build: a guard, a middle
+/// relay, and an exit.
+const EXIT_PATH_LEN: usize = 3;
 
 /// An exit policy, as supported by the last hop of a circuit.
 #[derive(Clone, Debug, PartialEq)]
@@ -26,6 +34,30 @@ pub(crate) struct ExitPolicy {
     v6: Arc<PortPolicy>,
 }
 
+/// How urgently we need a directory circuit.
+///
+/// Directory circuits with different priorities are never shared, so that
+/// background directory traffic can't delay a directory request that something
+/// is waiting on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum DirPriority {
+    /// We need this directory information in order to make progress: for
+    /// example, because we're bootstrapping.
+    Foreground,
+    /// We're refreshing directory information in the background, while we
+    /// already have a usable directory.
+    ///
+    /// We launch fewer circuits at once for requests with this priority.
+    Background,
+}
+
+impl Default for DirPriority {
+    fn default() -> Self {
+        DirPriority::Foreground
+    }
+}
+
 /// A port that we want to connect to as a client.
 ///
 /// Ordinarily, this is a TCP port, plus a flag to indicate whether we
@@ -252,7 +284,10 @@ impl ExitPolicy {
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) enum TargetCircUsage {
     /// Use for BEGINDIR-based non-anonymous directory connections
-    Dir,
+    Dir {
+        /// How urgently we need the directory information.
+        priority: DirPriority,
+    },
     /// Use to exit to one or more ports.
     Exit {
         /// List of ports the circuit has to allow.
@@ -262,6 +297,18 @@ pub(crate) enum TargetCircUsage {
         ports: Vec<TargetPort>,
         /// Isolation group the circuit shall be part of
         isolation: StreamIsolation,
+        /// The fewest hops that the circuit may have.
+        ///
+        /// We don't use this when we choose a path: every exit circuit that
+        /// we build has three hops, so any value up to 3 is always
+        /// satisfied, and any higher value never is.
+        min_hops: usize,
+        /// If true, this circuit is for interactive traffic, so we should
+        /// prefer relays that have answered us quickly in the past.
+        ///
+        /// Circuits for bulk traffic don't set this.  An existing circuit is
+        /// usable regardless of this preference.
+        prefer_low_latency: bool,
     },
     /// For a circuit is only used for the purpose of building it.
     TimeoutTesting,
@@ -289,7 +336,10 @@ pub(crate) enum TargetCircUsage {
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) enum SupportedCircUsage {
     /// Usable for BEGINDIR-based non-anonymous directory connections
-    Dir,
+    Dir {
+        /// The priority of the directory requests that this circuit is for.
+        priority: DirPriority,
+    },
     /// Usable to exit to a set of ports.
     Exit {
         /// Exit policy of the circuit
@@ -302,15 +352,43 @@ pub(crate) enum SupportedCircUsage {
     NoUsage,
 }
 
+/// The rules, beyond what a [`TargetCircUsage`] itself requires, that we
+/// follow when we pick a path for it.
+#[derive(Clone, Default)]
+pub(crate) struct PathRules {
+    /// If present, a callback that may veto relays for multi-hop paths.
+    pub(crate) hop_filter: Option<HopFilter>,
+    /// If present, extra rules that multi-hop paths must follow.
+    pub(crate) diversity: Option<DiversityPolicy>,
+    /// If present, our latency estimates, which we use to prefer faster
+    /// relays for usages that want low latency.
+    pub(crate) latencies: Option<Arc<RelayLatencies>>,
+}
+
+/// Helper: install the hop filter and diversity policy (if any) from
+/// `rules` on `builder`.
+fn with_path_rules<'a>(mut builder: ExitPathBuilder<'a>, rules: &PathRules) -> ExitPathBuilder<'a> {
+    if let Some(f) = &rules.hop_filter {
+        builder.set_hop_filter(Arc::clone(f));
+    }
+    if let Some(policy) = &rules.diversity {
+        builder.set_diversity_policy(policy.clone());
+    }
+    builder
+}
+
 impl TargetCircUsage {
     /// Construct path for a given circuit purpose; return it and the
     /// usage that it _actually_ supports.
+    ///
+    /// Multi-hop paths follow `rules`.
     pub(crate) fn build_path<'a, R: Rng, RT: Runtime>(
         &self,
         rng: &mut R,
         netdir: crate::DirInfo<'a>,
         guards: Option<&GuardMgr<RT>>,
         config: &crate::PathConfig,
+        rules: &PathRules,
     ) -> Result<(
         TorPath<'a>,
         SupportedCircUsage,
@@ -318,14 +396,24 @@ impl TargetCircUsage {
         Option<GuardUsable>,
     )> {
         match self {
-            TargetCircUsage::Dir => {
+            TargetCircUsage::Dir { priority } => {
                 let (path, mon, usable) = DirPathBuilder::new().pick_path(rng, netdir, guards)?;
-                Ok((path, SupportedCircUsage::Dir, mon, usable))
+                Ok((
+                    path,
+                    SupportedCircUsage::Dir {
+                        priority: *priority,
+                    },
+                    mon,
+                    usable,
+                ))
             }
             TargetCircUsage::Preemptive { port, .. } => {
                 // FIXME(eta): this is copypasta from `TargetCircUsage::Exit`.
-                let (path, mon, usable) = ExitPathBuilder::from_target_ports(port.iter().copied())
-                    .pick_path(rng, netdir, guards, config)?;
+                let (path, mon, usable) = with_path_rules(
+                    ExitPathBuilder::from_target_ports(port.iter().copied()),
+                    rules,
+                )
+                .pick_path(rng, netdir, guards, config)?;
                 let policy = path
                     .exit_policy()
                     .expect("ExitPathBuilder gave us a one-hop circuit?");
@@ -342,9 +430,21 @@ impl TargetCircUsage {
             TargetCircUsage::Exit {
                 ports: p,
                 isolation,
+                min_hops,
+                prefer_low_latency,
             } => {
-                let (path, mon, usable) = ExitPathBuilder::from_target_ports(p.clone())
-                    .pick_path(rng, netdir, guards, config)?;
+                if *min_hops > EXIT_PATH_LEN {
+                    return Err(Error::NoPath(format!(
+                        "Wanted a circuit with at least {} hops, but we only build exit circuits with {}",
+                        min_hops, EXIT_PATH_LEN
+                    )));
+                }
+                let mut builder =
+                    with_path_rules(ExitPathBuilder::from_target_ports(p.clone()), rules);
+                if let (true, Some(latencies)) = (*prefer_low_latency, &rules.latencies) {
+                    builder.prefer_low_latency(Arc::clone(latencies));
+                }
+                let (path, mon, usable) = builder.pick_path(rng, netdir, guards, config)?;
                 let policy = path
                     .exit_policy()
                     .expect("ExitPathBuilder gave us a one-hop circuit?");
@@ -359,8 +459,9 @@ impl TargetCircUsage {
                 ))
             }
             TargetCircUsage::TimeoutTesting => {
-                let (path, mon, usable) = ExitPathBuilder::for_timeout_testing()
-                    .pick_path(rng, netdir, guards, config)?;
+                let (path, mon, usable) =
+                    with_path_rules(ExitPathBuilder::for_timeout_testing(), rules)
+                        .pick_path(rng, netdir, guards, config)?;
                 let policy = path.exit_policy();
                 let usage = match policy {
                     Some(policy) if policy.allows_some_port() => SupportedCircUsage::Exit {
@@ -374,6 +475,17 @@ impl TargetCircUsage {
             }
         }
     }
+
+    /// Return the kind of circuit that we're building for this usage, for
+    /// reporting to a [`BuildMetrics`](crate::build::BuildMetrics) sink.
+    pub(crate) fn build_usage(&self) -> BuildUsage {
+        match self {
+            TargetCircUsage::Dir { .. } => BuildUsage::Dir,
+            TargetCircUsage::Exit { .. } => BuildUsage::Exit,
+            TargetCircUsage::TimeoutTesting => BuildUsage::TimeoutTesting,
+            TargetCircUsage::Preemptive { .. } => BuildUsage::Preemptive,
+        }
+    }
 }
 
 impl crate::mgr::AbstractSpec for SupportedCircUsage {
@@ -382,7 +494,7 @@ impl crate::mgr::AbstractSpec for SupportedCircUsage {
     fn supports(&self, target: &TargetCircUsage) -> bool {
         use SupportedCircUsage::*;
         match (self, target) {
-            (Dir, TargetCircUsage::Dir) => true,
+            (Dir { priority: p1 }, TargetCircUsage::Dir { priority: p2 }) => p1 == p2,
             (
                 Exit {
                     policy: p1,
@@ -391,9 +503,13 @@ impl crate::mgr::AbstractSpec for SupportedCircUsage {
                 TargetCircUsage::Exit {
                     ports: p2,
                     isolation: i2,
+                    min_hops,
+                    ..
                 },
             ) => {
-                i1.map(|i1| i1.may_share_circuit(i2)).unwrap_or(true)
+                // Every exit circuit we build has EXIT_PATH_LEN hops.
+                *min_hops <= EXIT_PATH_LEN
+                    && i1.map(|i1| i1.may_share_circuit(i2)).unwrap_or(true)
                     && p2.iter().all(|port| p1.allows_port(*port))
             }
             (Exit { policy, isolation }, TargetCircUsage::Preemptive { port, .. }) => {
@@ -417,7 +533,7 @@ impl crate::mgr::AbstractSpec for SupportedCircUsage {
         use SupportedCircUsage::*;
 
         match (self, usage) {
-            (Dir, TargetCircUsage::Dir) => Ok(()),
+            (Dir { priority: p1 }, TargetCircUsage::Dir { priority: p2 }) if p1 == p2 => Ok(()),
             // This usage is only used to create circuits preemptively, and doesn't actually
             // correspond to any streams; accordingly, we don't need to modify the circuit's
             // acceptable usage at all.
@@ -442,6 +558,15 @@ impl crate::mgr::AbstractSpec for SupportedCircUsage {
         }
     }
 
+    fn usage_class(&self) -> &'static str {
+        use SupportedCircUsage::*;
+        match self {
+            Dir { .. } => "dir",
+            Exit { .. } => "exit",
+            NoUsage => "none",
+        }
+    }
+
     fn find_supported<'a, 'b, C: AbstractCirc>(
         list: impl Iterator<Item = &'b mut OpenEntry<Self, C>>,
         usage: &TargetCircUsage,
@@ -475,6 +600,7 @@ mod test {
     use crate::path::OwnedPath;
     use crate::test::OptDummyGuardMgr;
     use std::convert::TryFrom;
+    use std::time::Duration;
     use tor_linkspec::ChanTarget;
     use tor_netdir::testnet;
 
@@ -556,8 +682,18 @@ mod test {
             .build()
             .unwrap();
 
-        let supp_dir = SupportedCircUsage::Dir;
-        let targ_dir = TargetCircUsage::Dir;
+        let supp_dir = SupportedCircUsage::Dir {
+            priority: DirPriority::Foreground,
+        };
+        let targ_dir = TargetCircUsage::Dir {
+            priority: DirPriority::Foreground,
+        };
+        let supp_dir_bg = SupportedCircUsage::Dir {
+            priority: DirPriority::Background,
+        };
+        let targ_dir_bg = TargetCircUsage::Dir {
+            priority: DirPriority::Background,
+        };
         let supp_exit = SupportedCircUsage::Exit {
             policy: policy.clone(),
             isolation: Some(isolation),
@@ -575,26 +711,46 @@ mod test {
         let targ_80_v4 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation,
+            min_hops: 0,
+            prefer_low_latency: false,
         };
         let targ_80_v4_iso2 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation: isolation2,
+            min_hops: 0,
+            prefer_low_latency: false,
         };
         let targ_80_23_v4 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80), TargetPort::ipv4(23)],
             isolation,
+            min_hops: 0,
+            prefer_low_latency: false,
         };
         let targ_80_23_mixed = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80), TargetPort::ipv6(23)],
             isolation,
+            min_hops: 0,
+            prefer_low_latency: false,
         };
         let targ_999_v6 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv6(999)],
             isolation,
+            min_hops: 0,
+            prefer_low_latency: false,
+        };
+        let targ_80_v4_4hops = TargetCircUsage::Exit {
+            ports: vec![TargetPort::ipv4(80)],
+            isolation,
+            min_hops: 4,
+            prefer_low_latency: false,
         };
         let targ_testing = TargetCircUsage::TimeoutTesting;
 
         assert!(supp_dir.supports(&targ_dir));
+        assert!(!supp_dir.supports(&targ_dir_bg));
+        assert!(supp_dir_bg.supports(&targ_dir_bg));
+        assert!(!supp_dir_bg.supports(&targ_dir));
+        assert!(!supp_exit.supports(&targ_dir_bg));
         assert!(!supp_dir.supports(&targ_80_v4));
         assert!(!supp_exit.supports(&targ_dir));
         assert!(supp_exit.supports(&targ_80_v4));
@@ -607,6 +763,8 @@ mod test {
         assert!(supp_exit_no_iso.supports(&targ_80_v4));
         assert!(supp_exit_no_iso.supports(&targ_80_v4_iso2));
         assert!(!supp_exit_no_iso.supports(&targ_80_23_v4));
+        assert!(!supp_exit.supports(&targ_80_v4_4hops));
+        assert!(!supp_exit_no_iso.supports(&targ_80_v4_4hops));
         assert!(!supp_none.supports(&targ_dir));
         assert!(!supp_none.supports(&targ_80_23_v4));
         assert!(!supp_none.supports(&targ_80_v4_iso2));
@@ -637,8 +795,15 @@ mod test {
             .build()
             .unwrap();
 
-        let supp_dir = SupportedCircUsage::Dir;
-        let targ_dir = TargetCircUsage::Dir;
+        let supp_dir = SupportedCircUsage::Dir {
+            priority: DirPriority::Foreground,
+        };
+        let targ_dir = TargetCircUsage::Dir {
+            priority: DirPriority::Foreground,
+        };
+        let targ_dir_bg = TargetCircUsage::Dir {
+            priority: DirPriority::Background,
+        };
         let supp_exit = SupportedCircUsage::Exit {
             policy: policy.clone(),
             isolation: Some(isolation),
@@ -655,10 +820,14 @@ mod test {
         let targ_exit = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation,
+            min_hops: 0,
+            prefer_low_latency: false,
         };
         let targ_exit_iso2 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation: isolation2,
+            min_hops: 0,
+            prefer_low_latency: false,
         };
         let targ_testing = TargetCircUsage::TimeoutTesting;
 
@@ -666,6 +835,7 @@ mod test {
         let mut supp_dir_c = supp_dir.clone();
         assert!(supp_dir_c.restrict_mut(&targ_exit).is_err());
         assert!(supp_dir_c.restrict_mut(&targ_testing).is_err());
+        assert!(supp_dir_c.restrict_mut(&targ_dir_bg).is_err());
         assert_eq!(supp_dir, supp_dir_c);
 
         let mut supp_exit_c = supp_exit.clone();
@@ -732,10 +902,32 @@ mod test {
         // and friends.
 
         // First, a one-hop directory circuit
-        let (p_dir, u_dir, _, _) = TargetCircUsage::Dir
-            .build_path(&mut rng, di, guards, &config)
-            .unwrap();
-        assert!(matches!(u_dir, SupportedCircUsage::Dir));
+        let (p_dir, u_dir, _, _) = TargetCircUsage::Dir {
+            priority: DirPriority::Foreground,
+        }
+        .build_path(&mut rng, di, guards, &config, &PathRules::default())
+        .unwrap();
+        assert!(matches!(
+            u_dir,
+            SupportedCircUsage::Dir {
+                priority: DirPriority::Foreground
+            }
+        ));
+        assert_eq!(p_dir.len(), 1);
+
+        // A background directory circuit is built the same way, but is only
+        // usable for background requests.
+        let (p_dir, u_dir, _, _) = TargetCircUsage::Dir {
+            priority: DirPriority::Background,
+        }
+        .build_path(&mut rng, di, guards, &config, &PathRules::default())
+        .unwrap();
+        assert!(matches!(
+            u_dir,
+            SupportedCircUsage::Dir {
+                priority: DirPriority::Background
+            }
+        ));
         assert_eq!(p_dir.len(), 1);
 
         // Now an exit circuit, to port 995.
@@ -748,9 +940,11 @@ mod test {
         let exit_usage = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(995)],
             isolation,
+            min_hops: 0,
+            prefer_low_latency: false,
         };
         let (p_exit, u_exit, _, _) = exit_usage
-            .build_path(&mut rng, di, guards, &config)
+            .build_path(&mut rng, di, guards, &config, &PathRules::default())
             .unwrap();
         assert!(matches!(
             u_exit,
@@ -762,9 +956,37 @@ mod test {
         assert!(u_exit.supports(&exit_usage));
         assert_eq!(p_exit.len(), 3);
 
+        // Asking for at least three hops never gives us a shorter path...
+        let min3_usage = TargetCircUsage::Exit {
+            ports: vec![TargetPort::ipv4(995)],
+            isolation,
+            min_hops: 3,
+            prefer_low_latency: false,
+        };
+        for _ in 0..20 {
+            let (p_exit, u_exit, _, _) = min3_usage
+                .build_path(&mut rng, di, guards, &config, &PathRules::default())
+                .unwrap();
+            assert!(p_exit.len() >= 3);
+            assert!(u_exit.supports(&min3_usage));
+        }
+        // ... and asking for more hops than we can build is an error.
+        let min4_usage = TargetCircUsage::Exit {
+            ports: vec![TargetPort::ipv4(995)],
+            isolation,
+            min_hops: 4,
+            prefer_low_latency: false,
+        };
+        assert!(matches!(
+            min4_usage.build_path(&mut rng, di, guards, &config, &PathRules::default()),
+            Err(Error::NoPath(_))
+        ));
+        // Nor will we use a three-hop circuit that's already open.
+        assert!(!u_exit.supports(&min4_usage));
+
         // Now try testing circuits.
         let (path, usage, _, _) = TargetCircUsage::TimeoutTesting
-            .build_path(&mut rng, di, guards, &config)
+            .build_path(&mut rng, di, guards, &config, &PathRules::default())
             .unwrap();
         let path = match OwnedPath::try_from(&path).unwrap() {
             OwnedPath::ChannelOnly(_) => panic!("Impossible path type."),
@@ -804,12 +1026,78 @@ mod test {
         let guards: OptDummyGuardMgr<'_> = None;
 
         let (path, usage, _, _) = TargetCircUsage::TimeoutTesting
-            .build_path(&mut rng, di, guards, &config)
+            .build_path(&mut rng, di, guards, &config, &PathRules::default())
             .unwrap();
         assert_eq!(path.len(), 3);
         assert_eq!(usage, SupportedCircUsage::NoUsage);
     }
 
+    #[test]
+    fn build_low_latency() {
+        let mut rng = rand::thread_rng();
+        let netdir = testnet::construct_netdir()
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+        let di = (&netdir).into();
+        let config = crate::PathConfig::default();
+        let guards: OptDummyGuardMgr<'_> = None;
+
+        // Even-numbered relays have been fast in the past; odd-numbered
+        // ones have been slow.
+        let latencies = Arc::new(RelayLatencies::new());
+        for r in netdir.relays() {
+            let delay = if r.rsa_id().as_bytes()[0] % 2 == 0 {
+                Duration::from_millis(100)
+            } else {
+                Duration::from_millis(900)
+            };
+            latencies.note_latency(r.rsa_id(), delay);
+        }
+        let is_fast = |id: &tor_llcrypto::pk::rsa::RsaIdentity| id.as_bytes()[0] % 2 == 0;
+        let rules = PathRules {
+            latencies: Some(latencies),
+            ..PathRules::default()
+        };
+
+        // Count how many fast middle and exit relays we pick, with and
+        // without asking for low latency.
+        let mut count_fast = |prefer_low_latency| {
+            let usage = TargetCircUsage::Exit {
+                ports: vec![],
+                isolation: StreamIsolation::no_isolation(),
+                min_hops: 0,
+                prefer_low_latency,
+            };
+            let mut n_fast = 0;
+            for _ in 0..1000 {
+                let (path, _, _, _) = usage
+                    .build_path(&mut rng, di, guards, &config, &rules)
+                    .unwrap();
+                let path = match OwnedPath::try_from(&path).unwrap() {
+                    OwnedPath::ChannelOnly(_) => panic!("Impossible path type."),
+                    OwnedPath::Normal(p) => p,
+                };
+                n_fast += path[1..]
+                    .iter()
+                    .filter(|r| is_fast(r.rsa_identity()))
+                    .count();
+            }
+            n_fast
+        };
+        let n_plain = count_fast(false);
+        let n_preferred = count_fast(true);
+
+        // Out of 2000 relays chosen each way, we expect about 900 fast ones
+        // without the preference, and about 1400 with it.
+        assert!(
+            n_preferred > n_plain + 200,
+            "{} fast relays with preference, {} without",
+            n_preferred,
+            n_plain
+        );
+    }
+
     #[test]
     fn build_isolation() {
         let no_isolation = StreamIsolation::no_isolation();
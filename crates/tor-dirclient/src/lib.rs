@@ -23,6 +23,8 @@
 //! `zstd` -- enable ZSTD compression.  (On by default.)
 //!
 //! `routerdesc` -- Add support for downloading router descriptors.
+//!
+//! `votes` -- Add support for downloading directory authorities' votes.
 
 #![deny(missing_docs)]
 #![warn(noop_method_call)]
@@ -59,7 +61,7 @@ pub mod request;
 mod response;
 mod util;
 
-use tor_circmgr::{CircMgr, DirInfo};
+use tor_circmgr::{CircMgr, DirInfo, DirPriority};
 use tor_rtcompat::{Runtime, SleepProvider, SleepProviderExt};
 
 // Zlib is required; the others are optional.
@@ -89,6 +91,12 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// Circuits are built or found using `circ_mgr`, using paths
 /// constructed using `dirinfo`.
 ///
+/// Each request gets a stream of its own, but requests share circuits:
+/// `circ_mgr` hands out an existing directory circuit when it has one.  We
+/// can't reuse a stream for a second request.  We speak HTTP/1.0 to the
+/// cache, and read each response until the cache closes the stream: the
+/// directory protocol doesn't delimit responses in any other way.
+///
 /// For more fine-grained control over the circuit and stream used,
 /// construct them yourself, and then call [`download`] instead.
 ///
@@ -107,11 +115,35 @@ where
     R: Runtime,
     SP: SleepProvider,
 {
-    let circuit = circ_mgr.get_or_launch_dir(dirinfo).await?;
+    get_resource_with_priority(req, dirinfo, runtime, circ_mgr, DirPriority::Foreground).await
+}
+
+/// Fetch the resource described by `req` over the Tor network, using a
+/// directory circuit with the given `priority`.
+///
+/// This is the same as [`get_resource`], except that background requests
+/// (such as routine refreshes of a directory we already have) don't share
+/// circuits with foreground ones.
+pub async fn get_resource_with_priority<CR, R, SP>(
+    req: &CR,
+    dirinfo: DirInfo<'_>,
+    runtime: &SP,
+    circ_mgr: Arc<CircMgr<R>>,
+    priority: DirPriority,
+) -> Result<DirResponse>
+where
+    CR: request::Requestable + ?Sized,
+    R: Runtime,
+    SP: SleepProvider,
+{
+    let circuit = circ_mgr
+        .get_or_launch_dir_with_priority(dirinfo, priority)
+        .await?;
 
     // TODO(nickm) This should be an option, and is too long.
     let begin_timeout = Duration::from_secs(5);
-    let source = SourceInfo::new(circuit.unique_id());
+    // The last hop of a directory circuit is the cache we're asking.
+    let source = SourceInfo::new(circuit.unique_id(), circuit.path().last().cloned());
 
     // Launch the stream.
     let mut stream = runtime
@@ -165,6 +197,8 @@ where
 {
     let partial_ok = req.partial_docs_ok();
     let maxlen = req.max_response_len();
+    let caller_decodes =
+        |encoding: &str| !request::supports_encoding(encoding) && req.handles_encoding(encoding);
     let req = req.make_request()?;
     let encoded = util::encode_request(&req);
 
@@ -186,7 +220,18 @@ where
         ));
     }
 
-    let mut decoder = get_decoder(buffered, header.encoding.as_deref())?;
+    // If this is an encoding we can't undo, but our caller can, we leave
+    // the body as it is.
+    let left_encoded = header
+        .encoding
+        .as_deref()
+        .filter(|e| caller_decodes(e))
+        .map(str::to_owned);
+    let encoding = match left_encoded {
+        Some(_) => None,
+        None => header.encoding.as_deref(),
+    };
+    let mut decoder = get_decoder(buffered, encoding)?;
 
     let mut result = Vec::new();
     let ok = read_and_decompress(runtime, &mut decoder, maxlen, &mut result).await;
@@ -202,7 +247,11 @@ where
         (_, Ok(()), _) => Ok(()),
     };
 
-    Ok(DirResponse::new(200, ok.err(), result, source))
+    let response = DirResponse::new(200, ok.err(), result, source);
+    Ok(match left_encoded {
+        Some(encoding) => response.with_encoding(encoding),
+        None => response,
+    })
 }
 
 /// Read and parse HTTP/1 headers from `stream`.
@@ -360,7 +409,10 @@ where
     R: Runtime,
     E: std::fmt::Display + ?Sized,
 {
-    let id = source_info.unique_circ_id();
+    let id = match source_info.unique_circ_id() {
+        Some(id) => id,
+        None => return,
+    };
     info!(
         "{}: Retiring circuit because of directory failure: {}",
         &id, &error
@@ -691,6 +743,47 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_download_caller_decodes() -> Result<()> {
+        /// A request that can undo an encoding we don't know about.
+        struct BackwardsRequest(request::MicrodescRequest);
+        impl request::Requestable for BackwardsRequest {
+            fn make_request(&self) -> Result<http::Request<()>> {
+                self.0.make_request()
+            }
+            fn partial_docs_ok(&self) -> bool {
+                self.0.partial_docs_ok()
+            }
+            fn handles_encoding(&self, encoding: &str) -> bool {
+                encoding == "x-backwards"
+            }
+        }
+        let md_req = || -> request::MicrodescRequest { vec![[9; 32]].into_iter().collect() };
+        let response_text =
+            b"HTTP/1.0 200 OK\r\nContent-Encoding: x-backwards\r\n\r\nog dluow csed ehT";
+
+        // A request that doesn't know the encoding gets an error...
+        let (response, _request) = run_download_test(md_req(), response_text);
+        assert!(matches!(response, Err(Error::ContentEncoding(e)) if e == "x-backwards"));
+
+        // ... but one that does gets the body as it was sent.
+        let (response, _request) = run_download_test(BackwardsRequest(md_req()), response_text);
+        let response = response?;
+        assert_eq!(response.encoding(), Some("x-backwards"));
+        assert_eq!(response.output(), b"og dluow csed ehT");
+
+        // We still undo the encodings we know about ourselves.
+        let (response, _request) = run_download_test(
+            BackwardsRequest(md_req()),
+            b"HTTP/1.0 200 OK\r\nContent-Encoding: identity\r\n\r\nThe descs",
+        );
+        let response = response?;
+        assert_eq!(response.encoding(), None);
+        assert_eq!(response.output(), b"The descs");
+
+        Ok(())
+    }
+
     #[test]
     fn test_download_truncated() -> Result<()> {
         // Request only one md, so "partial ok" will not be set.
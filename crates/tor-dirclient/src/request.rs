@@ -29,6 +29,17 @@ pub trait Requestable {
     fn max_response_len(&self) -> usize {
         (16 * 1024 * 1024) - 1
     }
+
+    /// Return true if the caller knows how to undo the content-encoding
+    /// called `encoding` by itself.
+    ///
+    /// If a directory cache sends a response with an encoding that we
+    /// don't support, but for which this returns true, we hand back the
+    /// response body undecoded, and record the encoding with
+    /// [`DirResponse::encoding`](crate::DirResponse::encoding).
+    fn handles_encoding(&self, _encoding: &str) -> bool {
+        false
+    }
 }
 
 /// A Request for a consensus directory.
@@ -81,6 +92,11 @@ impl ConsensusRequest {
         self.last_consensus_published = Some(when);
     }
 
+    /// Return the flavor of consensus that we're asking for.
+    pub fn flavor(&self) -> ConsensusFlavor {
+        self.flavor
+    }
+
     /// Return a slice of the consensus digests that we're saying we
     /// already have.
     pub fn old_consensus_digests(&self) -> impl Iterator<Item = &[u8; 32]> {
@@ -367,6 +383,71 @@ impl FromIterator<RdDigest> for RouterDescRequest {
     }
 }
 
+/// A request for the current votes of one or more directory authorities.
+///
+/// Votes carry the authorities' commitments and reveals for the shared
+/// random protocol, so this is also how to get those.
+#[derive(Debug, Clone, Default)]
+#[cfg(feature = "votes")]
+pub struct AuthVoteRequest {
+    /// The identity fingerprints of the authorities whose votes we want.
+    ids: Vec<RsaIdentity>,
+}
+
+#[cfg(feature = "votes")]
+impl AuthVoteRequest {
+    /// Construct a new empty request.
+    pub fn new() -> Self {
+        AuthVoteRequest::default()
+    }
+    /// Add `id` to the list of authorities whose votes we want.
+    pub fn push(&mut self, id: RsaIdentity) {
+        self.ids.push(id);
+    }
+
+    /// Return an iterator over the authority identities whose votes we're
+    /// asking for.
+    pub fn authority_ids(&self) -> impl Iterator<Item = &RsaIdentity> {
+        self.ids.iter()
+    }
+}
+
+#[cfg(feature = "votes")]
+impl Requestable for AuthVoteRequest {
+    fn make_request(&self) -> Result<http::Request<()>> {
+        // TODO: require that self.ids is nonempty.
+        let mut ids = self.ids.clone();
+        ids.sort_unstable();
+        let ids: Vec<String> = ids.iter().map(|id| hex::encode(id.as_bytes())).collect();
+        let uri = format!("/tor/status-vote/current/{}.z", &ids.join("+"));
+
+        let req = http::Request::builder().method("GET").uri(uri);
+        let req = add_common_headers(req);
+
+        Ok(req.body(())?)
+    }
+
+    fn partial_docs_ok(&self) -> bool {
+        self.ids.len() > 1
+    }
+
+    fn max_response_len(&self) -> usize {
+        // A vote is about as large as a full consensus.
+        self.ids.len().saturating_mul((16 << 20) - 1)
+    }
+}
+
+#[cfg(feature = "votes")]
+impl FromIterator<RsaIdentity> for AuthVoteRequest {
+    fn from_iter<I: IntoIterator<Item = RsaIdentity>>(iter: I) -> Self {
+        let mut req = Self::new();
+        for i in iter {
+            req.push(i);
+        }
+        req
+    }
+}
+
 /// List the encodings we accept
 fn encodings() -> String {
     let mut encodings = "deflate, identity".to_string();
@@ -382,6 +463,12 @@ fn encodings() -> String {
     encodings
 }
 
+/// Return true if we can undo the content-encoding called `encoding`
+/// ourselves.
+pub(crate) fn supports_encoding(encoding: &str) -> bool {
+    encodings().split(", ").any(|e| e == encoding)
+}
+
 /// Add commonly used headers to the HTTP request.
 ///
 /// (Right now, this is only Accept-Encoding.)
@@ -536,4 +623,31 @@ mod test {
         assert_eq!(req, req2);
         Ok(())
     }
+
+    #[test]
+    #[cfg(feature = "votes")]
+    fn test_vote_request() -> Result<()> {
+        let id1: RsaIdentity = [0xEE; 20].into();
+        let id2: RsaIdentity = [0x27; 20].into();
+
+        let mut req = AuthVoteRequest::new();
+        req.push(id1);
+        assert!(!req.partial_docs_ok());
+        req.push(id2);
+        assert!(req.partial_docs_ok());
+        assert_eq!(req.max_response_len(), 2 * ((16 << 20) - 1));
+
+        let req = crate::util::encode_request(&req.make_request()?);
+
+        assert_eq!(req,
+                   format!("GET /tor/status-vote/current/2727272727272727272727272727272727272727+eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee.z HTTP/1.0\r\naccept-encoding: {}\r\n\r\n", encodings()));
+
+        // Try it with FromIterator, and use some accessors.
+        let req2: AuthVoteRequest = vec![id1, id2].into_iter().collect();
+        let ids: Vec<_> = req2.authority_ids().collect();
+        assert_eq!(ids, vec![&id1, &id2]);
+        let req2 = crate::util::encode_request(&req2.make_request()?);
+        assert_eq!(req, req2);
+        Ok(())
+    }
 }
@@ -1,5 +1,6 @@
 //! Define a response type for directory requests.
 
+use tor_llcrypto::pk::rsa::RsaIdentity;
 use tor_proto::circuit::UniqId;
 
 use crate::Error;
@@ -16,19 +17,23 @@ pub struct DirResponse {
     error: Option<Error>,
     /// Information about the directory cache we used.
     source: Option<SourceInfo>,
+    /// The content-encoding that `output` still has, if we left it for
+    /// the caller to undo.
+    encoding: Option<String>,
 }
 
 /// Information about the source of a directory response.
 ///
 /// We use this to remember when a request has failed, so we can
-/// abandon the circuit.
-///
-/// (In the future, we will probably want to use this structure to
-/// remember that the cache isn't working.)
-#[derive(Debug, Clone)]
+/// abandon the circuit, and so that we can remember that the cache
+/// isn't working.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct SourceInfo {
-    /// Unique identifier for the circuit we're using
-    circuit: UniqId,
+    /// Unique identifier for the circuit we're using, if we used one.
+    circuit: Option<UniqId>,
+    /// Identity of the directory cache at the end of that circuit, if we
+    /// know it.
+    cache: Option<RsaIdentity>,
 }
 
 impl DirResponse {
@@ -44,6 +49,7 @@ impl DirResponse {
             output,
             error,
             source,
+            encoding: None,
         }
     }
 
@@ -52,6 +58,21 @@ impl DirResponse {
         Self::new(200, None, body.as_ref().to_vec(), None)
     }
 
+    /// Return this response, marked as still having the content-encoding
+    /// called `encoding`.
+    pub fn with_encoding(mut self, encoding: impl Into<String>) -> Self {
+        self.encoding = Some(encoding.into());
+        self
+    }
+
+    /// Return this response, with its HTTP status code replaced by
+    /// `status`.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
     /// Return the HTTP status code for this response.
     pub fn status_code(&self) -> u16 {
         self.status
@@ -77,20 +98,49 @@ impl DirResponse {
         self.output
     }
 
+    /// Return the content-encoding that this response's output still has,
+    /// if any.
+    ///
+    /// This is only set for encodings that we can't undo ourselves, when
+    /// the request said it could: see
+    /// [`Requestable::handles_encoding`](crate::request::Requestable::handles_encoding).
+    pub fn encoding(&self) -> Option<&str> {
+        self.encoding.as_deref()
+    }
+
     /// Return the source information about this response.
     pub fn source(&self) -> Option<&SourceInfo> {
         self.source.as_ref()
     }
+
+    /// Return this response, marked as having come from the directory
+    /// cache with identity `cache`, without any circuit.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn with_cache(mut self, cache: RsaIdentity) -> Self {
+        self.source = Some(SourceInfo {
+            circuit: None,
+            cache: Some(cache),
+        });
+        self
+    }
 }
 
 impl SourceInfo {
     /// Construct a new SourceInfo
-    pub(crate) fn new(circuit: UniqId) -> Self {
-        SourceInfo { circuit }
+    pub(crate) fn new(circuit: UniqId, cache: Option<RsaIdentity>) -> Self {
+        SourceInfo {
+            circuit: Some(circuit),
+            cache,
+        }
     }
     /// Return the unique circuit identifier for the circuit on which
-    /// we received this info.
-    pub fn unique_circ_id(&self) -> &UniqId {
-        &self.circuit
+    /// we received this info, if there was one.
+    pub fn unique_circ_id(&self) -> Option<&UniqId> {
+        self.circuit.as_ref()
+    }
+    /// Return the identity of the directory cache that we received this
+    /// info from, if we know it.
+    pub fn cache_id(&self) -> Option<&RsaIdentity> {
+        self.cache.as_ref()
     }
 }
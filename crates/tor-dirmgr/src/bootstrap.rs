@@ -2,115 +2,1160 @@
 //! state machines in the `states` module.
 
 use std::{
-    collections::HashMap,
-    sync::{Arc, Weak},
-    time::{Duration, SystemTime},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
-    docid::{self, ClientRequest},
-    upgrade_weak_ref, DirMgr, DirState, DocId, DocumentText, Error, Readiness, Result,
+    docid::{self, ClientRequest, DocType},
+    storage::WriteScope,
+    upgrade_weak_ref, CantAdvanceReason, DirMgr, DirMirror, DirResetReason, DirState, DocId,
+    DocSource, DownloadScheduleConfig, Error, Readiness, Result,
 };
 
 use futures::channel::oneshot;
 use futures::FutureExt;
 use futures::StreamExt;
+use tor_circmgr::DirPriority;
+use tor_dirclient::request::Requestable;
 use tor_dirclient::DirResponse;
-use tor_rtcompat::{Runtime, SleepProviderExt};
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdir::fallback::FallbackDir;
+use tor_rtcompat::{Runtime, SleepProviderExt, TcpProvider, TlsConnector, TlsProvider};
 use tracing::{info, trace, warn};
 
+/// Testing helper: a response that a `DirMgr` returns in place of
+/// downloading anything, as set with its `canned_response` field.
+///
+/// This can model a slow directory cache (when used with a mock runtime), a
+/// transfer that stopped partway through, or a request that failed outright.
 #[cfg(test)]
-use once_cell::sync::Lazy;
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CannedResponse {
+    /// The body of the response.
+    body: Vec<u8>,
+    /// How long to wait before delivering the response.
+    delay: Duration,
+    /// If true, fail with a timeout instead of delivering the response.
+    fail: bool,
+    /// If present, the HTTP status to report instead of 200.
+    status: Option<u16>,
+    /// If present, the body to deliver instead for authority certificate
+    /// requests.
+    certs_body: Option<Vec<u8>>,
+    /// How long to wait instead of `delay` when we're asking a particular
+    /// cache, by its RSA identity.
+    cache_delays: HashMap<RsaIdentity, Duration>,
+    /// The body to deliver instead when we're asking a particular cache,
+    /// by its RSA identity.
+    cache_bodies: HashMap<RsaIdentity, Vec<u8>>,
+}
+
 #[cfg(test)]
-use std::sync::Mutex;
+impl CannedResponse {
+    /// Make a new CannedResponse that delivers `body` right away.
+    pub(crate) fn new(body: impl AsRef<[u8]>) -> Self {
+        CannedResponse {
+            body: body.as_ref().to_vec(),
+            delay: Duration::default(),
+            fail: false,
+            status: None,
+            certs_body: None,
+            cache_delays: HashMap::new(),
+            cache_bodies: HashMap::new(),
+        }
+    }
 
-/// Try to read a set of documents from `dirmgr` by ID.
-fn load_all<R: Runtime>(
-    dirmgr: &DirMgr<R>,
-    missing: Vec<DocId>,
-) -> Result<HashMap<DocId, DocumentText>> {
-    let mut loaded = HashMap::new();
-    for query in docid::partition_by_type(missing.into_iter()).values() {
-        dirmgr.load_documents_into(query, &mut loaded)?;
+    /// Deliver `body` instead in reply to requests for authority
+    /// certificates.
+    pub(crate) fn certs(mut self, body: impl AsRef<[u8]>) -> Self {
+        self.certs_body = Some(body.as_ref().to_vec());
+        self
+    }
+
+    /// Wait for `delay` before delivering this response.
+    pub(crate) fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Wait for `delay` instead before delivering this response, when we
+    /// know that we're asking the cache with identity `id`.
+    pub(crate) fn cache_delay(mut self, id: RsaIdentity, delay: Duration) -> Self {
+        self.cache_delays.insert(id, delay);
+        self
+    }
+
+    /// Deliver `body` instead, when we know that we're asking the cache
+    /// with identity `id`.
+    pub(crate) fn cache_body(mut self, id: RsaIdentity, body: impl AsRef<[u8]>) -> Self {
+        self.cache_bodies.insert(id, body.as_ref().to_vec());
+        self
+    }
+
+    /// Deliver only the first `len` bytes of this response's body.
+    pub(crate) fn truncate(mut self, len: usize) -> Self {
+        self.body.truncate(len);
+        self
+    }
+
+    /// Time out instead of delivering this response.
+    pub(crate) fn fail(mut self) -> Self {
+        self.fail = true;
+        self
+    }
+
+    /// Report `status` instead of 200 with this response, as a cache that
+    /// declined our request would.
+    pub(crate) fn status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Wait for this response's delay on `runtime`, then return it in
+    /// reply to `request`, as if it came from `cache` (if we know which
+    /// cache we asked).
+    ///
+    /// Fail, as the directory client would, if the body is longer than
+    /// `max_len`.
+    async fn deliver<R: Runtime>(
+        self,
+        runtime: &R,
+        request: &ClientRequest,
+        cache: Option<&FallbackDir>,
+        max_len: usize,
+    ) -> Result<DirResponse> {
+        let delay = cache
+            .and_then(|c| self.cache_delays.get(c.rsa_identity()))
+            .copied()
+            .unwrap_or(self.delay);
+        if delay > Duration::default() {
+            runtime.sleep(delay).await;
+        }
+        if self.fail {
+            return Err(tor_dirclient::Error::DirTimeout.into());
+        }
+        let cache_body = cache.and_then(|c| self.cache_bodies.get(c.rsa_identity()));
+        let body = match (request, self.certs_body, cache_body) {
+            (ClientRequest::AuthCert(_), Some(body), _) => body,
+            (_, _, Some(body)) => body.clone(),
+            (_, _, None) => self.body,
+        };
+        if body.len() > max_len {
+            return Err(tor_dirclient::Error::ResponseTooLong(body.len()).into());
+        }
+        let mut response = DirResponse::from_body(body);
+        if let Some(cache) = cache {
+            response = response.with_cache(*cache.rsa_identity());
+        }
+        Ok(match self.status {
+            Some(status) => response.with_status(status),
+            None => response,
+        })
     }
-    Ok(loaded)
 }
 
-/// Testing helper: if this is Some, then we return it in place of any
-/// response to fetch_single.
+/// Testing helper: a script that a `DirMgr` follows to answer its download
+/// requests, as set with its `fault_script` field.
 ///
-/// Note that only one test uses this: otherwise there would be a race
-/// condition. :p
+/// Unlike a single [`CannedResponse`], this lets a test decide how each
+/// request goes, in the order that we make them: for example, that the
+/// first request times out, the second gets a 503, and the third succeeds.
+#[cfg(test)]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FaultScript {
+    /// How many requests have we answered so far?
+    n_requests: usize,
+    /// The requests that we have answered so far, in order.
+    requests: Vec<ClientRequest>,
+    /// The response to give to particular requests, by their position
+    /// (starting at 0) in the order that we made them.
+    steps: HashMap<usize, CannedResponse>,
+    /// The response to give to any request without a step of its own.
+    ///
+    /// If this is None, such requests time out.
+    otherwise: Option<CannedResponse>,
+}
+
+#[cfg(test)]
+impl FaultScript {
+    /// Make a new FaultScript that answers every request with `response`,
+    /// unless told otherwise.
+    pub(crate) fn new(response: CannedResponse) -> Self {
+        FaultScript {
+            otherwise: Some(response),
+            ..FaultScript::default()
+        }
+    }
+
+    /// Answer the `n`th request (starting at 0) with `response`.
+    pub(crate) fn at(mut self, n: usize, response: CannedResponse) -> Self {
+        self.steps.insert(n, response);
+        self
+    }
+
+    /// Make the `n`th request (starting at 0) time out.
+    pub(crate) fn timeout_at(self, n: usize) -> Self {
+        self.at(n, CannedResponse::default().fail())
+    }
+
+    /// Make the `n`th request (starting at 0) get an empty response with
+    /// HTTP status `status`.
+    pub(crate) fn status_at(self, n: usize, status: u16) -> Self {
+        self.at(n, CannedResponse::default().status(status))
+    }
+
+    /// Return how many requests we have answered so far.
+    pub(crate) fn n_requests(&self) -> usize {
+        self.n_requests
+    }
+
+    /// Return the requests that we have answered so far, in order.
+    pub(crate) fn requests(&self) -> &[ClientRequest] {
+        &self.requests[..]
+    }
+
+    /// Return the response for the next request, `request`, and move on to
+    /// the one after it.
+    fn next_response(&mut self, request: &ClientRequest) -> CannedResponse {
+        let n = self.n_requests;
+        self.n_requests += 1;
+        self.requests.push(request.clone());
+        self.steps
+            .remove(&n)
+            .or_else(|| self.otherwise.clone())
+            .unwrap_or_else(|| CannedResponse::default().fail())
+    }
+}
+
+/// Testing helper: if `dirmgr` has a fault script or a canned response,
+/// deliver the appropriate response in reply to `request`, as if it came
+/// from `cache`.
 #[cfg(test)]
-static CANNED_RESPONSE: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+async fn canned_response<R: Runtime>(
+    dirmgr: &DirMgr<R>,
+    request: &ClientRequest,
+    cache: Option<&FallbackDir>,
+) -> Option<Result<DirResponse>> {
+    let scripted = dirmgr
+        .fault_script
+        .lock()
+        .expect("Poisoned mutex")
+        .as_mut()
+        .map(|script| script.next_response(request));
+    let canned = match scripted {
+        Some(canned) => canned,
+        None => dirmgr
+            .canned_response
+            .lock()
+            .expect("Poisoned mutex")
+            .clone()?,
+    };
+    let config = dirmgr.config.get();
+    let timeout = request_timeout(config.schedule(), request);
+    let max_len = max_response_len(config.schedule(), request);
+    Some(
+        with_timeout(
+            &dirmgr.runtime,
+            timeout,
+            canned.deliver(&dirmgr.runtime, request, cache, max_len),
+        )
+        .await,
+    )
+}
 
 /// Launch a single client request and get an associated response.
-async fn fetch_single<R: Runtime>(
+pub(crate) async fn fetch_single<R: Runtime>(
     dirmgr: Arc<DirMgr<R>>,
     request: ClientRequest,
 ) -> Result<(ClientRequest, DirResponse)> {
+    let cur_netdir = dirmgr.opt_netdir();
+    let config = dirmgr.config.get();
+    // If we already have a usable directory, then this is a refresh, and
+    // nothing is blocked waiting for it: keep it out of the way of any
+    // directory requests that are more urgent.
+    //
+    // If we don't have a directory yet, ask a single fallback at a time, so
+    // that we move on to a different one after a failure.
+    //
+    // If we've been told to only use some caches, or to prefer ones that we
+    // can reach over IPv6, we choose among those.
+    let filtered = dirmgr.filtered_caches(cur_netdir.as_deref(), config.fallbacks());
+    let fallback: &[FallbackDir] = match (&filtered, &cur_netdir) {
+        (_, Some(_)) => &[],
+        (None, None) => dirmgr.current_fallback(config.fallbacks()),
+        (Some(caches), None) => dirmgr.current_fallback(caches),
+    };
+    let (dirinfo, priority) = match (&filtered, &cur_netdir) {
+        (None, Some(netdir)) => (netdir.as_ref().into(), DirPriority::Background),
+        (Some(caches), Some(_)) => (caches.as_slice().into(), DirPriority::Background),
+        (_, None) => (fallback.into(), DirPriority::Foreground),
+    };
+    // When we're asking a single fallback, we know which cache we're
+    // timing.
+    let fallback = fallback.first();
+    let timeout = request_timeout(config.schedule(), &request);
+    let limited = LimitedRequest::new(config.schedule(), &request)
+        .with_encodings(dirmgr.codec_names())
+        .with_hook(dirmgr.request_hook());
+    let start = dirmgr.runtime.now();
+    #[cfg(test)]
+    let canned = canned_response(&dirmgr, &request, fallback).await;
+    #[cfg(not(test))]
+    let canned = None;
+    let resource = match canned {
+        Some(response) => response,
+        // Without a circuit manager, we can still try our mirrors below.
+        None => match dirmgr.circmgr() {
+            Ok(circmgr) => {
+                with_timeout(
+                    &dirmgr.runtime,
+                    timeout,
+                    tor_dirclient::get_resource_with_priority(
+                        &limited,
+                        dirinfo,
+                        &dirmgr.runtime,
+                        circmgr,
+                        priority,
+                    ),
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        },
+    };
+    let elapsed = dirmgr.runtime.now().saturating_duration_since(start);
+
+    match resource {
+        Ok(resource) => {
+            if let Some(cache) = fallback {
+                dirmgr.note_cache_latency(cache, elapsed);
+            }
+            if let Some(tor_dirclient::Error::ResponseTooLong(n)) = resource.error() {
+                // We'll use whatever complete documents we got, but we
+                // won't ask this cache again soon.
+                warn!(
+                    "Directory cache sent us too much; gave up after {} bytes",
+                    n
+                );
+                dirmgr.note_cache_error(resource.source());
+            }
+            Ok((request, resource))
+        }
+        Err(e) => {
+            if let Some(cache) = fallback {
+                // A cache that failed us is no better than one that kept
+                // us waiting for our whole timeout.
+                dirmgr.note_cache_latency(cache, std::cmp::max(elapsed, timeout));
+                dirmgr.note_fallback_failed(cache.rsa_identity());
+            }
+            if cur_netdir.is_none() {
+                if let Some(response) = fetch_from_mirrors(&dirmgr, &request, timeout).await {
+                    return Ok((request, response));
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Try each of our configured directory mirrors in turn, over a direct
+/// connection, until one of them gives us a successful response to
+/// `request`.
+///
+/// Return None if no mirror did so.  (Errors from the mirrors are only
+/// logged: we're only trying them because the Tor network already failed
+/// us.)
+async fn fetch_from_mirrors<R: Runtime>(
+    dirmgr: &DirMgr<R>,
+    request: &ClientRequest,
+    timeout: Duration,
+) -> Option<DirResponse> {
+    let config = dirmgr.config.get();
+    for mirror in config.mirrors() {
+        let limited = LimitedRequest::new(config.schedule(), request)
+            .with_encodings(dirmgr.codec_names())
+            .with_host(mirror.http_host())
+            .with_hook(dirmgr.request_hook());
+        let outcome = with_timeout(
+            &dirmgr.runtime,
+            timeout,
+            fetch_from_mirror(&dirmgr.runtime, mirror, &limited),
+        )
+        .await;
+        match outcome {
+            Ok(response) if response.status_code() == 200 => {
+                info!(
+                    "Fetched directory information from mirror {}",
+                    mirror.address()
+                );
+                return Some(response);
+            }
+            Ok(response) => warn!(
+                "Directory mirror {} answered with status {}",
+                mirror.address(),
+                response.status_code()
+            ),
+            Err(e) => warn!(
+                "Unable to fetch from directory mirror {}: {}",
+                mirror.address(),
+                e
+            ),
+        }
+    }
+    None
+}
+
+/// Send `request` to `mirror` over a direct connection (not over Tor), and
+/// return its response.
+async fn fetch_from_mirror<R: Runtime>(
+    runtime: &R,
+    mirror: &DirMirror,
+    request: &LimitedRequest<'_>,
+) -> tor_dirclient::Result<DirResponse> {
+    let mut stream = runtime.connect(mirror.address()).await?;
+    match mirror.tls_hostname() {
+        Some(hostname) => {
+            // We don't check the mirror's certificate (our TLS connectors
+            // can't), so TLS here only hides what we ask for from a casual
+            // observer.  The signatures on the consensus are our only
+            // protection against a mirror, or anybody between us and it,
+            // that tampers with what it sends us: we check those just as we
+            // would for a response from any other cache.
+            let mut stream = runtime
+                .tls_connector()
+                .negotiate_unvalidated(stream, hostname)
+                .await?;
+            tor_dirclient::download(runtime, request, &mut stream, None).await
+        }
+        None => tor_dirclient::download(runtime, request, &mut stream, None).await,
+    }
+}
+
+/// What happened when we probed a single directory cache with
+/// [`DirMgr::probe_caches`](crate::DirMgr::probe_caches).
+#[derive(Clone, Debug)]
+pub struct CacheProbe {
+    /// The RSA identity of the cache that we probed.
+    cache: RsaIdentity,
+    /// How long the cache took to answer, or to fail.
+    latency: Duration,
+    /// The HTTP status code that the cache answered with, or the error that
+    /// we got instead of an answer.
+    outcome: std::result::Result<u16, Error>,
+}
+
+impl CacheProbe {
+    /// Return the RSA identity of the cache that we probed.
+    pub fn cache(&self) -> &RsaIdentity {
+        &self.cache
+    }
+
+    /// Return how long the cache took to answer, or to fail.
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// Return true if the cache answered our probe the way a working cache
+    /// would: either with a new document, or by telling us that it has
+    /// nothing newer than what we have.
+    pub fn succeeded(&self) -> bool {
+        matches!(self.outcome, Ok(200) | Ok(304))
+    }
+
+    /// Return the HTTP status code that the cache answered with, if it
+    /// answered at all.
+    pub fn status(&self) -> Option<u16> {
+        self.outcome.as_ref().ok().copied()
+    }
+
+    /// Return the error that we got instead of an answer, if any.
+    pub fn error(&self) -> Option<&Error> {
+        self.outcome.as_ref().err()
+    }
+}
+
+/// Send `request` to `cache`, and no other cache, and report how it went.
+///
+/// Unlike `fetch_single`, this never tells `dirmgr` about the outcome: a
+/// failed probe doesn't make us move on to another fallback.
+pub(crate) async fn probe_cache<R: Runtime>(
+    dirmgr: &DirMgr<R>,
+    cache: &FallbackDir,
+    request: &ClientRequest,
+) -> CacheProbe {
+    let start = dirmgr.runtime.now();
+    let outcome = probe_request(dirmgr, cache, request).await;
+    CacheProbe {
+        cache: *cache.rsa_identity(),
+        latency: dirmgr.runtime.now().saturating_duration_since(start),
+        outcome: outcome.map(|response| response.status_code()),
+    }
+}
+
+/// Helper for `probe_cache`: send `request` to `cache`, and return the
+/// response.
+async fn probe_request<R: Runtime>(
+    dirmgr: &DirMgr<R>,
+    cache: &FallbackDir,
+    request: &ClientRequest,
+) -> Result<DirResponse> {
     #[cfg(test)]
     {
-        let m = CANNED_RESPONSE.lock().expect("Poisoned mutex");
-        if let Some(s) = m.as_ref() {
-            return Ok((request, DirResponse::from_body(s)));
+        if let Some(response) = canned_response(dirmgr, request, Some(cache)).await {
+            return response;
         }
     }
     let circmgr = dirmgr.circmgr()?;
-    let cur_netdir = dirmgr.opt_netdir();
     let config = dirmgr.config.get();
-    let dirinfo = match cur_netdir {
-        Some(ref netdir) => netdir.as_ref().into(),
-        None => config.fallbacks().into(),
+    let timeout = request_timeout(config.schedule(), request);
+    let limited = LimitedRequest::new(config.schedule(), request)
+        .with_encodings(dirmgr.codec_names())
+        .with_hook(dirmgr.request_hook());
+    with_timeout(
+        &dirmgr.runtime,
+        timeout,
+        tor_dirclient::get_resource_with_priority(
+            &limited,
+            std::slice::from_ref(cache).into(),
+            &dirmgr.runtime,
+            circmgr,
+            DirPriority::Background,
+        ),
+    )
+    .await
+}
+
+/// How much longer than its base timeout we allow a request to take for each
+/// microdescriptor (or router descriptor) that it asks for.
+const TIMEOUT_PER_DESCRIPTOR: Duration = Duration::from_millis(100);
+
+/// Return how long we should wait for `request` to finish before giving up
+/// on it, according to `config`.
+///
+/// A consensus is far larger than anything else we download, so it gets the
+/// most time.  A descriptor request gets more time the more descriptors it
+/// asks for.
+fn request_timeout(config: &DownloadScheduleConfig, request: &ClientRequest) -> Duration {
+    /// Return the timeout for a request for `n` descriptors.
+    fn for_descriptors(config: &DownloadScheduleConfig, n: usize) -> Duration {
+        use std::convert::TryFrom;
+        let n = u32::try_from(n).unwrap_or(u32::MAX);
+        config
+            .microdescs_timeout()
+            .saturating_add(TIMEOUT_PER_DESCRIPTOR.saturating_mul(n))
+    }
+    match request {
+        ClientRequest::Consensus(_) => config.consensus_timeout(),
+        ClientRequest::AuthCert(_) => config.certs_timeout(),
+        ClientRequest::Microdescs(req) => for_descriptors(config, req.digests().count()),
+        #[cfg(feature = "routerdesc")]
+        ClientRequest::RouterDescs(req) => for_descriptors(config, req.digests().count()),
+        #[cfg(feature = "votes")]
+        ClientRequest::AuthVotes(_) => config.consensus_timeout(),
+    }
+}
+
+/// Return the longest response that `config` lets us accept for `request`.
+pub(crate) fn max_response_len(config: &DownloadScheduleConfig, request: &ClientRequest) -> usize {
+    LimitedRequest::new(config, request).max_response_len()
+}
+
+/// A directory request, along with the longest response that our
+/// configuration lets us accept for it, any extra content-encodings
+/// that we can undo, the host that we're asking for it, if we say, and a
+/// hook to rewrite it, if we have one.
+struct LimitedRequest<'a> {
+    /// The request itself.
+    inner: &'a (dyn Requestable + Send + Sync),
+    /// The most bytes we'll accept in response to `inner`.
+    max_len: usize,
+    /// The names of content-encodings that we have codecs for, beyond the
+    /// ones that the directory client supports.
+    encodings: Vec<String>,
+    /// A value to send in the `Host` header, if any.
+    host: Option<String>,
+    /// A function to rewrite the request before we send it, if any.
+    hook: Option<crate::RequestHook>,
+}
+
+impl<'a> LimitedRequest<'a> {
+    /// Return a [`LimitedRequest`] for `request`, with a size limit taken
+    /// from `config`.
+    ///
+    /// A request for several documents may get a response as large as all
+    /// of them together.
+    fn new(config: &DownloadScheduleConfig, request: &'a ClientRequest) -> Self {
+        let inner = request.as_requestable();
+        let max_len = match request {
+            ClientRequest::Consensus(_) => config.max_consensus_size(),
+            ClientRequest::AuthCert(req) => req
+                .keys()
+                .count()
+                .saturating_mul(config.max_authcert_size()),
+            ClientRequest::Microdescs(req) => req
+                .digests()
+                .count()
+                .saturating_mul(config.max_microdesc_size()),
+            #[cfg(feature = "routerdesc")]
+            ClientRequest::RouterDescs(_) => inner.max_response_len(),
+            // A vote is about as large as a consensus.
+            #[cfg(feature = "votes")]
+            ClientRequest::AuthVotes(req) => req
+                .authority_ids()
+                .count()
+                .saturating_mul(config.max_consensus_size()),
+        };
+        LimitedRequest {
+            inner,
+            max_len,
+            encodings: Vec::new(),
+            host: None,
+            hook: None,
+        }
+    }
+
+    /// Return this request, telling directory caches that we also accept
+    /// responses in each of `encodings`.
+    fn with_encodings(mut self, encodings: Vec<String>) -> Self {
+        self.encodings = encodings;
+        self
+    }
+
+    /// Return this request, sending `host` (if provided) in its `Host`
+    /// header.
+    ///
+    /// Tor directory caches don't need this, but a mirror behind a web
+    /// server might.
+    fn with_host(mut self, host: Option<&str>) -> Self {
+        self.host = host.map(str::to_owned);
+        self
+    }
+
+    /// Return this request, letting `hook` (if provided) rewrite it before
+    /// we send it.
+    fn with_hook(mut self, hook: Option<crate::RequestHook>) -> Self {
+        self.hook = hook;
+        self
+    }
+
+    /// Construct the HTTP request for this request, before our hook (if
+    /// any) has seen it.
+    fn unhooked_request(&self) -> tor_dirclient::Result<http::Request<()>> {
+        let mut req = self.inner.make_request()?;
+        if !self.encodings.is_empty() {
+            let headers = req.headers_mut();
+            let mut accept = headers
+                .get(http::header::ACCEPT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("identity")
+                .to_string();
+            for encoding in &self.encodings {
+                accept.push_str(", ");
+                accept.push_str(encoding);
+            }
+            let accept = http::HeaderValue::from_str(&accept).map_err(http::Error::from)?;
+            headers.insert(http::header::ACCEPT_ENCODING, accept);
+        }
+        if let Some(host) = &self.host {
+            let host = http::HeaderValue::from_str(host).map_err(http::Error::from)?;
+            req.headers_mut().insert(http::header::HOST, host);
+        }
+        Ok(req)
+    }
+}
+
+/// Return the content-encodings that `req` says it accepts.
+fn accepted_encodings(req: &http::Request<()>) -> Vec<&str> {
+    req.headers()
+        .get_all(http::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .collect()
+}
+
+/// Return an error message if `rewritten` would ask for anything different
+/// from `original`, or for a response that we couldn't decode.
+fn check_rewritten_request(
+    original: &http::Request<()>,
+    rewritten: &http::Request<()>,
+) -> std::result::Result<(), &'static str> {
+    if rewritten.method() != original.method() {
+        return Err("it changed the method");
+    }
+    if rewritten.uri() != original.uri() {
+        return Err("it changed the URI");
+    }
+    if rewritten.version() != original.version() {
+        return Err("it changed the HTTP version");
+    }
+    let headers = rewritten.headers();
+    if headers.contains_key(http::header::CONTENT_LENGTH)
+        || headers.contains_key(http::header::TRANSFER_ENCODING)
+    {
+        return Err("it added a request body");
+    }
+    let ok_encodings = accepted_encodings(original);
+    if accepted_encodings(rewritten)
+        .iter()
+        .any(|e| !ok_encodings.contains(e))
+    {
+        return Err("it asked for a content-encoding that we can't decode");
+    }
+    Ok(())
+}
+
+impl Requestable for LimitedRequest<'_> {
+    fn make_request(&self) -> tor_dirclient::Result<http::Request<()>> {
+        let req = self.unhooked_request()?;
+        let hook = match &self.hook {
+            Some(hook) => hook,
+            None => return Ok(req),
+        };
+        let mut rewritten = self.unhooked_request()?;
+        hook(&mut rewritten);
+        match check_rewritten_request(&req, &rewritten) {
+            Ok(()) => Ok(rewritten),
+            Err(why) => {
+                warn!("Ignoring rewritten directory request: {}", why);
+                Ok(req)
+            }
+        }
+    }
+    fn partial_docs_ok(&self) -> bool {
+        self.inner.partial_docs_ok()
+    }
+    fn max_response_len(&self) -> usize {
+        self.max_len
+    }
+    fn handles_encoding(&self, encoding: &str) -> bool {
+        self.encodings.iter().any(|e| e == encoding)
+    }
+}
+
+/// Wait for `future` to finish on `runtime`, but fail with a timeout error if
+/// it takes longer than `timeout`.
+async fn with_timeout<R, F, T, E>(runtime: &R, timeout: Duration, future: F) -> Result<T>
+where
+    R: Runtime,
+    F: std::future::Future<Output = std::result::Result<T, E>>,
+    E: Into<Error>,
+{
+    match runtime.timeout(timeout, future).await {
+        Ok(outcome) => outcome.map_err(Into::into),
+        Err(_) => Err(tor_dirclient::Error::DirTimeout.into()),
+    }
+}
+
+/// Launch a single client request, and return the response it received
+/// along with how long it took.
+///
+/// If a download rate limit is configured, hold back the response until
+/// the limit allows us to take it in.  (The time we report for the request
+/// doesn't include this wait.)
+async fn fetch_timed<R: Runtime>(
+    dirmgr: Arc<DirMgr<R>>,
+    request: ClientRequest,
+) -> (Result<(ClientRequest, DirResponse)>, Duration) {
+    let start = dirmgr.runtime.now();
+    let outcome = fetch_single(Arc::clone(&dirmgr), request).await;
+    let elapsed = dirmgr.runtime.now().saturating_duration_since(start);
+    if let Ok((_, response)) = &outcome {
+        dirmgr.note_bytes_received(response);
+    }
+    // A cache that says it has what we asked for, and then sends
+    // nothing, hasn't helped us: treat that as a failure, so that
+    // we ask somebody else next time.
+    let outcome = match outcome {
+        Ok((_, response)) if response.status_code() == 200 && response.output().is_empty() => {
+            dirmgr.note_cache_error(response.source());
+            Err(Error::EmptyResponse)
+        }
+        other => other,
     };
-    let resource =
-        tor_dirclient::get_resource(request.as_requestable(), dirinfo, &dirmgr.runtime, circmgr)
-            .await?;
+    if let Ok((_, response)) = &outcome {
+        let max_rate = dirmgr.config.get().schedule().max_download_rate();
+        dirmgr
+            .download_throttle
+            .consume(&dirmgr.runtime, response.output().len(), max_rate)
+            .await;
+    }
+    (outcome, elapsed)
+}
 
-    Ok((request, resource))
+/// A shared set of the documents that we still want from the requests that
+/// [`fetch_multiple`] launches.
+///
+/// Cloning a `WantedDocs` gives another handle to the same set.  Once
+/// nothing in the set is among the documents that a request asks for, that
+/// request is abandoned, whether or not it has been launched.
+#[derive(Clone)]
+pub(crate) struct WantedDocs {
+    /// The documents that we still want.
+    docs: Arc<Mutex<HashSet<DocId>>>,
+    /// A sender that we bump whenever `docs` shrinks.
+    send_changed: Arc<Mutex<postage::watch::Sender<u64>>>,
+    /// A receiver that requests watch to learn when `docs` has shrunk.
+    receive_changed: postage::watch::Receiver<u64>,
+}
+
+impl WantedDocs {
+    /// Make a new `WantedDocs` that wants every document in `docs`.
+    pub(crate) fn new(docs: impl IntoIterator<Item = DocId>) -> Self {
+        let (send_changed, receive_changed) = postage::watch::channel();
+        WantedDocs {
+            docs: Arc::new(Mutex::new(docs.into_iter().collect())),
+            send_changed: Arc::new(Mutex::new(send_changed)),
+            receive_changed,
+        }
+    }
+
+    /// Stop wanting every document that isn't in `still_missing`.
+    pub(crate) fn retain(&self, still_missing: &[DocId]) {
+        let still_missing: HashSet<_> = still_missing.iter().collect();
+        let removed = {
+            let mut docs = self.docs.lock().expect("poisoned lock");
+            let n_before = docs.len();
+            docs.retain(|id| still_missing.contains(id));
+            docs.len() != n_before
+        };
+        if removed {
+            self.note_changed();
+        }
+    }
+
+    /// Stop wanting `id`.
+    #[cfg(test)]
+    pub(crate) fn remove(&self, id: &DocId) {
+        if self.docs.lock().expect("poisoned lock").remove(id) {
+            self.note_changed();
+        }
+    }
+
+    /// Wake up every request that is waiting to learn whether we still want
+    /// its documents.
+    fn note_changed(&self) {
+        let mut sender = self.send_changed.lock().expect("poisoned lock");
+        *sender.borrow_mut() += 1;
+    }
+
+    /// Return true if we still want any of `docs`.
+    fn wants_any(&self, docs: &[DocId]) -> bool {
+        let wanted = self.docs.lock().expect("poisoned lock");
+        docs.iter().any(|id| wanted.contains(id))
+    }
+
+    /// Return once we no longer want any of `docs`.
+    async fn wait_until_unwanted(&self, docs: &[DocId]) {
+        let mut changed = self.receive_changed.clone();
+        while changed.next().await.is_some() {
+            if !self.wants_any(docs) {
+                return;
+            }
+        }
+        // The sender is gone, so the set can't change any more.
+        futures::future::pending().await
+    }
+}
+
+/// Launch `request` as with [`fetch_timed`], unless we stop wanting all of
+/// the documents that it asks for, according to `wanted`.  In that case,
+/// abandon the request, and return None.
+///
+/// Requests for documents we can't name (like a consensus) are never
+/// abandoned.
+async fn fetch_if_wanted<R: Runtime>(
+    dirmgr: Arc<DirMgr<R>>,
+    request: ClientRequest,
+    wanted: WantedDocs,
+) -> Option<(Result<(ClientRequest, DirResponse)>, Duration)> {
+    let docs = match request.docids() {
+        Some(docs) => docs,
+        None => return Some(fetch_timed(dirmgr, request).await),
+    };
+    if !wanted.wants_any(&docs) {
+        trace!(
+            "No longer want any of the {} documents in a request; not launching it.",
+            docs.len()
+        );
+        return None;
+    }
+    futures::select_biased! {
+        outcome = fetch_timed(dirmgr, request).fuse() => Some(outcome),
+        () = wanted.wait_until_unwanted(&docs).fuse() => {
+            trace!(
+                "No longer want any of the {} documents in a request; abandoning it.",
+                docs.len()
+            );
+            None
+        }
+    }
 }
 
 /// Launch a set of download requests for a set of missing objects in
-/// `missing`, and return each request along with the response it received.
+/// `missing`, and return a stream of each request along with the response it
+/// received and how long it took, in the order that the responses arrive.
+///
+/// Don't launch more than `parallelism` requests at once.  If the
+/// configuration has a separate limit on microdescriptor requests, then
+/// microdescriptor requests count against that limit (or `parallelism`,
+/// if it's lower) instead, so that they can't hold up requests for other
+/// documents.  Requests are only launched as the stream is polled, and
+/// dropping the stream cancels any requests that are still in progress.
 ///
-/// Don't launch more than `parallelism` requests at once.
-async fn fetch_multiple<R: Runtime>(
+/// Once we no longer want any of the documents that a request asks for,
+/// according to `wanted`, we abandon that request, and it doesn't appear in
+/// the stream.
+pub(crate) fn fetch_multiple<R: Runtime>(
     dirmgr: Arc<DirMgr<R>>,
     missing: Vec<DocId>,
     parallelism: usize,
-) -> Result<Vec<(ClientRequest, DirResponse)>> {
+    wanted: WantedDocs,
+) -> Result<impl futures::Stream<Item = (Result<(ClientRequest, DirResponse)>, Duration)>> {
     let mut requests = Vec::new();
     for (_type, query) in docid::partition_by_type(missing.into_iter()) {
         requests.extend(dirmgr.query_into_requests(query)?);
     }
 
-    // TODO: instead of waiting for all the queries to finish, we
-    // could stream the responses back or something.
-    let responses: Vec<Result<(ClientRequest, DirResponse)>> = futures::stream::iter(requests)
-        .map(|query| fetch_single(Arc::clone(&dirmgr), query))
+    let (md_requests, other_requests, md_parallelism) =
+        match dirmgr.config.get().schedule().max_microdesc_requests() {
+            // No separate limit: every request shares `parallelism`.
+            0 => (Vec::new(), requests, parallelism),
+            n => {
+                let (md_requests, other_requests): (Vec<_>, Vec<_>) = requests
+                    .into_iter()
+                    .partition(|r| matches!(r, ClientRequest::Microdescs(_)));
+                (
+                    md_requests,
+                    other_requests,
+                    std::cmp::min(parallelism, n.into()),
+                )
+            }
+        };
+
+    let md_dirmgr = Arc::clone(&dirmgr);
+    let md_wanted = wanted.clone();
+    let md_fetched = futures::stream::iter(md_requests)
+        .map(move |query| fetch_if_wanted(Arc::clone(&md_dirmgr), query, md_wanted.clone()))
+        .buffer_unordered(md_parallelism)
+        .filter_map(futures::future::ready);
+    let other_fetched = futures::stream::iter(other_requests)
+        .map(move |query| fetch_if_wanted(Arc::clone(&dirmgr), query, wanted.clone()))
         .buffer_unordered(parallelism)
-        .collect()
-        .await;
+        .filter_map(futures::future::ready);
 
-    let mut useful_responses = Vec::new();
-    for r in responses {
-        // TODO: on some error cases we might want to stop using this source.
-        match r {
-            Ok((request, response)) => {
-                if response.status_code() == 200 {
-                    useful_responses.push((request, response));
-                } else {
-                    trace!(
-                        "cache declined request; reported status {:?}",
-                        response.status_code()
-                    );
-                }
-            }
-            Err(e) => warn!("error while downloading: {:?}", e),
+    Ok(futures::stream::select(other_fetched, md_fetched))
+}
+
+/// Helper: decide how many requests to launch at once in each download
+/// attempt, based on how the requests in the previous attempt went.
+///
+/// We start out at the configured parallelism.  After an attempt where most
+/// requests failed, we halve it: the link is probably too slow or lossy for
+/// all those requests to share.  After an attempt where every request
+/// succeeded, and responses didn't get much slower than they were before, we
+/// raise it by one, up to twice the configured value.
+///
+/// If the configured value changes (as it can once we start retrying), we
+/// start over from the new value.
+#[derive(Clone, Debug)]
+struct Parallelism {
+    /// The configured parallelism that we started with.
+    baseline: usize,
+    /// How many requests to launch at once in the current attempt.
+    current: usize,
+    /// How many requests have succeeded in the current attempt.
+    n_succeeded: usize,
+    /// How many requests have failed in the current attempt.
+    n_failed: usize,
+    /// Total time taken by the requests that succeeded in the current attempt.
+    success_time: Duration,
+    /// Mean time taken by a successful request, during the last attempt in
+    /// which any request succeeded.
+    prev_mean: Option<Duration>,
+}
+
+impl Parallelism {
+    /// Make a new `Parallelism` that starts out at `baseline`.
+    fn new(baseline: usize) -> Self {
+        let baseline = std::cmp::max(baseline, 1);
+        Parallelism {
+            baseline,
+            current: baseline,
+            n_succeeded: 0,
+            n_failed: 0,
+            success_time: Duration::default(),
+            prev_mean: None,
+        }
+    }
+
+    /// Return how many requests to launch at once.
+    fn get(&self) -> usize {
+        self.current
+    }
+
+    /// Change the configured parallelism to `baseline`.
+    ///
+    /// If that's different from what it was, start over from the new value,
+    /// and forget about the requests we've noted since the last adjustment.
+    fn set_baseline(&mut self, baseline: usize) {
+        let baseline = std::cmp::max(baseline, 1);
+        if baseline == self.baseline {
+            return;
+        }
+        trace!(
+            "Changing configured download parallelism from {} to {}",
+            self.baseline,
+            baseline
+        );
+        self.baseline = baseline;
+        self.current = baseline;
+        self.n_succeeded = 0;
+        self.n_failed = 0;
+        self.success_time = Duration::default();
+    }
+
+    /// Note that a request succeeded after `elapsed`.
+    fn note_success(&mut self, elapsed: Duration) {
+        self.n_succeeded += 1;
+        self.success_time += elapsed;
+    }
+
+    /// Note that a request failed.
+    fn note_failure(&mut self) {
+        self.n_failed += 1;
+    }
+
+    /// Adjust our parallelism based on the requests we've noted since the
+    /// last time this was called, and start counting anew.
+    fn adjust(&mut self) {
+        let n_total = self.n_succeeded + self.n_failed;
+        if n_total == 0 {
+            return;
+        }
+
+        let mean = if self.n_succeeded > 0 {
+            // (We can't have more than u32::MAX requests in one attempt.)
+            Some(self.success_time / (self.n_succeeded as u32))
+        } else {
+            None
+        };
+        let slowed_down = match (self.prev_mean, mean) {
+            (Some(prev), Some(mean)) => mean > prev + prev / 2,
+            (_, _) => false,
+        };
+
+        let old = self.current;
+        if self.n_failed * 2 > n_total {
+            self.current = std::cmp::max(self.current / 2, 1);
+        } else if self.n_failed == 0 && !slowed_down {
+            self.current = std::cmp::min(self.current + 1, self.baseline * 2);
+        }
+        if self.current != old {
+            trace!(
+                n_succeeded = self.n_succeeded,
+                n_failed = self.n_failed,
+                "Changing download parallelism from {} to {}",
+                old,
+                self.current
+            );
+        }
+
+        self.prev_mean = mean.or(self.prev_mean);
+        self.n_succeeded = 0;
+        self.n_failed = 0;
+        self.success_time = Duration::default();
+    }
+}
+
+/// Helper: keep track of how many download attempts we've spent on each type
+/// of document that a state is missing.
+///
+/// Each type of document gets its own budget of attempts.  That way, if a
+/// state needs (say) a consensus and then some microdescriptors, the
+/// attempts that it took to get the consensus don't count against the
+/// microdescriptors, and a run of failed microdescriptor requests doesn't
+/// stop us from asking for anything else.
+#[derive(Clone, Debug)]
+struct RetryBudget {
+    /// How many attempts each type of document gets.
+    attempts: u32,
+    /// How many attempts we've made so far, by document type.
+    used: HashMap<DocType, u32>,
+}
+
+impl RetryBudget {
+    /// Make a new `RetryBudget` that allows `attempts` attempts for each
+    /// type of document.
+    fn new(attempts: u32) -> Self {
+        RetryBudget {
+            attempts,
+            used: HashMap::new(),
+        }
+    }
+
+    /// Return true if we have attempts left for documents of type `doctype`.
+    fn has_attempts_for(&self, doctype: &DocType) -> bool {
+        self.used.get(doctype).copied().unwrap_or(0) < self.attempts
+    }
+
+    /// Return true if none of the documents in `missing` are of a type
+    /// that we have attempts left for.
+    fn is_exhausted(&self, missing: &[DocId]) -> bool {
+        !missing
+            .iter()
+            .any(|doc| self.has_attempts_for(&doc.doctype()))
+    }
+
+    /// Return the documents in `missing` that are of a type we have
+    /// attempts left for, and use up one attempt for each of those types.
+    fn start_attempt(&mut self, missing: Vec<DocId>) -> Vec<DocId> {
+        let missing: Vec<_> = missing
+            .into_iter()
+            .filter(|doc| self.has_attempts_for(&doc.doctype()))
+            .collect();
+        let doctypes: BTreeSet<_> = missing.iter().map(DocId::doctype).collect();
+        for doctype in doctypes {
+            *self.used.entry(doctype).or_insert(0) += 1;
         }
+        missing
     }
+}
+
+/// Helper: remember what happened to the download attempts that we've made
+/// for a single state, so that we can say why we gave up on it.
+#[derive(Clone, Debug, Default)]
+struct AttemptLog {
+    /// How many responses have we gotten from directory caches, whether or
+    /// not they were any use?
+    n_responses: usize,
+    /// How many responses gave the state something that it wanted?
+    n_useful: usize,
+    /// The most recent consensus that a cache sent us, which was older than
+    /// we allow, if any.
+    too_old: Option<Error>,
+}
 
-    Ok(useful_responses)
+impl AttemptLog {
+    /// Return the reason that we couldn't advance a state which was missing
+    /// `wanted` when we started downloading, and is missing `missing` now.
+    fn reason(&self, wanted: &[DocId], missing: &[DocId]) -> CantAdvanceReason {
+        if self.n_useful > 0 {
+            let missing: HashSet<_> = missing.iter().collect();
+            CantAdvanceReason::InsufficientDocuments {
+                got: wanted.iter().filter(|d| !missing.contains(d)).count(),
+                needed: wanted.len(),
+            }
+        } else if self.n_responses > 0 {
+            CantAdvanceReason::NoUsableResponses
+        } else {
+            CantAdvanceReason::AllAttemptsErrored
+        }
+    }
 }
 
 /// Try tp update `state` by loading cached information from `dirmgr`.
@@ -128,22 +1173,60 @@ async fn load_once<R: Runtime>(
             "Found {} missing documents; trying to load them",
             missing.len()
         );
-        let documents = load_all(dirmgr, missing)?;
-        state.add_from_cache(documents, dirmgr.store_if_rw())
+        let documents = dirmgr.texts(missing)?;
+        state
+            .add_from_cache(documents, dirmgr.store_if_rw())
+            .map_err(cache_error)
     };
 
     if matches!(outcome, Ok(true)) {
-        dirmgr.update_status(state.bootstrap_status());
+        dirmgr.update_status(state.as_ref());
     }
+    dirmgr.note_missing(state.as_ref());
 
     outcome
 }
 
-/// Try to load as much state as possible for a provided `state` from the
-/// cache in `dirmgr`, advancing the state to the extent possible.
+/// Helper: If `err` means that we couldn't decode a document from our cache,
+/// replace it with an [`Error::CacheCorruption`].
 ///
-/// No downloads are performed; the provided state will not be reset.
-pub(crate) async fn load<R: Runtime>(
+/// The store only holds documents that we validated before saving them, so
+/// if we can't decode one now, the cache itself is at fault, and the caller
+/// may want to wipe it and bootstrap from the network instead.
+fn cache_error(err: Error) -> Error {
+    match err {
+        Error::BadUtf8InCache(_)
+        | Error::BadHexInCache(_)
+        | Error::NetDocError {
+            source: DocSource::LocalCache,
+            ..
+        } => {
+            warn!("Unable to decode a document from our cache: {}", err);
+            Error::CacheCorruption("undecodable document in cache")
+        }
+        other => other,
+    }
+}
+
+/// Return true if `missing` includes a consensus that we may only take from
+/// the cache, and never download.
+pub(crate) fn wants_consensus_from_cache(missing: &[DocId]) -> bool {
+    missing.iter().any(|id| {
+        matches!(
+            id,
+            DocId::LatestConsensus {
+                cache_usage: docid::CacheUsage::ConsensusFromCache,
+                ..
+            }
+        )
+    })
+}
+
+/// Try to load as much state as possible for a provided `state` from the
+/// cache in `dirmgr`, advancing the state to the extent possible.
+///
+/// No downloads are performed; the provided state will not be reset.
+pub(crate) async fn load<R: Runtime>(
     dirmgr: Arc<DirMgr<R>>,
     mut state: Box<dyn DirState>,
 ) -> Result<Box<dyn DirState>> {
@@ -153,16 +1236,18 @@ pub(crate) async fn load<R: Runtime>(
         let changed = load_once(&dirmgr, &mut state).await?;
 
         if state.can_advance() {
-            state = state.advance()?;
+            state = dirmgr.advance_state(state)?;
             safety_counter = 0;
         } else {
             if !changed {
                 break;
             }
             safety_counter += 1;
+            let max_iterations = state.max_load_iterations();
             assert!(
-                safety_counter < 100,
-                "Spent 100 iterations in the same state: this is a bug"
+                safety_counter < max_iterations,
+                "Spent {} iterations in the same state: this is a bug",
+                max_iterations
             );
         }
     }
@@ -174,41 +1259,159 @@ pub(crate) async fn load<R: Runtime>(
 /// and on success feed their results into the state object.
 ///
 /// This can launch one or more download requests, but will not launch more
-/// than `parallelism` requests at a time.
+/// than `parallelism.get()` requests at a time.  Before launching anything,
+/// adjust `parallelism` based on how the previous attempt went; then record
+/// how this attempt's requests go.
 ///
-/// Return true if the state reports that it changed.
+/// Only ask for the types of document that `budget` has attempts left for,
+/// and charge this attempt to each of those types.  Record the responses
+/// that we get in `log`.
+///
+/// Return whether the state reports that it changed, and which documents it
+/// no longer needs.
 async fn download_attempt<R: Runtime>(
     dirmgr: &Arc<DirMgr<R>>,
     state: &mut Box<dyn DirState>,
-    parallelism: usize,
-) -> Result<bool> {
+    parallelism: &mut Parallelism,
+    budget: &mut RetryBudget,
+    log: &mut AttemptLog,
+) -> Result<AttemptOutcome> {
     let mut changed = false;
-    let missing = state.missing_docs();
-    let fetched = fetch_multiple(Arc::clone(dirmgr), missing, parallelism).await?;
-    for (client_req, dir_response) in fetched {
-        let text =
-            String::from_utf8(dir_response.into_output()).map_err(Error::BadUtf8FromDirectory)?;
-        match dirmgr.expand_response_text(&client_req, text) {
+    let missing_before = state.missing_docs();
+    let missing = budget.start_attempt(missing_before.clone());
+    parallelism.adjust();
+    let wanted = WantedDocs::new(missing.iter().copied());
+    let mut fetched = fetch_multiple(
+        Arc::clone(dirmgr),
+        missing,
+        parallelism.get(),
+        wanted.clone(),
+    )?;
+    while let Some((r, elapsed)) = fetched.next().await {
+        // TODO: on some error cases we might want to stop using this source.
+        let (client_req, dir_response) = match r {
+            Ok((request, response)) if response.status_code() == 200 => {
+                parallelism.note_success(elapsed);
+                log.n_responses += 1;
+                (request, response)
+            }
+            Ok((_, response)) => {
+                parallelism.note_failure();
+                log.n_responses += 1;
+                trace!(
+                    "cache declined request; reported status {:?}",
+                    response.status_code()
+                );
+                continue;
+            }
+            Err(e) if e.retryable() => {
+                parallelism.note_failure();
+                warn!("error while downloading: {:?}", e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let source = dir_response.source().cloned();
+        let raw = dirmgr.keep_raw_response(&dir_response);
+        match dirmgr.expand_response_text(&client_req, dir_response) {
             Ok(text) => {
+                // Keep whatever this response makes us store all together,
+                // or not at all.
+                let scope = WriteScope::begin(&dirmgr.store)?;
                 let outcome = state.add_from_download(&text, &client_req, Some(&dirmgr.store));
+                if outcome.is_ok() {
+                    scope.commit()?;
+                }
+                if state.take_n_duplicates() > 0 {
+                    // This cache repeated itself: count that against it.
+                    dirmgr.note_cache_error(source.as_ref());
+                }
                 match outcome {
-                    Ok(b) => changed |= b,
+                    Ok(b) => {
+                        if b {
+                            log.n_useful += 1;
+                            if let Some((body, encoding)) = &raw {
+                                dirmgr.note_raw_response(&client_req, body, encoding.as_deref());
+                            }
+                            // Don't keep fetching anything that this
+                            // response made unnecessary.
+                            wanted.retain(&state.missing_docs());
+                        }
+                        changed |= b;
+                    }
+                    Err(e @ Error::NotEnoughSignatures { .. }) => {
+                        // This cache might be serving an old or forged
+                        // consensus: ask somebody else next time.
+                        warn!("error while adding directory info: {}", e);
+                        dirmgr.note_cache_error(source.as_ref());
+                    }
+                    Err(e @ Error::ConsensusTooOld { .. }) => {
+                        // This cache is behind, but another one might not
+                        // be: only give up if none of them can do better.
+                        warn!("error while adding directory info: {}", e);
+                        dirmgr.note_cache_error(source.as_ref());
+                        log.too_old = Some(e);
+                    }
                     // TODO: in this case we might want to stop using this source.
-                    Err(e) => warn!("error while adding directory info: {}", e),
+                    Err(e) if should_retry(dirmgr, &e) => {
+                        warn!("error while adding directory info: {}", e);
+                    }
+                    Err(e) => return Err(e),
                 }
             }
-            Err(e) => {
+            Err(e) if e.retryable() => {
                 // TODO: in this case we might want to stop using this source.
                 warn!("Error when expanding directory text: {}", e);
             }
+            Err(e) => return Err(e),
+        }
+
+        if state.can_advance() && dirmgr.config.get().cancel_unneeded_requests() {
+            // Whatever we're still waiting for, the next state won't want
+            // it: dropping `fetched` cancels the requests in progress.
+            trace!("Got enough to advance; abandoning other requests.");
+            break;
         }
     }
 
     if changed {
-        dirmgr.update_status(state.bootstrap_status());
+        dirmgr.update_status(state.as_ref());
+        dirmgr.note_missing(state.as_ref());
     }
 
-    Ok(changed)
+    let missing_after: HashSet<DocId> = state.missing_docs().into_iter().collect();
+    let obtained = missing_before
+        .into_iter()
+        .filter(|id| !missing_after.contains(id))
+        .collect();
+
+    Ok(AttemptOutcome { changed, obtained })
+}
+
+/// What happened during a single call to [`download_attempt`].
+#[derive(Clone, Debug, Default)]
+struct AttemptOutcome {
+    /// True if the state reported that it changed.
+    changed: bool,
+    /// The documents that the state was missing before the attempt, but
+    /// not after it.
+    obtained: Vec<DocId>,
+}
+
+impl AttemptOutcome {
+    /// Return a description of how many documents of each type we obtained,
+    /// for logging.
+    fn describe_obtained(&self) -> String {
+        let mut counts: BTreeMap<DocType, usize> = BTreeMap::new();
+        for id in &self.obtained {
+            *counts.entry(id.doctype()).or_insert(0) += 1;
+        }
+        let parts: Vec<_> = counts
+            .iter()
+            .map(|(doctype, n)| format!("{} {:?}", n, doctype))
+            .collect();
+        parts.join(", ")
+    }
 }
 
 /// Download information into a DirState state machine until it is
@@ -234,55 +1437,110 @@ pub(crate) async fn download<R: Runtime>(
 
     'next_state: loop {
         let retry_config = state.dl_config()?;
-        let parallelism = retry_config.parallelism();
+        let mut parallelism = Parallelism::new(retry_config.parallelism_for(0).into());
 
         // In theory this could be inside the loop below maybe?  If we
         // want to drop the restriction that the missing() members of a
         // state must never grow, then we'll need to move it inside.
         {
             let dirmgr = upgrade_weak_ref(&dirmgr)?;
+            dirmgr.apply_invalidations(state.as_mut());
             load_once(&dirmgr, &mut state).await?;
         }
 
         // Skip the downloads if we can...
         if state.can_advance() {
-            state = state.advance()?;
+            state = upgrade_weak_ref(&dirmgr)?.advance_state(state)?;
             continue 'next_state;
         }
         if state.is_ready(Readiness::Complete) {
             return Ok((state, None));
         }
+        // ...but never download a consensus that we may only take from the
+        // cache.
+        if wants_consensus_from_cache(&state.missing_docs()) {
+            warn!("No usable consensus in the cache, and we may not download one.");
+            return Ok((state, Some(Error::NoCachedConsensus)));
+        }
 
         let mut retry = retry_config.schedule();
+        let mut budget = RetryBudget::new(retry_config.n_attempts());
+        let mut log = AttemptLog::default();
+        let wanted = state.missing_docs();
+
+        // If we find that we were suspended while our consensus was still
+        // valid, we keep working on this state until that consensus
+        // expires, rather than resetting at the state's usual reset time.
+        let mut resume_deadline = None;
 
         // Make several attempts to fetch whatever we're missing,
         // until either we can advance, or we've got a complete
-        // document, or we run out of tries, or we run out of time.
-        'next_attempt: for attempt in retry_config.attempts() {
+        // document, or we run out of tries for everything we're missing,
+        // or we run out of time.
+        'next_attempt: for attempt in 0_u32.. {
+            if budget.is_exhausted(&state.missing_docs()) {
+                break;
+            }
             info!("{}: {}", attempt + 1, state.describe());
-            let reset_time = no_more_than_a_week_from(SystemTime::now(), state.reset_time());
+            parallelism.set_baseline(retry_config.parallelism_for(attempt).into());
+
+            // Before we ask the network for anything for the first time,
+            // wait a little, so that clients that start together don't all
+            // ask at once.
+            let jitter = upgrade_weak_ref(&dirmgr)?.take_startup_jitter();
+            if let Some(delay) = jitter {
+                info!("Waiting {:?} before our first directory request.", delay);
+                runtime.sleep(delay).await;
+            }
 
             {
                 let dirmgr = upgrade_weak_ref(&dirmgr)?;
+                let reset_time = local_reset_time(&dirmgr, state.as_ref(), resume_deadline);
+                let wait_start = (runtime.now(), runtime.wallclock());
+                // Only keep what this attempt stores once we're done
+                // waiting for it: if this future is dropped in the meantime
+                // (say, because the task running it was cancelled), the
+                // store shouldn't be left with part of an attempt.
+                let scope = WriteScope::begin(&dirmgr.store)?;
                 futures::select_biased! {
-                    outcome = download_attempt(&dirmgr, &mut state, parallelism.into()).fuse() => {
+                    outcome = download_attempt(&dirmgr, &mut state, &mut parallelism, &mut budget, &mut log).fuse() => {
                         match outcome {
-                            Err(e) => {
+                            Err(e) if should_retry(&dirmgr, &e) => {
+                                scope.commit()?;
                                 warn!("Error while downloading: {}", e);
                                 continue 'next_attempt;
                             }
-                            Ok(changed) => {
-                                changed
+                            Err(e) => return Err(e),
+                            Ok(outcome) => {
+                                scope.commit()?;
+                                if !outcome.obtained.is_empty() {
+                                    info!(
+                                        "{}: obtained {}",
+                                        attempt + 1,
+                                        outcome.describe_obtained()
+                                    );
+                                    dirmgr.note_obtained(&outcome.obtained);
+                                }
+                                outcome.changed
                             }
                         }
                     }
                     _ = runtime.sleep_until_wallclock(reset_time).fuse() => {
-                        // We need to reset. This can happen if (for
-                        // example) we're downloading the last few
-                        // microdescriptors on a consensus that now
-                        // we're ready to replace.
-                        state = state.reset()?;
-                        continue 'next_state;
+                        scope.commit()?;
+                        if let Some(deadline) = deadline_after_suspend(&dirmgr, wait_start) {
+                            // Our attempt was cut short, but not by a real
+                            // timeout.  Pick up whatever it managed to store.
+                            resume_deadline = Some(deadline);
+                            load_once(&dirmgr, &mut state).await?
+                        } else {
+                            // We need to reset. This can happen if (for
+                            // example) we're downloading the last few
+                            // microdescriptors on a consensus that now
+                            // we're ready to replace.
+                            dirmgr.note_reset(DirResetReason::ConsensusReplacement);
+                            state = dirmgr.reset_state(state)?;
+                            continue 'next_state;
+                        }
                     },
                 };
             }
@@ -301,17 +1559,25 @@ pub(crate) async fn download<R: Runtime>(
 
             if state.can_advance() {
                 // We have enough info to advance to another state.
-                state = state.advance()?;
+                state = upgrade_weak_ref(&dirmgr)?.advance_state(state)?;
                 continue 'next_state;
             } else {
                 // We should wait a bit, and then retry.
                 // TODO: we shouldn't wait on the final attempt.
-                let reset_time = no_more_than_a_week_from(SystemTime::now(), state.reset_time());
+                let reset_time =
+                    local_reset_time(&upgrade_weak_ref(&dirmgr)?, state.as_ref(), resume_deadline);
                 let delay = retry.next_delay(&mut rand::thread_rng());
+                let wait_start = (runtime.now(), runtime.wallclock());
                 futures::select_biased! {
                     _ = runtime.sleep_until_wallclock(reset_time).fuse() => {
-                        state = state.reset()?;
-                        continue 'next_state;
+                        let dirmgr = upgrade_weak_ref(&dirmgr)?;
+                        if let Some(deadline) = deadline_after_suspend(&dirmgr, wait_start) {
+                            resume_deadline = Some(deadline);
+                        } else {
+                            dirmgr.note_reset(DirResetReason::ConsensusReplacement);
+                            state = dirmgr.reset_state(state)?;
+                            continue 'next_state;
+                        }
                     }
                     _ = FutureExt::fuse(runtime.sleep(delay)) => {}
                 };
@@ -319,10 +1585,91 @@ pub(crate) async fn download<R: Runtime>(
         }
 
         // We didn't advance the state, after all the retries.
+        if let Some(e) = log.too_old.take() {
+            // Every consensus we could get was too old.  That's fatal
+            // unless we have a directory to use while we wait for a newer
+            // one.
+            if !should_retry(&upgrade_weak_ref(&dirmgr)?, &e) {
+                return Err(e);
+            }
+        }
+        let reason = log.reason(&wanted, &state.missing_docs());
         warn!(n_attempts=retry_config.n_attempts(),
               state=%state.describe(),
+              %reason,
               "Unable to advance downloading state");
-        return Ok((state, Some(Error::CantAdvanceState)));
+        return Ok((state, Some(Error::CantAdvanceState(reason))));
+    }
+}
+
+/// Helper: Return the time on `dirmgr`'s local wall clock at which we should
+/// give up on advancing `state` and reset it instead.
+///
+/// If `resume_deadline` is provided, don't reset before then.
+fn local_reset_time<R: Runtime>(
+    dirmgr: &DirMgr<R>,
+    state: &dyn DirState,
+    resume_deadline: Option<SystemTime>,
+) -> SystemTime {
+    let reset_time = no_more_than_a_week_from(dirmgr.trusted_now(), state.reset_time());
+    let reset_time = dirmgr.local_time(reset_time);
+    match resume_deadline {
+        Some(t) => std::cmp::max(t, reset_time),
+        None => reset_time,
+    }
+}
+
+/// How much further than the monotonic clock the wall clock can advance while
+/// we're waiting, before we decide that we must have been suspended.
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(60);
+
+/// Helper: Decide what to do when our reset time arrives, after a wait that
+/// began at the (monotonic, wallclock) times in `wait_start`.
+///
+/// Ordinarily, we should reset: return None.
+///
+/// But if the wall clock has moved far further than the monotonic clock
+/// during the wait, then we were probably suspended (for example, because a
+/// laptop went to sleep), and our reset time passed while we weren't
+/// running.  In that case, if our consensus is still valid, there's no
+/// reason to throw away our progress: return the time on our local wall
+/// clock at which that consensus stops being valid.
+fn deadline_after_suspend<R: Runtime>(
+    dirmgr: &DirMgr<R>,
+    wait_start: (Instant, SystemTime),
+) -> Option<SystemTime> {
+    let (mono_start, wall_start) = wait_start;
+    let mono_elapsed = dirmgr.runtime.now().saturating_duration_since(mono_start);
+    let wall_elapsed = dirmgr
+        .runtime
+        .wallclock()
+        .duration_since(wall_start)
+        .unwrap_or_default();
+    if wall_elapsed <= mono_elapsed + SUSPEND_THRESHOLD {
+        return None;
+    }
+
+    let valid_until = dirmgr.consensus_lifetime()?.valid_until();
+    if valid_until <= dirmgr.trusted_now() {
+        return None;
+    }
+    info!(
+        "It looks like we were suspended for about {:?}. Our consensus is still valid, so we'll keep downloading instead of starting over.",
+        wall_elapsed - mono_elapsed
+    );
+    Some(dirmgr.local_time(valid_until))
+}
+
+/// Return true if we should keep trying to download directory information
+/// for `dirmgr` after `err`.
+///
+/// Once we have a directory, we keep trying to replace it even after some
+/// errors that would end a bootstrap attempt.
+fn should_retry<R: Runtime>(dirmgr: &DirMgr<R>, err: &Error) -> bool {
+    if dirmgr.opt_netdir().is_some() {
+        err.retryable_with_directory()
+    } else {
+        err.retryable()
     }
 }
 
@@ -344,12 +1691,17 @@ fn no_more_than_a_week_from(now: SystemTime, v: Option<SystemTime>) -> SystemTim
 mod test {
     #![allow(clippy::unwrap_used)]
     use super::*;
-    use crate::storage::DynStore;
+    use crate::docmeta::ConsensusMeta;
+    use crate::state::GetConsensusState;
     use crate::test::new_mgr;
-    use crate::DownloadSchedule;
-    use std::convert::TryInto;
+    use crate::testing::{
+        authority_network, config_builder, consensus_time, fallback, fallback_network, md_digests,
+        DemoState, MemoryStore, MixedState, StubState, AUTHCERTS, CONSENSUS, H1, H2, H3, H4, H5,
+    };
+    use crate::{BootstrapPhase, CacheUsage, DirEvent, DirObserver, DownloadSchedule};
     use std::sync::Mutex;
-    use tor_netdoc::doc::microdesc::MdDigest;
+    use tor_netdoc::doc::authcert::AuthCertKeyIds;
+    use tor_netdoc::doc::netstatus::{ConsensusFlavor, Lifetime};
 
     #[test]
     fn week() {
@@ -371,128 +1723,6 @@ mod test {
         );
     }
 
-    /// A fake implementation of DirState that just wants a fixed set
-    /// of microdescriptors.  It doesn't care if it gets them: it just
-    /// wants to be told that the IDs exist.
-    #[derive(Debug, Clone)]
-    struct DemoState {
-        second_time_around: bool,
-        got_items: HashMap<MdDigest, bool>,
-    }
-
-    // Constants from Lou Reed
-    const H1: MdDigest = *b"satellite's gone up to the skies";
-    const H2: MdDigest = *b"things like that drive me out of";
-    const H3: MdDigest = *b"my mind i watched it for a littl";
-    const H4: MdDigest = *b"while i like to watch things on ";
-    const H5: MdDigest = *b"TV Satellite of love Satellite--";
-
-    impl DemoState {
-        fn new1() -> Self {
-            DemoState {
-                second_time_around: false,
-                got_items: vec![(H1, false), (H2, false)].into_iter().collect(),
-            }
-        }
-        fn new2() -> Self {
-            DemoState {
-                second_time_around: true,
-                got_items: vec![(H3, false), (H4, false), (H5, false)]
-                    .into_iter()
-                    .collect(),
-            }
-        }
-        fn n_ready(&self) -> usize {
-            self.got_items.values().filter(|x| **x).count()
-        }
-    }
-
-    impl DirState for DemoState {
-        fn describe(&self) -> String {
-            format!("{:?}", &self)
-        }
-        fn bootstrap_status(&self) -> crate::event::DirStatus {
-            crate::event::DirStatus::default()
-        }
-        fn is_ready(&self, ready: Readiness) -> bool {
-            match (ready, self.second_time_around) {
-                (_, false) => false,
-                (Readiness::Complete, true) => self.n_ready() == self.got_items.len(),
-                (Readiness::Usable, true) => self.n_ready() >= self.got_items.len() - 1,
-            }
-        }
-        fn can_advance(&self) -> bool {
-            if self.second_time_around {
-                false
-            } else {
-                self.n_ready() == self.got_items.len()
-            }
-        }
-        fn missing_docs(&self) -> Vec<DocId> {
-            self.got_items
-                .iter()
-                .filter_map(|(id, have)| {
-                    if *have {
-                        None
-                    } else {
-                        Some(DocId::Microdesc(*id))
-                    }
-                })
-                .collect()
-        }
-        fn add_from_cache(
-            &mut self,
-            docs: HashMap<DocId, DocumentText>,
-            _storage: Option<&Mutex<DynStore>>,
-        ) -> Result<bool> {
-            let mut changed = false;
-            for id in docs.keys() {
-                if let DocId::Microdesc(id) = id {
-                    if self.got_items.get(id) == Some(&false) {
-                        self.got_items.insert(*id, true);
-                        changed = true;
-                    }
-                }
-            }
-            Ok(changed)
-        }
-        fn add_from_download(
-            &mut self,
-            text: &str,
-            _request: &ClientRequest,
-            _storage: Option<&Mutex<DynStore>>,
-        ) -> Result<bool> {
-            let mut changed = false;
-            for token in text.split_ascii_whitespace() {
-                if let Ok(v) = hex::decode(token) {
-                    if let Ok(id) = v.try_into() {
-                        if self.got_items.get(&id) == Some(&false) {
-                            self.got_items.insert(id, true);
-                            changed = true;
-                        }
-                    }
-                }
-            }
-            Ok(changed)
-        }
-        fn dl_config(&self) -> Result<DownloadSchedule> {
-            Ok(DownloadSchedule::default())
-        }
-        fn advance(self: Box<Self>) -> Result<Box<dyn DirState>> {
-            if self.can_advance() {
-                Ok(Box::new(Self::new2()))
-            } else {
-                Ok(self)
-            }
-        }
-        fn reset_time(&self) -> Option<SystemTime> {
-            None
-        }
-        fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
-            Ok(Box::new(Self::new1()))
-        }
-    }
-
     #[test]
     fn all_in_cache() {
         // Let's try bootstrapping when everything is in the cache.
@@ -525,12 +1755,74 @@ mod test {
         });
     }
 
+    #[test]
+    fn observe_transitions() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+
+            {
+                let mut store = mgr.store_if_rw().unwrap().lock().unwrap();
+                for h in [H1, H2] {
+                    store
+                        .store_microdescs(&[("ignore", &h)], SystemTime::now())
+                        .unwrap();
+                }
+            }
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let seen2 = Arc::clone(&seen);
+            mgr.set_observer(
+                DirObserver::new().on_transition(move |t| seen2.lock().unwrap().push(t.clone())),
+            );
+            let mgr = Arc::new(mgr);
+
+            // We have everything that new1 wants, so we should advance to
+            // new2, and then get stuck.
+            let state = Box::new(DemoState::new1());
+            let result = super::load(Arc::clone(&mgr), state).await.unwrap();
+            assert!(!result.is_ready(Readiness::Usable));
+
+            let seen = seen.lock().unwrap();
+            assert_eq!(seen.len(), 1);
+            assert_eq!(seen[0].kind(), crate::TransitionKind::Advance);
+            assert!(seen[0].old_state().contains("second_time_around: false"));
+            assert!(seen[0].new_state().contains("second_time_around: true"));
+        });
+    }
+
+    #[test]
+    fn custom_store() {
+        // Make sure that we can load documents from a store that we were
+        // given, rather than the one under our cache path.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let mut store = MemoryStore::default();
+            for h in [H1, H2, H3, H4, H5] {
+                store.microdescs.insert(h, "ignore".into());
+            }
+            let config = config_builder("/there/is/no/cache/here".as_ref())
+                .build()
+                .unwrap();
+            let mgr = Arc::new(DirMgr::from_config_and_store(
+                config,
+                rt,
+                None,
+                false,
+                Box::new(store),
+            ));
+
+            let state = Box::new(DemoState::new1());
+            let result = super::load(Arc::clone(&mgr), state).await.unwrap();
+            assert!(result.is_ready(Readiness::Complete));
+        });
+    }
+
     #[test]
     fn partly_in_cache() {
         // Let's try bootstrapping with all of phase1 and part of
         // phase 2 in cache.
         tor_rtcompat::test_with_one_runtime!(|rt| async {
-            let (_tempdir, mgr) = new_mgr(rt);
+            use tor_rtcompat::BlockOn;
+            let rt = tor_rtmock::MockExecRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
 
             {
                 let mut store = mgr.store_if_rw().unwrap().lock().unwrap();
@@ -541,22 +1833,2195 @@ mod test {
                 }
             }
             {
-                let mut resp = CANNED_RESPONSE.lock().unwrap();
+                let mut resp = mgr.canned_response.lock().unwrap();
                 // H4 and H5.
-                *resp = Some(
+                *resp = Some(CannedResponse::new(
                     "7768696c652069206c696b6520746f207761746368207468696e6773206f6e20
-                     545620536174656c6c697465206f66206c6f766520536174656c6c6974652d2d"
-                        .to_owned(),
-                );
+                     545620536174656c6c697465206f66206c6f766520536174656c6c6974652d2d",
+                ));
             }
             let mgr = Arc::new(mgr);
             let mut on_usable = None;
 
             let state = Box::new(DemoState::new1());
-            let result = super::download(Arc::downgrade(&mgr), state, &mut on_usable)
-                .await
+            let result = rt
+                .block_on(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
                 .unwrap();
             assert!(result.0.is_ready(Readiness::Complete));
         });
     }
+
+    #[test]
+    fn phase_transitions() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_rtcompat::BlockOn;
+            let rt = tor_rtmock::MockExecRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            assert_eq!(mgr.bootstrap_phase(), BootstrapPhase::GettingConsensus);
+
+            {
+                let mut store = mgr.store_if_rw().unwrap().lock().unwrap();
+                for h in [H1, H2, H3] {
+                    store
+                        .store_microdescs(&[("ignore", &h)], SystemTime::now())
+                        .unwrap();
+                }
+            }
+            {
+                let mut resp = mgr.canned_response.lock().unwrap();
+                // H4 and H5.
+                *resp = Some(CannedResponse::new(
+                    "7768696c652069206c696b6520746f207761746368207468696e6773206f6e20
+                     545620536174656c6c697465206f66206c6f766520536174656c6c6974652d2d",
+                ));
+            }
+            let mgr = Arc::new(mgr);
+            let mut events = mgr.bootstrap_events();
+
+            // The cache has everything for new1, and some of what new2
+            // wants, so loading takes us to the second phase.
+            let state: Box<dyn DirState> = Box::new(DemoState::new1());
+            assert_eq!(state.bootstrap_phase(), BootstrapPhase::GettingConsensus);
+            let state = rt.block_on(super::load(Arc::clone(&mgr), state)).unwrap();
+            assert_eq!(state.bootstrap_phase(), BootstrapPhase::GettingMicrodescs);
+            assert_eq!(mgr.bootstrap_phase(), BootstrapPhase::GettingMicrodescs);
+
+            // Downloading the rest finishes the job.
+            let mut on_usable = None;
+            let (state, _) = rt
+                .block_on(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .unwrap();
+            assert_eq!(state.bootstrap_phase(), BootstrapPhase::Done);
+            assert_eq!(mgr.bootstrap_phase(), BootstrapPhase::Done);
+
+            // The phase shows up in our progress events too.
+            let status = rt.block_on(events.next()).unwrap();
+            assert_eq!(status.phase(), BootstrapPhase::Done);
+
+            // Starting over takes us back to the beginning.
+            let state = mgr.reset_state(state).unwrap();
+            assert_eq!(state.bootstrap_phase(), BootstrapPhase::GettingConsensus);
+            assert_eq!(mgr.bootstrap_phase(), BootstrapPhase::GettingConsensus);
+        });
+    }
+
+    #[test]
+    fn missing_summary() {
+        // Make sure that the manager reports what its current state is
+        // still missing.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            assert!(mgr.missing_summary().is_empty());
+
+            {
+                let mut store = mgr.store_if_rw().unwrap().lock().unwrap();
+                store
+                    .store_microdescs(&[("ignore", &H3)], SystemTime::now())
+                    .unwrap();
+            }
+            let mgr = Arc::new(mgr);
+
+            let mut state: Box<dyn DirState> = Box::new(DemoState::new2());
+            let changed = super::load_once(&mgr, &mut state).await.unwrap();
+            assert!(changed);
+
+            let summary = mgr.missing_summary();
+            assert_eq!(summary.n_microdescs(), state.missing_docs().len());
+            assert_eq!(summary.n_microdescs(), 2);
+            assert!(!summary.consensus_missing());
+            assert_eq!(summary.n_authcerts(), 0);
+        });
+    }
+
+    #[test]
+    fn partial_reset_after_failure() {
+        // When we can't finish the second phase of a download, we start
+        // that phase over, without losing what we got in the first one.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            // We can get everything that new1 wants, but nothing that new2
+            // wants.
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(format!(
+                "{}\n{}\n",
+                hex::encode(H1),
+                hex::encode(H2)
+            )));
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let seen2 = Arc::clone(&seen);
+            mgr.set_observer(
+                DirObserver::new().on_transition(move |t| seen2.lock().unwrap().push(t.clone())),
+            );
+            let mgr = Arc::new(mgr);
+
+            let state = Box::new(DemoState::new1());
+            let outcome = rt
+                .wait_for(DirMgr::download_forever(Arc::downgrade(&mgr), state, None))
+                .await;
+            assert!(matches!(outcome, Err(Error::CantAdvanceState(_))));
+
+            let seen = seen.lock().unwrap();
+            // We only got through the first phase once...
+            let advances: Vec<_> = seen
+                .iter()
+                .filter(|t| t.kind() == crate::TransitionKind::Advance)
+                .collect();
+            assert_eq!(advances.len(), 1);
+            assert!(advances[0]
+                .old_state()
+                .contains("second_time_around: false"));
+            // ... since every time we started over, we stayed in the second.
+            let resets: Vec<_> = seen
+                .iter()
+                .filter(|t| t.kind() == crate::TransitionKind::Reset)
+                .collect();
+            assert!(!resets.is_empty());
+            for t in resets {
+                assert!(t.old_state().contains("second_time_around: true"));
+                assert!(t.new_state().contains("second_time_around: true"));
+            }
+        });
+    }
+
+    #[test]
+    fn startup_jitter() {
+        // Make sure that we wait a random time within the configured window
+        // before our first network request, and only before the first.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let window = Duration::from_secs(60);
+            let mut sched = crate::DownloadScheduleConfig::builder();
+            sched.startup_jitter(window);
+            let config = config_builder(tempdir.path())
+                .schedule_config(sched.build().unwrap())
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+            {
+                let mut store = mgr.store_if_rw().unwrap().lock().unwrap();
+                for h in [H1, H2, H3] {
+                    store
+                        .store_microdescs(&[("ignore", &h)], SystemTime::now())
+                        .unwrap();
+                }
+            }
+            // H4 and H5.
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(
+                "7768696c652069206c696b6520746f207761746368207468696e6773206f6e20
+                 545620536174656c6c697465206f66206c6f766520536174656c6c6974652d2d",
+            ));
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let received2 = Arc::clone(&received);
+            let rt2 = rt.clone();
+            mgr.set_observer(DirObserver::new().on_bytes_received(move |_, _| {
+                received2.lock().unwrap().push(rt2.now());
+            }));
+            let mgr = Arc::new(mgr);
+
+            for first_time in [true, false] {
+                let start = rt.now();
+                received.lock().unwrap().clear();
+                let mut on_usable = None;
+                let state = Box::new(DemoState::new1());
+                let (state, err) = rt
+                    .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                    .await
+                    .unwrap();
+                assert!(err.is_none());
+                assert!(state.is_ready(Readiness::Complete));
+
+                let received = received.lock().unwrap();
+                assert_eq!(received.len(), 1);
+                let waited = received[0] - start;
+                if first_time {
+                    assert!(waited <= window);
+                } else {
+                    // We only wait before our very first request.
+                    assert_eq!(waited, Duration::default());
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn download_to_writable_store() {
+        // Make sure that when we read from one store and write to another,
+        // we use documents from both, but only save new ones to the
+        // writable store.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use crate::storage::{LayeredStore, SqliteStore, Store};
+            let base_dir = tempfile::TempDir::new().unwrap();
+            let scratch_dir = tempfile::TempDir::new().unwrap();
+            {
+                let mut base = SqliteStore::from_path(base_dir.path(), false).unwrap();
+                base.store_microdescs(&[("ignore", &H3)], SystemTime::now())
+                    .unwrap();
+            }
+            let base = SqliteStore::from_path(base_dir.path(), true).unwrap();
+            assert!(base.is_readonly());
+            let scratch = SqliteStore::from_path(scratch_dir.path(), false).unwrap();
+
+            let config = config_builder(scratch_dir.path()).build().unwrap();
+            let store = LayeredStore::new(Box::new(base), Box::new(scratch));
+            let mgr = DirMgr::from_config_and_store(config, rt, None, false, Box::new(store));
+            // H4 and H5.
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(
+                "7768696c652069206c696b6520746f207761746368207468696e6773206f6e20
+                 545620536174656c6c697465206f66206c6f766520536174656c6c6974652d2d",
+            ));
+            let mgr = Arc::new(mgr);
+
+            let mut state = DemoState::new2();
+            state.store_downloads = true;
+            let mut on_usable = None;
+            let (state, err) =
+                super::download(Arc::downgrade(&mgr), Box::new(state), &mut on_usable)
+                    .await
+                    .unwrap();
+            assert!(err.is_none());
+            // We could only have gotten H3 from the read-only store.
+            assert!(state.is_ready(Readiness::Complete));
+            drop(mgr);
+
+            let base = SqliteStore::from_path(base_dir.path(), true).unwrap();
+            let in_base = base.microdescs(&[H3, H4, H5]).unwrap();
+            assert_eq!(in_base.len(), 1);
+            assert!(in_base.contains_key(&H3));
+
+            let scratch = SqliteStore::from_path(scratch_dir.path(), true).unwrap();
+            let in_scratch = scratch.microdescs(&[H3, H4, H5]).unwrap();
+            assert_eq!(in_scratch.len(), 2);
+            assert!(in_scratch.contains_key(&H4));
+            assert!(in_scratch.contains_key(&H5));
+        });
+    }
+
+    #[test]
+    fn reset_event() {
+        // Make sure that we announce it when we reset a state because its
+        // reset time has arrived.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            let mgr = Arc::new(mgr);
+            let mut events = mgr.events();
+
+            let state = Box::new(
+                StubState::new(Vec::new()).reset_at(rt.wallclock() + Duration::from_secs(10)),
+            );
+            let mut on_usable = None;
+            let (state, err) = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+            assert!(err.is_none());
+            assert!(state.is_ready(Readiness::Complete));
+
+            assert_eq!(
+                events.next().now_or_never(),
+                Some(Some(DirEvent::Reset(DirResetReason::ConsensusReplacement)))
+            );
+        });
+    }
+
+    #[test]
+    fn resume_after_suspend() {
+        // Make sure that if we're suspended past our reset time, but our
+        // consensus is still valid, we keep going instead of resetting.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(""));
+            let start = rt.wallclock();
+            let hour = Duration::from_secs(3600);
+            {
+                let lifetime =
+                    Lifetime::new(start - hour, start + hour, start + 24 * hour).unwrap();
+                let meta = ConsensusMeta::new(lifetime, [1; 32], [2; 32]);
+                mgr.store_if_rw()
+                    .unwrap()
+                    .lock()
+                    .unwrap()
+                    .store_consensus(&meta, ConsensusFlavor::Microdesc, false, "ignored")
+                    .unwrap();
+            }
+            let mgr = Arc::new(mgr);
+            let mut events = mgr.events();
+
+            let state = Box::new(
+                StubState::new(vec![DocId::Microdesc(H1)])
+                    .reset_at(start + Duration::from_secs(10)),
+            );
+            let mut on_usable = None;
+            let rt2 = rt.clone();
+            let ((state, err), ()) = rt
+                .wait_for(futures::future::join(
+                    async {
+                        super::download(Arc::downgrade(&mgr), state, &mut on_usable)
+                            .await
+                            .unwrap()
+                    },
+                    async move {
+                        // Sleep through our reset time: only the wall clock
+                        // moves.
+                        rt2.sleep(Duration::from_secs(1)).await;
+                        rt2.jump_to(start + 2 * hour);
+                    },
+                ))
+                .await;
+
+            // We ran out of attempts without ever resetting.
+            assert!(err.is_some());
+            assert!(!state.is_ready(Readiness::Complete));
+            assert!(events.next().now_or_never().is_none());
+        });
+    }
+
+    #[test]
+    fn slow_response() {
+        // Make sure that a download which takes longer than our reset time
+        // doesn't keep us from resetting.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new(hex::encode(H1)).delay(Duration::from_secs(60)));
+            let mgr = Arc::new(mgr);
+            let mut events = mgr.events();
+
+            let start = rt.wallclock();
+            let state = Box::new(
+                StubState::new(vec![DocId::Microdesc(H1)])
+                    .reset_at(start + Duration::from_secs(10)),
+            );
+            let mut on_usable = None;
+            let (state, err) = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+            assert!(err.is_none());
+            assert!(state.is_ready(Readiness::Complete));
+            assert!(rt.wallclock() < start + Duration::from_secs(60));
+
+            assert_eq!(
+                events.next().now_or_never(),
+                Some(Some(DirEvent::Reset(DirResetReason::ConsensusReplacement)))
+            );
+        });
+    }
+
+    #[test]
+    fn cancel_download() {
+        // If the download future is dropped while an attempt is still
+        // waiting for some of its responses, nothing that the attempt stored
+        // is kept: not in the database, and not in `dir_blobs`.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let tempdir = tempfile::TempDir::new().unwrap();
+            // With a separate limit on microdescriptor requests, we launch
+            // the consensus request first.
+            let mut sched = crate::DownloadScheduleConfig::builder();
+            sched.max_microdesc_requests(1);
+            let config = config_builder(tempdir.path())
+                .schedule_config(sched.build().unwrap())
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+            // The consensus arrives right away; the microdescriptor takes a
+            // minute.
+            *mgr.fault_script.lock().unwrap() = Some(
+                FaultScript::new(CannedResponse::new("microdesc").delay(Duration::from_secs(60)))
+                    .at(0, CannedResponse::new("consensus")),
+            );
+            let mgr = Arc::new(mgr);
+
+            let blobs = tempdir.path().join("dir_blobs");
+            let n_blobs = || std::fs::read_dir(&blobs).unwrap().count();
+            let stored = || {
+                let store = mgr.store.lock().unwrap();
+                let consensus = store
+                    .latest_consensus(ConsensusFlavor::Microdesc, None)
+                    .unwrap();
+                let mds = store.microdescs(&[H1]).unwrap();
+                (consensus.is_some(), mds.len())
+            };
+
+            let state = Box::new(
+                StubState::new(vec![
+                    DocId::LatestConsensus {
+                        flavor: ConsensusFlavor::Microdesc,
+                        cache_usage: CacheUsage::CacheOkay,
+                    },
+                    DocId::Microdesc(H1),
+                ])
+                .store_responses(),
+            );
+            let mut on_usable = None;
+            {
+                let download = super::download(Arc::downgrade(&mgr), state, &mut on_usable).fuse();
+                futures::pin_mut!(download);
+                let rt2 = rt.clone();
+                let finished = rt
+                    .wait_for(async {
+                        futures::select_biased! {
+                            _ = download => true,
+                            () = rt2.sleep(Duration::from_secs(1)).fuse() => false,
+                        }
+                    })
+                    .await;
+                assert!(!finished);
+
+                // So far, the consensus is in the store...
+                assert_eq!(stored(), (true, 0));
+                assert_eq!(n_blobs(), 1);
+            }
+
+            // ... but once we drop the download, it's gone.
+            assert_eq!(stored(), (false, 0));
+            assert_eq!(n_blobs(), 0);
+        });
+    }
+
+    #[test]
+    fn truncated_response() {
+        // Make sure that a response cut off in the middle of a character
+        // is discarded without stopping the download.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let body = format!("{} {} \u{2014}", hex::encode(H1), hex::encode(H2));
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new(&body).truncate(body.len() - 1));
+            let mgr = Arc::new(mgr);
+
+            let mut state: Box<dyn DirState> = Box::new(DemoState::new1());
+            let changed = super::download_attempt(
+                &mgr,
+                &mut state,
+                &mut Parallelism::new(1),
+                &mut RetryBudget::new(1),
+                &mut AttemptLog::default(),
+            )
+            .await
+            .unwrap()
+            .changed;
+            assert!(!changed);
+            assert_eq!(state.missing_docs().len(), 2);
+
+            // The whole body, on the other hand, is fine.
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(&body));
+            let changed = super::download_attempt(
+                &mgr,
+                &mut state,
+                &mut Parallelism::new(1),
+                &mut RetryBudget::new(1),
+                &mut AttemptLog::default(),
+            )
+            .await
+            .unwrap()
+            .changed;
+            assert!(changed);
+            assert!(state.missing_docs().is_empty());
+        });
+    }
+
+    #[test]
+    fn obtained_docs() {
+        // Make sure that each attempt reports exactly the documents that it
+        // newly got, and that the download loop tells our observer.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let mgr = Arc::new(mgr);
+            let mut state: Box<dyn DirState> = Box::new(DemoState::new1());
+
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(hex::encode(H1)));
+            let outcome = super::download_attempt(
+                &mgr,
+                &mut state,
+                &mut Parallelism::new(1),
+                &mut RetryBudget::new(1),
+                &mut AttemptLog::default(),
+            )
+            .await
+            .unwrap();
+            assert!(outcome.changed);
+            assert_eq!(outcome.obtained, vec![DocId::Microdesc(H1)]);
+            assert_eq!(outcome.describe_obtained(), "1 Microdesc");
+
+            // Getting H1 again doesn't count.
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(format!(
+                "{} {}",
+                hex::encode(H1),
+                hex::encode(H2)
+            )));
+            let outcome = super::download_attempt(
+                &mgr,
+                &mut state,
+                &mut Parallelism::new(1),
+                &mut RetryBudget::new(1),
+                &mut AttemptLog::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(outcome.obtained, vec![DocId::Microdesc(H2)]);
+
+            // Nothing new, nothing obtained.
+            let outcome = super::download_attempt(
+                &mgr,
+                &mut state,
+                &mut Parallelism::new(1),
+                &mut RetryBudget::new(1),
+                &mut AttemptLog::default(),
+            )
+            .await
+            .unwrap();
+            assert!(!outcome.changed);
+            assert!(outcome.obtained.is_empty());
+
+            // The download loop tells our observer.
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let seen2 = Arc::clone(&seen);
+            mgr.set_observer(
+                DirObserver::new()
+                    .on_obtained(move |docs| seen2.lock().unwrap().push(docs.to_vec())),
+            );
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(format!(
+                "{} {} {}",
+                hex::encode(H3),
+                hex::encode(H4),
+                hex::encode(H5)
+            )));
+            let state = Box::new(DemoState::new2());
+            let mut on_usable = None;
+            let (state, _) = super::download(Arc::downgrade(&mgr), state, &mut on_usable)
+                .await
+                .unwrap();
+            assert!(state.is_ready(Readiness::Complete));
+            let seen = seen.lock().unwrap();
+            assert_eq!(seen.len(), 1);
+            let mut got = seen[0].clone();
+            got.sort_by_key(|id| format!("{:?}", id));
+            let mut expected = vec![
+                DocId::Microdesc(H3),
+                DocId::Microdesc(H4),
+                DocId::Microdesc(H5),
+            ];
+            expected.sort_by_key(|id| format!("{:?}", id));
+            assert_eq!(got, expected);
+        });
+    }
+
+    #[test]
+    fn raw_responses() {
+        // Our raw-response observer should get the exact body of each
+        // response whose documents we accept, with the request it answered.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let mgr = Arc::new(mgr);
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let seen2 = Arc::clone(&seen);
+            mgr.set_observer(
+                DirObserver::new().on_raw_response(move |req, body, encoding| {
+                    seen2.lock().unwrap().push((
+                        req.uri().to_string(),
+                        body.to_vec(),
+                        encoding.map(String::from),
+                    ));
+                }),
+            );
+            let body = format!("{} {}", hex::encode(H1), hex::encode(H2));
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(&body));
+
+            let mut state: Box<dyn DirState> = Box::new(DemoState::new1());
+            let outcome = super::download_attempt(
+                &mgr,
+                &mut state,
+                &mut Parallelism::new(1),
+                &mut RetryBudget::new(1),
+                &mut AttemptLog::default(),
+            )
+            .await
+            .unwrap();
+            assert!(outcome.changed);
+            {
+                let seen = seen.lock().unwrap();
+                assert_eq!(seen.len(), 1);
+                let (uri, raw, encoding) = &seen[0];
+                assert!(uri.starts_with("/tor/micro/d/"));
+                assert_eq!(raw, body.as_bytes());
+                assert_eq!(encoding, &None);
+            }
+
+            // A response with nothing that we want isn't reported.
+            let mut state: Box<dyn DirState> = Box::new(DemoState::new2());
+            let outcome = super::download_attempt(
+                &mgr,
+                &mut state,
+                &mut Parallelism::new(1),
+                &mut RetryBudget::new(1),
+                &mut AttemptLog::default(),
+            )
+            .await
+            .unwrap();
+            assert!(!outcome.changed);
+            assert_eq!(seen.lock().unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn percent_increases() {
+        // Make sure that our estimate of how far along we are goes up as
+        // documents arrive, and reaches 100 once we have them all.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let mgr = Arc::new(mgr);
+            assert_eq!(mgr.bootstrap_percent(), 0);
+
+            let now = mgr.trusted_now();
+            let hour = Duration::from_secs(3600);
+            let mut state = DemoState::new2();
+            state.lifetime = Some(Lifetime::new(now - hour, now + hour, now + 2 * hour).unwrap());
+            let mut state: Box<dyn DirState> = Box::new(state);
+
+            let mut last = mgr.bootstrap_percent();
+            for h in [H3, H4, H5] {
+                *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(hex::encode(h)));
+                let changed = super::download_attempt(
+                    &mgr,
+                    &mut state,
+                    &mut Parallelism::new(1),
+                    &mut RetryBudget::new(1),
+                    &mut AttemptLog::default(),
+                )
+                .await
+                .unwrap()
+                .changed;
+                assert!(changed);
+                let percent = mgr.bootstrap_percent();
+                assert!(percent > last);
+                last = percent;
+            }
+            assert_eq!(last, 100);
+        });
+    }
+
+    #[test]
+    fn empty_response() {
+        // Make sure that a successful response with nothing in it counts as
+        // a failure, and makes us move on to another cache.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use std::sync::atomic::Ordering;
+            let (_tempdir, mgr) = new_mgr(rt);
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(""));
+            let mgr = Arc::new(mgr);
+            let fallback_before = mgr.next_fallback.load(Ordering::SeqCst);
+
+            let mut state: Box<dyn DirState> = Box::new(DemoState::new1());
+            let mut parallelism = Parallelism::new(4);
+            let changed = super::download_attempt(
+                &mgr,
+                &mut state,
+                &mut parallelism,
+                &mut RetryBudget::new(1),
+                &mut AttemptLog::default(),
+            )
+            .await
+            .unwrap()
+            .changed;
+            assert!(!changed);
+            assert_eq!(state.missing_docs().len(), 2);
+            assert_eq!(parallelism.n_succeeded, 0);
+            assert_eq!(parallelism.n_failed, 1);
+            assert_eq!(
+                mgr.next_fallback.load(Ordering::SeqCst),
+                fallback_before.wrapping_add(1)
+            );
+        });
+    }
+
+    #[test]
+    fn parallelism() {
+        let mut p = Parallelism::new(2);
+        assert_eq!(p.get(), 2);
+
+        // Nothing happened: no change.
+        p.adjust();
+        assert_eq!(p.get(), 2);
+
+        // Everything succeeded: go up, but no more than twice the baseline.
+        for _ in 0..5 {
+            p.note_success(Duration::from_secs(1));
+            p.adjust();
+        }
+        assert_eq!(p.get(), 4);
+
+        // Responses got a lot slower: don't go up.
+        p.note_success(Duration::from_secs(3));
+        p.adjust();
+        assert_eq!(p.get(), 4);
+
+        // Most requests failed: go down, but never below 1.
+        p.note_success(Duration::from_secs(3));
+        p.note_failure();
+        p.note_failure();
+        p.adjust();
+        assert_eq!(p.get(), 2);
+        for _ in 0..3 {
+            p.note_failure();
+            p.adjust();
+        }
+        assert_eq!(p.get(), 1);
+    }
+
+    #[test]
+    fn retry_parallelism() {
+        // Our first attempt launches as many requests at once as the
+        // schedule says; our retries launch fewer.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            let delay = Duration::from_secs(1);
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new("nothing useful").delay(delay));
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let received2 = Arc::clone(&received);
+            let rt2 = rt.clone();
+            mgr.set_observer(DirObserver::new().on_bytes_received(move |_, _| {
+                received2.lock().unwrap().push(rt2.now());
+            }));
+            let mgr = Arc::new(mgr);
+
+            // Enough microdescriptors for four requests.
+            let wants = md_digests(2000).into_iter().map(DocId::Microdesc).collect();
+            let state = Box::new(StubState::new(wants).schedule(
+                DownloadSchedule::new(2, Duration::from_secs(1), 4).with_retry_parallelism(1),
+            ));
+            let mut on_usable = None;
+            let (_, err) = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+            assert!(matches!(err, Some(Error::CantAdvanceState(_))));
+
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 8);
+            // The first attempt's requests all ran at once...
+            assert!(received[1..4].iter().all(|t| *t == received[0]));
+            // ... but the second attempt's ran one at a time.
+            for pair in received[4..].windows(2) {
+                assert_eq!(pair[1] - pair[0], delay);
+            }
+        });
+    }
+
+    #[test]
+    fn reduce_parallelism_on_failure() {
+        // Make sure that after an attempt where our requests fail, we make
+        // fewer requests at once.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let body = format!("{} {}", hex::encode(H1), hex::encode(H2));
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(&body).fail());
+            let mgr = Arc::new(mgr);
+
+            let mut state: Box<dyn DirState> = Box::new(DemoState::new1());
+            let mut parallelism = Parallelism::new(4);
+            let changed = super::download_attempt(
+                &mgr,
+                &mut state,
+                &mut parallelism,
+                &mut RetryBudget::new(1),
+                &mut AttemptLog::default(),
+            )
+            .await
+            .unwrap()
+            .changed;
+            assert!(!changed);
+            assert_eq!(parallelism.get(), 4);
+
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(&body));
+            let changed = super::download_attempt(
+                &mgr,
+                &mut state,
+                &mut parallelism,
+                &mut RetryBudget::new(1),
+                &mut AttemptLog::default(),
+            )
+            .await
+            .unwrap()
+            .changed;
+            assert!(changed);
+            assert_eq!(parallelism.get(), 2);
+        });
+    }
+
+    #[test]
+    fn recover_through_scripted_faults() {
+        // Make sure that a download keeps going through a timeout and a
+        // refusal, and finishes once a cache finally answers.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            let body = format!(
+                "{} {} {}",
+                hex::encode(H3),
+                hex::encode(H4),
+                hex::encode(H5)
+            );
+            *mgr.fault_script.lock().unwrap() = Some(
+                FaultScript::new(CannedResponse::new(&body))
+                    .timeout_at(0)
+                    .status_at(1, 503),
+            );
+            let mgr = Arc::new(mgr);
+
+            let state = Box::new(DemoState::new2());
+            let mut on_usable = None;
+            let (state, err) = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+            assert!(err.is_none());
+            assert!(state.is_ready(Readiness::Complete));
+
+            // We needed exactly one attempt for each step of the script.
+            let script = mgr.fault_script.lock().unwrap();
+            assert_eq!(script.as_ref().unwrap().n_requests(), 3);
+        });
+    }
+
+    #[test]
+    fn timeout_by_kind() {
+        // A microdescriptor request should give up on a slow response long
+        // before a consensus request does.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_dirclient::request::{ConsensusRequest, MicrodescRequest};
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new("ok").delay(Duration::from_secs(3600)));
+            let mgr = Arc::new(mgr);
+
+            let md_req = ClientRequest::Microdescs([H1, H2].iter().copied().collect());
+            let start = rt.now();
+            let outcome = rt.wait_for(fetch_single(Arc::clone(&mgr), md_req)).await;
+            let md_elapsed = rt.now() - start;
+            assert!(outcome.is_err());
+
+            let cons_req =
+                ClientRequest::Consensus(ConsensusRequest::new(ConsensusFlavor::Microdesc));
+            let start = rt.now();
+            let outcome = rt.wait_for(fetch_single(Arc::clone(&mgr), cons_req)).await;
+            let cons_elapsed = rt.now() - start;
+            assert!(outcome.is_err());
+
+            let config = DownloadScheduleConfig::default();
+            assert!(md_elapsed < cons_elapsed);
+            assert!(md_elapsed >= config.microdescs_timeout());
+            assert!(cons_elapsed >= config.consensus_timeout());
+            assert!(cons_elapsed < Duration::from_secs(3600));
+
+            // Bigger microdescriptor requests get more time.
+            let big_req = ClientRequest::Microdescs(
+                md_digests(500).into_iter().collect::<MicrodescRequest>(),
+            );
+            let md_req = ClientRequest::Microdescs([H1].iter().copied().collect());
+            assert!(request_timeout(&config, &big_req) > request_timeout(&config, &md_req));
+        });
+    }
+
+    #[test]
+    fn throttled_download() {
+        // Make sure that when we have a download rate limit, a large
+        // response takes as long as the limit says it should.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let mut sched = crate::DownloadScheduleConfig::builder();
+            sched.max_download_rate(1000);
+            let config = config_builder(tempdir.path())
+                .schedule_config(sched.build().unwrap())
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+            // 20,000 bytes, at 1000 bytes per second.
+            let body = format!("{} {}", hex::encode(H1), hex::encode(H2));
+            let body = format!("{:<20000}", body);
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(&body));
+            let mgr = Arc::new(mgr);
+
+            let mut state: Box<dyn DirState> = Box::new(DemoState::new1());
+            let start = rt.now();
+            let changed = rt
+                .wait_for(super::download_attempt(
+                    &mgr,
+                    &mut state,
+                    &mut Parallelism::new(1),
+                    &mut RetryBudget::new(1),
+                    &mut AttemptLog::default(),
+                ))
+                .await
+                .unwrap()
+                .changed;
+            assert!(changed);
+            assert!(rt.now() >= start + Duration::from_secs(20));
+        });
+    }
+
+    #[test]
+    fn prefer_faster_fallback() {
+        // Without a directory, we learn how quickly each fallback answers
+        // us, and we ask the faster one.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use std::sync::atomic::Ordering;
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let slow = fallback(1);
+            let fast = fallback(2);
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let config = config_builder(tempdir.path())
+                .network_config(fallback_network(vec![slow.clone(), fast.clone()]))
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+            *mgr.canned_response.lock().unwrap() = Some(
+                CannedResponse::new("ok")
+                    .cache_delay(*slow.rsa_identity(), Duration::from_secs(5))
+                    .cache_delay(*fast.rsa_identity(), Duration::from_secs(1)),
+            );
+            let mgr = Arc::new(mgr);
+            let request = || ClientRequest::Microdescs([H1].iter().copied().collect());
+
+            // Ask each fallback in turn, a few times.
+            for _ in 0..3 {
+                for idx in 0..2 {
+                    mgr.next_fallback.store(idx, Ordering::SeqCst);
+                    rt.wait_for(fetch_single(Arc::clone(&mgr), request()))
+                        .await
+                        .unwrap();
+                }
+            }
+            let slow_estimate = mgr.cache_latency.estimate(slow.rsa_identity()).unwrap();
+            let fast_estimate = mgr.cache_latency.estimate(fast.rsa_identity()).unwrap();
+            assert!(fast_estimate < slow_estimate);
+
+            // Now even when it's the slow one's turn, we ask the fast one.
+            mgr.next_fallback.store(0, Ordering::SeqCst);
+            let start = rt.now();
+            rt.wait_for(fetch_single(Arc::clone(&mgr), request()))
+                .await
+                .unwrap();
+            assert!(rt.now() - start < Duration::from_secs(5));
+            let config = mgr.config.get();
+            assert_eq!(
+                mgr.current_fallback(config.fallbacks()),
+                std::slice::from_ref(&fast)
+            );
+
+            // If the fast one fails us, we move on, and it loses its place.
+            mgr.note_cache_latency(&fast, Duration::from_secs(60));
+            mgr.note_fallback_failed();
+            assert_eq!(
+                mgr.current_fallback(config.fallbacks()),
+                std::slice::from_ref(&slow)
+            );
+        });
+    }
+
+    #[test]
+    fn microdesc_request_limit() {
+        // Make sure that a separate limit on microdescriptor requests holds,
+        // and doesn't hold back requests for other documents.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let mut sched = crate::DownloadScheduleConfig::builder();
+            sched.max_microdesc_requests(2);
+            let config = config_builder(tempdir.path())
+                .schedule_config(sched.build().unwrap())
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new("ok").delay(Duration::from_secs(10)));
+            let mgr = Arc::new(mgr);
+
+            // This is enough microdescriptors for five separate requests,
+            // along with one request for a certificate.
+            let mut missing: Vec<_> = md_digests(2500).into_iter().map(DocId::Microdesc).collect();
+            missing.push(DocId::AuthCert(AuthCertKeyIds {
+                id_fingerprint: RsaIdentity::from_bytes(&[1; 20]).unwrap(),
+                sk_fingerprint: RsaIdentity::from_bytes(&[2; 20]).unwrap(),
+            }));
+
+            let start = rt.now();
+            let finished = rt
+                .wait_for(async {
+                    let mut finished = Vec::new();
+                    let wanted = WantedDocs::new(missing.iter().copied());
+                    let mut fetched = fetch_multiple(Arc::clone(&mgr), missing, 4, wanted).unwrap();
+                    while let Some((outcome, _)) = fetched.next().await {
+                        let (request, _) = outcome.unwrap();
+                        let is_md = matches!(request, ClientRequest::Microdescs(_));
+                        finished.push((is_md, rt.now() - start));
+                    }
+                    finished
+                })
+                .await;
+
+            let secs = |want_md: bool| {
+                let mut v: Vec<_> = finished
+                    .iter()
+                    .filter(|(is_md, _)| *is_md == want_md)
+                    .map(|(_, elapsed)| elapsed.as_secs())
+                    .collect();
+                v.sort_unstable();
+                v
+            };
+            // The microdescriptor requests went two at a time, even though
+            // we could have launched four...
+            assert_eq!(secs(true), vec![10, 10, 20, 20, 30]);
+            // ... and the certificate request didn't wait for them.
+            assert_eq!(secs(false), vec![10]);
+        });
+    }
+
+    #[test]
+    fn shared_request_limit() {
+        // Without a separate limit on microdescriptor requests, all our
+        // requests share a single limit.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new("ok").delay(Duration::from_secs(10)));
+            let mgr = Arc::new(mgr);
+
+            // Five microdescriptor requests, and one certificate request.
+            let mut missing: Vec<_> = md_digests(2500).into_iter().map(DocId::Microdesc).collect();
+            missing.push(DocId::AuthCert(AuthCertKeyIds {
+                id_fingerprint: RsaIdentity::from_bytes(&[1; 20]).unwrap(),
+                sk_fingerprint: RsaIdentity::from_bytes(&[2; 20]).unwrap(),
+            }));
+
+            let start = rt.now();
+            let mut finished: Vec<_> = rt
+                .wait_for(async {
+                    let wanted = WantedDocs::new(missing.iter().copied());
+                    fetch_multiple(Arc::clone(&mgr), missing, 4, wanted)
+                        .unwrap()
+                        .map(|(outcome, _)| {
+                            assert!(outcome.is_ok());
+                            (rt.now() - start).as_secs()
+                        })
+                        .collect::<Vec<_>>()
+                        .await
+                })
+                .await;
+            finished.sort_unstable();
+
+            // Only four requests were ever in progress at once.
+            assert_eq!(finished, vec![10, 10, 10, 10, 20, 20]);
+        });
+    }
+
+    #[test]
+    fn abandon_unwanted_requests() {
+        // Once we stop wanting a microdescriptor, the request for it is
+        // abandoned, but the other requests keep going.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new("ok").delay(Duration::from_secs(10)));
+            let mgr = Arc::new(mgr);
+
+            let cert = DocId::AuthCert(AuthCertKeyIds {
+                id_fingerprint: RsaIdentity::from_bytes(&[1; 20]).unwrap(),
+                sk_fingerprint: RsaIdentity::from_bytes(&[2; 20]).unwrap(),
+            });
+            let missing = vec![DocId::Microdesc(H1), cert];
+            let wanted = WantedDocs::new(missing.iter().copied());
+
+            let start = rt.now();
+            let finished = rt
+                .wait_for(async {
+                    let fetched =
+                        fetch_multiple(Arc::clone(&mgr), missing, 4, wanted.clone()).unwrap();
+                    let finished = fetched
+                        .map(|(outcome, _)| (outcome.unwrap().0, rt.now() - start))
+                        .collect::<Vec<_>>();
+                    let remove = async {
+                        rt.sleep(Duration::from_secs(5)).await;
+                        wanted.remove(&DocId::Microdesc(H1));
+                    };
+                    futures::future::join(finished, remove).await.0
+                })
+                .await;
+
+            // Only the certificate request finished; it wasn't held up.
+            assert_eq!(finished.len(), 1);
+            assert!(matches!(finished[0].0, ClientRequest::AuthCert(_)));
+            assert_eq!(finished[0].1, Duration::from_secs(10));
+            assert_eq!(rt.now() - start, Duration::from_secs(10));
+
+            // A request for documents we already don't want never starts.
+            let wanted = WantedDocs::new(vec![cert]);
+            let fetched = fetch_multiple(
+                Arc::clone(&mgr),
+                vec![DocId::Microdesc(H2), cert],
+                4,
+                wanted,
+            )
+            .unwrap();
+            let fetched: Vec<_> = rt.wait_for(fetched.collect()).await;
+            assert_eq!(fetched.len(), 1);
+        });
+    }
+
+    #[test]
+    fn bytes_received_observer() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let body = "x".repeat(1000);
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new(&body));
+            let total = Arc::new(Mutex::new((0_usize, 0_usize)));
+            let total2 = Arc::clone(&total);
+            mgr.set_observer(DirObserver::new().on_bytes_received(move |n, source| {
+                assert!(source.is_none());
+                let mut total = total2.lock().unwrap();
+                total.0 += 1;
+                total.1 += n;
+            }));
+            let mgr = Arc::new(mgr);
+
+            // This is enough microdescriptors for three separate requests.
+            let missing: Vec<_> = md_digests(1200).into_iter().map(DocId::Microdesc).collect();
+            let wanted = WantedDocs::new(missing.iter().copied());
+            let fetched: Vec<_> = fetch_multiple(Arc::clone(&mgr), missing, 2, wanted)
+                .unwrap()
+                .collect()
+                .await;
+            assert_eq!(fetched.len(), 3);
+
+            assert_eq!(*total.lock().unwrap(), (3, 3 * body.len()));
+        });
+    }
+
+    #[test]
+    fn retry_budget_per_type() {
+        let consensus = DocId::LatestConsensus {
+            flavor: ConsensusFlavor::Microdesc,
+            cache_usage: CacheUsage::CacheOkay,
+        };
+        let md1 = DocId::Microdesc(H1);
+        let md2 = DocId::Microdesc(H2);
+        let mut budget = RetryBudget::new(2);
+
+        // Every type starts out with its own attempts, and an attempt
+        // costs one per type, no matter how many documents it asks for.
+        let all = vec![consensus, md1, md2];
+        assert_eq!(budget.start_attempt(all.clone()), all);
+        assert_eq!(budget.start_attempt(vec![md1, md2]), vec![md1, md2]);
+        assert!(budget.is_exhausted(&[md1]));
+        assert!(!budget.is_exhausted(&[consensus, md1]));
+
+        // Once the microdescriptors are out of attempts, we only ask for
+        // the consensus.
+        assert_eq!(budget.start_attempt(all.clone()), vec![consensus]);
+        assert!(budget.is_exhausted(&all));
+        assert!(budget.start_attempt(all).is_empty());
+    }
+
+    #[test]
+    fn separate_retries_by_type() {
+        // Make sure that the attempts we spent getting a consensus don't
+        // count against the microdescriptors that we need afterwards, and
+        // that failing microdescriptors don't make us ask for the consensus
+        // again.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new("ok"));
+            let mgr = Arc::new(mgr);
+
+            let state: Box<dyn DirState> = Box::new(MixedState::default());
+            let mut on_usable = None;
+            let (state, err) = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+
+            assert!(matches!(err, Some(Error::CantAdvanceState(_))));
+            // Three tries to get a good consensus, and no more...
+            assert!(state.describe().contains("consensus_requests: 3"));
+            // ...and then a full three tries for the microdescriptor.
+            assert!(state.describe().contains("md_requests: 3"));
+        });
+    }
+
+    #[test]
+    fn cant_advance_reasons() {
+        // Make sure that when we give up on a state, we say why.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            let mgr = Arc::new(mgr);
+
+            let cases = vec![
+                // Every request times out.
+                (
+                    CannedResponse::new(hex::encode(H3)).fail(),
+                    CantAdvanceReason::AllAttemptsErrored,
+                ),
+                // Every response is junk.
+                (
+                    CannedResponse::new("nothing useful here"),
+                    CantAdvanceReason::NoUsableResponses,
+                ),
+                // We only ever get one of the three documents we want.
+                (
+                    CannedResponse::new(hex::encode(H3)),
+                    CantAdvanceReason::InsufficientDocuments { got: 1, needed: 3 },
+                ),
+            ];
+            for (canned, expected) in cases {
+                *mgr.canned_response.lock().unwrap() = Some(canned);
+                let state: Box<dyn DirState> = Box::new(DemoState::new2());
+                let mut on_usable = None;
+                let (_state, err) = rt
+                    .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                    .await
+                    .unwrap();
+                match err {
+                    Some(Error::CantAdvanceState(reason)) => assert_eq!(reason, expected),
+                    other => panic!("Unexpected outcome {:?}", other),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn probe_caches() {
+        // Make sure that probing caches reports how each one answered,
+        // without touching our download state, our cache, or our choice of
+        // fallback.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use std::sync::atomic::Ordering;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new(hex::encode(H1)).delay(Duration::from_secs(2)));
+            let mgr = Arc::new(mgr);
+            let fallback_before = mgr.next_fallback.load(Ordering::SeqCst);
+            let state: Box<dyn DirState> = Box::new(DemoState::new1());
+            let missing_before = state.missing_docs();
+
+            let probes = rt.wait_for(mgr.probe_caches(3)).await.unwrap();
+            assert_eq!(probes.len(), 3);
+            for probe in &probes {
+                assert!(probe.succeeded());
+                assert_eq!(probe.status(), Some(200));
+                assert!(probe.error().is_none());
+                assert_eq!(probe.latency(), Duration::from_secs(2));
+            }
+            // We probed three different caches.
+            let ids: HashSet<_> = probes.iter().map(|p| *p.cache()).collect();
+            assert_eq!(ids.len(), 3);
+
+            // Failures get reported, but don't make us change fallbacks.
+            *mgr.canned_response.lock().unwrap() = Some(CannedResponse::new("").fail());
+            let probes = rt.wait_for(mgr.probe_caches(2)).await.unwrap();
+            assert_eq!(probes.len(), 2);
+            for probe in &probes {
+                assert!(!probe.succeeded());
+                assert!(probe.status().is_none());
+                assert!(probe.error().is_some());
+            }
+            assert_eq!(mgr.next_fallback.load(Ordering::SeqCst), fallback_before);
+
+            // Nothing we got went into our state or our cache.
+            assert_eq!(state.missing_docs(), missing_before);
+            assert!(mgr.text(&DocId::Microdesc(H1)).unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn stop_when_can_advance() {
+        // Make sure that once we have enough to advance, we only wait for
+        // the rest of our requests if we've been told not to cancel them.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            for &cancel in &[false, true] {
+                let tempdir = tempfile::TempDir::new().unwrap();
+                let config = config_builder(tempdir.path())
+                    .cancel_unneeded_requests(cancel)
+                    .build()
+                    .unwrap();
+                let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+                *mgr.canned_response.lock().unwrap() =
+                    Some(CannedResponse::new("ok").delay(Duration::from_secs(10)));
+                let mgr = Arc::new(mgr);
+
+                // This is enough microdescriptors for three separate requests.
+                let wants = md_digests(1200).into_iter().map(DocId::Microdesc).collect();
+                let mut state: Box<dyn DirState> =
+                    Box::new(StubState::new(wants).accept_responses());
+
+                let start = rt.wallclock();
+                let changed = rt
+                    .wait_for(super::download_attempt(
+                        &mgr,
+                        &mut state,
+                        &mut Parallelism::new(1),
+                        &mut RetryBudget::new(1),
+                        &mut AttemptLog::default(),
+                    ))
+                    .await
+                    .unwrap()
+                    .changed;
+                assert!(changed);
+                assert!(state.can_advance());
+                if cancel {
+                    // Only the first request finished: the others were
+                    // cancelled.
+                    assert_eq!(rt.wallclock(), start + Duration::from_secs(10));
+                    assert!(state.describe().contains("n_responses: 1"));
+                } else {
+                    // By default, we waited for all three requests.
+                    assert_eq!(rt.wallclock(), start + Duration::from_secs(30));
+                    assert!(state.describe().contains("n_responses: 3"));
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn retry_after_unsigned_consensus() {
+        // If a fallback sends us a consensus that's missing signatures, we
+        // ask a different one.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            rt.jump_to(consensus_time());
+
+            let forger = fallback(1);
+            let honest = fallback(2);
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let mut netcfg = authority_network();
+            netcfg.fallback_caches(vec![forger.clone(), honest.clone()]);
+            let config = config_builder(tempdir.path())
+                .network_config(netcfg.build().unwrap())
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+
+            // The forger leaves out one of the two signatures we need.
+            let sig_start = CONSENSUS
+                .find("directory-signature sha256 5696AB38")
+                .unwrap();
+            let sig_end = sig_start
+                + CONSENSUS[sig_start..]
+                    .find("-----END SIGNATURE-----\n")
+                    .unwrap()
+                + "-----END SIGNATURE-----\n".len();
+            let forged = format!("{}{}", &CONSENSUS[..sig_start], &CONSENSUS[sig_end..]);
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new(CONSENSUS).cache_body(*forger.rsa_identity(), forged));
+            let mgr = Arc::new(mgr);
+            mgr.next_fallback
+                .store(0, std::sync::atomic::Ordering::SeqCst);
+            let config = mgr.config.get();
+
+            let mut state: Box<dyn DirState> = Box::new(
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap(),
+            );
+            let mut parallelism = Parallelism::new(1);
+            let mut budget = RetryBudget::new(3);
+            let mut log = AttemptLog::default();
+
+            // The forged consensus gets us nowhere, and we move on...
+            let changed = rt
+                .wait_for(super::download_attempt(
+                    &mgr,
+                    &mut state,
+                    &mut parallelism,
+                    &mut budget,
+                    &mut log,
+                ))
+                .await
+                .unwrap()
+                .changed;
+            assert!(!changed);
+            assert!(!state.can_advance());
+            assert_eq!(
+                mgr.current_fallback(config.fallbacks()),
+                std::slice::from_ref(&honest)
+            );
+
+            // ... to the cache that sends the real one.
+            let changed = rt
+                .wait_for(super::download_attempt(
+                    &mgr,
+                    &mut state,
+                    &mut parallelism,
+                    &mut budget,
+                    &mut log,
+                ))
+                .await
+                .unwrap()
+                .changed;
+            assert!(changed);
+            assert!(state.can_advance());
+        });
+    }
+
+    #[test]
+    fn refuse_old_consensus() {
+        // If even a freshly downloaded and validated consensus is older
+        // than we allow, bootstrapping fails instead of using it.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            // This consensus became valid five seconds ago.
+            rt.jump_to(consensus_time());
+
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let config = config_builder(tempdir.path())
+                .network_config(authority_network().build().unwrap())
+                .max_consensus_age(Duration::from_secs(1))
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new(CONSENSUS).certs(AUTHCERTS));
+            let mgr = Arc::new(mgr);
+
+            let state = Box::new(
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap(),
+            );
+            let mut on_usable = None;
+            let outcome = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await;
+            match outcome {
+                Err(Error::ConsensusTooOld { age, max_age }) => {
+                    assert_eq!(age, Duration::from_secs(5));
+                    assert_eq!(max_age, Duration::from_secs(1));
+                }
+                Err(e) => panic!("unexpected error {}", e),
+                Ok(_) => panic!("accepted an old consensus"),
+            }
+            assert!(mgr.opt_netdir().is_none());
+
+            // Once we have a directory, though, we keep it, and keep
+            // looking for a newer consensus.
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            let lifetime = netdir.lifetime().clone();
+            mgr.netdir.replace(netdir);
+            let state = Box::new(
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap(),
+            );
+            let mut on_usable = None;
+            let (_, err) = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+            assert!(matches!(err, Some(Error::CantAdvanceState(_))));
+            assert_eq!(mgr.netdir().unwrap().lifetime(), &lifetime);
+        });
+    }
+
+    #[test]
+    fn skip_too_old_consensus() {
+        // If one cache sends us a consensus that's older than we allow, we
+        // count that against it and ask another one, instead of giving up
+        // on bootstrapping.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let stale = fallback(1);
+            let good = fallback(2);
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let config = config_builder(tempdir.path())
+                .network_config(fallback_network(vec![stale.clone(), good.clone()]))
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new("fresh").cache_body(*stale.rsa_identity(), "stale"));
+            let mgr = Arc::new(mgr);
+            mgr.next_fallback
+                .store(0, std::sync::atomic::Ordering::SeqCst);
+            let config = mgr.config.get();
+
+            let mut state: Box<dyn DirState> = Box::new(
+                StubState::new(vec![DocId::LatestConsensus {
+                    flavor: ConsensusFlavor::Microdesc,
+                    cache_usage: CacheUsage::CacheOkay,
+                }])
+                .accept_responses()
+                .too_old("stale"),
+            );
+            let mut parallelism = Parallelism::new(1);
+            let mut budget = RetryBudget::new(3);
+            let mut log = AttemptLog::default();
+
+            // The old consensus doesn't stop us: we move on...
+            let changed = rt
+                .wait_for(super::download_attempt(
+                    &mgr,
+                    &mut state,
+                    &mut parallelism,
+                    &mut budget,
+                    &mut log,
+                ))
+                .await
+                .unwrap()
+                .changed;
+            assert!(!changed);
+            assert!(matches!(log.too_old, Some(Error::ConsensusTooOld { .. })));
+            assert_eq!(
+                mgr.current_fallback(config.fallbacks()),
+                std::slice::from_ref(&good)
+            );
+
+            // ... to the cache that has a newer one.
+            let changed = rt
+                .wait_for(super::download_attempt(
+                    &mgr,
+                    &mut state,
+                    &mut parallelism,
+                    &mut budget,
+                    &mut log,
+                ))
+                .await
+                .unwrap()
+                .changed;
+            assert!(changed);
+            assert!(state.can_advance());
+        });
+    }
+
+    #[test]
+    fn missing_required_protocols() {
+        // This consensus requires onion service protocols, which we don't
+        // implement yet.  We warn about that by default, and refuse the
+        // consensus if we're configured to.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            rt.jump_to(consensus_time());
+
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let make_mgr = |enforce: bool| {
+                let config = config_builder(tempdir.path())
+                    .network_config(authority_network().build().unwrap())
+                    .consensus_only(true)
+                    .enforce_required_protocols(enforce)
+                    .build()
+                    .unwrap();
+                let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+                *mgr.canned_response.lock().unwrap() =
+                    Some(CannedResponse::new(CONSENSUS).certs(AUTHCERTS));
+                Arc::new(mgr)
+            };
+
+            // By default, we announce the problem but keep going.
+            let mgr = make_mgr(false);
+            let mut events = mgr.events();
+            let state = Box::new(
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::MustDownload).unwrap(),
+            );
+            let mut on_usable = None;
+            let (state, _) = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+            assert!(state.is_ready(Readiness::Complete));
+            let mut seen = Vec::new();
+            while let Some(Some(ev)) = events.next().now_or_never() {
+                seen.push(ev);
+            }
+            assert!(seen.contains(&DirEvent::MissingRequiredProtocols));
+            assert!(seen.contains(&DirEvent::NewConsensus));
+            // We've warned about it now, so we won't again.
+            assert!(!crate::state::WriteNetDir::required_protocols_missing(
+                &*mgr
+            ));
+            drop(mgr);
+
+            // When we're told to enforce the requirements, we refuse.
+            let mgr = make_mgr(true);
+            let mut events = mgr.events();
+            let state = Box::new(
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::MustDownload).unwrap(),
+            );
+            let mut on_usable = None;
+            let outcome = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await;
+            match outcome {
+                Err(Error::MissingRequiredProtocols { missing }) => {
+                    assert_eq!(missing.to_string(), "DirCache=1 HSDir=1 HSIntro=3 HSRend=1");
+                }
+                Err(e) => panic!("unexpected error {}", e),
+                Ok(_) => panic!("accepted a consensus with missing protocols"),
+            }
+            assert_eq!(
+                events.next().now_or_never(),
+                Some(Some(DirEvent::MissingRequiredProtocols))
+            );
+            assert!(mgr.consensus().is_none());
+
+            // But if we already have a directory, we keep using it, and
+            // wait for a consensus that we can use.
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            mgr.netdir.replace(netdir);
+            let state = Box::new(
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::MustDownload).unwrap(),
+            );
+            let mut on_usable = None;
+            let (_, err) = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+            assert!(matches!(err, Some(Error::CantAdvanceState(_))));
+            assert!(mgr.consensus().is_none());
+            assert!(mgr.opt_netdir().is_some());
+        });
+    }
+
+    #[test]
+    fn consensus_from_cache() {
+        // When we may only take our consensus from the cache, we never ask
+        // for one, but we still download the microdescriptors we need.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use time::macros::datetime;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            rt.jump_to(consensus_time());
+
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let config = config_builder(tempdir.path())
+                .network_config(authority_network().build().unwrap())
+                .consensus_from_cache(true)
+                .build()
+                .unwrap();
+            let mgr = Arc::new(DirMgr::from_config(config, rt.clone(), None, false).unwrap());
+            // Every request times out.
+            *mgr.fault_script.lock().unwrap() = Some(FaultScript::default());
+
+            // With nothing in the cache, we give up right away.
+            let outcome = mgr.check_cached_consensus().await;
+            assert!(matches!(outcome, Err(Error::NoCachedConsensus)));
+
+            // Now the cache has a valid consensus and its certificates, but
+            // none of its microdescriptors.
+            let embedded =
+                crate::EmbeddedDirectory::new(CONSENSUS.as_bytes(), AUTHCERTS.as_bytes(), b"");
+            mgr.seed_from_embedded(&embedded).unwrap();
+            mgr.check_cached_consensus().await.unwrap();
+
+            let state = Box::new(
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::ConsensusFromCache)
+                    .unwrap(),
+            );
+            let mut on_usable = None;
+            let (state, err) = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+            assert!(matches!(err, Some(Error::CantAdvanceState(_))));
+            assert_eq!(state.bootstrap_phase(), BootstrapPhase::GettingMicrodescs);
+            {
+                let script = mgr.fault_script.lock().unwrap();
+                let requests = script.as_ref().unwrap().requests();
+                assert!(!requests.is_empty());
+                assert!(requests
+                    .iter()
+                    .all(|r| matches!(r, ClientRequest::Microdescs(_))));
+            }
+
+            // Once the cached consensus has expired, we don't ask for a new
+            // one: we give up.
+            *mgr.fault_script.lock().unwrap() = Some(FaultScript::default());
+            rt.jump_to(datetime!(2020-08-10 00:00:00 UTC).into());
+            let state = Box::new(
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::ConsensusFromCache)
+                    .unwrap(),
+            );
+            let mut on_usable = None;
+            let (state, err) = rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+            assert!(matches!(err, Some(Error::NoCachedConsensus)));
+            assert_eq!(state.bootstrap_phase(), BootstrapPhase::GettingConsensus);
+            let script = mgr.fault_script.lock().unwrap();
+            assert_eq!(script.as_ref().unwrap().n_requests(), 0);
+        });
+    }
+
+    /// Answer every request that arrives on `listener` as a directory
+    /// mirror would, serving `consensus` as our consensus.
+    ///
+    /// Return a list of the requests that we've received.
+    fn serve_mirror<R: Runtime>(
+        rt: &R,
+        mut listener: tor_rtmock::net::MockNetListener,
+        consensus: String,
+    ) -> Arc<Mutex<Vec<String>>> {
+        use futures::task::SpawnExt;
+        use futures::{AsyncReadExt, AsyncWriteExt};
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests2 = Arc::clone(&requests);
+        rt.spawn(async move {
+            while let Some(Ok((mut stream, _))) = listener.next().await {
+                let mut buf = vec![0_u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                let body = if request.starts_with("GET /tor/status-vote/") {
+                    consensus.as_str()
+                } else if request.starts_with("GET /tor/keys/") {
+                    AUTHCERTS
+                } else {
+                    ""
+                };
+                requests2.lock().unwrap().push(request);
+                let response = format!("HTTP/1.0 200 OK\r\n\r\n{}", body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.close().await.unwrap();
+            }
+        })
+        .unwrap();
+        requests
+    }
+
+    #[test]
+    fn bootstrap_from_mirror() {
+        // If we can't reach the Tor network, we can get a consensus from a
+        // mirror over a direct connection, and it gets validated as usual.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use time::macros::datetime;
+            let sleep_rt = tor_rtmock::MockSleepRuntime::new(rt.clone());
+            sleep_rt.jump_to(consensus_time());
+            let network = tor_rtmock::net::MockNetwork::new();
+            let client_rt = network
+                .builder()
+                .add_address("192.0.2.1".parse().unwrap())
+                .runtime(sleep_rt.clone());
+            let mirror_addr: std::net::SocketAddr = "198.51.100.7:443".parse().unwrap();
+            let listener = network
+                .builder()
+                .add_address(mirror_addr.ip())
+                .provider()
+                .listen_tls(&mirror_addr, b"not really a certificate".to_vec())
+                .unwrap();
+
+            // Our mirror answers each request with the document it asks for.
+            let requests = serve_mirror(&rt, listener, CONSENSUS.to_string());
+
+            let mirror = crate::DirMirror::builder()
+                .address(mirror_addr)
+                .tls_hostname("front.example.com")
+                .http_host("mirror.example.com")
+                .build()
+                .unwrap();
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let mut netcfg = authority_network();
+            netcfg.fallback_caches(vec![]).mirrors(vec![mirror]);
+            let config = config_builder(tempdir.path())
+                .network_config(netcfg.build().unwrap())
+                .consensus_only(true)
+                .build()
+                .unwrap();
+            // With no circuit manager, we can't use the Tor network at all.
+            let mgr = Arc::new(DirMgr::from_config(config, client_rt, None, false).unwrap());
+
+            let state = Box::new(
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap(),
+            );
+            let mut on_usable = None;
+            let (state, err) = sleep_rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+            assert!(err.is_none());
+            assert!(state.is_ready(Readiness::Complete));
+            let consensus = mgr.consensus().unwrap();
+            let valid_after: SystemTime = datetime!(2020-08-07 12:42:40 UTC).into();
+            assert_eq!(consensus.lifetime().valid_after(), valid_after);
+
+            // We asked the mirror for the consensus and then the certificates,
+            // naming the host behind the front.
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests.len(), 2);
+            assert!(requests[0].starts_with("GET /tor/status-vote/"));
+            assert!(requests[1].starts_with("GET /tor/keys/"));
+            for request in requests.iter() {
+                assert!(request.contains("host: mirror.example.com\r\n"));
+            }
+        });
+    }
+
+    #[test]
+    fn tampered_mirror_response() {
+        // We don't check a mirror's TLS certificate, so the signatures on
+        // the consensus are all that protect us from a mirror (or anybody
+        // between us and it) that changes what it sends us.  Make sure that
+        // a tampered consensus gets rejected.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let sleep_rt = tor_rtmock::MockSleepRuntime::new(rt.clone());
+            sleep_rt.jump_to(consensus_time());
+            let network = tor_rtmock::net::MockNetwork::new();
+            let client_rt = network
+                .builder()
+                .add_address("192.0.2.1".parse().unwrap())
+                .runtime(sleep_rt.clone());
+            let mirror_addr: std::net::SocketAddr = "198.51.100.7:443".parse().unwrap();
+            let listener = network
+                .builder()
+                .add_address(mirror_addr.ip())
+                .provider()
+                .listen_tls(&mirror_addr, b"not really a certificate".to_vec())
+                .unwrap();
+
+            // Change a router's bandwidth, inside the signed part of the
+            // document.
+            let tampered = CONSENSUS.replacen(
+                "w Bandwidth=0 Unmeasured=1",
+                "w Bandwidth=9999 Unmeasured=1",
+                1,
+            );
+            assert_ne!(tampered, CONSENSUS);
+            let requests = serve_mirror(&rt, listener, tampered);
+
+            let mirror = crate::DirMirror::builder()
+                .address(mirror_addr)
+                .tls_hostname("front.example.com")
+                .build()
+                .unwrap();
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let mut netcfg = authority_network();
+            netcfg.fallback_caches(vec![]).mirrors(vec![mirror]);
+            let config = config_builder(tempdir.path())
+                .network_config(netcfg.build().unwrap())
+                .consensus_only(true)
+                .build()
+                .unwrap();
+            let mgr = Arc::new(DirMgr::from_config(config, client_rt, None, false).unwrap());
+
+            let state = Box::new(
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap(),
+            );
+            let mut on_usable = None;
+            let (state, err) = sleep_rt
+                .wait_for(super::download(Arc::downgrade(&mgr), state, &mut on_usable))
+                .await
+                .unwrap();
+            assert!(err.is_some());
+            assert!(!state.is_ready(Readiness::Usable));
+            assert!(mgr.consensus().is_none());
+            assert!(mgr.opt_netdir().is_none());
+            // We did get the consensus and its certificates from the mirror.
+            let requests = requests.lock().unwrap();
+            assert!(requests.iter().any(|r| r.starts_with("GET /tor/keys/")));
+        });
+    }
+
+    #[test]
+    fn request_hook() {
+        // A request hook can change the headers of the requests that we
+        // send, but not what they ask for.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use futures::task::SpawnExt;
+            use futures::{AsyncReadExt, AsyncWriteExt};
+            let network = tor_rtmock::net::MockNetwork::new();
+            let client_rt = network
+                .builder()
+                .add_address("192.0.2.1".parse().unwrap())
+                .runtime(rt.clone());
+            let mirror_addr: std::net::SocketAddr = "198.51.100.7:80".parse().unwrap();
+            let mut listener = network
+                .builder()
+                .add_address(mirror_addr.ip())
+                .provider()
+                .listen(&mirror_addr)
+                .await
+                .unwrap();
+
+            // Our mirror writes down every request, and answers it with
+            // nothing.
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let requests2 = Arc::clone(&requests);
+            rt.spawn(async move {
+                while let Some(Ok((mut stream, _))) = listener.next().await {
+                    let mut buf = vec![0_u8; 4096];
+                    let n = stream.read(&mut buf).await.unwrap();
+                    let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                    requests2.lock().unwrap().push(request);
+                    stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+                    stream.close().await.unwrap();
+                }
+            })
+            .unwrap();
+
+            let mirror = crate::DirMirror::builder()
+                .address(mirror_addr)
+                .build()
+                .unwrap();
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let mut netcfg = crate::NetworkConfig::builder();
+            netcfg.fallback_caches(vec![]).mirrors(vec![mirror]);
+            let config = config_builder(tempdir.path())
+                .network_config(netcfg.build().unwrap())
+                .build()
+                .unwrap();
+            let mgr = Arc::new(DirMgr::from_config(config, client_rt, None, false).unwrap());
+            let request = || {
+                ClientRequest::Consensus(tor_dirclient::request::ConsensusRequest::new(
+                    ConsensusFlavor::Microdesc,
+                ))
+            };
+
+            // A hook that adds a header and narrows our encodings gets its way.
+            mgr.set_request_hook(|req| {
+                let headers = req.headers_mut();
+                headers.insert("x-test-marker", http::HeaderValue::from_static("1"));
+                headers.insert(
+                    http::header::ACCEPT_ENCODING,
+                    http::HeaderValue::from_static("identity"),
+                );
+            });
+            fetch_single(Arc::clone(&mgr), request()).await.unwrap();
+
+            // A hook that asks for a different document gets ignored, and so
+            // does one that asks for an encoding we can't decode.
+            mgr.set_request_hook(|req| {
+                req.headers_mut()
+                    .insert("x-test-marker", http::HeaderValue::from_static("2"));
+                *req.uri_mut() = "/tor/server/all".parse().unwrap();
+            });
+            fetch_single(Arc::clone(&mgr), request()).await.unwrap();
+            mgr.set_request_hook(|req| {
+                req.headers_mut().insert(
+                    http::header::ACCEPT_ENCODING,
+                    http::HeaderValue::from_static("x-mystery"),
+                );
+            });
+            fetch_single(Arc::clone(&mgr), request()).await.unwrap();
+
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests.len(), 3);
+            assert!(requests[0].starts_with("GET /tor/status-vote/"));
+            assert!(requests[0].contains("x-test-marker: 1\r\n"));
+            assert!(requests[0].contains("accept-encoding: identity\r\n"));
+            for request in &requests[1..] {
+                assert!(request.starts_with("GET /tor/status-vote/"));
+                assert!(!request.contains("x-test-marker"));
+                assert!(!request.contains("x-mystery"));
+                assert!(request.contains("accept-encoding: deflate, identity"));
+            }
+        });
+    }
+
+    #[test]
+    fn request_extra_encodings() {
+        // Registered codecs show up in our Accept-Encoding header, after the
+        // ones that the directory client supports.
+        let config = crate::DownloadScheduleConfig::default();
+        let request = ClientRequest::Consensus(tor_dirclient::request::ConsensusRequest::new(
+            ConsensusFlavor::Microdesc,
+        ));
+        let plain = LimitedRequest::new(&config, &request);
+        assert!(!plain.handles_encoding("x-backwards"));
+        let plain_req = plain.make_request().unwrap();
+        let plain_accept = plain_req.headers()[http::header::ACCEPT_ENCODING]
+            .to_str()
+            .unwrap();
+        assert!(!plain_accept.contains("x-backwards"));
+
+        let extra = LimitedRequest::new(&config, &request)
+            .with_encodings(vec!["x-backwards".into(), "x-sideways".into()]);
+        assert!(extra.handles_encoding("x-backwards"));
+        assert!(!extra.handles_encoding("x-upside-down"));
+        let extra_req = extra.make_request().unwrap();
+        assert_eq!(
+            extra_req.headers()[http::header::ACCEPT_ENCODING],
+            format!("{}, x-backwards, x-sideways", plain_accept).as_str()
+        );
+    }
+
+    #[test]
+    fn oversized_response() {
+        // A cache that sends us more than our configured limit gets
+        // rejected, and we move on to another one.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let greedy = fallback(1);
+            let modest = fallback(2);
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let mut sched = crate::DownloadScheduleConfig::builder();
+            sched.max_consensus_size(1000);
+            let config = config_builder(tempdir.path())
+                .network_config(fallback_network(vec![greedy.clone(), modest.clone()]))
+                .schedule_config(sched.build().unwrap())
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+            *mgr.canned_response.lock().unwrap() = Some(
+                CannedResponse::new("ok").cache_body(*greedy.rsa_identity(), vec![b'x'; 1001]),
+            );
+            let mgr = Arc::new(mgr);
+            mgr.next_fallback
+                .store(0, std::sync::atomic::Ordering::SeqCst);
+            let config = mgr.config.get();
+            let request = || {
+                ClientRequest::Consensus(tor_dirclient::request::ConsensusRequest::new(
+                    ConsensusFlavor::Microdesc,
+                ))
+            };
+
+            // The greedy cache's response is too big...
+            let outcome = rt.wait_for(fetch_single(Arc::clone(&mgr), request())).await;
+            assert!(matches!(
+                outcome,
+                Err(Error::DirClientError(
+                    tor_dirclient::Error::ResponseTooLong(1001)
+                ))
+            ));
+            // ... so it counts against the cache, and we move on.
+            assert_eq!(
+                mgr.cache_latency.estimate(greedy.rsa_identity()),
+                Some(config.schedule().consensus_timeout())
+            );
+            assert_eq!(
+                mgr.current_fallback(config.fallbacks()),
+                std::slice::from_ref(&modest)
+            );
+            let (_, response) = rt
+                .wait_for(fetch_single(Arc::clone(&mgr), request()))
+                .await
+                .unwrap();
+            assert_eq!(response.output(), b"ok");
+        });
+    }
+
+    #[test]
+    fn prefer_ipv6_caches() {
+        // When we prefer IPv6, we ask a cache that has an IPv6 address
+        // instead of one that doesn't, and connect to it over IPv6.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let v4_only = fallback(1);
+            let dual_stack = FallbackDir::builder()
+                .rsa_identity([2; 20].into())
+                .ed_identity([2; 32].into())
+                .orport("127.0.0.2:9001".parse().unwrap())
+                .orport("[::2]:9001".parse().unwrap())
+                .build()
+                .unwrap();
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let make_mgr = |prefer_ipv6: bool| {
+                let config = config_builder(tempdir.path())
+                    .network_config(fallback_network(vec![v4_only.clone(), dual_stack.clone()]))
+                    .prefer_ipv6_caches(prefer_ipv6)
+                    .build()
+                    .unwrap();
+                let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+                *mgr.canned_response.lock().unwrap() = Some(
+                    CannedResponse::new("v4 only").cache_body(*dual_stack.rsa_identity(), "dual"),
+                );
+                // Neither cache has a better record than the other, so
+                // we'd ask the IPv4-only one first if we had no preference.
+                mgr.next_fallback
+                    .store(0, std::sync::atomic::Ordering::SeqCst);
+                Arc::new(mgr)
+            };
+            let request = || {
+                ClientRequest::Consensus(tor_dirclient::request::ConsensusRequest::new(
+                    ConsensusFlavor::Microdesc,
+                ))
+            };
+
+            let mgr = make_mgr(false);
+            let (_, response) = fetch_single(Arc::clone(&mgr), request()).await.unwrap();
+            assert_eq!(response.output(), b"v4 only");
+            drop(mgr);
+
+            let mgr = make_mgr(true);
+            let (_, response) = fetch_single(Arc::clone(&mgr), request()).await.unwrap();
+            assert_eq!(response.output(), b"dual");
+            let candidates = mgr.candidate_dir_caches();
+            assert_eq!(candidates.len(), 1);
+            let cache = candidates[0].cache();
+            assert_eq!(cache.rsa_identity(), dual_stack.rsa_identity());
+            assert!(cache.addrs()[0].is_ipv6());
+        });
+    }
+
+    #[test]
+    fn load_stuck_state() {
+        // Make sure that a state which keeps changing without advancing is
+        // caught at the bound that it declares.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let mgr = Arc::new(mgr);
+
+            let n_loads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let state = Box::new(
+                StubState::new(vec![DocId::Microdesc([7; 32])])
+                    .change_on_load(Arc::clone(&n_loads), 3),
+            );
+            let result = std::panic::AssertUnwindSafe(super::load(Arc::clone(&mgr), state))
+                .catch_unwind()
+                .await;
+            let panic = result.unwrap_err();
+            let msg = panic.downcast_ref::<String>().unwrap();
+            assert_eq!(msg, "Spent 3 iterations in the same state: this is a bug");
+            assert_eq!(n_loads.load(std::sync::atomic::Ordering::SeqCst), 3);
+        });
+    }
+
+    #[test]
+    fn corrupt_cache() {
+        // Make sure that we notice when our cache holds a document that we
+        // can't decode.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+
+            {
+                let mut store = mgr.store_if_rw().unwrap().lock().unwrap();
+                let now = SystemTime::now();
+                let one_hour = Duration::new(3600, 0);
+                let lifetime = Lifetime::new(now, now + one_hour, now + one_hour * 2).unwrap();
+                let meta = ConsensusMeta::new(lifetime, [0x11; 32], [0x22; 32]);
+                store
+                    .store_consensus(
+                        &meta,
+                        ConsensusFlavor::Microdesc,
+                        false,
+                        "network-status-version 3 microdesc\nthis is not a consensus\n",
+                    )
+                    .unwrap();
+            }
+            let mgr = Arc::new(mgr);
+
+            let state =
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap();
+            let result = super::load(Arc::clone(&mgr), Box::new(state)).await;
+            assert!(matches!(result, Err(Error::CacheCorruption(_))));
+        });
+    }
 }
@@ -10,13 +10,14 @@
 
 use crate::retry::DownloadSchedule;
 use crate::storage::DynStore;
-use crate::{Authority, Result};
+use crate::{Authority, DirMirror, Result};
 use tor_config::ConfigBuildError;
 use tor_netdir::fallback::FallbackDir;
 use tor_netdoc::doc::netstatus;
 
 use derive_builder::Builder;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use serde::Deserialize;
 
@@ -47,10 +48,27 @@ pub struct NetworkConfig {
     /// (If none are specified, we use a default list of authorities shipped
     /// with Arti.)
     ///
+    /// Set this to use a private Tor network, such as a test network made
+    /// with Chutney: we only accept a consensus that enough of these
+    /// authorities have signed.  If you set it, you must set
+    /// `fallback_caches` too.
+    ///
     /// This section cannot be changed in a running Arti client.
     #[serde(default = "crate::authority::default_authorities")]
     #[builder(default = "crate::authority::default_authorities()")]
     authorities: Vec<Authority>,
+
+    /// List of mirrors that we can ask for directory information directly,
+    /// without using the Tor network, if we don't have a directory yet and
+    /// can't get one from any fallback cache.
+    ///
+    /// (By default there are none.)
+    ///
+    /// This section can be changed in a running Arti client.  Doing so will
+    /// affect future download attempts only.
+    #[serde(default)]
+    #[builder(default)]
+    mirrors: Vec<DirMirror>,
 }
 
 impl Default for NetworkConfig {
@@ -58,6 +76,7 @@ impl Default for NetworkConfig {
         NetworkConfig {
             fallback_caches: fallbacks::default_fallbacks(),
             authorities: crate::authority::default_authorities(),
+            mirrors: Vec::new(),
         }
     }
 }
@@ -67,7 +86,8 @@ impl From<NetworkConfig> for NetworkConfigBuilder {
         let mut builder = NetworkConfigBuilder::default();
         builder
             .fallback_caches(cfg.fallback_caches)
-            .authorities(cfg.authorities);
+            .authorities(cfg.authorities)
+            .mirrors(cfg.mirrors);
         builder
     }
 }
@@ -85,6 +105,10 @@ impl NetworkConfig {
     pub(crate) fn fallbacks(&self) -> &[FallbackDir] {
         &self.fallback_caches[..]
     }
+    /// Return the configured directory mirrors
+    pub(crate) fn mirrors(&self) -> &[DirMirror] {
+        &self.mirrors[..]
+    }
 }
 
 impl NetworkConfigBuilder {
@@ -130,6 +154,94 @@ pub struct DownloadScheduleConfig {
     #[serde(default = "default_microdesc_schedule")]
     #[builder(default = "default_microdesc_schedule()")]
     retry_microdescs: DownloadSchedule,
+
+    /// The most bytes per second that we'll take in from directory
+    /// downloads, across all of our requests at once.
+    ///
+    /// By default this is 0, which means "no limit".
+    #[serde(default)]
+    #[builder(default)]
+    max_download_rate: u64,
+
+    /// The most microdescriptor requests to have in progress at once, in
+    /// addition to any requests for other kinds of document.
+    ///
+    /// This never raises the parallelism configured in `retry_microdescs`:
+    /// it only keeps microdescriptor batches from using up all of our
+    /// circuits when other documents are wanted too.
+    ///
+    /// By default this is 0, which means "no separate limit".
+    #[serde(default)]
+    #[builder(default)]
+    max_microdesc_requests: u8,
+
+    /// How long to wait for a single consensus request to finish before we
+    /// give up on it.
+    #[serde(with = "humantime_serde", default = "default_consensus_timeout")]
+    #[builder(default = "default_consensus_timeout()")]
+    consensus_timeout: Duration,
+
+    /// How long to wait for a single authority certificate request to finish
+    /// before we give up on it.
+    #[serde(with = "humantime_serde", default = "default_certs_timeout")]
+    #[builder(default = "default_certs_timeout()")]
+    certs_timeout: Duration,
+
+    /// How long to wait for a single microdescriptor request to finish
+    /// before we give up on it, not counting the extra time we allow for
+    /// each microdescriptor that the request asks for.
+    #[serde(with = "humantime_serde", default = "default_microdescs_timeout")]
+    #[builder(default = "default_microdescs_timeout()")]
+    microdescs_timeout: Duration,
+
+    /// The largest consensus, in bytes, that we'll accept from a directory
+    /// cache.
+    ///
+    /// We stop reading a response once it gets this long, so that a cache
+    /// can't use up our memory by sending us something enormous.
+    #[serde(default = "default_max_consensus_size")]
+    #[builder(default = "default_max_consensus_size()")]
+    max_consensus_size: usize,
+
+    /// The most bytes we'll accept from a directory cache for each
+    /// authority certificate that we ask it for.
+    #[serde(default = "default_max_authcert_size")]
+    #[builder(default = "default_max_authcert_size()")]
+    max_authcert_size: usize,
+
+    /// The most bytes we'll accept from a directory cache for each
+    /// microdescriptor that we ask it for.
+    #[serde(default = "default_max_microdesc_size")]
+    #[builder(default = "default_max_microdesc_size()")]
+    max_microdesc_size: usize,
+
+    /// The longest we'll wait, at random, before making our first directory
+    /// request.
+    ///
+    /// This keeps a large number of clients that all start at once (for
+    /// example, after a coordinated restart) from all asking the directory
+    /// caches for documents at the same moment.  It only delays our first
+    /// request from the network: loading from our cache is never delayed.
+    ///
+    /// By default this is 0, which means "don't wait".
+    #[serde(with = "humantime_serde", default)]
+    #[builder(default)]
+    startup_jitter: Duration,
+
+    /// How many times a directory cache has to fail us within
+    /// `cache_error_window` before we stop asking it and move on to another
+    /// one.
+    ///
+    /// By default this is 1, which means that we move on after any error.
+    #[serde(default = "default_cache_error_threshold")]
+    #[builder(default = "default_cache_error_threshold()")]
+    cache_error_threshold: u32,
+
+    /// How long we remember each error from a directory cache when counting
+    /// toward `cache_error_threshold`.
+    #[serde(with = "humantime_serde", default = "default_cache_error_window")]
+    #[builder(default = "default_cache_error_window()")]
+    cache_error_window: Duration,
 }
 
 /// Default value for retry_bootstrap in DownloadScheduleConfig.
@@ -142,6 +254,46 @@ fn default_microdesc_schedule() -> DownloadSchedule {
     DownloadSchedule::new(3, std::time::Duration::new(1, 0), 4)
 }
 
+/// Default value for consensus_timeout in DownloadScheduleConfig.
+fn default_consensus_timeout() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+/// Default value for certs_timeout in DownloadScheduleConfig.
+fn default_certs_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Default value for microdescs_timeout in DownloadScheduleConfig.
+fn default_microdescs_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Default value for max_consensus_size in DownloadScheduleConfig.
+fn default_max_consensus_size() -> usize {
+    (16 * 1024 * 1024) - 1
+}
+
+/// Default value for max_authcert_size in DownloadScheduleConfig.
+fn default_max_authcert_size() -> usize {
+    16 * 1024
+}
+
+/// Default value for max_microdesc_size in DownloadScheduleConfig.
+fn default_max_microdesc_size() -> usize {
+    8 * 1024
+}
+
+/// Default value for cache_error_threshold in DownloadScheduleConfig.
+fn default_cache_error_threshold() -> u32 {
+    1
+}
+
+/// Default value for cache_error_window in DownloadScheduleConfig.
+fn default_cache_error_window() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
 impl Default for DownloadScheduleConfig {
     fn default() -> Self {
         Self::builder()
@@ -164,7 +316,18 @@ impl From<DownloadScheduleConfig> for DownloadScheduleConfigBuilder {
             .retry_bootstrap(cfg.retry_bootstrap)
             .retry_consensus(cfg.retry_consensus)
             .retry_certs(cfg.retry_certs)
-            .retry_microdescs(cfg.retry_microdescs);
+            .retry_microdescs(cfg.retry_microdescs)
+            .max_download_rate(cfg.max_download_rate)
+            .max_microdesc_requests(cfg.max_microdesc_requests)
+            .consensus_timeout(cfg.consensus_timeout)
+            .certs_timeout(cfg.certs_timeout)
+            .microdescs_timeout(cfg.microdescs_timeout)
+            .max_consensus_size(cfg.max_consensus_size)
+            .max_authcert_size(cfg.max_authcert_size)
+            .max_microdesc_size(cfg.max_microdesc_size)
+            .startup_jitter(cfg.startup_jitter)
+            .cache_error_threshold(cfg.cache_error_threshold)
+            .cache_error_window(cfg.cache_error_window);
         builder
     }
 }
@@ -180,7 +343,7 @@ impl From<DownloadScheduleConfig> for DownloadScheduleConfigBuilder {
 /// Many members of this type can be replaced with a new configuration on a
 /// running Arti client. Those that cannot are documented.
 #[derive(Debug, Clone, Builder, Eq, PartialEq)]
-#[builder(build_fn(error = "ConfigBuildError"))]
+#[builder(build_fn(validate = "Self::validate", error = "ConfigBuildError"))]
 pub struct DirMgrConfig {
     /// Location to use for storing and reading current-format
     /// directory information.
@@ -217,9 +380,154 @@ pub struct DirMgrConfig {
     /// option will always be delayed.)
     #[builder(default)]
     override_net_params: netstatus::NetParams<i32>,
+
+    /// How long after its valid-until time we are still willing to use a
+    /// consensus from our cache.
+    ///
+    /// By default this is zero, and we never use an expired consensus.
+    /// Setting it lets a client that can't reach the network keep working
+    /// from stale directory information; see
+    /// [`DirMgr::consensus_is_expired`](crate::DirMgr::consensus_is_expired)
+    /// to tell when that is happening.
+    ///
+    /// This is a security tradeoff: an expired consensus may list relays
+    /// that have since gone away or been found to be malicious.  We never
+    /// accept an expired consensus from the network, whatever this is set to.
+    ///
+    /// This can be replaced on a running Arti client.  Doing so will take
+    /// effect the next time we load a consensus from the cache.
+    #[builder(default)]
+    expired_consensus_tolerance: Duration,
+
+    /// The oldest consensus that we are willing to bootstrap from, measured
+    /// from its valid-after time.
+    ///
+    /// By default this is unset, and we accept any consensus that is
+    /// currently valid.  If it is set, and a consensus that we download is
+    /// older than this even after we have validated it, bootstrapping fails
+    /// with an error rather than going on with old directory information.
+    /// A cached consensus that is too old is ignored, and we download a new
+    /// one instead.
+    ///
+    /// This can be replaced on a running Arti client.  Doing so will take
+    /// effect the next time we fetch or load a consensus.
+    #[builder(default, setter(strip_option))]
+    max_consensus_age: Option<Duration>,
+
+    /// If true, check that each microdescriptor we load from our cache
+    /// still matches the digest we stored it under.
+    ///
+    /// By default this is false: we only store documents that we have
+    /// already checked, and checking them again costs time on every
+    /// startup.  Setting it protects against a cache that has been damaged
+    /// on disk.  Any microdescriptor that fails the check is discarded and
+    /// deleted from the cache, so that we download it again.
+    ///
+    /// This can be replaced on a running Arti client.  Doing so will take
+    /// effect the next time we load documents from the cache.
+    #[builder(default)]
+    check_cache_integrity: bool,
+
+    /// If true, refuse to use a consensus that requires subprotocol versions
+    /// that we don't implement.
+    ///
+    /// By default this is false: if the consensus requires something we
+    /// lack, we log a warning and announce
+    /// [`DirEvent::MissingRequiredProtocols`](crate::DirEvent::MissingRequiredProtocols),
+    /// but keep going.  Setting it makes bootstrapping fail with an error
+    /// instead, since the network has told us that this version of Arti is
+    /// too old to use it safely.
+    ///
+    /// This can be replaced on a running Arti client.  Doing so will take
+    /// effect the next time we validate a consensus.
+    #[builder(default)]
+    enforce_required_protocols: bool,
+
+    /// If true, prefer to ask directory caches that we can reach over IPv6.
+    ///
+    /// By default this is false, and we choose among directory caches
+    /// without regard to their addresses.  Setting it helps on networks
+    /// where IPv6 works better than IPv4 (or IPv4 doesn't work at all): if
+    /// any of the caches that we'd otherwise choose among has an IPv6
+    /// address, we only choose among those caches, and connect to them over
+    /// IPv6.  If none of them has one, this has no effect.
+    ///
+    /// This can be replaced on a running Arti client.  Doing so will take
+    /// effect the next time we choose a cache to ask.
+    #[builder(default)]
+    prefer_ipv6_caches: bool,
+
+    /// If true, cancel any download requests that are still in progress
+    /// once we've received enough to move on to the next stage of
+    /// bootstrapping.
+    ///
+    /// By default this is false, and we wait for every request that we've
+    /// launched before we move on.  Setting it can make bootstrapping
+    /// faster when some caches are slow, at the cost of throwing away
+    /// whatever those requests had already downloaded.
+    ///
+    /// This can be replaced on a running Arti client.  Doing so will take
+    /// effect the next time we download directory documents.
+    #[builder(default)]
+    cancel_unneeded_requests: bool,
+
+    /// Which flavor of consensus to download, and to look for in our cache.
+    ///
+    /// This must be [`ConsensusFlavor::Microdesc`](netstatus::ConsensusFlavor::Microdesc)
+    /// (the default) for now: we can't yet build a directory from an
+    /// "ns"-flavored consensus, so building a configuration that asks for
+    /// one is an error.
+    ///
+    /// Cannot be changed on a running Arti client.
+    #[builder(default = "netstatus::ConsensusFlavor::Microdesc")]
+    consensus_flavor: netstatus::ConsensusFlavor,
+
+    /// If true, stop bootstrapping once we have a validated consensus, and
+    /// never download microdescriptors.
+    ///
+    /// By default this is false.  Setting it saves a good deal of bandwidth
+    /// for applications that only want to know which relays are listed,
+    /// and never build circuits.  A `DirMgr` configured this way never has
+    /// a [`NetDir`](tor_netdir::NetDir): use
+    /// [`DirMgr::consensus`](crate::DirMgr::consensus) to get the
+    /// consensus instead.
+    ///
+    /// Cannot be changed on a running Arti client.
+    #[builder(default)]
+    consensus_only: bool,
+
+    /// If true, only take a consensus from our cache, and never download
+    /// one; download everything else as usual.
+    ///
+    /// By default this is false.  Setting it is useful for applications that
+    /// keep the cache's consensus up to date themselves, out of band, and
+    /// want predictable behavior at startup: if the cache has no consensus
+    /// that we can use (for example, because the one there has expired),
+    /// bootstrapping fails with
+    /// [`Error::NoCachedConsensus`](crate::Error::NoCachedConsensus) instead
+    /// of asking the network for a new one.  Authority certificates and
+    /// microdescriptors that the cache is missing are still downloaded.
+    ///
+    /// Cannot be changed on a running Arti client.
+    #[builder(default)]
+    consensus_from_cache: bool,
 }
 
 impl DirMgrConfigBuilder {
+    /// Check that this builder will give a configuration that we can use.
+    fn validate(&self) -> std::result::Result<(), ConfigBuildError> {
+        match self.consensus_flavor {
+            None | Some(netstatus::ConsensusFlavor::Microdesc) => Ok(()),
+            Some(flavor) => Err(ConfigBuildError::Invalid {
+                field: "consensus_flavor".to_owned(),
+                problem: format!(
+                    "We can't build a directory from a {} consensus yet",
+                    flavor.name()
+                ),
+            }),
+        }
+    }
+
     /// Overrides the network consensus parameter named `param` with a
     /// new value.
     ///
@@ -271,11 +579,66 @@ impl DirMgrConfig {
         self.network_config.fallbacks()
     }
 
+    /// Return the configured set of directory mirrors
+    pub(crate) fn mirrors(&self) -> &[DirMirror] {
+        self.network_config.mirrors()
+    }
+
     /// Return set of configured networkstatus parameter overrides.
     pub(crate) fn override_net_params(&self) -> &netstatus::NetParams<i32> {
         &self.override_net_params
     }
 
+    /// Return how long after its valid-until time we may still use a consensus
+    /// from the cache.
+    pub(crate) fn expired_consensus_tolerance(&self) -> Duration {
+        self.expired_consensus_tolerance
+    }
+
+    /// Return the oldest consensus we will accept, if there is a limit.
+    pub(crate) fn max_consensus_age(&self) -> Option<Duration> {
+        self.max_consensus_age
+    }
+
+    /// Return true if we should re-check documents that we load from the
+    /// cache.
+    pub(crate) fn check_cache_integrity(&self) -> bool {
+        self.check_cache_integrity
+    }
+
+    /// Return true if we should refuse a consensus that requires protocols we
+    /// don't implement.
+    pub(crate) fn enforce_required_protocols(&self) -> bool {
+        self.enforce_required_protocols
+    }
+
+    /// Return true if we should prefer directory caches with IPv6 addresses.
+    pub(crate) fn prefer_ipv6_caches(&self) -> bool {
+        self.prefer_ipv6_caches
+    }
+
+    /// Return true if we should cancel requests in progress once we can
+    /// advance to the next bootstrap stage.
+    pub(crate) fn cancel_unneeded_requests(&self) -> bool {
+        self.cancel_unneeded_requests
+    }
+
+    /// Return the flavor of consensus that we should download and cache.
+    pub(crate) fn consensus_flavor(&self) -> netstatus::ConsensusFlavor {
+        self.consensus_flavor
+    }
+
+    /// Return true if we should stop once we have a consensus, without
+    /// fetching microdescriptors.
+    pub(crate) fn consensus_only(&self) -> bool {
+        self.consensus_only
+    }
+
+    /// Return true if we may only take our consensus from the cache.
+    pub(crate) fn consensus_from_cache(&self) -> bool {
+        self.consensus_from_cache
+    }
+
     /// Return the schedule configuration we should use to decide when to
     /// attempt and retry downloads.
     pub(crate) fn schedule(&self) -> &DownloadScheduleConfig {
@@ -292,9 +655,19 @@ impl DirMgrConfig {
             network_config: NetworkConfig {
                 fallback_caches: new_config.network_config.fallback_caches.clone(),
                 authorities: self.network_config.authorities.clone(),
+                mirrors: new_config.network_config.mirrors.clone(),
             },
             schedule_config: new_config.schedule_config.clone(),
             override_net_params: new_config.override_net_params.clone(),
+            expired_consensus_tolerance: new_config.expired_consensus_tolerance,
+            max_consensus_age: new_config.max_consensus_age,
+            check_cache_integrity: new_config.check_cache_integrity,
+            enforce_required_protocols: new_config.enforce_required_protocols,
+            prefer_ipv6_caches: new_config.prefer_ipv6_caches,
+            cancel_unneeded_requests: new_config.cancel_unneeded_requests,
+            consensus_flavor: self.consensus_flavor,
+            consensus_only: self.consensus_only,
+            consensus_from_cache: self.consensus_from_cache,
         }
     }
 }
@@ -320,6 +693,70 @@ impl DownloadScheduleConfig {
     pub(crate) fn retry_microdescs(&self) -> &DownloadSchedule {
         &self.retry_microdescs
     }
+
+    /// Return the most bytes per second that we should take in from
+    /// directory downloads, or 0 if there is no limit.
+    pub(crate) fn max_download_rate(&self) -> u64 {
+        self.max_download_rate
+    }
+
+    /// Return the most microdescriptor requests to have in progress at
+    /// once, or 0 if there is no separate limit.
+    pub(crate) fn max_microdesc_requests(&self) -> u8 {
+        self.max_microdesc_requests
+    }
+
+    /// Return how long to wait for a single consensus request.
+    pub(crate) fn consensus_timeout(&self) -> Duration {
+        self.consensus_timeout
+    }
+
+    /// Return how long to wait for a single authority certificate request.
+    pub(crate) fn certs_timeout(&self) -> Duration {
+        self.certs_timeout
+    }
+
+    /// Return the base amount of time to wait for a single microdescriptor
+    /// request.
+    pub(crate) fn microdescs_timeout(&self) -> Duration {
+        self.microdescs_timeout
+    }
+
+    /// Return the largest consensus that we'll accept, in bytes.
+    pub(crate) fn max_consensus_size(&self) -> usize {
+        self.max_consensus_size
+    }
+
+    /// Return the most bytes we'll accept for each authority certificate
+    /// that we ask for.
+    pub(crate) fn max_authcert_size(&self) -> usize {
+        self.max_authcert_size
+    }
+
+    /// Return the most bytes we'll accept for each microdescriptor that we
+    /// ask for.
+    pub(crate) fn max_microdesc_size(&self) -> usize {
+        self.max_microdesc_size
+    }
+
+    /// Return the longest we'll wait before making our first directory
+    /// request.
+    pub(crate) fn startup_jitter(&self) -> Duration {
+        self.startup_jitter
+    }
+
+    /// Return how many times a directory cache has to fail us within
+    /// [`cache_error_window`](Self::cache_error_window) before we move on
+    /// from it.
+    pub(crate) fn cache_error_threshold(&self) -> u32 {
+        self.cache_error_threshold
+    }
+
+    /// Return how long an error from a directory cache counts toward
+    /// [`cache_error_threshold`](Self::cache_error_threshold).
+    pub(crate) fn cache_error_window(&self) -> Duration {
+        self.cache_error_window
+    }
 }
 
 /// Helpers for initializing the fallback list.
@@ -0,0 +1,130 @@
+//! Summaries of what changed between one network directory and the next.
+//!
+//! When a [`DirMgr`](crate::DirMgr) replaces its directory, it can tell an
+//! observer (see [`DirObserver::on_netdir_replaced`](crate::DirObserver::on_netdir_replaced))
+//! which relays the new directory lists that the old one didn't, and so on,
+//! so that the observer doesn't have to compare the two directories itself.
+
+use std::collections::BTreeMap;
+
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdir::NetDir;
+use tor_netdoc::doc::netstatus::RelayFlags;
+
+/// A summary of how the relays listed in one [`NetDir`] differ from those
+/// listed in the one that it replaced.
+///
+/// Relays are identified by their RSA identities, and each list is sorted.
+/// We consider every relay that a directory's consensus lists, including
+/// ones that we can't use because we don't have their microdescriptors.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetDirDiff {
+    /// Relays that the new directory lists, but the old one didn't.
+    added: Vec<RsaIdentity>,
+    /// Relays that the old directory listed, but the new one doesn't.
+    removed: Vec<RsaIdentity>,
+    /// Relays that both directories list, with different flags.
+    flags_changed: Vec<RsaIdentity>,
+}
+
+impl NetDirDiff {
+    /// Compute the difference between `old` and `new`.
+    ///
+    /// If there is no `old` directory, every relay in `new` counts as added.
+    pub(crate) fn new(old: Option<&NetDir>, new: &NetDir) -> Self {
+        let old_flags: BTreeMap<RsaIdentity, RelayFlags> = old
+            .into_iter()
+            .flat_map(NetDir::all_relays)
+            .map(|r| (*r.rsa_id(), *r.flags()))
+            .collect();
+        let new_flags: BTreeMap<RsaIdentity, RelayFlags> = new
+            .all_relays()
+            .map(|r| (*r.rsa_id(), *r.flags()))
+            .collect();
+
+        let mut diff = NetDirDiff::default();
+        for (id, flags) in &new_flags {
+            match old_flags.get(id) {
+                None => diff.added.push(*id),
+                Some(old) if old != flags => diff.flags_changed.push(*id),
+                Some(_) => {}
+            }
+        }
+        diff.removed = old_flags
+            .keys()
+            .filter(|id| !new_flags.contains_key(id))
+            .copied()
+            .collect();
+        diff
+    }
+
+    /// Return the relays that the new directory lists, but the old one
+    /// didn't.
+    pub fn added(&self) -> &[RsaIdentity] {
+        &self.added[..]
+    }
+
+    /// Return the relays that the old directory listed, but the new one
+    /// doesn't.
+    pub fn removed(&self) -> &[RsaIdentity] {
+        &self.removed[..]
+    }
+
+    /// Return the relays that both directories list, but with different
+    /// flags.
+    pub fn flags_changed(&self) -> &[RsaIdentity] {
+        &self.flags_changed[..]
+    }
+
+    /// Return true if the two directories list the same relays, with the
+    /// same flags.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.flags_changed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use tor_netdir::testnet;
+
+    #[test]
+    fn diff() {
+        let netdir = |func: fn(usize, &mut testnet::NodeBuilders)| {
+            testnet::construct_custom_netdir(func)
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap()
+        };
+        let base = netdir(|_, _| {});
+
+        // Nothing changed.
+        assert!(NetDirDiff::new(Some(&base), &base).is_empty());
+
+        // With no old directory, everything is new.
+        let diff = NetDirDiff::new(None, &base);
+        assert_eq!(diff.added().len(), 40);
+        assert!(diff.removed().is_empty());
+        assert!(diff.flags_changed().is_empty());
+
+        // Relay 3 goes away, and relay 7 loses its flags.
+        let changed = netdir(|idx, nb| {
+            if idx == 3 {
+                nb.omit_rs = true;
+            } else if idx == 7 {
+                nb.rs.set_flags(RelayFlags::RUNNING | RelayFlags::VALID);
+            }
+        });
+        let diff = NetDirDiff::new(Some(&base), &changed);
+        assert!(diff.added().is_empty());
+        assert_eq!(diff.removed(), &[RsaIdentity::from([3; 20])]);
+        assert_eq!(diff.flags_changed(), &[RsaIdentity::from([7; 20])]);
+
+        // And going back, relay 3 comes back.
+        let diff = NetDirDiff::new(Some(&changed), &base);
+        assert_eq!(diff.added(), &[RsaIdentity::from([3; 20])]);
+        assert!(diff.removed().is_empty());
+        assert_eq!(diff.flags_changed(), &[RsaIdentity::from([7; 20])]);
+    }
+}
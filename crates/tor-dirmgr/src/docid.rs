@@ -1,9 +1,12 @@
 //! Declare a general purpose "document ID type" for tracking which
 //! documents we want and which we have.
 
-use std::{borrow::Borrow, collections::HashMap};
+use std::collections::hash_map::Entry;
+use std::{collections::HashMap, fmt};
 
 use tor_dirclient::request;
+#[cfg(feature = "votes")]
+use tor_llcrypto::pk::rsa::RsaIdentity;
 #[cfg(feature = "routerdesc")]
 use tor_netdoc::doc::routerdesc::RdDigest;
 use tor_netdoc::doc::{authcert::AuthCertKeyIds, microdesc::MdDigest, netstatus::ConsensusFlavor};
@@ -29,6 +32,10 @@ pub enum DocId {
     /// digest.
     #[cfg(feature = "routerdesc")]
     RouterDesc(RdDigest),
+    /// A request for the current vote of a directory authority, by the
+    /// SHA1 digest of its identity key.
+    #[cfg(feature = "votes")]
+    AuthVote(RsaIdentity),
 }
 
 /// The underlying type of a DocId.
@@ -47,6 +54,9 @@ pub(crate) enum DocType {
     /// A router descriptor.
     #[cfg(feature = "routerdesc")]
     RouterDesc,
+    /// An authority's vote.
+    #[cfg(feature = "votes")]
+    AuthVote,
 }
 
 impl DocId {
@@ -60,6 +70,110 @@ impl DocId {
             Microdesc(_) => T::Microdesc,
             #[cfg(feature = "routerdesc")]
             RouterDesc(_) => T::RouterDesc,
+            #[cfg(feature = "votes")]
+            AuthVote(_) => T::AuthVote,
+        }
+    }
+}
+
+/// A summary of which documents a [`DirMgr`](crate::DirMgr) is still
+/// missing, grouped by type.
+///
+/// Returned by [`DirMgr::missing_summary`](crate::DirMgr::missing_summary).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MissingSummary {
+    /// True if we are missing a consensus document.
+    consensus: bool,
+    /// The number of authority certificates we are missing.
+    n_authcerts: usize,
+    /// The number of microdescriptors we are missing.
+    n_microdescs: usize,
+    /// The number of router descriptors we are missing.
+    #[cfg(feature = "routerdesc")]
+    n_routerdescs: usize,
+    /// The number of authority votes we are missing.
+    #[cfg(feature = "votes")]
+    n_votes: usize,
+}
+
+impl MissingSummary {
+    /// Construct a summary of the documents in `ids`.
+    pub(crate) fn from_docs<'a, I>(ids: I) -> Self
+    where
+        I: IntoIterator<Item = &'a DocId>,
+    {
+        let mut summary = MissingSummary::default();
+        for id in ids {
+            match id {
+                DocId::LatestConsensus { .. } => summary.consensus = true,
+                DocId::AuthCert(_) => summary.n_authcerts += 1,
+                DocId::Microdesc(_) => summary.n_microdescs += 1,
+                #[cfg(feature = "routerdesc")]
+                DocId::RouterDesc(_) => summary.n_routerdescs += 1,
+                #[cfg(feature = "votes")]
+                DocId::AuthVote(_) => summary.n_votes += 1,
+            }
+        }
+        summary
+    }
+
+    /// Return true if we are missing a consensus document.
+    pub fn consensus_missing(&self) -> bool {
+        self.consensus
+    }
+
+    /// Return the number of authority certificates we are missing.
+    pub fn n_authcerts(&self) -> usize {
+        self.n_authcerts
+    }
+
+    /// Return the number of microdescriptors we are missing.
+    pub fn n_microdescs(&self) -> usize {
+        self.n_microdescs
+    }
+
+    /// Return the number of router descriptors we are missing.
+    #[cfg(feature = "routerdesc")]
+    pub fn n_routerdescs(&self) -> usize {
+        self.n_routerdescs
+    }
+
+    /// Return the number of authority votes we are missing.
+    #[cfg(feature = "votes")]
+    pub fn n_votes(&self) -> usize {
+        self.n_votes
+    }
+
+    /// Return true if we are not missing any documents.
+    pub fn is_empty(&self) -> bool {
+        self == &MissingSummary::default()
+    }
+}
+
+impl fmt::Display for MissingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if self.consensus {
+            parts.push("a consensus".to_owned());
+        }
+        if self.n_authcerts > 0 {
+            parts.push(format!("{} authority certificates", self.n_authcerts));
+        }
+        if self.n_microdescs > 0 {
+            parts.push(format!("{} microdescriptors", self.n_microdescs));
+        }
+        #[cfg(feature = "routerdesc")]
+        if self.n_routerdescs > 0 {
+            parts.push(format!("{} router descriptors", self.n_routerdescs));
+        }
+        #[cfg(feature = "votes")]
+        if self.n_votes > 0 {
+            parts.push(format!("{} authority votes", self.n_votes));
+        }
+        if parts.is_empty() {
+            write!(f, "nothing missing")
+        } else {
+            write!(f, "missing {}", parts.join(", "))
         }
     }
 }
@@ -77,6 +191,9 @@ pub(crate) enum ClientRequest {
     /// Request for one or more router descriptors
     #[cfg(feature = "routerdesc")]
     RouterDescs(request::RouterDescRequest),
+    /// Request for one or more authority votes
+    #[cfg(feature = "votes")]
+    AuthVotes(request::AuthVoteRequest),
 }
 
 impl ClientRequest {
@@ -89,6 +206,34 @@ impl ClientRequest {
             Microdescs(a) => a,
             #[cfg(feature = "routerdesc")]
             RouterDescs(a) => a,
+            #[cfg(feature = "votes")]
+            AuthVotes(a) => a,
+        }
+    }
+
+    /// Return the documents that this request asks for, if we can name
+    /// them.
+    ///
+    /// We can't name the document that a consensus request asks for, or
+    /// the ones that a request for all router descriptors asks for, so we
+    /// return None for those.
+    pub(crate) fn docids(&self) -> Option<Vec<DocId>> {
+        use ClientRequest::*;
+        match self {
+            Consensus(_) => None,
+            AuthCert(a) => Some(a.keys().map(|k| DocId::AuthCert(*k)).collect()),
+            Microdescs(a) => Some(a.digests().map(|d| DocId::Microdesc(*d)).collect()),
+            #[cfg(feature = "routerdesc")]
+            RouterDescs(a) => {
+                let ids: Vec<_> = a.digests().map(|d| DocId::RouterDesc(*d)).collect();
+                if ids.is_empty() {
+                    None
+                } else {
+                    Some(ids)
+                }
+            }
+            #[cfg(feature = "votes")]
+            AuthVotes(a) => Some(a.authority_ids().map(|id| DocId::AuthVote(*id)).collect()),
         }
     }
 }
@@ -107,6 +252,11 @@ pub enum CacheUsage {
     /// The bootstrap attempt is trying to fetch a new consensus. Therefore,
     /// we don't want a consensus from the cache.
     MustDownload,
+    /// The bootstrap attempt must take its consensus from the cache, but is
+    /// willing to download everything else.  Therefore, we want the latest
+    /// cached consensus, whether it is pending or not; if there isn't a
+    /// usable one, we give up rather than downloading it.
+    ConsensusFromCache,
 }
 
 impl CacheUsage {
@@ -140,6 +290,9 @@ pub(crate) enum DocQuery {
     /// A request for router descriptors
     #[cfg(feature = "routerdesc")]
     RouterDesc(Vec<RdDigest>),
+    /// A request for authority votes
+    #[cfg(feature = "votes")]
+    AuthVote(Vec<RsaIdentity>),
 }
 
 impl DocQuery {
@@ -157,6 +310,8 @@ impl DocQuery {
             DocId::Microdesc(_) => Self::Microdesc(Vec::new()),
             #[cfg(feature = "routerdesc")]
             DocId::RouterDesc(_) => Self::RouterDesc(Vec::new()),
+            #[cfg(feature = "votes")]
+            DocId::AuthVote(_) => Self::AuthVote(Vec::new()),
         }
     }
 
@@ -168,16 +323,71 @@ impl DocQuery {
             (Self::Microdesc(ids), DocId::Microdesc(id)) => ids.push(id),
             #[cfg(feature = "routerdesc")]
             (Self::RouterDesc(ids), DocId::RouterDesc(id)) => ids.push(id),
+            #[cfg(feature = "votes")]
+            (Self::AuthVote(ids), DocId::AuthVote(id)) => ids.push(id),
             (_, _) => panic!(),
         }
     }
 
+    /// Add all of the documents in `other` to this query.
+    ///
+    /// If `other` is for a different type of document, return it unchanged as
+    /// an error.  (Two consensus queries for the same flavor always merge into
+    /// `self`, whatever cache usage `other` wanted.)
+    pub(crate) fn merge(&mut self, other: DocQuery) -> std::result::Result<(), DocQuery> {
+        use DocQuery::*;
+        match (self, other) {
+            (LatestConsensus { flavor: f1, .. }, LatestConsensus { flavor: f2, .. })
+                if *f1 == f2 => {}
+            (AuthCert(ids), AuthCert(more)) => ids.extend(more),
+            (Microdesc(ids), Microdesc(more)) => ids.extend(more),
+            #[cfg(feature = "routerdesc")]
+            (RouterDesc(ids), RouterDesc(more)) => ids.extend(more),
+            #[cfg(feature = "votes")]
+            (AuthVote(ids), AuthVote(more)) => ids.extend(more),
+            (_, other) => return Err(other),
+        }
+        Ok(())
+    }
+
+    /// Remove any duplicate documents from this query.
+    pub(crate) fn dedup(&mut self) {
+        use DocQuery::*;
+        match self {
+            LatestConsensus { .. } => {}
+            AuthCert(ids) => {
+                ids.sort_unstable();
+                ids.dedup();
+            }
+            Microdesc(ids) => {
+                ids.sort_unstable();
+                ids.dedup();
+            }
+            #[cfg(feature = "routerdesc")]
+            RouterDesc(ids) => {
+                ids.sort_unstable();
+                ids.dedup();
+            }
+            #[cfg(feature = "votes")]
+            AuthVote(ids) => {
+                ids.sort_unstable();
+                ids.dedup();
+            }
+        }
+    }
+
     /// If this query contains too many documents to download with a single
     /// request, divide it up.
     pub(crate) fn split_for_download(self) -> Vec<Self> {
         use DocQuery::*;
         /// How many objects can be put in a single HTTP GET line?
         const N: usize = 500;
+        /// How many votes should we ask for in a single request?
+        ///
+        /// Each vote is about as large as a consensus, so we ask for them
+        /// a few at a time.
+        #[cfg(feature = "votes")]
+        const N_VOTE: usize = 3;
         match self {
             LatestConsensus { .. } => vec![self],
             AuthCert(mut v) => {
@@ -193,6 +403,11 @@ impl DocQuery {
                 v.sort_unstable();
                 v[..].chunks(N).map(|s| RouterDesc(s.to_vec())).collect()
             }
+            #[cfg(feature = "votes")]
+            AuthVote(mut v) => {
+                v.sort_unstable();
+                v[..].chunks(N_VOTE).map(|s| AuthVote(s.to_vec())).collect()
+            }
         }
     }
 }
@@ -206,18 +421,27 @@ impl From<DocId> for DocQuery {
 }
 
 /// Given a list of DocId, split them up into queries, by type.
+///
+/// Each resulting query lists each document only once, even if it appeared
+/// more than once in `collection`.
 pub(crate) fn partition_by_type<T>(collection: T) -> HashMap<DocType, DocQuery>
 where
     T: IntoIterator<Item = DocId>,
 {
-    let mut result = HashMap::new();
+    let mut result: HashMap<DocType, DocQuery> = HashMap::new();
     for item in collection.into_iter() {
-        let b = item.borrow();
-        let tp = b.doctype();
-        result
-            .entry(tp)
-            .or_insert_with(|| DocQuery::empty_from_docid(b))
-            .push(item);
+        match result.entry(item.doctype()) {
+            Entry::Vacant(e) => {
+                e.insert(item.into());
+            }
+            Entry::Occupied(mut e) => e
+                .get_mut()
+                .merge(item.into())
+                .expect("Queries for the same type of document didn't merge"),
+        }
+    }
+    for query in result.values_mut() {
+        query.dedup();
     }
     result
 }
@@ -247,6 +471,41 @@ mod test {
         assert_eq!(DocId::Microdesc([22; 32]).doctype(), DocType::Microdesc);
         #[cfg(feature = "routerdesc")]
         assert_eq!(DocId::RouterDesc([42; 20]).doctype(), DocType::RouterDesc);
+        #[cfg(feature = "votes")]
+        assert_eq!(
+            DocId::AuthVote([43; 20].into()).doctype(),
+            DocType::AuthVote
+        );
+    }
+
+    #[test]
+    fn missing_summary() {
+        let auth_id = AuthCertKeyIds {
+            id_fingerprint: [10; 20].into(),
+            sk_fingerprint: [12; 20].into(),
+        };
+        let ids = vec![
+            DocId::LatestConsensus {
+                flavor: ConsensusFlavor::Microdesc,
+                cache_usage: CacheUsage::CacheOkay,
+            },
+            DocId::AuthCert(auth_id),
+            DocId::Microdesc([22; 32]),
+            DocId::Microdesc([23; 32]),
+        ];
+        let summary = MissingSummary::from_docs(&ids);
+        assert!(summary.consensus_missing());
+        assert_eq!(summary.n_authcerts(), 1);
+        assert_eq!(summary.n_microdescs(), 2);
+        assert!(!summary.is_empty());
+        assert_eq!(
+            summary.to_string(),
+            "missing a consensus, 1 authority certificates, 2 microdescriptors"
+        );
+
+        let summary = MissingSummary::from_docs(&[]);
+        assert!(summary.is_empty());
+        assert_eq!(summary.to_string(), "nothing missing");
     }
 
     #[test]
@@ -370,6 +629,111 @@ mod test {
         assert_eq!(split, vec![query]);
     }
 
+    #[test]
+    #[cfg(feature = "routerdesc")]
+    fn split_routerdescs_bounded() {
+        use rand::Rng;
+
+        // A large set of router descriptors gets split into requests no
+        // bigger than the ones we make for microdescriptors.
+        let mut rng = rand::thread_rng();
+        let ids: Vec<RdDigest> = (0..2345).into_iter().map(|_| rng.gen()).collect();
+        let split = DocQuery::RouterDesc(ids).split_for_download();
+        assert_eq!(split.len(), 5);
+        let mut total = 0;
+        for q in split {
+            match q {
+                DocQuery::RouterDesc(ids) => {
+                    assert!(!ids.is_empty());
+                    assert!(ids.len() <= 500);
+                    total += ids.len();
+                }
+                _ => panic!("Wrong type."),
+            }
+        }
+        assert_eq!(total, 2345);
+    }
+
+    #[test]
+    #[cfg(feature = "votes")]
+    fn split_votes() {
+        let ids: Vec<RsaIdentity> = (0..9_u8).map(|b| [b; 20].into()).collect();
+        let mut q = DocQuery::AuthVote(ids.clone());
+        q.merge(DocQuery::AuthVote(ids[..4].to_vec())).unwrap();
+        q.dedup();
+        assert_eq!(q, DocQuery::AuthVote(ids.clone()));
+
+        // Votes are big, so we only ask for a few of them at a time.
+        let split = q.split_for_download();
+        assert_eq!(split.len(), 3);
+        for q in split {
+            assert!(matches!(q, DocQuery::AuthVote(v) if v.len() == 3));
+        }
+    }
+
+    #[test]
+    fn partition_with_duplicates() {
+        let md1 = DocId::Microdesc([1; 32]);
+        let md2 = DocId::Microdesc([2; 32]);
+        let cert = DocId::AuthCert(AuthCertKeyIds {
+            id_fingerprint: [3; 20].into(),
+            sk_fingerprint: [4; 20].into(),
+        });
+        let consensus = DocId::LatestConsensus {
+            flavor: ConsensusFlavor::Microdesc,
+            cache_usage: CacheUsage::CacheOkay,
+        };
+        // As if we had gathered missing documents from several states.
+        let ids = vec![md1, cert, consensus, md2, md1, consensus, cert, md2, md1];
+
+        let split = partition_by_type(ids);
+        assert_eq!(split.len(), 3);
+        assert_eq!(
+            split.get(&DocType::Microdesc).unwrap(),
+            &DocQuery::Microdesc(vec![[1; 32], [2; 32]])
+        );
+        assert!(matches!(
+            split.get(&DocType::AuthCert).unwrap(),
+            DocQuery::AuthCert(v) if v.len() == 1
+        ));
+
+        // Every query fits in a single request.
+        let n_requests: usize = split
+            .into_iter()
+            .map(|(_, q)| q.split_for_download().len())
+            .sum();
+        assert_eq!(n_requests, 3);
+    }
+
+    #[test]
+    fn merge() {
+        let mut q = DocQuery::Microdesc(vec![[3; 32], [1; 32]]);
+        q.merge(DocQuery::Microdesc(vec![[2; 32], [1; 32]]))
+            .unwrap();
+        q.dedup();
+        assert_eq!(q, DocQuery::Microdesc(vec![[1; 32], [2; 32], [3; 32]]));
+
+        // Different types don't merge.
+        let certs = DocQuery::AuthCert(vec![]);
+        assert_eq!(q.merge(certs.clone()), Err(certs));
+
+        // Consensus queries merge only with the same flavor.
+        let mut q = DocQuery::LatestConsensus {
+            flavor: ConsensusFlavor::Microdesc,
+            cache_usage: CacheUsage::CacheOkay,
+        };
+        let q2 = DocQuery::LatestConsensus {
+            flavor: ConsensusFlavor::Microdesc,
+            cache_usage: CacheUsage::MustDownload,
+        };
+        assert_eq!(q.merge(q2), Ok(()));
+        let q3 = DocQuery::LatestConsensus {
+            flavor: ConsensusFlavor::Ns,
+            cache_usage: CacheUsage::CacheOkay,
+        };
+        assert_eq!(q.merge(q3.clone()), Err(q3));
+    }
+
     #[test]
     fn into_query() {
         let q: DocQuery = DocId::Microdesc([99; 32]).into();
@@ -385,5 +749,6 @@ mod test {
         // our needs.
         assert_eq!(CacheUsage::CacheOkay.pending_requirement(), None);
         assert_eq!(CacheUsage::MustDownload.pending_requirement(), None);
+        assert_eq!(CacheUsage::ConsensusFromCache.pending_requirement(), None);
     }
 }
@@ -0,0 +1,237 @@
+//! Code to seed our cache with directory documents that were compiled into
+//! the program.
+
+use crate::docmeta::{AuthCertMeta, ConsensusMeta};
+use crate::storage::Store;
+use crate::{DocSource, Error, Result};
+
+use tor_checkable::{ExternallySigned, SelfSigned, Timebound};
+use tor_netdoc::doc::authcert::AuthCert;
+use tor_netdoc::doc::microdesc::MicrodescReader;
+use tor_netdoc::doc::netstatus::{ConsensusFlavor, MdConsensus};
+use tor_netdoc::AllowAnnotations;
+
+use std::time::SystemTime;
+use tracing::debug;
+
+/// A consensus, along with the authority certificates and microdescriptors
+/// that go with it, embedded in the program as bytes.
+///
+/// Programs that ship with a known-good directory can use this (usually
+/// with `include_bytes!`) to give [`DirMgr`](crate::DirMgr) something to
+/// bootstrap from on its very first run.  See
+/// [`DirMgr::seed_from_embedded`](crate::DirMgr::seed_from_embedded).
+///
+/// None of these documents are trusted just because they were embedded:
+/// they are checked like any other document when we load them from the
+/// cache, and replaced by newer ones from the network as usual.
+#[derive(Clone, Debug)]
+pub struct EmbeddedDirectory {
+    /// The full text of a microdescriptor consensus.
+    consensus: &'static [u8],
+    /// The text of zero or more authority certificates, one after another.
+    authcerts: &'static [u8],
+    /// The text of zero or more microdescriptors, one after another.
+    microdescs: &'static [u8],
+}
+
+impl EmbeddedDirectory {
+    /// Construct a new EmbeddedDirectory from the text of a microdescriptor
+    /// consensus, the authority certificates that sign it, and the
+    /// microdescriptors that it lists.
+    ///
+    /// `authcerts` and `microdescs` may each hold any number of documents,
+    /// including none.
+    pub fn new(
+        consensus: &'static [u8],
+        authcerts: &'static [u8],
+        microdescs: &'static [u8],
+    ) -> Self {
+        EmbeddedDirectory {
+            consensus,
+            authcerts,
+            microdescs,
+        }
+    }
+}
+
+/// Return `bytes` as a string, or an error if it isn't UTF-8.
+fn as_str(bytes: &'static [u8]) -> Result<&'static str> {
+    std::str::from_utf8(bytes).map_err(Error::BadUtf8InEmbedded)
+}
+
+/// Put the documents from `embedded` into `store`, as of `now`.
+///
+/// If `store` already has a consensus at least as recent as the embedded
+/// one, we leave it alone; the certificates and microdescriptors are stored
+/// either way.
+pub(crate) fn seed_store(
+    store: &mut dyn Store,
+    embedded: &EmbeddedDirectory,
+    now: SystemTime,
+) -> Result<()> {
+    let from_netdoc = |e: tor_netdoc::Error| Error::from_netdoc(DocSource::Embedded, e);
+
+    let consensus = as_str(embedded.consensus)?;
+    let (signed, remainder, parsed) = MdConsensus::parse(consensus).map_err(from_netdoc)?;
+    // We only want the metadata here: we check the consensus when we load
+    // it from the cache.
+    let parsed = parsed
+        .dangerously_assume_timely()
+        .dangerously_assume_wellsigned();
+    let meta = ConsensusMeta::from_consensus(signed, remainder, &parsed);
+    // An embedded directory always holds a microdesc consensus, whatever
+    // flavor we've been configured to fetch.
+    let have_newer = store
+        .latest_consensus_meta(ConsensusFlavor::Microdesc)?
+        .map(|m| m.lifetime().valid_after() >= meta.lifetime().valid_after())
+        .unwrap_or(false);
+    if have_newer {
+        debug!("Cache already has a consensus as recent as the embedded one.");
+    } else {
+        // We store the consensus as pending, since we haven't yet made sure
+        // that we can use it.
+        store.store_consensus(&meta, ConsensusFlavor::Microdesc, true, consensus)?;
+    }
+
+    let authcerts = as_str(embedded.authcerts)?;
+    let mut certs = Vec::new();
+    for cert in AuthCert::parse_multiple(authcerts) {
+        let cert = cert.map_err(from_netdoc)?;
+        let text = cert
+            .within(authcerts)
+            .expect("Certificate was not in input as expected");
+        // We check whether the certificate is timely when we load it.
+        let cert = cert.check_signature()?.dangerously_assume_timely();
+        certs.push((AuthCertMeta::from_authcert(&cert), text));
+    }
+    if !certs.is_empty() {
+        store.store_authcerts(&certs[..])?;
+    }
+
+    let microdescs = as_str(embedded.microdescs)?;
+    let mds = MicrodescReader::new(microdescs, &AllowAnnotations::AnnotationsNotAllowed)
+        .map(|res| {
+            let anno = res.map_err(from_netdoc)?;
+            let text = anno
+                .within(microdescs)
+                .expect("Microdesc was not in input as expected");
+            Ok((text, *anno.into_microdesc().digest()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if !mds.is_empty() {
+        let mds: Vec<_> = mds.iter().map(|(text, d)| (*text, d)).collect();
+        store.store_microdescs(&mds[..], now)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::docid::CacheUsage;
+    use crate::state::GetConsensusState;
+    use crate::testing::{authority_network, config_builder, consensus_time, AUTHCERTS, CONSENSUS};
+    use crate::{DirMgr, DocId};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    const CONSENSUS2: &[u8] = include_bytes!("../testdata/mdconsensus2.txt");
+    const MICRODESCS: &[u8] = include_bytes!("../testdata/microdescs.txt");
+
+    #[test]
+    fn seed_and_load() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            rt.jump_to(consensus_time());
+
+            let tempdir = TempDir::new().unwrap();
+            let mut netcfg = authority_network();
+            netcfg.fallback_caches(vec![]);
+            let cfg = config_builder(tempdir.path())
+                .network_config(netcfg.build().unwrap())
+                .build()
+                .unwrap();
+            let mgr = Arc::new(DirMgr::from_config(cfg, rt.clone(), None, false).unwrap());
+
+            // Before we seed the cache, there's nothing to load.
+            let state =
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap();
+            let state = crate::bootstrap::load(Arc::clone(&mgr), Box::new(state))
+                .await
+                .unwrap();
+            assert_eq!(&state.describe(), "Looking for a consensus.");
+
+            let embedded =
+                EmbeddedDirectory::new(CONSENSUS.as_bytes(), AUTHCERTS.as_bytes(), MICRODESCS);
+            mgr.seed_from_embedded(&embedded).unwrap();
+
+            // Every embedded microdescriptor is in the cache now...
+            let mds: Vec<_> = MicrodescReader::new(
+                as_str(MICRODESCS).unwrap(),
+                &AllowAnnotations::AnnotationsNotAllowed,
+            )
+            .map(|res| *res.unwrap().into_microdesc().digest())
+            .collect();
+            assert_eq!(mds.len(), 4);
+            for d in mds {
+                assert!(mgr.text(&DocId::Microdesc(d)).unwrap().is_some());
+            }
+
+            // ... and the consensus and certificates are enough to get us
+            // to the point of fetching microdescriptors, without downloading
+            // anything.  (The embedded microdescriptors belong to a
+            // different consensus.)
+            let state =
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap();
+            let state = crate::bootstrap::load(Arc::clone(&mgr), Box::new(state))
+                .await
+                .unwrap();
+            assert_eq!(
+                &state.describe(),
+                "Downloading microdescriptors (we are missing 6)."
+            );
+        });
+    }
+
+    #[test]
+    fn keep_newer_consensus() {
+        let tempdir = TempDir::new().unwrap();
+        let mut store = crate::storage::SqliteStore::from_path(tempdir.path(), false).unwrap();
+        let now = SystemTime::now();
+        let valid_after = |store: &crate::storage::SqliteStore| {
+            store
+                .latest_consensus_meta(ConsensusFlavor::Microdesc)
+                .unwrap()
+                .unwrap()
+                .lifetime()
+                .valid_after()
+        };
+
+        let newer = EmbeddedDirectory::new(CONSENSUS2, b"", b"");
+        seed_store(&mut store, &newer, now).unwrap();
+        let newer_va = valid_after(&store);
+
+        // An older embedded consensus doesn't replace the newer one.
+        let older = EmbeddedDirectory::new(CONSENSUS.as_bytes(), b"", b"");
+        seed_store(&mut store, &older, now).unwrap();
+        assert_eq!(valid_after(&store), newer_va);
+
+        // Junk is an error.
+        let junk = EmbeddedDirectory::new(b"not a consensus", b"", b"");
+        assert!(matches!(
+            seed_store(&mut store, &junk, now),
+            Err(Error::NetDocError {
+                source: DocSource::Embedded,
+                ..
+            })
+        ));
+        let bad_utf8 = EmbeddedDirectory::new(b"\xff", b"", b"");
+        assert!(matches!(
+            seed_store(&mut store, &bad_utf8, now),
+            Err(Error::BadUtf8InEmbedded(_))
+        ));
+    }
+}
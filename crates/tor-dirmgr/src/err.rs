@@ -1,6 +1,7 @@
 //! Declare an error type for the tor-dirmgr crate.
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::DocSource;
 use futures::task::SpawnError;
@@ -37,13 +38,54 @@ pub enum Error {
     /// A consensus document is signed by an unrecognized authority set.
     #[error("authorities on consensus do not match what we expect.")]
     UnrecognizedAuthorities,
+    /// A consensus document claims to be signed by too few of the
+    /// authorities we recognize for us to trust it.
+    ///
+    /// The cache that sent it might be serving an old or forged consensus.
+    #[error("consensus is signed by {have} of the authorities we recognize, but we need {need}")]
+    NotEnoughSignatures {
+        /// How many of our authorities the consensus claims to be signed by.
+        have: usize,
+        /// How many of our authorities need to have signed it.
+        need: usize,
+    },
+    /// A correctly signed consensus was older than we are configured to
+    /// accept.
+    ///
+    /// This is not retryable: if the authorities signed it, the network is
+    /// probably not producing anything newer, and we would rather stop than
+    /// run on old information.
+    #[error("consensus is {age:?} old, but we only accept ones up to {max_age:?} old")]
+    ConsensusTooOld {
+        /// How long ago the consensus became valid.
+        age: Duration,
+        /// The oldest consensus we are configured to accept.
+        max_age: Duration,
+    },
+    /// A correctly signed consensus requires subprotocol versions that we
+    /// don't implement, and we are configured to refuse it.
+    ///
+    /// This is not retryable: the network has told us that we are too old
+    /// to use it, and fetching the consensus again won't change that.
+    #[error("consensus requires protocols that we don't implement: {missing}")]
+    MissingRequiredProtocols {
+        /// The required protocol versions that we lack.
+        missing: tor_protover::Protocols,
+    },
+    /// We may only take our consensus from the cache, but the cache has no
+    /// consensus that we can use.
+    ///
+    /// This is retryable: whoever keeps the cache up to date may put a new
+    /// consensus there.
+    #[error("no usable consensus in the cache, and we may not download one")]
+    NoCachedConsensus,
     /// A directory manager has been dropped; background tasks can exit too.
     #[error("dirmgr has been dropped; background tasks exiting")]
     ManagerDropped,
     /// We made a bunch of attempts, but weren't unable to advance the
     /// state of a download.
-    #[error("unable to finish bootstrapping a directory")]
-    CantAdvanceState,
+    #[error("unable to finish bootstrapping a directory: {0}")]
+    CantAdvanceState(CantAdvanceReason),
     /// Blob storage error
     #[error("storage error: {0}")]
     StorageError(String),
@@ -53,9 +95,26 @@ pub enum Error {
     /// Invalid UTF8 in directory response.
     #[error("invalid utf-8 from directory server")]
     BadUtf8FromDirectory(#[source] std::string::FromUtf8Error),
+    /// A codec that somebody registered failed to undo the content-encoding
+    /// on a directory response.
+    #[error("unable to decode {encoding:?} response from directory server")]
+    ContentDecoding {
+        /// The name of the content-encoding.
+        encoding: String,
+        /// The error that the codec gave us.
+        #[source]
+        cause: Arc<std::io::Error>,
+    },
+    /// A directory server said that it had what we asked for, but sent
+    /// nothing.
+    #[error("empty response from directory server")]
+    EmptyResponse,
     /// Invalid UTF8 from our cache.
     #[error("Invalid utf-8 in directory cache")]
     BadUtf8InCache(#[source] std::str::Utf8Error),
+    /// Invalid UTF8 in an embedded directory.
+    #[error("Invalid utf-8 in embedded directory")]
+    BadUtf8InEmbedded(#[source] std::str::Utf8Error),
     /// Invalid hexadecimal value in the cache.
     #[error("Invalid hexadecimal id in directory cache")]
     BadHexInCache(#[source] hex::FromHexError),
@@ -96,6 +155,35 @@ pub enum Error {
     Bug(#[from] tor_error::Bug),
 }
 
+/// Why we were unable to advance the state of a download.
+///
+/// Returned as part of [`Error::CantAdvanceState`].
+#[derive(Clone, Debug, Eq, PartialEq, derive_more::Display)]
+#[non_exhaustive]
+pub enum CantAdvanceReason {
+    /// Every request that we made failed without giving us a response.
+    #[display(fmt = "every download attempt failed")]
+    AllAttemptsErrored,
+    /// We got responses, but none of them told us anything that we could
+    /// use: the caches declined our requests, or sent us documents that we
+    /// rejected.
+    #[display(fmt = "no directory cache gave us a usable response")]
+    NoUsableResponses,
+    /// We got some of the documents we were missing, but not enough to
+    /// move on.
+    #[display(fmt = "only got {} of {} missing documents", got, needed)]
+    InsufficientDocuments {
+        /// How many of the documents that we were missing did we get?
+        got: usize,
+        /// How many documents were we missing when we started?
+        needed: usize,
+    },
+    /// The task that was downloading our directory stopped before it
+    /// finished.
+    #[display(fmt = "the download task exited early")]
+    TaskExited,
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Self::IOError(Arc::new(err))
@@ -123,6 +211,69 @@ impl Error {
     pub(crate) fn from_netdoc(source: DocSource, cause: tor_netdoc::Error) -> Error {
         Error::NetDocError { source, cause }
     }
+
+    /// Return true if this error is one that might go away if we try the same
+    /// operation again later, or with a different directory cache.
+    ///
+    /// Errors from the network, and from documents that a directory server
+    /// sent us, are retryable.  Errors from our own storage, our
+    /// configuration, or our code are not: trying again would give the same
+    /// result.
+    ///
+    /// (For a finer-grained classification, see [`HasKind::kind`].)
+    pub fn retryable(&self) -> bool {
+        use Error as E;
+        match self {
+            E::Unwanted(_)
+            | E::DirectoryNotPresent
+            | E::NoCachedConsensus
+            | E::UnrecognizedAuthorities
+            | E::NotEnoughSignatures { .. }
+            | E::CantAdvanceState(_)
+            | E::ConsensusDiffError(_)
+            | E::BadUtf8FromDirectory(_)
+            | E::ContentDecoding { .. }
+            | E::EmptyResponse
+            | E::DirClientError(_)
+            | E::SignatureError(_) => true,
+
+            E::NetDocError { source, .. } => match source {
+                DocSource::LocalCache | DocSource::Embedded => false,
+                DocSource::DirServer { .. } => true,
+            },
+
+            E::NoDownloadSupport
+            | E::CacheCorruption(_)
+            | E::SqliteError(_)
+            | E::UnrecognizedSchema
+            | E::BadNetworkConfig(_)
+            | E::ManagerDropped
+            | E::ConsensusTooOld { .. }
+            | E::MissingRequiredProtocols { .. }
+            | E::StorageError(_)
+            | E::BadUtf8InCache(_)
+            | E::BadUtf8InEmbedded(_)
+            | E::BadHexInCache(_)
+            | E::IOError(_)
+            | E::OfflineMode
+            | E::Spawn { .. }
+            | E::Bug(_) => false,
+        }
+    }
+
+    /// Return true if we should keep trying to replace a directory that we
+    /// already have, after encountering this error.
+    ///
+    /// This is the same as [`Error::retryable`], except for problems with a
+    /// consensus that make us give up on bootstrapping: while we have a
+    /// directory to use in the meantime, we can wait for a better one.
+    pub(crate) fn retryable_with_directory(&self) -> bool {
+        use Error as E;
+        match self {
+            E::ConsensusTooOld { .. } | E::MissingRequiredProtocols { .. } => true,
+            _ => self.retryable(),
+        }
+    }
 }
 
 impl From<rusqlite::Error> for Error {
@@ -152,16 +303,24 @@ impl HasKind for Error {
             E::BadNetworkConfig(_) => EK::InvalidConfig,
             E::DirectoryNotPresent => EK::DirectoryExpired,
             E::BadUtf8FromDirectory(_) => EK::TorProtocolViolation,
+            E::ContentDecoding { .. } => EK::TorProtocolViolation,
+            E::EmptyResponse => EK::TorProtocolViolation,
             E::BadUtf8InCache(_) => EK::CacheCorrupted,
+            E::BadUtf8InEmbedded(_) => EK::BadApiUsage,
             E::BadHexInCache(_) => EK::CacheCorrupted,
             E::UnrecognizedAuthorities => EK::TorProtocolViolation,
+            E::NotEnoughSignatures { .. } => EK::TorProtocolViolation,
+            E::ConsensusTooOld { .. } => EK::DirectoryExpired,
+            E::MissingRequiredProtocols { .. } => EK::NotImplemented,
+            E::NoCachedConsensus => EK::DirectoryExpired,
             E::ManagerDropped => EK::ArtiShuttingDown,
-            E::CantAdvanceState => EK::TorAccessFailed,
+            E::CantAdvanceState(_) => EK::TorAccessFailed,
             E::StorageError(_) => EK::CacheAccessFailed,
             E::ConsensusDiffError(_) => EK::TorProtocolViolation,
             E::NetDocError { source, .. } => match source {
                 DocSource::LocalCache => EK::CacheCorrupted,
                 DocSource::DirServer { .. } => EK::TorProtocolViolation,
+                DocSource::Embedded => EK::BadApiUsage,
             },
             E::DirClientError(e) => e.kind(),
             E::SignatureError(_) => EK::TorProtocolViolation,
@@ -213,3 +372,83 @@ fn sqlite_error_kind(e: &rusqlite::Error) -> ErrorKind {
         _ => EK::Internal,
     }
 }
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    /// Return a netdoc error to use in testing.
+    fn netdoc_err() -> tor_netdoc::Error {
+        tor_netdoc::doc::microdesc::Microdesc::parse("not a microdescriptor").unwrap_err()
+    }
+
+    #[test]
+    fn retryable() {
+        // Problems with what a directory server sent us.
+        assert!(Error::Unwanted("a cat").retryable());
+        assert!(Error::UnrecognizedAuthorities.retryable());
+        assert!(Error::NotEnoughSignatures { have: 1, need: 5 }.retryable());
+        assert!(Error::CantAdvanceState(CantAdvanceReason::NoUsableResponses).retryable());
+        let utf8_err = String::from_utf8(vec![0xff]).unwrap_err();
+        assert!(Error::BadUtf8FromDirectory(utf8_err).retryable());
+        assert!(Error::EmptyResponse.retryable());
+        let io_err = std::io::Error::new(std::io::ErrorKind::InvalidData, "not backwards");
+        assert!(Error::ContentDecoding {
+            encoding: "x-backwards".into(),
+            cause: Arc::new(io_err)
+        }
+        .retryable());
+        assert!(Error::from_netdoc(DocSource::DirServer {}, netdoc_err()).retryable());
+        assert!(Error::from(signature::Error::new()).retryable());
+
+        // Problems with our own storage.
+        assert!(!Error::StorageError("disk on fire".into()).retryable());
+        assert!(!Error::CacheCorruption("bad cache").retryable());
+        assert!(!Error::UnrecognizedSchema.retryable());
+        assert!(!Error::from_netdoc(DocSource::LocalCache, netdoc_err()).retryable());
+        assert!(Error::NoCachedConsensus.retryable());
+
+        // Problems with documents embedded in the program.
+        assert!(!Error::from_netdoc(DocSource::Embedded, netdoc_err()).retryable());
+        let utf8_err = std::str::from_utf8(&[0xff]).unwrap_err();
+        assert!(!Error::BadUtf8InEmbedded(utf8_err).retryable());
+        let io_err = std::io::Error::new(std::io::ErrorKind::Other, "oops");
+        assert!(!Error::from(io_err).retryable());
+
+        // Problems with our configuration or our code.
+        assert!(!Error::BadNetworkConfig("no authorities").retryable());
+        assert!(!Error::NoDownloadSupport.retryable());
+        assert!(!Error::OfflineMode.retryable());
+        assert!(!Error::ManagerDropped.retryable());
+        let day = Duration::from_secs(86400);
+        assert!(!Error::ConsensusTooOld {
+            age: day * 2,
+            max_age: day
+        }
+        .retryable());
+        assert!(!Error::from(tor_error::internal!("whoops")).retryable());
+    }
+
+    #[test]
+    fn retryable_with_directory() {
+        // Once we have a directory, a bad consensus doesn't stop us from
+        // waiting for a better one...
+        let day = Duration::from_secs(86400);
+        assert!(Error::ConsensusTooOld {
+            age: day * 2,
+            max_age: day
+        }
+        .retryable_with_directory());
+        let missing: tor_protover::Protocols = "Link=9".parse().unwrap();
+        assert!(!Error::MissingRequiredProtocols {
+            missing: missing.clone()
+        }
+        .retryable());
+        assert!(Error::MissingRequiredProtocols { missing }.retryable_with_directory());
+        // ... but everything else is the same.
+        assert!(Error::EmptyResponse.retryable_with_directory());
+        assert!(!Error::CacheCorruption("bad cache").retryable_with_directory());
+        assert!(!Error::ManagerDropped.retryable_with_directory());
+    }
+}
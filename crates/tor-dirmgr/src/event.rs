@@ -38,6 +38,83 @@ pub enum DirEvent {
     /// (This event is _not_ broadcast when receiving new descriptors for a
     /// consensus which is not yet ready to replace the current consensus.)
     NewDescriptors,
+
+    /// The download process has thrown away its progress and started over
+    /// from the beginning, by looking for a new consensus.
+    ///
+    /// Frequent resets for reasons other than
+    /// [`DirResetReason::Scheduled`] can indicate trouble reaching the
+    /// network.
+    Reset(DirResetReason),
+
+    /// We have validated a consensus that requires subprotocol versions
+    /// that we don't implement.
+    ///
+    /// The network is telling us that this version of Arti is too old to
+    /// use it safely: the user should upgrade.  Unless
+    /// [`DirMgrConfigBuilder::enforce_required_protocols`](crate::DirMgrConfigBuilder::enforce_required_protocols)
+    /// is set, we keep using the consensus anyway.
+    MissingRequiredProtocols,
+}
+
+/// The reason why a DirMgr reset its download process.
+///
+/// See [`DirEvent::Reset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DirResetReason {
+    /// We had a complete directory, and it was time to replace it.
+    Scheduled,
+    /// We were still downloading information for a consensus when it was
+    /// time to replace it.
+    ConsensusReplacement,
+    /// We failed to download the information we needed, and are trying again.
+    DownloadFailed,
+}
+
+/// A change from one bootstrapping state to another, as reported to an
+/// function set with
+/// [`DirObserver::on_transition`](crate::DirObserver::on_transition).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateTransition {
+    /// What kind of change this was.
+    kind: TransitionKind,
+    /// The `describe()` output of the state we left.
+    from: String,
+    /// The `describe()` output of the state we entered.
+    to: String,
+}
+
+/// The kind of a [`StateTransition`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TransitionKind {
+    /// The state had all it needed, and moved on to the next one.
+    Advance,
+    /// The state threw away its progress and started over.
+    Reset,
+}
+
+impl StateTransition {
+    /// Construct a new StateTransition.
+    pub(crate) fn new(kind: TransitionKind, from: String, to: String) -> Self {
+        StateTransition { kind, from, to }
+    }
+
+    /// Return what kind of change this was.
+    pub fn kind(&self) -> TransitionKind {
+        self.kind
+    }
+
+    /// Return a human-readable description of the state we left.
+    pub fn old_state(&self) -> &str {
+        &self.from
+    }
+
+    /// Return a human-readable description of the state we entered.
+    pub fn new_state(&self) -> &str {
+        &self.to
+    }
 }
 
 /// A trait to indicate something that can be published with [`FlagPublisher`].
@@ -62,17 +139,27 @@ pub(crate) trait FlagEvent: Sized {
 }
 
 impl FlagEvent for DirEvent {
-    const MAXIMUM: u16 = 1;
+    const MAXIMUM: u16 = 5;
     fn to_index(self) -> u16 {
+        use DirResetReason as R;
         match self {
             DirEvent::NewConsensus => 0,
             DirEvent::NewDescriptors => 1,
+            DirEvent::Reset(R::Scheduled) => 2,
+            DirEvent::Reset(R::ConsensusReplacement) => 3,
+            DirEvent::Reset(R::DownloadFailed) => 4,
+            DirEvent::MissingRequiredProtocols => 5,
         }
     }
     fn from_index(flag: u16) -> Option<Self> {
+        use DirResetReason as R;
         match flag {
             0 => Some(DirEvent::NewConsensus),
             1 => Some(DirEvent::NewDescriptors),
+            2 => Some(DirEvent::Reset(R::Scheduled)),
+            3 => Some(DirEvent::Reset(R::ConsensusReplacement)),
+            4 => Some(DirEvent::Reset(R::DownloadFailed)),
+            5 => Some(DirEvent::MissingRequiredProtocols),
             _ => None,
         }
     }
@@ -250,6 +337,34 @@ pub struct DirBootstrapStatus {
     ///
     /// This is "None" if we haven't started fetching the next consensus yet.
     pub(crate) next: Option<DirStatus>,
+    /// The phase of the download that we're working on.
+    pub(crate) phase: BootstrapPhase,
+}
+
+/// Which part of the directory the download process is working on.
+///
+/// Unlike the [`Display`](fmt::Display) output of a [`DirStatus`], this is
+/// meant to be machine-readable: a user interface can match on it to show
+/// its own (possibly localized) description of our progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum BootstrapPhase {
+    /// We're looking for a consensus.
+    GettingConsensus,
+    /// We have a consensus, and we're fetching the authority certificates
+    /// that we need to validate it.
+    GettingCerts,
+    /// We have a validated consensus, and we're fetching the
+    /// microdescriptors that it lists.
+    GettingMicrodescs,
+    /// We have everything we want for our current consensus.
+    Done,
+}
+
+impl Default for BootstrapPhase {
+    fn default() -> Self {
+        BootstrapPhase::GettingConsensus
+    }
 }
 
 /// The status for a single directory.
@@ -381,6 +496,52 @@ impl DirBootstrapStatus {
         self.current.usable() && self.current.valid_at(now)
     }
 
+    /// Return the phase of the download that we're working on.
+    ///
+    /// Once we're [`Done`](BootstrapPhase::Done), this goes back to
+    /// [`GettingConsensus`](BootstrapPhase::GettingConsensus) when we start
+    /// fetching a replacement directory.
+    pub fn phase(&self) -> BootstrapPhase {
+        self.phase
+    }
+
+    /// Return a description of this status in the format that C Tor uses
+    /// for bootstrap events on its control port, as of `now`.
+    ///
+    /// The result looks like `BOOTSTRAP PROGRESS=50 TAG=loading_descriptors
+    /// SUMMARY="Loading relay descriptors"`, and uses C Tor's progress
+    /// numbers and tags for the parts of bootstrapping that concern the
+    /// directory.  Since we only know about the directory here, we never
+    /// report more than C Tor's `enough_dirinfo` (75%).
+    pub fn to_control_status_line(&self, now: SystemTime) -> String {
+        let (progress, tag, summary) = if self.usable_at(now) {
+            (
+                75,
+                "enough_dirinfo",
+                "Loaded enough directory info to build circuits",
+            )
+        } else {
+            match self.phase {
+                BootstrapPhase::GettingConsensus | BootstrapPhase::Done => (
+                    25,
+                    "requesting_status",
+                    "Asking for networkstatus consensus",
+                ),
+                BootstrapPhase::GettingCerts => (40, "loading_keys", "Loading authority key certs"),
+                BootstrapPhase::GettingMicrodescs => {
+                    // C Tor moves from 50% to 75% as it gets descriptors.
+                    let fetching = self.next.as_ref().unwrap_or(&self.current);
+                    let progress = 50 + (fetching.md_frac() * 25.0) as u8;
+                    (progress, "loading_descriptors", "Loading relay descriptors")
+                }
+            }
+        };
+        format!(
+            "BOOTSTRAP PROGRESS={} TAG={} SUMMARY=\"{}\"",
+            progress, tag, summary
+        )
+    }
+
     /// Update this status by replacing its current status (or its next status)
     /// with `new_status`, as appropriate.
     pub(crate) fn update(&mut self, new_status: DirStatus) {
@@ -410,7 +571,7 @@ impl DirBootstrapStatus {
 
 impl DirStatus {
     /// Return the consensus lifetime for this directory, if we have one.
-    fn lifetime(&self) -> Option<&netstatus::Lifetime> {
+    pub(crate) fn lifetime(&self) -> Option<&netstatus::Lifetime> {
         match &self.0 {
             DirStatusInner::NoConsensus { .. } => None,
             DirStatusInner::FetchingCerts { lifetime, .. } => Some(lifetime),
@@ -468,6 +629,17 @@ impl DirStatus {
         }
     }
 
+    /// Return the fraction of the microdescriptors for this directory that
+    /// we have, or 0 if we aren't fetching microdescriptors yet.
+    fn md_frac(&self) -> f32 {
+        match &self.0 {
+            DirStatusInner::Validated { n_mds, .. } if n_mds.1 > 0 => {
+                (n_mds.0 as f32) / (n_mds.1 as f32)
+            }
+            _ => 0.0,
+        }
+    }
+
     /// Return true if the consensus in this DirStatus (if any) is at least as
     /// new as the one in `other`.
     fn at_least_as_new_as(&self, other: &DirStatus) -> bool {
@@ -655,6 +827,15 @@ mod test {
         assert_eq!(DirEvent::from_index(999), None);
     }
 
+    #[test]
+    fn index_roundtrip() {
+        for idx in 0..=DirEvent::MAXIMUM {
+            let ev = DirEvent::from_index(idx).unwrap();
+            assert_eq!(ev.to_index(), idx);
+        }
+        assert_eq!(DirEvent::from_index(DirEvent::MAXIMUM + 1), None);
+    }
+
     #[test]
     fn dir_status_basics() {
         let now = SystemTime::now();
@@ -760,6 +941,7 @@ mod test {
         let bs = DirBootstrapStatus {
             current: ds1.clone(),
             next: Some(ds2.clone()),
+            phase: BootstrapPhase::GettingMicrodescs,
         };
 
         assert_eq!(bs.to_string(),
@@ -820,4 +1002,54 @@ mod test {
         bs.update(ds2);
         assert!(bs.current.lifetime().is_some());
     }
+
+    #[test]
+    fn control_status_line() {
+        use time::macros::datetime;
+        let t1: SystemTime = datetime!(2022-01-17 11:00:00 UTC).into();
+        let hour = Duration::new(3600, 0);
+        let lifetime = netstatus::Lifetime::new(t1, t1 + hour, t1 + hour * 3).unwrap();
+        let now = t1 + hour / 2;
+
+        let bs = DirBootstrapStatus::default();
+        assert_eq!(
+            bs.to_control_status_line(now),
+            "BOOTSTRAP PROGRESS=25 TAG=requesting_status SUMMARY=\"Asking for networkstatus consensus\""
+        );
+
+        // Halfway through the microdescriptors, we're halfway from 50% to 75%.
+        let bs = DirBootstrapStatus {
+            current: DirStatusInner::Validated {
+                lifetime: lifetime.clone(),
+                n_mds: (20, 40),
+                usable: false,
+            }
+            .into(),
+            next: None,
+            phase: BootstrapPhase::GettingMicrodescs,
+        };
+        assert_eq!(
+            bs.to_control_status_line(now),
+            "BOOTSTRAP PROGRESS=62 TAG=loading_descriptors SUMMARY=\"Loading relay descriptors\""
+        );
+
+        let bs = DirBootstrapStatus {
+            current: DirStatusInner::Validated {
+                lifetime,
+                n_mds: (40, 40),
+                usable: true,
+            }
+            .into(),
+            next: None,
+            phase: BootstrapPhase::Done,
+        };
+        assert_eq!(
+            bs.to_control_status_line(now),
+            "BOOTSTRAP PROGRESS=75 TAG=enough_dirinfo SUMMARY=\"Loaded enough directory info to build circuits\""
+        );
+        // Once that directory has expired, we're looking for a new one.
+        assert!(bs
+            .to_control_status_line(t1 + hour * 4)
+            .contains("TAG=requesting_status"));
+    }
 }
@@ -0,0 +1,171 @@
+//! Code to write out our directory in the formats that Tor uses for its own
+//! cached directory files.
+
+use crate::docmeta::ConsensusMeta;
+use crate::storage::Store;
+use crate::{DocSource, Error, Result};
+
+use tor_checkable::{ExternallySigned, Timebound};
+use tor_netdoc::doc::netstatus::MdConsensus;
+
+use std::path::Path;
+
+/// The name that Tor uses for its cached microdescriptor consensus.
+const CONSENSUS_FILENAME: &str = "cached-microdesc-consensus";
+
+/// The name that Tor uses for its cached microdescriptors.
+const MICRODESCS_FILENAME: &str = "cached-microdescs";
+
+/// A consensus and the microdescriptors that it lists, in the formats that
+/// Tor uses for its cached directory files.
+///
+/// Returned by [`DirMgr::export_netdir`](crate::DirMgr::export_netdir).
+#[derive(Clone, Debug)]
+pub struct ExportedNetDir {
+    /// The full text of the consensus, including its signatures.
+    consensus: String,
+    /// The text of every microdescriptor that we have for a relay listed in
+    /// the consensus, one after another, in the order that the consensus
+    /// lists them.
+    microdescs: String,
+}
+
+impl ExportedNetDir {
+    /// Return the full text of the consensus.
+    pub fn consensus(&self) -> &str {
+        &self.consensus
+    }
+
+    /// Return the text of the microdescriptors, concatenated in the order
+    /// that the consensus lists them.
+    pub fn microdescs(&self) -> &str {
+        &self.microdescs
+    }
+
+    /// Write this directory into `dir`, using the filenames that Tor uses
+    /// for its own cache.
+    ///
+    /// Any existing files with those names are replaced.
+    pub fn write_to_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        std::fs::write(dir.join(CONSENSUS_FILENAME), &self.consensus)?;
+        std::fs::write(dir.join(MICRODESCS_FILENAME), &self.microdescs)?;
+        Ok(())
+    }
+}
+
+/// Load the consensus described by `meta` from `store`, along with every
+/// microdescriptor that `store` has for the relays it lists.
+///
+/// Relays whose microdescriptors are missing from the store are skipped.
+pub(crate) fn export_from_store(store: &dyn Store, meta: &ConsensusMeta) -> Result<ExportedNetDir> {
+    let text = store.consensus_by_meta(meta)?;
+    let consensus = text.as_str()?.to_owned();
+
+    // We checked this consensus when we first accepted it, and we only want
+    // to know which microdescriptors it lists.
+    let (_, _, parsed) =
+        MdConsensus::parse(&consensus).map_err(|e| Error::from_netdoc(DocSource::LocalCache, e))?;
+    let parsed = parsed
+        .dangerously_assume_timely()
+        .dangerously_assume_wellsigned();
+    let digests: Vec<_> = parsed.relays().iter().map(|rs| *rs.md_digest()).collect();
+
+    let mut found = store.microdescs(&digests)?;
+    let mut microdescs = String::new();
+    for d in &digests {
+        if let Some(md) = found.remove(d) {
+            microdescs.push_str(&md);
+        }
+    }
+
+    Ok(ExportedNetDir {
+        consensus,
+        microdescs,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::storage::SqliteStore;
+    use std::time::SystemTime;
+    use tempfile::TempDir;
+    use tor_netdoc::doc::microdesc::MicrodescReader;
+    use tor_netdoc::doc::netstatus::ConsensusFlavor;
+    use tor_netdoc::AllowAnnotations;
+
+    const CONSENSUS: &str = include_str!("../testdata/mdconsensus2.txt");
+    const MICRODESCS: &str = include_str!("../testdata/microdescs.txt");
+
+    #[test]
+    fn round_trip() {
+        let tempdir = TempDir::new().unwrap();
+        let mut store = SqliteStore::from_path(tempdir.path(), false).unwrap();
+
+        let (signed, rest, parsed) = MdConsensus::parse(CONSENSUS).unwrap();
+        let parsed = parsed
+            .dangerously_assume_timely()
+            .dangerously_assume_wellsigned();
+        let meta = ConsensusMeta::from_consensus(signed, rest, &parsed);
+        store
+            .store_consensus(&meta, ConsensusFlavor::Microdesc, false, CONSENSUS)
+            .unwrap();
+
+        // Store all but one of the microdescriptors that the consensus lists.
+        let mds: Vec<_> =
+            MicrodescReader::new(MICRODESCS, &AllowAnnotations::AnnotationsNotAllowed)
+                .map(|res| {
+                    let anno = res.unwrap();
+                    let text = anno.within(MICRODESCS).unwrap().to_owned();
+                    (text, *anno.into_microdesc().digest())
+                })
+                .collect();
+        assert_eq!(mds.len(), 4);
+        let stored: Vec<_> = mds[1..].iter().map(|(t, d)| (t.as_str(), d)).collect();
+        store.store_microdescs(&stored, SystemTime::now()).unwrap();
+
+        let exported = export_from_store(&store, &meta).unwrap();
+
+        // The consensus comes back exactly as it was.
+        assert_eq!(exported.consensus(), CONSENSUS);
+        let (signed2, rest2, _) = MdConsensus::parse(exported.consensus()).unwrap();
+        assert_eq!(signed2, signed);
+        assert_eq!(rest2, rest);
+
+        // So do the microdescriptors we had, in consensus order.
+        let consensus_order: Vec<_> = parsed.relays().iter().map(|rs| *rs.md_digest()).collect();
+        let reparsed: Vec<_> = MicrodescReader::new(
+            exported.microdescs(),
+            &AllowAnnotations::AnnotationsNotAllowed,
+        )
+        .map(|res| {
+            let anno = res.unwrap();
+            let text = anno.within(exported.microdescs()).unwrap().to_owned();
+            (text, *anno.into_microdesc().digest())
+        })
+        .collect();
+        assert_eq!(reparsed.len(), 3);
+        for (text, digest) in &reparsed {
+            assert!(mds[1..].contains(&(text.clone(), *digest)));
+        }
+        let positions: Vec<_> = reparsed
+            .iter()
+            .map(|(_, d)| consensus_order.iter().position(|c| c == d).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+
+        // And we can write them out as files.
+        let outdir = TempDir::new().unwrap();
+        exported.write_to_dir(outdir.path()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(outdir.path().join(CONSENSUS_FILENAME)).unwrap(),
+            CONSENSUS
+        );
+        assert_eq!(
+            std::fs::read_to_string(outdir.path().join(MICRODESCS_FILENAME)).unwrap(),
+            exported.microdescs()
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Keep track of how quickly our directory caches answer us.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdir::fallback::FallbackDir;
+
+/// How much weight we give each new latency sample, compared to everything
+/// we had seen before it.
+const SAMPLE_WEIGHT: f64 = 0.25;
+
+/// An exponentially weighted moving average of how long each directory
+/// cache has taken to answer our requests, keyed by the cache's RSA
+/// identity.
+///
+/// We only learn about a cache when we know which one we asked: that is,
+/// when we ask a single fallback directory because we have no directory
+/// yet.  We keep what we learn for as long as the `DirMgr` lives, so it
+/// carries over from one bootstrap attempt to the next, and we save it in
+/// our store so that it carries over when we restart.
+#[derive(Debug, Default)]
+pub(crate) struct CacheLatencies {
+    /// The current estimate for each cache that we've heard from.
+    estimates: Mutex<HashMap<RsaIdentity, Duration>>,
+}
+
+impl CacheLatencies {
+    /// Construct a new `CacheLatencies` that starts out with `estimates`,
+    /// as saved in our store.
+    pub(crate) fn from_estimates(estimates: HashMap<RsaIdentity, Duration>) -> Self {
+        CacheLatencies {
+            estimates: Mutex::new(estimates),
+        }
+    }
+
+    /// Return a copy of every estimate we have, so that we can save them.
+    pub(crate) fn estimates(&self) -> HashMap<RsaIdentity, Duration> {
+        self.estimates.lock().expect("Poisoned lock").clone()
+    }
+
+    /// Note that the cache with identity `id` took `rtt` to answer a
+    /// request.
+    pub(crate) fn note(&self, id: &RsaIdentity, rtt: Duration) {
+        let mut estimates = self.estimates.lock().expect("Poisoned lock");
+        let estimate = match estimates.get(id) {
+            Some(old) => {
+                let old = old.as_secs_f64();
+                Duration::from_secs_f64(old + SAMPLE_WEIGHT * (rtt.as_secs_f64() - old))
+            }
+            None => rtt,
+        };
+        estimates.insert(*id, estimate);
+    }
+
+    /// Return our current estimate of how long the cache with identity `id`
+    /// takes to answer, if we've heard from it.
+    pub(crate) fn estimate(&self, id: &RsaIdentity) -> Option<Duration> {
+        self.estimates
+            .lock()
+            .expect("Poisoned lock")
+            .get(id)
+            .copied()
+    }
+
+    /// Return the index of the cache in `caches` that we'd rather ask than
+    /// `caches[current]`, if there is one.
+    ///
+    /// That's the fastest cache we've heard from, if it's faster than the
+    /// current one.  If we haven't heard from the current one yet, we stick
+    /// with it, so that we find out how fast it is.
+    pub(crate) fn faster_than(&self, caches: &[FallbackDir], current: usize) -> Option<usize> {
+        let estimates = self.estimates.lock().expect("Poisoned lock");
+        let current = estimates.get(caches.get(current)?.rsa_identity())?;
+        caches
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, c)| estimates.get(c.rsa_identity()).map(|e| (idx, e)))
+            .filter(|(_, e)| *e < current)
+            .min_by_key(|(_, e)| *e)
+            .map(|(idx, _)| idx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::testing::fallback as cache;
+
+    #[test]
+    fn smoothing() {
+        let latencies = CacheLatencies::default();
+        let id: RsaIdentity = [1; 20].into();
+        assert_eq!(latencies.estimate(&id), None);
+
+        // The first sample is all we know.
+        latencies.note(&id, Duration::from_secs(4));
+        assert_eq!(latencies.estimate(&id), Some(Duration::from_secs(4)));
+
+        // Later ones move the estimate part of the way.
+        latencies.note(&id, Duration::from_secs(8));
+        assert_eq!(latencies.estimate(&id), Some(Duration::from_secs(5)));
+        latencies.note(&id, Duration::from_secs(1));
+        assert_eq!(latencies.estimate(&id), Some(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn prefer_faster() {
+        let latencies = CacheLatencies::default();
+        let caches = vec![cache(1), cache(2), cache(3)];
+
+        // Nothing known: stay put.
+        assert_eq!(latencies.faster_than(&caches, 0), None);
+
+        latencies.note(caches[1].rsa_identity(), Duration::from_secs(2));
+        latencies.note(caches[2].rsa_identity(), Duration::from_secs(1));
+        // We haven't tried cache 0 yet, so we try it before moving.
+        assert_eq!(latencies.faster_than(&caches, 0), None);
+        // Cache 2 is faster than cache 1...
+        assert_eq!(latencies.faster_than(&caches, 1), Some(2));
+        // ... and nothing is faster than cache 2.
+        assert_eq!(latencies.faster_than(&caches, 2), None);
+
+        // Once cache 0 turns out to be slow, we move to the fastest.
+        latencies.note(caches[0].rsa_identity(), Duration::from_secs(10));
+        assert_eq!(latencies.faster_than(&caches, 0), Some(2));
+    }
+}
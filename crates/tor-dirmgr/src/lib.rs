@@ -23,6 +23,9 @@
 //!
 //! `routerdesc` -- (Incomplete) support for downloading and storing
 //!      router descriptors.
+//!
+//! `votes` -- (Incomplete) support for downloading and storing the
+//!      directory authorities' votes.
 
 #![deny(missing_docs)]
 #![warn(noop_method_call)]
@@ -57,43 +60,79 @@
 pub mod authority;
 mod bootstrap;
 mod config;
+mod diff;
 mod docid;
 mod docmeta;
+mod embedded;
 mod err;
 mod event;
+mod export;
+mod latency;
+mod mirror;
+mod observer;
+mod penalty;
 mod retry;
 mod shared_ref;
 mod state;
 mod storage;
+#[cfg(test)]
+mod testing;
+mod throttle;
+mod verify;
 
 use crate::docid::{CacheUsage, ClientRequest, DocQuery};
 use crate::shared_ref::SharedMutArc;
-use crate::storage::DynStore;
+use crate::storage::{DynStore, Store};
 use postage::watch;
 pub use retry::DownloadSchedule;
+use tor_checkable::{SelfSigned, Timebound};
 use tor_circmgr::CircMgr;
+use tor_error::{bad_api_usage, internal};
+use tor_linkspec::ChanTarget;
+use tor_llcrypto::pk::rsa::RsaIdentity;
 use tor_netdir::NetDir;
-use tor_netdoc::doc::netstatus::ConsensusFlavor;
-
-use futures::{channel::oneshot, task::SpawnExt};
-use tor_rtcompat::{Runtime, SleepProviderExt};
+use tor_netdoc::doc::microdesc::{MdDigest, Microdesc};
+use tor_netdoc::doc::netstatus::{ConsensusFlavor, Lifetime, MdConsensus};
+
+use digest::Digest;
+use futures::{
+    channel::{mpsc, oneshot},
+    task::SpawnExt,
+    FutureExt, StreamExt,
+};
+use rand::Rng;
+use tor_rtcompat::{Runtime, SleepProviderExt, TimeoutError};
 use tracing::{debug, info, trace, warn};
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use std::{collections::HashMap, sync::Weak};
-use std::{fmt::Debug, time::SystemTime};
 
 pub use authority::{Authority, AuthorityBuilder};
+pub use bootstrap::CacheProbe;
 pub use config::{
     DirMgrConfig, DirMgrConfigBuilder, DownloadScheduleConfig, DownloadScheduleConfigBuilder,
     NetworkConfig, NetworkConfigBuilder,
 };
-pub use docid::DocId;
-pub use err::Error;
-pub use event::{DirBootstrapEvents, DirBootstrapStatus, DirEvent, DirStatus};
-pub use storage::DocumentText;
+pub use diff::NetDirDiff;
+pub use docid::{DocId, MissingSummary};
+pub use docmeta::{AuthCertMeta, ConsensusMeta};
+pub use embedded::EmbeddedDirectory;
+pub use err::{CantAdvanceReason, Error};
+pub use event::{
+    BootstrapPhase, DirBootstrapEvents, DirBootstrapStatus, DirEvent, DirResetReason, DirStatus,
+    StateTransition, TransitionKind,
+};
+pub use export::ExportedNetDir;
+pub use mirror::{DirMirror, DirMirrorBuilder};
+pub use observer::DirObserver;
+pub use storage::{DocumentText, ExpirationConfig, InputString, Store};
 pub use tor_netdir::fallback::{FallbackDir, FallbackDirBuilder};
+pub use verify::VerifyReport;
 
 /// A Result as returned by this crate.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -126,6 +165,10 @@ pub struct DirMgr<R: Runtime> {
     /// users, and replace it once a new directory is bootstrapped.
     netdir: SharedMutArc<NetDir>,
 
+    /// Our latest validated consensus, if we're configured to stop once we
+    /// have one and never build a directory.
+    consensus: SharedMutArc<MdConsensus>,
+
     /// A publisher handle that we notify whenever the consensus changes.
     events: event::FlagPublisher<DirEvent>,
 
@@ -156,8 +199,114 @@ pub struct DirMgr<R: Runtime> {
     ///
     /// (In offline mode, this does nothing.)
     bootstrap_started: AtomicBool,
+
+    /// A known error in our runtime's wall clock, if somebody has told us
+    /// about one.
+    ///
+    /// We use this to correct the current time whenever we decide whether a
+    /// directory document is timely.
+    clock_skew: Mutex<Option<ClockSkew>>,
+
+    /// A summary of the documents that our current bootstrapping state is
+    /// missing.
+    missing: Mutex<MissingSummary>,
+
+    /// A limit on how fast we take in downloaded documents.
+    download_throttle: throttle::Throttle,
+
+    /// The functions to call as we download and use directory
+    /// information, if somebody has asked us to report our progress.
+    observer: Mutex<Arc<DirObserver>>,
+
+    /// A function to decide which directory caches we may ask for
+    /// documents, if somebody has asked us to restrict them.
+    cache_filter: Mutex<Option<CacheFilter>>,
+
+    /// A function to inspect and rewrite each directory request just
+    /// before we send it, if somebody has asked to.
+    request_hook: Mutex<Option<RequestHook>>,
+
+    /// A publisher handle that we use to tell our download task whether
+    /// somebody has asked us to pause our periodic refreshes.
+    send_refresh_paused: Mutex<watch::Sender<bool>>,
+
+    /// A receiver handle that the download task watches to learn whether
+    /// our periodic refreshes are paused.
+    receive_refresh_paused: watch::Receiver<bool>,
+
+    /// Microdescriptors that somebody has invalidated, and that our download
+    /// task hasn't yet marked as missing.
+    invalidated_microdescs: Mutex<Vec<MdDigest>>,
+
+    /// A publisher handle that we use to wake our download task when we add
+    /// to `invalidated_microdescs`.
+    ///
+    /// The value is the number of microdescriptors invalidated so far.
+    send_invalidated: Mutex<watch::Sender<usize>>,
+
+    /// A receiver handle that the download task watches to learn when it
+    /// should fetch some invalidated microdescriptors.
+    receive_invalidated: watch::Receiver<usize>,
+
+    /// The time when our download task plans to start its next refresh, if
+    /// it is waiting to start one.
+    next_refresh: Mutex<Option<SystemTime>>,
+
+    /// The position in our configured list of fallback directories of the
+    /// one that we should ask for documents while we have no directory.
+    ///
+    /// This is reduced modulo the length of the list before it's used. We
+    /// start at a random position, so that different clients don't all try
+    /// the same fallback first.
+    next_fallback: AtomicUsize,
+
+    /// How quickly each of the directory caches we've asked has answered.
+    cache_latency: latency::CacheLatencies,
+
+    /// The errors we've had recently from each directory cache, by its
+    /// identity.
+    cache_errors: penalty::CacheErrors<RsaIdentity>,
+
+    /// Senders for each stream that somebody has asked for with
+    /// [`DirMgr::microdescs`], to tell about each microdescriptor that we
+    /// validate.
+    microdesc_senders: Mutex<Vec<mpsc::UnboundedSender<Microdesc>>>,
+
+    /// True if we've already made (or waited to make) our first directory
+    /// request from the network.
+    startup_jitter_done: AtomicBool,
+
+    /// True if we've already warned that a consensus requires protocols
+    /// that we don't implement.
+    warned_missing_protocols: AtomicBool,
+
+    /// Functions to undo the content-encodings that somebody has taught us
+    /// about with [`DirMgr::register_codec`], by name.
+    codecs: Mutex<HashMap<String, Codec>>,
+
+    /// Testing helper: if this is Some, then we return it in place of any
+    /// response to a download request.
+    #[cfg(test)]
+    canned_response: Mutex<Option<bootstrap::CannedResponse>>,
+
+    /// Testing helper: if this is Some, then it decides how we answer each
+    /// download request, ahead of `canned_response`.
+    #[cfg(test)]
+    fault_script: Mutex<Option<bootstrap::FaultScript>>,
 }
 
+/// A callback to decide whether a [`DirMgr`] may ask a given directory cache
+/// for documents.
+type CacheFilter = Arc<dyn Fn(&dyn ChanTarget) -> bool + Send + Sync>;
+
+/// A callback to rewrite the HTTP requests that a [`DirMgr`] sends to
+/// directory caches.
+type RequestHook = Arc<dyn Fn(&mut http::Request<()>) + Send + Sync>;
+
+/// A function to undo a content-encoding that a [`DirMgr`] doesn't support
+/// by itself.
+type Codec = Arc<dyn Fn(&[u8]) -> std::io::Result<Vec<u8>> + Send + Sync>;
+
 /// RAII guard to reset an AtomicBool on drop.
 struct BoolResetter<'a> {
     /// The bool to reset.
@@ -197,6 +346,132 @@ pub enum DocSource {
     // it's available from tor_dirclient::DirSource,
     #[display(fmt = "directory server")]
     DirServer {},
+    /// We got the document from an [`EmbeddedDirectory`].
+    #[display(fmt = "embedded directory")]
+    Embedded,
+}
+
+/// Return the directory caches that `filter` allows.
+///
+/// We consider the caches listed in `netdir` if it's present, and the ones in
+/// `fallbacks` otherwise.
+fn dir_caches(
+    netdir: Option<&NetDir>,
+    fallbacks: &[FallbackDir],
+    filter: &(dyn Fn(&dyn ChanTarget) -> bool + Send + Sync),
+) -> Vec<FallbackDir> {
+    match netdir {
+        Some(netdir) => netdir
+            .relays()
+            .filter(|r| r.is_dir_cache() && filter(r as &dyn ChanTarget))
+            .filter_map(|r| {
+                let mut builder = FallbackDir::builder();
+                builder.rsa_identity(*r.rsa_id()).ed_identity(*r.id());
+                for addr in r.addrs() {
+                    builder.orport(*addr);
+                }
+                builder.build().ok()
+            })
+            .collect(),
+        None => fallbacks.iter().filter(|f| filter(*f)).cloned().collect(),
+    }
+}
+
+/// If `cache` has an IPv6 address, return a copy of it that lists its IPv6
+/// addresses before its IPv4 ones, so that we'll connect to it over IPv6.
+///
+/// Return None if `cache` has no IPv6 address.
+fn ipv6_first(cache: &FallbackDir) -> Option<FallbackDir> {
+    let addrs = cache.addrs();
+    if !addrs.iter().any(SocketAddr::is_ipv6) {
+        return None;
+    }
+    let mut builder = FallbackDir::builder();
+    builder
+        .rsa_identity(*cache.rsa_identity())
+        .ed_identity(*cache.ed_identity());
+    for addr in addrs.iter().filter(|a| a.is_ipv6()) {
+        builder.orport(*addr);
+    }
+    for addr in addrs.iter().filter(|a| a.is_ipv4()) {
+        builder.orport(*addr);
+    }
+    builder.build().ok()
+}
+
+/// A directory cache that a [`DirMgr`] might ask for documents, along with
+/// what we know about it.
+///
+/// Returned by [`DirMgr::candidate_dir_caches`].
+#[derive(Clone, Debug)]
+pub struct DirCacheCandidate {
+    /// The identities and addresses of the cache.
+    cache: FallbackDir,
+    /// Our current estimate of how long the cache takes to answer.
+    latency: Option<Duration>,
+    /// True if this is the fallback that we'll ask next.
+    next: bool,
+}
+
+impl DirCacheCandidate {
+    /// Return the identities and addresses of this cache.
+    pub fn cache(&self) -> &FallbackDir {
+        &self.cache
+    }
+
+    /// Return our current estimate of how long this cache takes to answer
+    /// a request, if we've timed it.
+    ///
+    /// We only time a cache when we know which one we asked: that is, when
+    /// we ask a single fallback directory because we have no directory yet.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Return true if this is the cache that we'll ask for our next
+    /// download.
+    ///
+    /// This is only ever true when we have no directory, and ask our
+    /// fallbacks one at a time.  Once we have a directory, we let the
+    /// circuit manager choose among all the candidates.
+    pub fn is_next(&self) -> bool {
+        self.next
+    }
+}
+
+/// A known error in the local wall clock, as learned from some source that we
+/// trust more than the local clock.
+///
+/// See [`DirMgr::set_clock_skew`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ClockSkew {
+    /// The local clock is ahead of the real time by this much.
+    Fast(Duration),
+    /// The local clock is behind the real time by this much.
+    Slow(Duration),
+}
+
+impl ClockSkew {
+    /// Convert a time as read from the local clock into the real time.
+    ///
+    /// Return None if the result can't be represented.
+    fn correct(self, local: SystemTime) -> Option<SystemTime> {
+        match self {
+            ClockSkew::Fast(d) => local.checked_sub(d),
+            ClockSkew::Slow(d) => local.checked_add(d),
+        }
+    }
+
+    /// Convert a real time into the corresponding time on the local clock.
+    ///
+    /// Return None if the result can't be represented.
+    fn uncorrect(self, real: SystemTime) -> Option<SystemTime> {
+        match self {
+            ClockSkew::Fast(d) => real.checked_add(d),
+            ClockSkew::Slow(d) => real.checked_sub(d),
+        }
+    }
 }
 
 impl<R: Runtime> DirMgr<R> {
@@ -252,6 +527,49 @@ impl<R: Runtime> DirMgr<R> {
         )?))
     }
 
+    /// Create a new `DirMgr` in online mode that keeps its documents in
+    /// `store`, but don't bootstrap it yet.
+    ///
+    /// This is like [`DirMgr::create_unbootstrapped`], except that it doesn't
+    /// open the default SQLite store under the configured cache path.
+    pub fn create_unbootstrapped_with_store(
+        config: DirMgrConfig,
+        runtime: R,
+        circmgr: Arc<CircMgr<R>>,
+        store: Box<dyn Store + Send>,
+    ) -> Result<Arc<Self>> {
+        Ok(Arc::new(DirMgr::from_config_and_store(
+            config,
+            runtime,
+            Some(circmgr),
+            false,
+            store,
+        )))
+    }
+
+    /// Create a new `DirMgr` in online mode that reads its documents from
+    /// both `read_store` and `write_store`, but only writes to `write_store`;
+    /// don't bootstrap it yet.
+    ///
+    /// This is useful when `read_store` is a prepopulated cache that we
+    /// can't change.  When both stores have a document, we use the one in
+    /// `write_store`.  Everything we download, and every other change we'd
+    /// make to our cache, goes to `write_store` alone.
+    pub fn create_unbootstrapped_with_stores(
+        config: DirMgrConfig,
+        runtime: R,
+        circmgr: Arc<CircMgr<R>>,
+        read_store: Box<dyn Store + Send>,
+        write_store: Box<dyn Store + Send>,
+    ) -> Result<Arc<Self>> {
+        Self::create_unbootstrapped_with_store(
+            config,
+            runtime,
+            circmgr,
+            Box::new(storage::LayeredStore::new(read_store, write_store)),
+        )
+    }
+
     /// Bootstrap a `DirMgr` created in online mode that hasn't been bootstrapped yet.
     ///
     /// This function will not return until the directory is bootstrapped enough to build circuits.
@@ -267,6 +585,8 @@ impl<R: Runtime> DirMgr<R> {
     ///
     /// Returns an error if bootstrapping fails. If the error is [`Error::CantAdvanceState`],
     /// it may be possible to successfully bootstrap later on by calling this function again.
+    /// If we may only take our consensus from the cache, and the cache has no consensus that
+    /// we can use, returns [`Error::NoCachedConsensus`].
     ///
     /// # Panics
     ///
@@ -303,6 +623,12 @@ impl<R: Runtime> DirMgr<R> {
         // Try to load from the cache.
         let have_directory = self.load_directory().await?;
 
+        // If we may not download a consensus, there's no sense in waiting
+        // for one to turn up.
+        if !have_directory && self.config.get().consensus_from_cache() {
+            self.check_cached_consensus().await?;
+        }
+
         let (mut sender, receiver) = if have_directory {
             info!("Loaded a good directory from cache.");
             (None, None)
@@ -326,7 +652,7 @@ impl<R: Runtime> DirMgr<R> {
                         Error::ManagerDropped => {}
                         _ => warn!("Unrecovered error while waiting for bootstrap: {}", e),
                     }
-                } else if let Err(e) = Self::download_forever(dirmgr_weak, sender).await {
+                } else if let Err(e) = Self::start_download_forever(dirmgr_weak, sender).await {
                     match e {
                         Error::ManagerDropped => {}
                         _ => warn!("Unrecovered error while downloading: {}", e),
@@ -344,7 +670,7 @@ impl<R: Runtime> DirMgr<R> {
                 }
                 Err(_) => {
                     warn!("Bootstrapping task exited before finishing.");
-                    return Err(Error::CantAdvanceState);
+                    return Err(Error::CantAdvanceState(CantAdvanceReason::TaskExited));
                 }
             }
         }
@@ -387,7 +713,7 @@ impl<R: Runtime> DirMgr<R> {
         {
             let dirmgr = upgrade_weak_ref(weak)?;
             runtime = dirmgr.runtime.clone();
-            bootstrapped = dirmgr.netdir.get().is_some();
+            bootstrapped = dirmgr.netdir.get().is_some() || dirmgr.consensus.get().is_some();
         }
 
         loop {
@@ -442,23 +768,44 @@ impl<R: Runtime> DirMgr<R> {
         }
     }
 
-    /// Try to fetch our directory info and keep it updated, indefinitely.
+    /// Try to fetch our directory info and keep it updated, indefinitely,
+    /// starting by looking for a consensus.
     ///
     /// If we have begin to have a bootstrapped directory, send a
     /// message using `on_complete`.
-    async fn download_forever(
+    async fn start_download_forever(
         weak: Weak<Self>,
-        mut on_complete: Option<oneshot::Sender<()>>,
+        on_complete: Option<oneshot::Sender<()>>,
     ) -> Result<()> {
-        let mut state: Box<dyn DirState> = Box::new(state::GetConsensusState::new(
+        let cache_usage = if upgrade_weak_ref(&weak)?.config.get().consensus_from_cache() {
+            CacheUsage::ConsensusFromCache
+        } else {
+            CacheUsage::CacheOkay
+        };
+        let state: Box<dyn DirState> = Box::new(state::GetConsensusState::new(
             Weak::clone(&weak),
-            CacheUsage::CacheOkay,
+            cache_usage,
         )?);
+        Self::download_forever(weak, state, on_complete).await
+    }
 
+    /// Try to fetch our directory info and keep it updated, indefinitely,
+    /// starting from `state`.
+    ///
+    /// If we have begin to have a bootstrapped directory, send a
+    /// message using `on_complete`.
+    async fn download_forever(
+        weak: Weak<Self>,
+        mut state: Box<dyn DirState>,
+        mut on_complete: Option<oneshot::Sender<()>>,
+    ) -> Result<()> {
         let runtime = {
             let dirmgr = upgrade_weak_ref(&weak)?;
             dirmgr.runtime.clone()
         };
+        // How long to wait before replacing a directory that's stale and
+        // incomplete, if we keep ending up with one.
+        let mut stale_retry = None;
 
         loop {
             let mut usable = false;
@@ -471,6 +818,7 @@ impl<R: Runtime> DirMgr<R> {
                 *dirmgr.config.get().schedule().retry_bootstrap()
             };
             let mut retry_delay = retry_config.schedule();
+            let mut last_err = None;
 
             'retry_attempt: for _ in retry_config.attempts() {
                 let (newstate, recoverable_err) =
@@ -490,7 +838,10 @@ impl<R: Runtime> DirMgr<R> {
                         err, delay
                     );
                     runtime.sleep(delay).await;
-                    state = state.reset()?;
+                    let dirmgr = upgrade_weak_ref(&weak)?;
+                    dirmgr.note_reset(DirResetReason::DownloadFailed);
+                    state = dirmgr.reset_state_partial(state)?;
+                    last_err = Some(err);
                 } else {
                     info!("Directory is complete.");
                     usable = true;
@@ -504,7 +855,8 @@ impl<R: Runtime> DirMgr<R> {
                     "We failed {} times to bootstrap a directory. We're going to give up.",
                     retry_config.n_attempts()
                 );
-                return Err(Error::CantAdvanceState);
+                return Err(last_err
+                    .unwrap_or_else(|| internal!("gave up without any failed attempts").into()));
             } else {
                 // Report success, if appropriate.
                 if let Some(send_done) = on_complete.take() {
@@ -512,13 +864,131 @@ impl<R: Runtime> DirMgr<R> {
                 }
             }
 
-            let reset_at = state.reset_time();
-            match reset_at {
-                Some(t) => runtime.sleep_until_wallclock(t).await,
+            let reset_at = match state.reset_time() {
+                Some(t) => upgrade_weak_ref(&weak)?.local_time(t),
                 None => return Ok(()),
+            };
+            let reason = if state.is_ready(Readiness::Stale) && !state.is_ready(Readiness::Complete)
+            {
+                // There's no point in waiting around to finish a directory
+                // that's already out of date, but we still back off
+                // before we look for a new one, in case the caches
+                // don't have one for us yet.
+                let delay = stale_retry
+                    .get_or_insert_with(|| retry_config.schedule())
+                    .next_delay(&mut rand::thread_rng());
+                info!("Our directory is usable, but incomplete and no longer fresh. Looking for a new consensus in {:?}.", delay);
+                runtime.sleep(delay).await;
+                DirResetReason::ConsensusReplacement
+            } else {
+                stale_retry = None;
+                upgrade_weak_ref(&weak)?.set_next_refresh(Some(reset_at));
+                futures::select_biased! {
+                    r = Self::wait_for_invalidation(&weak).fuse() => {
+                        r?;
+                        // Go back and fetch what was invalidated, without
+                        // starting over.
+                        info!("Some of our microdescriptors were invalidated; fetching them again.");
+                        upgrade_weak_ref(&weak)?.set_next_refresh(None);
+                        continue;
+                    }
+                    _ = runtime.sleep_until_wallclock(reset_at).fuse() => DirResetReason::Scheduled,
+                }
+            };
+            // If our refreshes are paused, we'll notice when we wake up
+            // whether we've passed our reset time, since we only check once
+            // we're resumed.
+            Self::wait_until_unpaused(&weak).await?;
+            let dirmgr = upgrade_weak_ref(&weak)?;
+            dirmgr.set_next_refresh(None);
+            dirmgr.note_reset(reason);
+            state = dirmgr.reset_state(state)?;
+        }
+    }
+
+    /// Return the time when we plan to start refreshing our directory, if
+    /// we're waiting to do so.
+    ///
+    /// Once we have a complete directory, we pick a random time to start
+    /// looking for a new consensus: after the current one stops being fresh,
+    /// and well before it stops being valid, as the directory specification
+    /// says.  Schedulers and power-management logic can use this to plan
+    /// around our next burst of network activity.
+    ///
+    /// Return None if we're still bootstrapping, if a refresh is already in
+    /// progress, or if we aren't downloading at all.  If our refreshes are
+    /// [paused](Self::pause_refresh), the refresh starts once they're
+    /// resumed, which may be later than the time returned here.
+    pub fn next_refresh_time(&self) -> Option<SystemTime> {
+        *self.next_refresh.lock().expect("poisoned lock")
+    }
+
+    /// Record the time when our download task plans to start its next
+    /// refresh, or None if it isn't waiting to start one.
+    fn set_next_refresh(&self, when: Option<SystemTime>) {
+        *self.next_refresh.lock().expect("poisoned lock") = when;
+    }
+
+    /// Stop starting new attempts to refresh our directory, until somebody
+    /// calls [`resume_refresh`](Self::resume_refresh).
+    ///
+    /// Any download that is already in progress keeps going until it
+    /// finishes or fails; pausing only prevents the next one from starting.
+    /// This has no effect on the initial bootstrap of a directory, or on
+    /// downloads that [`bootstrap`](Self::bootstrap) starts after a
+    /// failure: it only delays the periodic refreshes that happen once we
+    /// have a usable directory.
+    ///
+    /// Pausing more than once has the same effect as pausing once.
+    pub fn pause_refresh(&self) {
+        let mut sender = self.send_refresh_paused.lock().expect("poisoned lock");
+        *sender.borrow_mut() = true;
+    }
+
+    /// Allow our directory refreshes to continue after a call to
+    /// [`pause_refresh`](Self::pause_refresh).
+    ///
+    /// If a refresh was due while we were paused, it starts right away.
+    pub fn resume_refresh(&self) {
+        let mut sender = self.send_refresh_paused.lock().expect("poisoned lock");
+        *sender.borrow_mut() = false;
+    }
+
+    /// Return once our directory refreshes are not paused.
+    ///
+    /// Give an error if the `DirMgr` is dropped while we're waiting.
+    async fn wait_until_unpaused(weak: &Weak<Self>) -> Result<()> {
+        let mut paused = upgrade_weak_ref(weak)?.receive_refresh_paused.clone();
+        let mut logged = false;
+        while let Some(is_paused) = paused.next().await {
+            if !is_paused {
+                if logged {
+                    info!("Directory refreshes resumed.");
+                }
+                return Ok(());
+            }
+            if !logged {
+                info!("Directory refreshes are paused; waiting to resume.");
+                logged = true;
+            }
+        }
+        Err(Error::ManagerDropped)
+    }
+
+    /// Return once somebody has invalidated a microdescriptor that our
+    /// download task hasn't yet marked as missing.
+    ///
+    /// Give an error if the `DirMgr` is dropped while we're waiting.
+    async fn wait_for_invalidation(weak: &Weak<Self>) -> Result<()> {
+        let mut invalidated = upgrade_weak_ref(weak)?.receive_invalidated.clone();
+        while invalidated.next().await.is_some() {
+            let dirmgr = upgrade_weak_ref(weak)?;
+            let pending = dirmgr.invalidated_microdescs.lock().expect("poisoned lock");
+            if !pending.is_empty() {
+                return Ok(());
             }
-            state = state.reset()?;
         }
+        Err(Error::ManagerDropped)
     }
 
     /// Get a reference to the circuit manager, if we have one.
@@ -547,6 +1017,15 @@ impl<R: Runtime> DirMgr<R> {
         if new_config.authorities() != config.authorities() {
             how.cannot_change("network.authorities")?;
         }
+        if new_config.consensus_flavor() != config.consensus_flavor() {
+            how.cannot_change("consensus_flavor")?;
+        }
+        if new_config.consensus_only() != config.consensus_only() {
+            how.cannot_change("consensus_only")?;
+        }
+        if new_config.consensus_from_cache() != config.consensus_from_cache() {
+            how.cannot_change("consensus_from_cache")?;
+        }
 
         if how == tor_config::Reconfigure::CheckAllOrNothing {
             return Ok(());
@@ -570,6 +1049,49 @@ impl<R: Runtime> DirMgr<R> {
         Ok(())
     }
 
+    /// Tell this `DirMgr` how far the runtime's wall clock is from the real
+    /// time, as learned from some source that we trust more than the local
+    /// clock (for example, an out-of-band NTP result).
+    ///
+    /// Once this is set, every decision we make about whether a directory
+    /// document is timely uses the corrected time.  This lets us bootstrap on
+    /// a machine whose clock is badly wrong, if the embedder knows better.
+    ///
+    /// Pass `None` to go back to trusting the runtime's wall clock.
+    pub fn set_clock_skew(&self, skew: Option<ClockSkew>) {
+        *self.clock_skew.lock().expect("poisoned lock") = skew;
+    }
+
+    /// Return our best guess of the current wall-clock time: the runtime's
+    /// wall clock, corrected by any [`ClockSkew`] we've been told about.
+    ///
+    /// If the skew is too large to apply, we ignore it.
+    fn trusted_now(&self) -> SystemTime {
+        let now = self.runtime.wallclock();
+        match *self.clock_skew.lock().expect("poisoned lock") {
+            Some(skew) => skew.correct(now).unwrap_or_else(|| {
+                warn!("Clock skew {:?} is out of range; ignoring it.", skew);
+                now
+            }),
+            None => now,
+        }
+    }
+
+    /// Convert a time expressed in corrected terms (as returned by
+    /// `trusted_now`) into the corresponding time on the runtime's wall clock.
+    ///
+    /// We need this before passing a time to `sleep_until_wallclock`.  As
+    /// with `trusted_now`, we ignore a skew that is too large to apply.
+    fn local_time(&self, t: SystemTime) -> SystemTime {
+        match *self.clock_skew.lock().expect("poisoned lock") {
+            Some(skew) => skew.uncorrect(t).unwrap_or_else(|| {
+                warn!("Clock skew {:?} is out of range; ignoring it.", skew);
+                t
+            }),
+            None => t,
+        }
+    }
+
     /// Return a stream of [`DirBootstrapStatus`] events to tell us about changes
     /// in the latest directory's bootstrap status.
     ///
@@ -579,122 +1101,1017 @@ impl<R: Runtime> DirMgr<R> {
         self.receive_status.clone()
     }
 
-    /// Replace the latest status with `new_status` and broadcast to anybody
-    /// watching via a [`DirBootstrapEvents`] stream.
-    fn update_status(&self, new_status: DirStatus) {
+    /// Return an estimate, from 0 to 100, of how close we are to having a
+    /// complete directory that's valid right now.
+    ///
+    /// Unlike the [`DirStatus`] of a single state, this takes every phase of
+    /// bootstrapping into account: the consensus counts for a large share,
+    /// its certificates for a smaller one, and the microdescriptors for the
+    /// rest, in proportion to how many of them we have.
+    ///
+    /// This can go down when we start replacing one directory with another.
+    /// As with [`DirBootstrapStatus::frac_at`], callers shouldn't depend on
+    /// the share that any particular phase gets.
+    pub fn bootstrap_percent(&self) -> u8 {
+        let frac = self
+            .receive_status
+            .inner
+            .borrow()
+            .frac_at(self.trusted_now());
+        // frac_at() should already be between 0.0 and 1.0; the clamp is
+        // just for safety.
+        (frac * 100.0).round().clamp(0.0, 100.0) as u8
+    }
+
+    /// Return the phase of the download that we're working on.
+    ///
+    /// This is also reported in every [`DirBootstrapStatus`] from
+    /// [`DirMgr::bootstrap_events`].
+    pub fn bootstrap_phase(&self) -> BootstrapPhase {
+        self.receive_status.inner.borrow().phase()
+    }
+
+    /// Replace the latest status and phase with those of `state`, and
+    /// broadcast to anybody watching via a [`DirBootstrapEvents`] stream.
+    fn update_status(&self, state: &dyn DirState) {
         // TODO(nickm): can I kill off this lock by having something else own the sender?
         let mut sender = self.send_status.lock().expect("poisoned lock");
         let mut status = sender.borrow_mut();
 
-        status.update(new_status);
+        status.update(state.bootstrap_status());
+        status.phase = state.bootstrap_phase();
     }
 
-    /// Try to make this a directory manager with read-write access to its
-    /// storage.
-    ///
-    /// Return true if we got the lock, or if we already had it.
+    /// Record the phase of `state`, if it differs from the last one we
+    /// reported, and broadcast it via a [`DirBootstrapEvents`] stream.
+    fn note_phase(&self, state: &dyn DirState) {
+        let phase = state.bootstrap_phase();
+        let mut sender = self.send_status.lock().expect("poisoned lock");
+        if sender.borrow().phase != phase {
+            sender.borrow_mut().phase = phase;
+        }
+    }
+
+    /// Return a summary of the documents that we still need to download (or
+    /// load from the cache) before our current directory is complete.
     ///
-    /// Return false if another process has the lock
-    fn try_upgrade_to_readwrite(&self) -> Result<bool> {
-        self.store
-            .lock()
-            .expect("Directory storage lock poisoned")
-            .upgrade_to_readwrite()
+    /// This reflects the bootstrapping state that we are currently working
+    /// on, so it may include only a consensus before we've fetched one, and
+    /// only microdescriptors after that.
+    pub fn missing_summary(&self) -> MissingSummary {
+        self.missing.lock().expect("poisoned lock").clone()
     }
 
-    /// Return a reference to the store, if it is currently read-write.
-    fn store_if_rw(&self) -> Option<&Mutex<DynStore>> {
-        let rw = !self
-            .store
-            .lock()
-            .expect("Directory storage lock poisoned")
-            .is_readonly();
-        // A race-condition is possible here, but I believe it's harmless.
-        if rw {
-            Some(&self.store)
-        } else {
-            None
-        }
+    /// Remember which documents `state` is missing, for use by
+    /// [`DirMgr::missing_summary`].
+    fn note_missing(&self, state: &dyn DirState) {
+        let summary = MissingSummary::from_docs(&state.missing_docs());
+        *self.missing.lock().expect("poisoned lock") = summary;
     }
 
-    /// Construct a DirMgr from a DirMgrConfig.
+    /// Note that we're resetting our download process because of `reason`,
+    /// and tell anybody watching via [`DirMgr::events`].
+    fn note_reset(&self, reason: DirResetReason) {
+        debug!("Resetting directory download: {:?}", reason);
+        self.events.publish(DirEvent::Reset(reason));
+    }
+
+    /// Install `observer` to hear about our progress as we download and use
+    /// directory information.
     ///
-    /// If `offline` is set, opens the SQLite store read-only and sets the offline flag in the
-    /// returned manager.
-    fn from_config(
-        config: DirMgrConfig,
-        runtime: R,
-        circmgr: Option<Arc<CircMgr<R>>>,
-        offline: bool,
-    ) -> Result<Self> {
-        let store = Mutex::new(config.open_store(offline)?);
-        let netdir = SharedMutArc::new();
-        let events = event::FlagPublisher::new();
+    /// This replaces any observer that was installed before.
+    pub fn set_observer(&self, observer: DirObserver) {
+        *self.observer.lock().expect("Poisoned lock") = Arc::new(observer);
+    }
 
-        let (send_status, receive_status) = postage::watch::channel();
-        let send_status = Mutex::new(send_status);
-        let receive_status = DirBootstrapEvents {
-            inner: receive_status,
-        };
+    /// Return our current observer.
+    fn observer(&self) -> Arc<DirObserver> {
+        Arc::clone(&self.observer.lock().expect("Poisoned lock"))
+    }
 
-        Ok(DirMgr {
-            config: config.into(),
-            store,
-            netdir,
-            events,
-            send_status,
-            receive_status,
-            circmgr,
-            runtime,
-            offline,
-            bootstrap_started: AtomicBool::new(false),
-        })
+    /// Replace `state` with the result of `transition` (which should be
+    /// either its `advance()` or its `reset()` method), record the phase of
+    /// the new state, and tell our observer about the change.
+    fn transition_state<F>(
+        &self,
+        kind: TransitionKind,
+        state: Box<dyn DirState>,
+        transition: F,
+    ) -> Result<Box<dyn DirState>>
+    where
+        F: FnOnce(Box<dyn DirState>) -> Result<Box<dyn DirState>>,
+    {
+        let observer = self.observer();
+        let from = observer.wants_transitions().then(|| state.describe());
+        let state = transition(state)?;
+        self.note_phase(state.as_ref());
+        if let Some(from) = from {
+            observer.transition(&StateTransition::new(kind, from, state.describe()));
+        }
+        Ok(state)
     }
 
-    /// Load the latest non-pending non-expired directory from the
-    /// cache, if it is newer than the one we have.
-    ///
-    /// Return false if there is no such consensus.
-    async fn load_directory(self: &Arc<Self>) -> Result<bool> {
-        let state = state::GetConsensusState::new(Arc::downgrade(self), CacheUsage::CacheOnly)?;
-        let _ = bootstrap::load(Arc::clone(self), Box::new(state)).await?;
+    /// Advance `state` to its next state, and tell our observer.
+    fn advance_state(&self, state: Box<dyn DirState>) -> Result<Box<dyn DirState>> {
+        self.transition_state(TransitionKind::Advance, state, |s| s.advance())
+    }
 
-        Ok(self.netdir.get().is_some())
+    /// Reset `state` to start over, and tell our observer.
+    fn reset_state(&self, state: Box<dyn DirState>) -> Result<Box<dyn DirState>> {
+        self.transition_state(TransitionKind::Reset, state, |s| s.reset())
     }
 
-    /// Return an Arc handle to our latest directory, if we have one.
-    pub fn opt_netdir(&self) -> Option<Arc<NetDir>> {
-        self.netdir.get()
+    /// Reset as little of `state` as we need to in order to try again after
+    /// a failed download, and tell our observer.
+    fn reset_state_partial(&self, state: Box<dyn DirState>) -> Result<Box<dyn DirState>> {
+        self.transition_state(TransitionKind::Reset, state, |s| s.reset_partial())
     }
 
-    /// Return an Arc handle to our latest directory, returning an error if there is none.
+    /// Install `filter` as a function to decide which directory caches we
+    /// may ask for documents.
     ///
-    /// # Errors
+    /// Before each request, we call the filter on every cache that we'd
+    /// otherwise choose among (our fallback directories if we have no
+    /// directory yet, or else the directory caches listed in our
+    /// directory), and only choose among the ones for which it returns
+    /// true.  If it rejects all of them, our requests will fail.
     ///
-    /// Errors with [`Error::DirectoryNotPresent`] if the `DirMgr` hasn't been bootstrapped yet.
-    // TODO: Add variants of this that make sure that it's up-to-date?
-    pub fn netdir(&self) -> Result<Arc<NetDir>> {
-        self.opt_netdir().ok_or(Error::DirectoryNotPresent)
+    /// While a filter is installed, we pick among the caches that it allows
+    /// uniformly at random, rather than by bandwidth or by using our
+    /// directory guards.
+    ///
+    /// This replaces any filter that was installed before.
+    pub fn set_cache_filter<F>(&self, filter: F)
+    where
+        F: Fn(&dyn ChanTarget) -> bool + Send + Sync + 'static,
+    {
+        *self.cache_filter.lock().expect("Poisoned lock") = Some(Arc::new(filter));
     }
 
-    /// Return a new asynchronous stream that will receive notification
-    /// whenever the consensus has changed.
+    /// Install `hook` as a function to inspect and modify each HTTP request
+    /// for directory documents, just before we send it.
     ///
-    /// Multiple events may be batched up into a single item: each time
-    /// this stream yields an event, all you can assume is that the event has
-    /// occurred at least once.
-    pub fn events(&self) -> impl futures::Stream<Item = DirEvent> {
-        self.events.subscribe()
+    /// This lets tests mark the requests that we send, and lets applications
+    /// make their requests look alike (for example, by always asking for
+    /// the same content-encodings).
+    ///
+    /// The hook may change the request's headers, but it may not change what
+    /// we're asking for: if it changes the method, URI, or HTTP version, adds
+    /// a header describing a request body, or asks for a content-encoding
+    /// that we didn't already ask for, we log a warning and send the
+    /// request without its changes.
+    ///
+    /// This replaces any hook that was installed before.
+    pub fn set_request_hook<F>(&self, hook: F)
+    where
+        F: Fn(&mut http::Request<()>) + Send + Sync + 'static,
+    {
+        *self.request_hook.lock().expect("Poisoned lock") = Some(Arc::new(hook));
     }
 
-    /// Try to load the text of a single document described by `doc` from
-    /// storage.
-    pub fn text(&self, doc: &DocId) -> Result<Option<DocumentText>> {
-        use itertools::Itertools;
-        let mut result = HashMap::new();
-        let query = (*doc).into();
-        self.load_documents_into(&query, &mut result)?;
-        let item = result.into_iter().at_most_one().map_err(|_| {
+    /// Return our request hook, if we have one.
+    fn request_hook(&self) -> Option<RequestHook> {
+        self.request_hook.lock().expect("Poisoned lock").clone()
+    }
+
+    /// Tell our observer about `docs`.
+    fn note_obtained(&self, docs: &[DocId]) {
+        self.observer().obtained(docs);
+    }
+
+    /// If our observer wants raw responses, and `response` is complete,
+    /// return a copy of its body and its content-encoding, to report once we
+    /// know whether we accept its documents.
+    fn keep_raw_response(
+        &self,
+        response: &tor_dirclient::DirResponse,
+    ) -> Option<(Vec<u8>, Option<String>)> {
+        if self.observer().wants_raw_responses() && !response.is_partial() {
+            Some((
+                response.output().to_vec(),
+                response.encoding().map(String::from),
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Tell our observer, if it wants to know, that we accepted the
+    /// documents in a response to `request`, whose body was `body` in
+    /// content-encoding `encoding`.
+    fn note_raw_response(&self, request: &ClientRequest, body: &[u8], encoding: Option<&str>) {
+        let observer = self.observer();
+        if observer.wants_raw_responses() {
+            match request.as_requestable().make_request() {
+                Ok(req) => observer.raw_response(&req, body, encoding),
+                Err(e) => warn!("Unable to describe a directory request: {}", e),
+            }
+        }
+    }
+
+    /// Tell our observer that we've replaced `old` with `new`.
+    fn note_netdir_replaced(&self, old: Option<&NetDir>, new: &NetDir) {
+        self.observer().netdir_replaced(old, new);
+    }
+
+    /// Tell our observer about `response`.
+    fn note_bytes_received(&self, response: &tor_dirclient::DirResponse) {
+        self.observer()
+            .bytes_received(response.output().len(), response.source());
+    }
+
+    /// Install `decoder` as a function to undo the content-encoding called
+    /// `name`.
+    ///
+    /// Once this is done, we tell directory caches that we accept responses
+    /// in this encoding, and use `decoder` on any that we get.  We always
+    /// handle the encodings that we support by ourselves (like `deflate`),
+    /// even if a codec is registered with the same name.  A directory cache
+    /// that sends an encoding we know no way to undo still gets an error.
+    ///
+    /// This replaces any codec that was registered before with the same
+    /// name.  Returns an error if `name` can't appear in an HTTP header.
+    pub fn register_codec<F>(&self, name: impl Into<String>, decoder: F) -> Result<()>
+    where
+        F: Fn(&[u8]) -> std::io::Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let is_token_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        if name.is_empty() || !name.chars().all(is_token_char) {
+            return Err(bad_api_usage!("invalid content-encoding name {:?}", name).into());
+        }
+        self.codecs
+            .lock()
+            .expect("Poisoned lock")
+            .insert(name, Arc::new(decoder));
+        Ok(())
+    }
+
+    /// Return the names of all the content-encodings that somebody has
+    /// registered codecs for.
+    fn codec_names(&self) -> Vec<String> {
+        let codecs = self.codecs.lock().expect("Poisoned lock");
+        let mut names: Vec<_> = codecs.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// If we have a cache filter, or we're configured to prefer caches with
+    /// IPv6 addresses, return the directory caches that we may ask for
+    /// documents.
+    ///
+    /// We consider the caches listed in `netdir` if it's present, and the
+    /// ones in `fallbacks` otherwise, and keep the ones that our cache filter
+    /// allows.  If we prefer IPv6, and any of those have an IPv6 address, we
+    /// return only those, with their IPv6 addresses first.  If we have no
+    /// cache filter and no preference, return None.
+    fn filtered_caches(
+        &self,
+        netdir: Option<&NetDir>,
+        fallbacks: &[FallbackDir],
+    ) -> Option<Vec<FallbackDir>> {
+        let filter = self.cache_filter.lock().expect("Poisoned lock").clone();
+        let prefer_ipv6 = self.config.get().prefer_ipv6_caches();
+        let caches = match filter {
+            Some(filter) => dir_caches(netdir, fallbacks, filter.as_ref()),
+            None if prefer_ipv6 => dir_caches(netdir, fallbacks, &|_: &dyn ChanTarget| true),
+            None => return None,
+        };
+        if prefer_ipv6 {
+            let ipv6_caches: Vec<_> = caches.iter().filter_map(ipv6_first).collect();
+            if !ipv6_caches.is_empty() {
+                return Some(ipv6_caches);
+            }
+        }
+        Some(caches)
+    }
+
+    /// Return every directory cache that we might ask for documents.
+    ///
+    /// As with [`DirMgr::filtered_caches`], we consider the caches listed
+    /// in `netdir` if it's present, and the ones in `fallbacks` otherwise,
+    /// but here we return all of them if we have no cache filter and no
+    /// preference for IPv6.
+    fn eligible_caches(
+        &self,
+        netdir: Option<&NetDir>,
+        fallbacks: &[FallbackDir],
+    ) -> Vec<FallbackDir> {
+        self.filtered_caches(netdir, fallbacks)
+            .unwrap_or_else(|| dir_caches(netdir, fallbacks, &|_: &dyn ChanTarget| true))
+    }
+
+    /// Return a list holding just the entry from `fallbacks` that we should
+    /// ask for documents while we have no directory.
+    ///
+    /// We keep asking the same fallback until it fails, unless another one
+    /// has been answering us faster: then we switch to that one.
+    ///
+    /// Return an empty list if `fallbacks` is empty.
+    fn current_fallback<'a>(&self, fallbacks: &'a [FallbackDir]) -> &'a [FallbackDir] {
+        match self.current_fallback_index(fallbacks) {
+            Some((idx, switched)) => {
+                if switched {
+                    self.next_fallback.store(idx, Ordering::SeqCst);
+                }
+                std::slice::from_ref(&fallbacks[idx])
+            }
+            None => fallbacks,
+        }
+    }
+
+    /// Return the index of the entry in `fallbacks` that
+    /// [`DirMgr::current_fallback`] would choose, and whether that means
+    /// switching to a faster one than the one we were going to ask.
+    ///
+    /// Return None if `fallbacks` is empty.
+    fn current_fallback_index(&self, fallbacks: &[FallbackDir]) -> Option<(usize, bool)> {
+        if fallbacks.is_empty() {
+            return None;
+        }
+        let idx = self.next_fallback.load(Ordering::SeqCst) % fallbacks.len();
+        Some(match self.cache_latency.faster_than(fallbacks, idx) {
+            Some(faster) => (faster, true),
+            None => (idx, false),
+        })
+    }
+
+    /// If we haven't yet made a directory request from the network, return
+    /// how long we should wait before making it.
+    ///
+    /// This returns a random delay no longer than our configured startup
+    /// jitter the first time it's called, and None every time after that.
+    fn take_startup_jitter(&self) -> Option<Duration> {
+        if self.startup_jitter_done.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        let window = self.config.get().schedule().startup_jitter();
+        if window == Duration::default() {
+            return None;
+        }
+        let millis = u64::try_from(window.as_millis()).unwrap_or(u64::MAX);
+        Some(Duration::from_millis(
+            rand::thread_rng().gen_range(0..=millis),
+        ))
+    }
+
+    /// Note that `cache` took `rtt` to answer a request, so that we can
+    /// prefer faster caches.
+    fn note_cache_latency(&self, cache: &FallbackDir, rtt: Duration) {
+        self.cache_latency.note(cache.rsa_identity(), rtt);
+    }
+
+    /// Note that the directory cache that sent the response described by
+    /// `source` gave us nothing useful, so that we ask a different one next
+    /// time.
+    ///
+    /// If we have no directory, that means moving on to our next fallback.
+    /// Either way, we stop using the circuit that the response came over,
+    /// so that our next request goes out on a new circuit.
+    ///
+    /// We only do this once the cache has failed us as many times as our
+    /// configured `cache_error_threshold` within the configured
+    /// `cache_error_window`: until then, we keep asking it.  If we don't
+    /// know which cache sent the response, we can't hold it against
+    /// anybody, so we do nothing.
+    fn note_cache_error(&self, source: Option<&tor_dirclient::SourceInfo>) {
+        let cache = match source.and_then(tor_dirclient::SourceInfo::cache_id) {
+            Some(cache) => *cache,
+            None => {
+                debug!("Directory request failed, but we don't know which cache it went to.");
+                return;
+            }
+        };
+        let (threshold, window) = {
+            let config = self.config.get();
+            let sched = config.schedule();
+            (sched.cache_error_threshold(), sched.cache_error_window())
+        };
+        let now = self.runtime.now();
+        if !self.cache_errors.note(cache, now, threshold, window) {
+            debug!("Directory cache failed us; not giving up on it yet.");
+            return;
+        }
+        if self.opt_netdir().is_none() {
+            self.note_fallback_failed(&cache);
+        }
+        let circ = source.and_then(tor_dirclient::SourceInfo::unique_circ_id);
+        if let (Some(circ), Ok(circmgr)) = (circ, self.circmgr()) {
+            circmgr.retire_circ(circ);
+        }
+    }
+
+    /// Note that a request to the fallback directory `cache` has failed, so
+    /// that we use the next one in the list instead.
+    ///
+    /// If `cache` isn't our current fallback any more, because somebody
+    /// else noted a failure first, we do nothing: several requests that
+    /// fail together shouldn't make us skip fallbacks we haven't tried.
+    fn note_fallback_failed(&self, cache: &RsaIdentity) {
+        let config = self.config.get();
+        let filtered = self.filtered_caches(None, config.fallbacks());
+        let fallbacks = filtered.as_deref().unwrap_or_else(|| config.fallbacks());
+        if fallbacks.is_empty() {
+            return;
+        }
+        let seen = self.next_fallback.load(Ordering::SeqCst);
+        if fallbacks[seen % fallbacks.len()].rsa_identity() != cache {
+            debug!("Fallback directory failed, but we've already moved on from it.");
+            return;
+        }
+        // If this fails, somebody else has moved us on since we looked.
+        let _ = self.next_fallback.compare_exchange(
+            seen,
+            seen.wrapping_add(1),
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    }
+
+    /// Try to make this a directory manager with read-write access to its
+    /// storage.
+    ///
+    /// Return true if we got the lock, or if we already had it.
+    ///
+    /// Return false if another process has the lock
+    fn try_upgrade_to_readwrite(&self) -> Result<bool> {
+        self.store
+            .lock()
+            .expect("Directory storage lock poisoned")
+            .upgrade_to_readwrite()
+    }
+
+    /// Return a reference to the store, if it is currently read-write.
+    fn store_if_rw(&self) -> Option<&Mutex<DynStore>> {
+        let rw = !self
+            .store
+            .lock()
+            .expect("Directory storage lock poisoned")
+            .is_readonly();
+        // A race-condition is possible here, but I believe it's harmless.
+        if rw {
+            Some(&self.store)
+        } else {
+            None
+        }
+    }
+
+    /// Construct a DirMgr from a DirMgrConfig.
+    ///
+    /// If `offline` is set, opens the SQLite store read-only and sets the offline flag in the
+    /// returned manager.
+    fn from_config(
+        config: DirMgrConfig,
+        runtime: R,
+        circmgr: Option<Arc<CircMgr<R>>>,
+        offline: bool,
+    ) -> Result<Self> {
+        let store = config.open_store(offline)?;
+        Ok(DirMgr::from_config_and_store(
+            config, runtime, circmgr, offline, store,
+        ))
+    }
+
+    /// Construct a DirMgr from a DirMgrConfig, keeping its documents in
+    /// `store`.
+    fn from_config_and_store(
+        config: DirMgrConfig,
+        runtime: R,
+        circmgr: Option<Arc<CircMgr<R>>>,
+        offline: bool,
+        store: DynStore,
+    ) -> Self {
+        // If we can't read what we learned about our caches last time, we
+        // just learn it again.
+        let cache_latency = match store.cache_latencies() {
+            Ok(estimates) => latency::CacheLatencies::from_estimates(estimates),
+            Err(e) => {
+                warn!("Unable to load directory cache latencies: {}", e);
+                latency::CacheLatencies::default()
+            }
+        };
+        let cache_errors = match store.load_source_reputation() {
+            Ok(saved) => {
+                let window = config.schedule().cache_error_window();
+                let now = (runtime.now(), runtime.wallclock());
+                penalty::CacheErrors::from_errors(restore_cache_errors(saved, now, window))
+            }
+            Err(e) => {
+                warn!("Unable to load directory cache errors: {}", e);
+                penalty::CacheErrors::default()
+            }
+        };
+        let store = Mutex::new(store);
+        let netdir = SharedMutArc::new();
+        let events = event::FlagPublisher::new();
+
+        let (send_status, receive_status) = postage::watch::channel();
+        let send_status = Mutex::new(send_status);
+        let receive_status = DirBootstrapEvents {
+            inner: receive_status,
+        };
+        let (send_refresh_paused, receive_refresh_paused) = postage::watch::channel();
+        let send_refresh_paused = Mutex::new(send_refresh_paused);
+        let (send_invalidated, receive_invalidated) = postage::watch::channel();
+        let send_invalidated = Mutex::new(send_invalidated);
+
+        DirMgr {
+            config: config.into(),
+            store,
+            netdir,
+            consensus: SharedMutArc::new(),
+            events,
+            send_status,
+            receive_status,
+            circmgr,
+            runtime,
+            offline,
+            bootstrap_started: AtomicBool::new(false),
+            clock_skew: Mutex::new(None),
+            missing: Mutex::new(MissingSummary::default()),
+            download_throttle: throttle::Throttle::default(),
+            observer: Mutex::new(Arc::new(DirObserver::new())),
+            cache_filter: Mutex::new(None),
+            request_hook: Mutex::new(None),
+            send_refresh_paused,
+            receive_refresh_paused,
+            invalidated_microdescs: Mutex::new(Vec::new()),
+            send_invalidated,
+            receive_invalidated,
+            next_refresh: Mutex::new(None),
+            next_fallback: AtomicUsize::new(rand::random()),
+            cache_latency,
+            cache_errors,
+            startup_jitter_done: AtomicBool::new(false),
+            warned_missing_protocols: AtomicBool::new(false),
+            codecs: Mutex::new(HashMap::new()),
+            microdesc_senders: Mutex::new(Vec::new()),
+            #[cfg(test)]
+            canned_response: Mutex::new(None),
+            #[cfg(test)]
+            fault_script: Mutex::new(None),
+        }
+    }
+
+    /// Load the latest non-pending non-expired directory from the
+    /// cache, if it is newer than the one we have.
+    ///
+    /// Return false if there is no such consensus.
+    async fn load_directory(self: &Arc<Self>) -> Result<bool> {
+        let state = state::GetConsensusState::new(Arc::downgrade(self), CacheUsage::CacheOnly)?;
+        let _ = bootstrap::load(Arc::clone(self), Box::new(state)).await?;
+
+        Ok(self.netdir.get().is_some() || self.consensus.get().is_some())
+    }
+
+    /// Make sure that the cache has a consensus that we could bootstrap from,
+    /// without downloading one.
+    ///
+    /// Return [`Error::NoCachedConsensus`] if there is no such consensus.
+    async fn check_cached_consensus(self: &Arc<Self>) -> Result<()> {
+        let state =
+            state::GetConsensusState::new(Arc::downgrade(self), CacheUsage::ConsensusFromCache)?;
+        let state = bootstrap::load(Arc::clone(self), Box::new(state)).await?;
+
+        if bootstrap::wants_consensus_from_cache(&state.missing_docs()) {
+            Err(Error::NoCachedConsensus)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return an Arc handle to our latest directory, if we have one.
+    pub fn opt_netdir(&self) -> Option<Arc<NetDir>> {
+        self.netdir.get()
+    }
+
+    /// Return an Arc handle to our latest directory, returning an error if there is none.
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`Error::DirectoryNotPresent`] if the `DirMgr` hasn't been bootstrapped yet.
+    // TODO: Add variants of this that make sure that it's up-to-date?
+    pub fn netdir(&self) -> Result<Arc<NetDir>> {
+        self.opt_netdir().ok_or(Error::DirectoryNotPresent)
+    }
+
+    /// Testing helper: make `netdir` our latest directory, as if we had
+    /// just bootstrapped and downloaded it.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn install_netdir_for_testing(&self, netdir: NetDir) {
+        self.netdir.replace(netdir);
+        self.bootstrap_started.store(true, Ordering::SeqCst);
+        self.events.publish(DirEvent::NewConsensus);
+    }
+
+    /// Return an Arc handle to our latest validated consensus, if we are
+    /// configured to fetch only a consensus and we have one.
+    ///
+    /// Unless the configuration sets `consensus_only`, this always returns
+    /// None: use [`DirMgr::netdir`] instead.
+    pub fn consensus(&self) -> Option<Arc<MdConsensus>> {
+        self.consensus.get()
+    }
+
+    /// Return the lifetime of our current consensus, if we have one.
+    ///
+    /// This is the lifetime of the consensus in our current directory, if we
+    /// have a directory; otherwise, it is the lifetime of the latest usable
+    /// consensus in our cache.
+    ///
+    /// Applications can use this to tell how old their directory information
+    /// is, and when it will stop being fresh or valid.
+    pub fn consensus_lifetime(&self) -> Option<Lifetime> {
+        if let Some(netdir) = self.opt_netdir() {
+            return Some(netdir.lifetime().clone());
+        }
+        if let Some(consensus) = self.consensus() {
+            return Some(consensus.lifetime().clone());
+        }
+
+        let flavor = self.config.get().consensus_flavor();
+        let store = self.store.lock().expect("Directory storage lock poisoned");
+        match store.latest_consensus_meta(flavor) {
+            Ok(meta) => meta.map(|meta| meta.lifetime().clone()),
+            Err(e) => {
+                warn!("Error loading directory metadata: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Return true if our current consensus (as described in
+    /// [`DirMgr::consensus_lifetime`]) is past its valid-until time.
+    ///
+    /// This can only happen when the configuration allows us to use an
+    /// expired consensus from our cache; when it does, callers should treat
+    /// our directory information as stale and untrustworthy until a fresh
+    /// consensus arrives.
+    pub fn consensus_is_expired(&self) -> bool {
+        match self.consensus_lifetime() {
+            Some(lifetime) => lifetime.valid_until() < self.trusted_now(),
+            None => false,
+        }
+    }
+
+    /// Return the consensus for our current directory, and the
+    /// microdescriptors it lists, in the formats that Tor uses for its own
+    /// cached directory files.
+    ///
+    /// The documents come from our cache, exactly as we received them.  Any
+    /// microdescriptors that we don't have are left out.
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`Error::DirectoryNotPresent`] if we don't have a
+    /// directory yet.
+    pub fn export_netdir(&self) -> Result<ExportedNetDir> {
+        let netdir = self.netdir()?;
+        let flavor = self.config.get().consensus_flavor();
+        let store = self.store.lock().expect("Directory storage lock poisoned");
+        let meta = store
+            .latest_consensus_meta(flavor)?
+            .filter(|meta| meta.lifetime().valid_after() == netdir.lifetime().valid_after())
+            .ok_or(Error::CacheCorruption(
+                "couldn't find the consensus for our current directory",
+            ))?;
+        export::export_from_store(&**store, &meta)
+    }
+
+    /// Make sure that every document we've downloaded so far has been
+    /// written to our cache, along with what we've learned about how
+    /// quickly (and how reliably) each directory cache answers us.
+    ///
+    /// This is called when the `DirMgr` is dropped; call it yourself if you
+    /// want to find out whether it failed.
+    pub fn flush(&self) -> Result<()> {
+        if let Some(store) = self.store_if_rw() {
+            let mut store = store.lock().expect("Directory storage lock poisoned");
+            store.store_cache_latencies(&self.cache_latency.estimates())?;
+            let now = (self.runtime.now(), self.runtime.wallclock());
+            store.store_source_reputation(&save_cache_errors(self.cache_errors.errors(), now))?;
+            store.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Throw away the microdescriptor with digest `digest`, so that we
+    /// download it again.
+    ///
+    /// Use this if you think that a microdescriptor we have is corrupt or
+    /// out of date.  We remove it from our cache (if our cache is
+    /// read-write) and from our current directory, so that it's missing.  If
+    /// our current directory listed it, our download task then fetches it
+    /// again right away, without waiting for our next refresh.  Until then,
+    /// we can't use the relay that it describes.
+    pub fn invalidate_microdesc(&self, digest: &MdDigest) -> Result<()> {
+        if let Some(store) = self.store_if_rw() {
+            store
+                .lock()
+                .expect("Directory storage lock poisoned")
+                .delete_microdescs(&[*digest])?;
+        }
+        let forgot = match self
+            .netdir
+            .mutate(|netdir| Ok(netdir.forget_microdesc(digest)))
+        {
+            Ok(forgot) => forgot,
+            Err(Error::DirectoryNotPresent) => false,
+            Err(e) => return Err(e),
+        };
+        if forgot {
+            self.events.publish(DirEvent::NewDescriptors);
+            self.invalidated_microdescs
+                .lock()
+                .expect("poisoned lock")
+                .push(*digest);
+            let mut sender = self.send_invalidated.lock().expect("poisoned lock");
+            *sender.borrow_mut() += 1;
+        }
+        Ok(())
+    }
+
+    /// Mark every microdescriptor that somebody has invalidated as missing
+    /// in `state`, so that we fetch it again.
+    fn apply_invalidations(&self, state: &mut dyn DirState) {
+        let digests =
+            std::mem::take(&mut *self.invalidated_microdescs.lock().expect("poisoned lock"));
+        if !digests.is_empty() {
+            state.refetch_microdescs(&digests);
+        }
+    }
+
+    /// Put the documents from `embedded` into our cache, so that we can
+    /// bootstrap from them without downloading anything that hasn't changed.
+    ///
+    /// Call this after creating the `DirMgr`, and before bootstrapping or
+    /// loading it.  If our cache already has a consensus at least as recent
+    /// as the embedded one, we keep that consensus instead.  If our cache is
+    /// read-only, this does nothing.
+    pub fn seed_from_embedded(&self, embedded: &EmbeddedDirectory) -> Result<()> {
+        if let Some(store) = self.store_if_rw() {
+            let mut store = store.lock().expect("Directory storage lock poisoned");
+            embedded::seed_store(&mut **store, embedded, self.trusted_now())?;
+        }
+        Ok(())
+    }
+
+    /// Ask up to `n` directory caches, chosen at random, whether they have a
+    /// newer consensus than ours, and report how each one answered.
+    ///
+    /// We choose among the caches in our current directory if we have one,
+    /// and among our fallback caches otherwise, subject to our cache filter
+    /// (see [`DirMgr::set_cache_filter`]).  A working cache answers with a
+    /// consensus diff, or with a "not modified" status if it has nothing
+    /// newer; if we don't have a consensus yet, though, it will send a
+    /// whole one.
+    ///
+    /// The answers are discarded: probing doesn't change our directory,
+    /// our cache, or the way we choose caches for real downloads.
+    pub async fn probe_caches(&self, n: usize) -> Result<Vec<CacheProbe>> {
+        use rand::seq::SliceRandom;
+
+        let request = self.make_consensus_request(self.config.get().consensus_flavor())?;
+        let netdir = self.opt_netdir();
+        let config = self.config.get();
+        let caches = self.eligible_caches(netdir.as_deref(), config.fallbacks());
+        let probes = caches
+            .choose_multiple(&mut rand::thread_rng(), n)
+            .map(|cache| bootstrap::probe_cache(self, cache, &request));
+        Ok(futures::future::join_all(probes).await)
+    }
+
+    /// Return the directory caches that we would currently choose among when
+    /// downloading documents, along with what we know about each one.
+    ///
+    /// If we have a directory, these are the relays that it lists as
+    /// directory caches; otherwise, they are our configured fallback
+    /// caches.  Either way, only the caches that our cache filter allows
+    /// (see [`DirMgr::set_cache_filter`]) are listed.
+    ///
+    /// Configured directory mirrors aren't included: we only ask them over
+    /// a direct connection, once the caches here have failed us.
+    pub fn candidate_dir_caches(&self) -> Vec<DirCacheCandidate> {
+        let netdir = self.opt_netdir();
+        let config = self.config.get();
+        let caches = self.eligible_caches(netdir.as_deref(), config.fallbacks());
+        let next = match netdir {
+            Some(_) => None,
+            None => self.current_fallback_index(&caches).map(|(idx, _)| idx),
+        };
+        caches
+            .into_iter()
+            .enumerate()
+            .map(|(idx, cache)| DirCacheCandidate {
+                latency: self.cache_latency.estimate(cache.rsa_identity()),
+                next: Some(idx) == next,
+                cache,
+            })
+            .collect()
+    }
+
+    /// Download a fresh consensus and the authority certificates that sign
+    /// it, and check them the same way we would when bootstrapping, but
+    /// without using them.
+    ///
+    /// Nothing we download is written to our cache, and our current
+    /// directory (if any) is left alone.  The returned [`VerifyReport`] says
+    /// whether the consensus was correctly signed, and what went wrong
+    /// along the way.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if this `DirMgr` is in offline mode, or if we can't
+    /// download anything at all.
+    pub async fn verify_only(self: &Arc<Self>) -> Result<VerifyReport> {
+        if self.offline {
+            return Err(Error::OfflineMode);
+        }
+        verify::verify_only(self).await
+    }
+
+    /// Download the consensus that became valid at `valid_after`, check it
+    /// as we would have at that time, and return it.
+    ///
+    /// Directory caches only serve the most recent consensus they have, so
+    /// we ask for one that is no older than `valid_after`, and accept it
+    /// only if it was valid at that time.  In practice, this works for a
+    /// consensus that is current or was replaced recently, depending on
+    /// how up-to-date the cache is.
+    ///
+    /// Nothing we download is written to our cache, and our current
+    /// directory (if any) is left alone.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if this `DirMgr` is in offline mode, if no cache
+    /// gave us a consensus that was valid at `valid_after`, or if we
+    /// couldn't check the signatures on it.
+    pub async fn fetch_consensus_at(
+        self: &Arc<Self>,
+        valid_after: SystemTime,
+    ) -> Result<MdConsensus> {
+        if self.offline {
+            return Err(Error::OfflineMode);
+        }
+        verify::fetch_consensus_at(self, valid_after).await
+    }
+
+    /// Download the current votes of the directory authorities whose
+    /// identities are in `authorities`, and store them in our cache.
+    ///
+    /// Once this returns, each vote can be read with [`DirMgr::text`], using
+    /// [`DocId::AuthVote`].  Votes carry the authorities' shared-random
+    /// commitments and reveals too.  We don't check the signatures on the
+    /// votes: they're for callers that want to check the consensus process
+    /// for themselves.
+    ///
+    /// Only available when the `votes` feature is present.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if this `DirMgr` is in offline mode, or if we
+    /// couldn't get every vote that we asked for.
+    #[cfg(feature = "votes")]
+    pub async fn fetch_votes(self: &Arc<Self>, authorities: &[RsaIdentity]) -> Result<()> {
+        if self.offline {
+            return Err(Error::OfflineMode);
+        }
+        let (status, phase) = {
+            let bootstrap_status = self.receive_status.inner.borrow();
+            (bootstrap_status.current.clone(), bootstrap_status.phase())
+        };
+        let state = state::GetVotesState::new(Arc::downgrade(self), authorities, status, phase);
+        let mut on_usable = None;
+        let (_, err) =
+            bootstrap::download(Arc::downgrade(self), Box::new(state), &mut on_usable).await?;
+        match err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Download the router descriptors whose digests are in `digests`,
+    /// and store them in our cache.
+    ///
+    /// Use this to fetch just enough router descriptors for what you're
+    /// doing (for example, the ones for the relays on a path that you want
+    /// to build), rather than every router descriptor in the network.  We
+    /// only ask for the ones that aren't in our cache already, in batches
+    /// like the ones we use for microdescriptors.  Once this returns, each
+    /// router descriptor can be read with [`DirMgr::text`], using
+    /// [`DocId::RouterDesc`].
+    ///
+    /// Only available when the `routerdesc` feature is present.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if this `DirMgr` is in offline mode, or if we
+    /// couldn't get every router descriptor that we asked for.
+    #[cfg(feature = "routerdesc")]
+    pub async fn fetch_routerdescs(
+        self: &Arc<Self>,
+        digests: &[tor_netdoc::doc::routerdesc::RdDigest],
+    ) -> Result<()> {
+        if self.offline {
+            return Err(Error::OfflineMode);
+        }
+        let (status, phase) = {
+            let bootstrap_status = self.receive_status.inner.borrow();
+            (bootstrap_status.current.clone(), bootstrap_status.phase())
+        };
+        let state = state::GetRouterDescsState::new(Arc::downgrade(self), digests, status, phase);
+        let mut on_usable = None;
+        let (_, err) =
+            bootstrap::download(Arc::downgrade(self), Box::new(state), &mut on_usable).await?;
+        match err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Return a new asynchronous stream that yields every microdescriptor
+    /// that we validate from now on, as we add it to our directory.
+    ///
+    /// Microdescriptors come out of this stream as soon as we've checked
+    /// them against our consensus, whether we downloaded them or loaded
+    /// them from our cache: this can be well before the directory that
+    /// lists them is usable, so it lets a caller build up its own view of
+    /// the relays a piece at a time.  (For the directory as a whole, wait
+    /// for a [`DirEvent`] and use [`DirMgr::netdir`].)
+    ///
+    /// The stream buffers as many microdescriptors as it needs to, so
+    /// callers should keep reading from it, or drop it.
+    pub fn microdescs(&self) -> impl futures::Stream<Item = Microdesc> + Unpin {
+        let (send, receive) = mpsc::unbounded();
+        self.microdesc_senders
+            .lock()
+            .expect("Poisoned lock")
+            .push(send);
+        receive
+    }
+
+    /// Send `md` to everybody who has asked for a stream of
+    /// microdescriptors, forgetting about any streams that have been
+    /// dropped.
+    fn publish_microdesc(&self, md: &Microdesc) {
+        self.microdesc_senders
+            .lock()
+            .expect("Poisoned lock")
+            .retain(|send| send.unbounded_send(md.clone()).is_ok());
+    }
+
+    /// Return a new asynchronous stream that will receive notification
+    /// whenever the consensus has changed.
+    ///
+    /// Multiple events may be batched up into a single item: each time
+    /// this stream yields an event, all you can assume is that the event has
+    /// occurred at least once.
+    pub fn events(&self) -> impl futures::Stream<Item = DirEvent> {
+        self.events.subscribe()
+    }
+
+    /// Wait until we install a new network directory, and return it.
+    ///
+    /// This resolves on the first [`DirEvent::NewConsensus`] or
+    /// [`DirEvent::NewDescriptors`] after this future is first polled,
+    /// provided that we have a directory to return.  Any directory we
+    /// already had when we started waiting does not count.
+    ///
+    /// This is a simpler alternative to [`DirMgr::events`] for callers that
+    /// only want the next directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TimeoutError`] if no new directory arrives within `timeout`.
+    pub async fn wait_for_netdir_change(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<Arc<NetDir>, TimeoutError> {
+        let mut events = self.events();
+        let wait = async {
+            while let Some(event) = events.next().await {
+                if matches!(event, DirEvent::NewConsensus | DirEvent::NewDescriptors) {
+                    if let Some(netdir) = self.opt_netdir() {
+                        return netdir;
+                    }
+                }
+            }
+            // The publisher lives as long as we do, so the stream can't end
+            // while we're borrowed. Just in case, let the timeout decide.
+            futures::future::pending().await
+        };
+        self.runtime.timeout(timeout, wait).await
+    }
+
+    /// Try to load the text of a single document described by `doc` from
+    /// storage.
+    pub fn text(&self, doc: &DocId) -> Result<Option<DocumentText>> {
+        use itertools::Itertools;
+        let mut result = HashMap::new();
+        let query = (*doc).into();
+        self.load_documents_into(&query, &mut result)?;
+        let item = result.into_iter().at_most_one().map_err(|_| {
             Error::CacheCorruption("Found more than one entry in storage for given docid")
         })?;
         if let Some((docid, doctext)) = item {
@@ -719,8 +2136,15 @@ impl<R: Runtime> DirMgr<R> {
     {
         let partitioned = docid::partition_by_type(docs);
         let mut result = HashMap::new();
+        // Every query goes to the same sqlite connection, so there would be
+        // nothing to gain from running them concurrently.  Instead, we take
+        // the lock once, and run them all while we hold it.
+        let mut store = self.store.lock().expect("Directory storage lock poisoned");
         for (_, query) in partitioned.into_iter() {
-            self.load_documents_into(&query, &mut result)?;
+            load_documents_from_store(&**store, &query, &mut result)?;
+        }
+        if self.config.get().check_cache_integrity() {
+            discard_corrupt_documents(&mut **store, &mut result)?;
         }
         Ok(result)
     }
@@ -731,48 +2155,10 @@ impl<R: Runtime> DirMgr<R> {
         query: &DocQuery,
         result: &mut HashMap<DocId, DocumentText>,
     ) -> Result<()> {
-        use DocQuery::*;
-        let store = self.store.lock().expect("Directory storage lock poisoned");
-        match query {
-            LatestConsensus {
-                flavor,
-                cache_usage,
-            } => {
-                if *cache_usage == CacheUsage::MustDownload {
-                    // Do nothing: we don't want a cached consensus.
-                    trace!("MustDownload is set; not checking for cached consensus.");
-                } else if let Some(c) =
-                    store.latest_consensus(*flavor, cache_usage.pending_requirement())?
-                {
-                    trace!("Found a reasonable consensus in the cache");
-                    let id = DocId::LatestConsensus {
-                        flavor: *flavor,
-                        cache_usage: *cache_usage,
-                    };
-                    result.insert(id, c.into());
-                }
-            }
-            AuthCert(ids) => result.extend(
-                store
-                    .authcerts(ids)?
-                    .into_iter()
-                    .map(|(id, c)| (DocId::AuthCert(id), DocumentText::from_string(c))),
-            ),
-            Microdesc(digests) => {
-                result.extend(
-                    store
-                        .microdescs(digests)?
-                        .into_iter()
-                        .map(|(id, md)| (DocId::Microdesc(id), DocumentText::from_string(md))),
-                );
-            }
-            #[cfg(feature = "routerdesc")]
-            RouterDesc(digests) => result.extend(
-                store
-                    .routerdescs(digests)?
-                    .into_iter()
-                    .map(|(id, rd)| (DocId::RouterDesc(id), DocumentText::from_string(rd))),
-            ),
+        let mut store = self.store.lock().expect("Directory storage lock poisoned");
+        load_documents_from_store(&**store, query, result)?;
+        if self.config.get().check_cache_integrity() {
+            discard_corrupt_documents(&mut **store, result)?;
         }
         Ok(())
     }
@@ -799,6 +2185,10 @@ impl<R: Runtime> DirMgr<R> {
                 DocQuery::RouterDesc(ids) => {
                     res.push(ClientRequest::RouterDescs(ids.into_iter().collect()));
                 }
+                #[cfg(feature = "votes")]
+                DocQuery::AuthVote(ids) => {
+                    res.push(ClientRequest::AuthVotes(ids.into_iter().collect()));
+                }
             }
         }
         Ok(res)
@@ -825,14 +2215,62 @@ impl<R: Runtime> DirMgr<R> {
         Ok(ClientRequest::Consensus(request))
     }
 
+    /// Construct a ClientRequest to download a consensus of the given
+    /// flavor that became valid no earlier than `valid_after`.
+    ///
+    /// We say that our latest consensus is from just before `valid_after`,
+    /// so that a cache without anything that recent will decline.  We don't
+    /// offer any consensus digests, since we want a whole consensus and not
+    /// a diff.
+    fn make_consensus_request_at(
+        &self,
+        flavor: ConsensusFlavor,
+        valid_after: SystemTime,
+    ) -> Result<ClientRequest> {
+        let last = valid_after
+            .checked_sub(Duration::from_secs(1))
+            .ok_or_else(|| bad_api_usage!("valid-after time {:?} out of range", valid_after))?;
+        let mut request = tor_dirclient::request::ConsensusRequest::new(flavor);
+        request.set_last_consensus_date(last);
+        Ok(ClientRequest::Consensus(request))
+    }
+
     /// Given a request we sent and the response we got from a
     /// directory server, see whether we should expand that response
-    /// into "something larger".
+    /// into "something larger", and return it as text.
     ///
-    /// Currently, this handles expanding consensus diffs, and nothing
-    /// else.  We do it at this stage of our downloading operation
-    /// because it requires access to the store.
-    fn expand_response_text(&self, req: &ClientRequest, text: String) -> Result<String> {
+    /// Currently, this handles undoing any content-encoding that we
+    /// left for a codec from [`DirMgr::register_codec`], and expanding
+    /// consensus diffs.  We do it at this stage of our downloading
+    /// operation because it requires access to the store.
+    fn expand_response_text(
+        &self,
+        req: &ClientRequest,
+        response: tor_dirclient::DirResponse,
+    ) -> Result<String> {
+        let body = match response.encoding() {
+            Some(encoding) => {
+                let codec = self
+                    .codecs
+                    .lock()
+                    .expect("Poisoned lock")
+                    .get(encoding)
+                    .cloned()
+                    .ok_or_else(|| tor_dirclient::Error::ContentEncoding(encoding.into()))?;
+                let body = codec(response.output()).map_err(|e| Error::ContentDecoding {
+                    encoding: encoding.into(),
+                    cause: Arc::new(e),
+                })?;
+                let config = self.config.get();
+                let max_len = bootstrap::max_response_len(config.schedule(), req);
+                if body.len() > max_len {
+                    return Err(tor_dirclient::Error::ResponseTooLong(body.len()).into());
+                }
+                body
+            }
+            None => response.into_output(),
+        };
+        let text = String::from_utf8(body).map_err(Error::BadUtf8FromDirectory)?;
         if let ClientRequest::Consensus(req) = req {
             if tor_consdiff::looks_like_diff(&text) {
                 if let Some(old_d) = req.old_consensus_digests().next() {
@@ -856,8 +2294,226 @@ impl<R: Runtime> DirMgr<R> {
                 ));
             }
         }
-        Ok(text)
+        Ok(text)
+    }
+}
+
+impl<R: Runtime> Drop for DirMgr<R> {
+    fn drop(&mut self) {
+        // Don't risk a second panic if we're already unwinding with the
+        // store's lock poisoned.
+        if std::thread::panicking() {
+            return;
+        }
+        if let Err(e) = self.flush() {
+            warn!("Unable to flush directory cache on shutdown: {}", e);
+        }
+    }
+}
+
+/// Load all the documents for a single DocumentQuery from `store` into
+/// `result`.
+fn load_documents_from_store(
+    store: &dyn Store,
+    query: &DocQuery,
+    result: &mut HashMap<DocId, DocumentText>,
+) -> Result<()> {
+    use DocQuery::*;
+    match query {
+        LatestConsensus {
+            flavor,
+            cache_usage,
+        } => {
+            if *cache_usage == CacheUsage::MustDownload {
+                // Do nothing: we don't want a cached consensus.
+                trace!("MustDownload is set; not checking for cached consensus.");
+            } else if let Some(c) =
+                store.latest_consensus(*flavor, cache_usage.pending_requirement())?
+            {
+                trace!("Found a reasonable consensus in the cache");
+                let id = DocId::LatestConsensus {
+                    flavor: *flavor,
+                    cache_usage: *cache_usage,
+                };
+                result.insert(id, c.into());
+            }
+        }
+        AuthCert(ids) => result.extend(
+            store
+                .authcerts(ids)?
+                .into_iter()
+                .map(|(id, c)| (DocId::AuthCert(id), DocumentText::from_string(c))),
+        ),
+        Microdesc(digests) => {
+            result.extend(
+                store
+                    .microdescs(digests)?
+                    .into_iter()
+                    .map(|(id, md)| (DocId::Microdesc(id), DocumentText::from_string(md))),
+            );
+        }
+        #[cfg(feature = "routerdesc")]
+        RouterDesc(digests) => result.extend(
+            store
+                .routerdescs(digests)?
+                .into_iter()
+                .map(|(id, rd)| (DocId::RouterDesc(id), DocumentText::from_string(rd))),
+        ),
+        #[cfg(feature = "votes")]
+        AuthVote(ids) => result.extend(
+            store
+                .votes(ids)?
+                .into_iter()
+                .map(|(id, v)| (DocId::AuthVote(id), DocumentText::from_string(v))),
+        ),
+    }
+    Ok(())
+}
+
+/// Return true if `text` can't be the document that we stored under `id`.
+///
+/// We can only check documents that are named after their own contents:
+/// descriptors by their digests, and authority certificates by their keys.
+/// A consensus is checked against its authorities' signatures whenever a
+/// state loads it, so we don't check it here.
+fn is_corrupt(id: &DocId, text: &DocumentText) -> bool {
+    match id {
+        DocId::Microdesc(digest) => tor_llcrypto::d::Sha256::digest(text)[..] != digest[..],
+        DocId::AuthCert(ids) => {
+            let cert = text
+                .as_str()
+                .ok()
+                .and_then(|text| tor_netdoc::doc::authcert::AuthCert::parse(text).ok())
+                .and_then(|cert| cert.check_signature().ok());
+            match cert {
+                Some(cert) => cert.dangerously_assume_timely().key_ids() != ids,
+                None => true,
+            }
+        }
+        #[cfg(feature = "routerdesc")]
+        DocId::RouterDesc(digest) => routerdesc_digest(text.as_ref()).as_ref() != Some(digest),
+        _ => false,
+    }
+}
+
+/// Convert `saved`, a record of when each directory cache has failed us as
+/// returned by `Store::load_source_reputation`, into the form that we count
+/// errors in.  `now` is the current time on the monotonic clock and on the
+/// wall clock.
+///
+/// Errors that are already `window` old don't count any more, so we forget
+/// about them.
+fn restore_cache_errors(
+    saved: HashMap<RsaIdentity, Vec<SystemTime>>,
+    now: (Instant, SystemTime),
+    window: Duration,
+) -> HashMap<RsaIdentity, Vec<Instant>> {
+    let (now, wallclock_now) = now;
+    saved
+        .into_iter()
+        .map(|(id, times)| {
+            let times: Vec<Instant> = times
+                .into_iter()
+                .filter_map(|t| {
+                    // If the clock has gone backwards, treat the error as
+                    // having happened just now.
+                    let age = wallclock_now.duration_since(t).unwrap_or_default();
+                    if age >= window {
+                        None
+                    } else {
+                        now.checked_sub(age)
+                    }
+                })
+                .collect();
+            (id, times)
+        })
+        .filter(|(_, times)| !times.is_empty())
+        .collect()
+}
+
+/// Convert `errors`, a record of when each directory cache has failed us as
+/// returned by `CacheErrors::errors`, into a form that we can save in our
+/// store.  `now` is as for [`restore_cache_errors`].
+fn save_cache_errors(
+    errors: HashMap<RsaIdentity, Vec<Instant>>,
+    now: (Instant, SystemTime),
+) -> HashMap<RsaIdentity, Vec<SystemTime>> {
+    let (now, wallclock_now) = now;
+    errors
+        .into_iter()
+        .map(|(id, times)| {
+            let times = times
+                .into_iter()
+                .filter_map(|t| wallclock_now.checked_sub(now.saturating_duration_since(t)))
+                .collect();
+            (id, times)
+        })
+        .collect()
+}
+
+/// Compute the digest that a consensus would use to refer to the router
+/// descriptor in `text`, if `text` looks like a router descriptor.
+///
+/// This is the SHA1 digest of everything up to and including the
+/// `router-signature` line.
+#[cfg(feature = "routerdesc")]
+pub(crate) fn routerdesc_digest(text: &[u8]) -> Option<tor_netdoc::doc::routerdesc::RdDigest> {
+    /// The keyword that ends the signed part of a router descriptor.
+    const SIG_LINE: &[u8] = b"\nrouter-signature\n";
+    if !text.starts_with(b"router ") {
+        return None;
+    }
+    let end = text.windows(SIG_LINE.len()).position(|w| w == SIG_LINE)? + SIG_LINE.len();
+    Some(tor_llcrypto::d::Sha1::digest(&text[..end]).into())
+}
+
+/// Remove from `docs` every document that doesn't match the identifier
+/// that we looked it up by, and delete those documents from `store` if we
+/// can.
+///
+/// The store only holds documents that we validated before saving them, so
+/// a mismatch means that the cache itself has been damaged.
+fn discard_corrupt_documents(
+    store: &mut dyn Store,
+    docs: &mut HashMap<DocId, DocumentText>,
+) -> Result<()> {
+    let corrupt: Vec<DocId> = docs
+        .iter()
+        .filter(|(id, text)| is_corrupt(id, text))
+        .map(|(id, _)| *id)
+        .collect();
+    if corrupt.is_empty() {
+        return Ok(());
+    }
+
+    warn!(
+        "Found {} documents in our cache that don't match their identities; discarding them.",
+        corrupt.len()
+    );
+    for id in &corrupt {
+        docs.remove(id);
+    }
+    if store.is_readonly() {
+        return Ok(());
+    }
+    let mut microdescs = Vec::new();
+    let mut authcerts = Vec::new();
+    #[cfg(feature = "routerdesc")]
+    let mut routerdescs = Vec::new();
+    for id in corrupt {
+        match id {
+            DocId::Microdesc(d) => microdescs.push(d),
+            DocId::AuthCert(ids) => authcerts.push(ids),
+            #[cfg(feature = "routerdesc")]
+            DocId::RouterDesc(d) => routerdescs.push(d),
+            _ => {}
+        }
     }
+    store.delete_microdescs(&microdescs)?;
+    store.delete_authcerts(&authcerts)?;
+    #[cfg(feature = "routerdesc")]
+    store.delete_routerdescs(&routerdescs)?;
+    Ok(())
 }
 
 /// A degree of readiness for a given directory state object.
@@ -867,6 +2523,10 @@ enum Readiness {
     Complete,
     /// There is more information to download, but we don't need to
     Usable,
+    /// The directory is usable, but its consensus is past its fresh-until
+    /// time: we should start looking for a new one soon, before it stops
+    /// being valid.
+    Stale,
 }
 
 /// A "state" object used to represent our progress in downloading a
@@ -930,8 +2590,20 @@ trait DirState: Send {
         request: &ClientRequest,
         storage: Option<&Mutex<DynStore>>,
     ) -> Result<bool>;
+    /// Return how many documents have appeared more than once within a
+    /// single response given to [`add_from_download`](DirState::add_from_download)
+    /// since we last asked, and start counting again from zero.
+    ///
+    /// We take each document only once, but a cache that repeats itself is
+    /// wasting our bandwidth, and may be misbehaving.  By default, this
+    /// is always zero.
+    fn take_n_duplicates(&mut self) -> usize {
+        0
+    }
     /// Return a summary of this state as a [`DirStatus`].
     fn bootstrap_status(&self) -> event::DirStatus;
+    /// Return which part of the directory this state is working on.
+    fn bootstrap_phase(&self) -> BootstrapPhase;
 
     /// Return a configuration for attempting downloads.
     fn dl_config(&self) -> Result<DownloadSchedule>;
@@ -942,6 +2614,27 @@ trait DirState: Send {
     fn reset_time(&self) -> Option<SystemTime>;
     /// Reset this state and start over.
     fn reset(self: Box<Self>) -> Result<Box<dyn DirState>>;
+    /// Start over after failing to download what this state needs, but keep
+    /// any progress from earlier states that is still good.
+    ///
+    /// By default, this is the same as [`reset`](DirState::reset).
+    fn reset_partial(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        self.reset()
+    }
+    /// Return how many times in a row loading from the cache may change this
+    /// state without letting it advance, before we decide that it is stuck.
+    ///
+    /// States that legitimately take many steps can raise this; simple ones
+    /// can lower it, to catch a bug sooner.
+    fn max_load_iterations(&self) -> usize {
+        100
+    }
+    /// Mark the microdescriptors in `digests` as missing, so that we fetch
+    /// them again, if this state is keeping our current directory up to
+    /// date.
+    ///
+    /// By default, this does nothing.
+    fn refetch_microdescs(&mut self, _digests: &[MdDigest]) {}
 }
 
 /// Try to upgrade a weak reference to a DirMgr, and give an error on
@@ -955,21 +2648,155 @@ mod test {
     #![allow(clippy::unwrap_used)]
     use super::*;
     use crate::docmeta::{AuthCertMeta, ConsensusMeta};
+    use crate::testing::{
+        authority, authority_network, config_builder, consensus_time, fallback, fallback_network,
+        test_authorities, StubState, AUTHCERTS, CONSENSUS,
+    };
     use std::time::Duration;
     use tempfile::TempDir;
-    use tor_netdoc::doc::{authcert::AuthCertKeyIds, netstatus::Lifetime};
+    use tor_dirclient::DirResponse;
+    use tor_netdoc::doc::authcert::AuthCertKeyIds;
 
     pub(crate) fn new_mgr<R: Runtime>(runtime: R) -> (TempDir, DirMgr<R>) {
         let dir = TempDir::new().unwrap();
-        let config = DirMgrConfig::builder()
-            .cache_path(dir.path())
-            .build()
-            .unwrap();
+        let config = config_builder(dir.path()).build().unwrap();
         let dirmgr = DirMgr::from_config(config, runtime, None, false).unwrap();
 
         (dir, dirmgr)
     }
 
+    #[test]
+    fn clock_skew_out_of_range() {
+        // A skew too large to apply shouldn't make us panic: we just go on
+        // trusting the runtime's clock.
+        use tor_rtcompat::SleepProvider;
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let now = rt.wallclock();
+            let huge = Duration::from_secs(u64::MAX);
+            assert_eq!(ClockSkew::Slow(huge).correct(now), None);
+            assert_eq!(ClockSkew::Fast(huge).uncorrect(now), None);
+            let hour = Duration::from_secs(3600);
+            assert_eq!(ClockSkew::Slow(hour).correct(now), Some(now + hour));
+            assert_eq!(ClockSkew::Slow(hour).uncorrect(now), Some(now - hour));
+
+            let (_dir, mgr) = new_mgr(rt.clone());
+            mgr.set_clock_skew(Some(ClockSkew::Slow(huge)));
+            let before = rt.wallclock();
+            let trusted = mgr.trusted_now();
+            assert!(trusted >= before && trusted <= rt.wallclock());
+            assert_eq!(mgr.local_time(now), now);
+        });
+    }
+
+    #[test]
+    fn cache_latencies_persist() {
+        // What we learn about how quickly our caches answer should survive
+        // a restart.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let dir = TempDir::new().unwrap();
+            let config = config_builder(dir.path()).build().unwrap();
+            let cache = fallback(1);
+
+            let mgr = DirMgr::from_config(config.clone(), rt.clone(), None, false).unwrap();
+            mgr.note_cache_latency(&cache, Duration::from_millis(300));
+            drop(mgr);
+
+            let mgr = DirMgr::from_config(config, rt, None, false).unwrap();
+            assert_eq!(
+                mgr.cache_latency.estimate(cache.rsa_identity()),
+                Some(Duration::from_millis(300))
+            );
+        });
+    }
+
+    #[test]
+    fn cache_errors_persist() {
+        // The errors that we've counted against our caches should survive a
+        // restart, until they're too old to count.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let dir = TempDir::new().unwrap();
+            let mut sched = DownloadScheduleConfig::builder();
+            sched
+                .cache_error_threshold(2)
+                .cache_error_window(Duration::from_secs(600));
+            let config = config_builder(dir.path())
+                .schedule_config(sched.build().unwrap())
+                .build()
+                .unwrap();
+            let cache = RsaIdentity::from([0xee; 20]);
+            let response = DirResponse::from_body("").with_cache(cache);
+
+            let mgr = DirMgr::from_config(config.clone(), rt.clone(), None, false).unwrap();
+            mgr.note_cache_error(response.source());
+            drop(mgr);
+
+            // After a restart, we still count the error...
+            rt.advance(Duration::from_secs(60)).await;
+            let mgr = DirMgr::from_config(config.clone(), rt.clone(), None, false).unwrap();
+            assert_eq!(mgr.cache_errors.errors()[&cache].len(), 1);
+            drop(mgr);
+
+            // ... but not once it's too old to count.
+            rt.advance(Duration::from_secs(600)).await;
+            let mgr = DirMgr::from_config(config, rt, None, false).unwrap();
+            assert!(mgr.cache_errors.errors().is_empty());
+        });
+    }
+
+    #[test]
+    fn cache_error_threshold() {
+        // Make sure that we only give up on a cache once it has failed us
+        // often enough within the configured window.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let dir = TempDir::new().unwrap();
+            let mut sched = DownloadScheduleConfig::builder();
+            sched
+                .cache_error_threshold(2)
+                .cache_error_window(Duration::from_secs(60));
+            let config = config_builder(dir.path())
+                .schedule_config(sched.build().unwrap())
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+            let config = mgr.config.get();
+            let fallback = mgr.next_fallback.load(Ordering::SeqCst);
+            let current = *mgr.current_fallback(config.fallbacks())[0].rsa_identity();
+            let from_cache = |id: RsaIdentity| DirResponse::from_body("").with_cache(id);
+            let cache1 = from_cache(current);
+            let cache2 = from_cache(RsaIdentity::from([0xee; 20]));
+
+            // Errors from a cache that we can't identify don't count.
+            for _ in 0..3 {
+                mgr.note_cache_error(None);
+            }
+            assert_eq!(mgr.next_fallback.load(Ordering::SeqCst), fallback);
+
+            // One error isn't enough.
+            mgr.note_cache_error(cache1.source());
+            assert_eq!(mgr.next_fallback.load(Ordering::SeqCst), fallback);
+
+            // Nor is a second one, once the first has expired.
+            rt.advance(Duration::from_secs(90)).await;
+            mgr.note_cache_error(cache1.source());
+            assert_eq!(mgr.next_fallback.load(Ordering::SeqCst), fallback);
+
+            // But a third one within the window of the second is.
+            rt.advance(Duration::from_secs(30)).await;
+            // (Errors from some other cache don't count towards it.)
+            mgr.note_cache_error(cache2.source());
+            assert_eq!(mgr.next_fallback.load(Ordering::SeqCst), fallback);
+            mgr.note_cache_error(cache1.source());
+            let fallback = fallback.wrapping_add(1);
+            assert_eq!(mgr.next_fallback.load(Ordering::SeqCst), fallback);
+
+            // After that, we start counting again.
+            mgr.note_cache_error(cache1.source());
+            assert_eq!(mgr.next_fallback.load(Ordering::SeqCst), fallback);
+        });
+    }
+
     #[test]
     fn failing_accessors() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
@@ -980,6 +2807,247 @@ mod test {
         });
     }
 
+    #[test]
+    fn rotate_fallbacks() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let fallbacks = vec![fallback(1), fallback(2)];
+            let dir = TempDir::new().unwrap();
+            let config = config_builder(dir.path())
+                .network_config(fallback_network(fallbacks.clone()))
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt, None, false).unwrap();
+            let config = mgr.config.get();
+
+            // With no directory, our first request goes to exactly one of
+            // the fallbacks we configured.
+            let first = mgr.current_fallback(config.fallbacks());
+            assert_eq!(first.len(), 1);
+            assert!(fallbacks.contains(&first[0]));
+            // We keep using it until it fails...
+            assert_eq!(mgr.current_fallback(config.fallbacks()), first);
+
+            // ... and then we move on to the other one, and back again.
+            let first_id = *first[0].rsa_identity();
+            mgr.note_fallback_failed(&first_id);
+            let second = mgr.current_fallback(config.fallbacks());
+            assert_eq!(second.len(), 1);
+            assert_ne!(second, first);
+            assert!(fallbacks.contains(&second[0]));
+
+            // Another failure from the first one, reported late, doesn't
+            // make us skip the second.
+            mgr.note_fallback_failed(&first_id);
+            assert_eq!(mgr.current_fallback(config.fallbacks()), second);
+
+            mgr.note_fallback_failed(second[0].rsa_identity());
+            assert_eq!(mgr.current_fallback(config.fallbacks()), first);
+
+            // An empty list gives us nothing to ask.
+            assert!(mgr.current_fallback(&[]).is_empty());
+        });
+    }
+
+    #[test]
+    fn cache_filter() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            let config = mgr.config.get();
+
+            // With no filter, we don't interfere with the usual choice.
+            assert!(mgr
+                .filtered_caches(Some(&netdir), config.fallbacks())
+                .is_none());
+
+            // Allow only one of the directory caches.
+            let chosen = netdir.relays().find(|r| r.is_dir_cache()).unwrap();
+            let chosen_id = *chosen.rsa_id();
+            mgr.set_cache_filter(move |c| c.rsa_identity() == &chosen_id);
+
+            // Every request we make, with a directory or without one, has
+            // only that cache to choose from.
+            for _ in 0..5 {
+                let caches = mgr
+                    .filtered_caches(Some(&netdir), config.fallbacks())
+                    .unwrap();
+                assert_eq!(caches.len(), 1);
+                assert_eq!(caches[0].rsa_identity(), &chosen_id);
+                assert_eq!(caches[0].addrs(), chosen.addrs());
+            }
+
+            // None of our fallbacks is that relay, so without a directory
+            // we have nobody to ask.
+            let caches = mgr.filtered_caches(None, config.fallbacks()).unwrap();
+            assert!(caches.is_empty());
+            assert!(mgr.current_fallback(&caches).is_empty());
+
+            // A filter that allows one of our fallbacks keeps us on it, even
+            // after it fails.
+            let fallback_id = *config.fallbacks()[0].rsa_identity();
+            mgr.set_cache_filter(move |c| c.rsa_identity() == &fallback_id);
+            for _ in 0..5 {
+                let caches = mgr.filtered_caches(None, config.fallbacks()).unwrap();
+                let current = mgr.current_fallback(&caches);
+                assert_eq!(current.len(), 1);
+                assert_eq!(current[0].rsa_identity(), &fallback_id);
+                mgr.note_fallback_failed(&fallback_id);
+            }
+        });
+    }
+
+    #[test]
+    fn candidate_dir_caches() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let config = mgr.config.get();
+
+            // Without a directory, we choose among our fallbacks, and we know
+            // which one we'll ask next.
+            let fallback_id = *config.fallbacks()[0].rsa_identity();
+            mgr.note_cache_latency(&config.fallbacks()[0], Duration::from_secs(3));
+            let candidates = mgr.candidate_dir_caches();
+            assert_eq!(candidates.len(), config.fallbacks().len());
+            let next: Vec<_> = candidates.iter().filter(|c| c.is_next()).collect();
+            assert_eq!(next.len(), 1);
+            assert_eq!(
+                next[0].cache(),
+                &mgr.current_fallback(config.fallbacks())[0]
+            );
+            for c in &candidates {
+                if c.cache().rsa_identity() == &fallback_id {
+                    assert_eq!(c.latency(), Some(Duration::from_secs(3)));
+                } else {
+                    assert_eq!(c.latency(), None);
+                }
+            }
+
+            // With a directory, we choose among the relays that it lists as
+            // directory caches: in this network, the even-numbered ones.
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            mgr.netdir.replace(netdir);
+            let candidates = mgr.candidate_dir_caches();
+            let mut ids: Vec<_> = candidates
+                .iter()
+                .map(|c| *c.cache().rsa_identity())
+                .collect();
+            ids.sort();
+            let expected: Vec<RsaIdentity> = (0..40_u8)
+                .filter(|idx| idx % 2 == 0)
+                .map(|idx| [idx; 20].into())
+                .collect();
+            assert_eq!(ids, expected);
+            assert!(candidates.iter().all(|c| !c.is_next()));
+
+            // Our cache filter narrows down the list.
+            let chosen: RsaIdentity = [4; 20].into();
+            mgr.set_cache_filter(move |c| c.rsa_identity() == &chosen);
+            let candidates = mgr.candidate_dir_caches();
+            assert_eq!(candidates.len(), 1);
+            assert_eq!(candidates[0].cache().rsa_identity(), &chosen);
+        });
+    }
+
+    #[test]
+    fn wait_for_netdir_change() {
+        use tor_rtcompat::SleepProvider;
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            let netdir = || {
+                tor_netdir::testnet::construct_netdir()
+                    .unwrap()
+                    .unwrap_if_sufficient()
+                    .unwrap()
+            };
+
+            // Nothing changes, so we give up once the timeout elapses, even
+            // though we already have a directory.
+            mgr.netdir.replace(netdir());
+            let outcome = rt
+                .wait_for(mgr.wait_for_netdir_change(Duration::from_secs(60)))
+                .await;
+            assert_eq!(outcome.err(), Some(TimeoutError));
+
+            // Events that don't install a directory don't wake us either.
+            let (outcome, ()) = rt
+                .wait_for(futures::future::join(
+                    mgr.wait_for_netdir_change(Duration::from_secs(60)),
+                    async {
+                        rt.sleep(Duration::from_secs(10)).await;
+                        mgr.events
+                            .publish(DirEvent::Reset(DirResetReason::Scheduled));
+                    },
+                ))
+                .await;
+            assert_eq!(outcome.err(), Some(TimeoutError));
+
+            // A new directory arriving before the timeout wakes us up.
+            let (outcome, ()) = rt
+                .wait_for(futures::future::join(
+                    mgr.wait_for_netdir_change(Duration::from_secs(60)),
+                    async {
+                        rt.sleep(Duration::from_secs(10)).await;
+                        mgr.netdir.replace(netdir());
+                        mgr.events.publish(DirEvent::NewConsensus);
+                    },
+                ))
+                .await;
+            let new_netdir = outcome.unwrap();
+            assert!(Arc::ptr_eq(&new_netdir, &mgr.netdir().unwrap()));
+        });
+    }
+
+    #[test]
+    fn netdir_observer() {
+        // When we replace our directory, our observer hears about the new
+        // one, and what changed.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use crate::state::WriteNetDir;
+            use tor_llcrypto::pk::rsa::RsaIdentity;
+            let (_tempdir, mgr) = new_mgr(rt);
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let seen2 = Arc::clone(&seen);
+            mgr.set_observer(DirObserver::new().on_netdir_replaced(move |netdir, diff| {
+                let n_relays = netdir.relays().count();
+                seen2.lock().unwrap().push((n_relays, diff.clone()));
+            }));
+
+            // Our first directory doesn't list relay 5; our second one does.
+            let first = tor_netdir::testnet::construct_custom_netdir(|idx, nb| {
+                if idx == 5 {
+                    nb.omit_rs = true;
+                }
+            })
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+            let second = tor_netdir::testnet::construct_netdir()
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            mgr.install_netdir(first);
+            mgr.install_netdir(second);
+
+            let seen = seen.lock().unwrap();
+            assert_eq!(seen.len(), 2);
+            // Everything in our first directory is new.
+            assert_eq!(seen[0].0, 39);
+            assert_eq!(seen[0].1.added().len(), 39);
+            // The second one only adds relay 5.
+            assert_eq!(seen[1].0, 40);
+            assert_eq!(seen[1].1.added(), &[RsaIdentity::from([5; 20])]);
+            assert!(seen[1].1.removed().is_empty());
+            assert!(seen[1].1.flags_changed().is_empty());
+        });
+    }
+
     #[test]
     fn load_and_store_internals() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
@@ -1092,6 +3160,32 @@ mod test {
                 res.get(&DocId::RouterDesc(d5)).unwrap().as_str(),
                 Ok("Fake rd2")
             );
+
+            // Loading a mixed set all at once gives the same answers as
+            // loading each document on its own.
+            let consensus_id = DocId::LatestConsensus {
+                flavor: ConsensusFlavor::Microdesc,
+                cache_usage: CacheUsage::CacheOkay,
+            };
+            let ids = vec![
+                consensus_id,
+                DocId::Microdesc(d1),
+                DocId::Microdesc(d3),
+                d_bogus,
+                DocId::AuthCert(certid1),
+                DocId::AuthCert(certid2),
+                #[cfg(feature = "routerdesc")]
+                DocId::RouterDesc(d5),
+            ];
+            let res = mgr.texts(ids.clone()).unwrap();
+            assert_eq!(res.len(), ids.len() - 1);
+            for id in &ids {
+                let one = mgr.text(id).unwrap();
+                assert_eq!(
+                    res.get(id).map(DocumentText::as_str),
+                    one.as_ref().map(DocumentText::as_str)
+                );
+            }
         });
     }
 
@@ -1144,6 +3238,148 @@ mod test {
                 }
                 _ => panic!("Wrong request type"),
             }
+
+            // Asking for a particular time ignores what we have, and never
+            // asks for a diff.
+            let yesterday = now - Duration::from_secs(86400);
+            let req = mgr
+                .make_consensus_request_at(ConsensusFlavor::Microdesc, yesterday)
+                .unwrap();
+            match req {
+                ClientRequest::Consensus(r) => {
+                    assert_eq!(r.old_consensus_digests().count(), 0);
+                    assert_eq!(
+                        r.last_consensus_date(),
+                        Some(yesterday - Duration::from_secs(1))
+                    );
+                }
+                _ => panic!("Wrong request type"),
+            }
+        });
+    }
+
+    #[test]
+    fn configured_consensus_flavor() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let dir = TempDir::new().unwrap();
+
+            // We can't build a directory from an ns consensus, so we don't
+            // let anybody ask for one.
+            let err = config_builder(dir.path())
+                .consensus_flavor(ConsensusFlavor::Ns)
+                .build()
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                tor_config::ConfigBuildError::Invalid { ref field, .. } if field == "consensus_flavor"
+            ));
+
+            // By default, the state asks for a microdesc consensus...
+            let config = config_builder(dir.path()).build().unwrap();
+            assert_eq!(config.consensus_flavor(), ConsensusFlavor::Microdesc);
+            let mgr = Arc::new(DirMgr::from_config(config, rt, None, false).unwrap());
+            let state =
+                state::GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap();
+            let missing = state.missing_docs();
+            assert_eq!(
+                missing,
+                vec![DocId::LatestConsensus {
+                    flavor: ConsensusFlavor::Microdesc,
+                    cache_usage: CacheUsage::CacheOkay,
+                }]
+            );
+
+            // ... and so that's what we request.
+            let mut requests = Vec::new();
+            for (_type, query) in docid::partition_by_type(missing.into_iter()) {
+                requests.extend(mgr.query_into_requests(query).unwrap());
+            }
+            assert_eq!(requests.len(), 1);
+            match &requests[0] {
+                ClientRequest::Consensus(r) => assert_eq!(r.flavor(), ConsensusFlavor::Microdesc),
+                _ => panic!("Wrong request type"),
+            }
+
+            // A request for some other flavor says so.
+            match mgr.make_consensus_request(ConsensusFlavor::Ns).unwrap() {
+                ClientRequest::Consensus(r) => assert_eq!(r.flavor(), ConsensusFlavor::Ns),
+                _ => panic!("Wrong request type"),
+            }
+        });
+    }
+
+    #[test]
+    fn consensus_lifetime() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+
+            // Nothing in the store: no lifetime.
+            assert!(mgr.consensus_lifetime().is_none());
+
+            let now = SystemTime::now();
+            let tomorrow = now + Duration::from_secs(86400);
+            let later = tomorrow + Duration::from_secs(86400);
+            {
+                let mut store = mgr.store.lock().unwrap();
+                let cmeta = ConsensusMeta::new(
+                    Lifetime::new(now, tomorrow, later).unwrap(),
+                    [42; 32],
+                    [103; 32],
+                );
+                store
+                    .store_consensus(&cmeta, ConsensusFlavor::Microdesc, false, "Fake consensus!")
+                    .unwrap();
+            }
+
+            let lifetime = mgr.consensus_lifetime().unwrap();
+            assert_eq!(lifetime.valid_after(), now);
+            assert_eq!(lifetime.fresh_until(), tomorrow);
+            assert_eq!(lifetime.valid_until(), later);
+        });
+    }
+
+    #[test]
+    fn custom_authorities() {
+        // On a private network, a consensus validates if we're configured
+        // with that network's authorities, and not otherwise.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            rt.jump_to(consensus_time());
+
+            let try_authorities = |authorities: Option<Vec<Authority>>| {
+                let tempdir = TempDir::new().unwrap();
+                let mut netcfg = NetworkConfig::builder();
+                if let Some(authorities) = authorities {
+                    netcfg.fallback_caches(vec![]).authorities(authorities);
+                }
+                let config = config_builder(tempdir.path())
+                    .network_config(netcfg.build().unwrap())
+                    .build()
+                    .unwrap();
+                let mgr = Arc::new(DirMgr::from_config(config, rt.clone(), None, false).unwrap());
+                let mut state =
+                    state::GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay)
+                        .unwrap();
+                let req = ClientRequest::Consensus(tor_dirclient::request::ConsensusRequest::new(
+                    ConsensusFlavor::Microdesc,
+                ));
+                state.add_from_download(CONSENSUS, &req, None)
+            };
+
+            // The authorities that signed this consensus.
+            let outcome = try_authorities(Some(test_authorities()));
+            assert!(outcome.unwrap());
+
+            // Some other network's authorities.
+            let outcome = try_authorities(Some(vec![
+                authority("0123456789ABCDEF0123456789ABCDEF01234567"),
+                authority("89ABCDEF0123456789ABCDEF0123456789ABCDEF"),
+            ]));
+            assert!(matches!(outcome, Err(Error::UnrecognizedAuthorities)));
+
+            // The authorities of the real Tor network.
+            let outcome = try_authorities(None);
+            assert!(matches!(outcome, Err(Error::UnrecognizedAuthorities)));
         });
     }
 
@@ -1190,6 +3426,143 @@ mod test {
         });
     }
 
+    #[test]
+    fn microdesc_requests_sorted() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use rand::seq::SliceRandom;
+            use rand::Rng;
+            let (_tempdir, mgr) = new_mgr(rt);
+
+            let mut rng = rand::thread_rng();
+            let mut md_ids: Vec<[u8; 32]> = (0..1234).map(|_| rng.gen()).collect();
+            let batches = |ids: Vec<[u8; 32]>| -> Vec<Vec<[u8; 32]>> {
+                mgr.query_into_requests(DocQuery::Microdesc(ids))
+                    .unwrap()
+                    .into_iter()
+                    .map(|req| match req {
+                        ClientRequest::Microdescs(r) => r.digests().copied().collect(),
+                        _ => panic!("Wrong type."),
+                    })
+                    .collect()
+            };
+
+            // Each batch is sorted, and the batches follow one another in
+            // digest order.
+            let first = batches(md_ids.clone());
+            assert_eq!(first.len(), 3);
+            let flat: Vec<_> = first.iter().flatten().copied().collect();
+            assert_eq!(flat.len(), 1234);
+            assert!(flat.windows(2).all(|w| w[0] < w[1]));
+
+            // The order we asked in doesn't matter: the same set of ids
+            // always gives the same batches.
+            md_ids.shuffle(&mut rng);
+            assert_eq!(batches(md_ids.clone()), first);
+            md_ids.reverse();
+            assert_eq!(batches(md_ids), first);
+        });
+    }
+
+    /// A vote with enough of a header for us to file it.
+    #[cfg(feature = "votes")]
+    const TEST_VOTE: &str = "network-status-version 3
+vote-status vote
+consensus-methods 28 29 30
+published 2020-08-07 11:50:00
+valid-after 2020-08-07 12:00:00
+fresh-until 2020-08-07 13:00:00
+valid-until 2020-08-07 15:00:00
+dir-source test27 2727272727272727272727272727272727272727 127.0.0.1 127.0.0.1 80 443
+contact nobody
+directory-footer
+";
+
+    #[test]
+    #[cfg(feature = "votes")]
+    fn fetch_votes() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            let id: RsaIdentity = [0x27; 20].into();
+
+            // We ask for votes by the authority's identity.
+            let reqs = mgr.query_into_requests(DocId::AuthVote(id).into()).unwrap();
+            assert_eq!(reqs.len(), 1);
+            assert!(matches!(reqs[0], ClientRequest::AuthVotes(_)));
+            let req = reqs[0].as_requestable().make_request().unwrap();
+            assert_eq!(
+                req.uri().path(),
+                "/tor/status-vote/current/2727272727272727272727272727272727272727.z"
+            );
+
+            // Whatever we download goes into the cache.
+            *mgr.canned_response.lock().unwrap() = Some(bootstrap::CannedResponse::new(TEST_VOTE));
+            let mgr = Arc::new(mgr);
+            assert!(mgr.text(&DocId::AuthVote(id)).unwrap().is_none());
+            rt.wait_for(mgr.fetch_votes(&[id])).await.unwrap();
+            let text = mgr.text(&DocId::AuthVote(id)).unwrap().unwrap();
+            assert_eq!(text.as_str(), Ok(TEST_VOTE));
+
+            // A vote from somebody we didn't ask about doesn't count.
+            let other: RsaIdentity = [0x28; 20].into();
+            let err = rt.wait_for(mgr.fetch_votes(&[other])).await.unwrap_err();
+            assert!(matches!(err, Error::CantAdvanceState(_)));
+            assert!(mgr.text(&DocId::AuthVote(other)).unwrap().is_none());
+        });
+    }
+
+    /// A router descriptor, signed by the relay that it describes.
+    #[cfg(feature = "routerdesc")]
+    const ROUTERDESC: &str = include_str!("../testdata/routerdesc1.txt");
+
+    #[test]
+    #[cfg(feature = "routerdesc")]
+    fn fetch_routerdescs() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            let digest = routerdesc_digest(ROUTERDESC.as_bytes()).unwrap();
+            let id = DocId::RouterDesc(digest);
+
+            // Whatever we download goes into the cache.
+            *mgr.canned_response.lock().unwrap() = Some(bootstrap::CannedResponse::new(ROUTERDESC));
+            let mgr = Arc::new(mgr);
+            assert!(mgr.text(&id).unwrap().is_none());
+            rt.wait_for(mgr.fetch_routerdescs(&[digest])).await.unwrap();
+            let text = mgr.text(&id).unwrap().unwrap();
+            assert_eq!(text.as_str(), Ok(ROUTERDESC));
+
+            // Once it's in the cache, we don't ask for it again.
+            *mgr.canned_response.lock().unwrap() = Some(bootstrap::CannedResponse::new("").fail());
+            rt.wait_for(mgr.fetch_routerdescs(&[digest])).await.unwrap();
+
+            // A router descriptor that we didn't ask for doesn't count.
+            *mgr.canned_response.lock().unwrap() = Some(bootstrap::CannedResponse::new(ROUTERDESC));
+            let other = [0x28; 20];
+            let err = rt
+                .wait_for(mgr.fetch_routerdescs(&[other]))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::CantAdvanceState(_)));
+            assert!(mgr.text(&DocId::RouterDesc(other)).unwrap().is_none());
+
+            // Nor does one with a bad signature, even if its digest is
+            // right.
+            let forged = ROUTERDESC.replace("uptime 1828391", "uptime 1828392");
+            let forged_digest = routerdesc_digest(forged.as_bytes()).unwrap();
+            *mgr.canned_response.lock().unwrap() = Some(bootstrap::CannedResponse::new(forged));
+            let err = rt
+                .wait_for(mgr.fetch_routerdescs(&[forged_digest]))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::CantAdvanceState(_)));
+            assert!(mgr
+                .text(&DocId::RouterDesc(forged_digest))
+                .unwrap()
+                .is_none());
+        });
+    }
+
     #[test]
     fn expand_response() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
@@ -1198,7 +3571,7 @@ mod test {
             // Try a simple request: nothing should happen.
             let q = DocId::Microdesc([99; 32]).into();
             let r = &mgr.query_into_requests(q).unwrap()[0];
-            let expanded = mgr.expand_response_text(r, "ABC".to_string());
+            let expanded = mgr.expand_response_text(r, DirResponse::from_body("ABC"));
             assert_eq!(&expanded.unwrap(), "ABC");
 
             // Try a consensus response that doesn't look like a diff in
@@ -1209,7 +3582,7 @@ mod test {
             };
             let q: DocQuery = latest_id.into();
             let r = &mgr.query_into_requests(q.clone()).unwrap()[0];
-            let expanded = mgr.expand_response_text(r, "DEF".to_string());
+            let expanded = mgr.expand_response_text(r, DirResponse::from_body("DEF"));
             assert_eq!(&expanded.unwrap(), "DEF");
 
             // Now stick some metadata and a string into the storage so that
@@ -1237,7 +3610,7 @@ mod test {
             // Try expanding something that isn't a consensus, even if we'd like
             // one.
             let r = &mgr.query_into_requests(q).unwrap()[0];
-            let expanded = mgr.expand_response_text(r, "hello".to_string());
+            let expanded = mgr.expand_response_text(r, DirResponse::from_body("hello"));
             assert_eq!(&expanded.unwrap(), "hello");
 
             // Finally, try "expanding" a diff (by applying it and checking the digest.
@@ -1247,7 +3620,7 @@ hash 9999999999999999999999999999999999999999999999999999999999999999 8382374ca7
 replacement line
 .
 ".to_string();
-            let expanded = mgr.expand_response_text(r, diff);
+            let expanded = mgr.expand_response_text(r, DirResponse::from_body(diff));
 
             assert_eq!(expanded.unwrap(), "line 1\nreplacement line\nline 3\n");
 
@@ -1258,11 +3631,271 @@ hash 9999999999999999999999999999999999999999999999999999999999999999 9999999999
 replacement line
 .
 ".to_string();
-            let expanded = mgr.expand_response_text(r, diff);
+            let expanded = mgr.expand_response_text(r, DirResponse::from_body(diff));
             assert!(expanded.is_err());
         });
     }
 
+    #[test]
+    fn check_cache_integrity() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let dir = TempDir::new().unwrap();
+            let config = config_builder(dir.path())
+                .check_cache_integrity(true)
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt, None, false).unwrap();
+
+            // One microdescriptor is stored under its real digest; the
+            // other has been damaged since we stored it.
+            const GOOD: &str = "onion-key\n";
+            let good: MdDigest = tor_llcrypto::d::Sha256::digest(GOOD).into();
+            let bad: MdDigest = tor_llcrypto::d::Sha256::digest("onion-kez\n").into();
+            mgr.store
+                .lock()
+                .unwrap()
+                .store_microdescs(&[(GOOD, &good), ("onion-key\n", &bad)], SystemTime::now())
+                .unwrap();
+
+            // We only load the good one...
+            let docs = mgr
+                .texts(vec![DocId::Microdesc(good), DocId::Microdesc(bad)])
+                .unwrap();
+            assert_eq!(docs.len(), 1);
+            assert_eq!(
+                docs.get(&DocId::Microdesc(good)).unwrap().as_str().unwrap(),
+                GOOD
+            );
+
+            // ... and the bad one is gone from the cache.
+            let stored = mgr.store.lock().unwrap().microdescs(&[good, bad]).unwrap();
+            assert_eq!(stored.len(), 1);
+            assert!(stored.contains_key(&good));
+
+            // The same goes for an authority certificate that we find under
+            // somebody else's keys.
+            let hex_id = |s: &str| RsaIdentity::from_bytes(&hex::decode(s).unwrap()).unwrap();
+            let real_ids = AuthCertKeyIds {
+                id_fingerprint: hex_id("5696ab38cb3852afa476a5c07b2d4788963d5567"),
+                sk_fingerprint: hex_id("f6ed4aa64d83caede34e19693a7fcf331aae8a6a"),
+            };
+            let wrong_ids = AuthCertKeyIds {
+                id_fingerprint: [1; 20].into(),
+                sk_fingerprint: [2; 20].into(),
+            };
+            const CERT: &str = include_str!("../testdata/cert-5696.txt");
+            let now = SystemTime::now();
+            let later = now + Duration::from_secs(86400);
+            mgr.store
+                .lock()
+                .unwrap()
+                .store_authcerts(&[
+                    (AuthCertMeta::new(real_ids, now, later), CERT),
+                    (AuthCertMeta::new(wrong_ids, now, later), CERT),
+                ])
+                .unwrap();
+            let docs = mgr
+                .texts(vec![DocId::AuthCert(real_ids), DocId::AuthCert(wrong_ids)])
+                .unwrap();
+            assert_eq!(docs.len(), 1);
+            assert!(docs.contains_key(&DocId::AuthCert(real_ids)));
+            let stored = mgr.store.lock().unwrap().authcerts(&[wrong_ids]).unwrap();
+            assert!(stored.is_empty());
+
+            // Without the check, we'd have taken the damaged one at its word.
+            mgr.store
+                .lock()
+                .unwrap()
+                .store_microdescs(&[("onion-key\n", &bad)], SystemTime::now())
+                .unwrap();
+            let config = config_builder(dir.path()).build().unwrap();
+            mgr.reconfigure(&config, tor_config::Reconfigure::AllOrNothing)
+                .unwrap();
+            let docs = mgr.texts(vec![DocId::Microdesc(bad)]).unwrap();
+            assert_eq!(docs.len(), 1);
+        });
+    }
+
+    #[test]
+    fn expand_with_codec() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (_tempdir, mgr) = new_mgr(rt);
+            let q = DocId::Microdesc([99; 32]).into();
+            let r = &mgr.query_into_requests(q).unwrap()[0];
+            let backwards = || DirResponse::from_body("CBA").with_encoding("x-backwards");
+
+            // We don't know this encoding yet.
+            let expanded = mgr.expand_response_text(r, backwards());
+            assert!(matches!(
+                expanded,
+                Err(Error::DirClientError(
+                    tor_dirclient::Error::ContentEncoding(_)
+                ))
+            ));
+
+            // Once we have a codec for it, we use that codec.
+            assert!(mgr
+                .register_codec("x-backwards no", |_| Ok(vec![]))
+                .is_err());
+            mgr.register_codec("x-backwards", |body| {
+                Ok(body.iter().rev().copied().collect())
+            })
+            .unwrap();
+            assert_eq!(mgr.codec_names(), vec!["x-backwards".to_string()]);
+            let expanded = mgr.expand_response_text(r, backwards());
+            assert_eq!(&expanded.unwrap(), "ABC");
+
+            // A codec that fails gives us an error.
+            mgr.register_codec("x-backwards", |_| {
+                Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "no"))
+            })
+            .unwrap();
+            let expanded = mgr.expand_response_text(r, backwards());
+            assert!(matches!(expanded, Err(Error::ContentDecoding { .. })));
+        });
+    }
+
+    #[test]
+    fn pause_refresh() {
+        use futures::FutureExt;
+        use tor_rtcompat::SleepProvider;
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            let mgr = Arc::new(mgr);
+            let mut events = mgr.events();
+            let state = Box::new(
+                StubState::new(vec![])
+                    .complete()
+                    .reset_at(rt.wallclock() + Duration::from_secs(3600)),
+            );
+            mgr.pause_refresh();
+
+            let refresher = DirMgr::download_forever(Arc::downgrade(&mgr), state, None);
+            let controller = async {
+                // Go well past the time when we should have refreshed.
+                rt.sleep(Duration::from_secs(3 * 3600)).await;
+                assert!(events.next().now_or_never().is_none());
+
+                // Once we resume, we refresh right away.
+                mgr.resume_refresh();
+                rt.sleep(Duration::from_secs(1)).await;
+                assert_eq!(
+                    events.next().now_or_never(),
+                    Some(Some(DirEvent::Reset(DirResetReason::Scheduled)))
+                );
+                assert!(events.next().now_or_never().is_none());
+            };
+            rt.wait_for(futures::future::select(
+                Box::pin(refresher),
+                Box::pin(controller),
+            ))
+            .await;
+        });
+    }
+
+    #[test]
+    fn stale_directory_backs_off() {
+        // An incomplete directory that's no longer fresh gets replaced
+        // without waiting for its reset time, but not before our retry
+        // delay.
+        use futures::FutureExt;
+        use tor_rtcompat::SleepProvider;
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let dir = TempDir::new().unwrap();
+            let mut sched = DownloadScheduleConfig::builder();
+            sched.retry_bootstrap(
+                DownloadSchedule::new(3, Duration::from_secs(60), 1).without_jitter(),
+            );
+            let config = config_builder(dir.path())
+                .schedule_config(sched.build().unwrap())
+                .build()
+                .unwrap();
+            let mgr = Arc::new(DirMgr::from_config(config, rt.clone(), None, false).unwrap());
+            let mut events = mgr.events();
+            let state = Box::new(
+                StubState::new(vec![])
+                    .stale()
+                    .reset_at(rt.wallclock() + Duration::from_secs(86400)),
+            );
+
+            let refresher = DirMgr::download_forever(Arc::downgrade(&mgr), state, None);
+            let controller = async {
+                rt.sleep(Duration::from_secs(59)).await;
+                assert!(events.next().now_or_never().is_none());
+
+                rt.sleep(Duration::from_secs(2)).await;
+                assert_eq!(
+                    events.next().now_or_never(),
+                    Some(Some(DirEvent::Reset(DirResetReason::ConsensusReplacement)))
+                );
+            };
+            rt.wait_for(futures::future::select(
+                Box::pin(refresher),
+                Box::pin(controller),
+            ))
+            .await;
+        });
+    }
+
+    #[test]
+    fn next_refresh_time() {
+        // Once we have a complete directory, we report when we'll start
+        // looking for a new consensus: after the current one stops being
+        // fresh, and before it stops being valid.
+        use futures::future::Either;
+        use tor_rtcompat::SleepProvider;
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use time::macros::datetime;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            rt.jump_to(consensus_time());
+
+            let tempdir = TempDir::new().unwrap();
+            let mut netcfg = authority_network();
+            netcfg.fallback_caches(vec![]);
+            let config = config_builder(tempdir.path())
+                .network_config(netcfg.build().unwrap())
+                .consensus_only(true)
+                .build()
+                .unwrap();
+            let mgr = DirMgr::from_config(config, rt.clone(), None, false).unwrap();
+            *mgr.canned_response.lock().unwrap() =
+                Some(bootstrap::CannedResponse::new(CONSENSUS).certs(AUTHCERTS));
+            let mgr = Arc::new(mgr);
+            assert!(mgr.next_refresh_time().is_none());
+
+            let downloader = DirMgr::start_download_forever(Arc::downgrade(&mgr), None);
+            let watcher = async {
+                loop {
+                    rt.sleep(Duration::from_secs(1)).await;
+                    if let Some(when) = mgr.next_refresh_time() {
+                        break when;
+                    }
+                }
+            };
+            let when = match rt
+                .wait_for(futures::future::select(
+                    Box::pin(downloader),
+                    Box::pin(watcher),
+                ))
+                .await
+            {
+                Either::Left((outcome, _)) => panic!("Download task exited: {:?}", outcome),
+                Either::Right((when, _)) => when,
+            };
+
+            // This consensus is fresh for 20 seconds, and valid for 20 more.
+            // We should refresh between 35 seconds after it became valid
+            // and the time it stops being valid.
+            let lifetime = mgr.consensus_lifetime().unwrap();
+            let earliest: SystemTime = datetime!(2020-08-07 12:43:15 UTC).into();
+            assert!(when > lifetime.fresh_until());
+            assert!(when >= earliest);
+            assert!(when < lifetime.valid_until());
+        });
+    }
+
     #[test]
     #[allow(clippy::bool_assert_comparison)]
     fn bool_resetter_works() {
@@ -0,0 +1,64 @@
+//! Information about directory mirrors that we can reach without Tor.
+//!
+//! A mirror is an ordinary HTTP or HTTPS server that serves directory
+//! documents at the same paths as a Tor directory cache.  When we don't have
+//! a directory yet, and we can't reach any of our fallback caches over the
+//! Tor network, we try our mirrors instead, over a direct connection.
+//!
+//! We don't trust anything that a mirror sends us: its documents go through
+//! the same validation as documents from a directory cache.  For that
+//! reason, we don't check the certificate of a mirror that we reach over
+//! TLS.  Note that this means the signatures on the consensus (and on the
+//! authority certificates that sign it) are our only protection against a
+//! mirror, or anybody on the path to it, that tampers with its responses.
+
+use derive_builder::Builder;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tor_config::ConfigBuildError;
+
+/// A server that we can ask for directory documents directly, without
+/// using the Tor network.
+///
+/// This is meant for censorship circumvention: for example, a mirror can
+/// sit behind a CDN, and be reached by domain fronting.
+#[derive(Deserialize, Debug, Clone, Builder, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[builder(build_fn(error = "ConfigBuildError"))]
+pub struct DirMirror {
+    /// The address to connect to.
+    address: SocketAddr,
+    /// If present, we talk TLS to this mirror, and send this as the server
+    /// name in our handshake.  We don't validate the mirror's certificate.
+    ///
+    /// When domain fronting, this is the name of the front.
+    #[serde(default)]
+    #[builder(default, setter(into, strip_option))]
+    tls_hostname: Option<String>,
+    /// If present, we send this as the `Host` header of our HTTP requests.
+    ///
+    /// When domain fronting, this is the name of the hidden server.
+    #[serde(default)]
+    #[builder(default, setter(into, strip_option))]
+    http_host: Option<String>,
+}
+
+impl DirMirror {
+    /// Return a new builder for constructing a [`DirMirror`].
+    pub fn builder() -> DirMirrorBuilder {
+        DirMirrorBuilder::default()
+    }
+    /// Return the address that we connect to for this mirror.
+    pub fn address(&self) -> &SocketAddr {
+        &self.address
+    }
+    /// Return the server name that we send in our TLS handshake with this
+    /// mirror, or None if we don't use TLS to talk to it.
+    pub fn tls_hostname(&self) -> Option<&str> {
+        self.tls_hostname.as_deref()
+    }
+    /// Return the `Host` header that we send to this mirror, if any.
+    pub fn http_host(&self) -> Option<&str> {
+        self.http_host.as_deref()
+    }
+}
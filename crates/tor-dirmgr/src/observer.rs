@@ -0,0 +1,194 @@
+//! Callbacks that let an application watch a [`DirMgr`](crate::DirMgr) at
+//! work.
+
+use std::fmt;
+use std::sync::Arc;
+
+use tor_dirclient::SourceInfo;
+use tor_netdir::NetDir;
+
+use crate::{DocId, NetDirDiff, StateTransition};
+
+/// A callback to tell somebody about changes in bootstrapping state.
+type TransitionFn = Arc<dyn Fn(&StateTransition) + Send + Sync>;
+
+/// A callback to tell somebody how many bytes of directory data we have just
+/// received, and where they came from.
+type BytesFn = Arc<dyn Fn(usize, Option<&SourceInfo>) + Send + Sync>;
+
+/// A callback to tell somebody which documents we have just obtained.
+type ObtainedFn = Arc<dyn Fn(&[DocId]) + Send + Sync>;
+
+/// A callback to tell somebody about a directory response whose documents we
+/// have just accepted: the request it answered, its body, and the
+/// content-encoding that the body is still in.
+type RawResponseFn = Arc<dyn Fn(&http::Request<()>, &[u8], Option<&str>) + Send + Sync>;
+
+/// A callback to tell somebody about each new directory that we have started
+/// using, and how it differs from the one before.
+type NetDirFn = Arc<dyn Fn(&NetDir, &NetDirDiff) + Send + Sync>;
+
+/// A set of functions for a [`DirMgr`](crate::DirMgr) to call as it
+/// downloads and uses directory information.
+///
+/// Every function is optional; start with [`DirObserver::new`], add the
+/// ones you want, and install the result with
+/// [`DirMgr::set_observer`](crate::DirMgr::set_observer).
+///
+/// All of these functions run in the middle of the download process, so
+/// they should return quickly.
+#[derive(Clone, Default)]
+pub struct DirObserver {
+    /// Called whenever our bootstrapping state advances or resets.
+    transition: Option<TransitionFn>,
+    /// Called with the size of every directory response that we receive.
+    bytes_received: Option<BytesFn>,
+    /// Called with the documents that each download attempt obtained.
+    obtained: Option<ObtainedFn>,
+    /// Called with every directory response whose documents we accept.
+    raw_response: Option<RawResponseFn>,
+    /// Called with each directory that we start using.
+    netdir_replaced: Option<NetDirFn>,
+}
+
+impl DirObserver {
+    /// Return a new observer that doesn't call anything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `f` whenever our bootstrapping state advances to the next
+    /// state, or resets to look for a new consensus.
+    ///
+    /// It gets descriptions of the state we left and the state we entered.
+    pub fn on_transition<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&StateTransition) + Send + Sync + 'static,
+    {
+        self.transition = Some(Arc::new(f));
+        self
+    }
+
+    /// Call `f` whenever we receive a directory response, with the number of
+    /// bytes in its body and the circuit it came from (if we know).
+    ///
+    /// It is called once for each response that we download, as soon as we
+    /// have it, whether or not it turns out to be useful.  The sizes are
+    /// after decompression.
+    pub fn on_bytes_received<F>(mut self, f: F) -> Self
+    where
+        F: Fn(usize, Option<&SourceInfo>) + Send + Sync + 'static,
+    {
+        self.bytes_received = Some(Arc::new(f));
+        self
+    }
+
+    /// Call `f` after each download attempt that gets us documents we were
+    /// missing, with the IDs of those documents.
+    ///
+    /// This lets applications show how bootstrapping is going, in more
+    /// detail than [`DirMgr::bootstrap_percent`](crate::DirMgr::bootstrap_percent).
+    pub fn on_obtained<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&[DocId]) + Send + Sync + 'static,
+    {
+        self.obtained = Some(Arc::new(f));
+        self
+    }
+
+    /// Call `f` with each directory response whose documents we accept:
+    /// with the request that it answered, its body, and the
+    /// content-encoding that the body is still in (if any).
+    ///
+    /// This lets an application keep the responses that we get, so that it
+    /// can serve them to other clients.  The body is exactly what the
+    /// directory cache sent, except for any content-encoding that the
+    /// directory client undid for us: only an encoding that we handle with
+    /// a codec from [`DirMgr::register_codec`](crate::DirMgr::register_codec)
+    /// is left in place.  If we asked for a consensus diff, the body may be
+    /// a diff.  We don't report a response that was cut short, even if we
+    /// used some of its documents.
+    pub fn on_raw_response<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&http::Request<()>, &[u8], Option<&str>) + Send + Sync + 'static,
+    {
+        self.raw_response = Some(Arc::new(f));
+        self
+    }
+
+    /// Call `f` whenever we replace our directory with a new one, with the
+    /// new directory and a summary of how it differs from the old one.
+    ///
+    /// This lets applications that keep their own information about relays
+    /// update it, without comparing whole directories themselves.  When we
+    /// get our first directory, every relay it lists counts as added.  We
+    /// don't call `f` when we only add microdescriptors to the directory
+    /// that we have.
+    pub fn on_netdir_replaced<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&NetDir, &NetDirDiff) + Send + Sync + 'static,
+    {
+        self.netdir_replaced = Some(Arc::new(f));
+        self
+    }
+
+    /// Return true if we have a function to call on state transitions.
+    pub(crate) fn wants_transitions(&self) -> bool {
+        self.transition.is_some()
+    }
+
+    /// Return true if we have a function to call with raw responses.
+    pub(crate) fn wants_raw_responses(&self) -> bool {
+        self.raw_response.is_some()
+    }
+
+    /// Report a state transition, if we have a function for it.
+    pub(crate) fn transition(&self, t: &StateTransition) {
+        if let Some(f) = &self.transition {
+            f(t);
+        }
+    }
+
+    /// Report a received response, if we have a function for it.
+    pub(crate) fn bytes_received(&self, n: usize, source: Option<&SourceInfo>) {
+        if let Some(f) = &self.bytes_received {
+            f(n, source);
+        }
+    }
+
+    /// Report newly obtained documents, if we have a function for it.
+    pub(crate) fn obtained(&self, docs: &[DocId]) {
+        if let Some(f) = &self.obtained {
+            f(docs);
+        }
+    }
+
+    /// Report an accepted response, if we have a function for it.
+    pub(crate) fn raw_response(&self, req: &http::Request<()>, body: &[u8], enc: Option<&str>) {
+        if let Some(f) = &self.raw_response {
+            f(req, body, enc);
+        }
+    }
+
+    /// Report that we replaced `old` with `new`, if we have a function for
+    /// it.
+    ///
+    /// We only compare the two directories if somebody wants to know.
+    pub(crate) fn netdir_replaced(&self, old: Option<&NetDir>, new: &NetDir) {
+        if let Some(f) = &self.netdir_replaced {
+            f(new, &NetDirDiff::new(old, new));
+        }
+    }
+}
+
+impl fmt::Debug for DirObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirObserver")
+            .field("transition", &self.transition.is_some())
+            .field("bytes_received", &self.bytes_received.is_some())
+            .field("obtained", &self.obtained.is_some())
+            .field("raw_response", &self.raw_response.is_some())
+            .field("netdir_replaced", &self.netdir_replaced.is_some())
+            .finish()
+    }
+}
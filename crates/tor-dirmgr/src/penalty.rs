@@ -0,0 +1,119 @@
+//! Decide when a directory cache has failed us often enough that we should
+//! stop asking it.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A record of the recent errors we've had from each directory cache, keyed
+/// by whatever we know about where each response came from.
+///
+/// A single bad response can be bad luck, so we only give up on a cache
+/// once it has failed us some number of times within a window of time.
+#[derive(Debug)]
+pub(crate) struct CacheErrors<K> {
+    /// When each source has failed us, oldest first.
+    errors: Mutex<HashMap<K, Vec<Instant>>>,
+}
+
+impl<K> Default for CacheErrors<K> {
+    fn default() -> Self {
+        CacheErrors {
+            errors: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> CacheErrors<K> {
+    /// Construct a new `CacheErrors` that starts out counting `errors`, as
+    /// returned by [`errors`](Self::errors).
+    pub(crate) fn from_errors(errors: HashMap<K, Vec<Instant>>) -> Self {
+        CacheErrors {
+            errors: Mutex::new(errors),
+        }
+    }
+
+    /// Return the times of every error that we're still counting, by
+    /// source.
+    pub(crate) fn errors(&self) -> HashMap<K, Vec<Instant>> {
+        self.errors.lock().expect("Poisoned lock").clone()
+    }
+
+    /// Note that `source` failed us at `now`, and return true if we should
+    /// stop asking it.
+    ///
+    /// That's the case once it has failed `threshold` times within the
+    /// `window` before `now`; a `threshold` of 0 is treated as 1.  When we
+    /// return true, we forget the errors we counted, so that we start over if
+    /// we come back to this source later.
+    pub(crate) fn note(&self, source: K, now: Instant, threshold: u32, window: Duration) -> bool {
+        let mut errors = self.errors.lock().expect("Poisoned lock");
+        // Forget about errors that are too old to count for anybody.
+        errors.retain(|_, times| {
+            times.retain(|t| now.saturating_duration_since(*t) < window);
+            !times.is_empty()
+        });
+        let times = errors.entry(source).or_insert_with(Vec::new);
+        times.push(now);
+        if times.len() >= std::cmp::max(threshold, 1) as usize {
+            times.clear();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn threshold() {
+        let errors = CacheErrors::default();
+        let window = Duration::from_secs(600);
+        let t0 = Instant::now();
+
+        // With a threshold of 1, every error counts.
+        assert!(errors.note(1_u8, t0, 1, window));
+        assert!(errors.note(1_u8, t0, 0, window));
+
+        // With a threshold of 3, we need three errors in the window...
+        assert!(!errors.note(2_u8, t0, 3, window));
+        assert!(!errors.note(2_u8, t0 + Duration::from_secs(60), 3, window));
+        // ... and they have to come from the same source.
+        assert!(!errors.note(3_u8, t0 + Duration::from_secs(60), 3, window));
+        assert!(errors.note(2_u8, t0 + Duration::from_secs(120), 3, window));
+        // Then we start counting again.
+        assert!(!errors.note(2_u8, t0 + Duration::from_secs(121), 3, window));
+    }
+
+    #[test]
+    fn window() {
+        let errors = CacheErrors::default();
+        let window = Duration::from_secs(600);
+        let t0 = Instant::now();
+
+        assert!(!errors.note((), t0, 2, window));
+        // Too late: the first error has expired, so this one is alone.
+        assert!(!errors.note((), t0 + Duration::from_secs(600), 2, window));
+        // But this one is soon enough after the second one.
+        assert!(errors.note((), t0 + Duration::from_secs(700), 2, window));
+    }
+
+    #[test]
+    fn restore() {
+        let window = Duration::from_secs(600);
+        let t0 = Instant::now();
+        let errors = CacheErrors::default();
+        assert!(!errors.note(1_u8, t0, 2, window));
+
+        // A copy made from what we've counted picks up where we left off.
+        let saved = errors.errors();
+        assert_eq!(saved[&1].as_slice(), &[t0]);
+        let errors = CacheErrors::from_errors(saved);
+        assert!(errors.note(1_u8, t0 + Duration::from_secs(60), 2, window));
+    }
+}
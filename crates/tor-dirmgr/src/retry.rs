@@ -31,6 +31,15 @@ pub struct RetryDelay {
     last_delay_ms: u32,
     /// The lowest allowable delay (in msec).
     low_bound_ms: u32,
+    /// The least amount to multiply the last delay by when picking the next
+    /// one.
+    min_mult: u32,
+    /// The most amount to multiply the last delay by when picking the next
+    /// one.
+    max_mult: u32,
+    /// If false, we don't randomize at all: every delay is the lowest
+    /// allowable delay.
+    jitter: bool,
 }
 
 /// Lowest possible lower bound, in milliseconds.
@@ -41,7 +50,10 @@ const MIN_LOW_BOUND: u32 = 1000;
 /// Largest possible lower bound, in milliseconds.
 const MAX_LOW_BOUND: u32 = std::u32::MAX - 1;
 
-/// Maximum amount to multiply the previous delay by.
+/// Default minimum amount to multiply the previous delay by.
+const MIN_DELAY_MULT: u32 = 0;
+
+/// Default maximum amount to multiply the previous delay by.
 const MAX_DELAY_MULT: u32 = 3;
 
 impl RetryDelay {
@@ -60,6 +72,9 @@ impl RetryDelay {
         RetryDelay {
             last_delay_ms: 0,
             low_bound_ms,
+            min_mult: MIN_DELAY_MULT,
+            max_mult: MAX_DELAY_MULT,
+            jitter: true,
         }
     }
 
@@ -72,15 +87,47 @@ impl RetryDelay {
         RetryDelay::from_msec(msec)
     }
 
+    /// Return a new RetryDelay like this one, but which picks each delay
+    /// between `min_mult` and `max_mult` times the last one.
+    ///
+    /// (No delay is ever shorter than the base delay.)  By default,
+    /// `min_mult` is 0 and `max_mult` is 3.  If `min_mult` is greater than
+    /// `max_mult`, we use `max_mult` for both.
+    pub fn with_multipliers(self, min_mult: u32, max_mult: u32) -> Self {
+        RetryDelay {
+            min_mult: std::cmp::min(min_mult, max_mult),
+            max_mult,
+            ..self
+        }
+    }
+
+    /// Return a new RetryDelay like this one, but which doesn't randomize
+    /// its delays: every delay is the base delay.
+    ///
+    /// This is mainly useful for tests, where we want to know exactly how
+    /// long we'll wait.
+    pub fn without_jitter(self) -> Self {
+        RetryDelay {
+            jitter: false,
+            ..self
+        }
+    }
+
     /// Helper: Return a lower and upper bound for the next delay to
     /// be yielded.
     fn delay_bounds(&self) -> (u32, u32) {
-        let low = self.low_bound_ms;
+        let low = std::cmp::max(
+            self.low_bound_ms,
+            std::cmp::min(
+                self.last_delay_ms.saturating_mul(self.min_mult),
+                MAX_LOW_BOUND,
+            ),
+        );
         let high = std::cmp::max(
-            // We don't need a saturating_add here, since low is always
-            // less than high, so low cannot be equal to u32::MAX.
+            // We don't need a saturating_add here, since low is never
+            // more than MAX_LOW_BOUND, so low cannot be equal to u32::MAX.
             low + 1,
-            self.last_delay_ms.saturating_mul(MAX_DELAY_MULT),
+            self.last_delay_ms.saturating_mul(self.max_mult),
         );
         (low, high)
     }
@@ -88,6 +135,10 @@ impl RetryDelay {
     /// Return the next delay to be used (in milliseconds), according
     /// to a given random number generator.
     pub fn next_delay_msec<R: Rng>(&mut self, rng: &mut R) -> u32 {
+        if !self.jitter {
+            self.last_delay_ms = self.low_bound_ms;
+            return self.low_bound_ms;
+        }
         let (low, high) = self.delay_bounds();
         assert!(low < high);
 
@@ -124,8 +175,34 @@ pub struct DownloadSchedule {
 
     /// When we want to download a bunch of these at a time, how many
     /// attempts should we try to launch at once?
-    #[serde(default = "default_parallelism")]
+    ///
+    /// This applies to our first attempt, and to our retries unless
+    /// `retry_parallelism` is set.
+    #[serde(default = "default_parallelism", alias = "first_attempt_parallelism")]
     parallelism: NonZeroU8,
+
+    /// If present, how many requests to launch at once on every attempt
+    /// after the first.
+    ///
+    /// If we have to retry, the network is probably struggling, so it can
+    /// make sense to ask for less at a time.
+    #[serde(default)]
+    retry_parallelism: Option<NonZeroU8>,
+
+    /// The least amount to multiply our last delay by when picking the next
+    /// one.  (We never wait less than `initial_delay`.)
+    #[serde(default = "default_min_delay_mult")]
+    min_delay_mult: u32,
+
+    /// The most amount to multiply our last delay by when picking the next
+    /// one.
+    #[serde(default = "default_max_delay_mult")]
+    max_delay_mult: u32,
+
+    /// If false, don't randomize our delays at all: always wait exactly
+    /// `initial_delay` between attempts.
+    #[serde(default = "default_jitter")]
+    jitter: bool,
 }
 
 impl Default for DownloadSchedule {
@@ -140,6 +217,21 @@ fn default_parallelism() -> NonZeroU8 {
     1.try_into().unwrap()
 }
 
+/// Return the default min_delay_mult for DownloadSchedule.
+fn default_min_delay_mult() -> u32 {
+    MIN_DELAY_MULT
+}
+
+/// Return the default max_delay_mult for DownloadSchedule.
+fn default_max_delay_mult() -> u32 {
+    MAX_DELAY_MULT
+}
+
+/// Return the default jitter setting for DownloadSchedule.
+fn default_jitter() -> bool {
+    true
+}
+
 impl DownloadSchedule {
     /// Create a new DownloadSchedule to control our logic for retrying
     /// a given download.
@@ -161,9 +253,60 @@ impl DownloadSchedule {
             num_retries,
             initial_delay,
             parallelism,
+            retry_parallelism: None,
+            min_delay_mult: MIN_DELAY_MULT,
+            max_delay_mult: MAX_DELAY_MULT,
+            jitter: true,
         }
     }
 
+    /// Return a new DownloadSchedule like this one, but which launches
+    /// `parallelism` requests at once on every attempt after the first.
+    #[allow(clippy::missing_panics_doc)] // can't really panic.
+    pub fn with_retry_parallelism(self, parallelism: u8) -> Self {
+        #![allow(clippy::unwrap_used)]
+        let parallelism = parallelism
+            .try_into()
+            .unwrap_or_else(|_| 1.try_into().unwrap());
+        DownloadSchedule {
+            retry_parallelism: Some(parallelism),
+            ..self
+        }
+    }
+
+    /// Return a new DownloadSchedule like this one, but which picks each
+    /// delay after the first between `min_mult` and `max_mult` times the
+    /// delay before it.
+    ///
+    /// See [`RetryDelay::with_multipliers`].
+    pub fn with_jitter_bounds(self, min_mult: u32, max_mult: u32) -> Self {
+        DownloadSchedule {
+            min_delay_mult: min_mult,
+            max_delay_mult: max_mult,
+            ..self
+        }
+    }
+
+    /// Return a new DownloadSchedule like this one, but which doesn't
+    /// randomize its delays: it always waits exactly its initial delay.
+    pub fn without_jitter(self) -> Self {
+        DownloadSchedule {
+            jitter: false,
+            ..self
+        }
+    }
+
+    /// Return the least and most amounts by which we multiply each delay to
+    /// find the next one.
+    pub fn jitter_bounds(&self) -> (u32, u32) {
+        (self.min_delay_mult, self.max_delay_mult)
+    }
+
+    /// Return true if we randomize our delays.
+    pub fn jitter(&self) -> bool {
+        self.jitter
+    }
+
     /// Return an iterator to use over all the supported attempts for
     /// this configuration.
     pub fn attempts(&self) -> impl Iterator<Item = u32> {
@@ -176,17 +319,39 @@ impl DownloadSchedule {
         self.num_retries.into()
     }
 
-    /// Return the number of parallel attempts that we're supposed to launch,
-    /// according to this DownloadSchedule.
+    /// Return the number of parallel attempts that we're supposed to launch
+    /// on our first attempt, according to this DownloadSchedule.
     pub fn parallelism(&self) -> u8 {
         self.parallelism.into()
     }
 
+    /// Return the number of parallel attempts that we're supposed to launch
+    /// on each attempt after the first, according to this DownloadSchedule.
+    pub fn retry_parallelism(&self) -> u8 {
+        self.retry_parallelism.unwrap_or(self.parallelism).into()
+    }
+
+    /// Return the number of parallel attempts that we're supposed to launch
+    /// on the attempt numbered `attempt` (starting at 0).
+    pub fn parallelism_for(&self, attempt: u32) -> u8 {
+        if attempt == 0 {
+            self.parallelism()
+        } else {
+            self.retry_parallelism()
+        }
+    }
+
     /// Return a RetryDelay object for this configuration.
     ///
     /// If the initial delay is longer than 32
     pub fn schedule(&self) -> RetryDelay {
-        RetryDelay::from_duration(self.initial_delay)
+        let delay = RetryDelay::from_duration(self.initial_delay)
+            .with_multipliers(self.min_delay_mult, self.max_delay_mult);
+        if self.jitter {
+            delay
+        } else {
+            delay.without_jitter()
+        }
     }
 }
 
@@ -253,6 +418,7 @@ mod test {
         let cfg = DownloadSchedule::new(0, Duration::new(0, 0), 0);
         assert_eq!(cfg.n_attempts(), 1);
         assert_eq!(cfg.parallelism(), 1);
+        assert_eq!(cfg.retry_parallelism(), 1);
         let v: Vec<_> = cfg.attempts().collect();
         assert_eq!(&v[..], &[0]);
 
@@ -260,4 +426,55 @@ mod test {
         assert_eq!(sched.last_delay_ms, 0);
         assert_eq!(sched.low_bound_ms, 1000);
     }
+
+    #[test]
+    fn multipliers() {
+        let mut rd = RetryDelay::from_msec(1000).with_multipliers(2, 4);
+        assert_eq!(rd.delay_bounds(), (1000, 1001));
+        rd.last_delay_ms = 1500;
+        assert_eq!(rd.delay_bounds(), (3000, 6000));
+
+        // A minimum above the maximum gets lowered to match.
+        let mut rd = RetryDelay::from_msec(1000).with_multipliers(5, 2);
+        rd.last_delay_ms = 1500;
+        assert_eq!(rd.delay_bounds(), (3000, 3001));
+
+        let cfg = DownloadSchedule::default();
+        assert_eq!(cfg.jitter_bounds(), (0, 3));
+        let cfg = cfg.with_jitter_bounds(1, 2);
+        assert_eq!(cfg.jitter_bounds(), (1, 2));
+        let mut rd = cfg.schedule();
+        rd.last_delay_ms = 1500;
+        assert_eq!(rd.delay_bounds(), (1500, 3000));
+    }
+
+    #[test]
+    fn no_jitter() {
+        let cfg = DownloadSchedule::new(4, Duration::from_millis(1500), 1).without_jitter();
+        assert!(!cfg.jitter());
+        let mut rd = cfg.schedule();
+        let mut rng = rand::thread_rng();
+        let delays: Vec<_> = cfg.attempts().map(|_| rd.next_delay(&mut rng)).collect();
+        assert_eq!(delays, vec![Duration::from_millis(1500); 4]);
+    }
+
+    #[test]
+    fn retry_parallelism() {
+        // By default, we use the same parallelism for every attempt.
+        let cfg = DownloadSchedule::new(3, Duration::from_secs(1), 4);
+        assert_eq!(cfg.retry_parallelism(), 4);
+        let v: Vec<_> = cfg.attempts().map(|a| cfg.parallelism_for(a)).collect();
+        assert_eq!(&v[..], &[4, 4, 4]);
+
+        // But we can ask for less after the first attempt...
+        let cfg = cfg.with_retry_parallelism(2);
+        assert_eq!(cfg.parallelism(), 4);
+        assert_eq!(cfg.retry_parallelism(), 2);
+        let v: Vec<_> = cfg.attempts().map(|a| cfg.parallelism_for(a)).collect();
+        assert_eq!(&v[..], &[4, 2, 2]);
+
+        // ... though never for nothing at all.
+        let cfg = cfg.with_retry_parallelism(0);
+        assert_eq!(cfg.retry_parallelism(), 1);
+    }
 }
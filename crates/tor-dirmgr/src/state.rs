@@ -3,7 +3,8 @@
 //! There are three (active) states that a download can be in: looking
 //! for a consensus ([`GetConsensusState`]), looking for certificates
 //! to validate that consensus ([`GetCertsState`]), and looking for
-//! microdescriptors ([`GetMicrodescsState`]).
+//! microdescriptors ([`GetMicrodescsState`]).  In consensus-only mode, we
+//! stop in [`GotConsensusState`] instead of fetching microdescriptors.
 //!
 //! These states have no contact with the network, and are purely
 //! reactive to other code that drives them.  See the
@@ -19,9 +20,9 @@ use time::OffsetDateTime;
 use tor_error::internal;
 use tor_netdir::{MdReceiver, NetDir, PartialNetDir};
 use tor_netdoc::doc::netstatus::Lifetime;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
-use crate::event::{DirStatus, DirStatusInner};
+use crate::event::{BootstrapPhase, DirStatus, DirStatusInner};
 
 use crate::storage::{DynStore, EXPIRATION_DEFAULTS};
 use crate::{
@@ -32,8 +33,10 @@ use crate::{
     Result,
 };
 use crate::{DirEvent, DocSource};
-use tor_checkable::{ExternallySigned, SelfSigned, Timebound};
+use tor_checkable::{ExternallySigned, SelfSigned, TimeValidityError, Timebound};
 use tor_llcrypto::pk::rsa::RsaIdentity;
+#[cfg(feature = "routerdesc")]
+use tor_netdoc::doc::routerdesc::{RdDigest, RouterDesc};
 use tor_netdoc::doc::{
     microdesc::{MdDigest, Microdesc},
     netstatus::MdConsensus,
@@ -50,8 +53,9 @@ use tor_rtcompat::Runtime;
 
 /// An object where we can put a usable netdir.
 ///
-/// Note that there's only one implementation for this trait: DirMgr.
-/// We make this a trait anyway to make sure that the different states
+/// The main implementation for this trait is DirMgr; the other one, in the
+/// [`verify`](crate::verify) module, discards whatever it's given.
+/// We make this a trait to make sure that the different states
 /// in this module can _only_ interact with the DirMgr through
 /// modifying the NetDir and looking at the configuration.
 pub(crate) trait WriteNetDir: 'static + Sync + Send {
@@ -70,6 +74,22 @@ pub(crate) trait WriteNetDir: 'static + Sync + Send {
     /// [`Self::netdir()`] have been changed.
     fn netdir_descriptors_changed(&self);
 
+    /// Called to note that `new` has replaced `old` (if there was one) in
+    /// [`Self::netdir()`].
+    fn netdir_replaced(&self, _old: Option<&NetDir>, _new: &NetDir) {}
+
+    /// Make `netdir` the one in [`Self::netdir()`], and tell anybody who
+    /// needs to know.
+    fn install_netdir(&self, netdir: NetDir) {
+        let old = self.netdir().get();
+        self.netdir().replace(netdir);
+        self.netdir_consensus_changed();
+        self.netdir_descriptors_changed();
+        if let Some(new) = self.netdir().get() {
+            self.netdir_replaced(old.as_deref(), &new);
+        }
+    }
+
     /// Checks whether the given `netdir` is ready to replace the previous
     /// one.
     ///
@@ -78,11 +98,31 @@ pub(crate) trait WriteNetDir: 'static + Sync + Send {
         true
     }
 
+    /// Called with each microdescriptor that we've validated, just before
+    /// we add it to the netdir we're building.
+    fn microdesc_validated(&self, _md: &Microdesc) {}
+
+    /// Called in consensus-only mode with a consensus that we've just
+    /// validated.
+    ///
+    /// We never build a NetDir from such a consensus, since we never fetch
+    /// its microdescriptors.
+    fn consensus_only_validated(&self, _consensus: MdConsensus, _meta: &ConsensusMeta) {}
+
+    /// Called when we validate a consensus that requires protocol versions
+    /// that we don't implement.
+    ///
+    /// Return true if this is the first time, so that we only warn about it
+    /// once.
+    fn required_protocols_missing(&self) -> bool {
+        true
+    }
+
     /// Called to find the current time.
     ///
-    /// This is just `SystemTime::now()` in production, but for
-    /// testing it is helpful to be able to mock our our current view
-    /// of the time.
+    /// This is the runtime's wall clock in production (corrected by any known
+    /// clock skew), but for testing it is helpful to be able to mock our
+    /// current view of the time.
     fn now(&self) -> SystemTime;
 }
 
@@ -99,14 +139,39 @@ impl<R: Runtime> WriteNetDir for crate::DirMgr<R> {
     fn netdir_descriptors_changed(&self) {
         self.events.publish(DirEvent::NewDescriptors);
     }
+    fn netdir_replaced(&self, old: Option<&NetDir>, new: &NetDir) {
+        self.note_netdir_replaced(old, new);
+    }
     fn netdir_is_sufficient(&self, netdir: &NetDir) -> bool {
         match &self.circmgr {
             Some(circmgr) => circmgr.netdir_is_sufficient(netdir),
             None => true, // no circmgr? then we can use anything.
         }
     }
+    fn microdesc_validated(&self, md: &Microdesc) {
+        self.publish_microdesc(md);
+    }
+    fn consensus_only_validated(&self, consensus: MdConsensus, meta: &ConsensusMeta) {
+        if let Some(store) = self.store_if_rw() {
+            let mut store = store.lock().expect("Directory storage lock poisoned");
+            let outcome = store
+                .mark_consensus_usable(meta)
+                .and_then(|()| store.expire_all(&EXPIRATION_DEFAULTS));
+            if let Err(e) = outcome {
+                warn!("Unable to mark consensus usable: {}", e);
+            }
+        }
+        self.consensus.replace(consensus);
+        self.events.publish(DirEvent::NewConsensus);
+    }
+    fn required_protocols_missing(&self) -> bool {
+        self.events.publish(DirEvent::MissingRequiredProtocols);
+        !self
+            .warned_missing_protocols
+            .swap(true, std::sync::atomic::Ordering::SeqCst)
+    }
     fn now(&self) -> SystemTime {
-        SystemTime::now()
+        self.trusted_now()
     }
 }
 
@@ -116,6 +181,9 @@ pub(crate) struct GetConsensusState<DM: WriteNetDir> {
     /// How should we get the consensus from the cache, if at all?
     cache_usage: CacheUsage,
 
+    /// Which flavor of consensus are we looking for?
+    flavor: ConsensusFlavor,
+
     /// If present, a time after which we want our consensus to have
     /// been published.
     //
@@ -144,9 +212,9 @@ impl<DM: WriteNetDir> GetConsensusState<DM> {
     /// Create a new GetConsensusState from a weak reference to a
     /// directory manager and a `cache_usage` flag.
     pub(crate) fn new(writedir: Weak<DM>, cache_usage: CacheUsage) -> Result<Self> {
-        let (authority_ids, after) = if let Some(writedir) = Weak::upgrade(&writedir) {
-            let ids: Vec<_> = writedir
-                .config()
+        let (authority_ids, after, flavor) = if let Some(writedir) = Weak::upgrade(&writedir) {
+            let config = writedir.config();
+            let ids: Vec<_> = config
                 .authorities()
                 .iter()
                 .map(|auth| *auth.v3ident())
@@ -156,12 +224,13 @@ impl<DM: WriteNetDir> GetConsensusState<DM> {
                 .get()
                 .map(|nd| nd.lifetime().valid_after());
 
-            (ids, after)
+            (ids, after, config.consensus_flavor())
         } else {
             return Err(Error::ManagerDropped);
         };
         Ok(GetConsensusState {
             cache_usage,
+            flavor,
             after,
             next: None,
             authority_ids,
@@ -176,7 +245,9 @@ impl<DM: WriteNetDir> DirState for GetConsensusState<DM> {
             "About to fetch certificates."
         } else {
             match self.cache_usage {
-                CacheUsage::CacheOnly => "Looking for a cached consensus.",
+                CacheUsage::CacheOnly | CacheUsage::ConsensusFromCache => {
+                    "Looking for a cached consensus."
+                }
                 CacheUsage::CacheOkay => "Looking for a consensus.",
                 CacheUsage::MustDownload => "Downloading a consensus.",
             }
@@ -187,9 +258,8 @@ impl<DM: WriteNetDir> DirState for GetConsensusState<DM> {
         if self.can_advance() {
             return Vec::new();
         }
-        let flavor = ConsensusFlavor::Microdesc;
         vec![DocId::LatestConsensus {
-            flavor,
+            flavor: self.flavor,
             cache_usage: self.cache_usage,
         }]
     }
@@ -206,6 +276,13 @@ impl<DM: WriteNetDir> DirState for GetConsensusState<DM> {
             DirStatusInner::NoConsensus { after: self.after }.into()
         }
     }
+    fn bootstrap_phase(&self) -> BootstrapPhase {
+        if let Some(next) = &self.next {
+            next.bootstrap_phase()
+        } else {
+            BootstrapPhase::GettingConsensus
+        }
+    }
     fn dl_config(&self) -> Result<DownloadSchedule> {
         if let Some(wd) = Weak::upgrade(&self.writedir) {
             Ok(*wd.config().schedule().retry_consensus())
@@ -220,14 +297,8 @@ impl<DM: WriteNetDir> DirState for GetConsensusState<DM> {
     ) -> Result<bool> {
         let text = match docs.into_iter().next() {
             None => return Ok(false),
-            Some((
-                DocId::LatestConsensus {
-                    flavor: ConsensusFlavor::Microdesc,
-                    ..
-                },
-                text,
-            )) => text,
-            _ => return Err(Error::Unwanted("Not an md consensus")),
+            Some((DocId::LatestConsensus { flavor, .. }, text)) if flavor == self.flavor => text,
+            _ => return Err(Error::Unwanted("Not a consensus of the flavor we wanted")),
         };
 
         let source = DocSource::LocalCache;
@@ -241,10 +312,11 @@ impl<DM: WriteNetDir> DirState for GetConsensusState<DM> {
         storage: Option<&Mutex<DynStore>>,
     ) -> Result<bool> {
         let source = DocSource::DirServer {};
+        let flavor = self.flavor;
         if let Some(meta) = self.add_consensus_text(source, text)? {
             if let Some(store) = storage {
                 let mut w = store.lock().expect("Directory storage lock poisoned");
-                w.store_consensus(meta, ConsensusFlavor::Microdesc, true, text)?;
+                w.store_consensus(meta, flavor, true, text)?;
             }
             Ok(true)
         } else {
@@ -274,17 +346,55 @@ impl<DM: WriteNetDir> GetConsensusState<DM> {
         source: DocSource,
         text: &str,
     ) -> Result<Option<&ConsensusMeta>> {
+        if self.flavor != ConsensusFlavor::Microdesc {
+            // TODO: Someday we may be able to build a directory from an
+            // ns-flavored consensus and router descriptors.
+            return Err(Error::Unwanted(
+                "Can only build a directory from a microdesc consensus",
+            ));
+        }
+
         // Try to parse it and get its metadata.
         let (consensus_meta, unvalidated) = {
             let (signedval, remainder, parsed) =
                 MdConsensus::parse(text).map_err(|e| Error::from_netdoc(source.clone(), e))?;
             let now = current_time(&self.writedir)?;
-            if let Ok(timely) = parsed.check_valid_at(&now) {
-                let meta = ConsensusMeta::from_unvalidated(signedval, remainder, &timely);
-                (meta, timely)
-            } else {
-                return Ok(None);
+            let timely = match parsed.is_valid_at(&now) {
+                Ok(()) => parsed.dangerously_assume_timely(),
+                Err(TimeValidityError::Expired(age))
+                    if matches!(source, DocSource::LocalCache)
+                        && age <= expired_consensus_tolerance(&self.writedir)? =>
+                {
+                    warn!(
+                        "Using a cached consensus that expired {:?} ago. Directory information may be out of date.",
+                        age
+                    );
+                    parsed.dangerously_assume_timely()
+                }
+                Err(_) => return Ok(None),
+            };
+            let meta = ConsensusMeta::from_unvalidated(signedval, remainder, &timely);
+            if matches!(source, DocSource::LocalCache) {
+                if self.cache_usage == CacheUsage::ConsensusFromCache
+                    && matches!(self.after, Some(after) if meta.lifetime().valid_after() <= after)
+                {
+                    // We can't download a replacement for the consensus we
+                    // have, so we have to wait for a newer one in the cache.
+                    debug!("Cached consensus is no newer than the one we have; ignoring it.");
+                    return Ok(None);
+                }
+                match check_consensus_age(&self.writedir, meta.lifetime()) {
+                    Err(Error::ConsensusTooOld { age, .. }) => {
+                        info!(
+                            "Ignoring a cached consensus that is {:?} old; we'll download a newer one.",
+                            age
+                        );
+                        return Ok(None);
+                    }
+                    r => r?,
+                }
             }
+            (meta, timely)
         };
 
         // Check out what authorities we believe in, and see if enough
@@ -294,7 +404,16 @@ impl<DM: WriteNetDir> GetConsensusState<DM> {
 
         let id_refs: Vec<_> = self.authority_ids.iter().collect();
         if !unvalidated.authorities_are_correct(&id_refs[..]) {
-            return Err(Error::UnrecognizedAuthorities);
+            let have = unvalidated.n_purported_signers(&id_refs[..]);
+            if have == 0 && unvalidated.signing_cert_ids().next().is_some() {
+                // It's signed, but by nobody we know: it's probably for
+                // some other network.
+                return Err(Error::UnrecognizedAuthorities);
+            }
+            return Err(Error::NotEnoughSignatures {
+                have,
+                need: id_refs.len() / 2 + 1,
+            });
         }
 
         // Make a set of all the certificates we want -- the subset of
@@ -384,6 +503,9 @@ impl<DM: WriteNetDir> DirState for GetCertsState<DM> {
         }
         .into()
     }
+    fn bootstrap_phase(&self) -> BootstrapPhase {
+        BootstrapPhase::GettingCerts
+    }
     fn dl_config(&self) -> Result<DownloadSchedule> {
         if let Some(wd) = Weak::upgrade(&self.writedir) {
             Ok(*wd.config().schedule().retry_certs())
@@ -492,6 +614,20 @@ impl<DM: WriteNetDir> DirState for GetCertsState<DM> {
                 .unvalidated
                 .check_signature(&self.certs[..])
                 .map_err(|e| Error::from_netdoc(consensus_source, e))?;
+            check_consensus_age(&self.writedir, validated.lifetime())?;
+            check_protocols(&self.writedir, &validated)?;
+            let consensus_only = match Weak::upgrade(&self.writedir) {
+                Some(wd) => wd.config().consensus_only(),
+                None => return Err(Error::ManagerDropped),
+            };
+            if consensus_only {
+                return Ok(Box::new(GotConsensusState::new(
+                    self.cache_usage,
+                    validated,
+                    self.consensus_meta,
+                    self.writedir,
+                )?));
+            }
             Ok(Box::new(GetMicrodescsState::new(
                 self.cache_usage,
                 validated,
@@ -541,6 +677,9 @@ struct GetMicrodescsState<DM: WriteNetDir> {
     ///
     /// Only cleared for testing.
     expire_when_complete: bool,
+    /// How many microdescriptors have been repeated within a single
+    /// download response, since we last reported them.
+    n_duplicates: usize,
 }
 
 /// A network directory that is not yet ready to become _the_ current network directory.
@@ -629,6 +768,7 @@ impl<DM: WriteNetDir> GetMicrodescsState<DM> {
             newly_listed: Vec::new(),
             reset_time,
             expire_when_complete: true,
+            n_duplicates: 0,
         };
 
         result.consider_upgrade();
@@ -642,6 +782,12 @@ impl<DM: WriteNetDir> GetMicrodescsState<DM> {
     where
         I: IntoIterator<Item = Microdesc>,
     {
+        let mds: Vec<_> = mds.into_iter().collect();
+        if let Some(wd) = Weak::upgrade(&self.writedir) {
+            for md in &mds {
+                wd.microdesc_validated(md);
+            }
+        }
         if let Some(p) = &mut self.partial {
             for md in mds {
                 self.newly_listed.push(*md.digest());
@@ -672,9 +818,7 @@ impl<DM: WriteNetDir> GetMicrodescsState<DM> {
                         // We re-set the parameters here, in case they have been
                         // reconfigured.
                         netdir.replace_overridden_parameters(wd.config().override_net_params());
-                        wd.netdir().replace(netdir);
-                        wd.netdir_consensus_changed();
-                        wd.netdir_descriptors_changed();
+                        wd.install_netdir(netdir);
                         return true;
                     }
                     Err(pending) => self.partial = Some(pending),
@@ -717,6 +861,12 @@ impl<DM: WriteNetDir> DirState for GetMicrodescsState<DM> {
         match ready {
             Readiness::Complete => self.missing.is_empty(),
             Readiness::Usable => self.partial.is_none(),
+            Readiness::Stale => {
+                self.partial.is_none()
+                    && current_time(&self.writedir)
+                        .map(|now| now >= self.meta.lifetime().fresh_until())
+                        .unwrap_or(false)
+            }
         }
     }
     fn can_advance(&self) -> bool {
@@ -731,6 +881,13 @@ impl<DM: WriteNetDir> DirState for GetMicrodescsState<DM> {
         }
         .into()
     }
+    fn bootstrap_phase(&self) -> BootstrapPhase {
+        if self.is_ready(Readiness::Complete) {
+            BootstrapPhase::Done
+        } else {
+            BootstrapPhase::GettingMicrodescs
+        }
+    }
     fn dl_config(&self) -> Result<DownloadSchedule> {
         if let Some(wd) = Weak::upgrade(&self.writedir) {
             Ok(*wd.config().schedule().retry_microdescs())
@@ -781,6 +938,8 @@ impl<DM: WriteNetDir> DirState for GetMicrodescsState<DM> {
             return Err(internal!("expected a microdesc request").into());
         };
         let mut new_mds = Vec::new();
+        let mut seen = HashSet::new();
+        let mut n_duplicates = 0;
         for anno in MicrodescReader::new(text, &AllowAnnotations::AnnotationsNotAllowed).flatten() {
             let txt = anno
                 .within(text)
@@ -793,9 +952,22 @@ impl<DM: WriteNetDir> DirState for GetMicrodescsState<DM> {
                 );
                 continue;
             }
+            if !seen.insert(*md.digest()) {
+                // We already have this one from this response: there's no
+                // sense in storing or announcing it twice.
+                n_duplicates += 1;
+                continue;
+            }
             self.missing.remove(md.digest());
             new_mds.push((txt, md));
         }
+        if n_duplicates > 0 {
+            debug!(
+                "Response repeated {} microdescriptor(s); the cache may be misbehaving.",
+                n_duplicates
+            );
+            self.n_duplicates += n_duplicates;
+        }
 
         let mark_listed = self.meta.lifetime().valid_after();
         if let Some(store) = storage {
@@ -823,6 +995,9 @@ impl<DM: WriteNetDir> DirState for GetMicrodescsState<DM> {
         }
         Ok(true)
     }
+    fn take_n_duplicates(&mut self) -> usize {
+        std::mem::take(&mut self.n_duplicates)
+    }
     fn advance(self: Box<Self>) -> Result<Box<dyn DirState>> {
         Ok(self)
     }
@@ -830,9 +1005,13 @@ impl<DM: WriteNetDir> DirState for GetMicrodescsState<DM> {
         Some(self.reset_time)
     }
     fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
-        let cache_usage = if self.cache_usage == CacheUsage::CacheOnly {
-            // Cache only means we can't ever download.
-            CacheUsage::CacheOnly
+        let cache_usage = if matches!(
+            self.cache_usage,
+            CacheUsage::CacheOnly | CacheUsage::ConsensusFromCache
+        ) {
+            // We can't ever download a consensus, so we keep looking in the
+            // cache.
+            self.cache_usage
         } else if self.is_ready(Readiness::Usable) {
             // If we managed to bootstrap a usable consensus, then we won't
             // accept our next consensus from the cache.
@@ -848,203 +1027,776 @@ impl<DM: WriteNetDir> DirState for GetMicrodescsState<DM> {
             cache_usage,
         )?))
     }
-}
-
-/// Choose a random download time to replace a consensus whose lifetime
-/// is `lifetime`.
-fn pick_download_time(lifetime: &Lifetime) -> SystemTime {
-    let (lowbound, uncertainty) = client_download_range(lifetime);
-    let zero = Duration::new(0, 0);
-    let t = lowbound + rand::thread_rng().gen_range(zero..uncertainty);
-    info!("The current consensus is fresh until {}, and valid until {}. I've picked {} as the earliest time to replace it.",
-          OffsetDateTime::from(lifetime.fresh_until()),
-          OffsetDateTime::from(lifetime.valid_until()),
-          OffsetDateTime::from(t));
-    t
-}
-
-/// Based on the lifetime for a consensus, return the time range during which
-/// clients should fetch the next one.
-fn client_download_range(lt: &Lifetime) -> (SystemTime, Duration) {
-    let valid_after = lt.valid_after();
-    let fresh_until = lt.fresh_until();
-    let valid_until = lt.valid_until();
-    let voting_interval = fresh_until
-        .duration_since(valid_after)
-        .expect("valid-after must precede fresh-until");
-    let whole_lifetime = valid_until
-        .duration_since(valid_after)
-        .expect("valid-after must precede valid-until");
-
-    // From dir-spec:
-    // "This time is chosen uniformly at random from the interval
-    // between the time 3/4 into the first interval after the
-    // consensus is no longer fresh, and 7/8 of the time remaining
-    // after that before the consensus is invalid."
-    let lowbound = voting_interval + (voting_interval * 3) / 4;
-    let remainder = whole_lifetime - lowbound;
-    let uncertainty = (remainder * 7) / 8;
-
-    (valid_after + lowbound, uncertainty)
-}
-
-/// Helper: call `now` on a Weak<WriteNetDir>.
-fn current_time<DM: WriteNetDir>(writedir: &Weak<DM>) -> Result<SystemTime> {
-    if let Some(writedir) = Weak::upgrade(writedir) {
-        Ok(writedir.now())
-    } else {
-        Err(Error::ManagerDropped)
-    }
-}
-
-#[cfg(test)]
-mod test {
-    #![allow(clippy::unwrap_used)]
-    #![allow(clippy::cognitive_complexity)]
-    use super::*;
-    use crate::{Authority, DownloadScheduleConfig};
-    use std::convert::TryInto;
-    use std::sync::{
-        atomic::{self, AtomicBool},
-        Arc,
-    };
-    use tempfile::TempDir;
-    use time::macros::datetime;
-    use tor_netdoc::doc::authcert::AuthCertKeyIds;
-
-    #[test]
-    fn download_schedule() {
-        let va = datetime!(2008-08-02 20:00 UTC).into();
-        let fu = datetime!(2008-08-02 21:00 UTC).into();
-        let vu = datetime!(2008-08-02 23:00 UTC).into();
-        let lifetime = Lifetime::new(va, fu, vu).unwrap();
-
-        let expected_start: SystemTime = datetime!(2008-08-02 21:45 UTC).into();
-        let expected_range = Duration::from_millis((75 * 60 * 1000) * 7 / 8);
-
-        let (start, range) = client_download_range(&lifetime);
-        assert_eq!(start, expected_start);
-        assert_eq!(range, expected_range);
-
-        for _ in 0..100 {
-            let when = pick_download_time(&lifetime);
-            assert!(when > va);
-            assert!(when >= expected_start);
-            assert!(when < vu);
-            assert!(when <= expected_start + range);
+    fn reset_partial(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        // If our consensus is still one that we'd use, there's no sense in
+        // fetching it again: keep it, and the microdescriptors we have, and
+        // try again for the rest.
+        if current_time(&self.writedir)? < self.reset_time {
+            Ok(self)
+        } else {
+            self.reset()
         }
     }
-
-    /// Makes a memory-backed storage.
-    fn temp_store() -> (TempDir, Mutex<DynStore>) {
-        let tempdir = TempDir::new().unwrap();
-
-        let store = crate::storage::SqliteStore::from_path(tempdir.path(), false).unwrap();
-
-        (tempdir, Mutex::new(Box::new(store)))
-    }
-
-    struct DirRcv {
-        cfg: Arc<DirMgrConfig>,
-        netdir: SharedMutArc<NetDir>,
-        consensus_changed: AtomicBool,
-        descriptors_changed: AtomicBool,
-        now: SystemTime,
-    }
-
-    impl DirRcv {
-        fn new(now: SystemTime, authorities: Option<Vec<Authority>>) -> Self {
-            let mut netcfg = crate::NetworkConfig::builder();
-            netcfg.fallback_caches(vec![]);
-            if let Some(a) = authorities {
-                netcfg.authorities(a);
-            }
-            let cfg = DirMgrConfig::builder()
-                .cache_path("/we_will_never_use_this/")
-                .network_config(netcfg.build().unwrap())
-                .build()
-                .unwrap();
-            let cfg = Arc::new(cfg);
-            DirRcv {
-                now,
-                cfg,
-                netdir: Default::default(),
-                consensus_changed: false.into(),
-                descriptors_changed: false.into(),
-            }
+    fn refetch_microdescs(&mut self, digests: &[MdDigest]) {
+        // Until we've installed our directory, we aren't the state that
+        // keeps the current one up to date.
+        if self.partial.is_some() {
+            return;
         }
+        let netdir = match Weak::upgrade(&self.writedir).and_then(|wd| wd.netdir().get()) {
+            Some(netdir) => netdir,
+            None => return,
+        };
+        // Only ask for the ones that our directory lists and lacks.
+        let wanted: HashSet<&MdDigest> = digests.iter().collect();
+        self.missing.extend(
+            netdir
+                .missing_microdescs()
+                .filter(|d| wanted.contains(d))
+                .copied(),
+        );
     }
+}
 
-    impl WriteNetDir for DirRcv {
-        fn config(&self) -> Arc<DirMgrConfig> {
-            Arc::clone(&self.cfg)
-        }
-        fn netdir(&self) -> &SharedMutArc<NetDir> {
-            &self.netdir
-        }
-        fn netdir_consensus_changed(&self) {
-            self.consensus_changed.store(true, atomic::Ordering::SeqCst);
-        }
-        fn netdir_descriptors_changed(&self) {
-            self.descriptors_changed
-                .store(true, atomic::Ordering::SeqCst);
-        }
-        fn now(&self) -> SystemTime {
-            self.now
+/// Final state in consensus-only mode: we have a validated consensus, and we
+/// don't want anything else.
+#[derive(Debug, Clone)]
+struct GotConsensusState<DM: WriteNetDir> {
+    /// How should we get the next consensus from the cache, if at all?
+    cache_usage: CacheUsage,
+    /// Metadata for the consensus we have.
+    meta: ConsensusMeta,
+    /// A time after which we should try to find a new consensus.  Since
+    /// this is randomized, we only compute it once.
+    reset_time: SystemTime,
+    /// The dirmgr to inform about our consensus.
+    writedir: Weak<DM>,
+}
+
+impl<DM: WriteNetDir> GotConsensusState<DM> {
+    /// Create a new [`GotConsensusState`] from a validated consensus, and
+    /// hand that consensus to `writedir`.
+    fn new(
+        cache_usage: CacheUsage,
+        consensus: MdConsensus,
+        meta: ConsensusMeta,
+        writedir: Weak<DM>,
+    ) -> Result<Self> {
+        let reset_time = pick_download_time(consensus.lifetime());
+        match Weak::upgrade(&writedir) {
+            Some(wd) => wd.consensus_only_validated(consensus, &meta),
+            None => return Err(Error::ManagerDropped),
         }
+        Ok(GotConsensusState {
+            cache_usage,
+            meta,
+            reset_time,
+            writedir,
+        })
     }
+}
 
-    // Test data
-    const CONSENSUS: &str = include_str!("../testdata/mdconsensus1.txt");
-    const CONSENSUS2: &str = include_str!("../testdata/mdconsensus2.txt");
-    const AUTHCERT_5696: &str = include_str!("../testdata/cert-5696.txt");
-    const AUTHCERT_5A23: &str = include_str!("../testdata/cert-5A23.txt");
-    #[allow(unused)]
-    const AUTHCERT_7C47: &str = include_str!("../testdata/cert-7C47.txt");
-    fn test_time() -> SystemTime {
-        datetime!(2020-08-07 12:42:45 UTC).into()
+impl<DM: WriteNetDir> DirState for GotConsensusState<DM> {
+    fn describe(&self) -> String {
+        "Have a consensus; not fetching microdescriptors.".to_string()
     }
-    fn rsa(s: &str) -> RsaIdentity {
-        let k = hex::decode(s).unwrap();
-        RsaIdentity::from_bytes(&k[..]).unwrap()
+    fn missing_docs(&self) -> Vec<DocId> {
+        Vec::new()
     }
-    fn test_authorities() -> Vec<Authority> {
-        fn a(s: &str) -> Authority {
-            Authority::builder()
-                .name("ignore")
-                .v3ident(rsa(s))
-                .build()
-                .unwrap()
+    fn is_ready(&self, ready: Readiness) -> bool {
+        match ready {
+            Readiness::Complete | Readiness::Usable => true,
+            Readiness::Stale => current_time(&self.writedir)
+                .map(|now| now >= self.meta.lifetime().fresh_until())
+                .unwrap_or(false),
         }
-        vec![
-            a("5696AB38CB3852AFA476A5C07B2D4788963D5567"),
-            a("5A23BA701776C9C1AB1C06E734E92AB3D5350D64"),
-            // This is an authority according to the consensus, but we'll
-            // pretend we don't recognize it, to make sure that we
-            // don't fetch or accept it.
-            // a("7C47DCB4A90E2C2B7C7AD27BD641D038CF5D7EBE"),
-        ]
     }
-    fn authcert_id_5696() -> AuthCertKeyIds {
-        AuthCertKeyIds {
-            id_fingerprint: rsa("5696ab38cb3852afa476a5c07b2d4788963d5567"),
-            sk_fingerprint: rsa("f6ed4aa64d83caede34e19693a7fcf331aae8a6a"),
-        }
+    fn can_advance(&self) -> bool {
+        false
     }
-    fn authcert_id_5a23() -> AuthCertKeyIds {
-        AuthCertKeyIds {
-            id_fingerprint: rsa("5a23ba701776c9c1ab1c06e734e92ab3d5350d64"),
-            sk_fingerprint: rsa("d08e965cc6dcb6cb6ed776db43e616e93af61177"),
+    fn bootstrap_status(&self) -> DirStatus {
+        DirStatusInner::Validated {
+            lifetime: self.meta.lifetime().clone(),
+            n_mds: (0, 0),
+            usable: true,
         }
+        .into()
     }
-    // remember, we're saying that we don't recognize this one as an authority.
-    fn authcert_id_7c47() -> AuthCertKeyIds {
-        AuthCertKeyIds {
-            id_fingerprint: rsa("7C47DCB4A90E2C2B7C7AD27BD641D038CF5D7EBE"),
-            sk_fingerprint: rsa("D3C013E0E6C82E246090D1C0798B75FCB7ACF120"),
+    fn bootstrap_phase(&self) -> BootstrapPhase {
+        BootstrapPhase::Done
+    }
+    fn dl_config(&self) -> Result<DownloadSchedule> {
+        if let Some(wd) = Weak::upgrade(&self.writedir) {
+            Ok(*wd.config().schedule().retry_consensus())
+        } else {
+            Err(Error::ManagerDropped)
         }
     }
-    fn microdescs() -> HashMap<MdDigest, String> {
+    fn add_from_cache(
+        &mut self,
+        _docs: HashMap<DocId, DocumentText>,
+        _storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+    fn add_from_download(
+        &mut self,
+        _text: &str,
+        _request: &ClientRequest,
+        _storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+    fn advance(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        Ok(self)
+    }
+    fn reset_time(&self) -> Option<SystemTime> {
+        Some(self.reset_time)
+    }
+    fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        let cache_usage = if matches!(
+            self.cache_usage,
+            CacheUsage::CacheOnly | CacheUsage::ConsensusFromCache
+        ) {
+            // We can't ever download a consensus, so we keep looking in the
+            // cache.
+            self.cache_usage
+        } else {
+            // We already have this consensus, so we won't accept our next
+            // one from the cache.
+            CacheUsage::MustDownload
+        };
+        Ok(Box::new(GetConsensusState::new(
+            self.writedir,
+            cache_usage,
+        )?))
+    }
+}
+
+/// A state for fetching the current votes of some directory authorities.
+///
+/// Unlike the other states here, this one doesn't help us build a
+/// directory: it only puts the votes in our cache, for callers that want to
+/// check the consensus process for themselves.  It never advances.
+#[cfg(feature = "votes")]
+#[derive(Clone, Debug)]
+pub(crate) struct GetVotesState<DM: WriteNetDir> {
+    /// The identities of the authorities whose votes we still need.
+    missing: HashSet<RsaIdentity>,
+    /// The bootstrap status to report while we're fetching votes.
+    ///
+    /// The votes have nothing to do with our directory, so we just report
+    /// whatever status it had when we started.
+    status: DirStatus,
+    /// The bootstrap phase to report while we're fetching votes, for the
+    /// same reason.
+    phase: BootstrapPhase,
+    /// A weak reference to the directory manager that wants us to
+    /// fetch this information.  When this references goes away, we exit.
+    writedir: Weak<DM>,
+}
+
+#[cfg(feature = "votes")]
+impl<DM: WriteNetDir> GetVotesState<DM> {
+    /// Create a new GetVotesState to fetch the votes of the authorities
+    /// with identities `ids`, reporting `status` and `phase` in the meantime.
+    pub(crate) fn new(
+        writedir: Weak<DM>,
+        ids: &[RsaIdentity],
+        status: DirStatus,
+        phase: BootstrapPhase,
+    ) -> Self {
+        GetVotesState {
+            missing: ids.iter().copied().collect(),
+            status,
+            phase,
+            writedir,
+        }
+    }
+}
+
+#[cfg(feature = "votes")]
+impl<DM: WriteNetDir> DirState for GetVotesState<DM> {
+    fn describe(&self) -> String {
+        format!("Fetching votes from {} authorities.", self.missing.len())
+    }
+    fn missing_docs(&self) -> Vec<DocId> {
+        self.missing.iter().copied().map(DocId::AuthVote).collect()
+    }
+    fn is_ready(&self, _ready: Readiness) -> bool {
+        self.missing.is_empty()
+    }
+    fn can_advance(&self) -> bool {
+        false
+    }
+    fn bootstrap_status(&self) -> DirStatus {
+        self.status.clone()
+    }
+    fn bootstrap_phase(&self) -> BootstrapPhase {
+        self.phase
+    }
+    fn dl_config(&self) -> Result<DownloadSchedule> {
+        if let Some(wd) = Weak::upgrade(&self.writedir) {
+            Ok(*wd.config().schedule().retry_consensus())
+        } else {
+            Err(Error::ManagerDropped)
+        }
+    }
+    fn add_from_cache(
+        &mut self,
+        _docs: HashMap<DocId, DocumentText>,
+        _storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        // The cache only knows the latest vote that we got from each
+        // authority, which may be for an old consensus: always download.
+        Ok(false)
+    }
+    fn add_from_download(
+        &mut self,
+        text: &str,
+        request: &ClientRequest,
+        storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        let requested: HashSet<_> = if let ClientRequest::AuthVotes(req) = request {
+            req.authority_ids().collect()
+        } else {
+            return Err(internal!("expected a vote request").into());
+        };
+        let mut new_votes = Vec::new();
+        for vote in split_votes(text) {
+            match vote_header(vote) {
+                Some((id, valid_after)) if requested.contains(&id) => {
+                    if self.missing.contains(&id) {
+                        new_votes.push((vote, valid_after, id));
+                    }
+                }
+                Some((id, _)) => warn!("Received a vote we did not ask for: {}", id),
+                None => warn!("Received a document that didn't look like a vote"),
+            }
+        }
+        if new_votes.is_empty() {
+            return Ok(false);
+        }
+
+        if let Some(store) = storage {
+            let mut w = store.lock().expect("Directory storage lock poisoned");
+            let to_store: Vec<_> = new_votes.iter().map(|(v, t, id)| (*v, *t, id)).collect();
+            w.store_votes(&to_store)?;
+        }
+        for (_, _, id) in &new_votes {
+            self.missing.remove(id);
+        }
+        Ok(true)
+    }
+    fn advance(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        Ok(self)
+    }
+    fn reset_time(&self) -> Option<SystemTime> {
+        None
+    }
+    fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        Ok(self)
+    }
+}
+
+/// Split `text` into the votes that it contains, each of which starts with
+/// a `network-status-version` line.
+#[cfg(feature = "votes")]
+fn split_votes(text: &str) -> Vec<&str> {
+    /// The keyword that starts every vote.
+    const START: &str = "network-status-version ";
+    let mut starts: Vec<usize> = text
+        .match_indices(START)
+        .map(|(pos, _)| pos)
+        .filter(|pos| *pos == 0 || text.as_bytes()[pos - 1] == b'\n')
+        .collect();
+    starts.push(text.len());
+    starts.windows(2).map(|w| &text[w[0]..w[1]]).collect()
+}
+
+/// If `vote` looks like a vote, return the identity of the authority that
+/// made it and the valid-after time of the consensus that it's for.
+///
+/// This only looks at the vote's header: we don't check its signature.
+#[cfg(feature = "votes")]
+fn vote_header(vote: &str) -> Option<(RsaIdentity, SystemTime)> {
+    let time_format =
+        time::format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").ok()?;
+    let mut is_vote = false;
+    let mut id = None;
+    let mut valid_after = None;
+    for line in vote.lines() {
+        let (keyword, args) = line.split_once(' ').unwrap_or((line, ""));
+        match keyword {
+            "vote-status" => is_vote = args == "vote",
+            "valid-after" => {
+                valid_after = time::PrimitiveDateTime::parse(args, &time_format)
+                    .ok()
+                    .map(|t| t.assume_utc().into());
+            }
+            // The first dir-source line is for the authority that made
+            // the vote: "dir-source nickname identity ...".
+            "dir-source" if id.is_none() => {
+                id = args
+                    .split(' ')
+                    .nth(1)
+                    .and_then(|h| hex::decode(h).ok())
+                    .and_then(|b| RsaIdentity::from_bytes(&b));
+            }
+            // The header is over once the router entries begin.
+            "r" => break,
+            _ => {}
+        }
+    }
+    if is_vote {
+        Some((id?, valid_after?))
+    } else {
+        None
+    }
+}
+
+/// A state for fetching a set of router descriptors, for callers that need
+/// them to build a particular path.
+///
+/// Like [`GetVotesState`], this has nothing to do with our directory: it
+/// stores what it gets in our cache, and it's done once it has everything.
+#[cfg(feature = "routerdesc")]
+pub(crate) struct GetRouterDescsState<DM: WriteNetDir> {
+    /// The digests of the router descriptors that we still need.
+    missing: HashSet<RdDigest>,
+    /// The bootstrap status to report while we're fetching router
+    /// descriptors.
+    status: DirStatus,
+    /// The bootstrap phase to report while we're fetching router
+    /// descriptors.
+    phase: BootstrapPhase,
+    /// A weak reference to the directory manager that wants us to
+    /// fetch this information.  When this references goes away, we exit.
+    writedir: Weak<DM>,
+}
+
+#[cfg(feature = "routerdesc")]
+impl<DM: WriteNetDir> GetRouterDescsState<DM> {
+    /// Create a new GetRouterDescsState to fetch the router descriptors
+    /// with digests `digests`, reporting `status` and `phase` in the
+    /// meantime.
+    pub(crate) fn new(
+        writedir: Weak<DM>,
+        digests: &[RdDigest],
+        status: DirStatus,
+        phase: BootstrapPhase,
+    ) -> Self {
+        GetRouterDescsState {
+            missing: digests.iter().copied().collect(),
+            status,
+            phase,
+            writedir,
+        }
+    }
+}
+
+#[cfg(feature = "routerdesc")]
+impl<DM: WriteNetDir> DirState for GetRouterDescsState<DM> {
+    fn describe(&self) -> String {
+        format!(
+            "Fetching router descriptors (we are missing {}).",
+            self.missing.len()
+        )
+    }
+    fn missing_docs(&self) -> Vec<DocId> {
+        self.missing
+            .iter()
+            .copied()
+            .map(DocId::RouterDesc)
+            .collect()
+    }
+    fn is_ready(&self, _ready: Readiness) -> bool {
+        self.missing.is_empty()
+    }
+    fn can_advance(&self) -> bool {
+        false
+    }
+    fn bootstrap_status(&self) -> DirStatus {
+        self.status.clone()
+    }
+    fn bootstrap_phase(&self) -> BootstrapPhase {
+        self.phase
+    }
+    fn dl_config(&self) -> Result<DownloadSchedule> {
+        if let Some(wd) = Weak::upgrade(&self.writedir) {
+            Ok(*wd.config().schedule().retry_microdescs())
+        } else {
+            Err(Error::ManagerDropped)
+        }
+    }
+    fn add_from_cache(
+        &mut self,
+        docs: HashMap<DocId, DocumentText>,
+        _storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        // We checked these before we stored them, so anything the cache
+        // has, we don't need to download.
+        let mut changed = false;
+        for id in docs.keys() {
+            if let DocId::RouterDesc(digest) = id {
+                changed |= self.missing.remove(digest);
+            }
+        }
+        Ok(changed)
+    }
+    fn add_from_download(
+        &mut self,
+        text: &str,
+        request: &ClientRequest,
+        storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        let requested: HashSet<_> = if let ClientRequest::RouterDescs(req) = request {
+            req.digests().copied().collect()
+        } else {
+            return Err(internal!("expected a router descriptor request").into());
+        };
+        let mut new_rds = Vec::new();
+        for rd in split_routerdescs(text) {
+            let digest = match crate::routerdesc_digest(rd.as_bytes()) {
+                Some(digest) => digest,
+                None => {
+                    warn!("Received a document that didn't look like a router descriptor");
+                    continue;
+                }
+            };
+            if !requested.contains(&digest) {
+                warn!("Received a router descriptor we did not ask for");
+                continue;
+            }
+            if !self.missing.contains(&digest) {
+                continue;
+            }
+            let checked = RouterDesc::parse(rd)
+                .ok()
+                .and_then(|rd| rd.check_signature().ok());
+            match (checked, routerdesc_published(rd)) {
+                (Some(_), Some(published)) => new_rds.push((rd, published, digest)),
+                _ => warn!("Received a router descriptor that we couldn't validate"),
+            }
+        }
+        if new_rds.is_empty() {
+            return Ok(false);
+        }
+
+        if let Some(store) = storage {
+            let mut w = store.lock().expect("Directory storage lock poisoned");
+            let to_store: Vec<_> = new_rds.iter().map(|(rd, t, d)| (*rd, *t, d)).collect();
+            w.store_routerdescs(&to_store)?;
+        }
+        for (_, _, digest) in &new_rds {
+            self.missing.remove(digest);
+        }
+        Ok(true)
+    }
+    fn advance(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        Ok(self)
+    }
+    fn reset_time(&self) -> Option<SystemTime> {
+        None
+    }
+    fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        Ok(self)
+    }
+}
+
+/// Split `text` into the router descriptors that it contains, each of which
+/// starts with a `router` line.
+#[cfg(feature = "routerdesc")]
+fn split_routerdescs(text: &str) -> Vec<&str> {
+    /// The keyword that starts every router descriptor.
+    const START: &str = "router ";
+    let mut starts: Vec<usize> = text
+        .match_indices(START)
+        .map(|(pos, _)| pos)
+        .filter(|pos| *pos == 0 || text.as_bytes()[pos - 1] == b'\n')
+        .collect();
+    starts.push(text.len());
+    starts.windows(2).map(|w| &text[w[0]..w[1]]).collect()
+}
+
+/// If `rd` looks like a router descriptor, return the time when it was
+/// published.
+#[cfg(feature = "routerdesc")]
+fn routerdesc_published(rd: &str) -> Option<SystemTime> {
+    let time_format =
+        time::format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").ok()?;
+    let args = rd
+        .lines()
+        .find_map(|line| line.strip_prefix("published "))?;
+    time::PrimitiveDateTime::parse(args, &time_format)
+        .ok()
+        .map(|t| t.assume_utc().into())
+}
+
+/// Choose a random download time to replace a consensus whose lifetime
+/// is `lifetime`.
+fn pick_download_time(lifetime: &Lifetime) -> SystemTime {
+    let (lowbound, uncertainty) = client_download_range(lifetime);
+    let zero = Duration::new(0, 0);
+    let t = lowbound + rand::thread_rng().gen_range(zero..uncertainty);
+    info!("The current consensus is fresh until {}, and valid until {}. I've picked {} as the earliest time to replace it.",
+          OffsetDateTime::from(lifetime.fresh_until()),
+          OffsetDateTime::from(lifetime.valid_until()),
+          OffsetDateTime::from(t));
+    t
+}
+
+/// Based on the lifetime for a consensus, return the time range during which
+/// clients should fetch the next one.
+fn client_download_range(lt: &Lifetime) -> (SystemTime, Duration) {
+    let valid_after = lt.valid_after();
+    let fresh_until = lt.fresh_until();
+    let valid_until = lt.valid_until();
+    let voting_interval = fresh_until
+        .duration_since(valid_after)
+        .expect("valid-after must precede fresh-until");
+    let whole_lifetime = valid_until
+        .duration_since(valid_after)
+        .expect("valid-after must precede valid-until");
+
+    // From dir-spec:
+    // "This time is chosen uniformly at random from the interval
+    // between the time 3/4 into the first interval after the
+    // consensus is no longer fresh, and 7/8 of the time remaining
+    // after that before the consensus is invalid."
+    let lowbound = voting_interval + (voting_interval * 3) / 4;
+    let remainder = whole_lifetime - lowbound;
+    let uncertainty = (remainder * 7) / 8;
+
+    (valid_after + lowbound, uncertainty)
+}
+
+/// Helper: look up how long a cached consensus may be used after it expires,
+/// according to the configuration of a Weak<WriteNetDir>.
+fn expired_consensus_tolerance<DM: WriteNetDir>(writedir: &Weak<DM>) -> Result<Duration> {
+    if let Some(writedir) = Weak::upgrade(writedir) {
+        Ok(writedir.config().expired_consensus_tolerance())
+    } else {
+        Err(Error::ManagerDropped)
+    }
+}
+
+/// Helper: return an error if a consensus with `lifetime` is older than the
+/// configuration of a Weak<WriteNetDir> allows.
+fn check_consensus_age<DM: WriteNetDir>(writedir: &Weak<DM>, lifetime: &Lifetime) -> Result<()> {
+    let (max_age, now) = if let Some(writedir) = Weak::upgrade(writedir) {
+        (writedir.config().max_consensus_age(), writedir.now())
+    } else {
+        return Err(Error::ManagerDropped);
+    };
+    if let Some(max_age) = max_age {
+        let age = now
+            .duration_since(lifetime.valid_after())
+            .unwrap_or_default();
+        if age > max_age {
+            return Err(Error::ConsensusTooOld { age, max_age });
+        }
+    }
+    Ok(())
+}
+
+/// The subprotocol versions that we implement as a client.
+///
+/// This needs to be kept up to date as tor-proto and tor-netdoc learn to
+/// speak more protocols.  We don't list any onion service protocols yet.
+const SUPPORTED_CLIENT_PROTOCOLS: &str =
+    "Cons=1-2 Desc=1-2 FlowCtrl=1 Link=4 Microdesc=1-2 Relay=2";
+
+/// Helper: compare the client protocol versions that `consensus` requires and
+/// recommends against the ones we implement.
+///
+/// If we are missing any required protocols, tell the Weak<WriteNetDir>
+/// about it, and return an error if its configuration says to refuse such a
+/// consensus.  Otherwise, warn about it, but only the first time: we'll see
+/// the same requirements in every consensus until we're upgraded.
+fn check_protocols<DM: WriteNetDir>(writedir: &Weak<DM>, consensus: &MdConsensus) -> Result<()> {
+    let writedir = Weak::upgrade(writedir).ok_or(Error::ManagerDropped)?;
+    let supported: tor_protover::Protocols = SUPPORTED_CLIENT_PROTOCOLS
+        .parse()
+        .map_err(|_| internal!("unparseable list of supported protocols"))?;
+    let status = consensus.client_protocol_status();
+
+    let missing = status.recommended().difference(&supported);
+    if !missing.is_empty() {
+        info!(
+            "The consensus recommends protocols that we don't implement: {}",
+            missing
+        );
+    }
+
+    let missing = status.required().difference(&supported);
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let first_time = writedir.required_protocols_missing();
+    if writedir.config().enforce_required_protocols() {
+        return Err(Error::MissingRequiredProtocols { missing });
+    }
+    if first_time {
+        warn!(
+            "The consensus requires protocols that we don't implement: {}. This version of Arti is too old; please upgrade.",
+            missing
+        );
+    } else {
+        debug!(
+            "The consensus still requires protocols that we don't implement: {}",
+            missing
+        );
+    }
+    Ok(())
+}
+
+/// Helper: call `now` on a Weak<WriteNetDir>.
+fn current_time<DM: WriteNetDir>(writedir: &Weak<DM>) -> Result<SystemTime> {
+    if let Some(writedir) = Weak::upgrade(writedir) {
+        Ok(writedir.now())
+    } else {
+        Err(Error::ManagerDropped)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::cognitive_complexity)]
+    use super::*;
+    use crate::testing::{test_authorities, CONSENSUS};
+    use crate::{Authority, DownloadScheduleConfig};
+    use std::convert::TryInto;
+    use std::sync::{
+        atomic::{self, AtomicBool},
+        Arc,
+    };
+    use tempfile::TempDir;
+    use time::macros::datetime;
+    use tor_netdoc::doc::authcert::AuthCertKeyIds;
+    use tor_rtcompat::SleepProvider;
+
+    #[test]
+    fn download_schedule() {
+        let va = datetime!(2008-08-02 20:00 UTC).into();
+        let fu = datetime!(2008-08-02 21:00 UTC).into();
+        let vu = datetime!(2008-08-02 23:00 UTC).into();
+        let lifetime = Lifetime::new(va, fu, vu).unwrap();
+
+        let expected_start: SystemTime = datetime!(2008-08-02 21:45 UTC).into();
+        let expected_range = Duration::from_millis((75 * 60 * 1000) * 7 / 8);
+
+        let (start, range) = client_download_range(&lifetime);
+        assert_eq!(start, expected_start);
+        assert_eq!(range, expected_range);
+
+        for _ in 0..100 {
+            let when = pick_download_time(&lifetime);
+            assert!(when > va);
+            assert!(when >= expected_start);
+            assert!(when < vu);
+            assert!(when <= expected_start + range);
+        }
+    }
+
+    /// Makes a memory-backed storage.
+    fn temp_store() -> (TempDir, Mutex<DynStore>) {
+        let tempdir = TempDir::new().unwrap();
+
+        let store = crate::storage::SqliteStore::from_path(tempdir.path(), false).unwrap();
+
+        (tempdir, Mutex::new(Box::new(store)))
+    }
+
+    struct DirRcv {
+        cfg: Arc<DirMgrConfig>,
+        netdir: SharedMutArc<NetDir>,
+        consensus: SharedMutArc<MdConsensus>,
+        consensus_changed: AtomicBool,
+        descriptors_changed: AtomicBool,
+        now: tor_rtmock::time::MockSleepProvider,
+    }
+
+    impl DirRcv {
+        fn new(now: SystemTime, authorities: Option<Vec<Authority>>) -> Self {
+            let mut netcfg = crate::NetworkConfig::builder();
+            netcfg.fallback_caches(vec![]);
+            if let Some(a) = authorities {
+                netcfg.authorities(a);
+            }
+            let cfg = DirMgrConfig::builder()
+                .cache_path("/we_will_never_use_this/")
+                .network_config(netcfg.build().unwrap())
+                .build()
+                .unwrap();
+            let cfg = Arc::new(cfg);
+            DirRcv {
+                now: tor_rtmock::time::MockSleepProvider::new(now),
+                cfg,
+                netdir: Default::default(),
+                consensus: Default::default(),
+                consensus_changed: false.into(),
+                descriptors_changed: false.into(),
+            }
+        }
+    }
+
+    impl WriteNetDir for DirRcv {
+        fn config(&self) -> Arc<DirMgrConfig> {
+            Arc::clone(&self.cfg)
+        }
+        fn netdir(&self) -> &SharedMutArc<NetDir> {
+            &self.netdir
+        }
+        fn netdir_consensus_changed(&self) {
+            self.consensus_changed.store(true, atomic::Ordering::SeqCst);
+        }
+        fn netdir_descriptors_changed(&self) {
+            self.descriptors_changed
+                .store(true, atomic::Ordering::SeqCst);
+        }
+        fn consensus_only_validated(&self, consensus: MdConsensus, _meta: &ConsensusMeta) {
+            self.consensus.replace(consensus);
+        }
+        fn now(&self) -> SystemTime {
+            self.now.wallclock()
+        }
+    }
+
+    // Test data
+    const CONSENSUS2: &str = include_str!("../testdata/mdconsensus2.txt");
+    const AUTHCERT_5696: &str = include_str!("../testdata/cert-5696.txt");
+    const AUTHCERT_5A23: &str = include_str!("../testdata/cert-5A23.txt");
+    #[allow(unused)]
+    const AUTHCERT_7C47: &str = include_str!("../testdata/cert-7C47.txt");
+    fn test_time() -> SystemTime {
+        datetime!(2020-08-07 12:42:45 UTC).into()
+    }
+    fn rsa(s: &str) -> RsaIdentity {
+        let k = hex::decode(s).unwrap();
+        RsaIdentity::from_bytes(&k[..]).unwrap()
+    }
+    fn authcert_id_5696() -> AuthCertKeyIds {
+        AuthCertKeyIds {
+            id_fingerprint: rsa("5696ab38cb3852afa476a5c07b2d4788963d5567"),
+            sk_fingerprint: rsa("f6ed4aa64d83caede34e19693a7fcf331aae8a6a"),
+        }
+    }
+    fn authcert_id_5a23() -> AuthCertKeyIds {
+        AuthCertKeyIds {
+            id_fingerprint: rsa("5a23ba701776c9c1ab1c06e734e92ab3d5350d64"),
+            sk_fingerprint: rsa("d08e965cc6dcb6cb6ed776db43e616e93af61177"),
+        }
+    }
+    // remember, we're saying that we don't recognize this one as an authority.
+    fn authcert_id_7c47() -> AuthCertKeyIds {
+        AuthCertKeyIds {
+            id_fingerprint: rsa("7C47DCB4A90E2C2B7C7AD27BD641D038CF5D7EBE"),
+            sk_fingerprint: rsa("D3C013E0E6C82E246090D1C0798B75FCB7ACF120"),
+        }
+    }
+    fn microdescs() -> HashMap<MdDigest, String> {
         const MICRODESCS: &str = include_str!("../testdata/microdescs.txt");
         let text = MICRODESCS;
         MicrodescReader::new(text, &AllowAnnotations::AnnotationsNotAllowed)
@@ -1157,6 +1909,46 @@ mod test {
         assert!(state.can_advance());
     }
 
+    #[test]
+    fn not_enough_signatures() {
+        // A consensus that's missing signatures from the authorities we
+        // recognize gets a specific error, and isn't stored.
+        let rcv = Arc::new(DirRcv::new(test_time(), Some(test_authorities())));
+        let (_tempdir, store) = temp_store();
+        let req = tor_dirclient::request::ConsensusRequest::new(ConsensusFlavor::Microdesc);
+        let req = crate::docid::ClientRequest::Consensus(req);
+
+        let sig_start = CONSENSUS
+            .find("directory-signature sha256 5696AB38")
+            .unwrap();
+        let sig_end = sig_start
+            + CONSENSUS[sig_start..]
+                .find("-----END SIGNATURE-----\n")
+                .unwrap()
+            + "-----END SIGNATURE-----\n".len();
+        let forged = format!("{}{}", &CONSENSUS[..sig_start], &CONSENSUS[sig_end..]);
+
+        let mut state =
+            GetConsensusState::new(Arc::downgrade(&rcv), CacheUsage::CacheOkay).unwrap();
+        let outcome = state.add_from_download(&forged, &req, Some(&store));
+        assert!(matches!(
+            outcome,
+            Err(Error::NotEnoughSignatures { have: 1, need: 2 })
+        ));
+        assert!(!state.can_advance());
+        assert!(store
+            .lock()
+            .unwrap()
+            .latest_consensus(ConsensusFlavor::Microdesc, None)
+            .unwrap()
+            .is_none());
+
+        // The real one is fine.
+        let outcome = state.add_from_download(CONSENSUS, &req, Some(&store));
+        assert!(outcome.unwrap());
+        assert!(state.can_advance());
+    }
+
     #[test]
     fn get_certs_state() {
         /// Construct a GetCertsState with our test data
@@ -1264,6 +2056,71 @@ mod test {
         // accept a certificate for an authority we don't believe in.
     }
 
+    #[test]
+    fn consensus_only() {
+        let mut rcv = DirRcv::new(test_time(), Some(test_authorities()));
+        let mut netcfg = crate::NetworkConfig::builder();
+        netcfg.fallback_caches(vec![]);
+        netcfg.authorities(test_authorities());
+        let cfg = DirMgrConfig::builder()
+            .cache_path("/we_will_never_use_this/")
+            .network_config(netcfg.build().unwrap())
+            .consensus_only(true)
+            .build()
+            .unwrap();
+        rcv.cfg = Arc::new(cfg);
+        let rcv = Arc::new(rcv);
+
+        let mut state =
+            GetConsensusState::new(Arc::downgrade(&rcv), CacheUsage::CacheOkay).unwrap();
+        let req = tor_dirclient::request::ConsensusRequest::new(ConsensusFlavor::Microdesc);
+        let req = crate::docid::ClientRequest::Consensus(req);
+        assert!(state.add_from_download(CONSENSUS, &req, None).unwrap());
+        let mut state = Box::new(state).advance().unwrap();
+
+        // Getting the certificates works just as usual.
+        let docs = vec![
+            (
+                DocId::AuthCert(authcert_id_5696()),
+                crate::storage::InputString::from(AUTHCERT_5696.to_owned()).into(),
+            ),
+            (
+                DocId::AuthCert(authcert_id_5a23()),
+                crate::storage::InputString::from(AUTHCERT_5A23.to_owned()).into(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        assert!(state.add_from_cache(docs, None).unwrap());
+        assert!(state.can_advance());
+        assert!(rcv.consensus.get().is_none());
+
+        // But once we have them, we're done: we never ask for any
+        // microdescriptors.
+        let state = state.advance().unwrap();
+        assert_eq!(
+            &state.describe(),
+            "Have a consensus; not fetching microdescriptors."
+        );
+        assert!(state.missing_docs().is_empty());
+        assert!(!state.can_advance());
+        assert!(state.is_ready(Readiness::Complete));
+        assert!(state.is_ready(Readiness::Usable));
+        assert!(!state.is_ready(Readiness::Stale));
+        assert_eq!(
+            state.bootstrap_status().to_string(),
+            "usable, fresh until 2020-08-07 12:43:00 UTC, and valid until 2020-08-07 12:43:20 UTC"
+        );
+
+        // We handed the consensus over, and didn't build a netdir.
+        assert!(rcv.consensus.get().is_some());
+        assert!(rcv.netdir.get().is_none());
+
+        // Resetting takes us back to the start.
+        let state = state.reset().unwrap();
+        assert_eq!(&state.describe(), "Downloading a consensus.");
+    }
+
     #[test]
     fn get_microdescs_state() {
         /// Construct a GetCertsState with our test data
@@ -1293,8 +2150,21 @@ mod test {
         let state = Box::new(state).reset().unwrap();
         assert_eq!(&state.describe(), "Looking for a consensus.");
 
+        // A partial reset keeps our consensus while it's still timely...
+        let (rcv, state) = new_getmicrodescs_state();
+        let reset_time = state.reset_time().unwrap();
+        let state = Box::new(state).reset_partial().unwrap();
+        assert_eq!(
+            &state.describe(),
+            "Downloading microdescriptors (we are missing 4)."
+        );
+        // ... but not once it's time to replace it.
+        rcv.now.jump_to(reset_time);
+        let state = state.reset_partial().unwrap();
+        assert_eq!(&state.describe(), "Looking for a consensus.");
+
         // Check the basics.
-        let (_rcv, mut state) = new_getmicrodescs_state();
+        let (rcv, mut state) = new_getmicrodescs_state();
         assert_eq!(
             &state.describe(),
             "Downloading microdescriptors (we are missing 4)."
@@ -1378,5 +2248,470 @@ mod test {
 
         let missing = state.missing_docs();
         assert!(missing.is_empty());
+
+        // Once the consensus is past its fresh-until time, the directory is
+        // still usable, but stale.
+        assert!(!state.is_ready(Readiness::Stale));
+        let fresh_until: SystemTime = datetime!(2021-10-27 21:27:00 UTC).into();
+        rcv.now.jump_to(fresh_until - Duration::from_secs(1));
+        assert!(!state.is_ready(Readiness::Stale));
+        rcv.now.jump_to(fresh_until);
+        assert!(state.is_ready(Readiness::Stale));
+        assert!(state.is_ready(Readiness::Usable));
+    }
+
+    #[test]
+    fn microdesc_stream() {
+        // Every microdescriptor that we take in comes out of the DirMgr's
+        // stream, in the order we parsed them.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use futures::{FutureExt, StreamExt};
+            let (_tempdir, mgr) = crate::test::new_mgr(rt);
+            let mgr = Arc::new(mgr);
+            let mut stream = mgr.microdescs();
+
+            let (signed, rest, consensus) = MdConsensus::parse(CONSENSUS2).unwrap();
+            let consensus = consensus
+                .dangerously_assume_timely()
+                .dangerously_assume_wellsigned();
+            let meta = ConsensusMeta::from_consensus(signed, rest, &consensus);
+            let mut state = GetMicrodescsState::new(
+                CacheUsage::CacheOkay,
+                consensus,
+                meta,
+                Arc::downgrade(&mgr),
+            )
+            .unwrap();
+            state.expire_when_complete = false;
+
+            // Nothing yet.
+            assert!(stream.next().now_or_never().is_none());
+
+            const MICRODESCS: &str = include_str!("../testdata/microdescs.txt");
+            let mut req = tor_dirclient::request::MicrodescRequest::new();
+            let mut in_order = Vec::new();
+            for anno in MicrodescReader::new(MICRODESCS, &AllowAnnotations::AnnotationsNotAllowed) {
+                let digest = *anno.unwrap().into_microdesc().digest();
+                req.push(digest);
+                in_order.push(digest);
+            }
+            assert_eq!(in_order.len(), 4);
+            let req = ClientRequest::Microdescs(req);
+            let outcome = state.add_from_download(MICRODESCS, &req, None);
+            assert!(outcome.unwrap());
+
+            for digest in in_order {
+                let md = stream.next().now_or_never().unwrap().unwrap();
+                assert_eq!(md.digest(), &digest);
+            }
+            assert!(stream.next().now_or_never().is_none());
+        });
+    }
+
+    #[test]
+    fn duplicate_microdescs() {
+        // If a response repeats a microdescriptor, we only take it once, and
+        // we notice.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use futures::{FutureExt, StreamExt};
+            let (_tempdir, mgr) = crate::test::new_mgr(rt);
+            let mgr = Arc::new(mgr);
+            let mut stream = mgr.microdescs();
+
+            let (signed, rest, consensus) = MdConsensus::parse(CONSENSUS2).unwrap();
+            let consensus = consensus
+                .dangerously_assume_timely()
+                .dangerously_assume_wellsigned();
+            let meta = ConsensusMeta::from_consensus(signed, rest, &consensus);
+            let mut state = GetMicrodescsState::new(
+                CacheUsage::CacheOkay,
+                consensus,
+                meta,
+                Arc::downgrade(&mgr),
+            )
+            .unwrap();
+            state.expire_when_complete = false;
+
+            // Ask for two microdescriptors, and get one of them twice.
+            let md_text = microdescs();
+            let mut digests: Vec<_> = md_text.keys().copied().collect();
+            digests.sort_unstable();
+            let (md1, md2) = (digests[0], digests[1]);
+            let mut req = tor_dirclient::request::MicrodescRequest::new();
+            req.push(md1);
+            req.push(md2);
+            let req = ClientRequest::Microdescs(req);
+            let response = format!(
+                "{}{}{}",
+                md_text.get(&md1).unwrap(),
+                md_text.get(&md2).unwrap(),
+                md_text.get(&md1).unwrap()
+            );
+            let (_store_dir, store) = temp_store();
+            let outcome = state.add_from_download(&response, &req, Some(&store));
+            assert!(outcome.unwrap());
+
+            // Each one counts once towards our progress...
+            assert_eq!(
+                state.bootstrap_status().to_string(),
+                "fetching microdescriptors (2/4)"
+            );
+            assert_eq!(state.missing_docs().len(), 2);
+            // ... and is announced once.
+            let mut announced: Vec<_> = std::iter::from_fn(|| stream.next().now_or_never())
+                .map(|md| *md.unwrap().digest())
+                .collect();
+            announced.sort_unstable();
+            assert_eq!(announced, vec![md1, md2]);
+
+            // We report the repeat once.
+            assert_eq!(state.take_n_duplicates(), 1);
+            assert_eq!(state.take_n_duplicates(), 0);
+        });
+    }
+
+    #[test]
+    fn flushed_microdescs_survive() {
+        // Microdescriptors that we download and then flush should be
+        // there when we open the cache again.
+        use crate::storage::{SqliteStore, Store};
+
+        let rcv = Arc::new(DirRcv::new(test_time(), Some(test_authorities())));
+        let (signed, rest, consensus) = MdConsensus::parse(CONSENSUS2).unwrap();
+        let consensus = consensus
+            .dangerously_assume_timely()
+            .dangerously_assume_wellsigned();
+        let meta = ConsensusMeta::from_consensus(signed, rest, &consensus);
+        let mut state =
+            GetMicrodescsState::new(CacheUsage::CacheOkay, consensus, meta, Arc::downgrade(&rcv))
+                .unwrap();
+        state.expire_when_complete = false;
+
+        let (tempdir, store) = temp_store();
+        let md_text = microdescs();
+        let mut req = tor_dirclient::request::MicrodescRequest::new();
+        let mut response = "".to_owned();
+        for (md_digest, text) in &md_text {
+            response.push_str(text);
+            req.push(*md_digest);
+        }
+        let req = ClientRequest::Microdescs(req);
+        let outcome = state.add_from_download(response.as_str(), &req, Some(&store));
+        assert!(outcome.unwrap());
+        assert!(state.is_ready(Readiness::Complete));
+
+        store.lock().unwrap().flush().unwrap();
+        // Close the store, so that its lock is released.
+        drop(store);
+
+        let reopened = SqliteStore::from_path(tempdir.path(), true).unwrap();
+        let digests: Vec<_> = md_text.keys().copied().collect();
+        let found = reopened.microdescs(&digests).unwrap();
+        assert_eq!(found.len(), md_text.len());
+        for (md_digest, text) in &md_text {
+            assert_eq!(found.get(md_digest), Some(text));
+        }
+    }
+
+    #[test]
+    fn invalidate_microdesc() {
+        // A microdescriptor that we invalidate should be missing again the
+        // next time we look for microdescriptors, and our download task
+        // should fetch it again without waiting for its next refresh.
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use tor_rtcompat::SleepProvider;
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            rt.jump_to(test_time());
+            let (_tempdir, mgr) = crate::test::new_mgr(rt.clone());
+            let mgr = Arc::new(mgr);
+            let (signed, rest, consensus) = MdConsensus::parse(CONSENSUS2).unwrap();
+            let consensus = consensus
+                .dangerously_assume_timely()
+                .dangerously_assume_wellsigned();
+            let meta = ConsensusMeta::from_consensus(signed, rest, &consensus);
+
+            let mut state = GetMicrodescsState::new(
+                CacheUsage::CacheOkay,
+                consensus.clone(),
+                meta.clone(),
+                Arc::downgrade(&mgr),
+            )
+            .unwrap();
+            state.expire_when_complete = false;
+            let md_text = microdescs();
+            let mut req = tor_dirclient::request::MicrodescRequest::new();
+            let mut response = "".to_owned();
+            for (md_digest, text) in &md_text {
+                response.push_str(text);
+                req.push(*md_digest);
+            }
+            let req = ClientRequest::Microdescs(req);
+            let outcome = state.add_from_download(response.as_str(), &req, Some(&mgr.store));
+            assert!(outcome.unwrap());
+            assert!(state.is_ready(Readiness::Complete));
+
+            let n_relays = mgr.netdir().unwrap().relays().count();
+            let digest = *md_text.keys().next().unwrap();
+            mgr.invalidate_microdesc(&digest).unwrap();
+            assert!(mgr.text(&DocId::Microdesc(digest)).unwrap().is_none());
+            assert_eq!(mgr.netdir().unwrap().relays().count(), n_relays - 1);
+
+            // Start over, as we would on our next download attempt: we find
+            // everything but the one we invalidated.
+            let mut fresh = GetMicrodescsState::new(
+                CacheUsage::CacheOkay,
+                consensus,
+                meta,
+                Arc::downgrade(&mgr),
+            )
+            .unwrap();
+            let cached = mgr.texts(fresh.missing_docs()).unwrap();
+            fresh.add_from_cache(cached, Some(&mgr.store)).unwrap();
+            assert_eq!(fresh.missing_docs(), vec![DocId::Microdesc(digest)]);
+
+            // Our download task, meanwhile, fetches it again as soon as it
+            // starts...
+            *mgr.canned_response.lock().unwrap() =
+                Some(crate::bootstrap::CannedResponse::new(&md_text[&digest]));
+            let refresher =
+                crate::DirMgr::download_forever(Arc::downgrade(&mgr), Box::new(state), None);
+            let controller = async {
+                let refetched = || {
+                    let text = mgr.text(&DocId::Microdesc(digest)).unwrap().unwrap();
+                    assert_eq!(text.as_str().unwrap(), md_text[&digest]);
+                    assert_eq!(mgr.netdir().unwrap().relays().count(), n_relays);
+                };
+                rt.sleep(Duration::from_secs(1)).await;
+                refetched();
+                assert!(mgr.next_refresh_time().is_some());
+
+                // ... and again when we invalidate it while it's waiting for
+                // its next refresh.
+                mgr.invalidate_microdesc(&digest).unwrap();
+                assert_eq!(mgr.netdir().unwrap().relays().count(), n_relays - 1);
+                rt.sleep(Duration::from_secs(1)).await;
+                refetched();
+                assert!(mgr.next_refresh_time().is_some());
+            };
+            rt.wait_for(futures::future::select(
+                Box::pin(refresher),
+                Box::pin(controller),
+            ))
+            .await;
+        });
+    }
+
+    #[test]
+    fn load_with_clock_skew() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            use crate::ClockSkew;
+
+            // Our local clock is more than a year fast: the test consensus
+            // and certificates will all look expired.
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let skew = Duration::from_secs(86400 * 400);
+            rt.jump_to(test_time() + skew);
+
+            let tempdir = TempDir::new().unwrap();
+            let mut netcfg = crate::NetworkConfig::builder();
+            netcfg
+                .fallback_caches(vec![])
+                .authorities(test_authorities());
+            let cfg = DirMgrConfig::builder()
+                .cache_path(tempdir.path())
+                .network_config(netcfg.build().unwrap())
+                .build()
+                .unwrap();
+            let mgr = Arc::new(crate::DirMgr::from_config(cfg, rt.clone(), None, false).unwrap());
+
+            // Put the consensus and its certificates in the cache.
+            {
+                let mut store = mgr.store.lock().unwrap();
+                let (signed, rest, consensus) = MdConsensus::parse(CONSENSUS).unwrap();
+                let consensus = consensus
+                    .dangerously_assume_timely()
+                    .dangerously_assume_wellsigned();
+                let meta = ConsensusMeta::from_consensus(signed, rest, &consensus);
+                store
+                    .store_consensus(&meta, ConsensusFlavor::Microdesc, true, CONSENSUS)
+                    .unwrap();
+                let year = Duration::from_secs(86400 * 365);
+                store
+                    .store_authcerts(&[
+                        (
+                            AuthCertMeta::new(authcert_id_5696(), test_time(), test_time() + year),
+                            AUTHCERT_5696,
+                        ),
+                        (
+                            AuthCertMeta::new(authcert_id_5a23(), test_time(), test_time() + year),
+                            AUTHCERT_5A23,
+                        ),
+                    ])
+                    .unwrap();
+            }
+
+            // Using the local clock, we can't accept the cached consensus.
+            let state =
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap();
+            let state = crate::bootstrap::load(Arc::clone(&mgr), Box::new(state))
+                .await
+                .unwrap();
+            assert_eq!(&state.describe(), "Looking for a consensus.");
+
+            // Once we're told how wrong our clock is, the consensus and
+            // certificates are timely, and we can advance to fetching
+            // microdescriptors.
+            mgr.set_clock_skew(Some(ClockSkew::Fast(skew)));
+            assert_eq!(mgr.trusted_now(), test_time());
+            assert_eq!(mgr.local_time(test_time()), rt.wallclock());
+            let state =
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap();
+            let state = crate::bootstrap::load(Arc::clone(&mgr), Box::new(state))
+                .await
+                .unwrap();
+            assert_eq!(
+                &state.describe(),
+                "Downloading microdescriptors (we are missing 6)."
+            );
+        });
+    }
+
+    #[test]
+    fn load_expired_consensus() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            // Our clock is correct, but our cached consensus expired an hour
+            // ago.
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let valid_until: SystemTime = datetime!(2020-08-07 12:43:20 UTC).into();
+            rt.jump_to(valid_until + Duration::from_secs(3600));
+
+            let tempdir = TempDir::new().unwrap();
+            let make_cfg = |tolerance| {
+                let mut netcfg = crate::NetworkConfig::builder();
+                netcfg
+                    .fallback_caches(vec![])
+                    .authorities(test_authorities());
+                DirMgrConfig::builder()
+                    .cache_path(tempdir.path())
+                    .network_config(netcfg.build().unwrap())
+                    .expired_consensus_tolerance(tolerance)
+                    .build()
+                    .unwrap()
+            };
+            let mgr = Arc::new(
+                crate::DirMgr::from_config(
+                    make_cfg(Duration::from_secs(0)),
+                    rt.clone(),
+                    None,
+                    false,
+                )
+                .unwrap(),
+            );
+
+            // Put the consensus and its certificates in the cache.
+            {
+                let mut store = mgr.store.lock().unwrap();
+                let (signed, rest, consensus) = MdConsensus::parse(CONSENSUS).unwrap();
+                let consensus = consensus
+                    .dangerously_assume_timely()
+                    .dangerously_assume_wellsigned();
+                let meta = ConsensusMeta::from_consensus(signed, rest, &consensus);
+                store
+                    .store_consensus(&meta, ConsensusFlavor::Microdesc, false, CONSENSUS)
+                    .unwrap();
+                let year = Duration::from_secs(86400 * 365);
+                store
+                    .store_authcerts(&[
+                        (
+                            AuthCertMeta::new(authcert_id_5696(), test_time(), test_time() + year),
+                            AUTHCERT_5696,
+                        ),
+                        (
+                            AuthCertMeta::new(authcert_id_5a23(), test_time(), test_time() + year),
+                            AUTHCERT_5A23,
+                        ),
+                    ])
+                    .unwrap();
+            }
+            assert!(mgr.consensus_is_expired());
+
+            // By default, we won't use the expired consensus.
+            let state =
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap();
+            let state = crate::bootstrap::load(Arc::clone(&mgr), Box::new(state))
+                .await
+                .unwrap();
+            assert_eq!(&state.describe(), "Looking for a consensus.");
+
+            // If we tolerate less expiry than it has, we still won't.
+            mgr.config.replace(make_cfg(Duration::from_secs(1800)));
+            let state =
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap();
+            let state = crate::bootstrap::load(Arc::clone(&mgr), Box::new(state))
+                .await
+                .unwrap();
+            assert_eq!(&state.describe(), "Looking for a consensus.");
+
+            // But once we're configured to tolerate it, we use it.
+            mgr.config.replace(make_cfg(Duration::from_secs(86400)));
+            let state =
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::CacheOkay).unwrap();
+            let state = crate::bootstrap::load(Arc::clone(&mgr), Box::new(state))
+                .await
+                .unwrap();
+            assert_eq!(
+                &state.describe(),
+                "Downloading microdescriptors (we are missing 6)."
+            );
+            assert!(mgr.consensus_is_expired());
+
+            // We never accept an expired consensus from the network, though.
+            let mut state =
+                GetConsensusState::new(Arc::downgrade(&mgr), CacheUsage::MustDownload).unwrap();
+            let req = tor_dirclient::request::ConsensusRequest::new(ConsensusFlavor::Microdesc);
+            let req = crate::docid::ClientRequest::Consensus(req);
+            let outcome = state.add_from_download(CONSENSUS, &req, None);
+            assert!(!outcome.unwrap());
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "votes")]
+    fn vote_headers() {
+        let vote = |status: &str, id: &str| {
+            format!(
+                "network-status-version 3
+vote-status {}
+valid-after 2020-08-07 12:00:00
+dir-source test {} 127.0.0.1 127.0.0.1 80 443
+r relay AAAA
+dir-source not-the-voter 0000000000000000000000000000000000000000 1.2.3.4 1.2.3.4 80 443
+",
+                status, id
+            )
+        };
+        let v1 = vote("vote", "2727272727272727272727272727272727272727");
+        let v2 = vote("vote", "2828282828282828282828282828282828282828");
+        let both = format!("{}{}", v1, v2);
+
+        let votes = split_votes(&both);
+        assert_eq!(votes, vec![v1.as_str(), v2.as_str()]);
+
+        let (id, valid_after) = vote_header(&v1).unwrap();
+        assert_eq!(id, [0x27; 20].into());
+        assert_eq!(
+            OffsetDateTime::from(valid_after).unix_timestamp(),
+            1_596_801_600 // 2020-08-07 12:00:00 UTC
+        );
+
+        // A consensus isn't a vote, and neither is a vote with a bad
+        // identity.
+        assert!(vote_header(&vote(
+            "consensus",
+            "2727272727272727272727272727272727272727"
+        ))
+        .is_none());
+        assert!(vote_header(&vote("vote", "not hex")).is_none());
+        assert!(split_votes("").is_empty());
     }
 }
@@ -13,21 +13,81 @@ use tor_netdoc::doc::netstatus::ConsensusFlavor;
 #[cfg(feature = "routerdesc")]
 use tor_netdoc::doc::routerdesc::RdDigest;
 
+use tor_llcrypto::pk::rsa::RsaIdentity;
+
 use crate::docmeta::{AuthCertMeta, ConsensusMeta};
 use crate::{Error, Result};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::sync::Mutex;
 use std::time::SystemTime;
 use std::{path::Path, str::Utf8Error};
 use time::Duration;
+use tracing::warn;
 
+pub(crate) mod layered;
 pub(crate) mod sqlite;
 
+pub(crate) use layered::LayeredStore;
 pub(crate) use sqlite::SqliteStore;
 
 /// Convenient Sized & dynamic [`Store`]
 pub(crate) type DynStore = Box<dyn Store + Send>;
 
+/// A group of writes to a store that we keep all together, or not at all.
+///
+/// If this is dropped before we call [`WriteScope::commit`] (for example,
+/// because the future that was making the writes was cancelled, or because
+/// it failed partway through), we undo every write made since it began.
+pub(crate) struct WriteScope<'a> {
+    /// The store that we're writing to.
+    store: &'a Mutex<DynStore>,
+    /// True once we've kept the writes.
+    committed: bool,
+}
+
+impl<'a> WriteScope<'a> {
+    /// Begin a group of writes to `store`.
+    ///
+    /// If we begin another group on the same store before this one is done,
+    /// that group must be done first.
+    pub(crate) fn begin(store: &'a Mutex<DynStore>) -> Result<Self> {
+        store
+            .lock()
+            .expect("Directory storage lock poisoned")
+            .begin_writes()?;
+        Ok(WriteScope {
+            store,
+            committed: false,
+        })
+    }
+
+    /// Keep every write that we've made since this group began.
+    pub(crate) fn commit(mut self) -> Result<()> {
+        self.store
+            .lock()
+            .expect("Directory storage lock poisoned")
+            .commit_writes()?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for WriteScope<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        // If the lock is poisoned, somebody panicked while writing: we
+        // can't do anything sensible with the store.
+        if let Ok(mut store) = self.store.lock() {
+            if let Err(e) = store.rollback_writes() {
+                warn!("Unable to undo writes to directory cache: {}", e);
+            }
+        }
+    }
+}
+
 /// A document returned by a directory manager.
 ///
 /// This document may be in memory, or may be mapped from a cache.  It is
@@ -59,16 +119,24 @@ impl DocumentText {
 
     /// Create a new DocumentText holding the provided string.
     pub(crate) fn from_string(s: String) -> Self {
-        DocumentText {
-            s: InputString::Utf8(s),
-        }
+        DocumentText { s: s.into() }
     }
 }
 
 /// An abstraction over a possible string that we've loaded or mapped from
 /// a cache.
+///
+/// A [`Store`] returns these when it reads a document.  Other crates can
+/// make one from a `String` or a `Vec<u8>`.
 #[derive(Debug)]
-pub(crate) enum InputString {
+pub struct InputString(InputStringInner);
+
+/// The representation of an [`InputString`].
+///
+/// (This is a separate type so that nobody outside this module can claim
+/// that some bytes have been validated as UTF-8.)
+#[derive(Debug)]
+enum InputStringInner {
     /// A string that's been validated as UTF-8
     Utf8(String),
     /// A set of unvalidated bytes.
@@ -101,9 +169,9 @@ impl InputString {
         // this function is called so remember the result
         // we got with `validated`
 
-        match self {
-            InputString::Utf8(s) => Ok(&s[..]),
-            InputString::UncheckedBytes { bytes, validated } => {
+        match &self.0 {
+            InputStringInner::Utf8(s) => Ok(&s[..]),
+            InputStringInner::UncheckedBytes { bytes, validated } => {
                 if *validated.borrow() {
                     unsafe { Ok(std::str::from_utf8_unchecked(&bytes[..])) }
                 } else {
@@ -113,7 +181,7 @@ impl InputString {
                 }
             }
             #[cfg(feature = "mmap")]
-            InputString::MappedBytes { bytes, validated } => {
+            InputStringInner::MappedBytes { bytes, validated } => {
                 if *validated.borrow() {
                     unsafe { Ok(std::str::from_utf8_unchecked(&bytes[..])) }
                 } else {
@@ -138,48 +206,52 @@ impl InputString {
                 memmap2::Mmap::map(&f)
             };
             if let Ok(bytes) = mapping {
-                return Ok(InputString::MappedBytes {
+                return Ok(InputString(InputStringInner::MappedBytes {
                     bytes,
                     validated: RefCell::new(false),
-                });
+                }));
             }
         }
         use std::io::{BufReader, Read};
         let mut f = BufReader::new(f);
         let mut result = String::new();
         f.read_to_string(&mut result)?;
-        Ok(InputString::Utf8(result))
+        Ok(result.into())
     }
 }
 
 impl AsRef<[u8]> for InputString {
     fn as_ref(&self) -> &[u8] {
-        match self {
-            InputString::Utf8(s) => s.as_ref(),
-            InputString::UncheckedBytes { bytes, .. } => &bytes[..],
+        match &self.0 {
+            InputStringInner::Utf8(s) => s.as_ref(),
+            InputStringInner::UncheckedBytes { bytes, .. } => &bytes[..],
             #[cfg(feature = "mmap")]
-            InputString::MappedBytes { bytes, .. } => &bytes[..],
+            InputStringInner::MappedBytes { bytes, .. } => &bytes[..],
         }
     }
 }
 
 impl From<String> for InputString {
     fn from(s: String) -> InputString {
-        InputString::Utf8(s)
+        InputString(InputStringInner::Utf8(s))
     }
 }
 
 impl From<Vec<u8>> for InputString {
     fn from(bytes: Vec<u8>) -> InputString {
-        InputString::UncheckedBytes {
+        InputString(InputStringInner::UncheckedBytes {
             bytes,
             validated: RefCell::new(false),
-        }
+        })
     }
 }
 
 /// Configuration of expiration of each element of a [`Store`].
-pub(crate) struct ExpirationConfig {
+///
+/// Each value says how long to keep a document of some kind after it has
+/// expired.
+#[derive(Clone, Debug)]
+pub struct ExpirationConfig {
     /// How long to keep expired router descriptors.
     pub(super) router_descs: Duration,
     /// How long to keep expired microdescriptors descriptors.
@@ -190,6 +262,31 @@ pub(crate) struct ExpirationConfig {
     pub(super) consensuses: Duration,
 }
 
+impl ExpirationConfig {
+    /// Return how long to keep expired router descriptors.
+    pub fn router_descs(&self) -> std::time::Duration {
+        std_duration(self.router_descs)
+    }
+    /// Return how long to keep expired microdescriptors.
+    pub fn microdescs(&self) -> std::time::Duration {
+        std_duration(self.microdescs)
+    }
+    /// Return how long to keep expired authority certificates.
+    pub fn authcerts(&self) -> std::time::Duration {
+        std_duration(self.authcerts)
+    }
+    /// Return how long to keep expired consensus documents.
+    pub fn consensuses(&self) -> std::time::Duration {
+        std_duration(self.consensuses)
+    }
+}
+
+/// Helper: convert a (non-negative) [`Duration`] into a
+/// [`std::time::Duration`].
+fn std_duration(d: Duration) -> std::time::Duration {
+    std::convert::TryFrom::try_from(d).unwrap_or_default()
+}
+
 /// Configuration of expiration shared between [`Store`] implementations.
 pub(crate) const EXPIRATION_DEFAULTS: ExpirationConfig = {
     ExpirationConfig {
@@ -204,9 +301,17 @@ pub(crate) const EXPIRATION_DEFAULTS: ExpirationConfig = {
 
 /// Representation of a storage.
 ///
+/// By default, a `DirMgr` keeps its documents in a SQLite database under its
+/// configured cache path.  To keep them somewhere else, implement this trait
+/// and pass your implementation to
+/// [`DirMgr::create_unbootstrapped_with_store`](crate::DirMgr::create_unbootstrapped_with_store),
+/// or to
+/// [`DirMgr::create_unbootstrapped_with_stores`](crate::DirMgr::create_unbootstrapped_with_stores)
+/// if you want to read from one store and write to another.
+///
 /// When creating an instance of this [`Store`], it should try to grab the lock during
 /// initialization (`is_readonly() iff some other implementation grabbed it`).
-pub(crate) trait Store {
+pub trait Store {
     /// Return true if this [`Store`] is opened in read-only mode.
     fn is_readonly(&self) -> bool;
     /// Try to upgrade from a read-only connection to a read-write connection.
@@ -259,15 +364,22 @@ pub(crate) trait Store {
     fn authcerts(&self, certs: &[AuthCertKeyIds]) -> Result<HashMap<AuthCertKeyIds, String>>;
     /// Save a list of authority certificates to the cache.
     fn store_authcerts(&mut self, certs: &[(AuthCertMeta, &str)]) -> Result<()>;
+    /// Remove every authority certificate in `certs` from the cache.
+    fn delete_authcerts(&mut self, certs: &[AuthCertKeyIds]) -> Result<()>;
 
     /// Read all the microdescriptors listed in `input` from the cache.
     fn microdescs(&self, digests: &[MdDigest]) -> Result<HashMap<MdDigest, String>>;
     /// Store every microdescriptor in `input` into the cache, and say that
     /// it was last listed at `when`.
+    ///
+    /// Microdescriptors are stored by digest: one that is listed in several
+    /// consensuses is only stored once.
     fn store_microdescs(&mut self, digests: &[(&str, &MdDigest)], when: SystemTime) -> Result<()>;
     /// Update the `last-listed` time of every microdescriptor in
     /// `input` to `when` or later.
     fn update_microdescs_listed(&mut self, digests: &[MdDigest], when: SystemTime) -> Result<()>;
+    /// Remove every microdescriptor in `digests` from the cache.
+    fn delete_microdescs(&mut self, digests: &[MdDigest]) -> Result<()>;
 
     /// Read all the microdescriptors listed in `input` from the cache.
     ///
@@ -278,6 +390,94 @@ pub(crate) trait Store {
     #[cfg(feature = "routerdesc")]
     #[allow(unused)]
     fn store_routerdescs(&mut self, digests: &[(&str, SystemTime, &RdDigest)]) -> Result<()>;
+    /// Remove every router descriptor in `digests` from the cache.
+    #[cfg(feature = "routerdesc")]
+    fn delete_routerdescs(&mut self, digests: &[RdDigest]) -> Result<()>;
+
+    /// Read the latest vote from each of the authorities in `ids` from the
+    /// cache.
+    ///
+    /// Only available when the `votes` feature is present.
+    #[cfg(feature = "votes")]
+    fn votes(&self, ids: &[RsaIdentity]) -> Result<HashMap<RsaIdentity, String>>;
+    /// Store every authority vote in `votes` into the cache, along with
+    /// its valid-after time and the identity of the authority that made it.
+    #[cfg(feature = "votes")]
+    fn store_votes(&mut self, votes: &[(&str, SystemTime, &RsaIdentity)]) -> Result<()>;
+
+    /// Read our saved estimate of how long each directory cache takes to
+    /// answer us, by the cache's RSA identity.
+    ///
+    /// By default, we have none.
+    fn cache_latencies(&self) -> Result<HashMap<RsaIdentity, std::time::Duration>> {
+        Ok(HashMap::new())
+    }
+    /// Replace our saved estimates of how long each directory cache takes
+    /// to answer us with `latencies`.
+    ///
+    /// By default, we don't save them.
+    fn store_cache_latencies(
+        &mut self,
+        _latencies: &HashMap<RsaIdentity, std::time::Duration>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Read our saved record of when each directory cache has recently
+    /// failed us, by the cache's RSA identity.
+    ///
+    /// By default, we have none.
+    fn load_source_reputation(&self) -> Result<HashMap<RsaIdentity, Vec<SystemTime>>> {
+        Ok(HashMap::new())
+    }
+    /// Replace our saved record of when each directory cache has recently
+    /// failed us with `errors`.
+    ///
+    /// By default, we don't save it.
+    fn store_source_reputation(
+        &mut self,
+        _errors: &HashMap<RsaIdentity, Vec<SystemTime>>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Start a group of writes that should be kept all together, or not at
+    /// all.
+    ///
+    /// Everything that we store after this, until the matching call to
+    /// [`commit_writes`](Store::commit_writes) or
+    /// [`rollback_writes`](Store::rollback_writes), is part of the group.
+    /// Groups can nest: if we keep an inner group, its writes are still
+    /// part of the group around it.
+    ///
+    /// By default, this does nothing, and each write is kept as soon as
+    /// it's made.
+    fn begin_writes(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Keep every write since the last call to
+    /// [`begin_writes`](Store::begin_writes).
+    ///
+    /// By default, this does nothing.
+    fn commit_writes(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Undo every write since the last call to
+    /// [`begin_writes`](Store::begin_writes).
+    ///
+    /// By default, this does nothing.
+    fn rollback_writes(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Make sure that everything we've been asked to store so far has been
+    /// written out, so that it will survive if we exit.
+    ///
+    /// Implementations that write everything as soon as they're asked to
+    /// don't need to do anything here.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[cfg(test)]
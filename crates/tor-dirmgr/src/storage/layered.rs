@@ -0,0 +1,436 @@
+//! A [`Store`] that reads from two other stores and writes to only one.
+//!
+//! We use this when our documents come from a cache that we may not change
+//! (for example, one that's shared among several clients), but we still
+//! want to keep the documents that we download ourselves.
+
+use super::{ExpirationConfig, InputString, Store};
+use crate::docmeta::{AuthCertMeta, ConsensusMeta};
+use crate::Result;
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::time::{Duration, SystemTime};
+
+use tor_netdoc::doc::authcert::AuthCertKeyIds;
+use tor_netdoc::doc::microdesc::MdDigest;
+use tor_netdoc::doc::netstatus::{ConsensusFlavor, MdConsensus};
+
+#[cfg(feature = "routerdesc")]
+use tor_netdoc::doc::routerdesc::RdDigest;
+
+use tor_checkable::Timebound;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+
+/// A [`Store`] made of a read-only `base` store and a writable `scratch`
+/// store.
+///
+/// We look for documents in both stores, and when both have what we're
+/// looking for, we prefer the one in `scratch`, except that we always give
+/// the newer of their latest consensuses.  We never make any changes to
+/// `base`: everything we're asked to store, mark, delete, or expire only
+/// happens in `scratch`.
+///
+/// So that a deleted document doesn't come back from `base`, we remember
+/// which ones we've deleted, and hide `base`'s copies of them until we
+/// store them again.  We only remember this for as long as the
+/// `LayeredStore` exists.  If we hide `base`'s latest consensus, we don't
+/// look for an older one there.
+pub(crate) struct LayeredStore {
+    /// The store that we only read from.
+    base: Box<dyn Store + Send>,
+    /// The store that we read from and write to.
+    scratch: Box<dyn Store + Send>,
+    /// The SHA3-256 digests of the consensuses that we've deleted.
+    deleted_consensuses: HashSet<[u8; 32]>,
+    /// The authority certificates that we've deleted.
+    deleted_authcerts: HashSet<AuthCertKeyIds>,
+    /// The microdescriptors that we've deleted.
+    deleted_microdescs: HashSet<MdDigest>,
+    /// The router descriptors that we've deleted.
+    #[cfg(feature = "routerdesc")]
+    deleted_routerdescs: HashSet<RdDigest>,
+}
+
+impl LayeredStore {
+    /// Construct a new `LayeredStore` that reads from `base` and `scratch`,
+    /// and writes only to `scratch`.
+    pub(crate) fn new(base: Box<dyn Store + Send>, scratch: Box<dyn Store + Send>) -> Self {
+        LayeredStore {
+            base,
+            scratch,
+            deleted_consensuses: HashSet::new(),
+            deleted_authcerts: HashSet::new(),
+            deleted_microdescs: HashSet::new(),
+            #[cfg(feature = "routerdesc")]
+            deleted_routerdescs: HashSet::new(),
+        }
+    }
+
+    /// Return true if `meta` describes a consensus that we've deleted.
+    fn consensus_deleted(&self, meta: &ConsensusMeta) -> bool {
+        self.deleted_consensuses.contains(meta.sha3_256_of_whole())
+    }
+}
+
+/// Helper: return everything in `base`, replaced with anything in `scratch`
+/// that has the same key.
+fn merge<K: Hash + Eq>(
+    mut base: HashMap<K, String>,
+    scratch: HashMap<K, String>,
+) -> HashMap<K, String> {
+    base.extend(scratch);
+    base
+}
+
+/// Helper: return everything in `found` whose key isn't in `deleted`.
+fn hide<K: Hash + Eq>(mut found: HashMap<K, String>, deleted: &HashSet<K>) -> HashMap<K, String> {
+    found.retain(|k, _| !deleted.contains(k));
+    found
+}
+
+/// Helper: return the metadata for the consensus in `text`, if it is a
+/// microdescriptor consensus that we can parse.
+///
+/// We don't check its signatures or whether it is timely.
+fn peek_consensus_meta(text: &InputString) -> Option<ConsensusMeta> {
+    let (signed, remainder, parsed) = MdConsensus::parse(text.as_str().ok()?).ok()?;
+    Some(ConsensusMeta::from_unvalidated(
+        signed,
+        remainder,
+        &parsed.dangerously_assume_timely(),
+    ))
+}
+
+impl Store for LayeredStore {
+    fn is_readonly(&self) -> bool {
+        self.scratch.is_readonly()
+    }
+    fn upgrade_to_readwrite(&mut self) -> Result<bool> {
+        self.scratch.upgrade_to_readwrite()
+    }
+    fn expire_all(&mut self, expiration: &ExpirationConfig) -> Result<()> {
+        self.scratch.expire_all(expiration)
+    }
+
+    fn latest_consensus(
+        &self,
+        flavor: ConsensusFlavor,
+        pending: Option<bool>,
+    ) -> Result<Option<InputString>> {
+        let scratch = self.scratch.latest_consensus(flavor, pending)?;
+        let base = match self.base.latest_consensus(flavor, pending)? {
+            Some(text) => text,
+            None => return Ok(scratch),
+        };
+        let base_meta = peek_consensus_meta(&base);
+        if base_meta
+            .as_ref()
+            .map_or(false, |m| self.consensus_deleted(m))
+        {
+            return Ok(scratch);
+        }
+        let scratch = match scratch {
+            Some(text) => text,
+            None => return Ok(Some(base)),
+        };
+        // If we can't tell which one is newer, we prefer our own.
+        let base_is_newer = match (base_meta, peek_consensus_meta(&scratch)) {
+            (Some(b), Some(s)) => b.lifetime().valid_after() > s.lifetime().valid_after(),
+            (_, _) => false,
+        };
+        Ok(Some(if base_is_newer { base } else { scratch }))
+    }
+    fn latest_consensus_meta(&self, flavor: ConsensusFlavor) -> Result<Option<ConsensusMeta>> {
+        let scratch = self.scratch.latest_consensus_meta(flavor)?;
+        let base = self
+            .base
+            .latest_consensus_meta(flavor)?
+            .filter(|m| !self.consensus_deleted(m));
+        Ok(match (base, scratch) {
+            (Some(b), Some(s)) if b.lifetime().valid_after() > s.lifetime().valid_after() => {
+                Some(b)
+            }
+            (b, s) => s.or(b),
+        })
+    }
+    fn consensus_by_meta(&self, cmeta: &ConsensusMeta) -> Result<InputString> {
+        match self.scratch.consensus_by_meta(cmeta) {
+            Ok(text) => Ok(text),
+            Err(e) if self.consensus_deleted(cmeta) => Err(e),
+            Err(_) => self.base.consensus_by_meta(cmeta),
+        }
+    }
+    fn consensus_by_sha3_digest_of_signed_part(
+        &self,
+        d: &[u8; 32],
+    ) -> Result<Option<(InputString, ConsensusMeta)>> {
+        match self.scratch.consensus_by_sha3_digest_of_signed_part(d)? {
+            Some(found) => Ok(Some(found)),
+            None => Ok(self
+                .base
+                .consensus_by_sha3_digest_of_signed_part(d)?
+                .filter(|(_, m)| !self.consensus_deleted(m))),
+        }
+    }
+    fn store_consensus(
+        &mut self,
+        cmeta: &ConsensusMeta,
+        flavor: ConsensusFlavor,
+        pending: bool,
+        contents: &str,
+    ) -> Result<()> {
+        self.scratch
+            .store_consensus(cmeta, flavor, pending, contents)?;
+        self.deleted_consensuses.remove(cmeta.sha3_256_of_whole());
+        Ok(())
+    }
+    fn mark_consensus_usable(&mut self, cmeta: &ConsensusMeta) -> Result<()> {
+        self.scratch.mark_consensus_usable(cmeta)
+    }
+    fn delete_consensus(&mut self, cmeta: &ConsensusMeta) -> Result<()> {
+        self.scratch.delete_consensus(cmeta)?;
+        self.deleted_consensuses.insert(*cmeta.sha3_256_of_whole());
+        Ok(())
+    }
+
+    fn authcerts(&self, certs: &[AuthCertKeyIds]) -> Result<HashMap<AuthCertKeyIds, String>> {
+        Ok(merge(
+            hide(self.base.authcerts(certs)?, &self.deleted_authcerts),
+            self.scratch.authcerts(certs)?,
+        ))
+    }
+    fn store_authcerts(&mut self, certs: &[(AuthCertMeta, &str)]) -> Result<()> {
+        self.scratch.store_authcerts(certs)?;
+        for (meta, _) in certs {
+            self.deleted_authcerts.remove(meta.key_ids());
+        }
+        Ok(())
+    }
+    fn delete_authcerts(&mut self, certs: &[AuthCertKeyIds]) -> Result<()> {
+        self.scratch.delete_authcerts(certs)?;
+        self.deleted_authcerts.extend(certs.iter().copied());
+        Ok(())
+    }
+
+    fn microdescs(&self, digests: &[MdDigest]) -> Result<HashMap<MdDigest, String>> {
+        Ok(merge(
+            hide(self.base.microdescs(digests)?, &self.deleted_microdescs),
+            self.scratch.microdescs(digests)?,
+        ))
+    }
+    fn store_microdescs(&mut self, digests: &[(&str, &MdDigest)], when: SystemTime) -> Result<()> {
+        self.scratch.store_microdescs(digests, when)?;
+        for (_, d) in digests {
+            self.deleted_microdescs.remove(*d);
+        }
+        Ok(())
+    }
+    fn update_microdescs_listed(&mut self, digests: &[MdDigest], when: SystemTime) -> Result<()> {
+        self.scratch.update_microdescs_listed(digests, when)
+    }
+    fn delete_microdescs(&mut self, digests: &[MdDigest]) -> Result<()> {
+        self.scratch.delete_microdescs(digests)?;
+        self.deleted_microdescs.extend(digests.iter().copied());
+        Ok(())
+    }
+
+    #[cfg(feature = "routerdesc")]
+    fn routerdescs(&self, digests: &[RdDigest]) -> Result<HashMap<RdDigest, String>> {
+        Ok(merge(
+            hide(self.base.routerdescs(digests)?, &self.deleted_routerdescs),
+            self.scratch.routerdescs(digests)?,
+        ))
+    }
+    #[cfg(feature = "routerdesc")]
+    fn store_routerdescs(&mut self, digests: &[(&str, SystemTime, &RdDigest)]) -> Result<()> {
+        self.scratch.store_routerdescs(digests)?;
+        for (_, _, d) in digests {
+            self.deleted_routerdescs.remove(*d);
+        }
+        Ok(())
+    }
+    #[cfg(feature = "routerdesc")]
+    fn delete_routerdescs(&mut self, digests: &[RdDigest]) -> Result<()> {
+        self.scratch.delete_routerdescs(digests)?;
+        self.deleted_routerdescs.extend(digests.iter().copied());
+        Ok(())
+    }
+
+    #[cfg(feature = "votes")]
+    fn votes(&self, ids: &[RsaIdentity]) -> Result<HashMap<RsaIdentity, String>> {
+        Ok(merge(self.base.votes(ids)?, self.scratch.votes(ids)?))
+    }
+    #[cfg(feature = "votes")]
+    fn store_votes(&mut self, votes: &[(&str, SystemTime, &RsaIdentity)]) -> Result<()> {
+        self.scratch.store_votes(votes)
+    }
+
+    fn cache_latencies(&self) -> Result<HashMap<RsaIdentity, Duration>> {
+        // These describe how the caches have treated us, so we don't merge
+        // ours with anybody else's.
+        let ours = self.scratch.cache_latencies()?;
+        if ours.is_empty() {
+            self.base.cache_latencies()
+        } else {
+            Ok(ours)
+        }
+    }
+    fn store_cache_latencies(&mut self, latencies: &HashMap<RsaIdentity, Duration>) -> Result<()> {
+        self.scratch.store_cache_latencies(latencies)
+    }
+
+    fn load_source_reputation(&self) -> Result<HashMap<RsaIdentity, Vec<SystemTime>>> {
+        // As with latencies, this is about how the caches treated us.
+        let ours = self.scratch.load_source_reputation()?;
+        if ours.is_empty() {
+            self.base.load_source_reputation()
+        } else {
+            Ok(ours)
+        }
+    }
+    fn store_source_reputation(
+        &mut self,
+        errors: &HashMap<RsaIdentity, Vec<SystemTime>>,
+    ) -> Result<()> {
+        self.scratch.store_source_reputation(errors)
+    }
+
+    fn begin_writes(&mut self) -> Result<()> {
+        // Only writes to `scratch` are part of the group: undoing it won't
+        // bring back anything that we've hidden from `base` in the
+        // meantime.
+        self.scratch.begin_writes()
+    }
+    fn commit_writes(&mut self) -> Result<()> {
+        self.scratch.commit_writes()
+    }
+    fn rollback_writes(&mut self) -> Result<()> {
+        self.scratch.rollback_writes()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.scratch.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::storage::SqliteStore;
+    use tempfile::{tempdir, TempDir};
+
+    fn new_empty() -> (TempDir, SqliteStore) {
+        let tmp_dir = tempdir().unwrap();
+        let conn = rusqlite::Connection::open(tmp_dir.path().join("db.sql")).unwrap();
+        let store = SqliteStore::from_conn(conn, &tmp_dir).unwrap();
+        (tmp_dir, store)
+    }
+
+    #[test]
+    fn read_both_write_scratch() {
+        let (_base_dir, mut base) = new_empty();
+        let (_scratch_dir, scratch) = new_empty();
+        let now = SystemTime::now();
+        let (d1, d2, d3) = ([1; 32], [2; 32], [3; 32]);
+        base.store_microdescs(&[("Base md 1", &d1), ("Base md 2", &d2)], now)
+            .unwrap();
+
+        let mut store = LayeredStore::new(Box::new(base), Box::new(scratch));
+        store
+            .store_microdescs(&[("Scratch md 2", &d2), ("Scratch md 3", &d3)], now)
+            .unwrap();
+
+        // We see documents from both stores, preferring the scratch store.
+        let mds = store.microdescs(&[d1, d2, d3]).unwrap();
+        assert_eq!(mds.len(), 3);
+        assert_eq!(mds[&d1], "Base md 1");
+        assert_eq!(mds[&d2], "Scratch md 2");
+        assert_eq!(mds[&d3], "Scratch md 3");
+
+        // Deleting only happens in the scratch store, but the base store's
+        // copies stay hidden until we store them again.
+        store.delete_microdescs(&[d1, d2]).unwrap();
+        let mds = store.microdescs(&[d1, d2, d3]).unwrap();
+        assert_eq!(mds.len(), 1);
+        assert_eq!(mds[&d3], "Scratch md 3");
+        store.store_microdescs(&[("New md 1", &d1)], now).unwrap();
+        let mds = store.microdescs(&[d1, d2, d3]).unwrap();
+        assert_eq!(mds.len(), 2);
+        assert_eq!(mds[&d1], "New md 1");
+
+        // There is no consensus in either store.
+        assert!(store
+            .latest_consensus(ConsensusFlavor::Microdesc, None)
+            .unwrap()
+            .is_none());
+        assert!(store
+            .latest_consensus_meta(ConsensusFlavor::Microdesc)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn deleted_documents_stay_hidden() {
+        let (_base_dir, mut base) = new_empty();
+        let (_scratch_dir, scratch) = new_empty();
+        let now = SystemTime::now();
+        let ids = AuthCertKeyIds {
+            id_fingerprint: [3; 20].into(),
+            sk_fingerprint: [4; 20].into(),
+        };
+        let meta = AuthCertMeta::new(ids, now, now + Duration::from_secs(86400));
+        base.store_authcerts(&[(meta.clone(), "Base cert")])
+            .unwrap();
+
+        let mut store = LayeredStore::new(Box::new(base), Box::new(scratch));
+        assert_eq!(store.authcerts(&[ids]).unwrap()[&ids], "Base cert");
+        store.delete_authcerts(&[ids]).unwrap();
+        assert!(store.authcerts(&[ids]).unwrap().is_empty());
+        store.store_authcerts(&[(meta, "New cert")]).unwrap();
+        assert_eq!(store.authcerts(&[ids]).unwrap()[&ids], "New cert");
+    }
+
+    #[test]
+    fn newest_consensus_wins() {
+        let (_base_dir, mut base) = new_empty();
+        let (_scratch_dir, scratch) = new_empty();
+        let old = crate::testing::CONSENSUS;
+        let new = include_str!("../../testdata/mdconsensus2.txt");
+        let old_meta = peek_consensus_meta(&old.to_owned().into()).unwrap();
+        let new_meta = peek_consensus_meta(&new.to_owned().into()).unwrap();
+        let flavor = ConsensusFlavor::Microdesc;
+
+        // The base store has a newer consensus than the one we store.
+        base.store_consensus(&new_meta, flavor, false, new).unwrap();
+        let mut store = LayeredStore::new(Box::new(base), Box::new(scratch));
+        store
+            .store_consensus(&old_meta, flavor, false, old)
+            .unwrap();
+
+        let latest = store.latest_consensus(flavor, None).unwrap().unwrap();
+        assert_eq!(latest.as_str().unwrap(), new);
+        let latest_meta = store.latest_consensus_meta(flavor).unwrap().unwrap();
+        assert_eq!(
+            latest_meta.sha3_256_of_whole(),
+            new_meta.sha3_256_of_whole()
+        );
+
+        // Once we delete it, we get our own, and can't find the base
+        // store's copy any more.
+        store.delete_consensus(&new_meta).unwrap();
+        let latest = store.latest_consensus(flavor, None).unwrap().unwrap();
+        assert_eq!(latest.as_str().unwrap(), old);
+        let latest_meta = store.latest_consensus_meta(flavor).unwrap().unwrap();
+        assert_eq!(
+            latest_meta.sha3_256_of_whole(),
+            old_meta.sha3_256_of_whole()
+        );
+        assert!(store.consensus_by_meta(&new_meta).is_err());
+        assert!(store
+            .consensus_by_sha3_digest_of_signed_part(new_meta.sha3_256_of_signed())
+            .unwrap()
+            .is_none());
+    }
+}
@@ -8,6 +8,7 @@ use crate::docmeta::{AuthCertMeta, ConsensusMeta};
 use crate::storage::{InputString, Store};
 use crate::{Error, Result};
 
+use tor_llcrypto::pk::rsa::RsaIdentity;
 use tor_netdoc::doc::authcert::AuthCertKeyIds;
 use tor_netdoc::doc::microdesc::MdDigest;
 use tor_netdoc::doc::netstatus::{ConsensusFlavor, Lifetime};
@@ -15,11 +16,11 @@ use tor_netdoc::doc::netstatus::{ConsensusFlavor, Lifetime};
 use tor_netdoc::doc::routerdesc::RdDigest;
 
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::path::{self, Path, PathBuf};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
-use rusqlite::{params, OpenFlags, OptionalExtension, Transaction};
+use rusqlite::{params, OpenFlags, OptionalExtension, Savepoint};
 use time::OffsetDateTime;
 use tracing::trace;
 
@@ -42,6 +43,12 @@ pub(crate) struct SqliteStore {
     /// (sqlite supports that with connection locking, but we want to
     /// be a little more coarse-grained here)
     lockfile: Option<fslock::LockFile>,
+    /// For each group of writes that we've begun and not yet finished
+    /// (innermost last), the blob files that we've written as part of it.
+    ///
+    /// If we undo a group, we delete these files: otherwise, with no entry
+    /// in ExtDocs, nothing would ever clean them up.
+    scope_blobs: Vec<Vec<PathBuf>>,
 }
 
 impl SqliteStore {
@@ -107,6 +114,7 @@ impl SqliteStore {
             path,
             lockfile: None,
             sql_path: None,
+            scope_blobs: Vec::new(),
         };
 
         result.check_schema()?;
@@ -130,6 +138,9 @@ impl SqliteStore {
         if !db_exists {
             tx.execute_batch(INSTALL_V0_SCHEMA)?;
             tx.execute_batch(UPDATE_SCHEMA_V0_TO_V1)?;
+            tx.execute_batch(UPDATE_SCHEMA_V1_TO_V2)?;
+            tx.execute_batch(UPDATE_SCHEMA_V2_TO_V3)?;
+            tx.execute_batch(UPDATE_SCHEMA_V3_TO_V4)?;
             tx.commit()?;
             return Ok(());
         }
@@ -143,7 +154,16 @@ impl SqliteStore {
 
         if version < SCHEMA_VERSION {
             // Update the schema.
-            tx.execute_batch(UPDATE_SCHEMA_V0_TO_V1)?;
+            if version < 1 {
+                tx.execute_batch(UPDATE_SCHEMA_V0_TO_V1)?;
+            }
+            if version < 2 {
+                tx.execute_batch(UPDATE_SCHEMA_V1_TO_V2)?;
+            }
+            if version < 3 {
+                tx.execute_batch(UPDATE_SCHEMA_V2_TO_V3)?;
+            }
+            tx.execute_batch(UPDATE_SCHEMA_V3_TO_V4)?;
             tx.commit()?;
             return Ok(());
         } else if readable_by > SCHEMA_VERSION {
@@ -205,10 +225,18 @@ impl SqliteStore {
         let fname = format!("{}_{}", doctype, digeststr);
         let full_path = self.blob_fname(&fname)?;
 
+        if let Some(blobs) = self.scope_blobs.last_mut() {
+            // If the file is already there, somebody outside this group
+            // may be using it: don't delete it if we undo the group.
+            if !full_path.exists() {
+                blobs.push(full_path.clone());
+            }
+        }
+
         let unlinker = Unlinker::new(&full_path);
         std::fs::write(full_path, contents)?;
 
-        let tx = self.conn.unchecked_transaction()?;
+        let tx = self.conn.savepoint()?;
         tx.execute(INSERT_EXTDOC, params![digeststr, expires, dtype, fname])?;
 
         Ok(SavedBlobHandle {
@@ -284,7 +312,7 @@ impl Store for SqliteStore {
         Ok(true)
     }
     fn expire_all(&mut self, expiration: &ExpirationConfig) -> Result<()> {
-        let tx = self.conn.transaction()?;
+        let tx = self.conn.savepoint()?;
         let expired_blobs: Vec<String> = {
             let mut stmt = tx.prepare(FIND_EXPIRED_EXTDOCS)?;
             let names = stmt
@@ -300,6 +328,7 @@ impl Store for SqliteStore {
         tx.execute(DROP_OLD_AUTHCERTS, [now - expiration.authcerts])?;
         tx.execute(DROP_OLD_CONSENSUSES, [now - expiration.consensuses])?;
         tx.execute(DROP_OLD_ROUTERDESCS, [now - expiration.router_descs])?;
+        tx.execute(DROP_OLD_VOTES, [now - expiration.consensuses])?;
         tx.commit()?;
         for name in expired_blobs {
             let fname = self.blob_fname(name);
@@ -425,7 +454,7 @@ impl Store for SqliteStore {
         let d = hex::encode(cmeta.sha3_256_of_whole());
         let digest = format!("sha3-256-{}", d);
 
-        let tx = self.conn.transaction()?;
+        let tx = self.conn.savepoint()?;
         let n = tx.execute(MARK_CONSENSUS_NON_PENDING, params![digest])?;
         trace!("Marked {} consensuses usable", n);
         tx.commit()?;
@@ -438,7 +467,7 @@ impl Store for SqliteStore {
 
         // TODO: We should probably remove the blob as well, but for now
         // this is enough.
-        let tx = self.conn.transaction()?;
+        let tx = self.conn.savepoint()?;
         tx.execute(REMOVE_CONSENSUS, params![digest])?;
         tx.commit()?;
 
@@ -464,7 +493,7 @@ impl Store for SqliteStore {
         Ok(result)
     }
     fn store_authcerts(&mut self, certs: &[(AuthCertMeta, &str)]) -> Result<()> {
-        let tx = self.conn.transaction()?;
+        let tx = self.conn.savepoint()?;
         let mut stmt = tx.prepare(INSERT_AUTHCERT)?;
         for (meta, content) in certs {
             let ids = meta.key_ids();
@@ -478,6 +507,19 @@ impl Store for SqliteStore {
         tx.commit()?;
         Ok(())
     }
+    fn delete_authcerts(&mut self, certs: &[AuthCertKeyIds]) -> Result<()> {
+        let tx = self.conn.savepoint()?;
+        let mut stmt = tx.prepare(DELETE_AUTHCERT)?;
+
+        for ids in certs {
+            let id_digest = hex::encode(ids.id_fingerprint.as_bytes());
+            let sk_digest = hex::encode(ids.sk_fingerprint.as_bytes());
+            stmt.execute(params![id_digest, sk_digest])?;
+        }
+        stmt.finalize()?;
+        tx.commit()?;
+        Ok(())
+    }
 
     fn microdescs(&self, digests: &[MdDigest]) -> Result<HashMap<MdDigest, String>> {
         let mut result = HashMap::new();
@@ -500,7 +542,7 @@ impl Store for SqliteStore {
     fn store_microdescs(&mut self, digests: &[(&str, &MdDigest)], when: SystemTime) -> Result<()> {
         let when: OffsetDateTime = when.into();
 
-        let tx = self.conn.transaction()?;
+        let tx = self.conn.savepoint()?;
         let mut stmt = tx.prepare(INSERT_MD)?;
 
         for (content, md_digest) in digests {
@@ -512,7 +554,7 @@ impl Store for SqliteStore {
         Ok(())
     }
     fn update_microdescs_listed(&mut self, digests: &[MdDigest], when: SystemTime) -> Result<()> {
-        let tx = self.conn.transaction()?;
+        let tx = self.conn.savepoint()?;
         let mut stmt = tx.prepare(UPDATE_MD_LISTED)?;
         let when: OffsetDateTime = when.into();
 
@@ -525,6 +567,18 @@ impl Store for SqliteStore {
         tx.commit()?;
         Ok(())
     }
+    fn delete_microdescs(&mut self, digests: &[MdDigest]) -> Result<()> {
+        let tx = self.conn.savepoint()?;
+        let mut stmt = tx.prepare(DELETE_MD)?;
+
+        for md_digest in digests {
+            let h_digest = hex::encode(md_digest);
+            stmt.execute(params![h_digest])?;
+        }
+        stmt.finalize()?;
+        tx.commit()?;
+        Ok(())
+    }
 
     #[cfg(feature = "routerdesc")]
     fn routerdescs(&self, digests: &[RdDigest]) -> Result<HashMap<RdDigest, String>> {
@@ -547,7 +601,7 @@ impl Store for SqliteStore {
     }
     #[cfg(feature = "routerdesc")]
     fn store_routerdescs(&mut self, digests: &[(&str, SystemTime, &RdDigest)]) -> Result<()> {
-        let tx = self.conn.transaction()?;
+        let tx = self.conn.savepoint()?;
         let mut stmt = tx.prepare(INSERT_RD)?;
 
         for (content, when, rd_digest) in digests {
@@ -559,13 +613,155 @@ impl Store for SqliteStore {
         tx.commit()?;
         Ok(())
     }
+    #[cfg(feature = "routerdesc")]
+    fn delete_routerdescs(&mut self, digests: &[RdDigest]) -> Result<()> {
+        let tx = self.conn.savepoint()?;
+        let mut stmt = tx.prepare(DELETE_RD)?;
+
+        for rd_digest in digests {
+            let h_digest = hex::encode(rd_digest);
+            stmt.execute(params![h_digest])?;
+        }
+        stmt.finalize()?;
+        tx.commit()?;
+        Ok(())
+    }
+    #[cfg(feature = "votes")]
+    fn votes(&self, ids: &[RsaIdentity]) -> Result<HashMap<RsaIdentity, String>> {
+        let mut result = HashMap::new();
+        let mut stmt = self.conn.prepare(FIND_VOTE)?;
+
+        for id in ids {
+            let id_digest = hex::encode(id.as_bytes());
+            if let Some(contents) = stmt
+                .query_row(params![id_digest], |row| row.get::<_, String>(0))
+                .optional()?
+            {
+                result.insert(*id, contents);
+            }
+        }
+
+        Ok(result)
+    }
+    #[cfg(feature = "votes")]
+    fn store_votes(&mut self, votes: &[(&str, SystemTime, &RsaIdentity)]) -> Result<()> {
+        let tx = self.conn.savepoint()?;
+        let mut stmt = tx.prepare(INSERT_VOTE)?;
+
+        for (content, valid_after, id) in votes {
+            let valid_after: OffsetDateTime = (*valid_after).into();
+            let id_digest = hex::encode(id.as_bytes());
+            stmt.execute(params![id_digest, valid_after, content])?;
+        }
+        stmt.finalize()?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn cache_latencies(&self) -> Result<HashMap<RsaIdentity, Duration>> {
+        let mut stmt = self.conn.prepare(FIND_ALL_CACHE_LATENCIES)?;
+        let mut rows = stmt.query([])?;
+        let mut result = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let id_digest: String = row.get(0)?;
+            let msec: i64 = row.get(1)?;
+            let id =
+                RsaIdentity::from_bytes(&hex::decode(id_digest).map_err(Error::BadHexInCache)?)
+                    .ok_or(Error::CacheCorruption("Invalid identity in database"))?;
+            let msec = u64::try_from(msec)
+                .map_err(|_| Error::CacheCorruption("Invalid latency in database"))?;
+            result.insert(id, Duration::from_millis(msec));
+        }
+        Ok(result)
+    }
+    fn store_cache_latencies(&mut self, latencies: &HashMap<RsaIdentity, Duration>) -> Result<()> {
+        let tx = self.conn.savepoint()?;
+        tx.execute(DELETE_ALL_CACHE_LATENCIES, [])?;
+        let mut stmt = tx.prepare(INSERT_CACHE_LATENCY)?;
+        for (id, latency) in latencies {
+            let id_digest = hex::encode(id.as_bytes());
+            let msec = i64::try_from(latency.as_millis()).unwrap_or(i64::MAX);
+            stmt.execute(params![id_digest, msec])?;
+        }
+        stmt.finalize()?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn load_source_reputation(&self) -> Result<HashMap<RsaIdentity, Vec<SystemTime>>> {
+        let mut stmt = self.conn.prepare(FIND_ALL_CACHE_ERRORS)?;
+        let mut rows = stmt.query([])?;
+        let mut result: HashMap<RsaIdentity, Vec<SystemTime>> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let id_digest: String = row.get(0)?;
+            let failed_at: OffsetDateTime = row.get(1)?;
+            let id =
+                RsaIdentity::from_bytes(&hex::decode(id_digest).map_err(Error::BadHexInCache)?)
+                    .ok_or(Error::CacheCorruption("Invalid identity in database"))?;
+            result.entry(id).or_default().push(failed_at.into());
+        }
+        for times in result.values_mut() {
+            times.sort();
+        }
+        Ok(result)
+    }
+    fn store_source_reputation(
+        &mut self,
+        errors: &HashMap<RsaIdentity, Vec<SystemTime>>,
+    ) -> Result<()> {
+        let tx = self.conn.savepoint()?;
+        tx.execute(DELETE_ALL_CACHE_ERRORS, [])?;
+        let mut stmt = tx.prepare(INSERT_CACHE_ERROR)?;
+        for (id, times) in errors {
+            let id_digest = hex::encode(id.as_bytes());
+            for failed_at in times {
+                let failed_at: OffsetDateTime = (*failed_at).into();
+                stmt.execute(params![id_digest, failed_at])?;
+            }
+        }
+        stmt.finalize()?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn begin_writes(&mut self) -> Result<()> {
+        // Each method above uses a savepoint of its own, so it nests inside
+        // this one; and sqlite lets savepoints with the same name nest, so
+        // groups do too.
+        self.conn.execute_batch(BEGIN_WRITES)?;
+        self.scope_blobs.push(Vec::new());
+        Ok(())
+    }
+    fn commit_writes(&mut self) -> Result<()> {
+        self.conn.execute_batch(COMMIT_WRITES)?;
+        let blobs = self.scope_blobs.pop().unwrap_or_default();
+        if let Some(outer) = self.scope_blobs.last_mut() {
+            // The enclosing group can still undo these.
+            outer.extend(blobs);
+        }
+        Ok(())
+    }
+    fn rollback_writes(&mut self) -> Result<()> {
+        self.conn.execute_batch(ROLLBACK_WRITES)?;
+        for blob in self.scope_blobs.pop().unwrap_or_default() {
+            let _ignore_err = std::fs::remove_file(blob);
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<()> {
+        // Every method above commits its own transaction before returning,
+        // so there should be nothing left open.  But if there is, commit it
+        // now rather than lose it.  (A group of writes that hasn't finished
+        // yet is another matter: it's up to whoever began it.)
+        if !self.is_readonly() && self.scope_blobs.is_empty() && !self.conn.is_autocommit() {
+            self.conn.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
 }
 
 /// Handle to a blob that we have saved to disk but not yet committed to
 /// the database.
 struct SavedBlobHandle<'a> {
-    /// Transaction we're using to add the blob to the ExtDocs table.
-    tx: Transaction<'a>,
+    /// Savepoint we're using to add the blob to the ExtDocs table.
+    tx: Savepoint<'a>,
     /// Filename for the file, with respect to the the blob directory.
     #[allow(unused)]
     fname: String,
@@ -645,7 +841,7 @@ fn cmeta_from_row(row: &rusqlite::Row<'_>) -> Result<ConsensusMeta> {
 }
 
 /// Version number used for this version of the arti cache schema.
-const SCHEMA_VERSION: u32 = 1;
+const SCHEMA_VERSION: u32 = 4;
 
 /// Set up the tables for the arti cache schema in a sqlite database.
 const INSTALL_V0_SCHEMA: &str = "
@@ -717,6 +913,41 @@ const UPDATE_SCHEMA_V0_TO_V1: &str = "
   UPDATE TorSchemaMeta SET version=1 WHERE version<1;
 ";
 
+/// Update the database schema from version 1 to version 2.
+const UPDATE_SCHEMA_V1_TO_V2: &str = "
+  -- The directory authorities' votes.
+  CREATE TABLE AuthVotes (
+    id_digest TEXT NOT NULL,
+    valid_after DATE NOT NULL,
+    contents BLOB NOT NULL,
+    PRIMARY KEY (id_digest, valid_after)
+  );
+
+  UPDATE TorSchemaMeta SET version=2 WHERE version<2;
+";
+
+/// Update the database schema from version 2 to version 3.
+const UPDATE_SCHEMA_V2_TO_V3: &str = "
+  -- How long each directory cache has taken to answer us, on average.
+  CREATE TABLE CacheLatencies (
+    id_digest TEXT PRIMARY KEY NOT NULL,
+    latency_msec INTEGER NOT NULL
+  );
+
+  UPDATE TorSchemaMeta SET version=3 WHERE version<3;
+";
+
+/// Update the database schema from version 3 to version 4.
+const UPDATE_SCHEMA_V3_TO_V4: &str = "
+  -- When each directory cache has recently failed us.
+  CREATE TABLE CacheErrors (
+    id_digest TEXT NOT NULL,
+    failed_at DATE NOT NULL
+  );
+
+  UPDATE TorSchemaMeta SET version=4 WHERE version<4;
+";
+
 /// Query: find the latest-expiring microdesc consensus with a given
 /// pending status.
 const FIND_CONSENSUS_P: &str = "
@@ -777,6 +1008,11 @@ const FIND_AUTHCERT: &str = "
   SELECT contents FROM AuthCerts WHERE id_digest = ? AND sk_digest = ?;
 ";
 
+/// Query: Remove the authority certificate with given key digests.
+const DELETE_AUTHCERT: &str = "
+  DELETE FROM AuthCerts WHERE id_digest = ? AND sk_digest = ?;
+";
+
 /// Query: find the microdescriptor with a given hex-encoded sha256 digest
 const FIND_MD: &str = "
   SELECT contents
@@ -784,6 +1020,12 @@ const FIND_MD: &str = "
   WHERE sha256_digest = ?
 ";
 
+/// Query: remove the microdescriptor with a given hex-encoded sha256 digest
+const DELETE_MD: &str = "
+  DELETE FROM Microdescs
+  WHERE sha256_digest = ?
+";
+
 /// Query: find the router descriptors with a given hex-encoded sha1 digest
 #[cfg(feature = "routerdesc")]
 const FIND_RD: &str = "
@@ -792,6 +1034,24 @@ const FIND_RD: &str = "
   WHERE sha1_digest = ?
 ";
 
+/// Query: remove the router descriptor with a given hex-encoded sha1 digest
+#[cfg(feature = "routerdesc")]
+const DELETE_RD: &str = "
+  DELETE FROM RouterDescs
+  WHERE sha1_digest = ?
+";
+
+/// Query: find the most recent vote from the authority with a given
+/// hex-encoded identity digest.
+#[cfg(feature = "votes")]
+const FIND_VOTE: &str = "
+  SELECT contents
+  FROM AuthVotes
+  WHERE id_digest = ?
+  ORDER BY valid_after DESC
+  LIMIT 1;
+";
+
 /// Query: find every ExtDocs member that has expired.
 const FIND_EXPIRED_EXTDOCS: &str = "
   SELECT filename FROM Extdocs where expires < datetime('now');
@@ -831,6 +1091,50 @@ const INSERT_RD: &str = "
   VALUES ( ?, ?, ? );
 ";
 
+/// Query: Add a new authority vote
+#[cfg(feature = "votes")]
+const INSERT_VOTE: &str = "
+  INSERT OR REPLACE INTO AuthVotes ( id_digest, valid_after, contents )
+  VALUES ( ?, ?, ? );
+";
+
+/// Query: Find every directory cache latency estimate.
+const FIND_ALL_CACHE_LATENCIES: &str = "
+  SELECT id_digest, latency_msec FROM CacheLatencies;
+";
+
+/// Query: Add a directory cache latency estimate.
+const INSERT_CACHE_LATENCY: &str = "
+  INSERT OR REPLACE INTO CacheLatencies ( id_digest, latency_msec )
+  VALUES ( ?, ? );
+";
+
+/// Query: Discard every directory cache latency estimate.
+const DELETE_ALL_CACHE_LATENCIES: &str = "DELETE FROM CacheLatencies;";
+
+/// Query: Find every recent directory cache error.
+const FIND_ALL_CACHE_ERRORS: &str = "
+  SELECT id_digest, failed_at FROM CacheErrors;
+";
+
+/// Query: Add a recent directory cache error.
+const INSERT_CACHE_ERROR: &str = "
+  INSERT INTO CacheErrors ( id_digest, failed_at )
+  VALUES ( ?, ? );
+";
+
+/// Query: Discard every recent directory cache error.
+const DELETE_ALL_CACHE_ERRORS: &str = "DELETE FROM CacheErrors;";
+
+/// Query: Begin a group of writes that we keep or undo all together.
+const BEGIN_WRITES: &str = "SAVEPOINT dirmgr_writes;";
+
+/// Query: Keep every write since BEGIN_WRITES.
+const COMMIT_WRITES: &str = "RELEASE dirmgr_writes;";
+
+/// Query: Undo every write since BEGIN_WRITES.
+const ROLLBACK_WRITES: &str = "ROLLBACK TO dirmgr_writes; RELEASE dirmgr_writes;";
+
 /// Query: Change the time when a given microdescriptor was last listed.
 const UPDATE_MD_LISTED: &str = "
   UPDATE Microdescs
@@ -855,6 +1159,9 @@ const DROP_OLD_AUTHCERTS: &str = "DELETE FROM Authcerts WHERE expires < ?;";
 /// Query: Discard every consensus that's been expired for at least
 /// two days.
 const DROP_OLD_CONSENSUSES: &str = "DELETE FROM Consensuses WHERE valid_until < ?;";
+/// Query: Discard every authority vote that was made for a consensus as
+/// long ago as the oldest consensus we keep.
+const DROP_OLD_VOTES: &str = "DELETE FROM AuthVotes WHERE valid_after < ?;";
 
 #[cfg(test)]
 mod test {
@@ -904,6 +1211,38 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn upgrade_from_v1() -> Result<()> {
+        let tmp_dir = tempdir().unwrap();
+        let sql_path = tmp_dir.path().join("db.sql");
+        // Make a database the way that an older version would have.
+        {
+            let conn = rusqlite::Connection::open(&sql_path)?;
+            conn.execute_batch(INSTALL_V0_SCHEMA)?;
+            conn.execute_batch(UPDATE_SCHEMA_V0_TO_V1)?;
+        }
+        // Opening it adds the tables that it's missing.
+        {
+            let conn = rusqlite::Connection::open(&sql_path)?;
+            let store = SqliteStore::from_conn(conn, &tmp_dir)?;
+            let version: u32 = store.conn.query_row(
+                "SELECT version FROM TorSchemaMeta WHERE name = 'TorDirStorage'",
+                [],
+                |row| row.get(0),
+            )?;
+            assert_eq!(version, SCHEMA_VERSION);
+            let n: u32 = store
+                .conn
+                .query_row("SELECT COUNT(*) FROM AuthVotes", [], |row| row.get(0))?;
+            assert_eq!(n, 0);
+            let n: u32 = store
+                .conn
+                .query_row("SELECT COUNT(*) FROM CacheErrors", [], |row| row.get(0))?;
+            assert_eq!(n, 0);
+        }
+        Ok(())
+    }
+
     #[test]
     fn bad_blob_fnames() -> Result<()> {
         let (_tmp_dir, store) = new_empty()?;
@@ -1104,6 +1443,9 @@ mod test {
         assert_eq!(certs.len(), 1);
         assert_eq!(certs.get(&keyids).unwrap(), "Pretend this is a cert");
 
+        store.delete_authcerts(&[keyids])?;
+        assert!(store.authcerts(&[keyids, keyids2])?.is_empty());
+
         Ok(())
     }
 
@@ -1147,6 +1489,201 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn microdescs_stored_once() -> Result<()> {
+        // A microdescriptor that two consensuses list, and that we store
+        // once for each of them, is only kept once.
+        let (_tmp_dir, mut store) = new_empty()?;
+
+        let now = OffsetDateTime::now_utc();
+        let earlier: OffsetDateTime = now - 1.hours();
+        let d1 = [5_u8; 32];
+        let d2 = [7; 32];
+
+        store.store_microdescs(
+            &[("Fake micro 1", &d1), ("Fake micro 2", &d2)],
+            earlier.into(),
+        )?;
+        store.store_microdescs(&[("Fake micro 1", &d1)], now.into())?;
+
+        let n: u32 = store
+            .conn
+            .query_row("SELECT COUNT(*) FROM Microdescs", [], |row| row.get(0))?;
+        assert_eq!(n, 2);
+        let n: u32 = store.conn.query_row(
+            "SELECT COUNT(*) FROM Microdescs WHERE sha256_digest = ?",
+            params![hex::encode(d1)],
+            |row| row.get(0),
+        )?;
+        assert_eq!(n, 1);
+
+        // The copy we kept says when it was most recently listed.
+        let listed: OffsetDateTime = store.conn.query_row(
+            "SELECT last_listed FROM Microdescs WHERE sha256_digest = ?",
+            params![hex::encode(d1)],
+            |row| row.get(0),
+        )?;
+        assert_eq!(listed.unix_timestamp(), now.unix_timestamp());
+
+        let mds = store.microdescs(&[d1, d2])?;
+        assert_eq!(mds.get(&d1).unwrap(), "Fake micro 1");
+        assert_eq!(mds.get(&d2).unwrap(), "Fake micro 2");
+
+        // Delete one of them.
+        store.delete_microdescs(&[d1])?;
+        let mds = store.microdescs(&[d1, d2])?;
+        assert!(mds.get(&d1).is_none());
+        assert_eq!(mds.get(&d2).unwrap(), "Fake micro 2");
+
+        Ok(())
+    }
+
+    #[test]
+    fn cache_latencies() -> Result<()> {
+        let (_tmp_dir, mut store) = new_empty()?;
+        assert!(store.cache_latencies()?.is_empty());
+
+        let id1: RsaIdentity = [5_u8; 20].into();
+        let id2: RsaIdentity = [7; 20].into();
+        let mut latencies = HashMap::new();
+        latencies.insert(id1, Duration::from_millis(250));
+        latencies.insert(id2, Duration::from_secs(3));
+        store.store_cache_latencies(&latencies)?;
+        assert_eq!(store.cache_latencies()?, latencies);
+
+        // Storing again replaces everything we had.
+        latencies.remove(&id2);
+        store.store_cache_latencies(&latencies)?;
+        assert_eq!(store.cache_latencies()?, latencies);
+
+        Ok(())
+    }
+
+    #[test]
+    fn source_reputation() -> Result<()> {
+        let (_tmp_dir, mut store) = new_empty()?;
+        assert!(store.load_source_reputation()?.is_empty());
+
+        let id1: RsaIdentity = [5_u8; 20].into();
+        let id2: RsaIdentity = [7; 20].into();
+        let t1 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let t2 = t1 + Duration::from_secs(60);
+        let mut errors = HashMap::new();
+        errors.insert(id1, vec![t1, t2]);
+        errors.insert(id2, vec![t2]);
+        store.store_source_reputation(&errors)?;
+        assert_eq!(store.load_source_reputation()?, errors);
+
+        // Storing again replaces everything we had.
+        errors.remove(&id1);
+        store.store_source_reputation(&errors)?;
+        assert_eq!(store.load_source_reputation()?, errors);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_scope() -> Result<()> {
+        use crate::storage::{DynStore, WriteScope};
+        use std::sync::Mutex;
+        use tor_netdoc::doc::netstatus;
+
+        let (tmp_dir, store) = new_empty()?;
+        let store: Mutex<DynStore> = Mutex::new(Box::new(store));
+        let now = OffsetDateTime::now_utc();
+        let d1 = [5_u8; 32];
+        let d2 = [7_u8; 32];
+        let cmeta = ConsensusMeta::new(
+            netstatus::Lifetime::new(
+                now.into(),
+                (now + 1.hours()).into(),
+                (now + 2.hours()).into(),
+            )
+            .unwrap(),
+            [0xAB; 32],
+            [0xBC; 32],
+        );
+        let n_blobs = || {
+            std::fs::read_dir(tmp_dir.path())
+                .unwrap()
+                .filter(|ent| {
+                    let name = ent.as_ref().unwrap().file_name();
+                    name.to_string_lossy().starts_with("con:")
+                })
+                .count()
+        };
+        let has_consensus = || -> Result<bool> {
+            Ok(store
+                .lock()
+                .unwrap()
+                .latest_consensus(ConsensusFlavor::Microdesc, None)?
+                .is_some())
+        };
+
+        // Writes in a group that we commit are kept.
+        let scope = WriteScope::begin(&store)?;
+        store
+            .lock()
+            .unwrap()
+            .store_microdescs(&[("Fake micro 1", &d1)], now.into())?;
+        scope.commit()?;
+
+        // If we drop a group without committing it, none of its writes are
+        // kept, including those of a group inside it that we did commit;
+        // and neither is the blob of any consensus that we stored.
+        {
+            let _outer = WriteScope::begin(&store)?;
+            let inner = WriteScope::begin(&store)?;
+            store.lock().unwrap().store_consensus(
+                &cmeta,
+                ConsensusFlavor::Microdesc,
+                true,
+                "Pretend this is a consensus",
+            )?;
+            inner.commit()?;
+            store
+                .lock()
+                .unwrap()
+                .store_microdescs(&[("Fake micro 2", &d2)], now.into())?;
+            assert!(has_consensus()?);
+            assert_eq!(n_blobs(), 1);
+        }
+        let found = store.lock().unwrap().microdescs(&[d1, d2])?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[&d1], "Fake micro 1");
+        assert!(!has_consensus()?);
+        assert_eq!(n_blobs(), 0);
+
+        // Undoing a group that stores a consensus we already had doesn't
+        // remove the blob that we stored before.
+        store.lock().unwrap().store_consensus(
+            &cmeta,
+            ConsensusFlavor::Microdesc,
+            true,
+            "Pretend this is a consensus",
+        )?;
+        {
+            let _scope = WriteScope::begin(&store)?;
+            store.lock().unwrap().store_consensus(
+                &cmeta,
+                ConsensusFlavor::Microdesc,
+                true,
+                "Pretend this is a consensus",
+            )?;
+        }
+        assert!(has_consensus()?);
+        assert_eq!(n_blobs(), 1);
+
+        // Once that's undone, we can write as usual.
+        store
+            .lock()
+            .unwrap()
+            .store_microdescs(&[("Fake micro 2", &d2)], now.into())?;
+        assert_eq!(store.lock().unwrap().microdescs(&[d2])?.len(), 1);
+
+        Ok(())
+    }
+
     #[test]
     #[cfg(feature = "routerdesc")]
     fn routerdescs() -> Result<()> {
@@ -1181,6 +1718,44 @@ mod test {
         assert_eq!(rds.len(), 1);
         assert_eq!(rds.get(&d2).unwrap(), "Fake routerdesc 2");
 
+        store.delete_routerdescs(&[d2])?;
+        assert!(store.routerdescs(&[d2])?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "votes")]
+    fn votes() -> Result<()> {
+        let (_tmp_dir, mut store) = new_empty()?;
+
+        let now = OffsetDateTime::now_utc();
+        let one_hour = 1.hours();
+        let long_ago: OffsetDateTime = now - one_hour * 24 * 5;
+
+        let id1: RsaIdentity = [5_u8; 20].into();
+        let id2: RsaIdentity = [7; 20].into();
+        let id3: RsaIdentity = [42; 20].into();
+
+        store.store_votes(&[
+            ("Old vote 1", long_ago.into(), &id1),
+            ("Vote 1", (now - one_hour).into(), &id1),
+            ("Vote 2", long_ago.into(), &id2),
+        ])?;
+
+        // We get the latest vote from each authority.
+        let votes = store.votes(&[id1, id2, id3])?;
+        assert_eq!(votes.len(), 2);
+        assert_eq!(votes.get(&id1).unwrap(), "Vote 1");
+        assert_eq!(votes.get(&id2).unwrap(), "Vote 2");
+        assert_eq!(votes.get(&id3), None);
+
+        // Expiring drops the votes that are as old as our oldest consensuses.
+        store.expire_all(&EXPIRATION_DEFAULTS)?;
+        let votes = store.votes(&[id1, id2, id3])?;
+        assert_eq!(votes.len(), 1);
+        assert_eq!(votes.get(&id1).unwrap(), "Vote 1");
+
         Ok(())
     }
 
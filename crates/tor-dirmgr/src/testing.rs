@@ -0,0 +1,736 @@
+//! Helpers shared by the tests in this crate.
+//!
+//! This module holds the fake [`DirState`] and [`Store`] implementations
+//! that our download tests run against, along with helpers for setting up a
+//! [`DirMgr`](crate::DirMgr) configuration.
+
+#![allow(clippy::unwrap_used)]
+
+use crate::docid::ClientRequest;
+use crate::docmeta::ConsensusMeta;
+use crate::event::{DirStatus, DirStatusInner};
+use crate::storage::DynStore;
+use crate::{
+    Authority, BootstrapPhase, CacheUsage, DirMgrConfig, DirMgrConfigBuilder, DirState, DocId,
+    DocumentText, DownloadSchedule, Error, FallbackDir, NetworkConfig, NetworkConfigBuilder,
+    Readiness, Result, Store,
+};
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use time::macros::datetime;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::doc::authcert::AuthCertKeyIds;
+use tor_netdoc::doc::microdesc::MdDigest;
+use tor_netdoc::doc::netstatus::{ConsensusFlavor, Lifetime};
+
+/// Return a fallback directory whose identities are made of the byte `b`,
+/// and whose address is 127.0.0.`b`.
+pub(crate) fn fallback(b: u8) -> FallbackDir {
+    FallbackDir::builder()
+        .rsa_identity([b; 20].into())
+        .ed_identity([b; 32].into())
+        .orport(format!("127.0.0.{}:9001", b).parse().unwrap())
+        .build()
+        .unwrap()
+}
+
+/// Return a network configuration that asks `fallbacks`, and no others,
+/// when we have no directory.
+pub(crate) fn fallback_network(fallbacks: Vec<FallbackDir>) -> NetworkConfig {
+    let mut netcfg = NetworkConfig::builder();
+    netcfg.fallback_caches(fallbacks);
+    netcfg.build().unwrap()
+}
+
+/// Return a builder for a configuration that keeps its cache in `dir`.
+pub(crate) fn config_builder(dir: &Path) -> DirMgrConfigBuilder {
+    let mut builder = DirMgrConfig::builder();
+    builder.cache_path(dir);
+    builder
+}
+
+/// A microdescriptor consensus, signed by the authorities in
+/// [`test_authorities`].
+pub(crate) const CONSENSUS: &str = include_str!("../testdata/mdconsensus1.txt");
+
+/// The certificates for the authorities that signed [`CONSENSUS`].
+pub(crate) const AUTHCERTS: &str = concat!(
+    include_str!("../testdata/cert-5696.txt"),
+    include_str!("../testdata/cert-5A23.txt")
+);
+
+/// Return a time at which [`CONSENSUS`] is live.
+pub(crate) fn consensus_time() -> SystemTime {
+    datetime!(2020-08-07 12:42:45 UTC).into()
+}
+
+/// Return an authority whose v3 identity is the hex string `id`.
+pub(crate) fn authority(id: &str) -> Authority {
+    let id = RsaIdentity::from_bytes(&hex::decode(id).unwrap()).unwrap();
+    Authority::builder()
+        .name("ignore")
+        .v3ident(id)
+        .build()
+        .unwrap()
+}
+
+/// Return the authorities whose signatures we need on [`CONSENSUS`].
+pub(crate) fn test_authorities() -> Vec<Authority> {
+    vec![
+        authority("5696AB38CB3852AFA476A5C07B2D4788963D5567"),
+        authority("5A23BA701776C9C1AB1C06E734E92AB3D5350D64"),
+        // This is an authority according to the consensus, but we'll
+        // pretend we don't recognize it, to make sure that we
+        // don't fetch or accept it.
+        // authority("7C47DCB4A90E2C2B7C7AD27BD641D038CF5D7EBE"),
+    ]
+}
+
+/// Return a builder for a network configuration that trusts
+/// [`test_authorities`].
+pub(crate) fn authority_network() -> NetworkConfigBuilder {
+    let mut netcfg = NetworkConfig::builder();
+    netcfg.authorities(test_authorities());
+    netcfg
+}
+
+/// Return `n` distinct microdescriptor digests, in sorted order.
+///
+/// These are enough to make `n / 500` separate requests, rounding up.
+pub(crate) fn md_digests(n: u32) -> Vec<MdDigest> {
+    (0..n)
+        .map(|n| {
+            let mut d = [0_u8; 32];
+            d[..4].copy_from_slice(&n.to_be_bytes());
+            d
+        })
+        .collect()
+}
+
+// Constants from Lou Reed
+pub(crate) const H1: MdDigest = *b"satellite's gone up to the skies";
+pub(crate) const H2: MdDigest = *b"things like that drive me out of";
+pub(crate) const H3: MdDigest = *b"my mind i watched it for a littl";
+pub(crate) const H4: MdDigest = *b"while i like to watch things on ";
+pub(crate) const H5: MdDigest = *b"TV Satellite of love Satellite--";
+
+/// A fake implementation of DirState that just wants a fixed set
+/// of microdescriptors.  It doesn't care if it gets them: it just
+/// wants to be told that the IDs exist.
+///
+/// In its first phase, it wants `H1` and `H2`; once it has those, it
+/// advances to a second phase that wants `H3`, `H4`, and `H5`.
+#[derive(Debug, Clone)]
+pub(crate) struct DemoState {
+    /// True if we're in the second phase.
+    second_time_around: bool,
+    /// Which digests do we want, and have we gotten each one?
+    got_items: HashMap<MdDigest, bool>,
+    /// If present, report our progress as if we were fetching the
+    /// microdescriptors for a consensus with this lifetime.
+    pub(crate) lifetime: Option<Lifetime>,
+    /// If true, save each item we download to our storage.
+    pub(crate) store_downloads: bool,
+}
+
+impl DemoState {
+    /// Return a new state in its first phase.
+    pub(crate) fn new1() -> Self {
+        DemoState {
+            second_time_around: false,
+            got_items: vec![(H1, false), (H2, false)].into_iter().collect(),
+            lifetime: None,
+            store_downloads: false,
+        }
+    }
+    /// Return a new state in its second phase.
+    pub(crate) fn new2() -> Self {
+        DemoState {
+            second_time_around: true,
+            got_items: vec![(H3, false), (H4, false), (H5, false)]
+                .into_iter()
+                .collect(),
+            lifetime: None,
+            store_downloads: false,
+        }
+    }
+    /// Return how many of the digests we want we have gotten.
+    fn n_ready(&self) -> usize {
+        self.got_items.values().filter(|x| **x).count()
+    }
+}
+
+impl DirState for DemoState {
+    fn describe(&self) -> String {
+        format!("{:?}", &self)
+    }
+    fn bootstrap_status(&self) -> DirStatus {
+        match &self.lifetime {
+            Some(lifetime) => DirStatusInner::Validated {
+                lifetime: lifetime.clone(),
+                n_mds: (self.n_ready() as u32, self.got_items.len() as u32),
+                usable: self.is_ready(Readiness::Complete),
+            }
+            .into(),
+            None => DirStatus::default(),
+        }
+    }
+    fn bootstrap_phase(&self) -> BootstrapPhase {
+        if !self.second_time_around {
+            BootstrapPhase::GettingConsensus
+        } else if self.is_ready(Readiness::Complete) {
+            BootstrapPhase::Done
+        } else {
+            BootstrapPhase::GettingMicrodescs
+        }
+    }
+    fn is_ready(&self, ready: Readiness) -> bool {
+        match (ready, self.second_time_around) {
+            (_, false) => false,
+            (Readiness::Complete, true) => self.n_ready() == self.got_items.len(),
+            (Readiness::Usable, true) => self.n_ready() >= self.got_items.len() - 1,
+            (Readiness::Stale, true) => false,
+        }
+    }
+    fn can_advance(&self) -> bool {
+        if self.second_time_around {
+            false
+        } else {
+            self.n_ready() == self.got_items.len()
+        }
+    }
+    fn missing_docs(&self) -> Vec<DocId> {
+        self.got_items
+            .iter()
+            .filter_map(|(id, have)| {
+                if *have {
+                    None
+                } else {
+                    Some(DocId::Microdesc(*id))
+                }
+            })
+            .collect()
+    }
+    fn add_from_cache(
+        &mut self,
+        docs: HashMap<DocId, DocumentText>,
+        _storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        let mut changed = false;
+        for id in docs.keys() {
+            if let DocId::Microdesc(id) = id {
+                if self.got_items.get(id) == Some(&false) {
+                    self.got_items.insert(*id, true);
+                    changed = true;
+                }
+            }
+        }
+        Ok(changed)
+    }
+    fn add_from_download(
+        &mut self,
+        text: &str,
+        _request: &ClientRequest,
+        storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        let mut changed = false;
+        for token in text.split_ascii_whitespace() {
+            if let Ok(v) = hex::decode(token) {
+                if let Ok(id) = v.try_into() {
+                    if self.got_items.get(&id) == Some(&false) {
+                        self.got_items.insert(id, true);
+                        changed = true;
+                        if let (true, Some(storage)) = (self.store_downloads, storage) {
+                            storage
+                                .lock()
+                                .unwrap()
+                                .store_microdescs(&[(token, &id)], SystemTime::now())?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(changed)
+    }
+    fn dl_config(&self) -> Result<DownloadSchedule> {
+        Ok(DownloadSchedule::default())
+    }
+    fn advance(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        if self.can_advance() {
+            Ok(Box::new(Self::new2()))
+        } else {
+            Ok(self)
+        }
+    }
+    fn reset_time(&self) -> Option<SystemTime> {
+        None
+    }
+    fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        Ok(Box::new(Self::new1()))
+    }
+    fn reset_partial(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        // Keep what we got in the first phase, but not the second.
+        if self.second_time_around {
+            Ok(Box::new(Self::new2()))
+        } else {
+            Ok(Box::new(Self::new1()))
+        }
+    }
+}
+
+/// A DirState that wants a fixed set of documents, and never does anything
+/// with the ones it gets.
+///
+/// By default, it is never satisfied.  Its other behavior can be adjusted,
+/// so that each test can make it model the kind of state that it needs.
+#[derive(Debug, Clone)]
+pub(crate) struct StubState {
+    /// Which documents does this state want?
+    wants: Vec<DocId>,
+    /// How should we try to download them?
+    schedule: DownloadSchedule,
+    /// Is this state complete?
+    complete: bool,
+    /// Is this state usable, but incomplete and no longer fresh?
+    stale: bool,
+    /// When should this state be reset, if ever?
+    reset_at: Option<SystemTime>,
+    /// If true, every response we get counts as a change, and lets this
+    /// state advance.
+    accept_responses: bool,
+    /// How many responses have we received?
+    n_responses: usize,
+    /// If true, every load from the cache claims to change this state.
+    change_on_load: bool,
+    /// How many times has add_from_cache been called?
+    ///
+    /// (This is shared, so that a test can check it after giving the state
+    /// away.)
+    n_loads: Arc<AtomicUsize>,
+    /// What should this state report from max_load_iterations?
+    max_load_iterations: usize,
+    /// If true, save every response we get to our storage.
+    store_responses: bool,
+    /// If present, refuse any response with this text, as a consensus
+    /// that's older than we allow.
+    too_old: Option<String>,
+}
+
+impl StubState {
+    /// Return a new state that wants `wants`.
+    pub(crate) fn new(wants: Vec<DocId>) -> Self {
+        StubState {
+            wants,
+            schedule: DownloadSchedule::default(),
+            complete: false,
+            stale: false,
+            reset_at: None,
+            accept_responses: false,
+            n_responses: 0,
+            change_on_load: false,
+            n_loads: Arc::new(AtomicUsize::new(0)),
+            max_load_iterations: 100,
+            store_responses: false,
+            too_old: None,
+        }
+    }
+    /// Try to download what we want according to `schedule`.
+    pub(crate) fn schedule(mut self, schedule: DownloadSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+    /// Start out complete.
+    pub(crate) fn complete(mut self) -> Self {
+        self.complete = true;
+        self
+    }
+    /// Start out usable, but incomplete and no longer fresh.
+    pub(crate) fn stale(mut self) -> Self {
+        self.stale = true;
+        self
+    }
+    /// Ask to be reset at `when`.
+    ///
+    /// Each reset makes this state complete, and moves its next reset a day
+    /// later.  Until then, we wait much longer between download attempts
+    /// than until our reset time.
+    pub(crate) fn reset_at(mut self, when: SystemTime) -> Self {
+        self.reset_at = Some(when);
+        self.schedule = DownloadSchedule::new(3, Duration::from_secs(3600), 1);
+        self
+    }
+    /// Count every response as a change, and become able to advance after
+    /// the first one.
+    pub(crate) fn accept_responses(mut self) -> Self {
+        self.accept_responses = true;
+        self
+    }
+    /// Claim to change every time we load from the cache, counting each
+    /// load in `n_loads`, and declare that `max_iterations` loads without
+    /// advancing means that we're stuck.
+    pub(crate) fn change_on_load(
+        mut self,
+        n_loads: Arc<AtomicUsize>,
+        max_iterations: usize,
+    ) -> Self {
+        self.change_on_load = true;
+        self.n_loads = n_loads;
+        self.max_load_iterations = max_iterations;
+        self
+    }
+    /// Save every response we get to our storage: a response to a
+    /// consensus request as a pending consensus, and a response to a
+    /// microdescriptor request as the text of every microdescriptor that
+    /// it asked for.
+    pub(crate) fn store_responses(mut self) -> Self {
+        self.store_responses = true;
+        self
+    }
+    /// Refuse any response whose text is `body`, as a consensus that's
+    /// older than we allow.
+    pub(crate) fn too_old(mut self, body: &str) -> Self {
+        self.too_old = Some(body.to_string());
+        self
+    }
+}
+
+impl DirState for StubState {
+    fn describe(&self) -> String {
+        format!("{:?}", &self)
+    }
+    fn bootstrap_status(&self) -> DirStatus {
+        DirStatus::default()
+    }
+    fn bootstrap_phase(&self) -> BootstrapPhase {
+        if self.complete {
+            BootstrapPhase::Done
+        } else {
+            BootstrapPhase::GettingConsensus
+        }
+    }
+    fn is_ready(&self, ready: Readiness) -> bool {
+        match ready {
+            Readiness::Complete => self.complete,
+            Readiness::Usable => self.complete || self.stale,
+            Readiness::Stale => self.stale,
+        }
+    }
+    fn can_advance(&self) -> bool {
+        self.accept_responses && self.n_responses > 0
+    }
+    fn missing_docs(&self) -> Vec<DocId> {
+        if self.complete {
+            Vec::new()
+        } else {
+            self.wants.clone()
+        }
+    }
+    fn add_from_cache(
+        &mut self,
+        _docs: HashMap<DocId, DocumentText>,
+        _storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        self.n_loads.fetch_add(1, Ordering::SeqCst);
+        Ok(self.change_on_load)
+    }
+    fn add_from_download(
+        &mut self,
+        text: &str,
+        request: &ClientRequest,
+        storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        if self.too_old.as_deref() == Some(text) {
+            return Err(Error::ConsensusTooOld {
+                age: Duration::from_secs(86400),
+                max_age: Duration::from_secs(3600),
+            });
+        }
+        self.n_responses += 1;
+        if let (true, Some(storage)) = (self.store_responses, storage) {
+            let mut storage = storage.lock().unwrap();
+            match request {
+                ClientRequest::Consensus(_) => {
+                    let now = SystemTime::now();
+                    let hour = Duration::from_secs(3600);
+                    let lifetime = Lifetime::new(now, now + hour, now + 2 * hour).unwrap();
+                    let meta = ConsensusMeta::new(lifetime, [1; 32], [2; 32]);
+                    storage.store_consensus(&meta, ConsensusFlavor::Microdesc, true, text)?;
+                }
+                ClientRequest::Microdescs(req) => {
+                    let mds: Vec<_> = req.digests().map(|d| (text, d)).collect();
+                    storage.store_microdescs(&mds, SystemTime::now())?;
+                }
+                _ => {}
+            }
+        }
+        Ok(self.accept_responses)
+    }
+    fn dl_config(&self) -> Result<DownloadSchedule> {
+        Ok(self.schedule)
+    }
+    fn advance(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        Ok(self)
+    }
+    fn reset_time(&self) -> Option<SystemTime> {
+        self.reset_at
+    }
+    fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        match self.reset_at {
+            Some(when) => Ok(Box::new(StubState {
+                complete: true,
+                stale: false,
+                reset_at: Some(when + Duration::from_secs(86400)),
+                ..*self
+            })),
+            None => Ok(self),
+        }
+    }
+    fn max_load_iterations(&self) -> usize {
+        self.max_load_iterations
+    }
+}
+
+/// A DirState that wants a consensus, and then a microdescriptor once it
+/// has the consensus.  It rejects the first two consensus responses it
+/// gets, and every microdescriptor response.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MixedState {
+    /// How many consensus responses have we received?
+    consensus_requests: usize,
+    /// How many microdescriptor responses have we received?
+    md_requests: usize,
+}
+
+impl MixedState {
+    /// Return true if we've accepted a consensus.
+    fn have_consensus(&self) -> bool {
+        self.consensus_requests >= 3
+    }
+}
+
+impl DirState for MixedState {
+    fn describe(&self) -> String {
+        format!("{:?}", &self)
+    }
+    fn bootstrap_status(&self) -> DirStatus {
+        DirStatus::default()
+    }
+    fn bootstrap_phase(&self) -> BootstrapPhase {
+        if self.have_consensus() {
+            BootstrapPhase::GettingMicrodescs
+        } else {
+            BootstrapPhase::GettingConsensus
+        }
+    }
+    fn is_ready(&self, _ready: Readiness) -> bool {
+        false
+    }
+    fn can_advance(&self) -> bool {
+        false
+    }
+    fn missing_docs(&self) -> Vec<DocId> {
+        if self.have_consensus() {
+            vec![DocId::Microdesc(H1)]
+        } else {
+            vec![DocId::LatestConsensus {
+                flavor: ConsensusFlavor::Microdesc,
+                cache_usage: CacheUsage::CacheOkay,
+            }]
+        }
+    }
+    fn add_from_cache(
+        &mut self,
+        _docs: HashMap<DocId, DocumentText>,
+        _storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+    fn add_from_download(
+        &mut self,
+        _text: &str,
+        request: &ClientRequest,
+        _storage: Option<&Mutex<DynStore>>,
+    ) -> Result<bool> {
+        match request {
+            ClientRequest::Consensus(_) => {
+                self.consensus_requests += 1;
+                Ok(self.have_consensus())
+            }
+            _ => {
+                self.md_requests += 1;
+                Ok(false)
+            }
+        }
+    }
+    fn dl_config(&self) -> Result<DownloadSchedule> {
+        Ok(DownloadSchedule::new(3, Duration::from_secs(1), 1))
+    }
+    fn advance(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        Ok(self)
+    }
+    fn reset_time(&self) -> Option<SystemTime> {
+        None
+    }
+    fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
+        Ok(self)
+    }
+}
+
+/// A trivial in-memory [`Store`], to make sure that a `DirMgr` can work
+/// with a store from outside this crate.
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    /// Every consensus we've stored, with its flavor and whether it is
+    /// pending, in the order that we stored them.
+    consensuses: Vec<(ConsensusMeta, ConsensusFlavor, bool, String)>,
+    /// The authority certificates we've stored.
+    authcerts: HashMap<AuthCertKeyIds, String>,
+    /// The microdescriptors we've stored.
+    pub(crate) microdescs: HashMap<MdDigest, String>,
+}
+
+impl MemoryStore {
+    /// Return the most recently stored consensus that matches `pred`.
+    fn find_consensus(
+        &self,
+        pred: impl Fn(&(ConsensusMeta, ConsensusFlavor, bool, String)) -> bool,
+    ) -> Option<&(ConsensusMeta, ConsensusFlavor, bool, String)> {
+        self.consensuses.iter().rev().find(|c| pred(c))
+    }
+}
+
+impl Store for MemoryStore {
+    fn is_readonly(&self) -> bool {
+        false
+    }
+    fn upgrade_to_readwrite(&mut self) -> Result<bool> {
+        Ok(true)
+    }
+    fn expire_all(&mut self, _expiration: &crate::ExpirationConfig) -> Result<()> {
+        Ok(())
+    }
+    fn latest_consensus(
+        &self,
+        flavor: ConsensusFlavor,
+        pending: Option<bool>,
+    ) -> Result<Option<crate::InputString>> {
+        Ok(self
+            .find_consensus(|(_, f, p, _)| *f == flavor && pending.map_or(true, |x| x == *p))
+            .map(|(_, _, _, text)| text.clone().into()))
+    }
+    fn latest_consensus_meta(&self, flavor: ConsensusFlavor) -> Result<Option<ConsensusMeta>> {
+        Ok(self
+            .find_consensus(|(_, f, p, _)| *f == flavor && !*p)
+            .map(|(meta, _, _, _)| meta.clone()))
+    }
+    fn consensus_by_meta(&self, cmeta: &ConsensusMeta) -> Result<crate::InputString> {
+        self.find_consensus(|(m, _, _, _)| m.sha3_256_of_whole() == cmeta.sha3_256_of_whole())
+            .map(|(_, _, _, text)| text.clone().into())
+            .ok_or(Error::CacheCorruption("consensus not found"))
+    }
+    fn consensus_by_sha3_digest_of_signed_part(
+        &self,
+        d: &[u8; 32],
+    ) -> Result<Option<(crate::InputString, ConsensusMeta)>> {
+        Ok(self
+            .find_consensus(|(m, _, _, _)| m.sha3_256_of_signed() == d)
+            .map(|(meta, _, _, text)| (text.clone().into(), meta.clone())))
+    }
+    fn store_consensus(
+        &mut self,
+        cmeta: &ConsensusMeta,
+        flavor: ConsensusFlavor,
+        pending: bool,
+        contents: &str,
+    ) -> Result<()> {
+        self.consensuses
+            .push((cmeta.clone(), flavor, pending, contents.to_owned()));
+        Ok(())
+    }
+    fn mark_consensus_usable(&mut self, cmeta: &ConsensusMeta) -> Result<()> {
+        for (m, _, pending, _) in self.consensuses.iter_mut() {
+            if m.sha3_256_of_whole() == cmeta.sha3_256_of_whole() {
+                *pending = false;
+            }
+        }
+        Ok(())
+    }
+    fn delete_consensus(&mut self, cmeta: &ConsensusMeta) -> Result<()> {
+        self.consensuses
+            .retain(|(m, _, _, _)| m.sha3_256_of_whole() != cmeta.sha3_256_of_whole());
+        Ok(())
+    }
+    fn authcerts(&self, certs: &[AuthCertKeyIds]) -> Result<HashMap<AuthCertKeyIds, String>> {
+        Ok(certs
+            .iter()
+            .filter_map(|id| self.authcerts.get(id).map(|t| (*id, t.clone())))
+            .collect())
+    }
+    fn store_authcerts(&mut self, certs: &[(crate::AuthCertMeta, &str)]) -> Result<()> {
+        for (meta, text) in certs {
+            self.authcerts.insert(*meta.key_ids(), (*text).to_owned());
+        }
+        Ok(())
+    }
+    fn delete_authcerts(&mut self, certs: &[AuthCertKeyIds]) -> Result<()> {
+        for id in certs {
+            self.authcerts.remove(id);
+        }
+        Ok(())
+    }
+    fn microdescs(&self, digests: &[MdDigest]) -> Result<HashMap<MdDigest, String>> {
+        Ok(digests
+            .iter()
+            .filter_map(|d| self.microdescs.get(d).map(|t| (*d, t.clone())))
+            .collect())
+    }
+    fn store_microdescs(&mut self, digests: &[(&str, &MdDigest)], _when: SystemTime) -> Result<()> {
+        for (text, d) in digests {
+            self.microdescs.insert(**d, (*text).to_owned());
+        }
+        Ok(())
+    }
+    fn update_microdescs_listed(&mut self, _digests: &[MdDigest], _when: SystemTime) -> Result<()> {
+        Ok(())
+    }
+    fn delete_microdescs(&mut self, digests: &[MdDigest]) -> Result<()> {
+        for d in digests {
+            self.microdescs.remove(d);
+        }
+        Ok(())
+    }
+    #[cfg(feature = "routerdesc")]
+    fn routerdescs(
+        &self,
+        _digests: &[tor_netdoc::doc::routerdesc::RdDigest],
+    ) -> Result<HashMap<tor_netdoc::doc::routerdesc::RdDigest, String>> {
+        Ok(HashMap::new())
+    }
+    #[cfg(feature = "routerdesc")]
+    fn store_routerdescs(
+        &mut self,
+        _digests: &[(&str, SystemTime, &tor_netdoc::doc::routerdesc::RdDigest)],
+    ) -> Result<()> {
+        Ok(())
+    }
+    #[cfg(feature = "routerdesc")]
+    fn delete_routerdescs(
+        &mut self,
+        _digests: &[tor_netdoc::doc::routerdesc::RdDigest],
+    ) -> Result<()> {
+        Ok(())
+    }
+    #[cfg(feature = "votes")]
+    fn votes(&self, _ids: &[RsaIdentity]) -> Result<HashMap<RsaIdentity, String>> {
+        Ok(HashMap::new())
+    }
+    #[cfg(feature = "votes")]
+    fn store_votes(&mut self, _votes: &[(&str, SystemTime, &RsaIdentity)]) -> Result<()> {
+        Ok(())
+    }
+}
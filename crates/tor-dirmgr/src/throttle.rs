@@ -0,0 +1,89 @@
+//! A shared limit on how fast we take in directory documents.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tor_rtcompat::SleepProvider;
+
+/// A limit on the rate at which we consume the bodies of directory
+/// responses, shared across all of a `DirMgr`'s concurrent requests.
+///
+/// Each response reserves the next stretch of time in which the configured
+/// rate would let us read its body, and waits until that stretch is over.
+/// So if several responses arrive at once, they wait one after another.
+///
+/// # Limitations
+///
+/// This can't slow down a response that's already arriving: we only see a
+/// body once it has been downloaded in full.  What it does is hold each
+/// request's download slot until the response's share of time has passed,
+/// which delays the requests that would come after it.
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    /// The time at which everything we've consumed so far would have finished
+    /// arriving at the configured rate, if that's in the future.
+    busy_until: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    /// Wait until we may consume `n_bytes` more bytes, if we're limited to
+    /// `max_rate` bytes per second.
+    ///
+    /// If `max_rate` is 0, return right away.
+    pub(crate) async fn consume<R: SleepProvider>(
+        &self,
+        runtime: &R,
+        n_bytes: usize,
+        max_rate: u64,
+    ) {
+        if max_rate == 0 {
+            return;
+        }
+        let cost = Duration::from_secs_f64(n_bytes as f64 / max_rate as f64);
+        let done_at = {
+            let mut busy_until = self.busy_until.lock().expect("Poisoned lock");
+            let now = runtime.now();
+            let start = match *busy_until {
+                Some(t) if t > now => t,
+                _ => now,
+            };
+            let done_at = start + cost;
+            *busy_until = Some(done_at);
+            done_at
+        };
+        runtime.sleep_until_instant(done_at).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn shared() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let throttle = Throttle::default();
+
+            // No limit: no waiting.
+            let start = rt.now();
+            rt.wait_for(throttle.consume(&rt, 1_000_000, 0)).await;
+            assert_eq!(rt.now(), start);
+
+            // Two responses that arrive at once have to wait for each other.
+            rt.wait_for(futures::future::join(
+                throttle.consume(&rt, 3000, 1000),
+                throttle.consume(&rt, 2000, 1000),
+            ))
+            .await;
+            assert_eq!(rt.now(), start + Duration::from_secs(5));
+
+            // Time that went by unused doesn't build up credit.
+            rt.wait_for(rt.sleep(Duration::from_secs(60))).await;
+            let start = rt.now();
+            rt.wait_for(throttle.consume(&rt, 500, 1000)).await;
+            assert_eq!(rt.now(), start + Duration::from_millis(500));
+        });
+    }
+}
@@ -0,0 +1,378 @@
+//! Code to download and check a consensus and its certificates, without
+//! storing or using them.  This includes fetching a consensus from some
+//! particular time.
+//!
+//! This runs the same states as a regular bootstrap (see the
+//! [`state`](crate::state) module), but gives them a [`WriteNetDir`] that
+//! throws away anything they write, and no store to write into.
+
+use crate::bootstrap::{fetch_multiple, fetch_single, WantedDocs};
+use crate::docid::ClientRequest;
+use crate::shared_ref::SharedMutArc;
+use crate::state::{GetConsensusState, WriteNetDir};
+use crate::{
+    CacheUsage, CantAdvanceReason, DirMgr, DirMgrConfig, DirState, DocSource, Error, Result,
+};
+
+use futures::stream::{self, StreamExt};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tor_netdir::NetDir;
+use tor_netdoc::doc::netstatus::{Lifetime, MdConsensus};
+use tor_rtcompat::{Runtime, SleepProvider};
+use tracing::{debug, trace};
+
+/// What we found out when we tried to verify a consensus.
+///
+/// Returned by [`DirMgr::verify_only`].
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// The lifetime of the consensus that we tried to verify, if we got one
+    /// that was timely and that claimed to be signed by enough of our
+    /// authorities.
+    consensus_lifetime: Option<Lifetime>,
+    /// True if we found enough certificates to check the consensus, and it
+    /// was correctly signed.
+    verified: bool,
+    /// Every error that we encountered along the way.
+    errors: Vec<Error>,
+}
+
+impl VerifyReport {
+    /// Return the lifetime of the consensus that we tried to verify, if we
+    /// got one that was worth checking.
+    pub fn consensus_lifetime(&self) -> Option<&Lifetime> {
+        self.consensus_lifetime.as_ref()
+    }
+
+    /// Return true if we verified the consensus's signatures with
+    /// certificates from our authorities.
+    pub fn verified(&self) -> bool {
+        self.verified
+    }
+
+    /// Return the errors that we encountered while downloading and checking
+    /// documents, in the order that they happened.
+    ///
+    /// There can be errors here even if [`verified`](Self::verified)
+    /// returns true: for example, if one cache sent us something bogus
+    /// before another sent us what we wanted.
+    pub fn errors(&self) -> &[Error] {
+        &self.errors[..]
+    }
+}
+
+/// A [`WriteNetDir`] that looks like its `DirMgr`, except that it keeps any
+/// directory to itself and tells nobody about changes.
+struct Verifier<R: Runtime> {
+    /// The directory manager whose configuration and clock we use.
+    dirmgr: Arc<DirMgr<R>>,
+    /// A place to put a directory that nobody will ever look at.
+    netdir: SharedMutArc<NetDir>,
+    /// If present, the time at which to check documents, instead of the
+    /// current time.
+    now: Option<SystemTime>,
+}
+
+impl<R: Runtime> WriteNetDir for Verifier<R> {
+    fn config(&self) -> Arc<DirMgrConfig> {
+        self.dirmgr.config.get()
+    }
+    fn netdir(&self) -> &SharedMutArc<NetDir> {
+        &self.netdir
+    }
+    fn netdir_consensus_changed(&self) {}
+    fn netdir_descriptors_changed(&self) {}
+    fn netdir_is_sufficient(&self, _netdir: &NetDir) -> bool {
+        // We never want to install anything.
+        false
+    }
+    fn now(&self) -> SystemTime {
+        self.now.unwrap_or_else(|| self.dirmgr.trusted_now())
+    }
+}
+
+/// Download a consensus and the certificates that sign it with `dirmgr`,
+/// and check them, without storing them or installing a directory.
+pub(crate) async fn verify_only<R: Runtime>(dirmgr: &Arc<DirMgr<R>>) -> Result<VerifyReport> {
+    let verifier = Arc::new(Verifier {
+        dirmgr: Arc::clone(dirmgr),
+        netdir: SharedMutArc::new(),
+        now: None,
+    });
+    let mut report = VerifyReport::default();
+    let mut state: Box<dyn DirState> = Box::new(GetConsensusState::new(
+        Arc::downgrade(&verifier),
+        CacheUsage::MustDownload,
+    )?);
+
+    // First we need a consensus, then its certificates.  Advancing past the
+    // certificates is what checks the signatures.
+    for _ in 0..2 {
+        if fetch_until_advance(dirmgr, state.as_mut(), None, &mut report)
+            .await?
+            .is_none()
+        {
+            debug!("Couldn't get enough documents to verify a consensus.");
+            return Ok(report);
+        }
+        state = match state.advance() {
+            Ok(state) => state,
+            Err(e) => {
+                report.errors.push(e);
+                return Ok(report);
+            }
+        };
+        if report.consensus_lifetime.is_none() {
+            report.consensus_lifetime = state.bootstrap_status().lifetime().cloned();
+        }
+    }
+
+    report.verified = true;
+    Ok(report)
+}
+
+/// Download the consensus that became valid at `valid_after` with `dirmgr`,
+/// and check it and its certificates as we would have at that time, without
+/// storing them or installing a directory.
+pub(crate) async fn fetch_consensus_at<R: Runtime>(
+    dirmgr: &Arc<DirMgr<R>>,
+    valid_after: SystemTime,
+) -> Result<MdConsensus> {
+    let verifier = Arc::new(Verifier {
+        dirmgr: Arc::clone(dirmgr),
+        netdir: SharedMutArc::new(),
+        now: Some(valid_after),
+    });
+    let mut report = VerifyReport::default();
+    let mut state: Box<dyn DirState> = Box::new(GetConsensusState::new(
+        Arc::downgrade(&verifier),
+        CacheUsage::MustDownload,
+    )?);
+
+    // We make the request for the consensus ourselves: fetch_multiple
+    // would ask for the latest one.
+    let flavor = dirmgr.config.get().consensus_flavor();
+    let request = dirmgr.make_consensus_request_at(flavor, valid_after)?;
+    let text =
+        match fetch_until_advance(dirmgr, state.as_mut(), Some(&request), &mut report).await? {
+            Some(text) => text,
+            None => return Err(last_error(report)),
+        };
+    state = state.advance()?;
+
+    // Advancing past the certificates is what checks the signatures.
+    if fetch_until_advance(dirmgr, state.as_mut(), None, &mut report)
+        .await?
+        .is_none()
+    {
+        return Err(last_error(report));
+    }
+    let _ = state.advance()?;
+
+    // We've checked this consensus now, so we don't need to check our copy
+    // of it again.
+    let (_, _, parsed) =
+        MdConsensus::parse(&text).map_err(|e| Error::from_netdoc(DocSource::DirServer {}, e))?;
+    Ok(parsed
+        .dangerously_assume_timely()
+        .dangerously_assume_wellsigned())
+}
+
+/// Return the last error in `report`, or a generic error if there wasn't
+/// one.
+fn last_error(mut report: VerifyReport) -> Error {
+    report.errors.pop().unwrap_or(Error::CantAdvanceState(
+        CantAdvanceReason::NoUsableResponses,
+    ))
+}
+
+/// Download documents for `state` until it can advance, or until we run out
+/// of attempts.  Don't store anything we download.
+///
+/// If `request` is given, make only that request on each attempt;
+/// otherwise, ask for whatever `state` is missing.
+///
+/// Return the text of the document that let the state advance, if we got
+/// one.  Record every recoverable error in `report`.
+async fn fetch_until_advance<R: Runtime>(
+    dirmgr: &Arc<DirMgr<R>>,
+    state: &mut dyn DirState,
+    request: Option<&ClientRequest>,
+    report: &mut VerifyReport,
+) -> Result<Option<String>> {
+    let schedule = state.dl_config()?;
+    let mut retry = schedule.schedule();
+    for attempt in schedule.attempts() {
+        if attempt > 0 {
+            let delay = retry.next_delay(&mut rand::thread_rng());
+            dirmgr.runtime.sleep(delay).await;
+        }
+        let mut fetched = match request {
+            Some(request) => {
+                stream::once(Box::pin(fetch_single(Arc::clone(dirmgr), request.clone())))
+                    .left_stream()
+            }
+            None => {
+                let missing = state.missing_docs();
+                let wanted = WantedDocs::new(missing.iter().copied());
+                fetch_multiple(
+                    Arc::clone(dirmgr),
+                    missing,
+                    schedule.parallelism().into(),
+                    wanted,
+                )?
+                .map(|(outcome, _)| outcome)
+                .right_stream()
+            }
+        };
+        while let Some(outcome) = fetched.next().await {
+            let (request, response) = match outcome {
+                Ok((request, response)) if response.status_code() == 200 => (request, response),
+                Ok((_, response)) => {
+                    trace!(
+                        "cache declined request; reported status {:?}",
+                        response.status_code()
+                    );
+                    continue;
+                }
+                Err(e) if e.retryable() => {
+                    report.errors.push(e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let text = match dirmgr.expand_response_text(&request, response) {
+                Ok(text) => text,
+                Err(e) => {
+                    report.errors.push(e);
+                    continue;
+                }
+            };
+            if let Err(e) = state.add_from_download(&text, &request, None) {
+                report.errors.push(e);
+            }
+            if state.can_advance() {
+                return Ok(Some(text));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::bootstrap::CannedResponse;
+    use crate::testing::{authority_network, config_builder, consensus_time, AUTHCERTS, CONSENSUS};
+    use crate::DocId;
+    use tempfile::TempDir;
+    use time::macros::datetime;
+    use tor_llcrypto::pk::rsa::RsaIdentity;
+    use tor_netdoc::doc::netstatus::ConsensusFlavor;
+
+    fn new_mgr<R: Runtime>(rt: R) -> (TempDir, DirMgr<R>) {
+        let tempdir = TempDir::new().unwrap();
+        let mut netcfg = authority_network();
+        netcfg.fallback_caches(vec![]);
+        let cfg = config_builder(tempdir.path())
+            .network_config(netcfg.build().unwrap())
+            .build()
+            .unwrap();
+        (tempdir, DirMgr::from_config(cfg, rt, None, false).unwrap())
+    }
+
+    #[test]
+    fn verify_without_storing() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            rt.jump_to(consensus_time());
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new(CONSENSUS).certs(AUTHCERTS));
+            let mgr = Arc::new(mgr);
+
+            let report = rt.wait_for(mgr.verify_only()).await.unwrap();
+            assert!(report.verified());
+            assert!(report.errors().is_empty());
+            assert_eq!(
+                report.consensus_lifetime().unwrap().valid_after(),
+                datetime!(2020-08-07 12:42:40 UTC).into()
+            );
+
+            // Nothing was stored or installed.
+            assert!(mgr.opt_netdir().is_none());
+            let store = mgr.store.lock().unwrap();
+            assert!(store
+                .latest_consensus_meta(ConsensusFlavor::Microdesc)
+                .unwrap()
+                .is_none());
+            drop(store);
+            let cert_id = DocId::AuthCert(tor_netdoc::doc::authcert::AuthCertKeyIds {
+                id_fingerprint: RsaIdentity::from_bytes(
+                    &hex::decode("5696AB38CB3852AFA476A5C07B2D4788963D5567").unwrap(),
+                )
+                .unwrap(),
+                sk_fingerprint: RsaIdentity::from_bytes(
+                    &hex::decode("F6ED4AA64D83CAEDE34E19693A7FCF331AAE8A6A").unwrap(),
+                )
+                .unwrap(),
+            });
+            assert!(mgr.text(&cert_id).unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn fetch_historical_consensus() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            // Our clock says it's long after this consensus expired.
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            rt.jump_to(datetime!(2022-03-01 00:00:00 UTC).into());
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new(CONSENSUS).certs(AUTHCERTS));
+            let mgr = Arc::new(mgr);
+
+            let valid_after = datetime!(2020-08-07 12:42:40 UTC).into();
+            let consensus = rt
+                .wait_for(mgr.fetch_consensus_at(valid_after))
+                .await
+                .unwrap();
+            assert_eq!(consensus.lifetime().valid_after(), valid_after);
+
+            // Nothing was stored or installed.
+            assert!(mgr.opt_netdir().is_none());
+            let store = mgr.store.lock().unwrap();
+            assert!(store
+                .latest_consensus_meta(ConsensusFlavor::Microdesc)
+                .unwrap()
+                .is_none());
+            drop(store);
+
+            // A consensus that wasn't valid at the time we asked for is no
+            // good to us.
+            let later = datetime!(2020-08-08 12:00:00 UTC).into();
+            let outcome = rt.wait_for(mgr.fetch_consensus_at(later)).await;
+            assert!(outcome.is_err());
+        });
+    }
+
+    #[test]
+    fn verify_without_certs() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            rt.jump_to(consensus_time());
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            *mgr.canned_response.lock().unwrap() =
+                Some(CannedResponse::new(CONSENSUS).certs("nothing useful here"));
+            let mgr = Arc::new(mgr);
+
+            // We get the consensus, but we can't check it.
+            let report = rt.wait_for(mgr.verify_only()).await.unwrap();
+            assert!(!report.verified());
+            assert!(report.consensus_lifetime().is_some());
+            assert!(mgr.opt_netdir().is_none());
+        });
+    }
+}
@@ -163,6 +163,12 @@ pub(crate) struct Guard {
     #[serde(skip)]
     circ_history: CircHistory,
 
+    /// How many circuits through this guard have died in a row, in a way
+    /// that we couldn't attribute to the guard, since it last succeeded
+    /// or failed?
+    #[serde(skip)]
+    n_consecutive_indeterminate: u32,
+
     /// True if we have warned about this guard behaving suspiciously.
     #[serde(skip)]
     suspicious_behavior_warned: bool,
@@ -217,6 +223,7 @@ impl Guard {
             is_dir_cache: true,
             exploratory_circ_pending: false,
             circ_history: CircHistory::default(),
+            n_consecutive_indeterminate: 0,
             suspicious_behavior_warned: false,
             unknown_fields: Default::default(),
         }
@@ -467,6 +474,7 @@ impl Guard {
         self.retry_at = Some(connect_attempt + retry_interval);
 
         self.circ_history.n_failures += 1;
+        self.n_consecutive_indeterminate = 0;
     }
 
     /// Note that we have launch an attempted use of this guard.
@@ -507,6 +515,7 @@ impl Guard {
         self.set_reachable(Reachable::Reachable);
         self.exploratory_circ_pending = false;
         self.circ_history.n_successes += 1;
+        self.n_consecutive_indeterminate = 0;
 
         if self.confirmed_at.is_none() {
             self.confirmed_at = Some(
@@ -526,10 +535,17 @@ impl Guard {
         }
     }
 
+    /// Return how many circuits through this guard have died in a row in a
+    /// way that we couldn't attribute to the guard.
+    pub(crate) fn n_consecutive_indeterminate(&self) -> u32 {
+        self.n_consecutive_indeterminate
+    }
+
     /// Note that a circuit through this guard died in a way that we couldn't
     /// necessarily attribute to the guard.
     pub(crate) fn record_indeterminate_result(&mut self) {
         self.circ_history.n_indeterminate += 1;
+        self.n_consecutive_indeterminate += 1;
 
         if let Some(ratio) = self.circ_history.indeterminate_ratio() {
             // TODO: These should not be hardwired, and they may be set
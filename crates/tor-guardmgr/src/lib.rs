@@ -197,6 +197,13 @@ struct GuardMgrInner {
     /// This is updated whenever the consensus parameters change.
     params: GuardParams,
 
+    /// How many circuits through the same guard may fail in a row, in a way
+    /// that we can't attribute to the guard, before we decide that the
+    /// guard is probably down?  Zero means "no limit".
+    ///
+    /// See [`GuardMgr::set_max_guard_failures`].
+    max_guard_failures: u32,
+
     /// A mpsc channel, used to tell the task running in
     /// [`daemon::report_status_events`] about a new event to monitor.
     ///
@@ -274,6 +281,7 @@ impl<R: Runtime> GuardMgr<R> {
             guards: state,
             last_primary_retry_time: runtime.now(),
             params: GuardParams::default(),
+            max_guard_failures: 0,
             ctrl,
             pending: HashMap::new(),
             waiting: Vec::new(),
@@ -403,6 +411,39 @@ impl<R: Runtime> GuardMgr<R> {
         inner.update(now, Some(netdir));
     }
 
+    /// Set how many circuits through the same guard may fail in a row
+    /// before we decide that the guard is probably down.
+    ///
+    /// This only counts failures that happen after we've connected to the
+    /// guard, which we can't blame on the guard alone.  (When we can't
+    /// connect to a guard at all, we mark it as down right away.)  Once a
+    /// guard is marked as down, [`GuardMgr::select_guard`] will give out
+    /// the next guard in our preference order instead, until it's time to
+    /// retry the first one.
+    ///
+    /// If `max_failures` is 0 (the default), we never mark a guard as down
+    /// for this reason.
+    pub fn set_max_guard_failures(&self, max_failures: u32) {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        inner.max_guard_failures = max_failures;
+    }
+
+    /// Return our current primary guards, in preference order (from best to
+    /// worst).
+    ///
+    /// These are the guards that [`GuardMgr::select_guard`] will prefer to
+    /// return, as long as any of them is usable.  The list is empty until
+    /// this `GuardMgr` has been given a [`NetDir`] to pick guards from.
+    pub fn primary_guards(&self) -> Vec<Guard> {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner
+            .guards
+            .active_guards()
+            .primary_guards()
+            .map(|g| g.get_external_rep())
+            .collect()
+    }
+
     /// Select a guard for a given [`GuardUsage`].
     ///
     /// On success, we return a [`GuardId`] object to identify which
@@ -668,9 +709,11 @@ impl GuardMgrInner {
                     pending.reply(false);
                 }
                 GuardStatus::Indeterminate => {
-                    self.guards
-                        .active_guards_mut()
-                        .record_indeterminate_result(guard_id);
+                    self.guards.active_guards_mut().record_indeterminate_result(
+                        guard_id,
+                        self.max_guard_failures,
+                        runtime.now(),
+                    );
                     pending.reply(false);
                 }
             };
@@ -1108,6 +1151,9 @@ mod test {
             guardmgr.update_network(&netdir);
 
             let (id, mon, usable) = guardmgr.select_guard(usage, Some(&netdir)).unwrap();
+            // The guard we got should be one of our primary guards.
+            let primary = guardmgr.primary_guards();
+            assert!(primary.contains(&id));
             // Report that the circuit succeeded.
             mon.succeeded();
 
@@ -1177,6 +1223,35 @@ mod test {
         });
     }
 
+    #[test]
+    fn switch_guard_after_repeated_failures() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, _statemgr, netdir) = init(rt);
+            let u = GuardUsage::default();
+            guardmgr.update_network(&netdir);
+            guardmgr.set_max_guard_failures(3);
+
+            // Every circuit through our first guard dies after we've
+            // connected to it.  Until we reach the limit, we keep trying
+            // that guard.
+            let mut first = None;
+            for _ in 0..3 {
+                let (id, mon, usable) = guardmgr.select_guard(u.clone(), Some(&netdir)).unwrap();
+                assert_eq!(first.get_or_insert_with(|| id.clone()), &id);
+                mon.report(GuardStatus::Indeterminate);
+                guardmgr.flush_msg_queue().await;
+                assert!(!usable.await.unwrap());
+            }
+
+            // Now we should have given up on it, and moved on to a backup
+            // guard, which works fine.
+            let (backup, mon, usable) = guardmgr.select_guard(u.clone(), Some(&netdir)).unwrap();
+            assert_ne!(Some(backup), first);
+            mon.succeeded();
+            assert!(usable.await.unwrap());
+        });
+    }
+
     #[test]
     fn filtering_basics() {
         test_with_all_runtimes!(|rt| async move {
@@ -143,6 +143,14 @@ impl GuardSet {
         self.guards.get(id)
     }
 
+    /// Return an iterator over our primary guards, in preference order
+    /// (from best to worst).
+    pub(crate) fn primary_guards(&self) -> impl Iterator<Item = &Guard> + '_ {
+        self.primary
+            .iter()
+            .filter_map(move |id| self.guards.get(id))
+    }
+
     /// Replace the filter used by this `GuardSet` with `filter`.
     ///
     /// Removes all primary guards that the filter doesn't permit.
@@ -603,10 +611,29 @@ impl GuardSet {
     /// Record that an attempt to use the guard with `guard_id` has
     /// just failed in a way that we could not definitively attribute to
     /// the guard.
-    pub(crate) fn record_indeterminate_result(&mut self, guard_id: &GuardId) {
+    ///
+    /// If this has now happened `max_failures` times in a row (and
+    /// `max_failures` is nonzero), we assume that the guard is probably
+    /// down after all, and record a failure for it, so that we'll choose
+    /// a different guard until it is time to retry this one.
+    pub(crate) fn record_indeterminate_result(
+        &mut self,
+        guard_id: &GuardId,
+        max_failures: u32,
+        now: Instant,
+    ) {
+        let is_primary = self.guard_is_primary(guard_id);
         if let Some(guard) = self.guards.get_mut(guard_id) {
             guard.note_exploratory_circ(false);
             guard.record_indeterminate_result();
+            if max_failures > 0 && guard.n_consecutive_indeterminate() >= max_failures {
+                info!(
+                    ?guard_id,
+                    "{} circuits in a row failed through this guard; trying a different one.",
+                    max_failures
+                );
+                guard.record_failure(now, is_primary);
+            }
         }
     }
 
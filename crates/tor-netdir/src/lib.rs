@@ -518,6 +518,35 @@ impl NetDir {
         self.params = new_params;
     }
 
+    /// Forget the microdescriptor with digest `digest`, so that it is
+    /// missing from this NetDir again.
+    ///
+    /// Return true if we had it, and false otherwise.
+    ///
+    /// Use this when we have reason to think that a microdescriptor is bad,
+    /// so that it gets downloaded again.
+    pub fn forget_microdesc(&mut self, digest: &MdDigest) -> bool {
+        let rs_idx = match self.mds.get(digest) {
+            Some(MdEntry::Present { .. }) => self
+                .consensus
+                .relays()
+                .iter()
+                .position(|rs| rs.md_digest() == digest),
+            _ => None,
+        };
+        let rs_idx = match rs_idx {
+            Some(idx) => idx,
+            None => return false,
+        };
+        if let Some(MdEntry::Present { md }) = self.mds.take(digest) {
+            if self.rs_idx_by_ed.get(md.ed25519_id()) == Some(&rs_idx) {
+                self.rs_idx_by_ed.remove(md.ed25519_id());
+            }
+        }
+        self.mds.insert(MdEntry::Absent { rs_idx, d: *digest });
+        true
+    }
+
     /// Return an iterator over all Relay objects, including invalid ones
     /// that we can't use.
     pub fn all_relays(&self) -> impl Iterator<Item = UncheckedRelay<'_>> {
@@ -905,6 +934,14 @@ impl<'a> UncheckedRelay<'a> {
     pub fn is_dir_cache(&self) -> bool {
         rs_is_dir_cache(self.rs)
     }
+    /// Return the RsaIdentity for this relay.
+    pub fn rsa_id(&self) -> &RsaIdentity {
+        self.rs.rsa_identity()
+    }
+    /// Return the flags that the consensus lists for this relay.
+    pub fn flags(&self) -> &netstatus::RelayFlags {
+        self.rs.flags()
+    }
 }
 
 impl<'a> Relay<'a> {
@@ -1153,6 +1190,32 @@ mod test {
         assert_eq!(dir.missing_microdescs().count(), 2);
     }
 
+    #[test]
+    fn forget_microdesc() {
+        let (consensus, microdescs) = construct_network().unwrap();
+        let mut dir = PartialNetDir::new(consensus, None);
+        for md in microdescs.iter() {
+            dir.add_microdesc(md.clone());
+        }
+        let mut dir = dir.unwrap_if_sufficient().unwrap();
+        let md = &microdescs[3];
+        assert!(dir.by_id(md.ed25519_id()).is_some());
+
+        assert!(dir.forget_microdesc(md.digest()));
+        let missing: Vec<_> = dir.missing_microdescs().collect();
+        assert_eq!(missing, vec![md.digest()]);
+        assert!(dir.by_id(md.ed25519_id()).is_none());
+
+        // We can't forget it twice, or forget something we never wanted.
+        assert!(!dir.forget_microdesc(md.digest()));
+        assert!(!dir.forget_microdesc(&[0x55; 32]));
+
+        // And we can get it back.
+        assert!(dir.add_microdesc(md.clone()));
+        assert_eq!(dir.missing_microdescs().count(), 0);
+        assert!(dir.by_id(md.ed25519_id()).is_some());
+    }
+
     #[test]
     fn path_count() {
         let low_threshold = "min_paths_for_circs_pct=64".parse().unwrap();
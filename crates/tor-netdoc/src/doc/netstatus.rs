@@ -185,7 +185,6 @@ where
 }
 
 /// A list of subprotocol versions that implementors should/must provide.
-#[allow(dead_code)]
 #[derive(Debug, Clone, Default)]
 pub struct ProtoStatus {
     /// Set of protocols that are recommended; if we're missing a protocol
@@ -538,6 +537,12 @@ impl<RS> Consensus<RS> {
     pub fn params(&self) -> &NetParams<i32> {
         &self.header.hdr.params
     }
+
+    /// Return the subprotocol versions that this consensus recommends and
+    /// requires for clients.
+    pub fn client_protocol_status(&self) -> &ProtoStatus {
+        &self.header.hdr.client_protos
+    }
 }
 
 decl_keyword! {
@@ -754,6 +759,23 @@ impl ProtoStatus {
             required,
         })
     }
+
+    /// Return the set of protocol versions that implementations should
+    /// provide.
+    ///
+    /// An implementation that is missing any of these should warn its user.
+    pub fn recommended(&self) -> &Protocols {
+        &self.recommended
+    }
+
+    /// Return the set of protocol versions that implementations must
+    /// provide.
+    ///
+    /// An implementation that is missing any of these should refuse to
+    /// start.
+    pub fn required(&self) -> &Protocols {
+        &self.required
+    }
 }
 
 impl<T> std::str::FromStr for NetParams<T>
@@ -1450,6 +1472,15 @@ impl<RS> UnvalidatedConsensus<RS> {
     pub fn authorities_are_correct(&self, authorities: &[&RsaIdentity]) -> bool {
         self.siggroup.could_validate(authorities)
     }
+
+    /// Return the number of authorities in `authorities` that this
+    /// consensus claims to be signed by.
+    ///
+    /// (The signatures are not checked: this only counts the ones that are
+    /// present.)
+    pub fn n_purported_signers(&self, authorities: &[&RsaIdentity]) -> usize {
+        self.siggroup.n_signed_by(authorities)
+    }
 }
 
 impl<RS> ExternallySigned<Consensus<RS>> for UnvalidatedConsensus<RS> {
@@ -1512,6 +1543,12 @@ impl SignatureGroup {
     /// this signature group is _potentially_ well-signed according to those
     /// authorities.
     fn could_validate(&self, authorities: &[&RsaIdentity]) -> bool {
+        self.n_signed_by(authorities) > (authorities.len() / 2)
+    }
+
+    /// Given a list of authority identity key fingerprints, return the
+    /// number of those authorities that have a signature in this group.
+    fn n_signed_by(&self, authorities: &[&RsaIdentity]) -> usize {
         let mut signed_by: HashSet<RsaIdentity> = HashSet::new();
         for sig in &self.signatures {
             let id_fp = &sig.key_ids.id_fingerprint;
@@ -1524,7 +1561,7 @@ impl SignatureGroup {
             }
         }
 
-        signed_by.len() > (authorities.len() / 2)
+        signed_by.len()
     }
 
     /// Return true if the signature group defines a valid signature.
@@ -1617,6 +1654,8 @@ mod test {
         assert!(consensus.authorities_are_correct(&auth_ids));
         // A subset would also work.
         assert!(consensus.authorities_are_correct(&auth_ids[0..1]));
+        assert_eq!(consensus.n_purported_signers(&auth_ids), 3);
+        assert_eq!(consensus.n_purported_signers(&auth_ids[0..1]), 1);
         {
             // If we only believe in an authority that isn't listed,
             // that won't work.
@@ -424,8 +424,12 @@ mod test {
             .build_into(&mut builder)
             .unwrap();
 
-        let _cons = builder.testing_consensus().unwrap();
+        let cons = builder.testing_consensus().unwrap();
 
-        // TODO: Check actual members of `cons` above.
+        let protos = cons.client_protocol_status();
+        assert_eq!(protos.required().to_string(), "DirCache=2 LinkAuth=3");
+        assert_eq!(protos.recommended().to_string(), "DirCache=6");
+
+        // TODO: Check the other members of `cons` above.
     }
 }
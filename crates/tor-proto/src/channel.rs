@@ -91,6 +91,8 @@ use crate::channel::unique_id::CircUniqIdContext;
 #[cfg(test)]
 pub(crate) use codec::CodecError;
 pub use handshake::{OutboundClientHandshake, UnverifiedChannel, VerifiedChannel};
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use reactor::new_reactor;
 
 /// Type alias: A Sink and Stream that transforms a TLS connection into
 /// a cell-based communication mechanism.
@@ -437,7 +439,7 @@ pub(crate) mod test {
     #![allow(clippy::unwrap_used)]
     use super::*;
     use crate::channel::codec::test::MsgBuf;
-    pub(crate) use crate::channel::reactor::test::new_reactor;
+    pub(crate) use crate::channel::reactor::new_reactor;
     use tor_cell::chancell::{msg, ChanCell};
 
     /// Make a new fake reactor-less channel.  For testing only, obviously.
@@ -408,45 +408,52 @@ impl Reactor {
     }
 }
 
+/// The type of the cells that a fake channel from [`new_reactor`] receives.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) type CodecResult = std::result::Result<ChanCell, CodecError>;
+
+/// Make a new channel and reactor that talk over a pair of queues instead
+/// of a TLS connection.  For testing only.
+///
+/// Returns the channel, its reactor, a queue of the cells that the channel
+/// sends, and a queue on which to send it cells.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn new_reactor() -> (
+    crate::channel::Channel,
+    Reactor,
+    mpsc::Receiver<ChanCell>,
+    mpsc::Sender<CodecResult>,
+) {
+    let link_protocol = 4;
+    let (send1, recv1) = mpsc::channel(32);
+    let (send2, recv2) = mpsc::channel(32);
+    let unique_id = unique_id::UniqId::new();
+    let ed_id = [6; 32].into();
+    let rsa_id = [10; 20].into();
+    let send1 = send1.sink_map_err(|e| {
+        trace!("got sink error: {}", e);
+        CodecError::Cell(tor_cell::Error::ChanProto("dummy message".into()))
+    });
+    let (chan, reactor) = crate::channel::Channel::new(
+        link_protocol,
+        Box::new(send1),
+        Box::new(recv2),
+        unique_id,
+        ed_id,
+        rsa_id,
+    );
+    (chan, reactor, recv1, send2)
+}
+
 #[cfg(test)]
 pub(crate) mod test {
     #![allow(clippy::unwrap_used)]
     use super::*;
-    use crate::channel::UniqId;
     use crate::circuit::CircParameters;
     use futures::sink::SinkExt;
     use futures::stream::StreamExt;
     use futures::task::SpawnExt;
 
-    type CodecResult = std::result::Result<ChanCell, CodecError>;
-
-    pub(crate) fn new_reactor() -> (
-        crate::channel::Channel,
-        Reactor,
-        mpsc::Receiver<ChanCell>,
-        mpsc::Sender<CodecResult>,
-    ) {
-        let link_protocol = 4;
-        let (send1, recv1) = mpsc::channel(32);
-        let (send2, recv2) = mpsc::channel(32);
-        let unique_id = UniqId::new();
-        let ed_id = [6; 32].into();
-        let rsa_id = [10; 20].into();
-        let send1 = send1.sink_map_err(|e| {
-            trace!("got sink error: {}", e);
-            CodecError::Cell(tor_cell::Error::ChanProto("dummy message".into()))
-        });
-        let (chan, reactor) = crate::channel::Channel::new(
-            link_protocol,
-            Box::new(send1),
-            Box::new(recv2),
-            unique_id,
-            ed_id,
-            rsa_id,
-        );
-        (chan, reactor, recv1, send2)
-    }
-
     // Try shutdown from inside run_once..
     #[test]
     fn shutdown() {
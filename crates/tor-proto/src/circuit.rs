@@ -44,6 +44,8 @@ mod halfstream;
 pub(crate) mod reactor;
 pub(crate) mod sendme;
 mod streammap;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod unique_id;
 
 use crate::channel::Channel;
@@ -69,8 +71,9 @@ use crate::circuit::sendme::StreamRecvWindow;
 use futures::SinkExt;
 use std::net::IpAddr;
 use std::sync::atomic::{AtomicU8, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tor_cell::relaycell::StreamId;
+use tor_llcrypto::pk::rsa::RsaIdentity;
 // use std::time::Duration;
 
 use crate::crypto::handshake::ntor::NtorPublicKey;
@@ -104,6 +107,11 @@ pub struct ClientCirc {
     ///
     /// This value is incremented after the circuit successfully completes extending to a new hop.
     hops: Arc<AtomicU8>,
+    /// The RSA identities of the relays on this circuit, in order.
+    ///
+    /// An identity is appended once the circuit has finished extending
+    /// to the corresponding hop.
+    path: Arc<Mutex<Vec<RsaIdentity>>>,
     /// A unique identifier for this circuit.
     unique_id: UniqId,
     /// Channel to send control messages to the reactor.
@@ -121,6 +129,9 @@ pub struct PendingClientCirc {
     /// A oneshot receiver on which we'll receive a CREATED* cell,
     /// or a DESTROY cell.
     recvcreated: oneshot::Receiver<CreateResponse>,
+    /// The RSA identity of the relay at the other end of our channel:
+    /// this will be the first hop of the circuit.
+    first_hop: RsaIdentity,
     /// The ClientCirc object that we can expose on success.
     circ: ClientCirc,
 }
@@ -225,9 +236,26 @@ impl ClientCirc {
 
         rx.await.map_err(|_| Error::CircuitClosed)??;
 
+        self.note_hop_added(*target.rsa_identity());
+
         Ok(())
     }
 
+    /// Record that this circuit has been extended to the relay with `id`.
+    fn note_hop_added(&self, id: RsaIdentity) {
+        self.path.lock().expect("poisoned lock").push(id);
+    }
+
+    /// Return the RSA identities of the relays that this circuit was built
+    /// through, starting with the first hop.
+    ///
+    /// Only the hops that were successfully added are reported, so this
+    /// list can be shorter than the path that was originally requested if
+    /// the circuit is still being built.
+    pub fn path(&self) -> Vec<RsaIdentity> {
+        self.path.lock().expect("poisoned lock").clone()
+    }
+
     /// Helper, used to begin a stream.
     ///
     /// This function allocates a stream ID, and sends the message
@@ -428,6 +456,7 @@ impl PendingClientCirc {
         let crypto_out = OutboundClientCrypt::new();
         let (control_tx, control_rx) = mpsc::unbounded();
         let num_hops = Arc::new(AtomicU8::new(0));
+        let first_hop = *channel.peer_rsa_id();
 
         let reactor = Reactor {
             control: control_rx,
@@ -445,6 +474,7 @@ impl PendingClientCirc {
 
         let circuit = ClientCirc {
             hops: num_hops,
+            path: Arc::new(Mutex::new(Vec::new())),
             unique_id,
             control: control_tx,
             #[cfg(test)]
@@ -453,6 +483,7 @@ impl PendingClientCirc {
 
         let pending = PendingClientCirc {
             recvcreated: createdreceiver,
+            first_hop,
             circ: circuit,
         };
         (pending, reactor)
@@ -485,6 +516,7 @@ impl PendingClientCirc {
 
         rx.await.map_err(|_| Error::CircuitClosed)??;
 
+        self.circ.note_hop_added(self.first_hop);
         Ok(self.circ)
     }
 
@@ -522,6 +554,7 @@ impl PendingClientCirc {
 
         rx.await.map_err(|_| Error::CircuitClosed)??;
 
+        self.circ.note_hop_added(*target.rsa_identity());
         Ok(self.circ)
     }
 }
@@ -776,9 +809,10 @@ mod test {
 
         let (circ, _) = futures::join!(client_fut, simulate_relay_fut);
 
-        let _circ = circ.unwrap();
+        let circ = circ.unwrap();
 
         // pfew!  We've build a circuit!  Let's make sure it has one hop.
+        assert_eq!(circ.path(), vec![example_target().rsa_id]);
         /* TODO: reinstate this.
         let inner = Arc::get_mut(&mut circuit).unwrap().c.into_inner();
         assert_eq!(inner.hops.len(), 1);
@@ -798,50 +832,6 @@ mod test {
         });
     }
 
-    // An encryption layer that doesn't do any crypto.   Can be used
-    // as inbound or outbound, but not both at once.
-    pub(crate) struct DummyCrypto {
-        counter_tag: [u8; 20],
-        counter: u32,
-        lasthop: bool,
-    }
-    impl DummyCrypto {
-        fn next_tag(&mut self) -> &[u8; 20] {
-            #![allow(clippy::identity_op)]
-            self.counter_tag[0] = ((self.counter >> 0) & 255) as u8;
-            self.counter_tag[1] = ((self.counter >> 8) & 255) as u8;
-            self.counter_tag[2] = ((self.counter >> 16) & 255) as u8;
-            self.counter_tag[3] = ((self.counter >> 24) & 255) as u8;
-            self.counter += 1;
-            &self.counter_tag
-        }
-    }
-
-    impl crate::crypto::cell::OutboundClientLayer for DummyCrypto {
-        fn originate_for(&mut self, _cell: &mut RelayCellBody) -> &[u8] {
-            self.next_tag()
-        }
-        fn encrypt_outbound(&mut self, _cell: &mut RelayCellBody) {}
-    }
-    impl crate::crypto::cell::InboundClientLayer for DummyCrypto {
-        fn decrypt_inbound(&mut self, _cell: &mut RelayCellBody) -> Option<&[u8]> {
-            if self.lasthop {
-                Some(self.next_tag())
-            } else {
-                None
-            }
-        }
-    }
-    impl DummyCrypto {
-        pub(crate) fn new(lasthop: bool) -> Self {
-            DummyCrypto {
-                counter_tag: [0; 20],
-                counter: 0,
-                lasthop,
-            }
-        }
-    }
-
     // Helper: set up a 3-hop circuit with no encryption, where the
     // next inbound message seems to come from hop next_msg_from
     async fn newcirc_ext<R: Runtime>(
@@ -865,6 +855,7 @@ mod test {
         let PendingClientCirc {
             circ,
             recvcreated: _,
+            first_hop: _,
         } = pending;
 
         for idx in 0_u8..3 {
@@ -880,6 +871,7 @@ mod test {
                 })
                 .unwrap();
             rx.await.unwrap().unwrap();
+            circ.note_hop_added([idx; 20].into());
         }
 
         (circ, circmsg_send)
@@ -1026,6 +1018,10 @@ mod test {
 
             // Did we really add another hop?
             assert_eq!(circ.n_hops(), 4);
+            let mut expected_path: Vec<pk::rsa::RsaIdentity> =
+                (0_u8..3).map(|idx| [idx; 20].into()).collect();
+            expected_path.push(example_target().rsa_id);
+            assert_eq!(circ.path(), expected_path);
         });
     }
 
@@ -1052,6 +1048,7 @@ mod test {
         let _sink = sink_handle.await;
 
         assert_eq!(circ.n_hops(), 3);
+        assert_eq!(circ.path().len(), 3);
         assert!(outcome.is_err());
         outcome.unwrap_err()
     }
@@ -1179,6 +1176,61 @@ mod test {
         });
     }
 
+    #[test]
+    fn stream_counters() {
+        use crate::stream::StreamCounters;
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+            let counters = Arc::new(StreamCounters::new());
+
+            let counters_clone = Arc::clone(&counters);
+            let begin_and_send_fut = async move {
+                let mut stream = circ.begin_dir_stream().await.unwrap();
+                stream.attach_counters(&counters_clone);
+                assert_eq!(counters_clone.n_opened(), 1);
+                assert_eq!(counters_clone.n_open(), 1);
+
+                stream.write_all(b"HTTP/1.0 GET /\r\n").await.unwrap();
+                stream.flush().await.unwrap();
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await.unwrap();
+                assert_eq!(&buf[..], b"HTTP/1.0 404 Not found\r\n");
+                // Once the stream is gone, it no longer counts as open.
+                drop(stream);
+            };
+            let reply_fut = async move {
+                let (_id, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match chmsg {
+                    ChanMsg::Relay(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+                    _ => panic!(),
+                };
+                let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+                assert!(matches!(rmsg, RelayMsg::BeginDir));
+                let connected = relaymsg::Connected::new_empty().into();
+                sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
+
+                // Wait for the request before we answer it.
+                let _ = rx.next().await.unwrap();
+                let data = relaymsg::Data::new(b"HTTP/1.0 404 Not found\r\n")
+                    .unwrap()
+                    .into();
+                sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+                let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE).into();
+                sink.send(rmsg_to_ccmsg(streamid, end)).await.unwrap();
+
+                (rx, sink) // gotta keep these alive, or the reactor will exit.
+            };
+
+            let ((), (_rx, _sink)) = futures::join!(begin_and_send_fut, reply_fut);
+
+            assert_eq!(counters.n_opened(), 1);
+            assert_eq!(counters.n_open(), 0);
+            assert_eq!(counters.bytes_sent(), 16);
+            assert_eq!(counters.bytes_received(), 24);
+        });
+    }
+
     // Set up a circuit and stream that expects some incoming SENDMEs.
     async fn setup_incoming_sendme_case<R: Runtime>(
         rt: &R,
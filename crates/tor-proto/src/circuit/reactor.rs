@@ -129,8 +129,8 @@ pub(super) enum CtrlMsg {
     },
     /// Shut down the reactor.
     Shutdown,
-    /// (tests only) Add a hop to the list of hops on this circuit, with dummy cryptography.
-    #[cfg(test)]
+    /// (testing only) Add a hop to the list of hops on this circuit, with dummy cryptography.
+    #[cfg(any(test, feature = "testing"))]
     AddFakeHop {
         supports_flowctrl_1: bool,
         fwd_lasthop: bool,
@@ -1093,7 +1093,7 @@ impl Reactor {
                 let cell = RelayCell::new(stream_id, sendme.into());
                 self.send_relay_cell(cx, hop_num, false, cell)?;
             }
-            #[cfg(test)]
+            #[cfg(any(test, feature = "testing"))]
             CtrlMsg::AddFakeHop {
                 supports_flowctrl_1,
                 fwd_lasthop,
@@ -1101,7 +1101,7 @@ impl Reactor {
                 params,
                 done,
             } => {
-                use crate::circuit::test::DummyCrypto;
+                use crate::circuit::testing::DummyCrypto;
 
                 // This kinds of conversion is okay for testing, but just for testing.
                 let require_sendme_auth = if supports_flowctrl_1 {
@@ -0,0 +1,128 @@
+//! Testing-only helpers for code that uses circuits.
+//!
+//! Nothing in this module is covered by semver.
+
+use super::celltypes::ClientCircChanMsg;
+use super::reactor::CtrlMsg;
+use super::{CircParameters, ClientCirc, PendingClientCirc, UniqId};
+use crate::crypto::cell::RelayCellBody;
+use tor_cell::chancell::msg::{ChanMsg, Relay};
+use tor_cell::relaycell::msg::{Connected, RelayMsg};
+use tor_cell::relaycell::RelayCell;
+
+use futures::channel::{mpsc, oneshot};
+use futures::task::{Spawn, SpawnError, SpawnExt};
+use futures::{SinkExt, StreamExt};
+
+/// An encryption layer that doesn't do any crypto.   Can be used
+/// as inbound or outbound, but not both at once.
+pub(crate) struct DummyCrypto {
+    /// The tag that we most recently generated.
+    counter_tag: [u8; 20],
+    /// How many tags have we generated?
+    counter: u32,
+    /// Are we the layer for the last hop of our circuit?
+    lasthop: bool,
+}
+impl DummyCrypto {
+    /// Construct a new DummyCrypto.
+    pub(crate) fn new(lasthop: bool) -> Self {
+        DummyCrypto {
+            counter_tag: [0; 20],
+            counter: 0,
+            lasthop,
+        }
+    }
+
+    /// Generate a new tag, based on how many we have generated so far.
+    fn next_tag(&mut self) -> &[u8; 20] {
+        #![allow(clippy::identity_op)]
+        self.counter_tag[0] = ((self.counter >> 0) & 255) as u8;
+        self.counter_tag[1] = ((self.counter >> 8) & 255) as u8;
+        self.counter_tag[2] = ((self.counter >> 16) & 255) as u8;
+        self.counter_tag[3] = ((self.counter >> 24) & 255) as u8;
+        self.counter += 1;
+        &self.counter_tag
+    }
+}
+
+impl crate::crypto::cell::OutboundClientLayer for DummyCrypto {
+    fn originate_for(&mut self, _cell: &mut RelayCellBody) -> &[u8] {
+        self.next_tag()
+    }
+    fn encrypt_outbound(&mut self, _cell: &mut RelayCellBody) {}
+}
+impl crate::crypto::cell::InboundClientLayer for DummyCrypto {
+    fn decrypt_inbound(&mut self, _cell: &mut RelayCellBody) -> Option<&[u8]> {
+        if self.lasthop {
+            Some(self.next_tag())
+        } else {
+            None
+        }
+    }
+}
+
+/// Build a fake three-hop circuit whose last hop acts as a trivial exit:
+/// it accepts every stream, and echoes back whatever data it receives.
+///
+/// The circuit uses no cryptography, and doesn't talk to any real relay.
+/// We use `spawner` to launch the tasks that run it.
+pub async fn fake_echo_circuit<S: Spawn>(spawner: &S) -> Result<ClientCirc, SpawnError> {
+    let (chan, chan_reactor, mut from_chan, to_chan) = crate::channel::new_reactor();
+    spawner.spawn(async {
+        let _ignore = chan_reactor.run().await;
+    })?;
+
+    let (_created_send, created_recv) = oneshot::channel();
+    let (mut circmsg_send, circmsg_recv) = mpsc::channel(64);
+    let unique_id = UniqId::new(23, 17);
+    let (pending, reactor) =
+        PendingClientCirc::new(128.into(), chan, created_recv, circmsg_recv, unique_id);
+    spawner.spawn(async {
+        let _ignore = reactor.run().await;
+    })?;
+
+    let circ = pending.circ;
+    for idx in 0_u8..3 {
+        let (tx, rx) = oneshot::channel();
+        let _ = circ.control.unbounded_send(CtrlMsg::AddFakeHop {
+            supports_flowctrl_1: true,
+            fwd_lasthop: idx == 2,
+            rev_lasthop: idx == 2,
+            params: CircParameters::default(),
+            done: tx,
+        });
+        let _ = rx.await;
+        circ.note_hop_added([idx; 20].into());
+    }
+
+    spawner.spawn(async move {
+        // Keep the channel's input open for as long as we're running.
+        let _to_chan = to_chan;
+        while let Some(cell) = from_chan.next().await {
+            let body = match cell.into_circid_and_msg() {
+                (_, ChanMsg::Relay(r)) => r.into_relay_body(),
+                _ => continue,
+            };
+            let (id, msg) = match RelayCell::decode(body) {
+                Ok(cell) => cell.into_streamid_and_msg(),
+                Err(_) => continue,
+            };
+            let reply = match msg {
+                RelayMsg::Begin(_) | RelayMsg::BeginDir => Connected::new_empty().into(),
+                msg @ RelayMsg::Data(_) => msg,
+                _ => continue,
+            };
+            let body = match RelayCell::new(id, reply).encode(&mut rand::thread_rng()) {
+                Ok(body) => body,
+                Err(_) => continue,
+            };
+            let msg = ClientCircChanMsg::Relay(Relay::from_raw(body));
+            if circmsg_send.send(msg).await.is_err() {
+                break;
+            }
+        }
+    })?;
+
+    Ok(circ)
+}
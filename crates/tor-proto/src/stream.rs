@@ -14,7 +14,7 @@ mod params;
 mod raw;
 mod resolve;
 
-pub use data::{DataReader, DataStream, DataWriter};
+pub use data::{DataReader, DataStream, DataWriter, StreamCounters};
 pub use params::StreamParameters;
 pub use raw::StreamReader;
 pub use resolve::ResolveStream;
@@ -18,6 +18,8 @@ use tokio_util::compat::{FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt};
 use std::fmt::{self, Debug};
 use std::io::Result as IoResult;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use crate::circuit::StreamTarget;
 use crate::stream::StreamReader;
@@ -119,6 +121,8 @@ pub struct DataWriter {
     /// AsyncWrite functions.  It might be possible to do better here,
     /// and we should refactor if so.
     state: Option<DataWriterState>,
+    /// The counters (if any) that we tell about the bytes we write.
+    counters: Option<Arc<CounterLink>>,
 }
 
 /// The read half of a [`DataStream`], implementing [`futures::io::AsyncRead`].
@@ -144,6 +148,79 @@ pub struct DataReader {
     /// poll_read().  It might be possible to do better here, and we
     /// should refactor if so.
     state: Option<DataReaderState>,
+    /// The counters (if any) that we tell about the bytes we read.
+    counters: Option<Arc<CounterLink>>,
+}
+
+/// A set of counters that one or more [`DataStream`]s update as they are
+/// used.
+///
+/// Attach a stream to these counters with
+/// [`DataStream::attach_counters`].  Bytes are counted as the application
+/// reads them from a stream, or hands them to a stream for sending.
+#[derive(Debug, Default)]
+pub struct StreamCounters {
+    /// How many streams have been attached to these counters?
+    n_opened: AtomicU64,
+    /// How many of those streams have not yet been dropped?
+    n_open: AtomicU64,
+    /// How many bytes have been written to the attached streams?
+    bytes_sent: AtomicU64,
+    /// How many bytes have been read from the attached streams?
+    bytes_received: AtomicU64,
+}
+
+impl StreamCounters {
+    /// Make a new set of counters, with every count at zero.
+    pub fn new() -> Self {
+        StreamCounters::default()
+    }
+
+    /// Return how many streams have ever been attached to these counters.
+    pub fn n_opened(&self) -> u64 {
+        self.n_opened.load(Ordering::Relaxed)
+    }
+
+    /// Return how many streams are attached to these counters, and have
+    /// not yet been dropped.
+    ///
+    /// A stream that has been split counts until both of its halves are
+    /// dropped.
+    pub fn n_open(&self) -> u64 {
+        self.n_open.load(Ordering::Relaxed)
+    }
+
+    /// Return how many bytes have been written to the attached streams.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Return how many bytes have been read from the attached streams.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+}
+
+/// A single stream's attachment to a [`StreamCounters`], shared between
+/// the two halves of the stream.
+///
+/// When the last half is dropped, the stream stops counting as open.
+#[derive(Debug)]
+struct CounterLink(Arc<StreamCounters>);
+
+impl CounterLink {
+    /// Attach a new stream to `counters`.
+    fn new(counters: &Arc<StreamCounters>) -> Self {
+        counters.n_opened.fetch_add(1, Ordering::Relaxed);
+        counters.n_open.fetch_add(1, Ordering::Relaxed);
+        CounterLink(Arc::clone(counters))
+    }
+}
+
+impl Drop for CounterLink {
+    fn drop(&mut self) {
+        self.0.n_open.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl DataStream {
@@ -159,6 +236,7 @@ impl DataStream {
                 offset: 0,
                 connected: false,
             })),
+            counters: None,
         };
         let w = DataWriter {
             state: Some(DataWriterState::Ready(DataWriterImpl {
@@ -166,6 +244,7 @@ impl DataStream {
                 buf: Box::new([0; Data::MAXLEN]),
                 n_pending: 0,
             })),
+            counters: None,
         };
         DataStream { w, r }
     }
@@ -175,6 +254,16 @@ impl DataStream {
         (self.r, self.w)
     }
 
+    /// Count this stream, and the bytes that are read from it and written
+    /// to it from now on, in `counters`.
+    ///
+    /// This replaces any counters that the stream was attached to before.
+    pub fn attach_counters(&mut self, counters: &Arc<StreamCounters>) {
+        let link = Arc::new(CounterLink::new(counters));
+        self.r.counters = Some(Arc::clone(&link));
+        self.w.counters = Some(link);
+    }
+
     /// Wait until a CONNECTED cell is received, or some other cell
     /// is received to indicate an error.
     ///
@@ -304,6 +393,13 @@ impl Debug for DataWriterImpl {
 }
 
 impl DataWriter {
+    /// Tell our counters (if any) that we've taken `n` bytes to send.
+    fn note_sent(&self, n: usize) {
+        if let Some(link) = &self.counters {
+            link.0.bytes_sent.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+
     /// Helper for poll_flush() and poll_close(): Performs a flush, then
     /// closes the stream if should_close is true.
     fn poll_flush_impl(
@@ -370,6 +466,7 @@ impl AsyncWrite for DataWriter {
                 let n_queued = imp.queue_bytes(buf);
                 if n_queued != 0 {
                     self.state = Some(DataWriterState::Ready(imp));
+                    self.note_sent(n_queued);
                     return Poll::Ready(Ok(n_queued));
                 }
                 // we couldn't queue anything, so the current cell must be full.
@@ -392,6 +489,7 @@ impl AsyncWrite for DataWriter {
                 // cell.
                 let n_queued = imp.queue_bytes(buf);
                 self.state = Some(DataWriterState::Ready(imp));
+                self.note_sent(n_queued);
                 Poll::Ready(Ok(n_queued))
             }
             Poll::Pending => {
@@ -504,6 +602,16 @@ struct DataReaderImpl {
     connected: bool,
 }
 
+impl DataReader {
+    /// Tell our counters (if any) that we've handed `n` bytes to the
+    /// application.
+    fn note_received(&self, n: usize) {
+        if let Some(link) = &self.counters {
+            link.0.bytes_received.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+}
+
 impl AsyncRead for DataReader {
     fn poll_read(
         mut self: Pin<&mut Self>,
@@ -522,6 +630,7 @@ impl AsyncRead for DataReader {
                     if n_copied != 0 {
                         // We read data into the buffer.  Tell the caller.
                         self.state = Some(DataReaderState::Ready(imp));
+                        self.note_received(n_copied);
                         return Poll::Ready(Ok(n_copied));
                     }
 
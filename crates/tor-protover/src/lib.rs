@@ -233,6 +233,56 @@ impl Protocols {
         }
     }
 
+    /// Return true if this set contains no protocol versions at all.
+    ///
+    /// ```
+    /// use tor_protover::*;
+    /// assert!(Protocols::new().is_empty());
+    /// assert!("Link=".parse::<Protocols>().unwrap().is_empty());
+    /// assert!(! "Link=4".parse::<Protocols>().unwrap().is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.recognized.iter().all(|mask| *mask == 0)
+            && self.unrecognized.iter().all(|ent| ent.supported == 0)
+    }
+
+    /// Return the set of protocol versions that are in this set, but not in
+    /// `other`.
+    ///
+    /// This is how we tell which of the versions that the network requires
+    /// (or recommends) we don't have.
+    ///
+    /// ```
+    /// use tor_protover::*;
+    /// let required: Protocols = "Link=4-5 Relay=2 Foobar=7".parse().unwrap();
+    /// let supported: Protocols = "Link=1-4 Relay=1-2".parse().unwrap();
+    ///
+    /// let missing = required.difference(&supported);
+    /// assert_eq!(missing.to_string(), "Foobar=7 Link=5");
+    /// assert!(supported.difference(&supported).is_empty());
+    /// ```
+    pub fn difference(&self, other: &Protocols) -> Protocols {
+        let mut result = Protocols::new();
+        for (idx, mask) in self.recognized.iter().enumerate() {
+            result.recognized[idx] = mask & !other.recognized[idx];
+        }
+        for ent in &self.unrecognized {
+            let other_mask = other
+                .unrecognized
+                .iter()
+                .find(|o| o.proto == ent.proto)
+                .map_or(0, |o| o.supported);
+            let supported = ent.supported & !other_mask;
+            if supported != 0 {
+                result.unrecognized.push(SubprotocolEntry {
+                    proto: ent.proto.clone(),
+                    supported,
+                });
+            }
+        }
+        result
+    }
+
     /// Parsing helper: Try to add a new entry `ent` to this set of protocols.
     ///
     /// Uses `foundmask`, a bit mask saying which recognized protocols
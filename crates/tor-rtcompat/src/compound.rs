@@ -1,7 +1,11 @@
 //! Define a [`CompoundRuntime`] part that can be built from several component
 //! pieces.
 
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::traits::*;
 use async_trait::async_trait;
@@ -92,6 +96,11 @@ where
     fn sleep(&self, duration: Duration) -> Self::SleepFuture {
         self.inner.sleep.sleep(duration)
     }
+
+    #[inline]
+    fn sleep_until_instant(&self, when: Instant) -> Self::SleepFuture {
+        self.inner.sleep.sleep_until_instant(when)
+    }
 }
 
 #[async_trait]
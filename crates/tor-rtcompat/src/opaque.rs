@@ -30,6 +30,10 @@ macro_rules! implement_opaque_runtime {
         fn sleep(&self, duration: std::time::Duration) -> Self::SleepFuture {
             self.$member.sleep(duration)
         }
+        #[inline]
+        fn sleep_until_instant(&self, when: std::time::Instant) -> Self::SleepFuture {
+            self.$member.sleep_until_instant(when)
+        }
     }
 
     #[async_trait::async_trait]
@@ -82,6 +82,20 @@ pub trait SleepProvider {
     #[must_use = "sleep() returns a future, which does nothing unless used"]
     fn sleep(&self, duration: Duration) -> Self::SleepFuture;
 
+    /// Return a future that will be ready once the monotonic clock (as
+    /// reported by [`SleepProvider::now()`]) reaches `when`.
+    ///
+    /// Unlike [`SleepProviderExt::sleep_until_wallclock`], this deadline
+    /// is unaffected by changes to the wall clock.
+    ///
+    /// If `when` is in the past, the future is ready right away.
+    ///
+    /// [`SleepProviderExt::sleep_until_wallclock`]: crate::SleepProviderExt::sleep_until_wallclock
+    #[must_use = "sleep_until_instant() returns a future, which does nothing unless used"]
+    fn sleep_until_instant(&self, when: Instant) -> Self::SleepFuture {
+        self.sleep(when.saturating_duration_since(self.now()))
+    }
+
     /// Return the SleepProvider's view of the current instant.
     ///
     /// (This is the same as `Instant::now`, if not running in test mode.)
@@ -0,0 +1,325 @@
+//! Declare MockExecRuntime.
+
+use crate::time::MockSleepProvider;
+use tor_rtcompat::{BlockOn, Runtime, SleepProvider, TcpProvider, TlsProvider};
+
+use async_trait::async_trait;
+use futures::task::{waker, ArcWake, FutureObj, Spawn, SpawnError};
+use futures::Future;
+use std::collections::{HashMap, VecDeque};
+use std::io::Result as IoResult;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::trace;
+
+/// A wrapper Runtime that runs tasks on its own deterministic executor,
+/// using a [`MockSleepProvider`] for time.
+///
+/// Unlike [`MockSleepRuntime`](crate::MockSleepRuntime), which lets the
+/// underlying runtime run tasks while
+/// [`wait_for`](crate::MockSleepRuntime::wait_for) guesses when to advance
+/// time, this runtime is driven entirely by the test.  Spawned tasks only
+/// run when you call [`run_until_stalled()`](Self::run_until_stalled) or
+/// [`block_on()`](BlockOn::block_on), and time only moves when you call
+/// [`advance_to_next_timeout()`](Self::advance_to_next_timeout) or when
+/// `block_on` finds that every task is waiting.  That way, the same test
+/// always runs the same way.
+///
+/// Network and TLS calls are still delegated to the underlying runtime, but
+/// a task waiting on real I/O will look (to this executor) like a task
+/// that's stuck: this runtime is meant for code that only needs time and
+/// tasks, or that uses a [`MockNetRuntime`](crate::MockNetRuntime).
+#[derive(Clone)]
+pub struct MockExecRuntime<R: Runtime> {
+    /// The underlying runtime. Network calls get delegated here.
+    runtime: R,
+    /// A MockSleepProvider.  Time-related calls get delegated here.
+    sleep: MockSleepProvider,
+    /// The executor that runs our tasks.
+    exec: Arc<Executor>,
+}
+
+/// Identifier for a task on an [`Executor`].
+type TaskId = usize;
+
+/// The id we use to wake the future passed to `block_on`, which is not
+/// stored with the other tasks.
+const MAIN_TASK: TaskId = 0;
+
+/// Shared state for the executor behind a [`MockExecRuntime`].
+#[derive(Default)]
+struct Executor {
+    /// The tasks that have been spawned and have not yet finished.
+    ///
+    /// A task is removed from this map while it is being polled.
+    tasks: Mutex<HashMap<TaskId, FutureObj<'static, ()>>>,
+    /// The id to give the next task that we spawn.
+    next_id: Mutex<TaskId>,
+    /// Ids of the tasks that have been woken, in the order they were woken.
+    ready: Arc<Mutex<VecDeque<TaskId>>>,
+}
+
+/// A waker that marks a single task on an [`Executor`] as ready to run.
+struct TaskWaker {
+    /// The task to mark as ready.
+    id: TaskId,
+    /// The executor's queue of ready tasks.
+    ready: Arc<Mutex<VecDeque<TaskId>>>,
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self
+            .ready
+            .lock()
+            .expect("Poisoned lock")
+            .push_back(arc_self.id);
+    }
+}
+
+impl Executor {
+    /// Return a [`Context`]-ready waker for the task with id `id`.
+    fn waker_for(&self, id: TaskId) -> std::task::Waker {
+        waker(Arc::new(TaskWaker {
+            id,
+            ready: Arc::clone(&self.ready),
+        }))
+    }
+
+    /// Return the id of the next task that has been woken, if any.
+    fn next_ready(&self) -> Option<TaskId> {
+        self.ready.lock().expect("Poisoned lock").pop_front()
+    }
+
+    /// Poll the spawned task with id `id`, if it still exists.
+    fn poll_task(&self, id: TaskId) {
+        let task = self.tasks.lock().expect("Poisoned lock").remove(&id);
+        // (If there's no task, it finished after being woken more than once.)
+        if let Some(mut task) = task {
+            let waker = self.waker_for(id);
+            let mut cx = Context::from_waker(&waker);
+            if Pin::new(&mut task).poll(&mut cx).is_pending() {
+                self.tasks.lock().expect("Poisoned lock").insert(id, task);
+            } else {
+                trace!(id, "task finished");
+            }
+        }
+    }
+}
+
+impl<R: Runtime> MockExecRuntime<R> {
+    /// Create a new runtime that wraps `runtime`, but runs its own tasks and
+    /// overrides its view of time with a [`MockSleepProvider`].
+    pub fn new(runtime: R) -> Self {
+        let sleep = MockSleepProvider::new(SystemTime::now());
+        MockExecRuntime {
+            runtime,
+            sleep,
+            exec: Arc::new(Executor::default()),
+        }
+    }
+
+    /// Return a reference to the underlying runtime.
+    pub fn inner(&self) -> &R {
+        &self.runtime
+    }
+
+    /// Return a reference to the [`MockSleepProvider`]
+    pub fn mock_sleep(&self) -> &MockSleepProvider {
+        &self.sleep
+    }
+
+    /// Run every spawned task that can make progress, until all of them are
+    /// waiting for something.
+    ///
+    /// Time does not advance while this function runs.
+    pub fn run_until_stalled(&self) {
+        while let Some(id) = self.exec.next_ready() {
+            if id != MAIN_TASK {
+                self.exec.poll_task(id);
+            }
+        }
+    }
+
+    /// Advance time to the moment when the next pending timeout elapses,
+    /// waking whatever was waiting for it.
+    ///
+    /// Return the amount of time that passed, or None if nothing is waiting
+    /// for a timeout.
+    pub fn advance_to_next_timeout(&self) -> Option<Duration> {
+        let dur = self.sleep.time_until_next_timeout()?;
+        trace!("Advancing by {:?}", dur);
+        self.sleep.advance_noyield(dur);
+        Some(dur)
+    }
+
+    /// Return the number of spawned tasks that have not yet finished.
+    pub fn n_tasks(&self) -> usize {
+        self.exec.tasks.lock().expect("Poisoned lock").len()
+    }
+}
+
+impl<R: Runtime> Spawn for MockExecRuntime<R> {
+    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        let id = {
+            let mut next_id = self.exec.next_id.lock().expect("Poisoned lock");
+            *next_id += 1;
+            *next_id
+        };
+        self.exec
+            .tasks
+            .lock()
+            .expect("Poisoned lock")
+            .insert(id, future);
+        self.exec.ready.lock().expect("Poisoned lock").push_back(id);
+        Ok(())
+    }
+}
+
+impl<R: Runtime> BlockOn for MockExecRuntime<R> {
+    /// Run `future` to completion, along with any tasks it spawns.
+    ///
+    /// Whenever the future and all of the spawned tasks are waiting, advance
+    /// time to the next pending timeout.
+    ///
+    /// # Panics
+    ///
+    /// Panics if everything is waiting and there is no pending timeout:
+    /// in that case, the future could never finish.
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        futures::pin_mut!(future);
+        let waker = self.exec.waker_for(MAIN_TASK);
+        let mut cx = Context::from_waker(&waker);
+        let mut main_ready = true;
+        loop {
+            if main_ready {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+                main_ready = false;
+            }
+            match self.exec.next_ready() {
+                Some(MAIN_TASK) => main_ready = true,
+                Some(id) => self.exec.poll_task(id),
+                None => {
+                    // Everything is waiting: only the passage of time can
+                    // help.
+                    if self.advance_to_next_timeout().is_none() {
+                        panic!("MockExecRuntime::block_on stalled with no pending timeouts");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<R: Runtime> TcpProvider for MockExecRuntime<R> {
+    type TcpStream = R::TcpStream;
+    type TcpListener = R::TcpListener;
+
+    async fn connect(&self, addr: &SocketAddr) -> IoResult<Self::TcpStream> {
+        self.runtime.connect(addr).await
+    }
+    async fn listen(&self, addr: &SocketAddr) -> IoResult<Self::TcpListener> {
+        self.runtime.listen(addr).await
+    }
+}
+
+impl<R: Runtime> TlsProvider<R::TcpStream> for MockExecRuntime<R> {
+    type Connector = R::Connector;
+    type TlsStream = R::TlsStream;
+    fn tls_connector(&self) -> Self::Connector {
+        self.runtime.tls_connector()
+    }
+}
+
+impl<R: Runtime> SleepProvider for MockExecRuntime<R> {
+    type SleepFuture = crate::time::Sleeping;
+    fn sleep(&self, dur: Duration) -> Self::SleepFuture {
+        self.sleep.sleep(dur)
+    }
+    fn sleep_until_instant(&self, when: Instant) -> Self::SleepFuture {
+        self.sleep.sleep_until_instant(when)
+    }
+    fn now(&self) -> Instant {
+        self.sleep.now()
+    }
+    fn wallclock(&self) -> SystemTime {
+        self.sleep.wallclock()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::task::SpawnExt;
+
+    #[test]
+    fn deterministic() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let rt = MockExecRuntime::new(rt);
+            let start = rt.now();
+
+            let (send, recv) = oneshot::channel();
+            let rt2 = rt.clone();
+            rt.spawn(async move {
+                rt2.sleep(Duration::from_secs(10)).await;
+                send.send(rt2.now()).unwrap();
+            })
+            .unwrap();
+
+            // Nothing happens until we run the task...
+            assert_eq!(rt.n_tasks(), 1);
+            rt.run_until_stalled();
+            assert_eq!(rt.n_tasks(), 1);
+
+            // ... and it can't finish until we advance time.
+            assert_eq!(rt.advance_to_next_timeout(), Some(Duration::from_secs(10)));
+            rt.run_until_stalled();
+            assert_eq!(rt.n_tasks(), 0);
+            assert_eq!(rt.advance_to_next_timeout(), None);
+
+            let finished = rt.block_on(recv).unwrap();
+            assert_eq!(finished, start + Duration::from_secs(10));
+        });
+    }
+
+    #[test]
+    fn block_on_advances() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let rt = MockExecRuntime::new(rt);
+            let start = rt.now();
+
+            let rt2 = rt.clone();
+            let (send, recv) = oneshot::channel();
+            rt.spawn(async move {
+                rt2.sleep(Duration::from_secs(5)).await;
+                send.send(()).unwrap();
+            })
+            .unwrap();
+
+            let rt3 = rt.clone();
+            rt.block_on(async move {
+                recv.await.unwrap();
+                rt3.sleep(Duration::from_secs(30)).await;
+            });
+            assert_eq!(rt.now(), start + Duration::from_secs(35));
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn block_on_stalled() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let rt = MockExecRuntime::new(rt);
+            let (_send, recv) = oneshot::channel::<()>();
+            let _ = rt.block_on(recv);
+        });
+    }
+}
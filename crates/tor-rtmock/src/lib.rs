@@ -16,7 +16,8 @@
 //! This crate should should only be used for writing tests.
 //!
 //! Currently, we support mocking the passage of time (via
-//! [`MockSleepRuntime`]), and impersonating the internet (via
+//! [`MockSleepRuntime`]), running tasks and time together deterministically
+//! (via [`MockExecRuntime`]), and impersonating the internet (via
 //! [`MockNetRuntime`]).
 //!
 //! # Examples
@@ -141,8 +142,10 @@ pub mod io;
 pub mod net;
 pub mod time;
 
+mod exec_runtime;
 mod net_runtime;
 mod sleep_runtime;
 
+pub use exec_runtime::MockExecRuntime;
 pub use net_runtime::MockNetRuntime;
 pub use sleep_runtime::MockSleepRuntime;
@@ -80,6 +80,9 @@ impl<R: Runtime> SleepProvider for MockNetRuntime<R> {
     fn sleep(&self, dur: Duration) -> Self::SleepFuture {
         self.runtime.sleep(dur)
     }
+    fn sleep_until_instant(&self, when: Instant) -> Self::SleepFuture {
+        self.runtime.sleep_until_instant(when)
+    }
     fn now(&self) -> Instant {
         self.runtime.now()
     }
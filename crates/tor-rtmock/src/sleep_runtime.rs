@@ -48,6 +48,14 @@ impl<R: Runtime> MockSleepRuntime<R> {
     pub fn jump_to(&self, new_wallclock: SystemTime) {
         self.sleep.jump_to(new_wallclock);
     }
+    /// See [`MockSleepProvider::freeze()`]
+    pub fn freeze(&self) {
+        self.sleep.freeze();
+    }
+    /// See [`MockSleepProvider::unfreeze()`]
+    pub fn unfreeze(&self) {
+        self.sleep.unfreeze();
+    }
     /// Run a future under mock time, advancing time forward where necessary until it completes.
     /// Users of this function should read the whole of this documentation before using!
     ///
@@ -118,6 +126,9 @@ impl<R: Runtime> SleepProvider for MockSleepRuntime<R> {
     fn sleep(&self, dur: Duration) -> Self::SleepFuture {
         self.sleep.sleep(dur)
     }
+    fn sleep_until_instant(&self, when: Instant) -> Self::SleepFuture {
+        self.sleep.sleep_until_instant(when)
+    }
     fn now(&self) -> Instant {
         self.sleep.now()
     }
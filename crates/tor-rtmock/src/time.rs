@@ -2,7 +2,9 @@
 //!
 //! We do this by providing [`MockSleepProvider`], a "SleepProvider"
 //! instance that can simulate timeouts and retries without requiring
-//! the actual system clock to advance.
+//! the actual system clock to advance.  For simulations with several
+//! nodes whose clocks move separately, [`MockSleepGroup`] holds a set of
+//! independent providers.
 
 #![allow(clippy::missing_docs_in_private_items)]
 
@@ -28,6 +30,9 @@ use tor_rtcompat::SleepProvider;
 /// can advance them in-step by calling `advance()`, and you can simulate
 /// jumps in the system clock by calling `jump()`.
 ///
+/// You can also stop time altogether by calling `freeze()`: until you call
+/// `unfreeze()`, no sleep will ever complete.
+///
 /// This is *not* for production use.
 #[derive(Clone)]
 pub struct MockSleepProvider {
@@ -58,6 +63,9 @@ struct SleepSchedule {
     blocked_advance: HashSet<String>,
     /// A time up to which advances are allowed, irrespective of them being blocked.
     allowed_advance: Duration,
+    /// If true, time doesn't move and no sleeper is woken, whatever
+    /// anybody asks.
+    frozen: bool,
 }
 
 /// An entry telling us when to wake which future up.
@@ -93,6 +101,7 @@ impl MockSleepProvider {
             should_advance: false,
             blocked_advance: HashSet::new(),
             allowed_advance: Duration::from_nanos(0),
+            frozen: false,
         };
         MockSleepProvider {
             state: Arc::new(Mutex::new(state)),
@@ -124,11 +133,47 @@ impl MockSleepProvider {
         // It's not so great to unwrap here in general, but since this is
         // only testing code we don't really care.
         let mut state = self.state.lock().expect("Poisoned lock for state");
+        if state.frozen {
+            trace!("not advancing by {:?}: time is frozen", dur);
+            return;
+        }
         state.wallclock += dur;
         state.instant += dur;
         state.fire();
     }
 
+    /// Stop the simulated timeline.
+    ///
+    /// Until [`unfreeze`](Self::unfreeze) is called, calls to
+    /// [`advance`](Self::advance) do nothing, and no sleep completes, not
+    /// even one whose deadline has already passed.  (A `WaitFor` driving
+    /// this provider won't try to advance time either.)
+    ///
+    /// This is useful for testing that something gives up because of some
+    /// external event, and not because our mock clock ran out.
+    pub fn freeze(&self) {
+        let mut state = self.state.lock().expect("Poisoned lock for state");
+        trace!("freezing time");
+        state.frozen = true;
+    }
+
+    /// Let the simulated timeline move again after a call to
+    /// [`freeze`](Self::freeze).
+    ///
+    /// Any sleeps whose deadlines had passed are woken.
+    pub fn unfreeze(&self) {
+        let mut state = self.state.lock().expect("Poisoned lock for state");
+        trace!("unfreezing time");
+        state.frozen = false;
+        state.fire();
+        state.maybe_advance();
+    }
+
+    /// Return true if time is currently frozen.
+    pub fn is_frozen(&self) -> bool {
+        self.state.lock().expect("Poisoned lock for state").frozen
+    }
+
     /// Simulate a discontinuity in the system clock, by jumping to
     /// `new_wallclock`.
     ///
@@ -162,6 +207,10 @@ impl MockSleepProvider {
     /// advance before calling it again.
     pub(crate) fn should_advance(&mut self) -> bool {
         let mut state = self.state.lock().expect("Poisoned lock for state");
+        if state.frozen {
+            trace!("should_advance = false: time is frozen");
+            return false;
+        }
         if !state.blocked_advance.is_empty() && state.allowed_advance == Duration::from_nanos(0) {
             // We've had advances blocked, and don't have any quota for doing allowances while
             // blocked left.
@@ -249,12 +298,75 @@ impl MockSleepProvider {
     }
 }
 
+/// A set of independent [`MockSleepProvider`]s, for simulating several
+/// nodes whose clocks don't move in step.
+///
+/// Each provider keeps its own view of the current time and its own queue
+/// of sleepers; advancing one of them has no effect on the others.
+///
+/// This is *not* for production use.
+#[derive(Clone)]
+pub struct MockSleepGroup {
+    /// The providers in this group, in the order they were created.
+    providers: Vec<MockSleepProvider>,
+}
+
+impl MockSleepGroup {
+    /// Create a new MockSleepGroup with `n` providers, all starting at the
+    /// given wall-clock time.
+    pub fn new(n: usize, wallclock: SystemTime) -> Self {
+        let providers = (0..n).map(|_| MockSleepProvider::new(wallclock)).collect();
+        MockSleepGroup { providers }
+    }
+
+    /// Create a new MockSleepGroup from a list of existing providers.
+    pub fn from_providers(providers: Vec<MockSleepProvider>) -> Self {
+        MockSleepGroup { providers }
+    }
+
+    /// Return the provider at position `idx` in this group, if there is one.
+    pub fn provider(&self, idx: usize) -> Option<&MockSleepProvider> {
+        self.providers.get(idx)
+    }
+
+    /// Return all the providers in this group.
+    pub fn providers(&self) -> &[MockSleepProvider] {
+        &self.providers[..]
+    }
+
+    /// Advance the simulated timeline of each provider by the corresponding
+    /// entry in `durs`.
+    ///
+    /// Calling this function will wake any pending futures on each provider
+    /// as appropriate, and then yield to the scheduler so they get a chance
+    /// to run.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `durs` doesn't have exactly one entry for each provider
+    /// in this group.
+    pub async fn advance_each(&self, durs: &[Duration]) {
+        assert_eq!(
+            durs.len(),
+            self.providers.len(),
+            "Wrong number of durations for MockSleepGroup"
+        );
+        for (provider, dur) in self.providers.iter().zip(durs) {
+            provider.advance_noyield(*dur);
+        }
+        tor_rtcompat::task::yield_now().await;
+    }
+}
+
 impl SleepSchedule {
     /// Wake any pending events that are ready according to the
     /// current simulated time.
     fn fire(&mut self) {
         use std::collections::binary_heap::PeekMut;
 
+        if self.frozen {
+            return;
+        }
         let now = self.instant;
         while let Some(top) = self.sleepers.peek_mut() {
             if now < top.when {
@@ -299,13 +411,17 @@ impl SleepSchedule {
 impl SleepProvider for MockSleepProvider {
     type SleepFuture = Sleeping;
     fn sleep(&self, duration: Duration) -> Self::SleepFuture {
+        let when = self.now() + duration;
+        self.sleep_until_instant(when)
+    }
+
+    fn sleep_until_instant(&self, when: Instant) -> Self::SleepFuture {
         let mut provider = self.state.lock().expect("Poisoned lock for state");
-        let when = provider.instant + duration;
         // We're making a new sleeper, so register this in the state.
         provider.sleepers_made += 1;
         trace!(
             "sleeper made for {:?}, {}/{}",
-            duration,
+            when.saturating_duration_since(provider.instant),
             provider.sleepers_polled,
             provider.sleepers_made
         );
@@ -395,7 +511,7 @@ impl Future for Sleeping {
             let mut provider = provider.lock().expect("Poisoned lock for provider");
             let now = provider.instant;
 
-            if now >= self.when {
+            if now >= self.when && !provider.frozen {
                 // The sleep time's elapsed.
                 if !self.inserted {
                     // If we never registered this sleeper as being polled, do so now.
@@ -454,6 +570,87 @@ mod test {
         assert_eq!(sp.wallclock(), w1 + interval * 3);
     }
 
+    #[test]
+    fn sleep_until_instant() {
+        use futures::FutureExt;
+
+        let sp = MockSleepProvider::new(SystemTime::now());
+        let start = sp.now();
+        let one_minute = Duration::new(60, 0);
+        let mut sleeping = sp.sleep_until_instant(start + one_minute * 2);
+        assert!((&mut sleeping).now_or_never().is_none());
+
+        // Changing the wall clock doesn't affect a monotonic deadline...
+        sp.jump_to(sp.wallclock() + one_minute * 60);
+        assert!((&mut sleeping).now_or_never().is_none());
+
+        // ... but advancing time does.
+        sp.advance_noyield(one_minute);
+        assert!((&mut sleeping).now_or_never().is_none());
+        sp.advance_noyield(one_minute);
+        assert!(sleeping.now_or_never().is_some());
+
+        // A deadline in the past is ready right away.
+        assert!(sp.sleep_until_instant(start).now_or_never().is_some());
+    }
+
+    #[test]
+    fn frozen_time() {
+        use futures::FutureExt;
+
+        let sp = MockSleepProvider::new(SystemTime::now());
+        let start = sp.now();
+        let one_minute = Duration::new(60, 0);
+        let mut sleeping = sp.sleep(one_minute);
+        assert!((&mut sleeping).now_or_never().is_none());
+
+        sp.freeze();
+        assert!(sp.is_frozen());
+        // Advancing does nothing...
+        sp.advance_noyield(one_minute * 2);
+        assert_eq!(sp.now(), start);
+        assert!((&mut sleeping).now_or_never().is_none());
+        // ... and even a sleep that's already due doesn't finish.
+        let mut due = sp.sleep(Duration::new(0, 0));
+        assert!((&mut due).now_or_never().is_none());
+
+        sp.unfreeze();
+        assert!(!sp.is_frozen());
+        assert!(due.now_or_never().is_some());
+        assert!((&mut sleeping).now_or_never().is_none());
+        sp.advance_noyield(one_minute);
+        assert!(sleeping.now_or_never().is_some());
+    }
+
+    #[test]
+    fn cancel_while_frozen() {
+        test_with_all_runtimes!(|_| async {
+            use futures::channel::oneshot;
+
+            let sp = MockSleepProvider::new(SystemTime::now());
+            sp.freeze();
+            let one_hour = Duration::new(3600, 0);
+            let (cancel, cancelled) = oneshot::channel::<()>();
+
+            let outcome = futures::join!(
+                async {
+                    futures::select_biased! {
+                        _ = sp.sleep(one_hour).fuse() => "timed out",
+                        _ = cancelled.fuse() => "cancelled",
+                    }
+                },
+                async {
+                    // Even if somebody tries to move the clock past the
+                    // deadline, only the cancellation can end the wait.
+                    sp.advance(one_hour * 2).await;
+                    cancel.send(()).unwrap();
+                }
+            );
+            assert_eq!(outcome.0, "cancelled");
+            std::io::Result::Ok(())
+        });
+    }
+
     #[test]
     fn time_moves_on() {
         test_with_all_runtimes!(|_| async {
@@ -516,4 +713,45 @@ mod test {
             std::io::Result::Ok(())
         });
     }
+
+    #[test]
+    fn independent_clocks() {
+        test_with_all_runtimes!(|_| async {
+            use futures::FutureExt;
+
+            let w1 = SystemTime::now();
+            let group = MockSleepGroup::new(2, w1);
+            assert_eq!(group.providers().len(), 2);
+            assert!(group.provider(2).is_none());
+            let sp1 = group.provider(0).unwrap();
+            let sp2 = group.provider(1).unwrap();
+            let (i1, i2) = (sp1.now(), sp2.now());
+
+            let one_minute = Duration::new(60, 0);
+            let mut sleep1 = sp1.sleep(one_minute * 3);
+            let mut sleep2 = sp2.sleep(one_minute * 3);
+            assert!((&mut sleep1).now_or_never().is_none());
+            assert!((&mut sleep2).now_or_never().is_none());
+
+            // Advancing the clocks by different amounts moves each one
+            // separately.
+            group.advance_each(&[one_minute * 4, one_minute]).await;
+            assert_eq!(sp1.now(), i1 + one_minute * 4);
+            assert_eq!(sp2.now(), i2 + one_minute);
+            assert_eq!(sp1.wallclock(), w1 + one_minute * 4);
+            assert_eq!(sp2.wallclock(), w1 + one_minute);
+
+            // Only the first clock has passed its sleeper's deadline.
+            assert!(sleep1.now_or_never().is_some());
+            assert!((&mut sleep2).now_or_never().is_none());
+
+            // Advancing only the second clock wakes its sleeper at last.
+            group
+                .advance_each(&[Duration::new(0, 0), one_minute * 2])
+                .await;
+            assert_eq!(sp1.now(), i1 + one_minute * 4);
+            assert!(sleep2.now_or_never().is_some());
+            std::io::Result::Ok(())
+        });
+    }
 }